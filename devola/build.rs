@@ -0,0 +1,32 @@
+fn main() {
+    println!("cargo:rerun-if-changed=src/ffi.rs");
+    #[cfg(feature = "header")]
+    generate_header();
+}
+
+/// Writes `include/devola.h`, declaring the C API
+#[cfg(feature = "header")]
+fn generate_header() {
+    use cbindgen::{Builder, Config, EnumConfig, Language, RenameRule};
+
+    let config = Config {
+        language: Language::C,
+        include_guard: Some(String::from("DEVOLA_H")),
+        autogen_warning: Some(String::from("/* Generated from src/ffi.rs by build.rs; don't edit it by hand */")),
+        // the VM is only ever handled through pointers, so C never needs to know what's in it
+        after_includes: Some(String::from("typedef struct Devola Devola;")),
+        cpp_compat: true,
+        usize_is_size_t: true,
+        enumeration: EnumConfig {
+            rename_variants: RenameRule::QualifiedScreamingSnakeCase,
+            ..EnumConfig::default()
+        },
+        ..Config::default()
+    };
+    Builder::new()
+        .with_config(config)
+        .with_src("src/ffi.rs")
+        .generate()
+        .expect("failed to generate the C header")
+        .write_to_file("include/devola.h");
+}
@@ -0,0 +1,189 @@
+#ifndef DEVOLA_H
+#define DEVOLA_H
+
+/* Generated from src/ffi.rs by build.rs; don't edit it by hand */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+typedef struct Devola Devola;
+
+typedef enum DevolaStatus {
+  DEVOLA_STATUS_OK,
+  /**
+   * The VM reached the end of its code
+   */
+  DEVOLA_STATUS_ENDED,
+  /**
+   * The VM faulted; `devola_last_error` says why
+   */
+  DEVOLA_STATUS_ERROR,
+} DevolaStatus;
+
+/**
+ * A library function, run when the program executes `call` with its name. Arguments and return values are passed
+ * on the stack, with `devola_pop` and `devola_push`.
+ */
+typedef void (*DevolaCallback)(Devola *devola, void *user_data);
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * Creates a VM with no code, which a program can be loaded into once its callbacks have been registered
+ */
+Devola *devola_create(void);
+
+/**
+ * # Safety
+ * `devola` must have come from `devola_create`, and not be used again.
+ */
+void devola_destroy(Devola *devola);
+
+/**
+ * The message explaining the last call on this thread that failed, or NULL. It's valid until the next call fails.
+ */
+const char *devola_last_error(void);
+
+/**
+ * Assembles a program and loads it, returning whether it could be built. Programs can only call the callbacks
+ * registered before they're loaded.
+ *
+ * # Safety
+ * `devola` must be a live VM, and `source` a NUL-terminated string.
+ */
+bool devola_load_source(Devola *devola,
+                        const char *source);
+
+/**
+ * Loads a program built by `devola-asm`, returning whether it could be decoded. Its data is copied into memory,
+ * and execution starts from its entry point.
+ *
+ * # Safety
+ * `devola` must be a live VM, and `bytes` must point to `length` readable bytes.
+ */
+bool devola_load_rom(Devola *devola,
+                     const uint8_t *bytes,
+                     size_t length);
+
+/**
+ * Registers a library function that programs loaded afterwards can `call` by name
+ *
+ * # Safety
+ * `devola` must be a live VM, and `name` a NUL-terminated string. `user_data` must stay valid for as long as the
+ * VM might call the callback.
+ */
+void devola_register_callback(Devola *devola,
+                              const char *name,
+                              DevolaCallback callback,
+                              void *user_data);
+
+/**
+ * Executes a single instruction
+ *
+ * # Safety
+ * `devola` must be a live VM.
+ */
+enum DevolaStatus devola_step(Devola *devola);
+
+/**
+ * Runs for at least `cycles` cycles, or until the end of the code. The cycles actually taken are written to
+ * `taken`, unless it's NULL.
+ *
+ * # Safety
+ * `devola` must be a live VM, and `taken` NULL or writable.
+ */
+enum DevolaStatus devola_run_cycles(Devola *devola,
+                                    uint64_t cycles,
+                                    uint64_t *taken);
+
+/**
+ * # Safety
+ * `devola` must be a live VM.
+ */
+uint8_t devola_peek(const Devola *devola, uint16_t address);
+
+/**
+ * # Safety
+ * `devola` must be a live VM.
+ */
+void devola_poke(Devola *devola, uint16_t address, uint8_t value);
+
+/**
+ * Copies `length` bytes of memory from `address` into `buffer`, wrapping around the end of memory
+ *
+ * # Safety
+ * `devola` must be a live VM, and `buffer` must point to `length` writable bytes.
+ */
+void devola_read_memory(const Devola *devola, uint16_t address, uint8_t *buffer, size_t length);
+
+/**
+ * Copies `length` bytes from `buffer` into memory at `address`, wrapping around the end of memory
+ *
+ * # Safety
+ * `devola` must be a live VM, and `buffer` must point to `length` readable bytes.
+ */
+void devola_write_memory(Devola *devola, uint16_t address, const uint8_t *buffer, size_t length);
+
+/**
+ * Pushes a byte to the stack, e.g. to return a value from a callback
+ *
+ * # Safety
+ * `devola` must be a live VM.
+ */
+void devola_push(Devola *devola, uint8_t value);
+
+/**
+ * Pops a byte from the stack, e.g. to take a callback's arguments
+ *
+ * # Safety
+ * `devola` must be a live VM.
+ */
+uint8_t devola_pop(Devola *devola);
+
+/**
+ * Writes the registers to `registers`, in the order A, B, C, X, Y
+ *
+ * # Safety
+ * `devola` must be a live VM, and `registers` must point to 5 writable bytes.
+ */
+void devola_registers(const Devola *devola, uint8_t *registers);
+
+/**
+ * The location of the next instruction
+ *
+ * # Safety
+ * `devola` must be a live VM.
+ */
+size_t devola_pc(const Devola *devola);
+
+/**
+ * The exit code the program stopped with at a `hlt N`, or -1 if it hasn't
+ *
+ * # Safety
+ * `devola` must be a live VM.
+ */
+int16_t devola_exit_code(const Devola *devola);
+
+/**
+ * # Safety
+ * `devola` must be a live VM.
+ */
+uint64_t devola_cycles(const Devola *devola);
+
+/**
+ * Requests an interrupt on a line (0-7)
+ *
+ * # Safety
+ * `devola` must be a live VM.
+ */
+void devola_request_interrupt(Devola *devola, uint8_t line);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* DEVOLA_H */
@@ -0,0 +1,251 @@
+use devola::{conformance, hotswap};
+use devola::binary::{DataSegment, RomImage};
+use devola::disasm::instruction_text;
+use devola::instructions::Instruction;
+#[cfg(feature = "serde")]
+use devola::json::{self, Program};
+use devola::parser::intermediate::{export_symbol_file, strip, LineTable, SymbolTable};
+use devola::parser::text::{compile_from, compile_optimized_from};
+use std::io::Write;
+use std::net::TcpStream;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+use std::{env, fs, process, thread};
+
+const USAGE: &str = "Usage: devola-asm (build | watch) <source.pop> [-o <program.popr | program.json>] [--sym <file.sym>] [--listing <file.lst>] [--push <address>] [-O] [--strip]
+       devola-asm flagtests <directory>";
+
+/// How often `watch` checks whether the source has changed
+const WATCH_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Where `build` writes its outputs
+#[derive(Debug, Default, PartialEq)]
+struct BuildOptions {
+    source: String,
+    output: String,
+    symbols: Option<String>,
+    listing: Option<String>,
+    /// Where a frontend started with `--hot-reload` is listening for programs
+    push: Option<String>,
+    /// Whether instructions that make no difference are left out
+    optimize: bool,
+    /// Whether code that can never run and unused labels are left out
+    strip: bool
+}
+
+fn parse_build_options(args: &[String]) -> Option<BuildOptions> {
+    let (source, rest) = args.split_first()?;
+    let mut options = BuildOptions {
+        source: source.clone(),
+        // next to the source by default
+        output: Path::new(source).with_extension("popr").to_string_lossy().into_owned(),
+        ..BuildOptions::default()
+    };
+    let mut rest = rest.iter();
+    while let Some(option) = rest.next() {
+        match option.as_str() {
+            "-o" => options.output = rest.next()?.clone(),
+            "--sym" => options.symbols = Some(rest.next()?.clone()),
+            "--listing" => options.listing = Some(rest.next()?.clone()),
+            "--push" => options.push = Some(rest.next()?.clone()),
+            "-O" => options.optimize = true,
+            "--strip" => options.strip = true,
+            _ => return None
+        }
+    }
+    Some(options)
+}
+
+/// Each instruction with its location, under the labels that point to it
+fn listing(code: &[Instruction], symbols: &SymbolTable) -> String {
+    let mut listing = String::new();
+    for (pc, instruction) in code.iter().enumerate() {
+        if let Some(label) = symbols.get(&pc) {
+            listing.push_str(&format!("{}:\n", label));
+        }
+        listing.push_str(&format!("{:>6}  {}\n", pc, instruction_text(instruction, Some(symbols))));
+    }
+    listing
+}
+
+fn write(path: &str, contents: &[u8]) {
+    if let Err(error) = fs::write(path, contents) {
+        eprintln!("Failed to write {}: {}", path, error);
+        process::exit(1);
+    }
+}
+
+/// The program in the JSON format, which keeps its symbols and source lines
+#[cfg(feature = "serde")]
+fn encode_json(code: &[Instruction], symbols: &SymbolTable, lines: LineTable, segments: Vec<DataSegment>) -> String {
+    json::encode(Program { code: code.to_vec(), symbols: Some(symbols.clone()), lines: Some(lines), segments })
+}
+
+#[cfg(not(feature = "serde"))]
+fn encode_json(_: &[Instruction], _: &SymbolTable, _: LineTable, _: Vec<DataSegment>) -> String {
+    eprintln!("JSON output needs devola-asm to be built with the serde feature");
+    process::exit(1);
+}
+
+/// Sends the program to a running frontend, to swap in for the one it's running
+fn push(address: &str, image: &RomImage, symbols: &SymbolTable) {
+    let message = match hotswap::encode(image, symbols) {
+        Ok(message) => message,
+        Err(error) => {
+            eprintln!("Failed to send the program to {}: {}", address, error);
+            return;
+        }
+    };
+    let sent = TcpStream::connect(address).and_then(|mut connection| connection.write_all(&message));
+    if let Err(error) = sent {
+        eprintln!("Failed to send the program to {}: {}", address, error);
+    }
+}
+
+/// Assembles the source and writes the outputs, returning whether it could be built
+fn build(options: &BuildOptions) -> bool {
+    let source = match fs::read_to_string(&options.source) {
+        Ok(source) => source,
+        Err(error) => {
+            eprintln!("Failed to read {}: {}", options.source, error);
+            return false;
+        }
+    };
+    let path = Path::new(&options.source);
+    let compiled = if options.optimize { compile_optimized_from(source, None, path) } else { compile_from(source, None, path) };
+    let (code, symbols, lines, segments) = match compiled {
+        Ok(compiled) => compiled,
+        Err(errors) => {
+            for error in errors {
+                eprintln!("{}: {}", options.source, error);
+            }
+            return false;
+        }
+    };
+
+    let (code, symbols, lines) = if options.strip {
+        let (stripped, stripped_symbols, stripped_lines, removed) = strip(&code, &symbols, &lines);
+        for pc in removed {
+            match symbols.get(&pc) {
+                Some(label) => println!("{}:{}: removed unused label {}", options.source, lines[pc] + 1, label),
+                None => println!("{}:{}: removed unreachable {}", options.source, lines[pc] + 1, instruction_text(&code[pc], Some(&symbols)))
+            }
+        }
+        (stripped, stripped_symbols, stripped_lines)
+    } else {
+        (code, symbols, lines)
+    };
+
+    let title = Path::new(&options.source).file_stem().map_or(String::new(), |stem| stem.to_string_lossy().into_owned());
+    let image = RomImage { title, segments, ..RomImage::new(code.clone()) };
+    if options.output.ends_with(".json") {
+        write(&options.output, encode_json(&code, &symbols, lines.clone(), image.segments.clone()).as_bytes());
+    } else {
+        match image.encode() {
+            Ok(bytes) => write(&options.output, &bytes),
+            Err(error) => {
+                eprintln!("{}: {}", options.source, error);
+                return false;
+            }
+        }
+    }
+    if let Some(path) = &options.symbols {
+        write(path, export_symbol_file(&symbols, &lines).as_bytes());
+    }
+    if let Some(path) = &options.listing {
+        write(path, listing(&code, &symbols).as_bytes());
+    }
+    if let Some(address) = &options.push {
+        push(address, &image, &symbols);
+    }
+    true
+}
+
+fn modified(path: &str) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+/// Builds the source again whenever it changes, until interrupted
+fn watch(options: &BuildOptions) {
+    let mut built = None;
+    loop {
+        let changed = modified(&options.source);
+        if changed.is_some() && changed != built {
+            built = changed;
+            if build(options) {
+                println!("Built {}", options.source);
+            }
+        }
+        thread::sleep(WATCH_INTERVAL);
+    }
+}
+
+/// Writes out the programs that check each instruction's results and flags, to run on another implementation
+fn flag_tests(directory: &str) {
+    if let Err(error) = fs::create_dir_all(directory) {
+        eprintln!("Failed to create {}: {}", directory, error);
+        process::exit(1);
+    }
+    for program in conformance::generate() {
+        write(&Path::new(directory).join(format!("{}.pop", program.name)).to_string_lossy(), program.source.as_bytes());
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    match args.split_first() {
+        Some((command, rest)) if command == "build" || command == "watch" => match parse_build_options(rest) {
+            Some(options) if command == "watch" => watch(&options),
+            Some(options) => {
+                if !build(&options) {
+                    process::exit(1);
+                }
+            },
+            None => {
+                eprintln!("{}", USAGE);
+                process::exit(2);
+            }
+        },
+        Some((command, [directory])) if command == "flagtests" => flag_tests(directory),
+        _ => {
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use devola::instructions::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_build_options() {
+        assert_eq!(parse_build_options(&args(&["games/game.pop", "--sym", "game.sym"])), Some(BuildOptions {
+            source: "games/game.pop".to_string(),
+            output: "games/game.popr".to_string(),
+            symbols: Some("game.sym".to_string()),
+            listing: None,
+            push: None,
+            optimize: false,
+            strip: false
+        }));
+        assert_eq!(parse_build_options(&args(&["game.pop", "-o", "out.popr", "--listing", "game.lst"])).unwrap().output, "out.popr");
+        assert_eq!(parse_build_options(&args(&["game.pop", "--push", "127.0.0.1:6502"])).unwrap().push.as_deref(), Some("127.0.0.1:6502"));
+        assert!(parse_build_options(&args(&["game.pop", "-O", "--sym", "game.sym"])).unwrap().optimize);
+        assert!(parse_build_options(&args(&["game.pop", "--strip"])).unwrap().strip);
+        assert_eq!(parse_build_options(&args(&["game.pop", "--sym"])), None);
+        assert_eq!(parse_build_options(&[]), None);
+    }
+
+    #[test]
+    fn test_listing() {
+        let code = vec![Instruction::Nop, Instruction::Jump(JumpType::Unconditional, 0), Instruction::Call(CallType::Local(1))];
+        let symbols = SymbolTable::from([(0, String::from("loop"))]);
+        assert_eq!(listing(&code, &symbols), "loop:\n     0  nop\n     1  jmp loop\n     2  call l1\n");
+    }
+}
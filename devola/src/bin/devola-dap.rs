@@ -0,0 +1,44 @@
+use devola::dap::DebugAdapter;
+use devola::framing;
+use std::io::{self, BufReader};
+use std::sync::mpsc::{self, TryRecvError};
+use std::{process, thread};
+
+/// Instructions run between checks for requests while the program is running
+const STEPS_PER_POLL: usize = 10_000;
+
+fn main() {
+    // requests are read on their own thread, so that a running program can still be paused
+    let (sender, requests) = mpsc::channel();
+    thread::spawn(move || {
+        let mut stdin = BufReader::new(io::stdin());
+        while let Ok(Some(request)) = framing::read_message(&mut stdin) {
+            if sender.send(request).is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut stdout = io::stdout().lock();
+    let mut adapter = DebugAdapter::default();
+    while !adapter.finished() {
+        let messages = if adapter.running() {
+            match requests.try_recv() {
+                Ok(request) => adapter.handle(&request),
+                Err(TryRecvError::Empty) => adapter.advance(STEPS_PER_POLL),
+                Err(TryRecvError::Disconnected) => break
+            }
+        } else {
+            match requests.recv() {
+                Ok(request) => adapter.handle(&request),
+                Err(_) => break
+            }
+        };
+        for message in messages {
+            if let Err(error) = framing::write_message(&mut stdout, &message) {
+                eprintln!("Failed to write to the client: {}", error);
+                process::exit(1);
+            }
+        }
+    }
+}
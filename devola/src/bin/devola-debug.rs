@@ -0,0 +1,399 @@
+use devola::binary::RomImage;
+use devola::cartridge::Cartridge;
+use devola::debugger::Debugger;
+use devola::disasm::instruction_text;
+use devola::instructions::{Flag, Register};
+use devola::parser::intermediate::{import_symbol_file, SymbolTable};
+use devola::stdlib::interface::DevolaExternTable;
+use devola::trace::TraceLog;
+use devola::vm::Devola;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Modifier, Style};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Paragraph};
+use ratatui::{DefaultTerminal, Frame};
+use std::path::Path;
+use std::time::Duration;
+use tracing_subscriber::layer::SubscriberExt;
+use std::{env, fs, io, process};
+
+const USAGE: &str = "Usage: devola-debug <source.pop | program.popr> [--sym <file.sym>]";
+const HELP: &str = "s step  c continue  p pause  PgUp/PgDn memory  : command (b <loc>, w <addr>, m <addr>)  q quit";
+
+/// Instructions run between checks for input while continuing
+const STEPS_PER_POLL: usize = 10_000;
+const HEXDUMP_ROWS: usize = 8;
+const STACK_ROWS: usize = 8;
+const LOG_ROWS: usize = 6;
+
+/// A command typed after `:`
+#[derive(Debug, PartialEq)]
+enum Command {
+    /// Toggle a breakpoint on an instruction
+    Break(usize),
+    /// Toggle a watchpoint on a byte of memory
+    Watch(u16),
+    /// Show memory from an address in the hexdump
+    Memory(u16)
+}
+
+/// A number in the assembler's syntax: decimal, or hexadecimal with an `h` suffix
+fn parse_number(text: &str) -> Option<u16> {
+    match text.strip_suffix(['h', 'H']) {
+        Some(hex) => u16::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok()
+    }
+}
+
+fn parse_command(text: &str, symbols: Option<&SymbolTable>) -> Result<Command, String> {
+    let words: Vec<&str> = text.split_whitespace().collect();
+    let number = |word: &str| parse_number(word).ok_or(format!("invalid number {}", word));
+    match words.as_slice() {
+        ["b", location] => {
+            // breakpoints can be given as labels
+            let label = symbols.and_then(|symbols| symbols.iter().find(|(_, label)| label == location));
+            match label {
+                Some((&pc, _)) => Ok(Command::Break(pc)),
+                None => Ok(Command::Break(number(location)? as usize))
+            }
+        },
+        ["w", address] => Ok(Command::Watch(number(address)?)),
+        ["m", address] => Ok(Command::Memory(number(address)?)),
+        _ => Err(format!("unknown command {}", text))
+    }
+}
+
+/// The code around the next instruction, marking it with `>` and breakpoints with `*`
+fn disassembly(debugger: &Debugger, rows: usize) -> Vec<Line<'static>> {
+    let devola = &debugger.devola;
+    let symbols = devola.symbols();
+    let start = devola.pc().saturating_sub(rows / 3);
+    let end = (start + rows).min(devola.code().len());
+    (start..end).map(|pc| {
+        let marker = if pc == devola.pc() { '>' } else { ' ' };
+        let breakpoint = if debugger.breakpoints().contains(&pc) { '*' } else { ' ' };
+        // labels compile to the nop at their location, so they're shown in its place
+        let text = match symbols.and_then(|symbols| symbols.get(&pc)) {
+            Some(label) => format!("{}:", label),
+            None => format!("    {}", instruction_text(&devola.code()[pc], symbols))
+        };
+        let line = Line::from(format!("{}{}{:>5}  {}", marker, breakpoint, pc, text));
+        if pc == devola.pc() { line.style(Style::new().add_modifier(Modifier::REVERSED)) } else { line }
+    }).collect()
+}
+
+fn registers(devola: &Devola) -> Vec<Line<'static>> {
+    let location = match devola.line(devola.pc()) {
+        Some(line) => format!("PC     {}  line {}", devola.pc(), line + 1),
+        None => format!("PC     {}", devola.pc())
+    };
+    let mut lines = vec![
+        Line::from(location),
+        Line::from(format!("cycles {}", devola.cycles())),
+        Line::from(format!("SP     {:04X}h", devola.stack_pointer()))
+    ];
+    for (name, register) in [('A', Register::Accumulator), ('B', Register::UtilityB), ('C', Register::UtilityC), ('X', Register::IndexX), ('Y', Register::IndexY)] {
+        let value = devola.register(register);
+        lines.push(Line::from(format!("{}      {:02X}h {:>3}", name, value, value)));
+    }
+    // set flags by name, clear ones as dashes
+    let flags: String = [('C', Flag::Carry), ('Z', Flag::Zero), ('P', Flag::Parity), ('S', Flag::Sign)].iter()
+        .map(|&(name, flag)| if devola.flag(flag) { name } else { '-' })
+        .collect();
+    lines.push(Line::from(format!("flags  {}", flags)));
+    lines
+}
+
+/// The bytes on the stack, most recently pushed first
+fn stack(devola: &Devola) -> Vec<Line<'static>> {
+    (devola.stack_pointer()..devola.memory_map().initial_stack_pointer)
+        .take(STACK_ROWS)
+        .map(|address| Line::from(format!("{:04X}h  {:02X}h", address, devola.peek(address))))
+        .collect()
+}
+
+fn hexdump(devola: &Devola, start: u16) -> Vec<Line<'static>> {
+    (0..HEXDUMP_ROWS).map(|row| {
+        let address = start.wrapping_add(16 * row as u16);
+        let bytes: Vec<String> = (0..16).map(|offset| format!("{:02X}", devola.peek(address.wrapping_add(offset)))).collect();
+        Line::from(format!("{:04X}h  {}", address, bytes.join(" ")))
+    }).collect()
+}
+
+struct App {
+    debugger: Debugger,
+    running: bool,
+    status: String,
+    /// The first address shown in the hexdump
+    memory_view: u16,
+    /// The command being typed, if any
+    command: Option<String>,
+    /// Calls, returns and faults, as the VM traces them
+    log: TraceLog,
+    quit: bool
+}
+
+impl App {
+    fn new(debugger: Debugger, log: TraceLog) -> App {
+        App { debugger, running: false, status: String::from("paused"), memory_view: 0, command: None, log, quit: false }
+    }
+
+    fn execute(&mut self, text: &str) {
+        self.status = match parse_command(text, self.debugger.devola.symbols()) {
+            Ok(Command::Break(pc)) if self.debugger.toggle_breakpoint(pc) => format!("breakpoint set at {}", pc),
+            Ok(Command::Break(pc)) => format!("breakpoint cleared at {}", pc),
+            Ok(Command::Watch(address)) if self.debugger.toggle_watchpoint(address) => format!("watching {:04X}h", address),
+            Ok(Command::Watch(address)) => format!("stopped watching {:04X}h", address),
+            Ok(Command::Memory(address)) => {
+                self.memory_view = address;
+                format!("showing memory from {:04X}h", address)
+            },
+            Err(error) => error
+        };
+    }
+
+    fn handle_key(&mut self, key: KeyCode) {
+        if let Some(command) = self.command.as_mut() {
+            match key {
+                KeyCode::Char(c) => command.push(c),
+                KeyCode::Backspace => { command.pop(); },
+                KeyCode::Enter => {
+                    let command = self.command.take().unwrap();
+                    self.execute(&command);
+                },
+                KeyCode::Esc => self.command = None,
+                _ => ()
+            }
+            return;
+        }
+        let page = 16 * HEXDUMP_ROWS as u16;
+        match key {
+            KeyCode::Char('s') if !self.running => {
+                self.status = match self.debugger.step() {
+                    Some(stop) => stop.to_string(),
+                    None => String::from("stepped")
+                };
+            },
+            KeyCode::Char('c') => {
+                self.running = true;
+                self.status = String::from("running");
+            },
+            KeyCode::Char('p') if self.running => {
+                self.running = false;
+                self.status = String::from("paused");
+            },
+            KeyCode::Char(':') => self.command = Some(String::new()),
+            KeyCode::Char('q') => self.quit = true,
+            KeyCode::PageUp => self.memory_view = self.memory_view.wrapping_sub(page),
+            KeyCode::PageDown => self.memory_view = self.memory_view.wrapping_add(page),
+            _ => ()
+        }
+    }
+
+    /// Continues running, if it is, until the next time to check for input
+    fn tick(&mut self) {
+        if self.running {
+            if let Some(stop) = self.debugger.run(STEPS_PER_POLL) {
+                self.running = false;
+                self.status = stop.to_string();
+            }
+        }
+    }
+
+    fn draw(&self, frame: &mut Frame) {
+        let [main, memory, status] = Layout::vertical([
+            Constraint::Fill(1), Constraint::Length(HEXDUMP_ROWS as u16 + 2), Constraint::Length(2)
+        ]).areas(frame.area());
+        let [code_column, side] = Layout::horizontal([Constraint::Fill(1), Constraint::Length(24)]).areas(main);
+        let [code, log] = Layout::vertical([Constraint::Fill(1), Constraint::Length(LOG_ROWS as u16 + 2)]).areas(code_column);
+        let [registers_area, stack_area] = Layout::vertical([Constraint::Length(11), Constraint::Fill(1)]).areas(side);
+
+        let rows = code.height.saturating_sub(2) as usize;
+        frame.render_widget(Paragraph::new(disassembly(&self.debugger, rows)).block(Block::bordered().title("Code")), code);
+        frame.render_widget(Paragraph::new(registers(&self.debugger.devola)).block(Block::bordered().title("Registers")), registers_area);
+        frame.render_widget(Paragraph::new(stack(&self.debugger.devola)).block(Block::bordered().title("Stack")), stack_area);
+        let log_lines: Vec<Line> = self.log.lines().into_iter().map(Line::from).collect();
+        frame.render_widget(Paragraph::new(log_lines).block(Block::bordered().title("Log")), log);
+        frame.render_widget(Paragraph::new(hexdump(&self.debugger.devola, self.memory_view)).block(Block::bordered().title("Memory")), memory);
+
+        let prompt = match &self.command {
+            Some(command) => format!(":{}", command),
+            None => self.status.clone()
+        };
+        frame.render_widget(Paragraph::new(vec![Line::from(prompt), Line::from(HELP)]), status);
+    }
+}
+
+fn run(terminal: &mut DefaultTerminal, mut app: App) -> io::Result<()> {
+    while !app.quit {
+        terminal.draw(|frame| app.draw(frame))?;
+        // while running, input is only checked between batches of instructions
+        if !app.running || event::poll(Duration::ZERO)? {
+            if let Event::Key(key) = event::read()? {
+                if key.kind == KeyEventKind::Press {
+                    app.handle_key(key.code);
+                }
+            }
+        }
+        app.tick();
+    }
+    Ok(())
+}
+
+/// Compiles a source file, or decodes a program built with `devola-asm`
+fn load(path: &str) -> Result<Devola, String> {
+    if path.ends_with(".popr") {
+        let bytes = fs::read(path).map_err(|error| error.to_string())?;
+        let image = RomImage::decode(&bytes).map_err(|error| error.to_string())?;
+        return Cartridge::from(image).load().map_err(|error| error.to_string());
+    }
+    let source = fs::read_to_string(path).map_err(|error| error.to_string())?;
+    let cartridge = Cartridge::compile_from(source, DevolaExternTable::new(), Path::new(path))
+        .map_err(|errors| errors.iter().map(|error| error.to_string()).collect::<Vec<_>>().join("\n"))?;
+    cartridge.load().map_err(|error| error.to_string())
+}
+
+/// Names the code and its source lines from a symbol file written by `devola-asm --sym`, for a program built
+/// without them
+fn load_symbols(devola: &mut Devola, path: &str) -> Result<(), String> {
+    let text = fs::read_to_string(path).map_err(|error| error.to_string())?;
+    let (symbols, lines) = import_symbol_file(&text).ok_or_else(|| String::from("not a symbol file"))?;
+    // a file with only labels has no line table to compare
+    if !lines.is_empty() && lines.len() != devola.code().len() {
+        return Err(format!("the symbol file is for a program of {} instructions, not {}", lines.len(), devola.code().len()));
+    }
+    if let Some((&location, label)) = symbols.iter().find(|(&location, _)| location >= devola.code().len()) {
+        return Err(format!("{} is past the end of the program, at {}", label, location));
+    }
+    devola.set_symbols(symbols);
+    if !lines.is_empty() {
+        devola.set_line_table(lines);
+    }
+    Ok(())
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let (path, symbols) = match args.as_slice() {
+        [path] => (path, None),
+        [path, flag, symbols] if flag == "--sym" => (path, Some(symbols)),
+        _ => {
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        }
+    };
+    let mut devola = match load(path) {
+        Ok(devola) => devola,
+        Err(error) => {
+            eprintln!("Failed to load {}: {}", path, error);
+            process::exit(1);
+        }
+    };
+    if let Some(symbols) = symbols {
+        if let Err(error) = load_symbols(&mut devola, symbols) {
+            eprintln!("Failed to load {}: {}", symbols, error);
+            process::exit(1);
+        }
+    }
+
+    // the VM's trace events are shown in the log panel
+    let log = TraceLog::new(LOG_ROWS);
+    tracing::subscriber::set_global_default(tracing_subscriber::registry().with(log.clone())).unwrap();
+    devola.enable_debug();
+
+    let mut terminal = ratatui::init();
+    let result = run(&mut terminal, App::new(Debugger::new(devola), log));
+    ratatui::restore();
+    if let Err(error) = result {
+        eprintln!("{}", error);
+        process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use devola::parser::text::compile;
+
+    fn debugger() -> Debugger {
+        let (code, symbols, _, _) = compile(String::from("lda 1\nloop:\npush a\njmp loop"), None).unwrap();
+        Debugger::new(Devola::new(code, Some(symbols)))
+    }
+
+    fn text(lines: Vec<Line>) -> Vec<String> {
+        lines.iter().map(Line::to_string).collect()
+    }
+
+    #[test]
+    fn test_parse_command() {
+        let debugger = debugger();
+        let symbols = debugger.devola.symbols();
+        assert_eq!(parse_command("b loop", symbols), Ok(Command::Break(1)));
+        assert_eq!(parse_command("b 3", symbols), Ok(Command::Break(3)));
+        assert_eq!(parse_command("w 0200h", symbols), Ok(Command::Watch(0x200)));
+        assert_eq!(parse_command("m 16", symbols), Ok(Command::Memory(16)));
+        assert_eq!(parse_command("b nowhere", symbols), Err(String::from("invalid number nowhere")));
+        assert_eq!(parse_command("x", symbols), Err(String::from("unknown command x")));
+    }
+
+    #[test]
+    fn test_panels() {
+        let mut debugger = debugger();
+        debugger.toggle_breakpoint(2);
+        debugger.run(2);
+        assert_eq!(text(disassembly(&debugger, 10)), [
+            "      0      lda 1",
+            "      1  loop:",
+            ">*    2      push a",
+            "      3      jmp loop"
+        ]);
+        assert_eq!(text(stack(&debugger.devola)), Vec::<String>::new());
+        debugger.step();
+        assert_eq!(text(stack(&debugger.devola)), ["0EFFh  01h"]);
+        assert_eq!(text(registers(&debugger.devola))[2], "SP     0EFFh");
+        assert_eq!(text(hexdump(&debugger.devola, 0x0EF0))[0], "0EF0h  00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 01");
+    }
+
+    #[test]
+    fn test_commands() {
+        let mut app = App::new(debugger(), TraceLog::new(LOG_ROWS));
+        for key in [':', 'b', ' ', '2'] {
+            app.handle_key(KeyCode::Char(key));
+        }
+        app.handle_key(KeyCode::Enter);
+        assert_eq!(app.status, "breakpoint set at 2");
+        app.handle_key(KeyCode::Char('c'));
+        app.tick();
+        assert!(!app.running);
+        assert_eq!(app.status, "breakpoint at 2");
+        assert_eq!(app.debugger.devola.pc(), 2);
+    }
+
+    #[test]
+    fn test_load_symbols() {
+        let (code, symbols, lines, _) = compile(String::from("lda 1\nloop:\npush a\njmp loop"), None).unwrap();
+        let path = env::temp_dir().join(format!("devola_debug_test_{}.sym", std::process::id()));
+        fs::write(&path, devola::parser::intermediate::export_symbol_file(&symbols, &lines)).unwrap();
+        // a program decoded from its binary encoding has no names
+        let mut debugger = Debugger::new(Devola::new(code, None));
+        load_symbols(&mut debugger.devola, path.to_str().unwrap()).unwrap();
+        debugger.run(2);
+        assert_eq!(text(disassembly(&debugger, 3))[0], "      1  loop:");
+        assert_eq!(text(registers(&debugger.devola))[0], "PC     2  line 3");
+
+        fs::write(&path, "loop 1\n").unwrap();
+        assert_eq!(load_symbols(&mut debugger.devola, path.to_str().unwrap()), Err(String::from("not a symbol file")));
+        // a stale file, for a shorter or longer build of the program, is refused
+        let (_, symbols, lines, _) = compile(String::from("lda 1\nloop:\njmp loop"), None).unwrap();
+        fs::write(&path, devola::parser::intermediate::export_symbol_file(&symbols, &lines)).unwrap();
+        assert_eq!(
+            load_symbols(&mut debugger.devola, path.to_str().unwrap()),
+            Err(String::from("the symbol file is for a program of 3 instructions, not 4"))
+        );
+        let (_, symbols, _, _) = compile(String::from("lda 1\npush a\njmp done\nnop\ndone:"), None).unwrap();
+        fs::write(&path, devola::parser::intermediate::export_symbol_file(&symbols, &Vec::new())).unwrap();
+        assert_eq!(load_symbols(&mut debugger.devola, path.to_str().unwrap()), Err(String::from("done is past the end of the program, at 4")));
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,26 @@
+use devola::framing;
+use devola::lsp::LanguageServer;
+use std::io::{self, BufReader};
+use std::process;
+
+fn main() {
+    let mut stdin = BufReader::new(io::stdin());
+    let mut stdout = io::stdout().lock();
+    let mut server = LanguageServer::default();
+    while !server.finished() {
+        let message = match framing::read_message(&mut stdin) {
+            Ok(Some(message)) => message,
+            Ok(None) => break,
+            Err(error) => {
+                eprintln!("Failed to read from the client: {}", error);
+                process::exit(1);
+            }
+        };
+        for reply in server.handle(&message) {
+            if let Err(error) = framing::write_message(&mut stdout, &reply) {
+                eprintln!("Failed to write to the client: {}", error);
+                process::exit(1);
+            }
+        }
+    }
+}
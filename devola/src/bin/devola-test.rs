@@ -0,0 +1,210 @@
+use devola::cartridge::Cartridge;
+use devola::coverage;
+use devola::parser::text::compile_from;
+use devola::stdlib::interface::{self, DevolaExtern, DevolaExternTable};
+use devola::vm::{Devola, DevolaError};
+use std::path::{Path, PathBuf};
+use std::{env, fs, process};
+
+const USAGE: &str = "Usage: devola-test [directory | program.pop] [--cycles <limit>] [--lcov <file.info>] [--annotate <directory>]";
+
+/// How long a program may run before it's taken to be stuck
+const DEFAULT_CYCLE_LIMIT: u64 = 10_000_000;
+
+#[derive(Debug, PartialEq)]
+struct Options {
+    /// A directory of programs, or a single program
+    directory: String,
+    cycle_limit: u64,
+    /// Where an lcov report of every program's coverage is written
+    lcov: Option<String>,
+    /// Where each program is written out annotated with its coverage, mirroring where it was found
+    annotate: Option<String>
+}
+
+impl Options {
+    fn coverage(&self) -> bool {
+        self.lcov.is_some() || self.annotate.is_some()
+    }
+}
+
+fn parse_options(args: &[String]) -> Option<Options> {
+    let mut options = Options { directory: String::from("sample"), cycle_limit: DEFAULT_CYCLE_LIMIT, lcov: None, annotate: None };
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--cycles" => options.cycle_limit = args.next()?.parse().ok()?,
+            "--lcov" => options.lcov = Some(args.next()?.clone()),
+            "--annotate" => options.annotate = Some(args.next()?.clone()),
+            _ if arg.starts_with("--") => return None,
+            _ => options.directory = arg.clone()
+        }
+    }
+    Some(options)
+}
+
+/// The program given, or every source file under the directory given
+fn sources(path: &Path) -> Vec<PathBuf> {
+    if path.is_file() {
+        vec![path.to_path_buf()]
+    } else {
+        discover(path)
+    }
+}
+
+/// Every source file under a directory, in a stable order
+fn discover(directory: &Path) -> Vec<PathBuf> {
+    let mut sources = Vec::new();
+    let mut entries: Vec<PathBuf> = match fs::read_dir(directory) {
+        Ok(entries) => entries.filter_map(|entry| entry.ok()).map(|entry| entry.path()).collect(),
+        Err(_) => return sources
+    };
+    entries.sort();
+    for path in entries {
+        if path.is_dir() {
+            sources.extend(discover(&path));
+        } else if path.extension().is_some_and(|extension| extension == "pop") {
+            sources.push(path);
+        }
+    }
+    sources
+}
+
+/// The library functions programs can call
+fn externs() -> DevolaExternTable {
+    let mut externs = DevolaExternTable::new();
+    externs.insert(String::from("assert_eq"), Box::new(interface::i_assert_eq) as Box<DevolaExtern>);
+    externs
+}
+
+/// Reads and assembles a program, returning its source along with it. Calls are followed so that failed
+/// assertions can say how they were reached.
+fn load(path: &Path) -> Result<(String, Devola), String> {
+    let source = fs::read_to_string(path).map_err(|error| error.to_string())?;
+    let (code, symbols, lines, segments) = compile_from(source.clone(), Some(externs()), path)
+        .map_err(|errors| errors.iter().map(|error| error.to_string()).collect::<Vec<_>>().join("; "))?;
+    let cartridge = Cartridge { symbols: Some(symbols), lines: Some(lines), segments, ..Cartridge::new(code) };
+    let mut devola = cartridge.load().map_err(|error| error.to_string())?;
+    devola.set_externs(externs());
+    devola.enable_debug();
+    Ok((source, devola))
+}
+
+/// Runs a program, returning the cycles it took to reach the end of its code or exit with `hlt 0`, or why it didn't
+fn run(devola: &mut Devola, cycle_limit: u64) -> Result<u64, String> {
+    // a failed assert_eq explains itself better than the fault does
+    let cycles = devola.run_cycles(cycle_limit).map_err(|error| match (&error, devola.assertion_report()) {
+        (DevolaError::AssertionFailed(_), Some(report)) => report.to_string(),
+        _ => error.to_string()
+    })?;
+    match devola.exit_code() {
+        Some(0) => Ok(cycles),
+        Some(code) => Err(format!("exited with code {}", code)),
+        None if devola.pc() < devola.code().len() => Err(format!("still running after {} cycles", cycles)),
+        None => Ok(cycles)
+    }
+}
+
+fn write(path: &Path, contents: &str) {
+    let written = path.parent().map_or(Ok(()), fs::create_dir_all).and_then(|_| fs::write(path, contents));
+    if let Err(error) = written {
+        eprintln!("Failed to write {}: {}", path.display(), error);
+        process::exit(1);
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let options = match parse_options(&args) {
+        Some(options) => options,
+        None => {
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        }
+    };
+    let sources = sources(Path::new(&options.directory));
+    // a single program's own exit code is passed on, for scripts
+    let single = Path::new(&options.directory).is_file();
+    let root = if single { Path::new(&options.directory).parent().unwrap_or(Path::new("")) } else { Path::new(&options.directory) };
+    if sources.is_empty() {
+        eprintln!("No programs found in {}", options.directory);
+        process::exit(1);
+    }
+
+    let mut failed = 0;
+    let mut exit_code = None;
+    let mut lcov = String::new();
+    for path in &sources {
+        let (source, mut devola) = match load(path) {
+            Ok(loaded) => loaded,
+            Err(reason) => {
+                println!("FAIL  {}: {}", path.display(), reason);
+                failed += 1;
+                continue;
+            }
+        };
+        if options.coverage() {
+            devola.enable_coverage();
+        }
+        match run(&mut devola, options.cycle_limit) {
+            Ok(cycles) => println!("ok    {} ({} cycles)", path.display(), cycles),
+            Err(reason) => {
+                println!("FAIL  {}: {}", path.display(), reason);
+                failed += 1;
+            }
+        }
+        exit_code = devola.exit_code();
+        lcov.push_str(&coverage::lcov(&devola, &path.to_string_lossy()));
+        if let Some(directory) = &options.annotate {
+            let relative = path.strip_prefix(root).unwrap_or(path);
+            write(&Path::new(directory).join(relative).with_extension("pop.cov"), &coverage::annotate(&devola, &source));
+        }
+    }
+    if let Some(path) = &options.lcov {
+        write(Path::new(path), &lcov);
+    }
+    println!("\n{} passed, {} failed", sources.len() - failed, failed);
+    match exit_code {
+        Some(code) if single && code != 0 => process::exit(code as i32),
+        _ if failed > 0 => process::exit(1),
+        _ => ()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_runner() {
+        let args: Vec<String> = ["tests", "--cycles", "500", "--lcov", "tests.info"].iter().map(|arg| arg.to_string()).collect();
+        assert_eq!(parse_options(&args), Some(Options {
+            directory: String::from("tests"), cycle_limit: 500, lcov: Some(String::from("tests.info")), annotate: None
+        }));
+        assert_eq!(parse_options(&[String::from("--verbose")]), None);
+
+        let programs = sources(Path::new("sample"));
+        assert!(programs.contains(&PathBuf::from("sample/stdlib_tests/memgetn.pop")));
+        for path in &programs {
+            assert!(run(&mut load(path).unwrap().1, DEFAULT_CYCLE_LIMIT).is_ok(), "{}", path.display());
+        }
+
+        let directory = env::temp_dir().join(format!("devola_test_runner_{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let stuck = directory.join("stuck.pop");
+        fs::write(&stuck, "forever:\njmp forever").unwrap();
+        assert_eq!(run(&mut load(&stuck).unwrap().1, 100), Err(String::from("still running after 100 cycles")));
+        let faulty = directory.join("faulty.pop");
+        fs::write(&faulty, "ldx ffh\nldy ffh\nlda xy+16").unwrap();
+        assert_eq!(run(&mut load(&faulty).unwrap().1, 100), Err(String::from("invalid argument at line 3 (instruction 2)")));
+        let failing = directory.join("failing.pop");
+        fs::write(&failing, "hlt 2\nhlt 0").unwrap();
+        assert_eq!(run(&mut load(&failing).unwrap().1, 100), Err(String::from("exited with code 2")));
+        assert_eq!(sources(&failing), [failing]);
+        let asserting = directory.join("asserting.pop");
+        fs::write(&asserting, "lda 1\npush a\npush a\npush a\npush a\ncall assert_eq").unwrap();
+        assert!(run(&mut load(&asserting).unwrap().1, 100).is_ok());
+        fs::write(&asserting, "lda 1\npush a\nlda 2\npush a\nlda 0\npush a\npush a\ncall assert_eq").unwrap();
+        assert!(run(&mut load(&asserting).unwrap().1, 100).unwrap_err().starts_with("assertion failed at line 8"));
+    }
+}
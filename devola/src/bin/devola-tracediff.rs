@@ -0,0 +1,182 @@
+use devola::binary::{self, RomImage};
+use devola::cartridge::Cartridge;
+use devola::json;
+use devola::recorder::{self, TraceRecorder};
+use devola::stdlib::interface::DevolaExternTable;
+use devola::vm::Devola;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::Path;
+use std::{env, fs, process};
+
+const USAGE: &str = "Usage: devola-tracediff <a> <b> [--steps <limit>] | devola-tracediff <program> --record <trace.jsonl> [--steps <limit>]";
+
+/// How many instructions a program is run for, unless it ends or faults first
+const DEFAULT_STEP_LIMIT: u64 = 1_000_000;
+
+#[derive(Debug, PartialEq)]
+struct Options {
+    inputs: Vec<String>,
+    step_limit: u64,
+    /// Where the single input's trace is saved, instead of comparing two
+    record: Option<String>
+}
+
+fn parse_options(args: &[String]) -> Option<Options> {
+    let mut options = Options { inputs: Vec::new(), step_limit: DEFAULT_STEP_LIMIT, record: None };
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--steps" => options.step_limit = args.next()?.parse().ok()?,
+            "--record" => options.record = Some(args.next()?.clone()),
+            _ if arg.starts_with("--") => return None,
+            _ => options.inputs.push(arg.clone())
+        }
+    }
+    let inputs = if options.record.is_some() { 1 } else { 2 };
+    (options.inputs.len() == inputs).then_some(options)
+}
+
+/// Compiles a source file, or decodes a program built with `devola-asm`, going by the extension and reporting a
+/// file whose contents don't match it
+fn load(path: &str) -> Result<Devola, String> {
+    let bytes = fs::read(path).map_err(|error| error.to_string())?;
+    if path.ends_with(".popr") {
+        if !binary::is_encoded(&bytes) {
+            return Err(String::from("not an encoded program; source should be named .pop"));
+        }
+        let image = RomImage::decode(&bytes).map_err(|error| error.to_string())?;
+        return Cartridge::from(image).load().map_err(|error| error.to_string());
+    }
+    if binary::is_encoded(&bytes) {
+        return Err(String::from("an encoded program, not source; rename it to .popr"));
+    }
+    let source = String::from_utf8(bytes).map_err(|_| String::from("not source, as it isn't valid UTF-8"))?;
+    if path.ends_with(".json") {
+        return json::decode(&source).map(json::Program::load).map_err(|error| error.to_string());
+    }
+    let cartridge = Cartridge::compile_from(source, DevolaExternTable::new(), Path::new(path))
+        .map_err(|errors| errors.iter().map(|error| error.to_string()).collect::<Vec<_>>().join("\n"))?;
+    cartridge.load().map_err(|error| error.to_string())
+}
+
+/// A program's trace, recorded a step at a time as it's read, so that a long run never has to be held in memory
+struct LiveTrace {
+    recorder: TraceRecorder<Vec<u8>>,
+    /// How much of the recorder's buffer has been read
+    position: usize,
+    steps_left: u64,
+    ended: bool
+}
+
+impl Read for LiveTrace {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.recorder.writer_mut().len() && !self.ended {
+            self.recorder.writer_mut().clear();
+            self.position = 0;
+            // the trace ends with the fault, if there is one
+            self.ended = self.steps_left == 0 || self.recorder.step()?.is_err();
+            self.steps_left = self.steps_left.saturating_sub(1);
+        }
+        let recorded = &self.recorder.writer_mut()[self.position..];
+        let length = recorded.len().min(buffer.len());
+        buffer[..length].copy_from_slice(&recorded[..length]);
+        self.position += length;
+        Ok(length)
+    }
+}
+
+/// A trace to compare: one recorded earlier, in a `.jsonl` file, or a program to run
+fn open(path: &str, step_limit: u64) -> Result<Box<dyn BufRead>, String> {
+    if path.ends_with(".jsonl") {
+        let file = File::open(path).map_err(|error| error.to_string())?;
+        return Ok(Box::new(BufReader::new(file)));
+    }
+    let recorder = TraceRecorder::new(load(path)?, Vec::new());
+    Ok(Box::new(BufReader::new(LiveTrace { recorder, position: 0, steps_left: step_limit, ended: false })))
+}
+
+fn fail(message: String) -> ! {
+    eprintln!("{}", message);
+    process::exit(2);
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let options = parse_options(&args).unwrap_or_else(|| fail(USAGE.to_string()));
+    let traces: Vec<Box<dyn BufRead>> = options.inputs.iter()
+        .map(|path| open(path, options.step_limit).unwrap_or_else(|error| fail(format!("Failed to load {}: {}", path, error))))
+        .collect();
+    let mut traces = traces.into_iter();
+
+    if let Some(path) = options.record.as_ref() {
+        let written = File::create(path).and_then(|mut file| io::copy(&mut traces.next().unwrap(), &mut file));
+        if let Err(error) = written {
+            fail(format!("Failed to write {}: {}", path, error));
+        }
+        return;
+    }
+
+    let (left, right) = (traces.next().unwrap(), traces.next().unwrap());
+    match recorder::diff(left, right) {
+        Ok(None) => println!("The traces match"),
+        Ok(Some(divergence)) => {
+            println!("The traces diverge at step {}:", divergence.step);
+            for (path, step) in options.inputs.iter().zip([&divergence.left, &divergence.right]) {
+                match step {
+                    Some(step) => println!("  {}: {}", path, step),
+                    None => println!("  {}: ended", path)
+                }
+            }
+            for difference in divergence.differences {
+                println!("  {}", difference);
+            }
+            process::exit(1);
+        },
+        Err(error) => fail(format!("Failed to read the traces: {}", error))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_options() {
+        let args = |args: &[&str]| args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+        assert_eq!(parse_options(&args(&["old.jsonl", "game.popr", "--steps", "500"])), Some(Options {
+            inputs: vec![String::from("old.jsonl"), String::from("game.popr")], step_limit: 500, record: None
+        }));
+        assert_eq!(parse_options(&args(&["game.popr", "--record", "old.jsonl"])).and_then(|options| options.record), Some(String::from("old.jsonl")));
+        assert_eq!(parse_options(&args(&["game.popr"])), None);
+        assert_eq!(parse_options(&args(&["a.pop", "b.pop", "--fast"])), None);
+
+        // a live trace reads the same as one recorded in full
+        let (code, symbols, _, _) = devola::parser::text::compile(String::from("loop:\ninc\njmp loop"), None).unwrap();
+        let recorder = TraceRecorder::new(Devola::new(code.clone(), Some(symbols.clone())), Vec::new());
+        let mut live = String::new();
+        LiveTrace { recorder, position: 0, steps_left: 10, ended: false }.read_to_string(&mut live).unwrap();
+        let mut recorder = TraceRecorder::new(Devola::new(code, Some(symbols)), Vec::new());
+        for _ in 0..10 {
+            recorder.step().unwrap().unwrap();
+        }
+        assert_eq!(live, String::from_utf8(recorder.finish().unwrap().1).unwrap());
+    }
+
+    #[test]
+    fn test_load() {
+        let directory = env::temp_dir();
+        let source = directory.join(format!("devola_tracediff_test_{}.pop", std::process::id()));
+        let rom = source.with_extension("popr");
+        fs::write(&source, "inc").unwrap();
+        assert!(load(source.to_str().unwrap()).is_ok());
+
+        // files whose contents don't match their extension are reported rather than misread
+        fs::rename(&source, &rom).unwrap();
+        assert_eq!(load(rom.to_str().unwrap()).err(), Some(String::from("not an encoded program; source should be named .pop")));
+        fs::write(&source, RomImage::new(Vec::new()).encode().unwrap()).unwrap();
+        assert_eq!(load(source.to_str().unwrap()).err(), Some(String::from("an encoded program, not source; rename it to .popr")));
+        fs::remove_file(&source).unwrap();
+        fs::remove_file(&rom).unwrap();
+    }
+}
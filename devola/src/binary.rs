@@ -0,0 +1,547 @@
+//! A binary encoding of compiled code, so that programs can be distributed without their source.
+//!
+//! An encoded program starts with `POPR` and a version byte. In version 3, that's followed by the number of
+//! instructions as a little-endian `u32`, then each instruction as an opcode byte and its operands. Multi-byte
+//! operands are little-endian; code locations are `u32`s and strings are prefixed with their length as a `u16`.
+//! Index offsets are `i16`s.
+//!
+//! Version 4 is a ROM image, which puts a header between the version and the code: the title as a string, the
+//! entry point as a code location, then the number of data segments as a `u16` and each segment as its address
+//! and length as `u16`s followed by its bytes. After the code comes the CRC-32 of everything before it, as a
+//! `u32`.
+//!
+//! Versions 1 and 2 were the same, but with unsigned index offsets. Offsets from 8000h up would now be read as
+//! negative, so images in those versions aren't loaded and need to be assembled again.
+use crate::instructions::*;
+use crate::vm::MEMORY_SIZE;
+
+const MAGIC: &[u8; 4] = b"POPR";
+/// Just the code
+const VERSION: u8 = 3;
+/// A ROM image, with a header and a checksum
+const ROM_VERSION: u8 = 4;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DecodeError {
+    /// The data doesn't start with the `POPR` header
+    NotAProgram,
+    UnsupportedVersion(u8),
+    /// The data ended partway through an instruction
+    UnexpectedEnd,
+    /// An opcode or operand tag didn't match any instruction, or a string wasn't UTF-8, at the given byte offset
+    InvalidByte(usize),
+    /// The image's checksum doesn't match its contents, so it has been corrupted
+    ChecksumMismatch { stored: u32, computed: u32 },
+    /// The entry point is past the end of the code
+    EntryPointOutOfRange(usize),
+    /// The data segment at the given index runs past the end of memory
+    SegmentOutOfRange(usize)
+}
+
+impl std::fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DecodeError::NotAProgram => write!(f, "not an encoded program"),
+            DecodeError::UnsupportedVersion(version) => write!(f, "unsupported version {}", version),
+            DecodeError::UnexpectedEnd => write!(f, "unexpected end of data"),
+            DecodeError::InvalidByte(offset) => write!(f, "invalid byte at offset {}", offset),
+            DecodeError::ChecksumMismatch { stored, computed } => {
+                write!(f, "checksum mismatch (stored {:08X}, computed {:08X})", stored, computed)
+            },
+            DecodeError::EntryPointOutOfRange(entry_point) => write!(f, "entry point {} is past the end of the code", entry_point),
+            DecodeError::SegmentOutOfRange(index) => write!(f, "data segment {} runs past the end of memory", index)
+        }
+    }
+}
+
+/// Why a ROM image couldn't be encoded
+#[derive(Debug, Clone, PartialEq)]
+pub enum EncodeError {
+    /// There are more data segments than the header can count
+    TooManySegments(usize),
+    /// The data segment at the given index is longer than its length in the header can hold
+    SegmentTooLong(usize)
+}
+
+impl std::fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EncodeError::TooManySegments(count) => write!(f, "{} data segments are more than the {} a ROM image can hold", count, u16::MAX),
+            EncodeError::SegmentTooLong(index) => write!(f, "data segment {} is longer than {} bytes", index, u16::MAX)
+        }
+    }
+}
+
+const REGISTERS: [Register; 5] = [Register::Accumulator, Register::IndexX, Register::IndexY, Register::UtilityB, Register::UtilityC];
+const FLAGS: [Flag; 4] = [Flag::Carry, Flag::Zero, Flag::Parity, Flag::Sign];
+
+struct Encoder {
+    bytes: Vec<u8>
+}
+
+impl Encoder {
+    fn byte(&mut self, byte: u8) {
+        self.bytes.push(byte);
+    }
+
+    fn u16(&mut self, value: u16) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn location(&mut self, location: usize) {
+        self.bytes.extend_from_slice(&(location as u32).to_le_bytes());
+    }
+
+    fn string(&mut self, string: &str) {
+        self.u16(string.len() as u16);
+        self.bytes.extend_from_slice(string.as_bytes());
+    }
+
+    fn register(&mut self, register: Register) {
+        self.byte(REGISTERS.iter().position(|&r| r == register).unwrap() as u8);
+    }
+
+    fn addressing_mode(&mut self, addressing_mode: AddressingMode) {
+        match addressing_mode {
+            AddressingMode::Register(register) => {
+                self.byte(0);
+                self.register(register);
+            },
+            AddressingMode::Immediate(value) => {
+                self.byte(1);
+                self.byte(value);
+            },
+            AddressingMode::Indirect(address) => {
+                self.byte(2);
+                self.u16(address);
+            },
+            AddressingMode::Index => self.byte(3),
+            AddressingMode::IndexOffset(offset) => {
+                self.byte(4);
+                self.u16(offset as u16);
+            }
+        }
+    }
+
+    fn jump_type(&mut self, jump_type: JumpType) {
+        match jump_type {
+            JumpType::Unconditional => self.byte(0),
+            JumpType::Flag(flag, set) => {
+                self.byte(1);
+                self.byte(FLAGS.iter().position(|&f| f == flag).unwrap() as u8);
+                self.byte(set as u8);
+            },
+            JumpType::CompareNotEqual(addressing_mode, value) => {
+                self.byte(2);
+                self.addressing_mode(addressing_mode);
+                self.byte(value);
+            }
+        }
+    }
+
+    fn instruction(&mut self, instruction: &Instruction) {
+        match instruction {
+            Instruction::Load(register, addressing_mode) => {
+                self.byte(0x00);
+                self.register(*register);
+                self.addressing_mode(*addressing_mode);
+            },
+            Instruction::Store(register, addressing_mode) => {
+                self.byte(0x01);
+                self.register(*register);
+                self.addressing_mode(*addressing_mode);
+            },
+            Instruction::Increment => self.byte(0x02),
+            Instruction::Decrement => self.byte(0x03),
+            Instruction::Add(addressing_mode) => {
+                self.byte(0x04);
+                self.addressing_mode(*addressing_mode);
+            },
+            Instruction::Subtract(addressing_mode) => {
+                self.byte(0x05);
+                self.addressing_mode(*addressing_mode);
+            },
+            Instruction::Compare(addressing_mode) => {
+                self.byte(0x06);
+                self.addressing_mode(*addressing_mode);
+            },
+            Instruction::Jump(jump_type, location) => {
+                self.byte(0x07);
+                self.jump_type(*jump_type);
+                self.location(*location);
+            },
+            Instruction::Call(CallType::Local(location)) => {
+                self.byte(0x08);
+                self.location(*location);
+            },
+            Instruction::Call(CallType::Library(name)) => {
+                self.byte(0x09);
+                self.string(name);
+            },
+            Instruction::Return => self.byte(0x0A),
+            Instruction::ReturnInterrupt => self.byte(0x0B),
+            Instruction::Push(register) => {
+                self.byte(0x0C);
+                self.register(*register);
+            },
+            Instruction::Pop(register) => {
+                self.byte(0x0D);
+                self.register(*register);
+            },
+            Instruction::AddXY(addressing_mode) => {
+                self.byte(0x0E);
+                self.addressing_mode(*addressing_mode);
+            },
+            Instruction::SubtractXY(addressing_mode) => {
+                self.byte(0x0F);
+                self.addressing_mode(*addressing_mode);
+            },
+            Instruction::Nop => self.byte(0x10),
+            Instruction::Halt => self.byte(0x11),
+            Instruction::Exit(code) => {
+                self.byte(0x12);
+                self.byte(*code);
+            },
+            // pseudo-instructions are normally gone once labels are processed, but are kept for completeness
+            Instruction::_Label(label) => {
+                self.byte(0x80);
+                self.string(label);
+            },
+            Instruction::_Assert(addressing_mode, value) => {
+                self.byte(0x81);
+                self.addressing_mode(*addressing_mode);
+                self.byte(*value);
+            },
+            Instruction::_LabeledJump(jump_type, label) => {
+                self.byte(0x82);
+                self.jump_type(*jump_type);
+                self.string(label);
+            },
+            Instruction::_LabeledCall(label) => {
+                self.byte(0x83);
+                self.string(label);
+            }
+        }
+    }
+}
+
+struct Decoder<'a> {
+    bytes: &'a [u8],
+    offset: usize
+}
+
+impl Decoder<'_> {
+    fn take(&mut self, count: usize) -> Result<&[u8], DecodeError> {
+        let taken = self.bytes.get(self.offset..self.offset + count).ok_or(DecodeError::UnexpectedEnd)?;
+        self.offset += count;
+        Ok(taken)
+    }
+
+    fn byte(&mut self) -> Result<u8, DecodeError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, DecodeError> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, DecodeError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn location(&mut self) -> Result<usize, DecodeError> {
+        Ok(self.u32()? as usize)
+    }
+
+    fn string(&mut self) -> Result<String, DecodeError> {
+        let length = self.u16()? as usize;
+        let start = self.offset;
+        String::from_utf8(self.take(length)?.to_vec()).map_err(|_| DecodeError::InvalidByte(start))
+    }
+
+    /// Reads a byte that indexes into `values`
+    fn lookup<T: Copy>(&mut self, values: &[T]) -> Result<T, DecodeError> {
+        let offset = self.offset;
+        values.get(self.byte()? as usize).copied().ok_or(DecodeError::InvalidByte(offset))
+    }
+
+    fn register(&mut self) -> Result<Register, DecodeError> {
+        self.lookup(&REGISTERS)
+    }
+
+    fn addressing_mode(&mut self) -> Result<AddressingMode, DecodeError> {
+        let offset = self.offset;
+        Ok(match self.byte()? {
+            0 => AddressingMode::Register(self.register()?),
+            1 => AddressingMode::Immediate(self.byte()?),
+            2 => AddressingMode::Indirect(self.u16()?),
+            3 => AddressingMode::Index,
+            4 => AddressingMode::IndexOffset(self.u16()? as i16),
+            _ => return Err(DecodeError::InvalidByte(offset))
+        })
+    }
+
+    fn jump_type(&mut self) -> Result<JumpType, DecodeError> {
+        let offset = self.offset;
+        Ok(match self.byte()? {
+            0 => JumpType::Unconditional,
+            1 => JumpType::Flag(self.lookup(&FLAGS)?, self.byte()? != 0),
+            2 => JumpType::CompareNotEqual(self.addressing_mode()?, self.byte()?),
+            _ => return Err(DecodeError::InvalidByte(offset))
+        })
+    }
+
+    fn instruction(&mut self) -> Result<Instruction, DecodeError> {
+        let offset = self.offset;
+        Ok(match self.byte()? {
+            0x00 => Instruction::Load(self.register()?, self.addressing_mode()?),
+            0x01 => Instruction::Store(self.register()?, self.addressing_mode()?),
+            0x02 => Instruction::Increment,
+            0x03 => Instruction::Decrement,
+            0x04 => Instruction::Add(self.addressing_mode()?),
+            0x05 => Instruction::Subtract(self.addressing_mode()?),
+            0x06 => Instruction::Compare(self.addressing_mode()?),
+            0x07 => Instruction::Jump(self.jump_type()?, self.location()?),
+            0x08 => Instruction::Call(CallType::Local(self.location()?)),
+            0x09 => Instruction::Call(CallType::Library(self.string()?)),
+            0x0A => Instruction::Return,
+            0x0B => Instruction::ReturnInterrupt,
+            0x0C => Instruction::Push(self.register()?),
+            0x0D => Instruction::Pop(self.register()?),
+            0x0E => Instruction::AddXY(self.addressing_mode()?),
+            0x0F => Instruction::SubtractXY(self.addressing_mode()?),
+            0x10 => Instruction::Nop,
+            0x11 => Instruction::Halt,
+            0x12 => Instruction::Exit(self.byte()?),
+            0x80 => Instruction::_Label(self.string()?),
+            0x81 => Instruction::_Assert(self.addressing_mode()?, self.byte()?),
+            0x82 => Instruction::_LabeledJump(self.jump_type()?, self.string()?),
+            0x83 => Instruction::_LabeledCall(self.string()?),
+            _ => return Err(DecodeError::InvalidByte(offset))
+        })
+    }
+}
+
+impl Encoder {
+    fn code(&mut self, code: &[Instruction]) {
+        self.bytes.extend_from_slice(&(code.len() as u32).to_le_bytes());
+        for instruction in code {
+            self.instruction(instruction);
+        }
+    }
+}
+
+impl Decoder<'_> {
+    fn code(&mut self) -> Result<Vec<Instruction>, DecodeError> {
+        let count = self.u32()?;
+        (0..count).map(|_| self.instruction()).collect()
+    }
+
+    fn segment(&mut self) -> Result<DataSegment, DecodeError> {
+        let address = self.u16()?;
+        let length = self.u16()? as usize;
+        Ok(DataSegment { address, bytes: self.take(length)?.to_vec() })
+    }
+}
+
+/// CRC-32, as used by zip and PNG
+fn crc32(bytes: &[u8]) -> u32 {
+    !bytes.iter().fold(!0, |crc, &byte| {
+        (0..8).fold(crc ^ byte as u32, |crc, _| if crc & 1 == 1 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 })
+    })
+}
+
+/// Bytes copied into memory before the program starts
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DataSegment {
+    pub address: u16,
+    pub bytes: Vec<u8>
+}
+
+/// A program as it's distributed: its code, and a header describing how to start it
+#[derive(Clone, Debug)]
+pub struct RomImage {
+    pub title: String,
+    /// The instruction execution starts from
+    pub entry_point: usize,
+    pub segments: Vec<DataSegment>,
+    pub code: Vec<Instruction>
+}
+
+impl RomImage {
+    /// An untitled image that starts at the beginning of the code, with no data
+    pub fn new(code: Vec<Instruction>) -> RomImage {
+        RomImage { title: String::new(), entry_point: 0, segments: Vec::new(), code }
+    }
+
+    /// Encodes the image, unless its data segments don't fit in the header
+    pub fn encode(&self) -> Result<Vec<u8>, EncodeError> {
+        let mut encoder = Encoder { bytes: MAGIC.to_vec() };
+        encoder.byte(ROM_VERSION);
+        encoder.string(&self.title);
+        encoder.location(self.entry_point);
+        let segment_count = u16::try_from(self.segments.len()).map_err(|_| EncodeError::TooManySegments(self.segments.len()))?;
+        encoder.u16(segment_count);
+        for (index, segment) in self.segments.iter().enumerate() {
+            let length = u16::try_from(segment.bytes.len()).map_err(|_| EncodeError::SegmentTooLong(index))?;
+            encoder.u16(segment.address);
+            encoder.u16(length);
+            encoder.bytes.extend_from_slice(&segment.bytes);
+        }
+        encoder.code(&self.code);
+        let checksum = crc32(&encoder.bytes);
+        encoder.bytes.extend_from_slice(&checksum.to_le_bytes());
+        Ok(encoder.bytes)
+    }
+
+    /// Decodes and validates an image written by `encode`. Bare code written by `encode` is read as an untitled
+    /// image.
+    pub fn decode(bytes: &[u8]) -> Result<RomImage, DecodeError> {
+        if !bytes.starts_with(MAGIC) {
+            return Err(DecodeError::NotAProgram);
+        }
+        let mut decoder = Decoder { bytes, offset: MAGIC.len() };
+        let image = match decoder.byte()? {
+            VERSION => RomImage::new(decoder.code()?),
+            ROM_VERSION => {
+                // the checksum is checked first, so that corruption isn't reported as whatever it happened to break
+                let (contents, checksum) = bytes.split_at_checked(bytes.len().saturating_sub(4))
+                    .filter(|(contents, _)| contents.len() > MAGIC.len())
+                    .ok_or(DecodeError::UnexpectedEnd)?;
+                let (stored, computed) = (u32::from_le_bytes(checksum.try_into().unwrap()), crc32(contents));
+                if stored != computed {
+                    return Err(DecodeError::ChecksumMismatch { stored, computed });
+                }
+                decoder.bytes = contents;
+
+                let title = decoder.string()?;
+                let entry_point = decoder.location()?;
+                let segment_count = decoder.u16()?;
+                let segments = (0..segment_count).map(|_| decoder.segment()).collect::<Result<_, _>>()?;
+                RomImage { title, entry_point, segments, code: decoder.code()? }
+            },
+            version => return Err(DecodeError::UnsupportedVersion(version))
+        };
+        if decoder.offset != decoder.bytes.len() {
+            return Err(DecodeError::InvalidByte(decoder.offset));
+        }
+
+        if image.entry_point >= image.code.len().max(1) {
+            return Err(DecodeError::EntryPointOutOfRange(image.entry_point));
+        }
+        if let Some(index) = image.segments.iter().position(|segment| segment.address as usize + segment.bytes.len() > MEMORY_SIZE) {
+            return Err(DecodeError::SegmentOutOfRange(index));
+        }
+        Ok(image)
+    }
+}
+
+/// Encodes compiled code on its own, without a header
+pub fn encode(code: &[Instruction]) -> Vec<u8> {
+    let mut encoder = Encoder { bytes: MAGIC.to_vec() };
+    encoder.byte(VERSION);
+    encoder.code(code);
+    encoder.bytes
+}
+
+/// Whether `bytes` start the way everything written by `encode` does, to tell encoded programs from source
+/// whatever their files are called
+pub fn is_encoded(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+/// Decodes the code written by `encode`, or in a ROM image
+pub fn decode(bytes: &[u8]) -> Result<Vec<Instruction>, DecodeError> {
+    RomImage::decode(bytes).map(|image| image.code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::Cartridge;
+    use crate::parser;
+
+    #[test]
+    fn test_round_trip() {
+        let code = vec![
+            Instruction::Load(Register::IndexY, AddressingMode::IndexOffset(-0x1234)),
+            Instruction::Store(Register::UtilityC, AddressingMode::Indirect(0xFFF0)),
+            Instruction::Jump(JumpType::Flag(Flag::Sign, false), 70000),
+            Instruction::Jump(JumpType::CompareNotEqual(AddressingMode::IndexOffset(-1), 3), 0),
+            Instruction::Call(CallType::Library(String::from("memset"))),
+            Instruction::Compare(AddressingMode::Register(Register::UtilityB)),
+            Instruction::_Assert(AddressingMode::Index, 3),
+            Instruction::_LabeledJump(JumpType::Unconditional, String::from("loop"))
+        ];
+        // instructions don't implement PartialEq, so they're compared by their debug output
+        let decoded = decode(&encode(&code)).unwrap();
+        assert_eq!(format!("{:?}", decoded), format!("{:?}", code));
+
+        let source = crate::util::read_from_file(std::path::Path::new("sample/square_subroutines.pop"));
+        let (code, _, _, _) = parser::text::compile(source, None).unwrap();
+        assert_eq!(format!("{:?}", decode(&encode(&code)).unwrap()), format!("{:?}", code));
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        let bytes = encode(&[Instruction::Push(Register::Accumulator)]);
+        assert_eq!(decode(b"PRPL").unwrap_err(), DecodeError::NotAProgram);
+        assert_eq!(decode(&bytes[..bytes.len() - 1]).unwrap_err(), DecodeError::UnexpectedEnd);
+
+        let mut invalid = bytes.clone();
+        *invalid.last_mut().unwrap() = 9;
+        assert_eq!(decode(&invalid).unwrap_err(), DecodeError::InvalidByte(bytes.len() - 1));
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        assert_eq!(decode(&trailing).unwrap_err(), DecodeError::InvalidByte(bytes.len()));
+    }
+
+    #[test]
+    fn test_rom_image() {
+        assert_eq!(crc32(b"123456789"), 0xCBF43926);
+
+        let image = RomImage {
+            title: String::from("Square"),
+            entry_point: 1,
+            segments: vec![DataSegment { address: 0x0200, bytes: vec![1, 2, 3] }, DataSegment { address: 0xFFFF, bytes: vec![4] }],
+            code: vec![Instruction::Increment, Instruction::Load(Register::Accumulator, AddressingMode::Indirect(0x0201))]
+        };
+        let bytes = image.encode().unwrap();
+        let decoded = RomImage::decode(&bytes).unwrap();
+        assert_eq!((decoded.title.as_str(), decoded.entry_point), ("Square", 1));
+        assert_eq!(decoded.segments, image.segments);
+        assert_eq!(format!("{:?}", decode(&bytes).unwrap()), format!("{:?}", image.code));
+        let mut devola = Cartridge::from(decoded).load().unwrap();
+        devola.run().unwrap();
+        // the increment before the entry point is skipped
+        assert_eq!((devola.register(Register::Accumulator), devola.peek(0xFFFF)), (2, 4));
+
+        let mut corrupt = bytes.clone();
+        corrupt[10] ^= 1;
+        assert!(matches!(RomImage::decode(&corrupt), Err(DecodeError::ChecksumMismatch { .. })));
+        assert_eq!(RomImage::decode(&bytes[..6]).unwrap_err(), DecodeError::UnexpectedEnd);
+        let mut newer = bytes.clone();
+        newer[4] = 5;
+        assert_eq!(RomImage::decode(&newer).unwrap_err(), DecodeError::UnsupportedVersion(5));
+        // images from before index offsets were signed can't be read as they were meant
+        for version in [1, 2] {
+            let mut older = bytes.clone();
+            older[4] = version;
+            assert_eq!(RomImage::decode(&older).unwrap_err(), DecodeError::UnsupportedVersion(version));
+        }
+
+        let past_end = RomImage { entry_point: 2, ..image.clone() };
+        assert_eq!(RomImage::decode(&past_end.encode().unwrap()).unwrap_err(), DecodeError::EntryPointOutOfRange(2));
+        let overflowing = RomImage { segments: vec![DataSegment { address: 0xFFFF, bytes: vec![4, 5] }], ..image };
+        assert_eq!(RomImage::decode(&overflowing.encode().unwrap()).unwrap_err(), DecodeError::SegmentOutOfRange(0));
+        // the longest segment the header can hold makes it through, and a longer one isn't cut short
+        let longest = RomImage { segments: vec![DataSegment { address: 0, bytes: vec![7; u16::MAX as usize] }], ..overflowing.clone() };
+        assert_eq!(RomImage::decode(&longest.encode().unwrap()).unwrap().segments, longest.segments);
+        let too_long = RomImage { segments: vec![DataSegment { address: 0, bytes: vec![7; MEMORY_SIZE] }], ..overflowing };
+        assert_eq!(too_long.encode().unwrap_err(), EncodeError::SegmentTooLong(0));
+        // code without a header can still be loaded
+        let bare = RomImage::decode(&encode(&[Instruction::Nop])).unwrap();
+        assert_eq!((bare.title.as_str(), bare.entry_point, bare.segments.len()), ("", 0, 0));
+    }
+}
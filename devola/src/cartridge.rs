@@ -0,0 +1,197 @@
+//! Everything a program needs to start: its code, the data it expects in memory, and what it asks of the machine.
+//! A cartridge is checked as a whole before it's loaded, so a VM is never left half set up.
+use crate::binary::{DataSegment, RomImage};
+use crate::instructions::Instruction;
+use crate::parser::intermediate::{LineTable, SymbolTable};
+use crate::parser::text::{compile, compile_from, ParseError};
+use crate::stdlib::interface::DevolaExternTable;
+use crate::vm::{Devola, MEMORY_SIZE};
+use std::path::Path;
+
+/// The memory banks the VM has to offer, each one all of its address space
+pub const AVAILABLE_BANKS: u8 = 1;
+
+/// A region of memory for the embedder to keep between runs, like a battery-backed cartridge. Only its bounds are
+/// checked when loading; nothing is saved or restored for it
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SaveRam {
+    pub address: u16,
+    pub size: u16
+}
+
+impl SaveRam {
+    /// Copies the region out of memory, to be saved. A region that hasn't been validated with its cartridge wraps
+    /// around the end of memory
+    pub fn read(&self, devola: &Devola) -> Vec<u8> {
+        (0..self.size).map(|offset| devola.peek(self.address.wrapping_add(offset))).collect()
+    }
+
+    /// Restores saved bytes into the region, ignoring any past its end
+    pub fn write(&self, devola: &mut Devola, bytes: &[u8]) {
+        for (offset, &byte) in bytes.iter().take(self.size as usize).enumerate() {
+            devola.poke(self.address.wrapping_add(offset as u16), byte);
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum CartridgeError {
+    /// The entry point is past the end of the code
+    EntryPointOutOfRange(usize),
+    /// The data segment at the given index runs past the end of memory
+    SegmentOutOfRange(usize),
+    /// The data segments at the given indices would be written over each other
+    SegmentsOverlap(usize, usize),
+    /// The save RAM runs past the end of memory
+    SaveRamOutOfRange,
+    /// The program needs more memory banks than the VM has
+    TooManyBanks(u8)
+}
+
+impl std::fmt::Display for CartridgeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CartridgeError::EntryPointOutOfRange(entry_point) => write!(f, "entry point {} is past the end of the code", entry_point),
+            CartridgeError::SegmentOutOfRange(index) => write!(f, "data segment {} runs past the end of memory", index),
+            CartridgeError::SegmentsOverlap(first, second) => write!(f, "data segments {} and {} overlap", first, second),
+            CartridgeError::SaveRamOutOfRange => write!(f, "save RAM runs past the end of memory"),
+            CartridgeError::TooManyBanks(banks) => {
+                write!(f, "needs {} memory banks, but only {} are available", banks, AVAILABLE_BANKS)
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Cartridge {
+    pub title: String,
+    pub code: Vec<Instruction>,
+    pub symbols: Option<SymbolTable>,
+    pub lines: Option<LineTable>,
+    /// The instruction execution starts from
+    pub entry_point: usize,
+    /// Bytes copied into memory before the program starts
+    pub segments: Vec<DataSegment>,
+    /// The memory banks the program needs
+    pub banks: u8,
+    /// Not kept in ROM images, so it's lost when a cartridge is written out as one
+    pub save_ram: Option<SaveRam>
+}
+
+impl Cartridge {
+    /// An untitled cartridge that starts at the beginning of the code, with no data
+    pub fn new(code: Vec<Instruction>) -> Cartridge {
+        Cartridge {
+            title: String::new(),
+            code,
+            symbols: None,
+            lines: None,
+            entry_point: 0,
+            segments: Vec::new(),
+            banks: 1,
+            save_ram: None
+        }
+    }
+
+    /// Assembles a program, keeping its labels and lines for reporting faults
+    pub fn compile(source: String) -> Result<Cartridge, Vec<ParseError>> {
+        Cartridge::compile_with_externs(source, DevolaExternTable::new())
+    }
+
+    /// Assembles a program that calls library functions, which the VM it's loaded into then has to be given
+    pub fn compile_with_externs(source: String, externs: DevolaExternTable) -> Result<Cartridge, Vec<ParseError>> {
+        let (code, symbols, lines, segments) = compile(source, Some(externs))?;
+        Ok(Cartridge { symbols: Some(symbols), lines: Some(lines), segments, ..Cartridge::new(code) })
+    }
+
+    /// Assembles a program read from the file at `path`, which the files it includes are found relative to
+    pub fn compile_from(source: String, externs: DevolaExternTable, path: &Path) -> Result<Cartridge, Vec<ParseError>> {
+        let (code, symbols, lines, segments) = compile_from(source, Some(externs), path)?;
+        Ok(Cartridge { symbols: Some(symbols), lines: Some(lines), segments, ..Cartridge::new(code) })
+    }
+
+    /// Checks that the cartridge can be loaded, without loading it
+    pub fn validate(&self) -> Result<(), CartridgeError> {
+        if self.banks > AVAILABLE_BANKS {
+            return Err(CartridgeError::TooManyBanks(self.banks));
+        }
+        if self.entry_point >= self.code.len().max(1) {
+            return Err(CartridgeError::EntryPointOutOfRange(self.entry_point));
+        }
+        let ranges: Vec<_> = self.segments.iter()
+            .map(|segment| segment.address as usize..segment.address as usize + segment.bytes.len())
+            .collect();
+        if let Some(index) = ranges.iter().position(|range| range.end > MEMORY_SIZE) {
+            return Err(CartridgeError::SegmentOutOfRange(index));
+        }
+        for (second, range) in ranges.iter().enumerate() {
+            if let Some(first) = ranges[..second].iter().position(|other| other.start < range.end && range.start < other.end) {
+                return Err(CartridgeError::SegmentsOverlap(first, second));
+            }
+        }
+        if self.save_ram.is_some_and(|save_ram| save_ram.address as usize + save_ram.size as usize > MEMORY_SIZE) {
+            return Err(CartridgeError::SaveRamOutOfRange);
+        }
+        Ok(())
+    }
+
+    /// Creates a VM with the data segments in memory, about to execute the entry point
+    pub fn load(self) -> Result<Devola, CartridgeError> {
+        self.validate()?;
+        let mut devola = Devola::with_data(self.code, self.symbols, &self.segments);
+        if let Some(lines) = self.lines {
+            devola.set_line_table(lines);
+        }
+        devola.set_pc(self.entry_point);
+        Ok(devola)
+    }
+}
+
+impl From<RomImage> for Cartridge {
+    fn from(image: RomImage) -> Self {
+        Cartridge { title: image.title, entry_point: image.entry_point, segments: image.segments, ..Cartridge::new(image.code) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instructions::Register;
+
+    #[test]
+    fn test_cartridge() {
+        let mut cartridge = Cartridge::compile(String::from("inc\nlda #0201h\nsta #0300h")).unwrap();
+        cartridge.entry_point = 1;
+        cartridge.segments = vec![DataSegment { address: 0x0200, bytes: vec![1, 2] }, DataSegment { address: 0x0202, bytes: vec![3] }];
+        cartridge.save_ram = Some(SaveRam { address: 0x0300, size: 2 });
+        let mut devola = cartridge.clone().load().unwrap();
+        assert_eq!(devola.line(devola.pc()), Some(1));
+        devola.run().unwrap();
+        // the increment before the entry point is skipped
+        assert_eq!(devola.register(Register::Accumulator), 2);
+        let save_ram = cartridge.save_ram.unwrap();
+        assert_eq!(save_ram.read(&devola), vec![2, 0]);
+        save_ram.write(&mut devola, &[4, 5, 6]);
+        assert_eq!((devola.peek(0x0301), devola.peek(0x0302)), (5, 0));
+
+        let overlapping = vec![DataSegment { address: 0x0200, bytes: vec![0] }, DataSegment { address: 0x01FF, bytes: vec![0; 2] }];
+        let invalid = [
+            (Cartridge { banks: 2, ..cartridge.clone() }, CartridgeError::TooManyBanks(2)),
+            (Cartridge { entry_point: 3, ..cartridge.clone() }, CartridgeError::EntryPointOutOfRange(3)),
+            (Cartridge { segments: overlapping, ..cartridge.clone() }, CartridgeError::SegmentsOverlap(0, 1)),
+            (Cartridge { segments: vec![DataSegment { address: 0xFFFF, bytes: vec![0; 2] }], ..cartridge.clone() }, CartridgeError::SegmentOutOfRange(0)),
+            (Cartridge { save_ram: Some(SaveRam { address: 0xFFFF, size: 2 }), ..cartridge.clone() }, CartridgeError::SaveRamOutOfRange)
+        ];
+        for (cartridge, error) in invalid {
+            assert_eq!(cartridge.validate(), Err(error));
+        }
+        // a region built by hand can still run past the end of memory, and wraps around
+        let save_ram = SaveRam { address: 0xFFFF, size: 2 };
+        save_ram.write(&mut devola, &[7, 8]);
+        assert_eq!(save_ram.read(&devola), vec![7, 8]);
+
+        let image = RomImage { title: String::from("Square"), ..RomImage::new(vec![Instruction::Nop]) };
+        let cartridge = Cartridge::from(image);
+        assert_eq!((cartridge.title.as_str(), cartridge.banks, cartridge.save_ram), ("Square", 1, None));
+    }
+}
@@ -0,0 +1,366 @@
+//! Generates programs that check the results and flags of every ALU instruction in every addressing mode,
+//! against a model of the documented semantics written separately from the VM.
+//!
+//! Each program runs a series of cases. A case puts its operand in place, sets the flags to a known state,
+//! runs the instruction, then checks each flag with a conditional jump and the result with `cmp`. A failing
+//! case jumps to a block that writes its number, counting from 1, to `FAILED_CASE`; a program that gets through
+//! every case writes 1 to `FINISHED`.
+use crate::parser::text::compile;
+use crate::vm::Devola;
+
+/// Where a program writes the number of the case that failed
+pub const FAILED_CASE: u16 = 0x0400;
+/// Where a program writes 1 once every case has passed
+pub const FINISHED: u16 = 0x0401;
+
+/// Where operands are stored for indirect and indexed addressing, and the offset used with `xy+`
+const OPERAND_ADDRESS: u16 = 0x0300;
+const INDEX_OFFSET: u16 = 16;
+
+const BYTES: [u8; 5] = [0x00, 0x01, 0x7F, 0x80, 0xFF];
+/// Values of `XY`, which leave room for `INDEX_OFFSET` before the end of memory
+const WORDS: [u16; 5] = [0x0000, 0x0001, 0x00FF, 0x7FFF, 0xFFEF];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Flags {
+    carry: bool,
+    zero: bool,
+    parity: bool,
+    sign: bool
+}
+
+/// Two ways to set the flags before an instruction, which between them set and clear each one, so that flags
+/// an instruction shouldn't touch are checked both ways
+const FLAG_SETUPS: [(u8, u8, Flags); 2] = [
+    (0, 1, Flags { carry: true, zero: false, parity: false, sign: true }),
+    (128, 0, Flags { carry: false, zero: false, parity: true, sign: false })
+];
+
+impl Flags {
+    /// The zero, parity and sign flags of an 8-bit result, with the carry flag left as it was
+    fn of(result: u8, before: Flags) -> Flags {
+        Flags { carry: before.carry, zero: result == 0, parity: result % 2 == 1, sign: result & 0x80 != 0 }
+    }
+
+    fn checks(&self) -> [(char, bool); 4] {
+        [('c', self.carry), ('z', self.zero), ('p', self.parity), ('s', self.sign)]
+    }
+}
+
+/// The flags that are set, e.g. `CZ`
+impl std::fmt::Display for Flags {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let set: String = self.checks().iter().filter(|(_, set)| *set).map(|(flag, _)| flag.to_ascii_uppercase()).collect();
+        write!(f, "{}", if set.is_empty() { "none" } else { &set })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Mode {
+    Register,
+    Immediate,
+    Indirect,
+    Index,
+    IndexOffset
+}
+
+const MODES: [Mode; 5] = [Mode::Register, Mode::Immediate, Mode::Indirect, Mode::Index, Mode::IndexOffset];
+
+impl Mode {
+    fn name(&self) -> &'static str {
+        match self {
+            Mode::Register => "register",
+            Mode::Immediate => "immediate",
+            Mode::Indirect => "indirect",
+            Mode::Index => "index",
+            Mode::IndexOffset => "index_offset"
+        }
+    }
+
+    /// How the operand is written for the instruction
+    fn source(&self, operand: u8) -> String {
+        match self {
+            Mode::Register => String::from("b"),
+            Mode::Immediate => operand.to_string(),
+            Mode::Indirect => format!("#{:04X}h", OPERAND_ADDRESS),
+            Mode::Index => String::from("xy"),
+            Mode::IndexOffset => format!("xy+{}", INDEX_OFFSET)
+        }
+    }
+
+    /// The address the operand is stored at, for modes that read it from memory
+    fn address(&self, index: u16) -> Option<u16> {
+        match self {
+            Mode::Register | Mode::Immediate => None,
+            Mode::Indirect => Some(OPERAND_ADDRESS),
+            Mode::Index => Some(index),
+            Mode::IndexOffset => Some(index + INDEX_OFFSET)
+        }
+    }
+
+    /// Puts the operand where the instruction will find it, and sets `XY`. Doesn't touch the flags.
+    fn setup(&self, operand: u8, index: u16) -> Vec<String> {
+        let mut lines = vec![format!("ldx {}", index >> 8), format!("ldy {}", index & 0xFF)];
+        match self.address(index) {
+            Some(address) => lines.extend([format!("lda {}", operand), format!("sta #{:04X}h", address)]),
+            None => lines.push(format!("ldb {}", operand))
+        }
+        lines
+    }
+}
+
+/// What a case leaves behind to be checked
+enum Expected {
+    Accumulator(u8),
+    Index(u16),
+    Memory(u16, u8)
+}
+
+struct Case {
+    description: String,
+    setup: Vec<String>,
+    instruction: String,
+    flags: Flags,
+    expected: Expected
+}
+
+/// One of the generated programs, and what each of its cases tests
+pub struct TestProgram {
+    pub name: String,
+    pub source: String,
+    pub cases: Vec<String>
+}
+
+impl TestProgram {
+    fn new(name: String, cases: Vec<Case>) -> TestProgram {
+        let mut lines = Vec::new();
+        for (number, case) in cases.iter().enumerate() {
+            let fail = format!("fail{}", number + 1);
+            lines.push(format!("; {}", case.description));
+            lines.extend(case.setup.iter().cloned());
+            lines.push(case.instruction.clone());
+            for (flag, set) in case.flags.checks() {
+                // jump away if the flag isn't what it should be
+                lines.push(format!("j{}{} {}", if set { "n" } else { "" }, flag, fail));
+            }
+            match case.expected {
+                Expected::Accumulator(value) => lines.extend([format!("cmp {}", value), format!("jnz {}", fail)]),
+                Expected::Index(value) => lines.extend([
+                    String::from("lda x"), format!("cmp {}", value >> 8), format!("jnz {}", fail),
+                    String::from("lda y"), format!("cmp {}", value & 0xFF), format!("jnz {}", fail)
+                ]),
+                Expected::Memory(address, value) => lines.extend([
+                    format!("lda #{:04X}h", address), format!("cmp {}", value), format!("jnz {}", fail)
+                ])
+            }
+        }
+        lines.extend([String::from("lda 1"), format!("sta #{:04X}h", FINISHED), String::from("jmp end")]);
+        for number in 1..=cases.len() {
+            lines.extend([
+                format!("fail{}:", number), format!("lda {}", number), format!("sta #{:04X}h", FAILED_CASE),
+                String::from("jmp end")
+            ]);
+        }
+        lines.push(String::from("end:"));
+
+        let source = lines.iter().map(|line| {
+            if line.ends_with(':') { format!("{}\n", line) } else { format!("    {}\n", line) }
+        }).collect();
+        TestProgram { name, source, cases: cases.into_iter().map(|case| case.description).collect() }
+    }
+
+    /// Assembles and runs the program, returning what went wrong if it didn't pass every case
+    pub fn run(&self) -> Result<(), String> {
+        let (code, symbols, _, _) = compile(self.source.clone(), None)
+            .map_err(|errors| format!("{}: {}", self.name, errors[0]))?;
+        let mut devola = Devola::new(code, Some(symbols));
+        devola.run().map_err(|error| format!("{}: {}", self.name, error))?;
+        match devola.peek(FAILED_CASE) {
+            0 if devola.peek(FINISHED) == 1 => Ok(()),
+            0 => Err(format!("{}: didn't finish", self.name)),
+            number => Err(format!("{}: {}", self.name, self.cases[number as usize - 1]))
+        }
+    }
+}
+
+/// Sets the flags to one of `FLAG_SETUPS`, returning what they are
+fn set_flags(setup: &mut Vec<String>, number: usize) -> Flags {
+    let (accumulator, operand, flags) = FLAG_SETUPS[number % FLAG_SETUPS.len()];
+    setup.extend([format!("lda {}", accumulator), format!("cmp {}", operand)]);
+    flags
+}
+
+/// What an instruction does to the accumulator and flags, given the accumulator, the operand and the flags before
+type AccumulatorModel = fn(u8, u8, Flags) -> (u8, Flags);
+/// What an instruction does to `XY` and the flags, given `XY`, the operand and the flags before
+type IndexModel = fn(u16, u8, Flags) -> (u16, Flags);
+
+/// Cases for an instruction taking the accumulator and an operand, modelled by `model`
+fn accumulator_cases(mnemonic: &str, mode: Mode, model: AccumulatorModel) -> Vec<Case> {
+    let mut cases = Vec::new();
+    for accumulator in BYTES {
+        for operand in BYTES {
+            let mut setup = mode.setup(operand, OPERAND_ADDRESS);
+            let before = set_flags(&mut setup, cases.len());
+            setup.push(format!("lda {}", accumulator));
+            let (result, flags) = model(accumulator, operand, before);
+            cases.push(Case {
+                description: format!("{} {} with a = {}, flags {}", mnemonic, operand, accumulator, before),
+                setup,
+                instruction: format!("{} {}", mnemonic, mode.source(operand)),
+                flags,
+                expected: Expected::Accumulator(result)
+            });
+        }
+    }
+    cases
+}
+
+/// Cases for `inc` and `dec`
+fn unary_cases(mnemonic: &str, model: fn(u8, Flags) -> (u8, Flags)) -> Vec<Case> {
+    let mut cases = Vec::new();
+    for accumulator in BYTES {
+        let mut setup = Vec::new();
+        let before = set_flags(&mut setup, cases.len());
+        setup.push(format!("lda {}", accumulator));
+        let (result, flags) = model(accumulator, before);
+        cases.push(Case {
+            description: format!("{} with a = {}, flags {}", mnemonic, accumulator, before),
+            setup,
+            instruction: String::from(mnemonic),
+            flags,
+            expected: Expected::Accumulator(result)
+        });
+    }
+    cases
+}
+
+/// Cases for `adxy` and `sbxy`, modelled by `model`
+fn index_cases(mnemonic: &str, mode: Mode, model: IndexModel) -> Vec<Case> {
+    let mut cases = Vec::new();
+    for index in WORDS {
+        for operand in BYTES {
+            let mut setup = mode.setup(operand, index);
+            let before = set_flags(&mut setup, cases.len());
+            let (result, flags) = model(index, operand, before);
+            cases.push(Case {
+                description: format!("{} {} with xy = {}, flags {}", mnemonic, operand, index, before),
+                setup,
+                instruction: format!("{} {}", mnemonic, mode.source(operand)),
+                flags,
+                expected: Expected::Index(result)
+            });
+        }
+    }
+    cases
+}
+
+fn store_cases(mode: Mode) -> Vec<Case> {
+    let mut cases = Vec::new();
+    for value in BYTES {
+        let mut setup = mode.setup(!value, OPERAND_ADDRESS);
+        let before = set_flags(&mut setup, cases.len());
+        setup.push(format!("ldb {}", value));
+        cases.push(Case {
+            description: format!("stb {} with flags {}", value, before),
+            setup,
+            instruction: format!("stb {}", mode.source(value)),
+            flags: before,
+            expected: Expected::Memory(mode.address(OPERAND_ADDRESS).unwrap(), value)
+        });
+    }
+    cases
+}
+
+fn add(accumulator: u8, operand: u8, before: Flags) -> (u8, Flags) {
+    let (result, carry) = accumulator.overflowing_add(operand);
+    (result, Flags { carry, ..Flags::of(result, before) })
+}
+
+fn subtract(accumulator: u8, operand: u8, before: Flags) -> (u8, Flags) {
+    let (result, carry) = accumulator.overflowing_sub(operand);
+    (result, Flags { carry, ..Flags::of(result, before) })
+}
+
+fn compare(accumulator: u8, operand: u8, _: Flags) -> (u8, Flags) {
+    (accumulator, Flags {
+        carry: accumulator < operand,
+        zero: accumulator == operand,
+        parity: accumulator % 2 == operand % 2,
+        sign: accumulator & 0x80 == operand & 0x80
+    })
+}
+
+fn load(_: u8, operand: u8, before: Flags) -> (u8, Flags) {
+    (operand, before)
+}
+
+// the zero flag is set by wrapping around, rather than by the result being zero
+fn increment(accumulator: u8, before: Flags) -> (u8, Flags) {
+    let result = accumulator.wrapping_add(1);
+    (result, Flags { zero: accumulator == 0xFF, ..Flags::of(result, before) })
+}
+
+fn decrement(accumulator: u8, before: Flags) -> (u8, Flags) {
+    let result = accumulator.wrapping_sub(1);
+    (result, Flags { zero: accumulator == 0x00, ..Flags::of(result, before) })
+}
+
+// 16-bit arithmetic leaves the sign flag alone
+fn add_index(index: u16, operand: u8, before: Flags) -> (u16, Flags) {
+    let (result, carry) = index.overflowing_add(operand as u16);
+    (result, Flags { carry, zero: result == 0, parity: result % 2 == 1, sign: before.sign })
+}
+
+fn subtract_index(index: u16, operand: u8, before: Flags) -> (u16, Flags) {
+    let (result, carry) = index.overflowing_sub(operand as u16);
+    (result, Flags { carry, zero: result == 0, parity: result % 2 == 1, sign: before.sign })
+}
+
+/// A program for each instruction that touches the flags, or could disturb them, in each of its addressing modes
+pub fn generate() -> Vec<TestProgram> {
+    let mut programs = vec![
+        TestProgram::new(String::from("inc"), unary_cases("inc", increment)),
+        TestProgram::new(String::from("dec"), unary_cases("dec", decrement))
+    ];
+    let accumulator_instructions: [(&str, AccumulatorModel); 4] =
+        [("lda", load), ("add", add), ("sub", subtract), ("cmp", compare)];
+    let index_instructions: [(&str, IndexModel); 2] =
+        [("adxy", add_index), ("sbxy", subtract_index)];
+    for mode in MODES {
+        for (mnemonic, model) in accumulator_instructions {
+            programs.push(TestProgram::new(format!("{}_{}", mnemonic, mode.name()), accumulator_cases(mnemonic, mode, model)));
+        }
+        for (mnemonic, model) in index_instructions {
+            programs.push(TestProgram::new(format!("{}_{}", mnemonic, mode.name()), index_cases(mnemonic, mode, model)));
+        }
+        if mode.address(OPERAND_ADDRESS).is_some() {
+            programs.push(TestProgram::new(format!("stb_{}", mode.name()), store_cases(mode)));
+        }
+    }
+    programs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_conformance() {
+        let programs = generate();
+        assert_eq!(programs.len(), 2 + 5 * 6 + 3);
+        for program in &programs {
+            assert_eq!(program.run(), Ok(()));
+        }
+
+        // a program that expects different semantics fails at the right case
+        let wrong = TestProgram::new(String::from("inc"), unary_cases("inc", |accumulator, before| {
+            let (result, flags) = increment(accumulator, before);
+            (result, Flags { carry: accumulator == 0xFF, ..flags })
+        }));
+        assert_eq!(wrong.run(), Err(format!("inc: {}", wrong.cases[0])));
+        let mut unfinished = TestProgram::new(String::from("dec"), unary_cases("dec", decrement));
+        unfinished.source = unfinished.source.replace("    lda 1\n    sta #0401h\n", "");
+        assert_eq!(unfinished.run(), Err(String::from("dec: didn't finish")));
+    }
+}
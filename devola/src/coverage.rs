@@ -0,0 +1,145 @@
+//! Which instructions a run executed, and reports of it against the source: in lcov's tracefile format for
+//! coverage tools, or as the source annotated with how often each line ran.
+use crate::instructions::{Instruction, JumpType};
+use crate::vm::Devola;
+use std::collections::BTreeMap;
+
+/// How many times each instruction was executed, and how many of those times it jumped
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Coverage {
+    hits: Vec<u64>,
+    jumps: Vec<u64>
+}
+
+impl Coverage {
+    pub fn new(length: usize) -> Coverage {
+        Coverage { hits: vec![0; length], jumps: vec![0; length] }
+    }
+
+    /// Counts an execution of the instruction at `pc`
+    pub(crate) fn record(&mut self, pc: usize, jumped: bool) {
+        // code swapped in while running can be longer than it was
+        if pc >= self.hits.len() {
+            self.hits.resize(pc + 1, 0);
+            self.jumps.resize(pc + 1, 0);
+        }
+        self.hits[pc] += 1;
+        self.jumps[pc] += jumped as u64;
+    }
+
+    /// How many times the instruction at `pc` was executed
+    pub fn hits(&self, pc: usize) -> u64 {
+        self.hits.get(pc).copied().unwrap_or(0)
+    }
+
+    /// How many times the instruction at `pc` moved execution somewhere other than the next instruction
+    pub fn jumps(&self, pc: usize) -> u64 {
+        self.jumps.get(pc).copied().unwrap_or(0)
+    }
+}
+
+/// What ran of each source line with code on it, by zero-based line number
+#[derive(Default)]
+struct LineCoverage {
+    hits: u64,
+    /// Each conditional jump on the line, as the times it jumped and the times it fell through
+    branches: Vec<(u64, u64)>
+}
+
+fn line_coverage(devola: &Devola) -> BTreeMap<usize, LineCoverage> {
+    let empty = Coverage::default();
+    let coverage = devola.coverage().unwrap_or(&empty);
+    let mut lines: BTreeMap<usize, LineCoverage> = BTreeMap::new();
+    let labels = devola.symbols();
+    for (pc, instruction) in devola.code().iter().enumerate() {
+        // labels are placeholders, which jumps to them skip over
+        if labels.is_some_and(|labels| labels.contains_key(&pc)) {
+            continue;
+        }
+        let Some(line) = devola.line(pc) else { continue };
+        let line = lines.entry(line).or_default();
+        line.hits = line.hits.max(coverage.hits(pc));
+        if let Instruction::Jump(JumpType::Flag(..) | JumpType::CompareNotEqual(..), _) = instruction {
+            line.branches.push((coverage.jumps(pc), coverage.hits(pc) - coverage.jumps(pc)));
+        }
+    }
+    lines
+}
+
+/// An lcov tracefile record for the source the VM's code was assembled from. Conditional jumps are reported as
+/// branches, one for jumping and one for falling through.
+pub fn lcov(devola: &Devola, source_path: &str) -> String {
+    let lines = line_coverage(devola);
+    let mut report = format!("TN:\nSF:{}\n", source_path);
+    let mut branches = (0, 0);
+    for (&line, coverage) in &lines {
+        for (block, &(jumped, fell_through)) in coverage.branches.iter().enumerate() {
+            for (branch, taken) in [jumped, fell_through].into_iter().enumerate() {
+                // lcov uses `-` for branches on lines that never ran
+                let taken = if coverage.hits == 0 { String::from("-") } else { taken.to_string() };
+                report.push_str(&format!("BRDA:{},{},{},{}\n", line + 1, block, branch, taken));
+                branches.0 += 1;
+                branches.1 += (taken != "-" && taken != "0") as usize;
+            }
+        }
+    }
+    report.push_str(&format!("BRF:{}\nBRH:{}\n", branches.0, branches.1));
+    for (&line, coverage) in &lines {
+        report.push_str(&format!("DA:{},{}\n", line + 1, coverage.hits));
+    }
+    let hit = lines.values().filter(|coverage| coverage.hits > 0).count();
+    report.push_str(&format!("LF:{}\nLH:{}\nend_of_record\n", lines.len(), hit));
+    report
+}
+
+/// The source with how many times each line ran in front of it, `#####` for code that never ran, and how often
+/// each conditional jump jumped after it
+pub fn annotate(devola: &Devola, source: &str) -> String {
+    let lines = line_coverage(devola);
+    let mut annotated = String::new();
+    for (number, text) in source.lines().enumerate() {
+        let (count, branches) = match lines.get(&number) {
+            Some(coverage) if coverage.hits == 0 => (String::from("#####"), String::new()),
+            Some(coverage) => {
+                let branches: Vec<String> = coverage.branches.iter()
+                    .map(|(jumped, fell_through)| format!("  [jumped {} of {}]", jumped, jumped + fell_through))
+                    .collect();
+                (coverage.hits.to_string(), branches.concat())
+            },
+            None => (String::from("-"), String::new())
+        };
+        annotated.push_str(&format!("{:>9}: {}{}\n", count, text, branches));
+    }
+    annotated
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::Cartridge;
+
+    #[test]
+    fn test_coverage() {
+        let source = "lda 2\nloop:\ndec\njnz loop\njmp end\nlda 5 ; dead\nend:";
+        let mut devola = Cartridge::compile(String::from(source)).unwrap().load().unwrap();
+        devola.enable_coverage();
+        devola.run().unwrap();
+        let coverage = devola.coverage().unwrap();
+        assert_eq!((coverage.hits(0), coverage.hits(3), coverage.jumps(3)), (1, 3, 2));
+
+        assert_eq!(annotate(&devola, source), [
+            "        1: lda 2",
+            "        -: loop:",
+            "        3: dec",
+            "        3: jnz loop  [jumped 2 of 3]",
+            "        1: jmp end",
+            "    #####: lda 5 ; dead",
+            "        -: end:",
+            ""
+        ].join("\n"));
+
+        let report = lcov(&devola, "loop.pop");
+        assert!(report.starts_with("TN:\nSF:loop.pop\nBRDA:4,0,0,2\nBRDA:4,0,1,1\nBRF:2\nBRH:2\nDA:1,1\nDA:3,3\n"));
+        assert!(report.ends_with("DA:5,1\nDA:6,0\nLF:5\nLH:4\nend_of_record\n"));
+    }
+}
@@ -0,0 +1,395 @@
+//! A Debug Adapter Protocol server, so that editors can debug source programs with breakpoints on their lines.
+//! Messages are handled here without any I/O; `devola-dap` carries them over stdin and stdout, framed by
+//! `crate::framing`.
+use crate::debugger::{Debugger, Stop};
+use crate::instructions::{CallType, Flag, Instruction, Register};
+use crate::parser::text::compile_from;
+use crate::vm::Devola;
+use serde_json::{json, Value};
+use std::{fs, path::Path};
+
+/// Programs only have the one thread
+const THREAD_ID: u64 = 1;
+const REGISTERS_REFERENCE: u64 = 1;
+const FLAGS_REFERENCE: u64 = 2;
+
+/// Whether two paths name the same file, comparing them as given if either can't be resolved
+fn same_file(first: &str, second: &str) -> bool {
+    match (fs::canonicalize(first), fs::canonicalize(second)) {
+        (Ok(first), Ok(second)) => first == second,
+        _ => first == second
+    }
+}
+
+/// What the program is doing between requests
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Run {
+    Paused,
+    Continuing,
+    /// Running until the stack pointer is back up to a value, to step over or out of a call
+    Returning(u16)
+}
+
+pub struct DebugAdapter {
+    debugger: Option<Debugger>,
+    /// The path of the source file being debugged
+    program: String,
+    run: Run,
+    stop_on_entry: bool,
+    /// Whether the client counts lines from 1, as it does unless it says otherwise
+    lines_start_at_1: bool,
+    finished: bool,
+    seq: u64,
+    /// Events to send after the response to the request being handled
+    events: Vec<Value>
+}
+
+impl Default for DebugAdapter {
+    fn default() -> Self {
+        DebugAdapter {
+            debugger: None,
+            program: String::new(),
+            run: Run::Paused,
+            stop_on_entry: false,
+            lines_start_at_1: true,
+            finished: false,
+            seq: 0,
+            events: Vec::new()
+        }
+    }
+}
+
+impl DebugAdapter {
+    /// Whether the program is running, and `advance` should be called while waiting for requests
+    pub fn running(&self) -> bool {
+        self.debugger.is_some() && self.run != Run::Paused
+    }
+
+    /// Whether the client has ended the session
+    pub fn finished(&self) -> bool {
+        self.finished
+    }
+
+    /// Handles a request, returning the response followed by any events it caused
+    pub fn handle(&mut self, request: &Value) -> Vec<Value> {
+        let command = request["command"].as_str().unwrap_or_default();
+        let arguments = &request["arguments"];
+        let result = match command {
+            "initialize" => self.initialize(arguments),
+            "launch" => self.launch(arguments),
+            "setBreakpoints" => self.set_breakpoints(arguments),
+            "configurationDone" => {
+                if self.stop_on_entry {
+                    self.stopped("entry", None);
+                } else {
+                    self.run = Run::Continuing;
+                }
+                Ok(Value::Null)
+            },
+            "threads" => Ok(json!({ "threads": [{ "id": THREAD_ID, "name": "devola" }] })),
+            "stackTrace" => self.stack_trace(),
+            "scopes" => Ok(json!({ "scopes": [
+                { "name": "Registers", "variablesReference": REGISTERS_REFERENCE, "expensive": false },
+                { "name": "Flags", "variablesReference": FLAGS_REFERENCE, "expensive": false }
+            ] })),
+            "variables" => self.variables(arguments["variablesReference"].as_u64().unwrap_or_default()),
+            "continue" => {
+                self.run = Run::Continuing;
+                Ok(json!({ "allThreadsContinued": true }))
+            },
+            "next" => self.next(),
+            "stepIn" => self.step(),
+            "stepOut" => self.debugger().map(|debugger| debugger.devola.return_stack_pointer()).map(|stack_pointer| {
+                // returning pops the address the call pushed, leaving the stack pointer above where it is now
+                self.run = Run::Returning(stack_pointer.saturating_add(1));
+                Value::Null
+            }),
+            "pause" => {
+                self.run = Run::Paused;
+                self.stopped("pause", None);
+                Ok(Value::Null)
+            },
+            "disconnect" | "terminate" => {
+                self.finished = true;
+                Ok(Value::Null)
+            },
+            _ => Err(format!("unsupported request {}", command))
+        };
+
+        let mut response = json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": command,
+            "success": result.is_ok()
+        });
+        match result {
+            Ok(Value::Null) => (),
+            Ok(body) => response["body"] = body,
+            Err(message) => response["message"] = json!(message)
+        }
+        let mut messages = vec![self.sequence(response)];
+        messages.extend(self.take_events());
+        messages
+    }
+
+    /// Runs the program for up to `steps` instructions, returning the events sent if it stops
+    pub fn advance(&mut self, steps: usize) -> Vec<Value> {
+        let run = self.run;
+        let Some(debugger) = self.debugger.as_mut() else {
+            return Vec::new();
+        };
+        let stop = match run {
+            Run::Paused => None,
+            Run::Continuing => debugger.run(steps).map(Some),
+            // stepping is a stop too, but one the debugger doesn't know about
+            Run::Returning(stack_pointer) => (0..steps).find_map(|_| match debugger.run(1) {
+                Some(stop) => Some(Some(stop)),
+                None => (debugger.devola.return_stack_pointer() >= stack_pointer).then_some(None)
+            })
+        };
+        match stop {
+            Some(Some(stop)) => self.stop(stop),
+            Some(None) => {
+                self.run = Run::Paused;
+                self.stopped("step", None);
+            },
+            None => ()
+        }
+        self.take_events()
+    }
+
+    fn debugger(&self) -> Result<&Debugger, String> {
+        self.debugger.as_ref().ok_or_else(|| String::from("no program has been launched"))
+    }
+
+    fn debugger_mut(&mut self) -> Result<&mut Debugger, String> {
+        self.debugger.as_mut().ok_or_else(|| String::from("no program has been launched"))
+    }
+
+    /// Converts a zero-based source line to the client's numbering
+    fn client_line(&self, line: usize) -> usize {
+        line + self.lines_start_at_1 as usize
+    }
+
+    fn initialize(&mut self, arguments: &Value) -> Result<Value, String> {
+        self.lines_start_at_1 = arguments["linesStartAt1"].as_bool().unwrap_or(true);
+        Ok(json!({ "supportsConfigurationDoneRequest": true, "supportsTerminateRequest": true }))
+    }
+
+    fn launch(&mut self, arguments: &Value) -> Result<Value, String> {
+        let program = arguments["program"].as_str().ok_or_else(|| String::from("no program given"))?;
+        let source = fs::read_to_string(program).map_err(|error| format!("failed to read {}: {}", program, error))?;
+        let (code, symbols, lines, segments) = compile_from(source, None, Path::new(program)).map_err(|errors| {
+            errors.iter().map(|error| format!("{}: {}", program, error)).collect::<Vec<_>>().join("\n")
+        })?;
+        let mut devola = Devola::with_data(code, Some(symbols), &segments);
+        devola.set_line_table(lines);
+
+        self.debugger = Some(Debugger::new(devola));
+        self.program = program.to_string();
+        self.stop_on_entry = arguments["stopOnEntry"].as_bool().unwrap_or_default();
+        // breakpoints can only be set once there's a program to set them in
+        self.events.push(json!({ "type": "event", "event": "initialized" }));
+        Ok(Value::Null)
+    }
+
+    /// Replaces the breakpoints in the program with ones on the first instruction at or after each line
+    fn set_breakpoints(&mut self, arguments: &Value) -> Result<Value, String> {
+        let requested: Vec<usize> = arguments["breakpoints"].as_array().into_iter().flatten()
+            .filter_map(|breakpoint| breakpoint["line"].as_u64())
+            .map(|line| line as usize)
+            .collect();
+        let path = arguments["source"]["path"].as_str().unwrap_or_default();
+        let same_program = same_file(path, &self.program);
+        let offset = self.lines_start_at_1 as usize;
+        let debugger = self.debugger_mut()?;
+
+        for pc in debugger.breakpoints().clone() {
+            debugger.toggle_breakpoint(pc);
+        }
+        let breakpoints: Vec<Value> = requested.into_iter().map(|line| {
+            let found = (0..debugger.devola.code().len())
+                .filter_map(|pc| Some((pc, debugger.devola.line(pc)?)))
+                .find(|&(_, at)| same_program && at + offset >= line);
+            match found {
+                Some((pc, at)) => {
+                    debugger.toggle_breakpoint(pc);
+                    json!({ "verified": true, "line": at + offset })
+                },
+                None => json!({ "verified": false, "line": line })
+            }
+        }).collect();
+        Ok(json!({ "breakpoints": breakpoints }))
+    }
+
+    fn stack_trace(&self) -> Result<Value, String> {
+        let devola = &self.debugger()?.devola;
+        let pc = devola.pc();
+        // frames are named after the closest label before them
+        let name = devola.enclosing_label(pc).unwrap_or("main");
+        let source = Path::new(&self.program).file_name().map(|name| name.to_string_lossy());
+        let line = devola.line(pc).or_else(|| devola.line(pc.saturating_sub(1))).unwrap_or_default();
+        Ok(json!({
+            "stackFrames": [{
+                "id": 0,
+                "name": name,
+                "source": { "name": source, "path": self.program },
+                "line": self.client_line(line),
+                "column": 1,
+                "instructionPointerReference": pc.to_string()
+            }],
+            "totalFrames": 1
+        }))
+    }
+
+    fn variables(&self, reference: u64) -> Result<Value, String> {
+        let devola = &self.debugger()?.devola;
+        let variable = |name: &str, value: String| json!({ "name": name, "value": value, "variablesReference": 0 });
+        let variables: Vec<Value> = match reference {
+            REGISTERS_REFERENCE => {
+                let mut variables: Vec<Value> = [
+                    Register::Accumulator, Register::UtilityB, Register::UtilityC, Register::IndexX, Register::IndexY
+                ].into_iter().map(|register| {
+                    let value = devola.register(register);
+                    variable(&register.to_string(), format!("{} ({:02X}h)", value, value))
+                }).collect();
+                variables.push(variable("sp", format!("{:04X}h", devola.stack_pointer())));
+                variables.push(variable("pc", devola.pc().to_string()));
+                variables
+            },
+            FLAGS_REFERENCE => [Flag::Carry, Flag::Zero, Flag::Parity, Flag::Sign].into_iter()
+                .map(|flag| variable(&flag.to_string(), devola.flag(flag).to_string()))
+                .collect(),
+            _ => return Err(format!("no variables with reference {}", reference))
+        };
+        Ok(json!({ "variables": variables }))
+    }
+
+    /// Steps a single instruction
+    fn step(&mut self) -> Result<Value, String> {
+        match self.debugger_mut()?.step() {
+            Some(stop) => self.stop(stop),
+            None => self.stopped("step", None)
+        }
+        Ok(Value::Null)
+    }
+
+    /// Steps a single instruction, or over a whole subroutine if it's a call
+    fn next(&mut self) -> Result<Value, String> {
+        let devola = &self.debugger()?.devola;
+        match devola.code().get(devola.pc()) {
+            Some(Instruction::Call(CallType::Local(_))) => {
+                self.run = Run::Returning(devola.return_stack_pointer());
+                Ok(Value::Null)
+            },
+            _ => self.step()
+        }
+    }
+
+    /// Reports why the program stopped
+    fn stop(&mut self, stop: Stop) {
+        self.run = Run::Paused;
+        match stop {
+            Stop::Breakpoint(_) => self.stopped("breakpoint", None),
+            Stop::Watchpoint { .. } => self.stopped("data breakpoint", Some(stop.to_string())),
+            Stop::Error(error) => self.stopped("exception", Some(error.to_string())),
+            Stop::Ended => {
+                self.events.push(json!({ "type": "event", "event": "exited", "body": { "exitCode": 0 } }));
+                self.events.push(json!({ "type": "event", "event": "terminated" }));
+            }
+        }
+    }
+
+    fn stopped(&mut self, reason: &str, description: Option<String>) {
+        let mut body = json!({ "reason": reason, "threadId": THREAD_ID, "allThreadsStopped": true });
+        if let Some(description) = description {
+            body["description"] = json!(description);
+            body["text"] = body["description"].clone();
+        }
+        self.events.push(json!({ "type": "event", "event": "stopped", "body": body }));
+    }
+
+    fn take_events(&mut self) -> Vec<Value> {
+        let events = std::mem::take(&mut self.events);
+        events.into_iter().map(|event| self.sequence(event)).collect()
+    }
+
+    /// Numbers an outgoing message
+    fn sequence(&mut self, mut message: Value) -> Value {
+        self.seq += 1;
+        message["seq"] = json!(self.seq);
+        message
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(adapter: &mut DebugAdapter, command: &str, arguments: Value) -> Vec<Value> {
+        adapter.handle(&json!({ "seq": 1, "type": "request", "command": command, "arguments": arguments }))
+    }
+
+    /// The names of the events in some messages, and why the program stopped if it did
+    fn events(messages: &[Value]) -> Vec<String> {
+        messages.iter()
+            .filter(|message| message["type"] == "event")
+            .map(|event| match event["body"]["reason"].as_str() {
+                Some(reason) => format!("{} {}", event["event"].as_str().unwrap(), reason),
+                None => event["event"].as_str().unwrap().to_string()
+            })
+            .collect()
+    }
+
+    fn current_line(adapter: &mut DebugAdapter) -> u64 {
+        request(adapter, "stackTrace", json!({ "threadId": THREAD_ID }))[0]["body"]["stackFrames"][0]["line"].as_u64().unwrap()
+    }
+
+    #[test]
+    fn test_session() {
+        let path = std::env::temp_dir().join(format!("devola_dap_test_{}.pop", std::process::id()));
+        fs::write(&path, "ldb 0\n\nloop:\n    call add\n    cmp 3\n    jnz loop\n    jmp end\nadd:\n    inc\n    ret\nend:\n").unwrap();
+        let path = path.to_str().unwrap();
+        let mut adapter = DebugAdapter::default();
+
+        let response = &request(&mut adapter, "initialize", json!({}))[0];
+        assert_eq!(response["success"], true);
+        assert!(!request(&mut adapter, "launch", json!({ "program": "missing.pop" }))[0]["success"].as_bool().unwrap());
+        let messages = request(&mut adapter, "launch", json!({ "program": path, "stopOnEntry": true }));
+        assert_eq!(events(&messages), vec!["initialized"]);
+
+        // the blank line has no instruction, so its breakpoint moves onto the label after it
+        let response = &request(&mut adapter, "setBreakpoints", json!({
+            "source": { "path": path },
+            "breakpoints": [{ "line": 2 }, { "line": 9 }, { "line": 40 }]
+        }))[0];
+        assert_eq!(response["body"]["breakpoints"], json!([
+            { "verified": true, "line": 3 }, { "verified": true, "line": 9 }, { "verified": false, "line": 40 }
+        ]));
+        assert_eq!(events(&request(&mut adapter, "configurationDone", json!({}))), vec!["stopped entry"]);
+        assert!(!adapter.running());
+        assert_eq!(current_line(&mut adapter), 1);
+
+        request(&mut adapter, "continue", json!({ "threadId": THREAD_ID }));
+        assert_eq!(events(&adapter.advance(100)), vec!["stopped breakpoint"]);
+        assert_eq!(current_line(&mut adapter), 3);
+
+        // stepping over the call runs through the breakpoint inside it
+        assert_eq!(events(&request(&mut adapter, "stepIn", json!({}))), vec!["stopped step"]);
+        request(&mut adapter, "next", json!({}));
+        assert_eq!(events(&adapter.advance(100)), vec!["stopped breakpoint"]);
+        assert_eq!(current_line(&mut adapter), 9);
+        request(&mut adapter, "stepOut", json!({}));
+        assert_eq!(events(&adapter.advance(100)), vec!["stopped step"]);
+        assert_eq!(current_line(&mut adapter), 5);
+
+        let response = &request(&mut adapter, "variables", json!({ "variablesReference": REGISTERS_REFERENCE }))[0];
+        assert_eq!(response["body"]["variables"][0], json!({ "name": "a", "value": "1 (01h)", "variablesReference": 0 }));
+
+        request(&mut adapter, "setBreakpoints", json!({ "source": { "path": path }, "breakpoints": [] }));
+        request(&mut adapter, "continue", json!({}));
+        assert_eq!(events(&adapter.advance(100)), vec!["exited", "terminated"]);
+        request(&mut adapter, "disconnect", json!({}));
+        assert!(adapter.finished());
+    }
+}
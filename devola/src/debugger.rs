@@ -0,0 +1,119 @@
+//! Breakpoints and watchpoints on top of a VM, for debugger frontends to drive.
+use crate::vm::{Devola, DevolaError};
+use std::collections::BTreeSet;
+
+/// Why execution stopped before running as far as it was asked to
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Stop {
+    /// Execution reached a breakpoint at the given location, and hasn't run the instruction there yet
+    Breakpoint(usize),
+    /// The instruction just executed changed a watched byte
+    Watchpoint { address: u16, old: u8, new: u8 },
+    /// The VM reached the end of its code
+    Ended,
+    Error(DevolaError)
+}
+
+impl std::fmt::Display for Stop {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Stop::Breakpoint(pc) => write!(f, "breakpoint at {}", pc),
+            Stop::Watchpoint { address, old, new } => write!(f, "{:04X}h changed from {} to {}", address, old, new),
+            Stop::Ended => write!(f, "reached the end of the code"),
+            Stop::Error(error) => write!(f, "halted with error: {}", error)
+        }
+    }
+}
+
+pub struct Debugger {
+    pub devola: Devola,
+    breakpoints: BTreeSet<usize>,
+    watchpoints: BTreeSet<u16>
+}
+
+impl Debugger {
+    pub fn new(devola: Devola) -> Debugger {
+        Debugger { devola, breakpoints: BTreeSet::new(), watchpoints: BTreeSet::new() }
+    }
+
+    /// Sets or clears the breakpoint at a location, returning whether it's now set
+    pub fn toggle_breakpoint(&mut self, pc: usize) -> bool {
+        !self.breakpoints.remove(&pc) && self.breakpoints.insert(pc)
+    }
+
+    /// Sets or clears the watchpoint on an address, returning whether it's now set
+    pub fn toggle_watchpoint(&mut self, address: u16) -> bool {
+        !self.watchpoints.remove(&address) && self.watchpoints.insert(address)
+    }
+
+    pub fn breakpoints(&self) -> &BTreeSet<usize> {
+        &self.breakpoints
+    }
+
+    pub fn watchpoints(&self) -> &BTreeSet<u16> {
+        &self.watchpoints
+    }
+
+    /// Executes a single instruction, ignoring breakpoints
+    pub fn step(&mut self) -> Option<Stop> {
+        let watched: Vec<(u16, u8)> = self.watchpoints.iter()
+            .map(|&address| (address, self.devola.peek(address)))
+            .collect();
+        match self.devola.step() {
+            Ok(()) => (),
+            Err(DevolaError::EndCode) => return Some(Stop::Ended),
+            Err(error) => return Some(Stop::Error(error))
+        }
+        watched.into_iter().find_map(|(address, old)| {
+            let new = self.devola.peek(address);
+            (new != old).then_some(Stop::Watchpoint { address, old, new })
+        })
+    }
+
+    /// Executes up to `steps` instructions, stopping early at a breakpoint or watchpoint. The first instruction
+    /// always runs, so that continuing from a breakpoint moves past it.
+    pub fn run(&mut self, steps: usize) -> Option<Stop> {
+        for _ in 0..steps {
+            if let Some(stop) = self.step() {
+                return Some(stop);
+            }
+            if self.breakpoints.contains(&self.devola.pc()) {
+                return Some(Stop::Breakpoint(self.devola.pc()));
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::text::compile;
+
+    #[test]
+    fn test_debugger() {
+        let code = "
+            ldb 0
+        loop:
+            inc
+            cmp 3
+            jnz loop
+            sta #0200h
+            ldb a".to_string();
+        let (code, symbols, _, _) = compile(code, None).unwrap();
+        let mut debugger = Debugger::new(Devola::new(code, Some(symbols)));
+
+        assert!(debugger.toggle_breakpoint(2));
+        assert_eq!(debugger.run(100), Some(Stop::Breakpoint(2)));
+        assert_eq!(debugger.run(100), Some(Stop::Breakpoint(2)));
+        assert_eq!(debugger.devola.register(crate::instructions::Register::Accumulator), 1);
+        assert!(!debugger.toggle_breakpoint(2));
+
+        debugger.toggle_watchpoint(0x200);
+        assert_eq!(debugger.run(100), Some(Stop::Watchpoint { address: 0x200, old: 0, new: 3 }));
+        assert_eq!(debugger.devola.pc(), 6);
+        // running out of steps isn't a stop
+        assert_eq!(debugger.run(0), None);
+        assert_eq!(debugger.run(100), Some(Stop::Ended));
+    }
+}
@@ -0,0 +1,250 @@
+//! Turns compiled code back into assembly text that compiles to the same instructions.
+//!
+//! Compiled code has no labels left in it, so they're reconstructed: every jump or call target gets a label,
+//! named from the symbol table when there is one and `l<location>` otherwise. A label compiles to a `nop` at
+//! its location, so targets have to be `nop`s for the text to compile back to the same code, and the label
+//! line stands in for that `nop`. Everything else is written as its `Display` form.
+use crate::binary::{self, DecodeError};
+use crate::instructions::*;
+use crate::parser::intermediate::SymbolTable;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DisasmError {
+    Decode(DecodeError),
+    /// The instruction at the given location has no assembly syntax
+    Unrepresentable(usize),
+    /// The jump or call at the given location targets something other than a `nop`
+    InvalidTarget(usize)
+}
+
+impl std::fmt::Display for DisasmError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DisasmError::Decode(error) => write!(f, "{}", error),
+            DisasmError::Unrepresentable(pc) => write!(f, "instruction {} has no assembly syntax", pc),
+            DisasmError::InvalidTarget(pc) => write!(f, "instruction {} doesn't target a label", pc)
+        }
+    }
+}
+
+impl From<DecodeError> for DisasmError {
+    fn from(error: DecodeError) -> Self {
+        DisasmError::Decode(error)
+    }
+}
+
+/// Names a label for each jump and call target, and for each symbol that's on a `nop`
+fn reconstruct_labels(code: &[Instruction], symbols: Option<&SymbolTable>) -> Result<HashMap<usize, String>, DisasmError> {
+    let mut labels: HashMap<usize, String> = symbols.into_iter()
+        .flatten()
+        .filter(|(pc, _)| matches!(code.get(**pc), Some(Instruction::Nop)))
+        .map(|(pc, label)| (*pc, label.clone()))
+        .collect();
+    let mut taken: HashSet<String> = labels.values().cloned().collect();
+
+    for (pc, instruction) in code.iter().enumerate() {
+        let target = match instruction {
+            Instruction::Jump(_, target) | Instruction::Call(CallType::Local(target)) => *target,
+            _ => continue
+        };
+        if !matches!(code.get(target), Some(Instruction::Nop)) {
+            return Err(DisasmError::InvalidTarget(pc));
+        }
+        if let Entry::Vacant(entry) = labels.entry(target) {
+            let mut label = format!("l{}", target);
+            while taken.contains(&label) {
+                label.push('_');
+            }
+            taken.insert(label.clone());
+            entry.insert(label);
+        }
+    }
+    Ok(labels)
+}
+
+/// Writes out `code` as assembly, using the names in `symbols` for its labels where possible
+pub fn disassemble(code: &[Instruction], symbols: Option<&SymbolTable>) -> Result<String, DisasmError> {
+    let labels = reconstruct_labels(code, symbols)?;
+    let mut text = String::new();
+
+    for (pc, instruction) in code.iter().enumerate() {
+        if let Some(label) = labels.get(&pc) {
+            text.push_str(&format!("{}:\n", label));
+            continue;
+        }
+        let line = match instruction {
+            Instruction::Jump(jump_type, target) => format!("{} {}", jump_type, labels[target]),
+            Instruction::Call(CallType::Local(target)) => format!("call {}", labels[target]),
+            Instruction::_Label(label) => {
+                text.push_str(&format!("{}:\n", label));
+                continue;
+            },
+            Instruction::Store(_, AddressingMode::Register(_) | AddressingMode::Immediate(_)) | Instruction::_Assert(_, _) =>
+                return Err(DisasmError::Unrepresentable(pc)),
+            _ => instruction.to_string()
+        };
+        text.push_str(&format!("    {}\n", line));
+    }
+    Ok(text)
+}
+
+/// An instruction as a line of assembly, with its jump or call target named from `symbols` where it's known
+pub fn instruction_text(instruction: &Instruction, symbols: Option<&SymbolTable>) -> String {
+    let label = match instruction {
+        Instruction::Jump(_, pc) | Instruction::Call(CallType::Local(pc)) => symbols.and_then(|symbols| symbols.get(pc)),
+        _ => None
+    };
+    match (instruction, label) {
+        (Instruction::Jump(jump_type, _), Some(label)) => format!("{} {}", jump_type, label),
+        (Instruction::Call(_), Some(label)) => format!("call {}", label),
+        _ => instruction.to_string()
+    }
+}
+
+/// Writes out a program encoded with `binary::encode` as assembly
+pub fn disassemble_bytes(bytes: &[u8]) -> Result<String, DisasmError> {
+    disassemble(&binary::decode(bytes)?, None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::text::compile;
+    use crate::stdlib::interface::DevolaExternTable;
+    use proptest::prelude::*;
+    use proptest::sample::{select, Index};
+    use std::path::Path;
+
+    const EXTERNS: [&str; 2] = ["memclear", "memcpy"];
+
+    fn externs() -> DevolaExternTable {
+        EXTERNS.iter().map(|name| (name.to_string(), Box::new(|_: &mut crate::vm::Devola| ()) as Box<_>)).collect()
+    }
+
+    fn register() -> impl Strategy<Value = Register> {
+        select(vec![Register::Accumulator, Register::IndexX, Register::IndexY, Register::UtilityB, Register::UtilityC])
+    }
+
+    fn indirect() -> impl Strategy<Value = AddressingMode> {
+        prop_oneof![
+            any::<u16>().prop_map(AddressingMode::Indirect),
+            Just(AddressingMode::Index),
+            any::<i16>().prop_map(AddressingMode::IndexOffset)
+        ]
+    }
+
+    fn addressing_mode() -> impl Strategy<Value = AddressingMode> {
+        prop_oneof![
+            register().prop_map(AddressingMode::Register),
+            any::<u8>().prop_map(AddressingMode::Immediate),
+            indirect()
+        ]
+    }
+
+    fn jump_type() -> impl Strategy<Value = JumpType> {
+        prop_oneof![
+            Just(JumpType::Unconditional),
+            (select(vec![Flag::Carry, Flag::Zero, Flag::Parity, Flag::Sign]), any::<bool>())
+                .prop_map(|(flag, condition)| JumpType::Flag(flag, condition)),
+            (addressing_mode(), any::<u8>()).prop_map(|(source, value)| JumpType::CompareNotEqual(source, value))
+        ]
+    }
+
+    /// Any compiled instruction; jump and call targets are filled in by `program`
+    fn instruction() -> impl Strategy<Value = Instruction> {
+        prop_oneof![
+            (register(), addressing_mode()).prop_map(|(target, source)| Instruction::Load(target, source)),
+            (register(), indirect()).prop_map(|(source, target)| Instruction::Store(source, target)),
+            Just(Instruction::Increment),
+            Just(Instruction::Decrement),
+            addressing_mode().prop_map(Instruction::Add),
+            addressing_mode().prop_map(Instruction::Subtract),
+            addressing_mode().prop_map(Instruction::Compare),
+            addressing_mode().prop_map(Instruction::AddXY),
+            addressing_mode().prop_map(Instruction::SubtractXY),
+            jump_type().prop_map(|jump_type| Instruction::Jump(jump_type, 0)),
+            Just(Instruction::Call(CallType::Local(0))),
+            select(EXTERNS.to_vec()).prop_map(|name| Instruction::Call(CallType::Library(name.to_string()))),
+            Just(Instruction::Return),
+            Just(Instruction::ReturnInterrupt),
+            Just(Instruction::Halt),
+            any::<u8>().prop_map(Instruction::Exit),
+            register().prop_map(Instruction::Push),
+            register().prop_map(Instruction::Pop),
+            Just(Instruction::Nop)
+        ]
+    }
+
+    /// Compiled code whose jumps and calls all land on `nop`s, as they do in code compiled from text
+    fn program() -> impl Strategy<Value = Vec<Instruction>> {
+        prop::collection::vec((instruction(), any::<Index>()), 1..64).prop_map(|entries| {
+            let mut code: Vec<Instruction> = entries.iter().map(|(instruction, _)| instruction.clone()).collect();
+            let mut nops: Vec<usize> = code.iter().enumerate()
+                .filter(|(_, instruction)| matches!(instruction, Instruction::Nop))
+                .map(|(pc, _)| pc)
+                .collect();
+            if nops.is_empty() {
+                nops.push(code.len());
+                code.push(Instruction::Nop);
+            }
+            for (instruction, (_, index)) in code.iter_mut().zip(&entries) {
+                if let Instruction::Jump(_, target) | Instruction::Call(CallType::Local(target)) = instruction {
+                    *target = nops[index.index(nops.len())];
+                }
+            }
+            code
+        })
+    }
+
+    proptest! {
+        #[test]
+        fn test_round_trip(code in program()) {
+            let text = disassemble(&code, None).unwrap();
+            let (compiled, _, _, _) = compile(text, Some(externs())).unwrap();
+            // instructions don't implement PartialEq, but their debug output covers every field
+            prop_assert_eq!(format!("{:?}", compiled), format!("{:?}", code));
+        }
+
+        #[test]
+        fn test_round_trip_bytes(code in program()) {
+            let text = disassemble_bytes(&binary::encode(&code)).unwrap();
+            let (compiled, _, _, _) = compile(text, Some(externs())).unwrap();
+            prop_assert_eq!(format!("{:?}", compiled), format!("{:?}", code));
+        }
+    }
+
+    #[test]
+    fn test_samples() {
+        for sample in ["sample/square.pop", "sample/square_subroutines.pop", "sample/load_store.pop"] {
+            let (code, symbols, _, _) = compile(crate::util::read_from_file(Path::new(sample)), None).unwrap();
+            let text = disassemble(&code, Some(&symbols)).unwrap();
+            let (compiled, compiled_symbols, _, _) = compile(text, None).unwrap();
+            assert_eq!(format!("{:?}", compiled), format!("{:?}", code));
+            assert_eq!(compiled_symbols, symbols);
+        }
+    }
+
+    #[test]
+    fn test_disassemble() {
+        let code = vec![
+            Instruction::Load(Register::Accumulator, AddressingMode::Immediate(255)),
+            Instruction::Nop,
+            Instruction::Store(Register::UtilityB, AddressingMode::IndexOffset(16)),
+            Instruction::Jump(JumpType::Flag(Flag::Zero, false), 1),
+            Instruction::Call(CallType::Local(5)),
+            Instruction::Nop
+        ];
+        let symbols = SymbolTable::from([(1, String::from("loop"))]);
+        assert_eq!(
+            disassemble(&code, Some(&symbols)).unwrap(),
+            "    lda 255\nloop:\n    stb xy+16\n    jnz loop\n    call l5\nl5:\n"
+        );
+
+        assert_eq!(disassemble(&[Instruction::Jump(JumpType::Unconditional, 0)], None), Err(DisasmError::InvalidTarget(0)));
+        let store = Instruction::Store(Register::Accumulator, AddressingMode::Immediate(1));
+        assert_eq!(disassemble(&[Instruction::Nop, store], None), Err(DisasmError::Unrepresentable(1)));
+        assert_eq!(disassemble_bytes(b"POPR"), Err(DisasmError::Decode(DecodeError::UnexpectedEnd)));
+    }
+}
@@ -0,0 +1,114 @@
+//! A snapshot of the machine for working out why a program faulted: its registers, flags and stack, where it
+//! was, and what it ran to get there.
+use crate::disasm::instruction_text;
+use crate::instructions::{Flag, Register};
+use crate::vm::Devola;
+
+/// How many bytes from the top of the stack are dumped
+const STACK_BYTES: u16 = 16;
+
+const REGISTERS: [(char, Register); 5] = [
+    ('A', Register::Accumulator), ('B', Register::UtilityB), ('C', Register::UtilityC),
+    ('X', Register::IndexX), ('Y', Register::IndexY)
+];
+const FLAGS: [(char, Flag); 4] = [('C', Flag::Carry), ('Z', Flag::Zero), ('P', Flag::Parity), ('S', Flag::Sign)];
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct MachineDump {
+    pub pc: usize,
+    /// The instruction at `pc`, if there is one
+    pub instruction: Option<String>,
+    /// The closest label at or before `pc`
+    pub symbol: Option<String>,
+    /// The zero-based source line `pc` was compiled from
+    pub line: Option<usize>,
+    /// A, B, C, X and Y
+    pub registers: [u8; 5],
+    /// C, Z, P and S
+    pub flags: [bool; 4],
+    pub stack_pointer: u16,
+    /// The bytes on the stack, the most recently pushed first
+    pub stack: Vec<u8>,
+    /// The instructions executed before this one, oldest first, as their location and text
+    pub history: Vec<(usize, String)>
+}
+
+impl MachineDump {
+    /// Dumps the VM's state as it is at the instruction at `pc`
+    pub fn new(devola: &Devola, pc: usize) -> MachineDump {
+        let stack_pointer = devola.stack_pointer();
+        let stack_top = stack_pointer.saturating_add(STACK_BYTES).min(devola.memory_map().initial_stack_pointer);
+        let text = |pc: usize| devola.code().get(pc).map(|instruction| instruction_text(instruction, devola.symbols()));
+        MachineDump {
+            pc,
+            instruction: text(pc),
+            symbol: devola.enclosing_label(pc).map(String::from),
+            line: devola.line(pc),
+            registers: REGISTERS.map(|(_, register)| devola.register(register)),
+            flags: FLAGS.map(|(_, flag)| devola.flag(flag)),
+            stack_pointer,
+            stack: (stack_pointer..stack_top).map(|address| devola.peek(address)).collect(),
+            history: devola.history().filter_map(|pc| Some((pc, text(pc)?))).collect()
+        }
+    }
+}
+
+impl std::fmt::Display for MachineDump {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "pc {}", self.pc)?;
+        if let Some(symbol) = &self.symbol {
+            write!(f, " in {}", symbol)?;
+        }
+        if let Some(line) = self.line {
+            write!(f, " at line {}", line + 1)?;
+        }
+        writeln!(f, ": {}", self.instruction.as_deref().unwrap_or("(end of code)"))?;
+        for ((name, _), value) in REGISTERS.iter().zip(self.registers) {
+            write!(f, "{} {:02X}h  ", name, value)?;
+        }
+        // set flags by name, clear ones as dashes
+        let flags: String = FLAGS.iter().zip(self.flags).map(|((name, _), set)| if set { *name } else { '-' }).collect();
+        writeln!(f, "flags {}", flags)?;
+        let stack: Vec<String> = self.stack.iter().map(|byte| format!("{:02X}", byte)).collect();
+        write!(f, "stack {:04X}h: {}", self.stack_pointer, if stack.is_empty() { String::from("(empty)") } else { stack.join(" ") })?;
+        if !self.history.is_empty() {
+            write!(f, "\nlast {} instructions:", self.history.len())?;
+            for (pc, text) in &self.history {
+                write!(f, "\n{:>6}  {}", pc, text)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cartridge::Cartridge;
+
+    #[test]
+    fn test_fault_dump() {
+        let source = "lda 3\nstart:\npush a\nldx ffh\nldy ffh\nlda xy+16";
+        let mut devola = Cartridge::compile(String::from(source)).unwrap().load().unwrap();
+        devola.enable_debug();
+        assert!(devola.run().is_err());
+        let dump = devola.fault_dump().unwrap();
+        assert_eq!((dump.pc, dump.symbol.as_deref(), dump.line), (5, Some("start"), Some(5)));
+        assert_eq!(dump.stack, [3]);
+        assert_eq!(dump.to_string(), [
+            "pc 5 in start at line 6: lda xy+16",
+            "A 03h  B 00h  C 00h  X FFh  Y FFh  flags ----",
+            "stack 0EFFh: 03",
+            "last 5 instructions:",
+            "     0  lda 3",
+            "     1  nop",
+            "     2  push a",
+            "     3  ldx 255",
+            "     4  ldy 255"
+        ].join("\n"));
+
+        // without debugging, nothing is kept
+        let mut devola = Cartridge::compile(String::from(source)).unwrap().load().unwrap();
+        assert!(devola.run().is_err());
+        assert!(devola.fault_dump().is_none());
+    }
+}
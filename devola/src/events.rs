@@ -0,0 +1,215 @@
+//! Driving a VM from async code: a `FrameRunner` runs the VM a frame's worth of cycles at a time and sends what
+//! happened along the way to an `EventStream`, which can be awaited instead of polled. Nothing here depends on
+//! a particular executor or on threads, so it works the same in a web host as in an async frontend.
+use crate::debugger::{Debugger, Stop};
+use crate::instructions::{CallType, Instruction};
+use crate::vm::Devola;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum VmEvent {
+    /// A frame's worth of cycles has run, giving how many frames have completed so far
+    FrameCompleted(u64),
+    /// The VM called the library function with this name
+    ExternCalled(String),
+    /// Execution stopped partway through a frame, at a breakpoint or watchpoint, the end of the code or a fault
+    Stopped(Stop)
+}
+
+struct Shared {
+    events: VecDeque<VmEvent>,
+    /// The task waiting on the next event, if one is
+    waker: Option<Waker>,
+    senders: usize
+}
+
+/// Makes a channel of events, which ends once every sender has been dropped
+pub fn channel() -> (EventSender, EventStream) {
+    let shared = Arc::new(Mutex::new(Shared { events: VecDeque::new(), waker: None, senders: 1 }));
+    (EventSender { shared: shared.clone() }, EventStream { shared })
+}
+
+pub struct EventSender {
+    shared: Arc<Mutex<Shared>>
+}
+
+impl EventSender {
+    /// Queues an event, waking the task waiting on it if there is one
+    pub fn send(&self, event: VmEvent) {
+        let mut shared = self.shared.lock().unwrap();
+        shared.events.push_back(event);
+        if let Some(waker) = shared.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+impl Clone for EventSender {
+    fn clone(&self) -> Self {
+        self.shared.lock().unwrap().senders += 1;
+        EventSender { shared: self.shared.clone() }
+    }
+}
+
+impl Drop for EventSender {
+    fn drop(&mut self) {
+        let mut shared = self.shared.lock().unwrap();
+        shared.senders -= 1;
+        // the waiting task has to hear that the stream has ended
+        if shared.senders == 0 {
+            if let Some(waker) = shared.waker.take() {
+                waker.wake();
+            }
+        }
+    }
+}
+
+pub struct EventStream {
+    shared: Arc<Mutex<Shared>>
+}
+
+impl EventStream {
+    /// The next event, once there is one, or `None` once the stream has ended
+    pub fn recv(&mut self) -> Recv<'_> {
+        Recv { stream: self }
+    }
+
+    /// The next event if there's one queued, without waiting
+    pub fn try_recv(&mut self) -> Option<VmEvent> {
+        self.shared.lock().unwrap().events.pop_front()
+    }
+}
+
+/// The future returned by `EventStream::recv`
+pub struct Recv<'a> {
+    stream: &'a mut EventStream
+}
+
+impl Future for Recv<'_> {
+    type Output = Option<VmEvent>;
+
+    fn poll(self: Pin<&mut Self>, context: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut shared = self.stream.shared.lock().unwrap();
+        match shared.events.pop_front() {
+            Some(event) => Poll::Ready(Some(event)),
+            None if shared.senders == 0 => Poll::Ready(None),
+            None => {
+                shared.waker = Some(context.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/// Runs a VM a frame at a time, one instruction at a time so that it can stop at breakpoints and report
+/// library calls, sending events as it goes
+pub struct FrameRunner {
+    pub debugger: Debugger,
+    cycles_per_frame: u64,
+    /// The VM's cycle count at which the current frame ends
+    frame_end: u64,
+    frames: u64,
+    events: EventSender
+}
+
+impl FrameRunner {
+    pub fn new(devola: Devola, cycles_per_frame: u64, events: EventSender) -> FrameRunner {
+        let frame_end = devola.cycles() + cycles_per_frame;
+        FrameRunner { debugger: Debugger::new(devola), cycles_per_frame, frame_end, frames: 0, events }
+    }
+
+    /// Runs to the end of the current frame, or until execution stops, which is returned. A frame that stopped
+    /// partway carries on from there the next time.
+    pub fn run_frame(&mut self) -> Option<Stop> {
+        while self.debugger.devola.cycles() < self.frame_end {
+            let pc = self.debugger.devola.pc();
+            let library = match self.debugger.devola.code().get(pc) {
+                Some(Instruction::Call(CallType::Library(name))) => Some(name.clone()),
+                _ => None
+            };
+            let stop = self.debugger.step();
+            if stop.is_some() {
+                return self.stop_after(stop);
+            }
+            // an interrupt taken instead of the call leaves the VM somewhere other than after it
+            if let Some(name) = library.filter(|_| self.debugger.devola.pc() == pc + 1) {
+                self.events.send(VmEvent::ExternCalled(name));
+            }
+            let pc = self.debugger.devola.pc();
+            if self.debugger.breakpoints().contains(&pc) {
+                return self.stop_after(Some(Stop::Breakpoint(pc)));
+            }
+        }
+        self.frames += 1;
+        self.frame_end += self.cycles_per_frame;
+        self.events.send(VmEvent::FrameCompleted(self.frames));
+        None
+    }
+
+    fn stop_after(&self, stop: Option<Stop>) -> Option<Stop> {
+        if let Some(stop) = stop {
+            self.events.send(VmEvent::Stopped(stop));
+        }
+        stop
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::text::compile;
+    use crate::stdlib::interface::{DevolaExtern, DevolaExternTable};
+    use std::pin::pin;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::task::Wake;
+
+    /// Counts how many times it's been woken
+    struct CountingWaker(AtomicUsize);
+
+    impl Wake for CountingWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn test_events() {
+        let beep = || {
+            let mut externs = DevolaExternTable::new();
+            externs.insert(String::from("beep"), Box::new(|_: &mut Devola| ()) as Box<DevolaExtern>);
+            externs
+        };
+        let code = String::from("again:\ncall beep\ninc\njmp again");
+        let (code, symbols, _, _) = compile(code, Some(beep())).unwrap();
+        let mut devola = Devola::new(code, Some(symbols));
+        devola.set_externs(beep());
+
+        let (sender, mut stream) = channel();
+        let counter = Arc::new(CountingWaker(AtomicUsize::new(0)));
+        let waker = Waker::from(counter.clone());
+        let mut context = Context::from_waker(&waker);
+        // waiting with nothing queued wakes the task once there's an event
+        assert_eq!(pin!(stream.recv()).poll(&mut context), Poll::Pending);
+        let mut runner = FrameRunner::new(devola, 10, sender.clone());
+        assert_eq!(runner.run_frame(), None);
+        assert_eq!(counter.0.load(Ordering::SeqCst), 1);
+        assert_eq!(pin!(stream.recv()).poll(&mut context), Poll::Ready(Some(VmEvent::ExternCalled(String::from("beep")))));
+        assert_eq!(std::iter::from_fn(|| stream.try_recv()).last(), Some(VmEvent::FrameCompleted(1)));
+
+        // a breakpoint stops the frame partway, and the rest of it runs next time
+        runner.debugger.toggle_breakpoint(3);
+        assert_eq!(runner.run_frame(), Some(Stop::Breakpoint(3)));
+        assert_eq!(std::iter::from_fn(|| stream.try_recv()).last(), Some(VmEvent::Stopped(Stop::Breakpoint(3))));
+        runner.debugger.toggle_breakpoint(3);
+        assert_eq!(runner.run_frame(), None);
+        assert_eq!(std::iter::from_fn(|| stream.try_recv()).last(), Some(VmEvent::FrameCompleted(2)));
+
+        // the stream ends once the runner and every other sender are gone
+        drop((runner, sender));
+        assert_eq!(pin!(stream.recv()).poll(&mut context), Poll::Ready(None));
+    }
+}
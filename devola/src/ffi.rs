@@ -0,0 +1,328 @@
+//! A C API, for embedding the VM in programs that aren't written in Rust. It's declared in `include/devola.h`,
+//! which the build script regenerates whenever the `header` feature is enabled.
+//!
+//! Calls that can fail return a `DevolaStatus` or `false`, and leave a message for `devola_last_error`.
+use crate::binary::RomImage;
+use crate::cartridge::Cartridge;
+use crate::instructions::Register;
+use crate::parser::text::compile;
+use crate::stdlib::interface::{DevolaExtern, DevolaExternTable};
+use crate::vm::{Devola, DevolaError};
+use std::cell::RefCell;
+use std::ffi::{c_char, c_void, CStr, CString};
+use std::{ptr, slice};
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_last_error(message: String) {
+    // messages come from the assembler and VM, which never put NULs in them
+    let message = CString::new(message.replace('\0', "")).unwrap();
+    LAST_ERROR.with(|error| *error.borrow_mut() = Some(message));
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DevolaStatus {
+    Ok,
+    /// The VM reached the end of its code
+    Ended,
+    /// The VM faulted; `devola_last_error` says why
+    Error
+}
+
+impl From<Result<(), DevolaError>> for DevolaStatus {
+    fn from(result: Result<(), DevolaError>) -> Self {
+        match result {
+            Ok(()) => DevolaStatus::Ok,
+            Err(DevolaError::EndCode) => DevolaStatus::Ended,
+            Err(error) => {
+                set_last_error(error.to_string());
+                DevolaStatus::Error
+            }
+        }
+    }
+}
+
+/// A library function, run when the program executes `call` with its name. Arguments and return values are passed
+/// on the stack, with `devola_pop` and `devola_push`.
+pub type DevolaCallback = extern "C" fn(devola: *mut Devola, user_data: *mut c_void);
+
+/// Wraps a callback as an extern. The user data is only ever handed back to the callback.
+fn to_extern(callback: DevolaCallback, user_data: *mut c_void) -> Box<DevolaExtern> {
+    Box::new(move |devola: &mut Devola| callback(devola, user_data))
+}
+
+/// Creates a VM with no code, which a program can be loaded into once its callbacks have been registered
+#[no_mangle]
+pub extern "C" fn devola_create() -> *mut Devola {
+    Box::into_raw(Box::new(Devola::new(Vec::new(), None)))
+}
+
+/// # Safety
+/// `devola` must have come from `devola_create`, and not be used again.
+#[no_mangle]
+pub unsafe extern "C" fn devola_destroy(devola: *mut Devola) {
+    if !devola.is_null() {
+        drop(Box::from_raw(devola));
+    }
+}
+
+/// The message explaining the last call on this thread that failed, or NULL. It's valid until the next call fails.
+#[no_mangle]
+pub extern "C" fn devola_last_error() -> *const c_char {
+    LAST_ERROR.with(|error| error.borrow().as_ref().map_or(ptr::null(), |message| message.as_ptr()))
+}
+
+/// Replaces the VM with a freshly loaded one, keeping the callbacks that were registered
+fn reload(devola: &mut Devola, mut reloaded: Devola) -> Devola {
+    if let Some(externs) = devola.take_externs() {
+        reloaded.set_externs(externs);
+    }
+    reloaded
+}
+
+/// Assembles a program and loads it, returning whether it could be built. Programs can only call the callbacks
+/// registered before they're loaded.
+///
+/// # Safety
+/// `devola` must be a live VM, and `source` a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn devola_load_source(devola: *mut Devola, source: *const c_char) -> bool {
+    let devola = &mut *devola;
+    let source = CStr::from_ptr(source).to_string_lossy().into_owned();
+    // the assembler only needs the callbacks' names, to tell them apart from labels
+    let externs = devola.take_externs().unwrap_or_default();
+    let names: DevolaExternTable = externs.keys()
+        .map(|name| (name.clone(), Box::new(|_: &mut Devola| ()) as Box<DevolaExtern>))
+        .collect();
+    devola.set_externs(externs);
+
+    match compile(source, Some(names)) {
+        Ok((code, symbols, lines, segments)) => {
+            *devola = reload(devola, Devola::with_data(code, Some(symbols), &segments));
+            devola.set_line_table(lines);
+            true
+        },
+        Err(errors) => {
+            set_last_error(errors.iter().map(|error| error.to_string()).collect::<Vec<_>>().join("\n"));
+            false
+        }
+    }
+}
+
+/// Loads a program built by `devola-asm`, returning whether it could be decoded. Its data is copied into memory,
+/// and execution starts from its entry point.
+///
+/// # Safety
+/// `devola` must be a live VM, and `bytes` must point to `length` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn devola_load_rom(devola: *mut Devola, bytes: *const u8, length: usize) -> bool {
+    let devola = &mut *devola;
+    let loaded = RomImage::decode(slice::from_raw_parts(bytes, length)).map_err(|error| error.to_string())
+        .and_then(|image| Cartridge::from(image).load().map_err(|error| error.to_string()));
+    match loaded {
+        Ok(loaded) => {
+            *devola = reload(devola, loaded);
+            true
+        },
+        Err(message) => {
+            set_last_error(message);
+            false
+        }
+    }
+}
+
+/// Registers a library function that programs loaded afterwards can `call` by name
+///
+/// # Safety
+/// `devola` must be a live VM, and `name` a NUL-terminated string. `user_data` must stay valid for as long as the
+/// VM might call the callback.
+#[no_mangle]
+pub unsafe extern "C" fn devola_register_callback(
+    devola: *mut Devola, name: *const c_char, callback: DevolaCallback, user_data: *mut c_void
+) {
+    let name = CStr::from_ptr(name).to_string_lossy();
+    (*devola).register_extern(&name, to_extern(callback, user_data));
+}
+
+/// Executes a single instruction
+///
+/// # Safety
+/// `devola` must be a live VM.
+#[no_mangle]
+pub unsafe extern "C" fn devola_step(devola: *mut Devola) -> DevolaStatus {
+    (*devola).step().into()
+}
+
+/// Runs for at least `cycles` cycles, or until the end of the code. The cycles actually taken are written to
+/// `taken`, unless it's NULL.
+///
+/// # Safety
+/// `devola` must be a live VM, and `taken` NULL or writable.
+#[no_mangle]
+pub unsafe extern "C" fn devola_run_cycles(devola: *mut Devola, cycles: u64, taken: *mut u64) -> DevolaStatus {
+    match (*devola).run_cycles(cycles) {
+        Ok(elapsed) => {
+            if !taken.is_null() {
+                *taken = elapsed;
+            }
+            DevolaStatus::Ok
+        },
+        Err(error) => Err(error).into()
+    }
+}
+
+/// # Safety
+/// `devola` must be a live VM.
+#[no_mangle]
+pub unsafe extern "C" fn devola_peek(devola: *const Devola, address: u16) -> u8 {
+    (*devola).peek(address)
+}
+
+/// # Safety
+/// `devola` must be a live VM.
+#[no_mangle]
+pub unsafe extern "C" fn devola_poke(devola: *mut Devola, address: u16, value: u8) {
+    (*devola).poke(address, value);
+}
+
+/// Copies `length` bytes of memory from `address` into `buffer`, wrapping around the end of memory
+///
+/// # Safety
+/// `devola` must be a live VM, and `buffer` must point to `length` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn devola_read_memory(devola: *const Devola, address: u16, buffer: *mut u8, length: usize) {
+    for (offset, byte) in slice::from_raw_parts_mut(buffer, length).iter_mut().enumerate() {
+        *byte = (*devola).peek(address.wrapping_add(offset as u16));
+    }
+}
+
+/// Copies `length` bytes from `buffer` into memory at `address`, wrapping around the end of memory
+///
+/// # Safety
+/// `devola` must be a live VM, and `buffer` must point to `length` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn devola_write_memory(devola: *mut Devola, address: u16, buffer: *const u8, length: usize) {
+    for (offset, &byte) in slice::from_raw_parts(buffer, length).iter().enumerate() {
+        (*devola).poke(address.wrapping_add(offset as u16), byte);
+    }
+}
+
+/// Pushes a byte to the stack, e.g. to return a value from a callback
+///
+/// # Safety
+/// `devola` must be a live VM.
+#[no_mangle]
+pub unsafe extern "C" fn devola_push(devola: *mut Devola, value: u8) {
+    (*devola).push(value);
+}
+
+/// Pops a byte from the stack, e.g. to take a callback's arguments
+///
+/// # Safety
+/// `devola` must be a live VM.
+#[no_mangle]
+pub unsafe extern "C" fn devola_pop(devola: *mut Devola) -> u8 {
+    (*devola).pop()
+}
+
+/// Writes the registers to `registers`, in the order A, B, C, X, Y
+///
+/// # Safety
+/// `devola` must be a live VM, and `registers` must point to 5 writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn devola_registers(devola: *const Devola, registers: *mut u8) {
+    let order = [Register::Accumulator, Register::UtilityB, Register::UtilityC, Register::IndexX, Register::IndexY];
+    for (value, register) in slice::from_raw_parts_mut(registers, order.len()).iter_mut().zip(order) {
+        *value = (*devola).register(register);
+    }
+}
+
+/// The location of the next instruction
+///
+/// # Safety
+/// `devola` must be a live VM.
+#[no_mangle]
+pub unsafe extern "C" fn devola_pc(devola: *const Devola) -> usize {
+    (*devola).pc()
+}
+
+/// The exit code the program stopped with at a `hlt N`, or -1 if it hasn't
+///
+/// # Safety
+/// `devola` must be a live VM.
+#[no_mangle]
+pub unsafe extern "C" fn devola_exit_code(devola: *const Devola) -> i16 {
+    (*devola).exit_code().map_or(-1, i16::from)
+}
+
+/// # Safety
+/// `devola` must be a live VM.
+#[no_mangle]
+pub unsafe extern "C" fn devola_cycles(devola: *const Devola) -> u64 {
+    (*devola).cycles()
+}
+
+/// Requests an interrupt on a line (0-7)
+///
+/// # Safety
+/// `devola` must be a live VM.
+#[no_mangle]
+pub unsafe extern "C" fn devola_request_interrupt(devola: *mut Devola, line: u8) {
+    (*devola).request_interrupt(line);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instructions::{CallType, Instruction};
+
+    /// Adds the byte the user data points to onto the argument on the stack
+    extern "C" fn add(devola: *mut Devola, user_data: *mut c_void) {
+        unsafe {
+            let value = devola_pop(devola);
+            devola_push(devola, value + *(user_data as *const u8));
+        }
+    }
+
+    fn last_error() -> String {
+        unsafe { CStr::from_ptr(devola_last_error()).to_string_lossy().into_owned() }
+    }
+
+    #[test]
+    fn test_ffi() {
+        let mut amount: u8 = 5;
+        unsafe {
+            let devola = devola_create();
+            assert!(!devola_load_source(devola, c"lda 1\npush a\ncall add".as_ptr()));
+            assert_eq!(last_error(), "line 3: InvalidLabel (Call: add)");
+
+            devola_register_callback(devola, c"add".as_ptr(), add, &mut amount as *mut u8 as *mut c_void);
+            assert!(devola_load_source(devola, c"lda 1\npush a\ncall add\npop b\nstb #0200h".as_ptr()));
+            assert_eq!(devola_step(devola), DevolaStatus::Ok);
+            let mut taken = 0;
+            assert_eq!(devola_run_cycles(devola, 1000, &mut taken), DevolaStatus::Ok);
+            assert!(taken > 0);
+            assert_eq!(devola_step(devola), DevolaStatus::Ended);
+            // it ran off the end rather than exiting
+            assert_eq!(devola_exit_code(devola), -1);
+
+            let mut registers = [0; 5];
+            devola_registers(devola, registers.as_mut_ptr());
+            assert_eq!(registers, [1, 6, 0, 0, 0]);
+            let mut memory = [0; 2];
+            devola_write_memory(devola, 0x0201, [7].as_ptr(), 1);
+            devola_read_memory(devola, 0x0200, memory.as_mut_ptr(), memory.len());
+            assert_eq!(memory, [6, 7]);
+
+            let rom = RomImage::new(vec![Instruction::Call(CallType::Library(String::from("missing")))]).encode().unwrap();
+            assert!(devola_load_rom(devola, rom.as_ptr(), rom.len()));
+            assert_eq!(devola_step(devola), DevolaStatus::Error);
+            assert_eq!(last_error(), "unimplemented library call at instruction 0");
+            assert!(!devola_load_rom(devola, rom.as_ptr(), 2));
+            devola_destroy(devola);
+        }
+    }
+}
@@ -0,0 +1,48 @@
+//! Messages framed by a `Content-Length` header, as both the Debug Adapter and Language Server Protocols send them.
+use serde_json::Value;
+use std::io::{self, BufRead, Write};
+
+/// Reads a message framed with a `Content-Length` header, or returns `None` at the end of the input
+pub fn read_message(reader: &mut impl BufRead) -> io::Result<Option<Value>> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some(value) = header.strip_prefix("Content-Length:") {
+            length = value.trim().parse::<usize>().ok();
+        }
+    }
+    let length = length.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "missing Content-Length"))?;
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body)?;
+    Ok(Some(serde_json::from_slice(&body)?))
+}
+
+pub fn write_message(writer: &mut impl Write, message: &Value) -> io::Result<()> {
+    let body = message.to_string();
+    write!(writer, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_framing() {
+        let mut written = Vec::new();
+        write_message(&mut written, &json!({ "seq": 1 })).unwrap();
+        assert_eq!(written, b"Content-Length: 9\r\n\r\n{\"seq\":1}");
+
+        let mut reader = &written[..];
+        assert_eq!(read_message(&mut reader).unwrap(), Some(json!({ "seq": 1 })));
+        assert_eq!(read_message(&mut reader).unwrap(), None);
+    }
+}
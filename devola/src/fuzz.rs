@@ -0,0 +1,73 @@
+//! An entry point for fuzzing the VM. Instructions implement `arbitrary::Arbitrary`, so a cargo-fuzz target can
+//! be as short as:
+//!
+//! ```ignore
+//! fuzz_target!(|input: (Vec<Instruction>, u16)| {
+//!     let _ = devola::fuzz::fuzz_step(input.0, input.1 as usize);
+//! });
+//! ```
+use crate::instructions::Instruction;
+use crate::vm::{Devola, DevolaError};
+
+/// Runs any code for up to `steps` instructions. Reaching the end of the code isn't an error, and anything
+/// else that goes wrong is returned rather than panicking.
+pub fn fuzz_step(code: Vec<Instruction>, steps: usize) -> Result<(), DevolaError> {
+    let mut devola = Devola::new(code, None);
+    for _ in 0..steps {
+        match devola.step() {
+            Err(DevolaError::EndCode) => break,
+            result => result?
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instructions::*;
+    use crate::vm::FaultLocation;
+    use arbitrary::{Arbitrary, Unstructured};
+    use proptest::prelude::*;
+
+    fn location(pc: usize) -> FaultLocation {
+        FaultLocation { pc, line: None }
+    }
+
+    #[test]
+    fn test_faults() {
+        let overflow = vec![
+            Instruction::Load(Register::Accumulator, AddressingMode::Immediate(0)),
+            Instruction::Store(Register::Accumulator, AddressingMode::Indirect(0x0FF0)),
+            Instruction::Store(Register::Accumulator, AddressingMode::Indirect(0x0FF1)),
+            Instruction::Push(Register::Accumulator)
+        ];
+        assert_eq!(fuzz_step(overflow, 10), Err(DevolaError::StackOverflow(location(3))));
+        let underflow = vec![
+            Instruction::Load(Register::Accumulator, AddressingMode::Immediate(0xFF)),
+            Instruction::Store(Register::Accumulator, AddressingMode::Indirect(0x0FF0)),
+            Instruction::Store(Register::Accumulator, AddressingMode::Indirect(0x0FF1)),
+            Instruction::Return
+        ];
+        assert_eq!(fuzz_step(underflow, 10), Err(DevolaError::StackOverflow(location(3))));
+
+        let index = vec![Instruction::Load(Register::IndexX, AddressingMode::Immediate(0xFF)), Instruction::Add(AddressingMode::IndexOffset(0x0100))];
+        assert_eq!(fuzz_step(index, 10), Err(DevolaError::InvalidArgument(location(1))));
+        let jump = vec![Instruction::Nop, Instruction::Jump(JumpType::Unconditional, usize::MAX)];
+        assert_eq!(fuzz_step(jump, 10), Err(DevolaError::InvalidArgument(location(1))));
+        let assert = vec![Instruction::_Assert(AddressingMode::Register(Register::Accumulator), 1)];
+        assert_eq!(fuzz_step(assert, 10), Err(DevolaError::AssertionFailed(location(0))));
+
+        // running out of steps in an infinite loop is fine
+        assert_eq!(fuzz_step(vec![Instruction::Jump(JumpType::Unconditional, 0)], 1000), Ok(()));
+    }
+
+    proptest! {
+        #[test]
+        fn test_fuzz_step(bytes in prop::collection::vec(any::<u8>(), 0..1024)) {
+            if let Ok(code) = Vec::<Instruction>::arbitrary(&mut Unstructured::new(&bytes)) {
+                let _ = fuzz_step(code, 1000);
+            }
+        }
+    }
+}
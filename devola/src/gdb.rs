@@ -0,0 +1,299 @@
+//! The GDB remote serial protocol, for attaching GDB frontends to a VM.
+//!
+//! This handles the protocol itself: splitting received bytes into packets, and answering the packets that
+//! inspect or change the VM. Moving the connection's bytes around, and running the VM when asked to step or
+//! continue, is left to the host, which knows how the VM is being clocked.
+//!
+//! Registers are described to GDB by `TARGET_XML`. Code and memory are separate on Devola, so breakpoint and
+//! `pc` addresses are locations in the code, while memory addresses are locations in memory.
+use crate::instructions::{Flag, Register};
+use crate::vm::Devola;
+use std::collections::BTreeSet;
+
+/// Signals reported to GDB when the VM stops
+pub const SIGINT: u8 = 2;
+pub const SIGTRAP: u8 = 5;
+
+/// The `a`, `b`, `c`, `x` and `y` registers and the flags are a byte each, `sp` is 2 bytes and `pc` 4
+pub const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <architecture>devola</architecture>
+  <feature name="org.popola.devola">
+    <flags id="devola_flags" size="1">
+      <field name="C" start="0" end="0"/>
+      <field name="Z" start="1" end="1"/>
+      <field name="P" start="2" end="2"/>
+      <field name="S" start="3" end="3"/>
+    </flags>
+    <reg name="a" bitsize="8" type="uint8" regnum="0"/>
+    <reg name="b" bitsize="8" type="uint8"/>
+    <reg name="c" bitsize="8" type="uint8"/>
+    <reg name="x" bitsize="8" type="uint8"/>
+    <reg name="y" bitsize="8" type="uint8"/>
+    <reg name="flags" bitsize="8" type="devola_flags"/>
+    <reg name="sp" bitsize="16" type="data_ptr"/>
+    <reg name="pc" bitsize="32" type="code_ptr"/>
+  </feature>
+</target>
+"#;
+
+const REGISTERS: [Register; 5] = [Register::Accumulator, Register::UtilityB, Register::UtilityC, Register::IndexX, Register::IndexY];
+const FLAGS: [Flag; 4] = [Flag::Carry, Flag::Zero, Flag::Parity, Flag::Sign];
+/// The most data GDB is told it can send in a packet. A packet that runs on longer is dropped as corrupt.
+const PACKET_SIZE: usize = 0x1000;
+
+/// Something received from GDB
+#[derive(Debug, PartialEq)]
+pub enum Incoming {
+    Packet(String),
+    /// A packet that didn't match its checksum, which GDB should be asked to send again
+    Corrupt,
+    /// GDB asking for the running VM to stop
+    Interrupt
+}
+
+/// Splits the bytes received from GDB into packets. Acknowledgements are skipped.
+#[derive(Default)]
+pub struct Packets {
+    buffer: Vec<u8>
+}
+
+impl Packets {
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+}
+
+impl Iterator for Packets {
+    type Item = Incoming;
+
+    fn next(&mut self) -> Option<Incoming> {
+        loop {
+            match self.buffer.first()? {
+                0x03 => {
+                    self.buffer.remove(0);
+                    return Some(Incoming::Interrupt);
+                },
+                b'$' => {
+                    // the packet is complete once the two checksum digits after `#` have arrived
+                    let end = match self.buffer.iter().take(PACKET_SIZE + 2).position(|&byte| byte == b'#') {
+                        Some(end) => end,
+                        None if self.buffer.len() > PACKET_SIZE + 1 => {
+                            self.buffer.clear();
+                            return Some(Incoming::Corrupt);
+                        },
+                        None => return None
+                    };
+                    let checksum = self.buffer.get(end + 1..end + 3)?;
+                    let valid = std::str::from_utf8(checksum).ok()
+                        .and_then(|checksum| u8::from_str_radix(checksum, 16).ok())
+                        == Some(self::checksum(&self.buffer[1..end]));
+                    let data = String::from_utf8_lossy(&self.buffer[1..end]).into_owned();
+                    self.buffer.drain(..end + 3);
+                    return Some(if valid { Incoming::Packet(data) } else { Incoming::Corrupt });
+                },
+                _ => {
+                    self.buffer.remove(0);
+                }
+            }
+        }
+    }
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum, &byte| sum.wrapping_add(byte))
+}
+
+/// Wraps a reply up as a packet to send to GDB
+pub fn frame(data: &str) -> Vec<u8> {
+    format!("${}#{:02x}", data, checksum(data.as_bytes())).into_bytes()
+}
+
+/// The reply telling GDB that the VM has stopped with a signal
+pub fn stop_reply(signal: u8) -> String {
+    format!("S{:02x}", signal)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn parse_hex(text: &str) -> Option<usize> {
+    usize::from_str_radix(text, 16).ok()
+}
+
+fn parse_bytes(text: &str) -> Option<Vec<u8>> {
+    (0..text.len()).step_by(2)
+        .map(|start| u8::from_str_radix(text.get(start..start + 2)?, 16).ok())
+        .collect()
+}
+
+/// Parses `address,length`, as long as it's all within memory
+fn parse_range(text: &str) -> Option<(u16, usize)> {
+    let (address, length) = text.split_once(',')?;
+    let (address, length) = (parse_hex(address)?, parse_hex(length)?);
+    (address.checked_add(length)? <= crate::vm::MEMORY_SIZE).then_some((address as u16, length))
+}
+
+/// Each register's bytes, little-endian, in the order they appear in `TARGET_XML`
+fn registers(devola: &Devola) -> Vec<Vec<u8>> {
+    let mut registers: Vec<Vec<u8>> = REGISTERS.iter().map(|&register| vec![devola.register(register)]).collect();
+    let flags = FLAGS.iter().enumerate().fold(0, |flags, (bit, &flag)| flags | (devola.flag(flag) as u8) << bit);
+    registers.push(vec![flags]);
+    registers.push(devola.stack_pointer().to_le_bytes().to_vec());
+    registers.push((devola.pc() as u32).to_le_bytes().to_vec());
+    registers
+}
+
+/// What the host should do after a packet
+#[derive(Debug, PartialEq)]
+pub enum Reply {
+    Send(String),
+    /// Execute a single instruction, then send a stop reply
+    Step,
+    /// Run until a breakpoint is reached or GDB interrupts, then send a stop reply
+    Continue,
+    /// Reply `OK` and close the connection
+    Detach
+}
+
+/// Answers GDB's packets, and keeps track of its breakpoints
+#[derive(Default)]
+pub struct GdbStub {
+    breakpoints: BTreeSet<usize>
+}
+
+impl GdbStub {
+    pub fn breakpoints(&self) -> &BTreeSet<usize> {
+        &self.breakpoints
+    }
+
+    /// Handles a packet. Packets that aren't supported get an empty reply, as the protocol expects.
+    pub fn handle(&mut self, packet: &str, devola: &mut Devola) -> Reply {
+        let reply = match packet.split_at(packet.len().min(1)) {
+            ("?", _) => stop_reply(SIGTRAP),
+            ("g", _) => hex(&registers(devola).concat()),
+            ("p", number) => match parse_hex(number).and_then(|number| registers(devola).get(number).cloned()) {
+                Some(register) => hex(&register),
+                None => String::from("E01")
+            },
+            ("m", range) => match parse_range(range) {
+                Some((address, length)) => {
+                    let bytes: Vec<u8> = (0..length).map(|offset| devola.peek(address.wrapping_add(offset as u16))).collect();
+                    hex(&bytes)
+                },
+                None => String::from("E01")
+            },
+            ("M", write) => {
+                let write = write.split_once(':')
+                    .and_then(|(range, data)| Some((parse_range(range)?, parse_bytes(data)?)))
+                    .filter(|((_, length), bytes)| *length == bytes.len());
+                match write {
+                    Some(((address, _), bytes)) => {
+                        for (offset, byte) in bytes.into_iter().enumerate() {
+                            devola.poke(address.wrapping_add(offset as u16), byte);
+                        }
+                        String::from("OK")
+                    },
+                    None => String::from("E01")
+                }
+            },
+            // only software breakpoints are supported
+            ("Z" | "z", breakpoint) if breakpoint.starts_with("0,") => {
+                let location = breakpoint[2..].split(',').next().and_then(parse_hex);
+                match location {
+                    Some(location) => {
+                        if packet.starts_with('Z') {
+                            self.breakpoints.insert(location);
+                        } else {
+                            self.breakpoints.remove(&location);
+                        }
+                        String::from("OK")
+                    },
+                    None => String::from("E01")
+                }
+            },
+            // resuming from a given address isn't supported, so it's ignored
+            ("s", _) => return Reply::Step,
+            ("c", _) => return Reply::Continue,
+            ("D" | "k", _) => return Reply::Detach,
+            // there's only ever the one thread
+            ("H" | "T", _) => String::from("OK"),
+            _ if packet.starts_with("qSupported") => format!("PacketSize={:x};qXfer:features:read+", PACKET_SIZE),
+            _ if packet.starts_with("qXfer:features:read:target.xml:") => {
+                let range = &packet["qXfer:features:read:target.xml:".len()..];
+                match range.split_once(',').and_then(|(offset, length)| Some((parse_hex(offset)?, parse_hex(length)?))) {
+                    Some((offset, length)) => {
+                        let rest = TARGET_XML.get(offset.min(TARGET_XML.len())..).unwrap_or("");
+                        if rest.len() > length { format!("m{}", &rest[..length]) } else { format!("l{}", rest) }
+                    },
+                    None => String::from("E01")
+                }
+            },
+            _ if packet == "qAttached" => String::from("1"),
+            _ if packet == "qC" => String::from("QC1"),
+            _ if packet == "qfThreadInfo" => String::from("m1"),
+            _ if packet == "qsThreadInfo" => String::from("l"),
+            _ => String::new()
+        };
+        Reply::Send(reply)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instructions::{AddressingMode, Instruction};
+
+    #[test]
+    fn test_packets() {
+        let mut packets = Packets::default();
+        packets.push(b"+$g#67$m0,");
+        assert_eq!(packets.next(), Some(Incoming::Packet(String::from("g"))));
+        assert_eq!(packets.next(), None);
+        packets.push(b"4#fd\x03$?#00");
+        assert_eq!(packets.next(), Some(Incoming::Packet(String::from("m0,4"))));
+        assert_eq!(packets.next(), Some(Incoming::Interrupt));
+        assert_eq!(packets.next(), Some(Incoming::Corrupt));
+        assert_eq!(packets.next(), None);
+        // a packet that never ends is dropped rather than kept growing
+        packets.push(b"$");
+        packets.push(&[b'0'; PACKET_SIZE + 1]);
+        assert_eq!(packets.next(), Some(Incoming::Corrupt));
+        assert_eq!(packets.next(), None);
+
+        assert_eq!(frame("OK"), b"$OK#9a");
+    }
+
+    #[test]
+    fn test_stub() {
+        let mut devola = Devola::new(vec![Instruction::Load(Register::Accumulator, AddressingMode::Immediate(0x80)), Instruction::Nop], None);
+        devola.step().unwrap();
+        let mut stub = GdbStub::default();
+        let mut send = |packet: &str| stub.handle(packet, &mut devola);
+
+        // a is 80h, the stack is empty at 0F00h, and the next instruction is 1
+        assert_eq!(send("g"), Reply::Send(String::from(concat!("8000000000", "00", "000f", "01000000"))));
+        assert_eq!(send("p7"), Reply::Send(String::from("01000000")));
+        assert_eq!(send("p8"), Reply::Send(String::from("E01")));
+
+        assert_eq!(send("M200,2:beef"), Reply::Send(String::from("OK")));
+        assert_eq!(send("m1ff,4"), Reply::Send(String::from("00beef00")));
+        assert_eq!(send("mffff,2"), Reply::Send(String::from("E01")));
+        assert_eq!(send("mffffffffffffffff,1"), Reply::Send(String::from("E01")));
+
+        assert_eq!(send("Z0,1,1"), Reply::Send(String::from("OK")));
+        assert_eq!(send("Z2,200,1"), Reply::Send(String::new()));
+        assert_eq!(send("c"), Reply::Continue);
+        assert_eq!(send("s"), Reply::Step);
+        assert_eq!(send("D"), Reply::Detach);
+
+        // the target description is read in chunks, the last marked with `l`
+        assert_eq!(send("qXfer:features:read:target.xml:0,10"), Reply::Send(format!("m{}", &TARGET_XML[..16])));
+        assert_eq!(send("qXfer:features:read:target.xml:10,1000"), Reply::Send(format!("l{}", &TARGET_XML[16..])));
+
+        assert_eq!(stub.breakpoints(), &BTreeSet::from([1]));
+        assert_eq!(devola.peek(0x200), 0xBE);
+    }
+}
@@ -0,0 +1,173 @@
+//! Running a VM on a thread of its own at a steady speed, so that an embedder's event loop never waits on it.
+//! The embedder keeps a handle, which pauses and resumes the VM, hands it closures to run between slices of
+//! execution, and hears about it stopping through a channel.
+use crate::vm::{Devola, DevolaError};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// How often the thread wakes up to run the cycles that have come due
+const SLICE: Duration = Duration::from_millis(10);
+
+/// Why the VM stopped running on its own. It's paused afterwards, until resumed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DevolaEvent {
+    /// The VM reached the end of its code
+    Ended,
+    Halted(DevolaError)
+}
+
+type Job = Box<dyn FnOnce(&mut Devola) + Send>;
+
+enum Command {
+    Pause,
+    Resume,
+    SetSpeed(u64),
+    Run(Job),
+    Stop
+}
+
+/// A VM running on another thread. Dropping the handle stops the thread.
+pub struct DevolaHandle {
+    commands: Sender<Command>,
+    events: Receiver<DevolaEvent>,
+    thread: Option<JoinHandle<()>>
+}
+
+impl DevolaHandle {
+    /// Starts running the VM made by `create`, at `cycles_per_second`. The VM is made on the new thread, as the
+    /// library functions it's given don't have to be `Send`.
+    pub fn spawn(create: impl FnOnce() -> Devola + Send + 'static, cycles_per_second: u64) -> DevolaHandle {
+        let (commands, received) = mpsc::channel();
+        let (sent, events) = mpsc::channel();
+        let thread = thread::spawn(move || run(create(), cycles_per_second, received, sent));
+        DevolaHandle { commands, events, thread: Some(thread) }
+    }
+
+    fn send(&self, command: Command) {
+        // the thread only stops once the handle is dropped, or if a closure given to `with_vm` panicked
+        self.commands.send(command).expect("the VM's thread has stopped");
+    }
+
+    pub fn pause(&self) {
+        self.send(Command::Pause);
+    }
+
+    /// Carries on running, from where the VM was paused or stopped
+    pub fn resume(&self) {
+        self.send(Command::Resume);
+    }
+
+    pub fn set_speed(&self, cycles_per_second: u64) {
+        self.send(Command::SetSpeed(cycles_per_second));
+    }
+
+    /// Runs a closure on the VM between slices of execution, waiting for its result
+    pub fn with_vm<R: Send + 'static>(&self, function: impl FnOnce(&mut Devola) -> R + Send + 'static) -> R {
+        let (sent, result) = mpsc::channel();
+        self.send(Command::Run(Box::new(move |devola| {
+            let _ = sent.send(function(devola));
+        })));
+        result.recv().expect("the VM's thread has stopped")
+    }
+
+    /// Why the VM has stopped on its own, as it happens
+    pub fn events(&self) -> &Receiver<DevolaEvent> {
+        &self.events
+    }
+}
+
+impl Drop for DevolaHandle {
+    fn drop(&mut self) {
+        let _ = self.commands.send(Command::Stop);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// The VM's thread: runs a slice's worth of cycles each slice while running, and carries out commands in between
+fn run(mut devola: Devola, mut cycles_per_second: u64, commands: Receiver<Command>, events: Sender<DevolaEvent>) {
+    let mut running = true;
+    let mut next_slice = Instant::now();
+    loop {
+        let command = if running {
+            match commands.recv_timeout(next_slice.saturating_duration_since(Instant::now())) {
+                Ok(command) => Some(command),
+                Err(RecvTimeoutError::Timeout) => None,
+                Err(RecvTimeoutError::Disconnected) => return
+            }
+        } else {
+            match commands.recv() {
+                Ok(command) => Some(command),
+                Err(_) => return
+            }
+        };
+        match command {
+            Some(Command::Pause) => running = false,
+            Some(Command::Resume) => if !running {
+                running = true;
+                next_slice = Instant::now();
+            },
+            Some(Command::SetSpeed(speed)) => cycles_per_second = speed,
+            Some(Command::Run(job)) => job(&mut devola),
+            Some(Command::Stop) => return,
+            None => {
+                // as fast as it can go, rather than overflowing, for speeds too high to count a slice of
+                let cycles = (cycles_per_second.saturating_mul(SLICE.as_millis() as u64) / 1000).max(1);
+                let stopped = match devola.run_cycles(cycles) {
+                    Ok(_) if devola.pc() >= devola.code().len() => Some(DevolaEvent::Ended),
+                    Ok(_) => None,
+                    Err(error) => Some(DevolaEvent::Halted(error))
+                };
+                if let Some(event) = stopped {
+                    running = false;
+                    let _ = events.send(event);
+                }
+                // a slice that ran late isn't made up for, so that falling behind doesn't snowball
+                next_slice = (next_slice + SLICE).max(Instant::now());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::text::compile;
+
+    fn program(source: &str) -> impl FnOnce() -> Devola + Send + 'static {
+        let source = String::from(source);
+        move || {
+            let (code, symbols, _, _) = compile(source, None).unwrap();
+            Devola::new(code, Some(symbols))
+        }
+    }
+
+    #[test]
+    fn test_handle() {
+        let handle = DevolaHandle::spawn(program("again:\ninc\nsta #0200h\njmp again"), 1_000_000);
+        // assembling can take a while in a debug build, so the VM may take more than a slice to get going
+        while handle.with_vm(|devola| devola.cycles()) == 0 {
+            thread::sleep(SLICE);
+        }
+        handle.pause();
+        let cycles = handle.with_vm(|devola| devola.cycles());
+        thread::sleep(SLICE * 3);
+        // nothing runs while paused
+        assert_eq!(handle.with_vm(|devola| devola.cycles()), cycles);
+        handle.with_vm(|devola| devola.poke(0x0300, 7));
+        handle.resume();
+        thread::sleep(SLICE * 3);
+        assert!(handle.with_vm(|devola| devola.cycles()) > cycles);
+        assert_eq!(handle.with_vm(|devola| devola.peek(0x0300)), 7);
+        assert!(handle.events().try_recv().is_err());
+
+        let handle = DevolaHandle::spawn(program("lda 1\ninc"), 1_000_000);
+        assert_eq!(handle.events().recv_timeout(Duration::from_secs(5)), Ok(DevolaEvent::Ended));
+        let handle = DevolaHandle::spawn(program("lda 1\ninc"), u64::MAX);
+        assert_eq!(handle.events().recv_timeout(Duration::from_secs(5)), Ok(DevolaEvent::Ended));
+        let handle = DevolaHandle::spawn(program("ldx ffh\nldy ffh\nlda xy+16"), 1_000_000);
+        assert!(matches!(handle.events().recv_timeout(Duration::from_secs(5)), Ok(DevolaEvent::Halted(_))));
+    }
+}
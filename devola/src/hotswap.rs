@@ -0,0 +1,109 @@
+//! The messages `devola-asm watch` sends a running frontend to swap in a re-assembled program. A message is
+//! the program's ROM image, with its data and entry point, then its symbols as written by `export_symbols`, each
+//! prefixed with its length as a little-endian `u32`.
+use crate::binary::{DecodeError, EncodeError, RomImage};
+use crate::parser::intermediate::{export_symbols, import_symbols, SymbolTable};
+
+/// The longest message accepted, far more than any program and its symbols need. One said to run on longer is
+/// dropped as corrupt.
+const MESSAGE_SIZE: usize = 0x100_0000;
+
+pub fn encode(image: &RomImage, symbols: &SymbolTable) -> Result<Vec<u8>, EncodeError> {
+    let mut message = Vec::new();
+    for section in [image.encode()?, export_symbols(symbols).into_bytes()] {
+        message.extend_from_slice(&(section.len() as u32).to_le_bytes());
+        message.extend_from_slice(&section);
+    }
+    Ok(message)
+}
+
+/// Collects the bytes of messages as they arrive, and splits them up
+#[derive(Default)]
+pub struct Messages {
+    buffer: Vec<u8>
+}
+
+impl Messages {
+    pub fn push(&mut self, bytes: &[u8]) {
+        // a whole message always fits, so anything past the limit would only be dropped
+        let room = MESSAGE_SIZE.saturating_sub(self.buffer.len());
+        self.buffer.extend_from_slice(&bytes[..bytes.len().min(room)]);
+    }
+
+    /// The end of the length-prefixed section at `offset`, once its length has arrived
+    fn section_end(&self, offset: usize) -> Option<usize> {
+        let length = u32::from_le_bytes(self.buffer.get(offset..offset + 4)?.try_into().ok()?) as usize;
+        Some(offset + 4 + length)
+    }
+
+    /// The next whole message, decoded. A message too long to accept is reported where the length that made it
+    /// so starts, and everything received is dropped.
+    pub fn next_message(&mut self) -> Option<Result<(RomImage, SymbolTable), DecodeError>> {
+        let symbols_offset = self.section_end(0)?;
+        if symbols_offset > MESSAGE_SIZE {
+            self.buffer.clear();
+            return Some(Err(DecodeError::InvalidByte(0)));
+        }
+        let length = self.section_end(symbols_offset)?;
+        if length > MESSAGE_SIZE {
+            self.buffer.clear();
+            return Some(Err(DecodeError::InvalidByte(symbols_offset)));
+        }
+        let (program, symbols) = (self.buffer.get(4..symbols_offset)?, self.buffer.get(symbols_offset + 4..length)?);
+        let decoded = RomImage::decode(program).and_then(|image| {
+            // symbols that can't be read are reported where their section starts
+            let symbols = std::str::from_utf8(symbols).ok().and_then(import_symbols)
+                .ok_or(DecodeError::InvalidByte(symbols_offset))?;
+            Ok((image, symbols))
+        });
+        self.buffer.drain(..length);
+        Some(decoded)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::binary::DataSegment;
+    use crate::parser::text::compile;
+
+    #[test]
+    fn test_messages() {
+        let (code, symbols, _, segments) = compile(String::from("loop:\ninc\njmp loop\n.org 0200h\n.db 1, 2"), None).unwrap();
+        let image = RomImage { entry_point: 1, segments, ..RomImage::new(code.clone()) };
+        let message = encode(&image, &symbols).unwrap();
+        let mut messages = Messages::default();
+        messages.push(&message[..10]);
+        assert!(messages.next_message().is_none());
+        messages.push(&message[10..]);
+        messages.push(&message);
+        for _ in 0..2 {
+            let (decoded, decoded_symbols) = messages.next_message().unwrap().unwrap();
+            assert_eq!(format!("{:?}", decoded.code), format!("{:?}", code));
+            assert_eq!((decoded.entry_point, decoded.segments), (1, vec![DataSegment { address: 0x0200, bytes: vec![1, 2] }]));
+            assert_eq!(decoded_symbols, symbols);
+        }
+        assert!(messages.next_message().is_none());
+
+        // a label without a location
+        let program = image.encode().unwrap();
+        let mut messages = Messages::default();
+        messages.push(&(program.len() as u32).to_le_bytes());
+        messages.push(&program);
+        messages.push(&4u32.to_le_bytes());
+        messages.push(b"loop");
+        assert_eq!(messages.next_message().unwrap().unwrap_err(), DecodeError::InvalidByte(4 + program.len()));
+        assert!(messages.next_message().is_none());
+
+        // a message said to be too long is dropped without waiting for it, and what's sent after it isn't kept
+        messages.push(&(program.len() as u32).to_le_bytes());
+        messages.push(&program);
+        messages.push(&(MESSAGE_SIZE as u32).to_le_bytes());
+        assert_eq!(messages.next_message().unwrap().unwrap_err(), DecodeError::InvalidByte(4 + program.len()));
+        messages.push(&u32::MAX.to_le_bytes());
+        messages.push(&vec![0; 2*MESSAGE_SIZE]);
+        assert_eq!(messages.buffer.len(), MESSAGE_SIZE);
+        assert_eq!(messages.next_message().unwrap().unwrap_err(), DecodeError::InvalidByte(0));
+        assert!(messages.buffer.is_empty());
+    }
+}
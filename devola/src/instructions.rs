@@ -1,14 +1,20 @@
 #[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub enum Flag {
     Carry, Zero, Parity, Sign
 }
 
 #[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub enum Register {
     Accumulator, IndexX, IndexY, UtilityB, UtilityC
 }
 
 #[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub enum AddressingMode {
     /// The byte stored in the corresponding register
     Register(Register),
@@ -18,23 +24,31 @@ pub enum AddressingMode {
     Indirect(u16),
     /// The byte located at X:Y
     Index,
-    /// An offset from the index register
-    IndexOffset(u16)
+    /// A signed offset from the index register, such as `XY+2` or `XY-3`
+    IndexOffset(i16)
 }
 
 #[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub enum JumpType {
     Unconditional,
-    Flag(Flag, bool)
+    Flag(Flag, bool),
+    /// Compares the argument with the byte, as `cmp` would the accumulator, and jumps if they differ
+    CompareNotEqual(AddressingMode, u8)
 }
 
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub enum CallType {
     Local(usize),
     Library(String)
 }
 
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub enum Instruction {
     /// `ld[Ra] [Rb | N | I | XY]`
     /// - For `Rb`: Sets `Ra` to the value of `Rb`
@@ -91,14 +105,217 @@ pub enum Instruction {
     /// - `P` sets if `A % 2 == x % 2`, resets otherwise
     /// - `C` sets if `A < x`, resets if `A >= x`
     Compare(AddressingMode),
+    /// `jmp [label]`, `j[F] [label]`, `jn[F] [label]`, `cjne [Rb | N | I | XY], [N], [label]`
+    /// - For `jmp`: Jumps to `label`
+    /// - For `j[F]`: Jumps to `label` if the flag `F` is set
+    /// - For `jn[F]`: Jumps to `label` if the flag `F` is unset
+    /// - For `cjne`: Compares the argument with `N`, then jumps to `label` if they aren't equal
+    ///
+    /// **Flags affected:** None, except by `cjne`, which sets them as `cmp` would with the argument in place of `A`
     Jump(JumpType, usize),
+    /// `call [label]`
+    /// - Pushes the program counter to the stack and jumps to `label`, or calls the library function `label`
+    ///
+    /// **Flags affected:** None
     Call(CallType),
+    /// `ret`
+    /// - Pops the program counter from the stack, returning to the instruction after the `call`
+    ///
+    /// **Flags affected:** None
     Return,
-    Push(Register), Pop(Register),
-    AddXY(AddressingMode), SubtractXY(AddressingMode),
+    /// `reti`
+    /// - Returns from an interrupt handler, restoring the flags saved when the interrupt was taken
+    ReturnInterrupt,
+    /// `push [Rb]`
+    /// - Decrements the stack pointer and stores the contents of `Rb` at it
+    ///
+    /// **Flags affected:** None
+    Push(Register),
+    /// `pop [Ra]`
+    /// - Sets `Ra` to the byte at the stack pointer and increments the stack pointer
+    ///
+    /// **Flags affected:** None
+    Pop(Register),
+    /// `adxy [Rb | N | I | XY]`
+    /// - Adds the argument to the 16-bit value of `XY`
+    ///
+    /// **Flags affected:**
+    /// - `Z` sets if `XY` is now `0`, resets otherwise
+    /// - `P` sets if the least significant bit of `Y` is now `1`, resets otherwise
+    /// - `C` sets if the addition results in a carry
+    AddXY(AddressingMode),
+    /// `sbxy [Rb | N | I | XY]`
+    ///
+    /// Subtracts the argument from `XY`. See documentation for `Instruction::AddXY`.
+    SubtractXY(AddressingMode),
+    /// `nop`
+    /// - Does nothing; labels compile to these
     Nop,
+    /// `hlt`
+    /// - Stops fetching instructions until an interrupt is taken, while cycles carry on passing. Execution
+    ///   continues after the `hlt` once the handler returns. With no interrupt to wake it, it waits forever.
+    ///
+    /// **Flags affected:** None
+    Halt,
+    /// `hlt [N]`
+    /// - Stops the program for good with the exit code `N`, e.g. to tell a test runner whether it passed. `0` is
+    ///   success, as when the end of the code is reached. Execution never moves past it.
+    ///
+    /// **Flags affected:** None
+    Exit(u8),
     _Label(String),
     _Assert(AddressingMode, u8),
     _LabeledJump(JumpType, String),
     _LabeledCall(String)
-}
\ No newline at end of file
+}
+
+impl AddressingMode {
+    /// The number of additional cycles taken to resolve the operand
+    pub fn cycles(&self) -> u64 {
+        match self {
+            AddressingMode::Register(_) => 0,
+            AddressingMode::Immediate(_) => 1,
+            AddressingMode::Indirect(_) => 3,
+            AddressingMode::Index => 2,
+            AddressingMode::IndexOffset(_) => 3
+        }
+    }
+}
+
+impl Instruction {
+    /// The number of cycles taken to execute the instruction. Pseudo-instructions that are
+    /// removed during label processing take no cycles.
+    pub fn cycles(&self) -> u64 {
+        match self {
+            Instruction::Load(_, addressing_mode)
+            | Instruction::Add(addressing_mode)
+            | Instruction::Subtract(addressing_mode)
+            | Instruction::Compare(addressing_mode) => 1 + addressing_mode.cycles(),
+            Instruction::Store(_, addressing_mode) => 2 + addressing_mode.cycles(),
+            Instruction::AddXY(addressing_mode)
+            | Instruction::SubtractXY(addressing_mode) => 2 + addressing_mode.cycles(),
+            Instruction::Increment | Instruction::Decrement => 1,
+            // the comparison takes a cycle and the byte's, on top of the argument's
+            Instruction::Jump(JumpType::CompareNotEqual(addressing_mode, _), _) => 4 + addressing_mode.cycles(),
+            Instruction::Jump(_, _) => 3,
+            Instruction::Call(_) | Instruction::Return => 5,
+            Instruction::ReturnInterrupt => 6,
+            Instruction::Push(_) => 2,
+            Instruction::Pop(_) => 3,
+            Instruction::Nop | Instruction::Halt | Instruction::Exit(_) => 1,
+            Instruction::_Label(_) | Instruction::_Assert(_, _)
+            | Instruction::_LabeledJump(_, _) | Instruction::_LabeledCall(_) => 0
+        }
+    }
+}
+
+impl std::fmt::Display for Register {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Register::Accumulator => "a",
+            Register::IndexX => "x",
+            Register::IndexY => "y",
+            Register::UtilityB => "b",
+            Register::UtilityC => "c"
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl std::fmt::Display for Flag {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Flag::Carry => "c",
+            Flag::Zero => "z",
+            Flag::Parity => "p",
+            Flag::Sign => "s"
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Written as the operand of an instruction. Numbers are decimal, which the parser can't mistake for a register.
+impl std::fmt::Display for AddressingMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AddressingMode::Register(register) => write!(f, "{}", register),
+            AddressingMode::Immediate(value) => write!(f, "{}", value),
+            AddressingMode::Indirect(address) => write!(f, "#{}", address),
+            AddressingMode::Index => write!(f, "xy"),
+            AddressingMode::IndexOffset(offset) if *offset < 0 => write!(f, "xy-{}", offset.unsigned_abs()),
+            AddressingMode::IndexOffset(offset) => write!(f, "xy+{}", offset)
+        }
+    }
+}
+
+/// Written as the mnemonic of a jump with this condition
+impl std::fmt::Display for JumpType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            JumpType::Unconditional => write!(f, "jmp"),
+            JumpType::Flag(flag, true) => write!(f, "j{}", flag),
+            JumpType::Flag(flag, false) => write!(f, "jn{}", flag),
+            // followed by the label like the rest
+            JumpType::CompareNotEqual(source, value) => write!(f, "cjne {}, {},", source, value)
+        }
+    }
+}
+
+/// Written as a line of assembly. Compiled jumps and calls have lost their labels, so their targets are
+/// written as `l<location>`, the name `disasm` gives them.
+impl std::fmt::Display for Instruction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Instruction::Load(target, source) => write!(f, "ld{} {}", target, source),
+            Instruction::Store(source, target) => write!(f, "st{} {}", source, target),
+            Instruction::Increment => write!(f, "inc"),
+            Instruction::Decrement => write!(f, "dec"),
+            Instruction::Add(source) => write!(f, "add {}", source),
+            Instruction::Subtract(source) => write!(f, "sub {}", source),
+            Instruction::Compare(source) => write!(f, "cmp {}", source),
+            Instruction::AddXY(source) => write!(f, "adxy {}", source),
+            Instruction::SubtractXY(source) => write!(f, "sbxy {}", source),
+            Instruction::Jump(jump_type, target) => write!(f, "{} l{}", jump_type, target),
+            Instruction::Call(CallType::Local(target)) => write!(f, "call l{}", target),
+            Instruction::Call(CallType::Library(name)) => write!(f, "call {}", name),
+            Instruction::Return => write!(f, "ret"),
+            Instruction::ReturnInterrupt => write!(f, "reti"),
+            Instruction::Push(source) => write!(f, "push {}", source),
+            Instruction::Pop(target) => write!(f, "pop {}", target),
+            Instruction::Nop => write!(f, "nop"),
+            Instruction::Halt => write!(f, "hlt"),
+            Instruction::Exit(code) => write!(f, "hlt {}", code),
+            Instruction::_Label(label) => write!(f, "{}:", label),
+            // assertions only exist in code built by hand, so there's no syntax for them
+            Instruction::_Assert(source, expected) => write!(f, "; assert {} == {}", source, expected),
+            Instruction::_LabeledJump(jump_type, label) => write!(f, "{} {}", jump_type, label),
+            Instruction::_LabeledCall(label) => write!(f, "call {}", label)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_display() {
+        assert_eq!(Register::UtilityC.to_string(), "c");
+        assert_eq!(AddressingMode::IndexOffset(16).to_string(), "xy+16");
+        assert_eq!(AddressingMode::IndexOffset(i16::MIN).to_string(), "xy-32768");
+        assert_eq!(JumpType::Flag(Flag::Sign, false).to_string(), "jns");
+
+        let code = [
+            Instruction::Load(Register::Accumulator, AddressingMode::Indirect(0x0F61)),
+            Instruction::Store(Register::IndexX, AddressingMode::Index),
+            Instruction::Compare(AddressingMode::Immediate(255)),
+            Instruction::Jump(JumpType::Flag(Flag::Zero, true), 7),
+            Instruction::Jump(JumpType::CompareNotEqual(AddressingMode::Register(Register::UtilityB), 10), 2),
+            Instruction::Call(CallType::Library(String::from("memcpy"))),
+            Instruction::_Label(String::from("loop")),
+            Instruction::_LabeledJump(JumpType::Unconditional, String::from("loop"))
+        ];
+        let lines: Vec<String> = code.iter().map(Instruction::to_string).collect();
+        assert_eq!(lines, ["lda #3937", "stx xy", "cmp 255", "jz l7", "cjne b, 10, l2", "call memcpy", "loop:", "jmp loop"]);
+    }
+}
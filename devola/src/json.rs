@@ -0,0 +1,154 @@
+//! A JSON encoding of compiled programs, for tools that would rather not read or write the binary format.
+//!
+//! A program is an object with `"format": "popr"`, a `"version"` (currently 1), and `"code"`, the list of
+//! instructions. `"symbols"`, mapping code locations to labels, and `"lines"`, the zero-based source line of
+//! each instruction, are optional, as is `"segments"`, the data copied into memory before the program starts, as a
+//! list of objects with an `"address"` and its `"bytes"`. Instructions are written the way serde writes enums: a unit variant is its
+//! name, and any other variant is an object with its name as the only key, e.g.
+//!
+//! ```json
+//! {
+//!     "format": "popr",
+//!     "version": 1,
+//!     "code": [
+//!         { "Load": ["Accumulator", { "Immediate": 3 }] },
+//!         "Decrement",
+//!         { "Jump": [{ "Flag": ["Zero", false] }, 1] },
+//!         { "Call": { "Library": "memcpy" } }
+//!     ],
+//!     "symbols": { "1": "loop" },
+//!     "lines": [0, 2, 3, 4]
+//! }
+//! ```
+use crate::binary::DataSegment;
+use crate::instructions::Instruction;
+use crate::parser::intermediate::{LineTable, SymbolTable};
+use crate::vm::Devola;
+use serde::{Deserialize, Serialize};
+
+const FORMAT: &str = "popr";
+const VERSION: u8 = 1;
+
+#[derive(Debug)]
+pub enum DecodeError {
+    /// The data isn't JSON in the expected shape
+    Invalid(serde_json::Error),
+    /// The `format` field is missing or isn't `popr`
+    NotAProgram,
+    UnsupportedVersion(u8)
+}
+
+impl std::fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DecodeError::Invalid(error) => write!(f, "invalid program: {}", error),
+            DecodeError::NotAProgram => write!(f, "not an encoded program"),
+            DecodeError::UnsupportedVersion(version) => write!(f, "unsupported version {}", version)
+        }
+    }
+}
+
+/// A compiled program, with whatever was kept of its source
+#[derive(Debug)]
+pub struct Program {
+    pub code: Vec<Instruction>,
+    pub symbols: Option<SymbolTable>,
+    pub lines: Option<LineTable>,
+    pub segments: Vec<DataSegment>
+}
+
+impl Program {
+    /// Creates a VM running the program, which can name the labels and lines of its faults if it has them
+    pub fn load(self) -> Devola {
+        let mut devola = Devola::with_data(self.code, self.symbols, &self.segments);
+        if let Some(lines) = self.lines {
+            devola.set_line_table(lines);
+        }
+        devola
+    }
+}
+
+// `#[serde(flatten)]` can't be used to share `Program`'s fields, as it loses track of the symbol table's numeric keys
+#[derive(Serialize, Deserialize)]
+struct Document {
+    #[serde(default)]
+    format: String,
+    version: u8,
+    code: Vec<Instruction>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    symbols: Option<SymbolTable>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    lines: Option<LineTable>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    segments: Vec<DataSegment>
+}
+
+pub fn encode(program: Program) -> String {
+    let document = Document {
+        format: String::from(FORMAT),
+        version: VERSION,
+        code: program.code,
+        symbols: program.symbols,
+        lines: program.lines,
+        segments: program.segments
+    };
+    serde_json::to_string_pretty(&document).unwrap()
+}
+
+/// Decodes a program written by `encode`, or by another tool following the format
+pub fn decode(json: &str) -> Result<Program, DecodeError> {
+    let document: Document = serde_json::from_str(json).map_err(DecodeError::Invalid)?;
+    if document.format != FORMAT {
+        return Err(DecodeError::NotAProgram);
+    }
+    if document.version != VERSION {
+        return Err(DecodeError::UnsupportedVersion(document.version));
+    }
+    Ok(Program { code: document.code, symbols: document.symbols, lines: document.lines, segments: document.segments })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instructions::{AddressingMode, Register};
+    use crate::parser::text::compile;
+    use crate::vm::DevolaState;
+
+    #[test]
+    fn test_json() {
+        let source = crate::util::read_from_file(std::path::Path::new("sample/square_subroutines.pop"));
+        let (code, symbols, lines, _) = compile(source, None).unwrap();
+        let segments = vec![DataSegment { address: 0x0300, bytes: vec![1, 2] }];
+        let json = encode(Program { code: code.clone(), symbols: Some(symbols.clone()), lines: Some(lines.clone()), segments: segments.clone() });
+        let program = decode(&json).unwrap();
+        // instructions don't implement PartialEq, so they're compared by their debug output
+        assert_eq!(format!("{:?}", program.code), format!("{:?}", code));
+        assert_eq!(program.symbols, Some(symbols));
+        assert_eq!(program.lines, Some(lines));
+        assert_eq!(program.segments, segments);
+        let mut devola = program.load();
+        assert_eq!(devola.peek(0x0301), 2);
+        devola.run().unwrap();
+
+        let program = decode(r#"{"format": "popr", "version": 1, "code": [{"Load": ["Accumulator", {"Immediate": 3}]}, "Increment"]}"#).unwrap();
+        assert_eq!(format!("{:?}", program.code), format!("{:?}", [
+            Instruction::Load(Register::Accumulator, AddressingMode::Immediate(3)), Instruction::Increment
+        ]));
+        let mut devola = program.load();
+        devola.run().unwrap();
+        assert_eq!(devola.register(Register::Accumulator), 4);
+
+        // a segment running past the end of memory wraps around to the start
+        let program = Program { code: Vec::new(), symbols: None, lines: None, segments: vec![DataSegment { address: 0xFFFF, bytes: vec![1, 2] }] };
+        let wrapped = decode(&encode(program)).unwrap().load();
+        assert_eq!((wrapped.peek(0xFFFF), wrapped.peek(0x0000)), (1, 2));
+
+        let state = devola.save_state();
+        let restored: DevolaState = serde_json::from_str(&serde_json::to_string(&state).unwrap()).unwrap();
+        assert_eq!(restored, state);
+
+        assert!(matches!(decode(r#"{"version": 1, "code": []}"#), Err(DecodeError::NotAProgram)));
+        assert!(matches!(decode(r#"{"format": "popr", "version": 2, "code": []}"#), Err(DecodeError::UnsupportedVersion(2))));
+        assert!(matches!(decode(r#"{"format": "popr", "version": 1, "code": ["Jump"]}"#), Err(DecodeError::Invalid(_))));
+    }
+}
@@ -1,6 +1,38 @@
 pub mod instructions;
 pub mod vm;
-mod parser;
+pub mod parser;
+pub mod binary;
+pub mod disasm;
+pub mod debugger;
+pub mod gdb;
+pub mod conformance;
+pub mod hotswap;
+pub mod cartridge;
+pub mod coverage;
+pub mod stats;
+pub mod dump;
+pub mod events;
+// there are no threads to run it on in the browser
+#[cfg(not(target_arch = "wasm32"))]
+pub mod handle;
+#[cfg(feature = "dap")]
+pub mod dap;
+#[cfg(feature = "lsp")]
+pub mod lsp;
+#[cfg(any(feature = "dap", feature = "lsp"))]
+pub mod framing;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+#[cfg(feature = "serde")]
+pub mod json;
+#[cfg(feature = "serde")]
+pub mod recorder;
+#[cfg(feature = "arbitrary")]
+pub mod fuzz;
+#[cfg(feature = "trace")]
+pub mod trace;
 mod util;
 pub mod stdlib;
 
@@ -0,0 +1,348 @@
+//! A language server for `.pop` files: diagnostics from the assembler, go-to-definition for labels, hover
+//! documentation for instructions, and completion. Messages are handled here without any I/O; `devola-lsp` carries
+//! them over stdin and stdout, framed by `crate::framing`.
+use crate::parser::text::compile;
+use crate::vm::{INTERRUPT_ENABLE, INTERRUPT_REQUEST, INTERRUPT_STACK_LSB, INTERRUPT_STACK_MSB, STACK_POINTER_LSB, STACK_POINTER_MSB};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+
+/// Hover documentation is taken from the doc comments on `Instruction`
+const INSTRUCTIONS_SOURCE: &str = include_str!("instructions.rs");
+
+const REGISTERS: &str = "abcxy";
+const FLAGS: &str = "czps";
+
+/// Named memory-mapped registers, which completion inserts as addresses. Popola's device registers mirror its
+/// `inter::mmio`.
+const MMIO_REGISTERS: &[(&str, u16, &str)] = &[
+    ("STACK_POINTER_MSB", STACK_POINTER_MSB, "Most significant byte of the stack pointer"),
+    ("STACK_POINTER_LSB", STACK_POINTER_LSB, "Least significant byte of the stack pointer"),
+    ("INTERRUPT_STACK_MSB", INTERRUPT_STACK_MSB, "Most significant byte of the stack handlers run on, or 0 for none"),
+    ("INTERRUPT_STACK_LSB", INTERRUPT_STACK_LSB, "Least significant byte of the stack handlers run on"),
+    ("INTERRUPT_ENABLE", INTERRUPT_ENABLE, "Interrupt enable mask, 1 bit per line"),
+    ("INTERRUPT_REQUEST", INTERRUPT_REQUEST, "Interrupt request flags, 1 bit per line"),
+    ("SPRITE_TOGGLES", 0x0FF2, "[all off|sprite 6-0]; all off hides every sprite, and sprite 1-127 flips that sprite's enabled bit at vblank"),
+    ("BG_SETTINGS", 0x0FF3, "[enable|tilemap|palette 2-0|bg 1-0|set]; set copies the bits to the background's control at vblank"),
+    ("BG_CONTROL", 0x0F00, "1 byte per background [enable|tilemap|unused|palette 2-0|priority 1-0]"),
+    ("VIDEO_STATUS", 0x0F04, "[unused 3-0|bg collision|sprite collision|sprite overflow|vblank]"),
+    ("FADE", 0x0F05, "0-15, darkens the final image by 16ths"),
+    ("HDMA_CONTROL", 0x0F06, "Enables the scanline register table"),
+    ("HDMA_TABLE", 0x0F07, "2 bytes (MSB first), the address of the scanline register table"),
+    ("BG_SCROLL", 0x0F10, "2 bytes per background (x then y)"),
+    ("WINDOW_START", 0x0F18, "4 bytes per window [left, top, right, bottom]"),
+    ("BG_WINDOW_MASK", 0x0F20, "1 byte per background [unused 4-0|invert|window 1|window 0]"),
+    ("SPRITE_WINDOW_MASK", 0x0F24, "The window mask for all sprites"),
+    ("BACKDROP_COLOR", 0x0F25, "2 bytes (MSB first), the color shown where nothing is drawn"),
+    ("BG_MAP_SIZE", 0x0F27, "1 byte per background [unused 5-0|tall|wide]"),
+    ("BG_SCROLL_HIGH", 0x0F2B, "1 byte per background [unused 5-0|y 8|x 8]"),
+    ("BG_MAP_ADDRESS", 0x0F2F, "2 bytes per background (MSB first), where the map is read from"),
+    ("VIDEO_CONTROL", 0x0F37, "[unused 5-0|present|manual]"),
+    ("COLOR_MATH", 0x0F38, "[enable|subtract|half|unused 1-0|layer 2-0]"),
+    ("VIDEO_MODE", 0x0F39, "[unused|affine 2-0|unused 1-0|mode 1-0]"),
+    ("JOYPAD", 0x0F3A, "[start|select|b|a|right|left|down|up]"),
+    ("SQUARE_START", 0x0F40, "4 bytes per square channel [period LSB, control, envelope, length]"),
+    ("AUDIO_STATUS", 0x0F48, "[unused 3-0|wave|noise|square 1|square 0]"),
+    ("NOISE_START", 0x0F49, "[period, control, envelope, length]"),
+    ("WAVE_START", 0x0F4D, "[period LSB, control, volume, length]"),
+    ("WAVE_TABLE", 0x0F51, "16 bytes, 32 4-bit samples with the high nibble first"),
+    ("SERIAL_OUT", 0x0F61, "The byte to send"),
+    ("SERIAL_IN", 0x0F62, "The last byte received"),
+    ("SERIAL_STATUS", 0x0F63, "[send|unused 5-0|received]"),
+    ("FRAME_COUNTER", 0x0F64, "2 bytes (MSB first), counts up as each frame starts"),
+    ("VBLANK_COUNT", 0x0F66, "Counts up as each vblank begins, up to 255, until written to"),
+    ("TEXT_OUT", 0x0F67, "Write a character to print it on the text console"),
+    ("SPRITE_ANIMATION", 0x0F68, "2 bytes (MSB first), the address of the sprite animation table; 0 turns it off"),
+    ("PALETTE_START", 0x6000, "The palettes, at the start of VRAM"),
+    ("TILEMAP_START", 0x6100, "The tilemaps"),
+    ("BG_START", 0xE100, "The background maps"),
+    ("SPRITE_START", 0xFD00, "The sprites"),
+    ("AFFINE_START", 0xFF80, "The affine parameters")
+];
+
+// LSP constants
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const SEVERITY_ERROR: u64 = 1;
+const KIND_VARIABLE: u64 = 6;
+const KIND_KEYWORD: u64 = 14;
+const KIND_REFERENCE: u64 = 18;
+const KIND_CONSTANT: u64 = 21;
+
+/// An instruction's documentation, and the mnemonics it covers
+struct InstructionDoc {
+    mnemonics: Vec<String>,
+    documentation: String
+}
+
+/// Replaces the placeholder in a mnemonic, e.g. `ld[Ra]`, with each register or flag it could be
+fn expand(mnemonic: &str) -> Vec<String> {
+    for (placeholder, letters) in [("[Ra]", REGISTERS), ("[Rb]", REGISTERS), ("[F]", FLAGS)] {
+        if mnemonic.contains(placeholder) {
+            return letters.chars().map(|letter| mnemonic.replacen(placeholder, &letter.to_string(), 1)).collect();
+        }
+    }
+    vec![mnemonic.to_string()]
+}
+
+/// Reads the documented variants of `Instruction`, whose docs start with the syntax of each form in backticks
+fn instruction_docs() -> Vec<InstructionDoc> {
+    let body = INSTRUCTIONS_SOURCE.split("pub enum Instruction {").nth(1).unwrap_or_default();
+    let body = body.split("\n}").next().unwrap_or_default();
+    let mut docs = Vec::new();
+    let mut comment: Vec<&str> = Vec::new();
+    for line in body.lines().map(str::trim) {
+        if let Some(text) = line.strip_prefix("///") {
+            comment.push(text.strip_prefix(' ').unwrap_or(text));
+        } else if !line.is_empty() {
+            if let Some(syntax) = comment.first() {
+                let mnemonics = syntax.split('`').skip(1).step_by(2)
+                    .filter_map(|form| form.split(' ').next())
+                    .flat_map(expand)
+                    .collect();
+                docs.push(InstructionDoc { mnemonics, documentation: comment.join("\n") });
+            }
+            comment.clear();
+        }
+    }
+    docs
+}
+
+/// The labels defined in a document, with the zero-based line and column of each
+fn labels(text: &str) -> HashMap<&str, (usize, usize)> {
+    let mut labels = HashMap::new();
+    for (line, content) in text.lines().enumerate() {
+        let code = content.split(';').next().unwrap_or_default();
+        let Some(label) = code.trim().strip_suffix(':') else {
+            continue;
+        };
+        if label.starts_with(|c: char| c.is_ascii_alphabetic()) && label.chars().all(|c| c.is_alphanumeric() || c == '_') {
+            labels.entry(label).or_insert((line, code.find(label).unwrap_or_default()));
+        }
+    }
+    labels
+}
+
+/// The word at a zero-based position in a document
+fn word_at(text: &str, line: usize, character: usize) -> Option<&str> {
+    let content = text.lines().nth(line)?;
+    let character = character.min(content.len());
+    if !content.is_char_boundary(character) {
+        return None;
+    }
+    let is_word = |c: char| c.is_alphanumeric() || c == '_';
+    let start = content[..character].rfind(|c: char| !is_word(c)).map_or(0, |index| index + 1);
+    let end = content[character..].find(|c: char| !is_word(c)).map_or(content.len(), |index| character + index);
+    (start < end).then(|| &content[start..end])
+}
+
+fn range(line: usize, start: usize, end: usize) -> Value {
+    json!({ "start": { "line": line, "character": start }, "end": { "line": line, "character": end } })
+}
+
+pub struct LanguageServer {
+    /// The text of each open document, by URI
+    documents: HashMap<String, String>,
+    instructions: Vec<InstructionDoc>,
+    finished: bool
+}
+
+impl Default for LanguageServer {
+    fn default() -> Self {
+        LanguageServer { documents: HashMap::new(), instructions: instruction_docs(), finished: false }
+    }
+}
+
+impl LanguageServer {
+    /// Whether the client has asked the server to exit
+    pub fn finished(&self) -> bool {
+        self.finished
+    }
+
+    /// Handles a request or notification, returning the response to a request followed by any notifications
+    pub fn handle(&mut self, message: &Value) -> Vec<Value> {
+        let method = message["method"].as_str().unwrap_or_default();
+        let params = &message["params"];
+        let uri = params["textDocument"]["uri"].as_str().unwrap_or_default().to_string();
+        let mut notifications = Vec::new();
+        let result = match method {
+            "initialize" => Ok(json!({
+                "capabilities": {
+                    // documents are always sent in full
+                    "textDocumentSync": 1,
+                    "definitionProvider": true,
+                    "hoverProvider": true,
+                    "completionProvider": {}
+                },
+                "serverInfo": { "name": "devola-lsp" }
+            })),
+            "shutdown" => Ok(Value::Null),
+            "exit" => {
+                self.finished = true;
+                Ok(Value::Null)
+            },
+            "textDocument/didOpen" | "textDocument/didChange" => {
+                let text = match method {
+                    "textDocument/didOpen" => &params["textDocument"]["text"],
+                    _ => params["contentChanges"].as_array().and_then(|changes| changes.last())
+                        .map_or(&Value::Null, |change| &change["text"])
+                };
+                if let Some(text) = text.as_str() {
+                    self.documents.insert(uri.clone(), text.to_string());
+                    notifications.push(self.diagnostics(&uri));
+                }
+                Ok(Value::Null)
+            },
+            "textDocument/didClose" => {
+                self.documents.remove(&uri);
+                notifications.push(self.diagnostics(&uri));
+                Ok(Value::Null)
+            },
+            "textDocument/definition" => self.word(&uri, params).map(|(text, word)| {
+                match labels(text).get(word) {
+                    Some(&(line, column)) => json!({ "uri": uri, "range": range(line, column, column + word.len()) }),
+                    None => Value::Null
+                }
+            }),
+            "textDocument/hover" => self.word(&uri, params).map(|(text, word)| self.hover(text, word)),
+            "textDocument/completion" => self.word(&uri, params).map(|(text, _)| self.completion(text))
+                .or_else(|_| Ok(self.completion(""))),
+            _ => Err((METHOD_NOT_FOUND, format!("unsupported method {}", method)))
+        };
+
+        let mut messages = Vec::new();
+        // notifications don't get a response
+        if !message["id"].is_null() {
+            messages.push(match result {
+                Ok(result) => json!({ "jsonrpc": "2.0", "id": message["id"], "result": result }),
+                Err((code, error)) => json!({
+                    "jsonrpc": "2.0", "id": message["id"], "error": { "code": code, "message": error }
+                })
+            });
+        }
+        messages.extend(notifications);
+        messages
+    }
+
+    /// The text of a document and the word at the position given in a request
+    fn word<'a>(&'a self, uri: &str, params: &Value) -> Result<(&'a str, &'a str), (i64, String)> {
+        let text = self.documents.get(uri).ok_or_else(|| (INVALID_PARAMS, format!("{} isn't open", uri)))?;
+        let line = params["position"]["line"].as_u64().unwrap_or_default() as usize;
+        let character = params["position"]["character"].as_u64().unwrap_or_default() as usize;
+        Ok((text, word_at(text, line, character).unwrap_or_default()))
+    }
+
+    /// Publishes the errors from assembling a document, or clears them once it's closed
+    fn diagnostics(&self, uri: &str) -> Value {
+        let errors = match self.documents.get(uri) {
+            Some(text) => compile(text.clone(), None).err().unwrap_or_default(),
+            None => Vec::new()
+        };
+        let diagnostics: Vec<Value> = errors.iter().map(|error| {
+            let length = self.documents[uri].lines().nth(error.line()).map_or(0, str::len);
+            json!({
+                "range": range(error.line(), 0, length),
+                "severity": SEVERITY_ERROR,
+                "source": "devola",
+                "message": error.description()
+            })
+        }).collect();
+        json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/publishDiagnostics",
+            "params": { "uri": uri, "diagnostics": diagnostics }
+        })
+    }
+
+    fn hover(&self, text: &str, word: &str) -> Value {
+        let lowercase = word.to_ascii_lowercase();
+        let documentation = match self.instructions.iter().find(|doc| doc.mnemonics.contains(&lowercase)) {
+            Some(doc) => doc.documentation.clone(),
+            None => match labels(text).get(word) {
+                Some(&(line, _)) => format!("`{}`: label on line {}", word, line + 1),
+                None => return Value::Null
+            }
+        };
+        json!({ "contents": { "kind": "markdown", "value": documentation } })
+    }
+
+    fn completion(&self, text: &str) -> Value {
+        let mut items: Vec<Value> = self.instructions.iter()
+            .flat_map(|doc| doc.mnemonics.iter().map(move |mnemonic| json!({
+                "label": mnemonic,
+                "kind": KIND_KEYWORD,
+                "documentation": { "kind": "markdown", "value": doc.documentation }
+            })))
+            .collect();
+        items.extend(REGISTERS.chars().map(|register| json!({
+            "label": register.to_string(), "kind": KIND_VARIABLE, "detail": "register"
+        })));
+        items.extend(MMIO_REGISTERS.iter().map(|&(name, address, description)| json!({
+            "label": name,
+            "kind": KIND_CONSTANT,
+            "detail": format!("{:04X}h: {}", address, description),
+            "insertText": format!("#{:04X}h", address)
+        })));
+        items.extend(labels(text).into_keys().map(|label| json!({ "label": label, "kind": KIND_REFERENCE })));
+        json!(items)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const URI: &str = "file:///game.pop";
+
+    fn request(server: &mut LanguageServer, method: &str, params: Value) -> Value {
+        server.handle(&json!({ "jsonrpc": "2.0", "id": 1, "method": method, "params": params })).remove(0)
+    }
+
+    fn position(line: usize, character: usize) -> Value {
+        json!({ "textDocument": { "uri": URI }, "position": { "line": line, "character": character } })
+    }
+
+    #[test]
+    fn test_instruction_docs() {
+        let docs = instruction_docs();
+        let find = |mnemonic: &str| docs.iter().find(|doc| doc.mnemonics.iter().any(|m| m == mnemonic));
+        assert!(find("ldy").unwrap().documentation.starts_with("`ld[Ra] [Rb | N | I | XY]`"));
+        assert!(find("jnz").unwrap().documentation.contains("unset"));
+        for mnemonic in ["sta", "inc", "jmp", "jc", "call", "ret", "reti", "push", "pop", "adxy", "sbxy", "nop", "hlt"] {
+            assert!(find(mnemonic).is_some(), "{} isn't documented", mnemonic);
+        }
+    }
+
+    #[test]
+    fn test_language_server() {
+        let mut server = LanguageServer::default();
+        assert_eq!(request(&mut server, "initialize", json!({}))["result"]["capabilities"]["hoverProvider"], true);
+
+        let opened = server.handle(&json!({ "jsonrpc": "2.0", "method": "textDocument/didOpen", "params": {
+            "textDocument": { "uri": URI, "text": "loop:\n    lda #0F3Ah\n    ldq 1\n    jmp loop ; forever" }
+        } }));
+        assert_eq!(opened.len(), 1);
+        assert_eq!(opened[0]["params"]["diagnostics"], json!([{
+            "range": range(2, 0, 9), "severity": SEVERITY_ERROR, "source": "devola", "message": "InvalidInstruction (ldq 1)"
+        }]));
+
+        assert_eq!(request(&mut server, "textDocument/definition", position(3, 11))["result"], json!({
+            "uri": URI, "range": range(0, 0, 4)
+        }));
+        assert_eq!(request(&mut server, "textDocument/definition", position(3, 5))["result"], Value::Null);
+        let hover = request(&mut server, "textDocument/hover", position(1, 6));
+        assert!(hover["result"]["contents"]["value"].as_str().unwrap().starts_with("`ld[Ra]"));
+
+        let completion = request(&mut server, "textDocument/completion", position(1, 0));
+        let items = completion["result"].as_array().unwrap();
+        assert!(items.iter().any(|item| item["label"] == "JOYPAD" && item["insertText"] == "#0F3Ah"));
+        assert!(items.iter().any(|item| item["label"] == "loop"));
+
+        let changed = server.handle(&json!({ "jsonrpc": "2.0", "method": "textDocument/didChange", "params": {
+            "textDocument": { "uri": URI }, "contentChanges": [{ "text": "lda 1" }]
+        } }));
+        assert_eq!(changed[0]["params"]["diagnostics"], json!([]));
+        assert_eq!(request(&mut server, "bogus", json!({}))["error"]["code"], METHOD_NOT_FOUND);
+        server.handle(&json!({ "jsonrpc": "2.0", "method": "exit" }));
+        assert!(server.finished());
+    }
+}
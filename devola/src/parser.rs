@@ -3,20 +3,62 @@ pub mod text {
     use regex::{RegexBuilder, Regex};
     use lazy_static::lazy_static;
     use crate::stdlib::interface::DevolaExternTable;
+    use crate::binary::DataSegment;
+    use crate::vm::MEMORY_SIZE;
+    use std::collections::HashMap;
+    use std::path::Path;
 
     #[derive(Debug, Copy, Clone, PartialEq)]
     pub enum ParseErrorType {
         InvalidRegister, InvalidFlag,
-        InvalidNumericLiteral, InvalidInstruction, InvalidLabel
+        InvalidNumericLiteral, InvalidInstruction, InvalidLabel, InvalidExpression, InvalidDirective
     }
 
     #[derive(Debug, Clone, PartialEq)]
     pub struct ParseError {
         error_type: ParseErrorType,
         location: usize,
-        info: Option<String>
+        info: Option<String>,
+        /// A known mnemonic or label close to one that wasn't recognised, as it's likely a typo of it
+        suggestion: Option<String>
+    }
+    pub type ParseResult = Result<(Vec<Instruction>, super::intermediate::SymbolTable, super::intermediate::LineTable, Vec<DataSegment>), Vec<ParseError>>;
+
+    impl ParseError {
+        /// An error on a zero-based line, with what it was about and no suggestion
+        pub fn new(error_type: ParseErrorType, location: usize, info: String) -> ParseError {
+            ParseError { error_type, location, info: Some(info), suggestion: None }
+        }
+
+        /// The zero-based line the error is on
+        pub fn line(&self) -> usize {
+            self.location
+        }
+
+        /// What went wrong, without where
+        pub fn description(&self) -> String {
+            let description = match &self.info {
+                Some(info) => format!("{:?} ({})", self.error_type, info),
+                None => format!("{:?}", self.error_type)
+            };
+            match &self.suggestion {
+                Some(suggestion) => format!("{}; did you mean `{}`?", description, suggestion),
+                None => description
+            }
+        }
+
+        /// What was probably meant instead of an unrecognised mnemonic or label
+        pub fn suggestion(&self) -> Option<&str> {
+            self.suggestion.as_deref()
+        }
+    }
+
+    impl std::fmt::Display for ParseError {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            // locations are zero-based line indices
+            write!(f, "line {}: {}", self.location + 1, self.description())
+        }
     }
-    type ParseResult = Result<(Vec<Instruction>, super::intermediate::SymbolTable), Vec<ParseError>>;
 
     impl TryFrom<char> for Register {
         type Error = ParseError;
@@ -27,11 +69,7 @@ pub mod text {
                 'c' | 'C' => Ok(Self::UtilityC),
                 'x' | 'X' => Ok(Self::IndexX),
                 'y' | 'Y' => Ok(Self::IndexY),
-                _ => Err(ParseError {
-                    error_type: ParseErrorType::InvalidRegister,
-                    location: 0,
-                    info: Some(value.to_string())
-                })
+                _ => Err(ParseError::new(ParseErrorType::InvalidRegister, 0, value.to_string()))
             }
         }
     }
@@ -43,11 +81,7 @@ pub mod text {
                 'p' | 'P' => Ok(Self::Parity),
                 's' | 'S' => Ok(Self::Sign),
                 'z' | 'Z' => Ok(Self::Zero),
-                _ => Err(ParseError {
-                    error_type: ParseErrorType::InvalidFlag,
-                    location: 0,
-                    info: Some(value.to_string())
-                })
+                _ => Err(ParseError::new(ParseErrorType::InvalidFlag, 0, value.to_string()))
             }
         }
     }
@@ -59,10 +93,10 @@ pub mod text {
         static ref LEADING_SPACE: Regex = Regex::new(r"^\s+").unwrap();
         static ref TRAILING_SPACE: Regex = Regex::new(r"\s+$").unwrap();
 
-        static ref ONLY_INDIRECT: &'static str = r"(?<source>#[0-9a-f]+[bh]?|XY(?<offset>\+[0-9a-f]+[bh]?)?)";
-        static ref ANY_SOURCE: &'static str = r"(?<source>XY(?<offset>\+[0-9a-f]+[bh]?)?|[abcxy]|#?[0-9a-f]+[bh]?)";
+        static ref ONLY_INDIRECT: &'static str = r"(?<source>#[0-9a-f]+[bh]?|XY(?<offset>[+-][0-9a-f]+[bh]?)?)";
+        static ref ANY_SOURCE: &'static str = r"(?<source>XY(?<offset>[+-][0-9a-f]+[bh]?)?|[abcxy]|#?[0-9a-f]+[bh]?)";
 
-        static ref INDEX_OFFSET: Regex = RegexBuilder::new(r"XY(?<offset>\+[0-9a-f]+[bh]?)?")
+        static ref INDEX_OFFSET: Regex = RegexBuilder::new(r"XY(?<offset>[+-][0-9a-f]+[bh]?)?")
             .case_insensitive(true)
             .build()
             .unwrap();
@@ -103,6 +137,10 @@ pub mod text {
             .case_insensitive(true)
             .build()
             .unwrap();
+        static ref INST_COMPARE_JUMP: Regex = RegexBuilder::new((String::from(r"^cjne ") + *ANY_SOURCE + r" ?, ?(?<value>[0-9a-f]+[bh]?) ?, ?(?<label>[a-z]\w*)$").as_str())
+            .case_insensitive(true)
+            .build()
+            .unwrap();
         static ref INST_JUMP: Regex = RegexBuilder::new(r"jmp (?<label>[a-z]\w*)")
             .case_insensitive(true)
             .build()
@@ -115,10 +153,22 @@ pub mod text {
             .case_insensitive(true)
             .build()
             .unwrap();
+        static ref INST_RETURN_INTERRUPT: Regex = RegexBuilder::new(r"reti")
+            .case_insensitive(true)
+            .build()
+            .unwrap();
         static ref INST_RETURN: Regex = RegexBuilder::new(r"ret")
             .case_insensitive(true)
             .build()
             .unwrap();
+        static ref INST_EXIT: Regex = RegexBuilder::new(r"^hlt (?<code>[0-9a-f]+[bh]?)$")
+            .case_insensitive(true)
+            .build()
+            .unwrap();
+        static ref INST_HALT: Regex = RegexBuilder::new(r"^hlt$")
+            .case_insensitive(true)
+            .build()
+            .unwrap();
         static ref INST_NOP: Regex = RegexBuilder::new(r"nop")
             .case_insensitive(true)
             .build()
@@ -132,6 +182,55 @@ pub mod text {
             .build()
             .unwrap();
         static ref INST_LABEL: Regex = Regex::new(r"(?<label>[a-z]\w*):").unwrap();
+
+        static ref EQU: Regex = RegexBuilder::new(r"^\.equ (?<name>[a-z_]\w*) (?<value>.+)$")
+            .case_insensitive(true)
+            .build()
+            .unwrap();
+        static ref ORG: Regex = RegexBuilder::new(r"^\.org (?<address>.+)$")
+            .case_insensitive(true)
+            .build()
+            .unwrap();
+        static ref DATA: Regex = RegexBuilder::new(r"^\.d(?<size>[bw]) (?<values>.+)$")
+            .case_insensitive(true)
+            .build()
+            .unwrap();
+        static ref INCBIN: Regex = RegexBuilder::new(r#"^\.incbin "(?<path>[^"]+)"(,(?<offset>[^,]+)(,(?<length>.+))?)?$"#)
+            .case_insensitive(true)
+            .build()
+            .unwrap();
+        static ref LOCAL_LABEL: Regex = Regex::new(r"^(?<number>\d+):$").unwrap();
+        static ref LOCAL_REFERENCE: Regex = RegexBuilder::new(r"^(?<instruction>(?:jmp|jn?[czsp]|call) |cjne [^,]+,[^,]+, ?)(?<number>\d+)(?<direction>[fb])$")
+            .case_insensitive(true)
+            .build()
+            .unwrap();
+        /// Every mnemonic, to suggest in place of one that wasn't recognised
+        static ref MNEMONICS: Vec<String> = ["ld", "st"].iter()
+            .flat_map(|prefix| "abcxy".chars().map(move |register| format!("{}{}", prefix, register)))
+            .chain(["j", "jn"].iter().flat_map(|prefix| "czsp".chars().map(move |flag| format!("{}{}", prefix, flag))))
+            .chain(["inc", "dec", "add", "adxy", "sub", "sbxy", "cmp", "cjne", "jmp", "call", "ret", "reti", "push", "pop", "nop", "hlt"].map(String::from))
+            .collect();
+        static ref LABEL_LINE: Regex = Regex::new(r"^(?<label>[a-z]\w*):$").unwrap();
+        static ref NUMERIC_OPERAND: Regex = RegexBuilder::new(r"^(?<mnemonic>ld[abcxy]|st[abcxy]|add|adxy|sub|sbxy|cmp|hlt) (?<operand>.+)$")
+            .case_insensitive(true)
+            .build()
+            .unwrap();
+        static ref COMPARE_JUMP_OPERANDS: Regex = RegexBuilder::new(r"^(?<mnemonic>cjne) (?<source>[^,]+?) ?, ?(?<value>[^,]+?) ?, ?(?<label>[^,]+)$")
+            .case_insensitive(true)
+            .build()
+            .unwrap();
+        static ref JUMP_TARGET: Regex = RegexBuilder::new(r"^(?<mnemonic>jmp|jn?[czsp]|call) (?<target>.+)$")
+            .case_insensitive(true)
+            .build()
+            .unwrap();
+        static ref PLAIN_LABEL: Regex = RegexBuilder::new(r"^[a-z]\w*$")
+            .case_insensitive(true)
+            .build()
+            .unwrap();
+        static ref PLAIN_LITERAL: Regex = RegexBuilder::new(r"^[0-9a-f]+[bh]?$")
+            .case_insensitive(true)
+            .build()
+            .unwrap();
     }
 
     fn extract_args_target_source(captures: regex::Captures) -> Vec<&str> {
@@ -154,12 +253,252 @@ pub mod text {
 
         match u16::from_str_radix(num, base) {
             Ok(literal) => Ok(literal),
-            Err(_) => Err(ParseError {
-                error_type: ParseErrorType::InvalidNumericLiteral,
-                location: 0,
-                info: Some(arg.to_string())
-            })
+            Err(_) => Err(ParseError::new(ParseErrorType::InvalidNumericLiteral, 0, arg.to_string()))
+        }
+    }
+
+    /// Names that can be used in operand expressions: `.equ` constants, and labels, which stand for the index of
+    /// the instruction they label
+    type Constants = HashMap<String, i64>;
+
+    /// Every label, by where it is among the lines that become instructions
+    fn labels(lines: &[(usize, String)]) -> Constants {
+        lines.iter()
+            .enumerate()
+            .filter_map(|(index, (_, line))| LABEL_LINE.captures(line).map(|captures| (captures["label"].to_string(), index as i64)))
+            .collect()
+    }
+
+    /// Names the numeric labels, which can be defined any number of times, after the number and which definition
+    /// of it they are. `1f` refers to the next `1:` and `1b` to the previous one.
+    fn local_labels(lines: Vec<(usize, String)>, errors: &mut Vec<ParseError>) -> Vec<(usize, String)> {
+        let mut definitions: HashMap<String, Vec<usize>> = HashMap::new();
+        for (index, (_, line)) in lines.iter().enumerate() {
+            if let Some(captures) = LOCAL_LABEL.captures(line) {
+                definitions.entry(captures["number"].to_string()).or_default().push(index);
+            }
+        }
+        let name = |number: &str, definition: usize| format!("local_{}_{}", number, definition);
+        lines.into_iter().enumerate().filter_map(|(index, (location, line))| {
+            if let Some(captures) = LOCAL_LABEL.captures(&line) {
+                let definition = definitions[&captures["number"]].iter().position(|&defined| defined == index).unwrap();
+                Some((location, format!("{}:", name(&captures["number"], definition))))
+            } else if let Some(captures) = LOCAL_REFERENCE.captures(&line) {
+                let defined = definitions.get(&captures["number"]).map_or(&[][..], Vec::as_slice);
+                let definition = if captures["direction"].eq_ignore_ascii_case("f") {
+                    defined.iter().position(|&defined| defined > index)
+                } else {
+                    defined.iter().rposition(|&defined| defined < index)
+                };
+                match definition {
+                    Some(definition) => Some((location, format!("{}{}", &captures["instruction"], name(&captures["number"], definition)))),
+                    None => {
+                        errors.push(ParseError::new(ParseErrorType::InvalidLabel, location, format!("{}{}", &captures["number"], &captures["direction"])));
+                        None
+                    }
+                }
+            } else {
+                Some((location, line))
+            }
+        }).collect()
+    }
+
+    fn expression_error(expression: &str, location: usize) -> ParseError {
+        ParseError::new(ParseErrorType::InvalidExpression, location, expression.to_string())
+    }
+
+    /// Evaluates a constant expression of numbers and names joined by `+`, `-`, `*` and `/`, with brackets
+    fn evaluate(expression: &str, constants: &Constants, location: usize) -> Result<i64, ParseError> {
+        let mut tokens: Vec<String> = Vec::new();
+        let mut chars = expression.chars().peekable();
+        while let Some(&next) = chars.peek() {
+            if next.is_whitespace() {
+                chars.next();
+            } else if next.is_alphanumeric() || next == '_' {
+                let mut word = String::new();
+                while let Some(&next) = chars.peek().filter(|next| next.is_alphanumeric() || **next == '_') {
+                    word.push(next);
+                    chars.next();
+                }
+                tokens.push(word);
+            } else if "+-*/()".contains(next) {
+                tokens.push(next.to_string());
+                chars.next();
+            } else {
+                return Err(expression_error(expression, location));
+            }
         }
+
+        let mut position = 0;
+        let value = sum(&tokens, &mut position, constants).map_err(|error| match error {
+            Some(error) => ParseError { location, ..error },
+            None => expression_error(expression, location)
+        })?;
+        if position < tokens.len() {
+            return Err(expression_error(expression, location));
+        }
+        Ok(value)
+    }
+
+    // each of these returns `None` for a malformed expression, to be reported as the whole expression
+
+    fn sum(tokens: &[String], position: &mut usize, constants: &Constants) -> Result<i64, Option<ParseError>> {
+        let mut value = product(tokens, position, constants)?;
+        while let Some(operator) = tokens.get(*position).filter(|token| *token == "+" || *token == "-") {
+            *position += 1;
+            let operand = product(tokens, position, constants)?;
+            value = if operator == "+" { value.checked_add(operand) } else { value.checked_sub(operand) }.ok_or(None)?;
+        }
+        Ok(value)
+    }
+
+    fn product(tokens: &[String], position: &mut usize, constants: &Constants) -> Result<i64, Option<ParseError>> {
+        let mut value = term(tokens, position, constants)?;
+        while let Some(operator) = tokens.get(*position).filter(|token| *token == "*" || *token == "/") {
+            *position += 1;
+            let operand = term(tokens, position, constants)?;
+            value = if operator == "*" { value.checked_mul(operand) } else { value.checked_div(operand) }.ok_or(None)?;
+        }
+        Ok(value)
+    }
+
+    fn term(tokens: &[String], position: &mut usize, constants: &Constants) -> Result<i64, Option<ParseError>> {
+        let token = tokens.get(*position).ok_or(None)?;
+        *position += 1;
+        match token.as_str() {
+            "-" => term(tokens, position, constants)?.checked_neg().ok_or(None),
+            "(" => {
+                let value = sum(tokens, position, constants)?;
+                if tokens.get(*position).is_some_and(|token| token == ")") {
+                    *position += 1;
+                    Ok(value)
+                } else {
+                    Err(None)
+                }
+            },
+            "+" | "*" | "/" | ")" => Err(None),
+            // names shadow numbers that look like them, e.g. a label `each` over the hex number EAC
+            word => match constants.get(word) {
+                Some(&value) => Ok(value),
+                None if PLAIN_LITERAL.is_match(word) => Ok(to_literal(&word.to_ascii_uppercase())? as i64),
+                None => Err(Some(ParseError::new(ParseErrorType::InvalidExpression, 0, format!("undefined: {}", word))))
+            }
+        }
+    }
+
+    /// Replaces an instruction's operand with the number it works out to, if it's an expression rather than a
+    /// register or a plain number, so that the rest of the assembler only ever sees numbers
+    fn fold_operand(line: &str, constants: &Constants, location: usize) -> Result<String, ParseError> {
+        if let Some(captures) = COMPARE_JUMP_OPERANDS.captures(line) {
+            let value = &captures["value"];
+            if PLAIN_LITERAL.is_match(value) && !constants.contains_key(value) {
+                return Ok(line.to_string());
+            }
+            let value = evaluate(value, constants, location)?;
+            return Ok(format!("{} {}, {}, {}", &captures["mnemonic"], &captures["source"], value, &captures["label"]));
+        }
+        let Some(captures) = NUMERIC_OPERAND.captures(line) else { return Ok(line.to_string()) };
+        let operand = &captures["operand"];
+        let (prefix, expression) = if operand.len() >= 2 && operand[..2].eq_ignore_ascii_case("xy") {
+            match operand[2..].split_at_checked(1) {
+                Some((sign @ ("+" | "-"), offset)) if !offset.is_empty() => (sign, offset),
+                _ => return Ok(line.to_string())
+            }
+        } else if let Some(address) = operand.strip_prefix('#') {
+            ("#", address)
+        } else if operand.len() == 1 && Register::try_from(operand.chars().next().unwrap()).is_ok() {
+            return Ok(line.to_string());
+        } else {
+            ("", operand)
+        };
+        if PLAIN_LITERAL.is_match(expression) && !constants.contains_key(expression) {
+            return Ok(line.to_string());
+        }
+        let value = evaluate(expression, constants, location)?;
+        let operand = match prefix {
+            // an offset worked out to be negative is written the other way around
+            "+" | "-" => {
+                let offset = if prefix == "-" { -value } else { value };
+                format!("xy{}{}", if offset < 0 { "-" } else { "+" }, offset.abs())
+            },
+            _ => format!("{}{}", prefix, value)
+        };
+        Ok(format!("{} {}", &captures["mnemonic"], operand))
+    }
+
+    /// Works out a jump or call to an expression rather than a plain label, such as `jmp table+4`, as one to the
+    /// instruction it comes to. Anything else is left to `to_instruction`.
+    fn computed_target(line: &str, constants: &Constants, length: usize, location: usize) -> Option<Result<Instruction, ParseError>> {
+        let captures = JUMP_TARGET.captures(line)?;
+        if PLAIN_LABEL.is_match(&captures["target"]) {
+            return None;
+        }
+        Some(evaluate(&captures["target"], constants, location).and_then(|target| {
+            if !(0..length as i64).contains(&target) {
+                return Err(ParseError::new(ParseErrorType::InvalidLabel, location, format!("{} is past the end of the code", &captures["target"])));
+            }
+            // the mnemonic is parsed as if it had a label, to find out what kind of jump it is
+            match to_instruction(&format!("{} target", &captures["mnemonic"]), location)? {
+                Instruction::_LabeledJump(jump_type, _) => Ok(Instruction::Jump(jump_type, target as usize)),
+                _ => Ok(Instruction::Call(CallType::Local(target as usize)))
+            }
+        }))
+    }
+
+    fn directive_error(info: &str, location: usize) -> ParseError {
+        ParseError::new(ParseErrorType::InvalidDirective, location, info.to_string())
+    }
+
+    /// The bytes of a file for `.incbin`, from `offset` and for `length` bytes if given, or to the end of the file.
+    /// A relative path is found from `directory` if the source came from a file, or the working directory if not.
+    fn include_binary(path: &str, directory: Option<&Path>, offset: Option<i64>, length: Option<i64>, location: usize) -> Result<Vec<u8>, ParseError> {
+        let path = directory.map_or_else(|| Path::new(path).to_path_buf(), |directory| directory.join(path));
+        let bytes = std::fs::read(&path).map_err(|error| directive_error(&format!("{}: {}", path.display(), error), location))?;
+        let start = offset.unwrap_or(0);
+        let end = length.map_or(bytes.len() as i64, |length| start + length);
+        if start < 0 || end < start || end > bytes.len() as i64 {
+            return Err(directive_error(&format!("{} is only {} bytes long", path.display(), bytes.len()), location));
+        }
+        Ok(bytes[start as usize..end as usize].to_vec())
+    }
+
+    /// Carries out a directive: defining a constant with `.equ`, starting a data segment with `.org`, or adding
+    /// bytes or big-endian words to it with `.db` or `.dw`, or the contents of a file with `.incbin`
+    fn directive(line: &str, location: usize, directory: Option<&Path>, constants: &mut Constants, segments: &mut Vec<DataSegment>) -> Result<(), ParseError> {
+        if let Some(captures) = EQU.captures(line) {
+            let value = evaluate(&captures["value"], constants, location)?;
+            constants.insert(captures["name"].to_string(), value);
+        } else if let Some(captures) = ORG.captures(line) {
+            let address = evaluate(&captures["address"], constants, location)?;
+            let address = u16::try_from(address).map_err(|_| ParseError::new(ParseErrorType::InvalidNumericLiteral, location, address.to_string()))?;
+            segments.push(DataSegment { address, bytes: Vec::new() });
+        } else if let Some(captures) = DATA.captures(line) {
+            let segment = segments.last_mut().ok_or_else(|| directive_error("data before .org", location))?;
+            let word = captures["size"].eq_ignore_ascii_case("w");
+            for value in captures["values"].split(',') {
+                let value = evaluate(value, constants, location)?;
+                let limit = if word { u16::MAX as i64 } else { u8::MAX as i64 };
+                if !(0..=limit).contains(&value) {
+                    return Err(ParseError::new(ParseErrorType::InvalidNumericLiteral, location, value.to_string()));
+                }
+                if word {
+                    segment.bytes.extend_from_slice(&(value as u16).to_be_bytes());
+                } else {
+                    segment.bytes.push(value as u8);
+                }
+            }
+        } else if let Some(captures) = INCBIN.captures(line) {
+            let segment = segments.last_mut().ok_or_else(|| directive_error("data before .org", location))?;
+            let argument = |name: &str| captures.name(name).map(|value| evaluate(value.as_str(), constants, location)).transpose();
+            let (offset, length) = (argument("offset")?, argument("length")?);
+            segment.bytes.extend(include_binary(&captures["path"], directory, offset, length, location)?);
+        } else {
+            return Err(directive_error(line, location));
+        }
+        if segments.last().is_some_and(|segment| segment.address as usize + segment.bytes.len() > MEMORY_SIZE) {
+            return Err(directive_error("data runs past the end of memory", location));
+        }
+        Ok(())
     }
 
     fn to_addressing_mode(arg: &str) -> Result<AddressingMode, ParseError> {
@@ -167,7 +506,10 @@ pub mod text {
 
         if let Some(captures) = INDEX_OFFSET.captures(&arg) {
             if let Some(offset) = captures.name("offset") {
-                Ok(AddressingMode::IndexOffset(to_literal(&offset.as_str()[1..])?))
+                let magnitude = to_literal(&offset.as_str()[1..])? as i32;
+                let offset_value = if offset.as_str().starts_with('-') { -magnitude } else { magnitude };
+                let offset_value = i16::try_from(offset_value).map_err(|_| ParseError::new(ParseErrorType::InvalidNumericLiteral, 0, offset.as_str().to_string()))?;
+                Ok(AddressingMode::IndexOffset(offset_value))
             } else {
                 Ok(AddressingMode::Index)
             }
@@ -181,11 +523,7 @@ pub mod text {
                     } else {
                         let literal = to_literal(&arg)?;
                         if literal > u8::MAX as u16 {
-                            Err(ParseError {
-                                error_type: ParseErrorType::InvalidNumericLiteral,
-                                location: 0,
-                                info: Some(literal.to_string())
-                            })
+                            Err(ParseError::new(ParseErrorType::InvalidNumericLiteral, 0, literal.to_string()))
                         } else {
                             Ok(AddressingMode::Immediate(literal as u8))
                         }
@@ -196,7 +534,18 @@ pub mod text {
     }
 
     fn to_instruction(line: &str, location: usize) -> Result<Instruction, ParseError> {
-        if let Some(captures) = INST_LOAD.captures(line) {
+        // first, as its label could contain another mnemonic
+        if let Some(captures) = INST_COMPARE_JUMP.captures(line) {
+            let addressing_mode = to_addressing_mode(&captures["source"])?;
+            let value = to_literal(&captures["value"].to_ascii_uppercase())?;
+            if value > u8::MAX as u16 {
+                return Err(ParseError::new(ParseErrorType::InvalidNumericLiteral, 0, value.to_string()));
+            }
+
+            Ok(Instruction::_LabeledJump(
+                JumpType::CompareNotEqual(addressing_mode, value as u8), captures["label"].to_string()
+            ))
+        } else if let Some(captures) = INST_LOAD.captures(line) {
             let target_source = extract_args_target_source(captures);
             let (target, source) = (target_source[0], target_source[1]);
             let target_register = Register::try_from(target.chars().next().unwrap())?;
@@ -257,6 +606,8 @@ pub mod text {
             let label = captures.name("label").to_owned().unwrap().as_str().to_string();
 
             Ok(Instruction::_LabeledCall(label))
+        } else if INST_RETURN_INTERRUPT.is_match(line) {
+            Ok(Instruction::ReturnInterrupt)
         } else if INST_RETURN.is_match(line) {
             Ok(Instruction::Return)
         } else if let Some(captures) = INST_PUSH.captures(line) {
@@ -269,19 +620,51 @@ pub mod text {
             Ok(Instruction::Pop(source))
         } else if INST_NOP.is_match(line) {
             Ok(Instruction::Nop)
+        } else if let Some(captures) = INST_EXIT.captures(line) {
+            let code = to_literal(&captures["code"].to_ascii_uppercase())?;
+            if code > u8::MAX as u16 {
+                return Err(ParseError::new(ParseErrorType::InvalidNumericLiteral, 0, code.to_string()));
+            }
+
+            Ok(Instruction::Exit(code as u8))
+        } else if INST_HALT.is_match(line) {
+            Ok(Instruction::Halt)
         } else if let Some(captures) = INST_LABEL.captures(line) {
             let label = captures.name("label").to_owned().unwrap().as_str().to_string();
 
             Ok(Instruction::_Label(label))
         } else {
+            let mnemonic = line.split(' ').next().unwrap_or(line);
             Err(ParseError {
-                error_type: ParseErrorType::InvalidInstruction,
-                location,
-                info: Some(line.to_string())
+                suggestion: suggest(mnemonic, MNEMONICS.iter().map(String::as_str)),
+                ..ParseError::new(ParseErrorType::InvalidInstruction, location, line.to_string())
             })
         }
     }
 
+    /// The candidate closest to a word that wasn't recognised, if one is close enough to be what was meant and no
+    /// other is as close. Case is ignored, and swapping two neighbouring letters counts as a single edit.
+    fn suggest<'a>(word: &str, candidates: impl Iterator<Item = &'a str>) -> Option<String> {
+        let word = word.to_ascii_lowercase();
+        let allowed = (word.len() / 3).max(1);
+        let mut closest: Vec<(usize, &str)> = candidates
+            .map(|candidate| (crate::util::edit_distance(&word, &candidate.to_ascii_lowercase()), candidate))
+            .filter(|&(distance, _)| distance > 0 && distance <= allowed)
+            .collect();
+        closest.sort();
+        match closest.as_slice() {
+            [(distance, candidate), rest @ ..] if rest.first().is_none_or(|(next, _)| next > distance) => Some(candidate.to_string()),
+            _ => None
+        }
+    }
+
+    /// Where the quoted string in a line starts and ends, if it has one outside of a comment
+    fn quoted(line: &str) -> Option<(usize, usize)> {
+        let start = line.find('"').filter(|&start| !line[..start].contains(';'))?;
+        let end = start + 1 + line[start + 1..].find('"')? + 1;
+        Some((start, end))
+    }
+
     fn preprocess(code: String) -> Vec<(usize, String)> {
 
         code
@@ -289,8 +672,10 @@ pub mod text {
             .enumerate()
             .filter_map(|(loc, line)| {
                 let trimmed = LEADING_SPACE.replace(line, "");
-                let trimmed = DUPLICATE_SPACE.replace(&trimmed, " ");
-                let trimmed = COMMENTS.replace(&trimmed, "");
+                // a quoted path is kept as it's written, so only what's around it is tidied up
+                let (start, end) = quoted(&trimmed).unwrap_or((trimmed.len(), trimmed.len()));
+                let tidy = |part: &str| COMMENTS.replace(&DUPLICATE_SPACE.replace(part, " "), "").into_owned();
+                let trimmed = format!("{}{}{}", tidy(&trimmed[..start]), &trimmed[start..end], tidy(&trimmed[end..]));
                 let trimmed = TRAILING_SPACE.replace(&trimmed, "");
 
                 if BLANK_LINES.is_match(&trimmed) {
@@ -302,13 +687,47 @@ pub mod text {
             .collect()
     }
     pub fn compile(code: String, externs: Option<DevolaExternTable>) -> ParseResult {
-        let preprocessed = preprocess(code);
-        let mut output: Vec<Instruction> = Vec::new();
+        assemble(code, externs, false, None)
+    }
+
+    /// Compiles a program, leaving out instructions that make no difference to it. See `intermediate::optimize`.
+    pub fn compile_optimized(code: String, externs: Option<DevolaExternTable>) -> ParseResult {
+        assemble(code, externs, true, None)
+    }
+
+    /// Compiles a program read from the file at `path`, which the paths it includes are relative to
+    pub fn compile_from(code: String, externs: Option<DevolaExternTable>, path: &Path) -> ParseResult {
+        assemble(code, externs, false, path.parent())
+    }
+
+    /// `compile_optimized` for a program read from the file at `path`
+    pub fn compile_optimized_from(code: String, externs: Option<DevolaExternTable>, path: &Path) -> ParseResult {
+        assemble(code, externs, true, path.parent())
+    }
+
+    fn assemble(code: String, externs: Option<DevolaExternTable>, optimize: bool, directory: Option<&Path>) -> ParseResult {
+        let (directives, preprocessed): (Vec<_>, Vec<_>) = preprocess(code).into_iter().partition(|(_, line)| line.starts_with('.'));
+        let mut output: Vec<(Instruction, usize)> = Vec::new();
         let mut parse_errors: Vec<ParseError> = Vec::new();
+        let preprocessed = local_labels(preprocessed, &mut parse_errors);
 
+        // constants can use labels anywhere in the program, but only the constants defined above them
+        let mut constants = labels(&preprocessed);
+        let mut segments = Vec::new();
+        for (location, line) in directives {
+            if let Err(error) = directive(&line, location, directory, &mut constants, &mut segments) {
+                parse_errors.push(error);
+            }
+        }
+        segments.retain(|segment| !segment.bytes.is_empty());
+
+        // every remaining line becomes exactly one instruction, labels included
+        let length = preprocessed.len();
         for (location, line) in preprocessed {
-            match to_instruction(&line, location) {
-                Ok(instruction) => output.push(instruction),
+            let instruction = computed_target(&line, &constants, length, location)
+                .unwrap_or_else(|| fold_operand(&line, &constants, location).and_then(|line| to_instruction(&line, location)));
+            match instruction {
+                Ok(instruction) => output.push((instruction, location)),
                 Err(error) => parse_errors.push(error)
             }
         }
@@ -316,18 +735,39 @@ pub mod text {
         if parse_errors.len() > 0 {
             Err(parse_errors)
         } else {
-            let processed = super::intermediate::process_labels(output, externs).map_err(
+            if optimize {
+                let written: Vec<usize> = output.iter().map(|(_, location)| *location).collect();
+                output = super::intermediate::optimize(output);
+                // computed targets count instructions as written, so they follow what they pointed at, or the next
+                // instruction if it was left out
+                let kept: Vec<usize> = output.iter().map(|(_, location)| *location).collect();
+                for (instruction, _) in output.iter_mut() {
+                    if let Instruction::Jump(_, target) | Instruction::Call(CallType::Local(target)) = instruction {
+                        *target = kept.partition_point(|&location| location < written[*target]);
+                    }
+                }
+            }
+            let (output, lines): (Vec<Instruction>, super::intermediate::LineTable) = output.into_iter().unzip();
+            // what a missing label might have been meant to be, leaving out the names given to numeric labels
+            let mut known: Vec<String> = output.iter().filter_map(|instruction| match instruction {
+                Instruction::_Label(label) if !label.starts_with("local_") => Some(label.clone()),
+                _ => None
+            }).collect();
+            known.extend(externs.iter().flat_map(|externs| externs.keys().cloned()));
+            let (code, symbols) = super::intermediate::process_labels(output, externs).map_err(
                 |missing_labels| {
                     missing_labels.iter().map(|(label, location)| {
+                            // missing labels are described by what used them, as in `Jump: label`
+                            let name = label.split_once(": ").map_or(label.as_str(), |(_, name)| name);
                             ParseError {
-                                error_type: ParseErrorType::InvalidLabel,
-                                location: *location,
-                                info: Some(label.clone())
+                                suggestion: suggest(name, known.iter().map(String::as_str)),
+                                // missing labels are found by where they're used in the code, which is translated back to a line
+                                ..ParseError::new(ParseErrorType::InvalidLabel, lines[*location], label.clone())
                             }
                     }).collect::<Vec<_>>()
                 }
             )?;
-            Ok(processed)
+            Ok((code, symbols, lines, segments))
         }
     }
 
@@ -369,6 +809,153 @@ pub mod text {
             println!("{:?}", compile(code, None));
         }
 
+        #[test]
+        fn test_computed_targets() {
+            let source = ".org 0300h\n.dw first, second+1\njmp table+1\ntable:\njnz table+2\ncall second\nfirst:\nldb 1\nsecond:\nldc 2";
+            let (code, _, _, segments) = compile(String::from(source), None).unwrap();
+            assert!(matches!(code[0], Instruction::Jump(JumpType::Unconditional, 2)));
+            assert!(matches!(code[2], Instruction::Jump(JumpType::Flag(Flag::Zero, false), 3)));
+            assert_eq!(segments[0].bytes, [0, 4, 0, 7]);
+
+            // what was pointed at moves along with the instructions left out before it
+            let (code, _, _, _) = compile_optimized(String::from("jmp start+3\nstart:\npush a\npop a\nldb 1"), None).unwrap();
+            assert!(matches!(code[0], Instruction::Jump(JumpType::Unconditional, 2)));
+            assert!(matches!(code[2], Instruction::Load(Register::UtilityB, _)));
+
+            let errors = compile(String::from("call table+5\ntable:"), None).unwrap_err();
+            assert_eq!(errors[0].to_string(), "line 1: InvalidLabel (table+5 is past the end of the code)");
+        }
+
+        #[test]
+        fn test_compile_errors() {
+            let errors = compile(String::from("lda 0\n\nldq 1\njmp nowhere"), None).unwrap_err();
+            let messages: Vec<String> = errors.iter().map(|error| error.to_string()).collect();
+            assert_eq!(messages, vec![String::from("line 3: InvalidInstruction (ldq 1)")]);
+
+            let errors = compile(String::from("lda 0\njmp nowhere"), None).unwrap_err();
+            assert_eq!(errors[0].to_string(), "line 2: InvalidLabel (Jump: nowhere)");
+
+            // close misspellings get a suggestion, unless there's more than one as close
+            let errors = compile(String::from("PSUH a\nlad 1\nldq 1"), None).unwrap_err();
+            let suggestions: Vec<Option<&str>> = errors.iter().map(ParseError::suggestion).collect();
+            assert_eq!(suggestions, [Some("push"), Some("lda"), None]);
+            assert_eq!(errors[0].to_string(), "line 1: InvalidInstruction (PSUH a); did you mean `push`?");
+            let errors = compile(String::from("main_loop:\ncall main_lopo\njmp mian_loop\njmp elsewhere"), None).unwrap_err();
+            let suggestions: Vec<Option<&str>> = errors.iter().map(ParseError::suggestion).collect();
+            assert_eq!(suggestions, [Some("main_loop"), Some("main_loop"), None]);
+        }
+
+        #[test]
+        fn test_compile_lines() {
+            let (code, _, lines, _) = compile(String::from("lda 0\n\nloop:\n  inc ; count\njmp loop"), None).unwrap();
+            assert_eq!(lines, vec![0, 2, 3, 4]);
+            assert_eq!(lines.len(), code.len());
+        }
+
+        #[test]
+        fn test_constant_expressions() {
+            let source = "
+                .equ base 0200h
+                .equ size end-start
+                .equ half size/2
+                lda (size+1)*2
+                sta #base+size
+                ldx half-1
+                adxy xy+size
+                stb xy-half
+                start:
+                nop
+                nop
+                end:
+            ";
+            let (code, _, _, _) = compile(String::from(source), None).unwrap();
+            assert!(matches!(code[0], Instruction::Load(Register::Accumulator, AddressingMode::Immediate(8))));
+            assert!(matches!(code[1], Instruction::Store(Register::Accumulator, AddressingMode::Indirect(0x0203))));
+            assert!(matches!(code[2], Instruction::Load(Register::IndexX, AddressingMode::Immediate(0))));
+            assert!(matches!(code[3], Instruction::AddXY(AddressingMode::IndexOffset(3))));
+            assert!(matches!(code[4], Instruction::Store(Register::UtilityB, AddressingMode::IndexOffset(-1))));
+
+            let errors = compile(String::from(".equ early late\n.equ late 1\nlda 1+\nsta #(2\nlda FFFFh*FFFFh*FFFFh*FFFFh*FFFFh"), None).unwrap_err();
+            let messages: Vec<String> = errors.iter().map(|error| error.to_string()).collect();
+            assert_eq!(messages, vec![
+                String::from("line 1: InvalidExpression (undefined: late)"),
+                String::from("line 3: InvalidExpression (1+)"),
+                String::from("line 4: InvalidExpression ((2)"),
+                String::from("line 5: InvalidExpression (FFFFh*FFFFh*FFFFh*FFFFh*FFFFh)")
+            ]);
+        }
+
+        #[test]
+        fn test_data_segments() {
+            let source = "
+                .equ table 0300h
+                .org table
+                .db 1, 2, 3
+                .dw 1234h, table+1
+                .org 0FFFEh
+                .db 9
+                .org 0
+                lda #table+2
+            ";
+            let (_, _, _, segments) = compile(String::from(source), None).unwrap();
+            assert_eq!(segments, vec![
+                DataSegment { address: 0x0300, bytes: vec![1, 2, 3, 0x12, 0x34, 0x03, 0x01] },
+                DataSegment { address: 0xFFFE, bytes: vec![9] }
+            ]);
+
+            let errors = compile(String::from(".db 1\n.org 0FFFFh\n.dw 1\n.org 0\n.db 256\n.dx 1"), None).unwrap_err();
+            let messages: Vec<String> = errors.iter().map(|error| error.to_string()).collect();
+            assert_eq!(messages, vec![
+                String::from("line 1: InvalidDirective (data before .org)"),
+                String::from("line 3: InvalidDirective (data runs past the end of memory)"),
+                String::from("line 5: InvalidNumericLiteral (256)"),
+                String::from("line 6: InvalidDirective (.dx 1)")
+            ]);
+
+            let directory = std::env::temp_dir().join(format!("devola_incbin_{}", std::process::id()));
+            std::fs::create_dir_all(&directory).unwrap();
+            let path = directory.join("data.bin");
+            std::fs::write(&path, [10, 20, 30, 40, 50]).unwrap();
+            let source = format!(".org 0400h\n.incbin \"{0}\"\n.incbin \"{0}\", 3\n.db 0\n.incbin \"{0}\", 1, 2", path.display());
+            let (_, _, _, segments) = compile(source, None).unwrap();
+            assert_eq!(segments, vec![DataSegment { address: 0x0400, bytes: vec![10, 20, 30, 40, 50, 40, 50, 0, 20, 30] }]);
+            let source = format!(".org 0400h\n.incbin \"{}\", 4, 2", path.display());
+            assert_eq!(compile(source, None).unwrap_err()[0].to_string(),
+                format!("line 2: InvalidDirective ({} is only 5 bytes long)", path.display()));
+            // paths are relative to the source's directory, and kept as written, spaces and semicolons included
+            std::fs::write(directory.join("two  spaces;.bin"), [60]).unwrap();
+            let source = String::from(".org 0400h\n  .incbin \"data.bin\", 4   ; the last byte\n.incbin \"two  spaces;.bin\"");
+            let (_, _, _, segments) = compile_from(source, None, &directory.join("test.pop")).unwrap();
+            assert_eq!(segments, vec![DataSegment { address: 0x0400, bytes: vec![50, 60] }]);
+            std::fs::remove_dir_all(directory).unwrap();
+        }
+
+        #[test]
+        fn test_local_labels() {
+            let source = "
+                lda 3
+                1:
+                dec
+                jnz 1b
+                call 1f
+                jmp 2f
+                1:
+                ret
+                2:
+                cjne a, 3, 2b
+            ";
+            let (code, symbols, _, _) = compile(String::from(source), None).unwrap();
+            assert!(matches!(code[3], Instruction::Jump(_, 1)));
+            assert!(matches!(code[4], Instruction::Call(CallType::Local(6))));
+            assert!(matches!(code[5], Instruction::Jump(_, 8)));
+            assert!(matches!(code[9], Instruction::Jump(JumpType::CompareNotEqual(_, 3), 8)));
+            assert_eq!(symbols.get(&6).map(String::as_str), Some("local_1_1"));
+
+            let errors = compile(String::from("1:\njmp 1f\njmp 2b"), None).unwrap_err();
+            let messages: Vec<String> = errors.iter().map(|error| error.to_string()).collect();
+            assert_eq!(messages, vec![String::from("line 2: InvalidLabel (1f)"), String::from("line 3: InvalidLabel (2b)")]);
+        }
+
         #[test]
         fn test_regex_load() {
             expect_parse_target_source(
@@ -403,11 +990,15 @@ pub mod text {
             assert_eq!(to_addressing_mode("XY+10b"), Ok(AddressingMode::IndexOffset(2)));
             assert_eq!(to_addressing_mode("XY+10h"), Ok(AddressingMode::IndexOffset(16)));
             assert_eq!(to_addressing_mode("XY+"), Ok(AddressingMode::Index));
-            assert_eq!(to_addressing_mode("XY+d"), Err(ParseError {
-                error_type: ParseErrorType::InvalidNumericLiteral,
-                location: 0,
-                info: Some(String::from("D"))
-            }));
+            assert_eq!(to_addressing_mode("XY-3"), Ok(AddressingMode::IndexOffset(-3)));
+            assert!(matches!(
+                to_instruction("cjne xy-1, 0ah, loop", 0),
+                Ok(Instruction::_LabeledJump(JumpType::CompareNotEqual(AddressingMode::IndexOffset(-1), 10), label)) if label == "loop"
+            ));
+            assert!(to_instruction("cjne a, 256, loop", 0).is_err());
+            assert_eq!(to_addressing_mode("XY-8000h"), Ok(AddressingMode::IndexOffset(i16::MIN)));
+            assert_eq!(to_addressing_mode("XY+8000h"), Err(ParseError::new(ParseErrorType::InvalidNumericLiteral, 0, String::from("+8000H"))));
+            assert_eq!(to_addressing_mode("XY+d"), Err(ParseError::new(ParseErrorType::InvalidNumericLiteral, 0, String::from("D"))));
         }
 
         #[test]
@@ -437,28 +1028,12 @@ pub mod text {
             assert_eq!(to_addressing_mode("#FFFFh"), Ok(AddressingMode::Indirect(0xFFFF)));
 
             // Invalid numbers
-            assert_eq!(to_addressing_mode("-"), Err(ParseError {
-                error_type: ParseErrorType::InvalidNumericLiteral,
-                location: 0,
-                info: Some(String::from("-"))
-            }));
-            assert_eq!(to_addressing_mode("-100h"), Err(ParseError {
-                error_type: ParseErrorType::InvalidNumericLiteral,
-                location: 0,
-                info: Some(String::from("-100H"))
-            }));
+            assert_eq!(to_addressing_mode("-"), Err(ParseError::new(ParseErrorType::InvalidNumericLiteral, 0, String::from("-"))));
+            assert_eq!(to_addressing_mode("-100h"), Err(ParseError::new(ParseErrorType::InvalidNumericLiteral, 0, String::from("-100H"))));
             // Invalid base
-            assert_eq!(to_addressing_mode("2b"), Err(ParseError {
-                error_type: ParseErrorType::InvalidNumericLiteral,
-                location: 0,
-                info: Some(String::from("2B"))
-            }));
+            assert_eq!(to_addressing_mode("2b"), Err(ParseError::new(ParseErrorType::InvalidNumericLiteral, 0, String::from("2B"))));
             // Invalid range
-            assert_eq!(to_addressing_mode("FFFFh"), Err(ParseError {
-                error_type: ParseErrorType::InvalidNumericLiteral,
-                location: 0,
-                info: Some(0xFFFF.to_string())
-            }));
+            assert_eq!(to_addressing_mode("FFFFh"), Err(ParseError::new(ParseErrorType::InvalidNumericLiteral, 0, 0xFFFF.to_string())));
         }
     }
 }
@@ -472,6 +1047,171 @@ pub mod intermediate {
 
     pub type SymbolTable = HashMap<usize, String>;
     pub type ReverseSymbolTable = HashMap<String, usize>;
+    /// The zero-based source line of each instruction, indexed by location in the code
+    pub type LineTable = Vec<usize>;
+
+    /// Writes out a symbol table with a line per label: its location in the code, then its name, in code order
+    pub fn export_symbols(symbols: &SymbolTable) -> String {
+        let mut entries: Vec<_> = symbols.iter().collect();
+        entries.sort();
+        entries.iter().map(|(pc, label)| format!("{} {}\n", pc, label)).collect()
+    }
+
+    /// Reads a symbol table written by `export_symbols`, or returns `None` if a line isn't a location and a label
+    pub fn import_symbols(text: &str) -> Option<SymbolTable> {
+        text.lines().map(|line| {
+            let (pc, label) = line.split_once(' ')?;
+            Some((pc.parse().ok()?, label.to_string()))
+        }).collect()
+    }
+
+    /// Starts the source lines in a symbol file, after the labels. Labels can't start with a `.`.
+    const LINES_SECTION: &str = ".lines";
+
+    /// Writes out a symbol file for debugging a program built without its source: the labels as written by
+    /// `export_symbols`, then `.lines` and the one-based source line of each instruction, a line each in code order
+    pub fn export_symbol_file(symbols: &SymbolTable, lines: &LineTable) -> String {
+        let mut text = export_symbols(symbols);
+        text.push_str(LINES_SECTION);
+        text.push('\n');
+        text.extend(lines.iter().map(|line| format!("{}\n", line + 1)));
+        text
+    }
+
+    /// Reads a symbol file written by `export_symbol_file`. A file with only labels, as written by
+    /// `export_symbols`, gives an empty line table.
+    pub fn import_symbol_file(text: &str) -> Option<(SymbolTable, LineTable)> {
+        let (labels, lines) = match text.split_once(&format!("{}\n", LINES_SECTION)) {
+            Some((labels, lines)) => (labels, lines),
+            None => (text, "")
+        };
+        let lines = lines.lines().map(|line| line.parse::<usize>().ok()?.checked_sub(1)).collect::<Option<_>>()?;
+        Some((import_symbols(labels)?, lines))
+    }
+
+    /// Whether an addressing mode reads a register, directly or as part of `XY`
+    fn reads_register(mode: &AddressingMode, register: Register) -> bool {
+        match mode {
+            AddressingMode::Register(source) => *source == register,
+            AddressingMode::Index | AddressingMode::IndexOffset(_) => matches!(register, Register::IndexX | Register::IndexY),
+            AddressingMode::Immediate(_) | AddressingMode::Indirect(_) => false
+        }
+    }
+
+    /// Whether the flags left by the instruction before `code` are always overwritten before anything reads them
+    fn flags_overwritten(code: &[(Instruction, usize)]) -> bool {
+        for (instruction, _) in code {
+            match instruction {
+                // these set every flag without looking at them
+                Instruction::Add(_) | Instruction::Subtract(_) | Instruction::Compare(_) => return true,
+                Instruction::Load(_, _) | Instruction::Store(_, _) | Instruction::Push(_) | Instruction::Pop(_)
+                | Instruction::Nop | Instruction::_Label(_) => (),
+                _ => return false
+            }
+        }
+        false
+    }
+
+    /// Removes instructions that make no difference to what a program does, before its labels are resolved.
+    /// Each instruction comes with its source line, which stays with it. Instructions are removed when they:
+    /// - push a register and pop it straight back
+    /// - load a register from itself, or into a register that's loaded again straight away
+    /// - add or subtract 0, when the flags they set are overwritten before they're read
+    /// - jump to a label that comes next
+    ///
+    /// A pushed and popped register still leaves its value in the memory below the stack.
+    pub fn optimize(mut code: Vec<(Instruction, usize)>) -> Vec<(Instruction, usize)> {
+        // removing an instruction can bring two others together that can go too
+        loop {
+            let length = code.len();
+            let mut optimized = Vec::with_capacity(length);
+            let mut index = 0;
+            while index < code.len() {
+                let next = code.get(index + 1).map(|(instruction, _)| instruction);
+                let removed = match (&code[index].0, next) {
+                    (Instruction::Push(pushed), Some(Instruction::Pop(popped))) if pushed == popped => 2,
+                    (Instruction::Load(target, AddressingMode::Register(source)), _) if target == source => 1,
+                    (Instruction::Load(target, _), Some(Instruction::Load(reloaded, source)))
+                        if target == reloaded && !reads_register(source, *target) => 1,
+                    (Instruction::Add(AddressingMode::Immediate(0)) | Instruction::Subtract(AddressingMode::Immediate(0)), _)
+                        if flags_overwritten(&code[index + 1..]) => 1,
+                    // `cjne` sets the flags, and can fault reading its argument, even when it doesn't jump
+                    (Instruction::_LabeledJump(jump_type, label), _) if !matches!(jump_type, JumpType::CompareNotEqual(..)) => {
+                        let lands_next = code[index + 1..].iter()
+                            .map_while(|(instruction, _)| match instruction {
+                                Instruction::_Label(next_label) => Some(next_label),
+                                _ => None
+                            })
+                            .any(|next_label| next_label == label);
+                        lands_next as usize
+                    },
+                    _ => 0
+                };
+                if removed == 0 {
+                    optimized.push(code[index].clone());
+                    index += 1;
+                } else {
+                    index += removed;
+                }
+            }
+            code = optimized;
+            if code.len() == length {
+                return code;
+            }
+        }
+    }
+
+    /// Removes the instructions of an assembled program that can never run, and the labels that nothing jumps to or
+    /// calls, returning what's left along with the locations of what was removed. Code is reachable from the start
+    /// of the program and from the `irq` handler, which keeps its label.
+    pub fn strip(code: &[Instruction], symbols: &SymbolTable, lines: &LineTable) -> (Vec<Instruction>, SymbolTable, LineTable, Vec<usize>) {
+        let mut reachable = vec![false; code.len()];
+        let mut targeted = vec![false; code.len()];
+        let mut pending = vec![0];
+        if let Some((&handler, _)) = symbols.iter().find(|(_, label)| label.as_str() == crate::vm::INTERRUPT_VECTOR_LABEL) {
+            targeted[handler] = true;
+            pending.push(handler);
+        }
+        while let Some(pc) = pending.pop() {
+            if pc >= code.len() || reachable[pc] {
+                continue;
+            }
+            reachable[pc] = true;
+            let (target, falls_through) = match &code[pc] {
+                Instruction::Jump(JumpType::Unconditional, target) => (Some(*target), false),
+                Instruction::Jump(JumpType::Flag(..) | JumpType::CompareNotEqual(..), target) => (Some(*target), true),
+                // the call returns to the instruction after it
+                Instruction::Call(CallType::Local(target)) => (Some(*target), true),
+                Instruction::Return | Instruction::ReturnInterrupt | Instruction::Exit(_) => (None, false),
+                _ => (None, true)
+            };
+            if let Some(target) = target.filter(|target| *target < code.len()) {
+                targeted[target] = true;
+                pending.push(target);
+            }
+            if falls_through {
+                pending.push(pc + 1);
+            }
+        }
+
+        // labels are only ever run by falling through to them, and do nothing, so unused ones can go too
+        let kept: Vec<bool> = (0..code.len()).map(|pc| reachable[pc] && (targeted[pc] || !symbols.contains_key(&pc))).collect();
+        let mut relocated = vec![0; code.len()];
+        let mut next = 0;
+        for (pc, &keep) in kept.iter().enumerate() {
+            relocated[pc] = next;
+            next += keep as usize;
+        }
+        let stripped_code = code.iter().enumerate().filter(|(pc, _)| kept[*pc]).map(|(_, instruction)| match instruction {
+            Instruction::Jump(jump_type, target) => Instruction::Jump(*jump_type, relocated[*target]),
+            Instruction::Call(CallType::Local(target)) => Instruction::Call(CallType::Local(relocated[*target])),
+            instruction => instruction.clone()
+        }).collect();
+        let stripped_symbols = symbols.iter().filter(|(pc, _)| kept[**pc]).map(|(pc, label)| (relocated[*pc], label.clone())).collect();
+        let stripped_lines = lines.iter().enumerate().filter(|(pc, _)| kept[*pc]).map(|(_, line)| *line).collect();
+        let removed = (0..code.len()).filter(|pc| !kept[*pc]).collect();
+        (stripped_code, stripped_symbols, stripped_lines, removed)
+    }
 
     pub fn process_labels(code: Vec<Instruction>, externs: Option<DevolaExternTable>) -> Result<(Vec<Instruction>, SymbolTable), Vec<(String, usize)>> {
         let extern_table = externs.unwrap_or(HashMap::new());
@@ -494,7 +1234,7 @@ pub mod intermediate {
                 match instruction {
                     Instruction::_LabeledJump(jump_type, label) => {
                         if let Some(pc) = jump_table.get(label) {
-                            Some(Instruction::Jump(jump_type.clone(), *pc))
+                            Some(Instruction::Jump(*jump_type, *pc))
                         } else {
                             missing_labels.push((format!("Jump: {}", label.clone()), line));
                             None
@@ -579,5 +1319,84 @@ pub mod intermediate {
                 }
             };
         }
+
+        #[test]
+        fn test_label_containing_hlt() {
+            let source = "jmp xhlt\nhlt\nxhlt:\nhlt";
+            let (code, _, _, _) = crate::parser::text::compile(String::from(source), None).unwrap();
+            assert_eq!(code.iter().filter(|instruction| matches!(instruction, Instruction::Halt)).count(), 2);
+        }
+
+        #[test]
+        fn test_exit_code() {
+            let (code, _, _, _) = crate::parser::text::compile(String::from("hlt 7"), None).unwrap();
+            assert!(matches!(code[..], [Instruction::Exit(7)]));
+            assert!(crate::parser::text::compile(String::from("xhlt 7"), None).is_err());
+        }
+
+        #[test]
+        fn test_optimize() {
+            let source = "lda 2\npush b\npop b\nldb a\nldb 3\nldb b\nadd 0\nsta #0200h\ncmp b\njmp next\nnext:\nadd 0\njz end\nsub 0\nend:";
+            let (code, _, lines, _) = crate::parser::text::compile_optimized(String::from(source), None).unwrap();
+            // the second `add 0` stays, as `jz` reads the flags it sets
+            assert_eq!(code.iter().map(|instruction| instruction.to_string()).collect::<Vec<_>>(), [
+                "lda 2", "ldb 3", "sta #512", "cmp b", "nop", "add 0", "jz l8", "sub 0", "nop"
+            ]);
+            assert_eq!(lines, [0, 4, 7, 8, 10, 11, 12, 13, 14]);
+
+            // optimized programs end up the same as they would have, in fewer cycles
+            for sample in ["sample/square.pop", "sample/square_subroutines.pop", "sample/stdlib_tests/memgetn.pop"] {
+                let source = crate::util::read_from_file(std::path::Path::new(sample));
+                let (code, symbols, _, _) = crate::parser::text::compile(source.clone(), None).unwrap();
+                let mut devola = Devola::new(code, Some(symbols));
+                devola.run().unwrap();
+                let (code, symbols, _, _) = crate::parser::text::compile_optimized(source, None).unwrap();
+                let mut optimized = Devola::new(code, Some(symbols));
+                optimized.run().unwrap();
+                let registers = [Register::Accumulator, Register::UtilityB, Register::UtilityC, Register::IndexX, Register::IndexY];
+                assert_eq!(registers.map(|register| optimized.register(register)), registers.map(|register| devola.register(register)));
+                assert!(optimized.cycles() <= devola.cycles());
+            }
+        }
+
+        #[test]
+        fn test_strip() {
+            let source = "lda 1\ncall double\njnz done\nunused:\nlda 9\ndone:\njmp end\nlda 2 ; dead\ndouble:\nadd a\nret\nsub 1 ; dead\nirq:\nreti\nend:";
+            let (code, symbols, lines, _) = crate::parser::text::compile(String::from(source), None).unwrap();
+            let (stripped, stripped_symbols, stripped_lines, removed) = strip(&code, &symbols, &lines);
+            assert_eq!(removed, [3, 7, 11]);
+            assert_eq!(stripped.iter().map(|instruction| instruction.to_string()).collect::<Vec<_>>(), [
+                "lda 1", "call l6", "jnz l4", "lda 9", "nop", "jmp l11", "nop", "add a", "ret", "nop", "reti", "nop"
+            ]);
+            assert_eq!(stripped_lines, [0, 1, 2, 4, 5, 6, 8, 9, 10, 12, 13, 14]);
+            let mut labels: Vec<_> = stripped_symbols.values().cloned().collect();
+            labels.sort();
+            assert_eq!(labels, ["done", "double", "end", "irq"]);
+
+            let mut devola = Devola::new(stripped, Some(stripped_symbols));
+            devola.run().unwrap();
+            assert_eq!(devola.register(Register::Accumulator), 2);
+        }
+
+        #[test]
+        fn test_export_symbols() {
+            let code: Vec<Instruction> = vec![
+                Instruction::_Label(String::from("start")),
+                Instruction::Nop,
+                Instruction::_Label(String::from("end")),
+                Instruction::_LabeledJump(JumpType::Unconditional, String::from("start"))
+            ];
+            let (_, symbols) = process_labels(code, None).unwrap();
+            assert_eq!(export_symbols(&symbols), "0 start\n2 end\n");
+            assert_eq!(import_symbols(&export_symbols(&symbols)), Some(symbols.clone()));
+            assert_eq!(import_symbols("start 0\n"), None);
+
+            // symbol files add the source lines, and can be read without them
+            let file = export_symbol_file(&symbols, &vec![0, 0, 3, 4]);
+            assert_eq!(file, "0 start\n2 end\n.lines\n1\n1\n4\n5\n");
+            assert_eq!(import_symbol_file(&file), Some((symbols.clone(), vec![0, 0, 3, 4])));
+            assert_eq!(import_symbol_file("0 start\n2 end\n"), Some((symbols, Vec::new())));
+            assert_eq!(import_symbol_file("0 start\n.lines\n0\n"), None);
+        }
     }
 }
\ No newline at end of file
@@ -0,0 +1,305 @@
+//! Recording what a program does as newline-delimited JSON, one record per line, for analysing a run offline or
+//! lining two runs up to find where they diverge. Each record has an `"event"`, the number of instructions
+//! executed before it (`"step"`) and the VM's cycle count once it happened (`"cycles"`):
+//!
+//! ```json
+//! {"event":"instruction","step":0,"cycles":2,"pc":0,"instruction":"lda 3","registers":[3,0,0,0,0],"flags":0}
+//! {"event":"instruction","step":1,"cycles":7,"pc":1,"instruction":"sta #512","registers":[3,0,0,0,0],"flags":0}
+//! {"event":"write","step":1,"cycles":7,"address":512,"value":3}
+//! {"event":"interrupt","step":1,"cycles":12,"lines":1}
+//! {"event":"fault","step":2,"cycles":12,"pc":9,"error":"stack overflow at instruction 9"}
+//! ```
+//!
+//! Registers are listed in the order A, B, C, X, Y, and flags are packed as `0b0000SPZC`. Writes follow the
+//! instruction that made them, and an interrupt follows the instruction it was taken after; the flags and
+//! return address pushed on entering the handler are among that instruction's writes.
+use crate::instructions::{Flag, Instruction, Register};
+use crate::vm::{Devola, DevolaError};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io::{self, BufRead, Write};
+
+const REGISTERS: [Register; 5] = [
+    Register::Accumulator, Register::UtilityB, Register::UtilityC, Register::IndexX, Register::IndexY
+];
+const FLAGS: [Flag; 4] = [Flag::Carry, Flag::Zero, Flag::Parity, Flag::Sign];
+/// The registers as they're listed in a record
+const REGISTER_NAMES: [&str; 5] = ["a", "b", "c", "x", "y"];
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "lowercase")]
+pub enum TraceRecord {
+    /// An instruction was executed, leaving the registers and flags as given
+    Instruction { step: u64, cycles: u64, pc: usize, instruction: String, registers: [u8; 5], flags: u8 },
+    /// A byte of memory was written by the last instruction
+    Write { step: u64, cycles: u64, address: u16, value: u8 },
+    /// An interrupt handler was entered, for the enabled lines that were requested
+    Interrupt { step: u64, cycles: u64, lines: u8 },
+    /// The instruction at `pc` faulted, and wasn't executed
+    Fault { step: u64, cycles: u64, pc: usize, error: String }
+}
+
+/// A VM whose execution is written to a trace as it runs
+pub struct TraceRecorder<W: Write> {
+    pub devola: Devola,
+    writer: W,
+    /// Instructions executed so far
+    steps: u64
+}
+
+impl<W: Write> TraceRecorder<W> {
+    pub fn new(mut devola: Devola, writer: W) -> TraceRecorder<W> {
+        devola.record_writes(true);
+        TraceRecorder { devola, writer, steps: 0 }
+    }
+
+    /// Stops recording, handing back the VM and the writer
+    pub fn finish(mut self) -> io::Result<(Devola, W)> {
+        self.writer.flush()?;
+        self.devola.record_writes(false);
+        Ok((self.devola, self.writer))
+    }
+
+    /// Where the trace is being written, e.g. to take what's been recorded so far out of a buffer
+    pub fn writer_mut(&mut self) -> &mut W {
+        &mut self.writer
+    }
+
+    fn write_record(&mut self, record: TraceRecord) -> io::Result<()> {
+        serde_json::to_writer(&mut self.writer, &record)?;
+        self.writer.write_all(b"\n")
+    }
+
+    /// Executes a single instruction, recording it along with its writes and any interrupt taken after it.
+    /// Writes made to the VM's memory since the last step, e.g. by a frontend, aren't recorded.
+    pub fn step(&mut self) -> io::Result<Result<(), DevolaError>> {
+        self.devola.take_writes();
+        let pc = self.devola.pc();
+        let instruction = self.devola.code().get(pc).cloned();
+        let was_in_interrupt = self.devola.in_interrupt();
+        let result = self.devola.step();
+        let (step, cycles) = (self.steps, self.devola.cycles());
+        match (result, instruction) {
+            (Err(DevolaError::EndCode), _) | (_, None) => return Ok(result),
+            (Err(error), _) => {
+                self.write_record(TraceRecord::Fault { step, cycles, pc, error: error.to_string() })?;
+                return Ok(Err(error));
+            },
+            (Ok(()), Some(instruction)) => {
+                let registers = REGISTERS.map(|register| self.devola.register(register));
+                let flags = FLAGS.iter().enumerate()
+                    .fold(0, |flags, (bit, &flag)| flags | (self.devola.flag(flag) as u8) << bit);
+                let record = TraceRecord::Instruction { step, cycles, pc, instruction: instruction.to_string(), registers, flags };
+                self.write_record(record)?;
+                for address in self.devola.take_writes() {
+                    let value = self.devola.peek(address);
+                    self.write_record(TraceRecord::Write { step, cycles, address, value })?;
+                }
+                // a `reti` can be followed straight away by the next handler
+                let entered = !was_in_interrupt || matches!(instruction, Instruction::ReturnInterrupt);
+                if entered && self.devola.in_interrupt() {
+                    // the handler hasn't run yet, so the lines it was entered for are still requested
+                    let memory_map = self.devola.memory_map();
+                    let lines = self.devola.peek(memory_map.interrupt_enable()) & self.devola.peek(memory_map.interrupt_request());
+                    self.write_record(TraceRecord::Interrupt { step, cycles, lines })?;
+                }
+            }
+        }
+        self.steps += 1;
+        Ok(Ok(()))
+    }
+
+    /// Runs until at least `cycles` cycles have elapsed or the end of the code is reached, like
+    /// `Devola::run_cycles`
+    pub fn run_cycles(&mut self, cycles: u64) -> io::Result<Result<u64, DevolaError>> {
+        let start = self.devola.cycles();
+        while self.devola.cycles() - start < cycles {
+            match self.step()? {
+                Err(DevolaError::EndCode) => break,
+                Err(error) => return Ok(Err(error)),
+                Ok(()) => ()
+            }
+        }
+        Ok(Ok(self.devola.cycles() - start))
+    }
+
+    /// Runs until the end of the code, like `Devola::run`
+    pub fn run(&mut self) -> io::Result<Result<(), DevolaError>> {
+        loop {
+            match self.step()? {
+                Err(DevolaError::EndCode) => return Ok(Ok(())),
+                Err(error) => return Ok(Err(error)),
+                Ok(()) => ()
+            }
+        }
+    }
+}
+
+/// Reads a trace back, one record per line
+pub fn read(reader: impl BufRead) -> impl Iterator<Item = io::Result<TraceRecord>> {
+    reader.lines().map(|line| Ok(serde_json::from_str(&line?)?))
+}
+
+/// Everything recorded for one instruction: the instruction, or the fault that stopped it, followed by the writes
+/// it made and the lines of the interrupt taken after it, if one was
+#[derive(Clone, Debug, PartialEq)]
+pub struct TraceStep {
+    pub record: TraceRecord,
+    pub writes: Vec<(u16, u8)>,
+    pub interrupt: Option<u8>
+}
+
+/// Written as the instruction and where it was, e.g. `12: sta #512 at cycle 208`
+impl fmt::Display for TraceStep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.record {
+            TraceRecord::Instruction { cycles, pc, instruction, .. } => write!(f, "{}: {} at cycle {}", pc, instruction, cycles),
+            TraceRecord::Fault { cycles, pc, error, .. } => write!(f, "{}: {} at cycle {}", pc, error, cycles),
+            record => write!(f, "{:?}", record)
+        }
+    }
+}
+
+/// Groups a trace's records by the instruction they belong to
+pub fn steps<I: Iterator<Item = io::Result<TraceRecord>>>(records: I) -> impl Iterator<Item = io::Result<TraceStep>> {
+    let mut records = records.peekable();
+    std::iter::from_fn(move || {
+        let mut step = match records.next()? {
+            Ok(record) => TraceStep { record, writes: Vec::new(), interrupt: None },
+            Err(error) => return Some(Err(error))
+        };
+        while let Some(Ok(TraceRecord::Write { .. } | TraceRecord::Interrupt { .. })) = records.peek() {
+            match records.next() {
+                Some(Ok(TraceRecord::Write { address, value, .. })) => step.writes.push((address, value)),
+                Some(Ok(TraceRecord::Interrupt { lines, .. })) => step.interrupt = Some(lines),
+                _ => unreachable!()
+            }
+        }
+        Some(Ok(step))
+    })
+}
+
+/// Where two traces first differ: the step, what each did there, or `None` if it had already ended, and how
+/// they differ
+#[derive(Clone, Debug, PartialEq)]
+pub struct Divergence {
+    pub step: u64,
+    pub left: Option<TraceStep>,
+    pub right: Option<TraceStep>,
+    pub differences: Vec<String>
+}
+
+/// Lines up two traces step by step, returning the first where the instruction run, the registers or flags it
+/// left, its writes, an interrupt taken after it or a fault differ. Cycle counts aren't compared, so that runs
+/// can be compared across changes to instruction timings until those change what the program does.
+pub fn diff(left: impl BufRead, right: impl BufRead) -> io::Result<Option<Divergence>> {
+    let (mut left, mut right) = (steps(read(left)), steps(read(right)));
+    let mut step = 0;
+    loop {
+        let (left_step, right_step) = (left.next().transpose()?, right.next().transpose()?);
+        let differences = match (&left_step, &right_step) {
+            (None, None) => return Ok(None),
+            (Some(_), None) => vec![String::from("the second trace ends first")],
+            (None, Some(_)) => vec![String::from("the first trace ends first")],
+            (Some(left_step), Some(right_step)) => differences(left_step, right_step)
+        };
+        if !differences.is_empty() {
+            return Ok(Some(Divergence { step, left: left_step, right: right_step, differences }));
+        }
+        step += 1;
+    }
+}
+
+fn differences(left: &TraceStep, right: &TraceStep) -> Vec<String> {
+    let mut differences = Vec::new();
+    let mut compare = |name: &str, left: String, right: String| {
+        if left != right {
+            differences.push(format!("{} {} != {}", name, left, right));
+        }
+    };
+    match (&left.record, &right.record) {
+        (
+            TraceRecord::Instruction { pc, instruction, registers, flags, .. },
+            TraceRecord::Instruction { pc: right_pc, instruction: right_instruction, registers: right_registers, flags: right_flags, .. }
+        ) => {
+            compare("pc", pc.to_string(), right_pc.to_string());
+            compare("instruction", instruction.clone(), right_instruction.clone());
+            for (index, name) in REGISTER_NAMES.iter().enumerate() {
+                compare(name, registers[index].to_string(), right_registers[index].to_string());
+            }
+            compare("flags", format!("{:04b}", flags), format!("{:04b}", right_flags));
+        },
+        (TraceRecord::Fault { pc, error, .. }, TraceRecord::Fault { pc: right_pc, error: right_error, .. }) => {
+            compare("pc", pc.to_string(), right_pc.to_string());
+            compare("fault", error.clone(), right_error.clone());
+        },
+        (TraceRecord::Fault { error, .. }, _) => compare("fault", error.clone(), String::from("none")),
+        (_, TraceRecord::Fault { error, .. }) => compare("fault", String::from("none"), error.clone()),
+        (left, right) => compare("record", format!("{:?}", left), format!("{:?}", right))
+    }
+    let writes = |writes: &[(u16, u8)]| {
+        let writes: Vec<String> = writes.iter().map(|(address, value)| format!("{:04X}h={}", address, value)).collect();
+        format!("[{}]", writes.join(", "))
+    };
+    compare("writes", writes(&left.writes), writes(&right.writes));
+    let interrupt = |lines: Option<u8>| lines.map_or_else(|| String::from("none"), |lines| format!("{:08b}", lines));
+    compare("interrupt", interrupt(left.interrupt), interrupt(right.interrupt));
+    differences
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instructions::CallType;
+    use crate::parser::text::compile;
+
+    #[test]
+    fn test_recorder() {
+        let source = String::from("lda 1\nsta #0FFEh\nsta #0FFFh\nlda 3\nsta #0200h\nend:\njmp end\nirq:\nlda 0\nsta #0FFFh\nreti");
+        let (code, symbols, _, _) = compile(source, None).unwrap();
+        let mut recorder = TraceRecorder::new(Devola::new(code, Some(symbols)), Vec::new());
+        assert!(recorder.run_cycles(100).unwrap().is_ok());
+        let (devola, trace) = recorder.finish().unwrap();
+        let records: Vec<TraceRecord> = read(trace.as_slice()).map(Result::unwrap).collect();
+        assert_eq!(records[0], TraceRecord::Instruction {
+            step: 0, cycles: devola.code()[0].cycles(), pc: 0, instruction: String::from("lda 1"), registers: [1, 0, 0, 0, 0], flags: 0
+        });
+        // requesting line 0 enters the handler straight after the instruction that requested it
+        let interrupt = records.iter().position(|record| matches!(record, TraceRecord::Interrupt { .. })).unwrap();
+        assert!(records[..interrupt].iter().any(|record| matches!(record, TraceRecord::Write { step: 2, address: 0x0FFF, value: 1, .. })));
+        assert!(matches!(records[interrupt], TraceRecord::Interrupt { step: 2, lines: 1, .. }));
+        assert!(matches!(records[interrupt + 1], TraceRecord::Instruction { step: 3, pc: 7, .. }));
+        // the handler returns to where the program left off, and writes to memory
+        assert!(records.iter().any(|record| matches!(record, TraceRecord::Write { step: 8, address: 0x0200, value: 3, .. })));
+        assert_eq!(devola.peek(0x0200), 3);
+
+        let mut recorder = TraceRecorder::new(Devola::new(vec![Instruction::Call(CallType::Library(String::from("missing")))], None), Vec::new());
+        assert!(recorder.step().unwrap().is_err());
+        let (_, trace) = recorder.finish().unwrap();
+        let records: Vec<TraceRecord> = read(trace.as_slice()).map(Result::unwrap).collect();
+        assert!(matches!(records[..], [TraceRecord::Fault { step: 0, pc: 0, .. }]));
+    }
+
+    #[test]
+    fn test_diff() {
+        let trace = |source: &str| {
+            let (code, symbols, _, _) = compile(String::from(source), None).unwrap();
+            let mut recorder = TraceRecorder::new(Devola::new(code, Some(symbols)), Vec::new());
+            let _ = recorder.run().unwrap();
+            recorder.finish().unwrap().1
+        };
+        let original = trace("lda 1\nsta #0200h\nadd 2\nsta #0201h");
+        assert_eq!(diff(original.as_slice(), original.as_slice()).unwrap(), None);
+
+        let divergence = diff(original.as_slice(), trace("lda 1\nsta #0200h\nadd 3\nsta #0201h").as_slice()).unwrap().unwrap();
+        assert_eq!(divergence.step, 2);
+        assert_eq!(divergence.differences, ["instruction add 2 != add 3", "a 3 != 4", "flags 0100 != 0000"]);
+        assert_eq!(divergence.left.unwrap().to_string(), "2: add 2 at cycle 9");
+
+        // the same value written somewhere else, and a trace that runs out
+        let divergence = diff(original.as_slice(), trace("lda 1\nsta #0202h").as_slice()).unwrap().unwrap();
+        assert_eq!((divergence.step, &divergence.differences[1]), (1, &String::from("writes [0200h=1] != [0202h=1]")));
+        let divergence = diff(original.as_slice(), trace("lda 1\nsta #0200h").as_slice()).unwrap().unwrap();
+        assert_eq!((divergence.step, divergence.right), (2, None));
+    }
+}
@@ -0,0 +1,63 @@
+//! Counts of what a VM has done, for frontends to show and benchmarks to compare.
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// How much a VM has run since it was made. Cycles are the VM's own count, which loading a state sets back,
+/// while the other counts only ever go up.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Stats {
+    /// Instructions executed, not counting cycles spent waiting in a `hlt`
+    pub instructions: u64,
+    pub cycles: u64,
+    /// How many times each library function has been called, by name
+    pub extern_calls: HashMap<String, u64>
+}
+
+impl Stats {
+    /// How long the cycles would have taken on hardware clocked at `cycles_per_second`
+    pub fn emulated_seconds(&self, cycles_per_second: u64) -> f64 {
+        self.cycles as f64 / cycles_per_second as f64
+    }
+
+    /// How many times faster than hardware clocked at `cycles_per_second` the VM ran between `earlier` and these
+    /// stats, which took `elapsed` to run. Less than 1 is slower than the hardware.
+    pub fn speed(&self, earlier: &Stats, elapsed: Duration, cycles_per_second: u64) -> f64 {
+        let emulated = self.cycles.saturating_sub(earlier.cycles) as f64 / cycles_per_second as f64;
+        emulated / elapsed.as_secs_f64()
+    }
+
+    /// All the library calls made, whichever function they were to
+    pub fn total_extern_calls(&self) -> u64 {
+        self.extern_calls.values().sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parser::text::compile;
+    use crate::stdlib::interface::{DevolaExtern, DevolaExternTable};
+    use crate::vm::Devola;
+    use std::time::Duration;
+
+    #[test]
+    fn test_stats() {
+        let externs = || {
+            let mut externs = DevolaExternTable::new();
+            externs.insert(String::from("beep"), Box::new(|_: &mut Devola| ()) as Box<DevolaExtern>);
+            externs
+        };
+        let (code, symbols, _, _) = compile(String::from("call beep\ncall beep\nlda 1"), Some(externs())).unwrap();
+        let mut devola = Devola::new(code, Some(symbols));
+        devola.set_externs(externs());
+        let start = devola.stats();
+        devola.run().unwrap();
+
+        let stats = devola.stats();
+        assert_eq!((stats.instructions, stats.cycles), (3, 12));
+        assert_eq!(devola.instructions(), stats.instructions);
+        assert_eq!((stats.extern_calls["beep"], stats.total_extern_calls()), (2, 2));
+        assert_eq!(stats.emulated_seconds(4), 3.0);
+        // 12 cycles at 4 a second is 3 seconds, run in half a second
+        assert_eq!(stats.speed(&start, Duration::from_millis(500), 4), 6.0);
+    }
+}
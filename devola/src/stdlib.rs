@@ -1,13 +1,41 @@
-use crate::vm::Devola;
+use crate::instructions::Register;
+use crate::vm::{Devola, FaultLocation};
 
-/// Returns a read-only slice of memory.
-pub fn memgetn(devola: &mut Devola, start: u16, size: u16) -> &[u8] {
-    &devola.memory.memory[start as usize..(start+size) as usize]
+/// A ring buffer starts with its capacity, the offset of its oldest byte and how many bytes it holds, followed by
+/// room for `capacity` bytes
+pub const RING_BUFFER_HEADER: u16 = 3;
+
+/// A music player is the address of the next command of its song, followed by how many more frames to wait
+pub const MUSIC_PLAYER_SIZE: u16 = 3;
+/// Songs are streams of commands, each a byte followed by its arguments: the song ends at `MUSIC_END`,
+/// `MUSIC_WRITE` writes a byte to an address (MSB first), `MUSIC_WAIT` waits a number of frames and `MUSIC_JUMP`
+/// carries on from an address, e.g. to loop
+pub const MUSIC_END: u8 = 0;
+pub const MUSIC_WRITE: u8 = 1;
+pub const MUSIC_WAIT: u8 = 2;
+pub const MUSIC_JUMP: u8 = 3;
+/// How many commands a frame can run, so that a song jumping around without waiting can't hang the VM
+const MUSIC_COMMANDS_PER_FRAME: usize = 256;
+/// The longest string `read_string` reads, so that a missing terminator can't run through all of memory
+const STRING_LENGTH_LIMIT: u16 = 256;
+
+/// Returns a read-only slice of memory, or None if the range runs past the end of memory.
+pub fn memgetn(devola: &Devola, start: u16, size: u16) -> Option<&[u8]> {
+    devola.memory().view(start, size as usize).map(|view| view.bytes())
 }
 /// Sets all bytes in the range to 0.
 pub fn memclear(devola: &mut Devola, start: u16, size: u16) {
-    for i in start..start+size {
-        devola.memory[i] = 0;
+    memfill(devola, start, size, 0);
+}
+/// Sets all bytes in the range to `value`.
+pub fn memfill(devola: &mut Devola, start: u16, size: u16, value: u8) {
+    memfill_stride(devola, start, size, 1, value);
+}
+/// Sets every `stride`th byte in the range to `value`, starting with the first, e.g. one byte of each entry in a
+/// table. A stride of 0 is taken as 1, and a range past the end of memory wraps around to the start.
+pub fn memfill_stride(devola: &mut Devola, start: u16, size: u16, stride: u16, value: u8) {
+    for offset in (0..size).step_by(stride.max(1) as usize) {
+        devola.memory[start.wrapping_add(offset)] = value;
     }
 }
 /// Copies all data from the source slice to the destination slice.
@@ -19,8 +47,115 @@ pub fn memcpy(devola: &mut Devola, source: u16, destination: u16, size: u16) {
 /// Copy bytes from a source buffer
 pub fn memset(devola: &mut Devola, source: &[u8], destination: u16, size: u16) {
     for i in 0..size {
-        devola.memory[destination+i] = source[i as usize];
+        devola.memory[destination.wrapping_add(i)] = source[i as usize];
+    }
+}
+/// Sets up an empty ring buffer at `buffer`, taking `RING_BUFFER_HEADER + capacity` bytes.
+pub fn rb_init(devola: &mut Devola, buffer: u16, capacity: u8) {
+    memset(devola, &[capacity, 0, 0], buffer, RING_BUFFER_HEADER);
+}
+/// The capacity, head and length of a ring buffer. A buffer, like the rest of memory, wraps around its end, and one
+/// with no capacity is always empty.
+fn rb_header(devola: &Devola, buffer: u16) -> (u8, u8, u8) {
+    let capacity = devola.memory[buffer];
+    let length = if capacity == 0 { 0 } else { devola.memory[buffer.wrapping_add(2)] };
+    (capacity, devola.memory[buffer.wrapping_add(1)], length)
+}
+/// Adds a byte to the end of a ring buffer, returning false without adding it if the buffer is full.
+pub fn rb_push(devola: &mut Devola, buffer: u16, value: u8) -> bool {
+    let (capacity, head, length) = rb_header(devola, buffer);
+    if length >= capacity {
+        return false;
+    }
+    let tail = (head as u16 + length as u16) % capacity as u16;
+    devola.memory[buffer.wrapping_add(RING_BUFFER_HEADER + tail)] = value;
+    devola.memory[buffer.wrapping_add(2)] = length + 1;
+    true
+}
+/// Takes the oldest byte from a ring buffer, if it has any.
+pub fn rb_pop(devola: &mut Devola, buffer: u16) -> Option<u8> {
+    let (capacity, head, length) = rb_header(devola, buffer);
+    if length == 0 {
+        return None;
+    }
+    let value = devola.memory[buffer.wrapping_add(RING_BUFFER_HEADER + head as u16)];
+    devola.memory[buffer.wrapping_add(1)] = ((head as u16 + 1) % capacity as u16) as u8;
+    devola.memory[buffer.wrapping_add(2)] = length - 1;
+    Some(value)
+}
+/// The number of bytes in a ring buffer.
+pub fn rb_len(devola: &mut Devola, buffer: u16) -> u8 {
+    rb_header(devola, buffer).2
+}
+/// Reads the string of bytes at `address`, up to the first 0.
+pub fn read_string(devola: &Devola, address: u16) -> String {
+    let bytes: Vec<u8> = (0..STRING_LENGTH_LIMIT)
+        .map(|offset| devola.peek(address.wrapping_add(offset)))
+        .take_while(|&byte| byte != 0)
+        .collect();
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+/// Checks that `actual` is `expected`, returning a report of the failure if it isn't: the message at `message`,
+/// where the check was made from and, while debugging, the local calls that led there.
+pub fn assert_eq(devola: &Devola, expected: u8, actual: u8, message: u16) -> Option<String> {
+    if actual == expected {
+        return None;
+    }
+    let place = |pc: usize| {
+        let location = FaultLocation { pc, line: devola.line(pc) };
+        match devola.enclosing_label(pc) {
+            Some(label) => format!("{} in {}", location, label),
+            None => location.to_string()
+        }
+    };
+    let mut report = format!("assertion failed at {}: expected {}, got {}: {}", place(devola.pc()), expected, actual, read_string(devola, message));
+    for pc in devola.backtrace() {
+        report.push_str(&format!("\n    called from {}", place(pc)));
+    }
+    Some(report)
+}
+/// Starts playing the song at `song` with the player at `player`, taking `MUSIC_PLAYER_SIZE` bytes.
+pub fn music_play(devola: &mut Devola, player: u16, song: u16) {
+    let (msb, lsb) = crate::util::break_u16(song);
+    for (offset, value) in [msb, lsb, 0].into_iter().enumerate() {
+        devola.memory[player.wrapping_add(offset as u16)] = value;
+    }
+}
+/// Plays a frame of a song, running its commands up to the next wait. Returns false once the song has ended.
+pub fn music_tick(devola: &mut Devola, player: u16) -> bool {
+    let wait = devola.memory[player.wrapping_add(2)];
+    if wait > 0 {
+        devola.memory[player.wrapping_add(2)] = wait - 1;
+        return true;
     }
+    let mut pointer = crate::util::build_u16(devola.memory[player], devola.memory[player.wrapping_add(1)]);
+    let mut playing = true;
+    for _ in 0..MUSIC_COMMANDS_PER_FRAME {
+        let argument = [1, 2, 3].map(|offset| devola.memory[pointer.wrapping_add(offset)]);
+        match devola.memory[pointer] {
+            MUSIC_WRITE => {
+                let address = crate::util::build_u16(argument[0], argument[1]);
+                devola.memory[address] = argument[2];
+                pointer = pointer.wrapping_add(4);
+            },
+            MUSIC_WAIT => {
+                // this frame is the first of the wait
+                devola.memory[player.wrapping_add(2)] = argument[0].saturating_sub(1);
+                pointer = pointer.wrapping_add(2);
+                break;
+            },
+            MUSIC_JUMP => pointer = crate::util::build_u16(argument[0], argument[1]),
+            // anything else ends the song, staying there
+            _ => {
+                playing = false;
+                break;
+            }
+        }
+    }
+    let (msb, lsb) = crate::util::break_u16(pointer);
+    devola.memory[player] = msb;
+    devola.memory[player.wrapping_add(1)] = lsb;
+    playing
 }
 
 pub mod interface {
@@ -40,6 +175,32 @@ pub mod interface {
         memclear(devola, start, size);
     }
 
+    /// `memfill(start_hi, start_lo, size_hi, size_lo, value)`
+    ///
+    /// Accepts arguments from the stack. Sets the specified range of `size` bytes
+    /// starting at `start` in memory to `value`.
+    pub fn i_memfill(devola: &mut Devola) {
+        let value = devola.pop();
+        let (size_lo, size_hi) = (devola.pop(), devola.pop());
+        let (start_lo, start_hi) = (devola.pop(), devola.pop());
+        let size = util::build_u16(size_hi, size_lo);
+        let start = util::build_u16(start_hi, start_lo);
+        memfill(devola, start, size, value);
+    }
+
+    /// `memfill_stride(start_hi, start_lo, size_hi, size_lo, stride, value)`
+    ///
+    /// Accepts arguments from the stack. Sets every `stride`th byte of the specified range
+    /// of `size` bytes starting at `start` in memory to `value`.
+    pub fn i_memfill_stride(devola: &mut Devola) {
+        let (value, stride) = (devola.pop(), devola.pop());
+        let (size_lo, size_hi) = (devola.pop(), devola.pop());
+        let (start_lo, start_hi) = (devola.pop(), devola.pop());
+        let size = util::build_u16(size_hi, size_lo);
+        let start = util::build_u16(start_hi, start_lo);
+        memfill_stride(devola, start, size, stride as u16, value);
+    }
+
     /// `memcpy(source_hi, source_lo, dest_hi, dest_lo, size_hi, size_lo)`
     ///
     /// Accepts arguments from the stack. Copies `size` bytes starting from `source` to the
@@ -54,6 +215,78 @@ pub mod interface {
         memcpy(devola, source, destination, size);
     }
 
+    /// `rb_init(buffer_hi, buffer_lo, capacity)`
+    ///
+    /// Accepts arguments from the stack. Sets up an empty ring buffer of `capacity` bytes at
+    /// `buffer`, which takes up `capacity + 3` bytes of memory.
+    pub fn i_rb_init(devola: &mut Devola) {
+        let capacity = devola.pop();
+        let (buffer_lo, buffer_hi) = (devola.pop(), devola.pop());
+        rb_init(devola, util::build_u16(buffer_hi, buffer_lo), capacity);
+    }
+
+    /// `rb_push(buffer_hi, buffer_lo, value)`
+    ///
+    /// Accepts arguments from the stack. Adds `value` to the end of the ring buffer at `buffer`,
+    /// setting `B` to 1 if it was added or 0 if the buffer was full.
+    pub fn i_rb_push(devola: &mut Devola) {
+        let value = devola.pop();
+        let (buffer_lo, buffer_hi) = (devola.pop(), devola.pop());
+        devola.memory[Register::UtilityB] = rb_push(devola, util::build_u16(buffer_hi, buffer_lo), value) as u8;
+    }
+
+    /// `rb_pop(buffer_hi, buffer_lo)`
+    ///
+    /// Accepts arguments from the stack. Takes the oldest byte from the ring buffer at `buffer`
+    /// into `B`, setting `C` to 1, or sets both to 0 if the buffer was empty.
+    pub fn i_rb_pop(devola: &mut Devola) {
+        let (buffer_lo, buffer_hi) = (devola.pop(), devola.pop());
+        let popped = rb_pop(devola, util::build_u16(buffer_hi, buffer_lo));
+        devola.memory[Register::UtilityB] = popped.unwrap_or(0);
+        devola.memory[Register::UtilityC] = popped.is_some() as u8;
+    }
+
+    /// `rb_len(buffer_hi, buffer_lo)`
+    ///
+    /// Accepts arguments from the stack. Sets `B` to the number of bytes in the ring buffer at
+    /// `buffer`.
+    pub fn i_rb_len(devola: &mut Devola) {
+        let (buffer_lo, buffer_hi) = (devola.pop(), devola.pop());
+        devola.memory[Register::UtilityB] = rb_len(devola, util::build_u16(buffer_hi, buffer_lo));
+    }
+
+    /// `music_play(player_hi, player_lo, song_hi, song_lo)`
+    ///
+    /// Accepts arguments from the stack. Starts the player at `player`, which takes up 3 bytes of
+    /// memory, playing the song at `song`.
+    pub fn i_music_play(devola: &mut Devola) {
+        let (song_lo, song_hi) = (devola.pop(), devola.pop());
+        let (player_lo, player_hi) = (devola.pop(), devola.pop());
+        music_play(devola, util::build_u16(player_hi, player_lo), util::build_u16(song_hi, song_lo));
+    }
+
+    /// `music_tick(player_hi, player_lo)`
+    ///
+    /// Accepts arguments from the stack. Plays a frame of the song of the player at `player`,
+    /// setting `B` to 1 while it's playing or 0 once it has ended. Call it once a frame.
+    pub fn i_music_tick(devola: &mut Devola) {
+        let (player_lo, player_hi) = (devola.pop(), devola.pop());
+        devola.memory[Register::UtilityB] = music_tick(devola, util::build_u16(player_hi, player_lo)) as u8;
+    }
+
+    /// `assert_eq(expected, actual, message_hi, message_lo)`
+    ///
+    /// Accepts arguments from the stack. If `actual` isn't `expected`, prints the string at
+    /// `message` along with where the check was made from, and faults with a failed assertion.
+    pub fn i_assert_eq(devola: &mut Devola) {
+        let (message_lo, message_hi) = (devola.pop(), devola.pop());
+        let (actual, expected) = (devola.pop(), devola.pop());
+        if let Some(report) = assert_eq(devola, expected, actual, util::build_u16(message_hi, message_lo)) {
+            tracing::warn!("{}", report);
+            devola.report_assertion(report);
+        }
+    }
+
     pub fn i_debug_println(devola: &mut Devola) {
         let argc = devola.pop();
         let mut argv: Vec<u8> = Vec::with_capacity(argc as usize);
@@ -74,13 +307,120 @@ mod tests {
     #[test]
     fn test_memgetn_clear() {
         let mut devola = crate::util::execute_file("sample/stdlib_tests/memgetn.pop").unwrap();
-        let range = memgetn(&mut devola, 0, 256);
+        let range = memgetn(&devola, 0, 256).unwrap();
 
         assert!((0..=255u8).all(|n| n == range[n as usize]));
 
         memclear(&mut devola, 0, 256);
-        let range = memgetn(&mut devola, 0, 256);
+        let range = memgetn(&devola, 0, 256).unwrap();
         assert!((0..=255u8).all(|n| range[n as usize] == 0));
+        // a range can end at the last byte of memory, but not run past it
+        assert_eq!(memgetn(&devola, 0xFFFF, 1), Some(&[0][..]));
+        assert_eq!(memgetn(&devola, 0xFFFF, 2), None);
+    }
+
+    #[test]
+    fn test_memfill() {
+        let mut devola = Devola::new(Vec::new(), None);
+        memfill(&mut devola, 0x0200, 4, 7);
+        assert_eq!(memgetn(&devola, 0x01FF, 6).unwrap(), [0, 7, 7, 7, 7, 0]);
+
+        // the second byte of each two-byte entry, as pushed by a program
+        for byte in [0x03, 0x01, 0x00, 0x06, 2, 9] {
+            devola.push(byte);
+        }
+        interface::i_memfill_stride(&mut devola);
+        assert_eq!(memgetn(&devola, 0x0300, 8).unwrap(), [0, 9, 0, 9, 0, 9, 0, 0]);
+
+        // a range running past the end of memory carries on from the start
+        memfill_stride(&mut devola, 0xFFFD, 6, 2, 4);
+        assert_eq!((devola.peek(0xFFFD), devola.peek(0xFFFF), devola.peek(0x0001), devola.peek(0x0000)), (4, 4, 4, 0));
+    }
+
+    #[test]
+    fn test_ring_buffer() {
+        let mut devola = Devola::new(Vec::new(), None);
+        rb_init(&mut devola, 0x0200, 3);
+        assert_eq!(rb_pop(&mut devola, 0x0200), None);
+        assert!([1, 2, 3].iter().all(|&value| rb_push(&mut devola, 0x0200, value)));
+        assert!(!rb_push(&mut devola, 0x0200, 4));
+        assert_eq!(rb_pop(&mut devola, 0x0200), Some(1));
+        // wraps around to where the popped byte was
+        assert!(rb_push(&mut devola, 0x0200, 4));
+        assert_eq!(memgetn(&devola, 0x0200, 6).unwrap(), [3, 1, 3, 4, 2, 3]);
+
+        // a buffer at the end of memory wraps around to the start
+        rb_init(&mut devola, 0xFFFE, 2);
+        assert!(rb_push(&mut devola, 0xFFFE, 5) && rb_push(&mut devola, 0xFFFE, 6));
+        assert_eq!((devola.peek(0x0001), devola.peek(0x0002)), (5, 6));
+        assert_eq!(rb_pop(&mut devola, 0xFFFE), Some(5));
+        // one with no capacity stays empty, whatever its length says
+        memset(&mut devola, &[0, 0, 4], 0x0300, 3);
+        assert_eq!((rb_pop(&mut devola, 0x0300), rb_len(&mut devola, 0x0300)), (None, 0));
+        assert!(!rb_push(&mut devola, 0x0300, 1));
+
+        // through the stack interface, as a program would
+        let mut externs = interface::DevolaExternTable::new();
+        externs.insert(String::from("rb_pop"), Box::new(interface::i_rb_pop));
+        externs.insert(String::from("rb_len"), Box::new(interface::i_rb_len));
+        let names = externs.keys().map(|name| (name.clone(), Box::new(|_: &mut Devola| ()) as Box<interface::DevolaExtern>)).collect();
+        let source = "lda 2\npush a\nlda 0\npush a\ncall rb_pop\nldx b\nlda 2\npush a\nlda 0\npush a\ncall rb_len";
+        let (code, symbols, _, _) = crate::parser::text::compile(String::from(source), Some(names)).unwrap();
+        let mut program = Devola::new(code, Some(symbols));
+        program.set_externs(externs);
+        memset(&mut program, memgetn(&devola, 0x0200, 6).unwrap(), 0x0200, 6);
+        program.run().unwrap();
+        assert_eq!((program.register(Register::IndexX), program.register(Register::UtilityB)), (2, 2));
+    }
+
+    #[test]
+    fn test_assert_eq() {
+        let source = "call check\njmp done\ncheck:\nlda 2\npush a\nlda 3\npush a\nlda 2\npush a\nlda 0\npush a\ncall assert_eq\nret\ndone:";
+        let mut externs = interface::DevolaExternTable::new();
+        externs.insert(String::from("assert_eq"), Box::new(interface::i_assert_eq) as Box<interface::DevolaExtern>);
+        let names = externs.keys().map(|name| (name.clone(), Box::new(|_: &mut Devola| ()) as Box<interface::DevolaExtern>)).collect();
+        let (code, symbols, lines, _) = crate::parser::text::compile(String::from(source), Some(names)).unwrap();
+        let mut devola = Devola::new(code, Some(symbols));
+        devola.set_line_table(lines);
+        devola.set_externs(externs);
+        devola.enable_debug();
+        memset(&mut devola, b"score\0", 0x0200, 6);
+
+        let location = FaultLocation { pc: 11, line: Some(11) };
+        assert_eq!(devola.run(), Err(crate::vm::DevolaError::AssertionFailed(location)));
+        assert!(devola.assertion_report().unwrap().starts_with("assertion failed at line 12"));
+        assert_eq!(assert_eq(&devola, 2, 3, 0x0200).unwrap(), [
+            "assertion failed at line 12 (instruction 11) in check: expected 2, got 3: score",
+            "    called from line 1 (instruction 0)"
+        ].join("\n"));
+        assert_eq!(assert_eq(&devola, 3, 3, 0x0200), None);
+    }
+
+    #[test]
+    fn test_music() {
+        let mut devola = Devola::new(Vec::new(), None);
+        // writes 1 then waits 2 frames, writes 2 then waits a frame, and loops back to the second write
+        let song = [MUSIC_WRITE, 0x03, 0x00, 1, MUSIC_WAIT, 2, MUSIC_WRITE, 0x03, 0x00, 2, MUSIC_WAIT, 1, MUSIC_JUMP, 0x02, 0x06];
+        memset(&mut devola, &song, 0x0200, song.len() as u16);
+        music_play(&mut devola, 0x0100, 0x0200);
+        let frames: Vec<u8> = (0..5).map(|_| {
+            assert!(music_tick(&mut devola, 0x0100));
+            let value = devola.peek(0x0300);
+            devola.poke(0x0300, 0);
+            value
+        }).collect();
+        assert_eq!(frames, [1, 0, 2, 2, 2]);
+
+        devola.poke(0x0206, MUSIC_END);
+        assert!(!music_tick(&mut devola, 0x0100));
+        assert!(!music_tick(&mut devola, 0x0100));
+
+        // a player at the top of memory wraps around to the bottom
+        music_play(&mut devola, 0xFFFE, 0x0204);
+        assert!(music_tick(&mut devola, 0xFFFE));
+        assert_eq!((devola.peek(0xFFFE), devola.peek(0xFFFF), devola.peek(0x0000)), (0x02, 0x06, 1));
+        assert!(music_tick(&mut devola, 0xFFFE));
+        assert_eq!(devola.peek(0x0000), 0);
     }
 
     #[test]
@@ -89,7 +429,7 @@ mod tests {
         let buffer: Vec<u8> = vec![3, 14, 1, 5];
 
         memset(&mut devola, buffer.as_slice(), 0, 4);
-        let range = memgetn(&mut devola, 0, 4);
+        let range = memgetn(&devola, 0, 4).unwrap();
         assert!(buffer.iter().enumerate().all(|(i, n)| range[i] == *n));
     }
 }
\ No newline at end of file
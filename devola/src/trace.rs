@@ -0,0 +1,107 @@
+//! Collects the VM's trace events, for debuggers to show alongside the code. Debugging has to be enabled on the
+//! VM for calls and faults to be traced.
+use std::collections::VecDeque;
+use std::fmt::{Debug, Write};
+use std::sync::{Arc, Mutex};
+use tracing::field::{Field, Visit};
+use tracing::{Event, Subscriber};
+use tracing_subscriber::layer::{Context, Layer};
+use tracing_subscriber::registry::LookupSpan;
+
+/// A `tracing` layer keeping the latest events as lines of text, indented by how many calls deep they happened.
+/// Clones share the same lines, so one can be handed to the subscriber and another kept to read them.
+#[derive(Clone)]
+pub struct TraceLog {
+    lines: Arc<Mutex<VecDeque<String>>>,
+    capacity: usize
+}
+
+impl TraceLog {
+    /// A log keeping up to `capacity` lines, dropping the oldest
+    pub fn new(capacity: usize) -> TraceLog {
+        TraceLog { lines: Arc::new(Mutex::new(VecDeque::new())), capacity }
+    }
+
+    /// The lines logged so far, oldest first
+    pub fn lines(&self) -> Vec<String> {
+        self.lines.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+/// Writes an event's message, then the rest of its fields as `name=value`
+#[derive(Default)]
+struct Fields {
+    message: String,
+    others: String
+}
+
+impl Visit for Fields {
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        match field.name() {
+            "message" => write!(self.message, "{:?}", value).unwrap(),
+            name => write!(self.others, " {}={:?}", name, value).unwrap()
+        }
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        match field.name() {
+            "message" => self.message.push_str(value),
+            name => write!(self.others, " {}={}", name, value).unwrap()
+        }
+    }
+}
+
+impl<S: Subscriber + for<'a> LookupSpan<'a>> Layer<S> for TraceLog {
+    fn on_event(&self, event: &Event<'_>, context: Context<'_, S>) {
+        let mut fields = Fields::default();
+        event.record(&mut fields);
+        let depth = context.event_scope(event).map_or(0, |scope| scope.count());
+        let line = format!("{}{}{}", "  ".repeat(depth), fields.message, fields.others);
+
+        let mut lines = self.lines.lock().unwrap();
+        if lines.len() == self.capacity {
+            lines.pop_front();
+        }
+        lines.push_back(line);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::text::compile;
+    use crate::vm::Devola;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[test]
+    fn test_trace_log() {
+        let (code, symbols, _, _) = compile(String::from("
+            call outer
+            jmp end
+        outer:
+            call inner
+            ldb 5
+            ret
+        inner:
+            ret
+        end:
+            ldx 255
+            ldy 255
+            lda xy+16"), None).unwrap();
+        let mut devola = Devola::new(code, Some(symbols));
+        devola.enable_debug();
+        let log = TraceLog::new(4);
+        tracing::subscriber::with_default(tracing_subscriber::registry().with(log.clone()), || {
+            assert!(devola.run().is_err());
+        });
+        // the oldest lines have been dropped to make room
+        let lines = log.lines();
+        assert_eq!(lines[..3], [
+            "    return 0 pc=7 returned=0",
+            "  return 5 pc=5 returned=5",
+            "invalid argument at instruction 11 pc=11 symbol=end instruction=lda xy+16"
+        ]);
+        // followed by a dump of the machine
+        assert!(lines[3].starts_with("pc 11 in end: lda xy+16\n"));
+    }
+}
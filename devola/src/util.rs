@@ -1,28 +1,56 @@
-use std::fs::File;
-use std::path::Path;
-use std::io::Read;
+// there's no filesystem to read from in the browser
+#[cfg(not(target_arch = "wasm32"))]
+use std::{fs::File, io::Read, path::Path};
+#[cfg(not(target_arch = "wasm32"))]
 use crate::parser;
+#[cfg(not(target_arch = "wasm32"))]
 use crate::vm::{Devola, DevolaError};
 
+#[cfg(not(target_arch = "wasm32"))]
 pub fn read_from_file(path: &Path) -> String {
     let mut output = String::new();
     File::open(path).unwrap().read_to_string(&mut output).unwrap();
 
     output
 }
+#[cfg(not(target_arch = "wasm32"))]
 pub fn execute_file(path: &str) -> Result<Devola, DevolaError> {
     let file = Path::new(path);
     let code = read_from_file(file);
 
-    let (code, symbols) = parser::text::compile(code, None).unwrap();
+    let (code, symbols, lines, segments) = parser::text::compile_from(code, None, file).unwrap();
 
-    let mut devola = Devola::new(code, Some(symbols));
+    let mut devola = Devola::with_data(code, Some(symbols), &segments);
+    devola.set_line_table(lines);
     devola.enable_debug();
 
     devola.run()?;
     Ok(devola)
 }
 
+/// How many single-character insertions, deletions, substitutions or swaps of neighbouring characters it takes to
+/// turn one string into the other
+pub fn edit_distance(from: &str, to: &str) -> usize {
+    let (from, to): (Vec<char>, Vec<char>) = (from.chars().collect(), to.chars().collect());
+    // distances[i][j] is between the first i characters of `from` and the first j of `to`
+    let mut distances = vec![vec![0; to.len() + 1]; from.len() + 1];
+    for i in 0..=from.len() {
+        for j in 0..=to.len() {
+            distances[i][j] = if i == 0 || j == 0 {
+                i + j
+            } else {
+                let substitution = distances[i - 1][j - 1] + (from[i - 1] != to[j - 1]) as usize;
+                let mut distance = substitution.min(distances[i - 1][j] + 1).min(distances[i][j - 1] + 1);
+                if i > 1 && j > 1 && from[i - 1] == to[j - 2] && from[i - 2] == to[j - 1] {
+                    distance = distance.min(distances[i - 2][j - 2] + 1);
+                }
+                distance
+            };
+        }
+    }
+    distances[from.len()][to.len()]
+}
+
 pub fn build_u16(msb: u8, lsb: u8) -> u16 {
     ((msb as u16) << 8) | lsb as u16
 }
@@ -1,37 +1,151 @@
-use std::collections::HashMap;
-use std::ops::{Index, IndexMut};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::ops::{Deref, Index, IndexMut};
+use crate::binary::{DataSegment, RomImage};
+use crate::coverage::Coverage;
+use crate::dump::MachineDump;
+use crate::stats::Stats;
 use crate::instructions::*;
-use crate::parser::intermediate::SymbolTable;
+use crate::parser::intermediate::{LineTable, SymbolTable};
 use crate::util::{build_u16, break_u16};
-use crate::stdlib::interface::DevolaExtern;
+use crate::stdlib::interface::{DevolaExtern, DevolaExternTable};
+use tracing::Span;
 
 pub const MEMORY_SIZE: usize = (u16::MAX as usize)+1;
 /// There are 16 bytes of memory-mapped I/O (MMIO). They are labeled as (relative to the base MMIO address):
 /// - `0`: MSB of the stack pointer
 /// - `1`: LSB of the stack pointer
-/// - `2`-`F`: Reserved
+/// - `2`-`9`: Reserved
+/// - `A`: MSB of the interrupt stack pointer, where the stack pointer is moved while an interrupt is handled, or
+///   `0` to keep using the stack that was interrupted
+/// - `B`: LSB of the interrupt stack pointer
+/// - `C`: MSB of the return stack pointer, when there's a separate return stack
+/// - `D`: LSB of the return stack pointer
+/// - `E`: Interrupt enable mask; bit `n` enables interrupt line `n`
+/// - `F`: Interrupt request flags; bit `n` is set when interrupt line `n` is requested
+///
+/// These addresses, and the stack's, are for the default `MemoryMap`.
 pub const MMIO: u16             = 0x0FF0;
 pub const STACK_POINTER_MSB: u16    = MMIO+0x0;
 pub const STACK_POINTER_LSB: u16    = MMIO+0x1;
+pub const INTERRUPT_STACK_MSB: u16  = MMIO+0xA;
+pub const INTERRUPT_STACK_LSB: u16  = MMIO+0xB;
+pub const INTERRUPT_ENABLE: u16     = MMIO+0xE;
+pub const INTERRUPT_REQUEST: u16    = MMIO+0xF;
+
+/// Code at this label is run when an enabled interrupt is requested
+pub const INTERRUPT_VECTOR_LABEL: &str = "irq";
+/// The number of cycles taken to enter an interrupt handler
+const INTERRUPT_CYCLES: u64 = 5;
 
 /// The stack begins at 0x0F00 and grows down
-const INITIAL_STACK_POINTER: u16 = 0x0F00;
+pub const INITIAL_STACK_POINTER: u16 = 0x0F00;
+
+/// Where the VM keeps its stack and registers, and how much memory it has. Alternative hardware, or tests that
+/// want a tiny memory, can change these from the defaults given by the constants above.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MemoryMap {
+    /// How many bytes of memory there are, a power of two no larger than `MEMORY_SIZE`. Smaller memories are
+    /// mirrored across the address space, so address `size` is address 0 again.
+    pub size: usize,
+    /// The first of the 16 bytes of MMIO, laid out as described for `MMIO`
+    pub mmio: u16,
+    /// Where the stack starts, growing down
+    pub initial_stack_pointer: u16,
+    /// Where a separate stack for return addresses starts, growing down, if calls and interrupts keep them apart
+    /// from the data stack that `push` and `pop` use
+    pub return_stack: Option<u16>
+}
+
+impl Default for MemoryMap {
+    fn default() -> Self {
+        MemoryMap { size: MEMORY_SIZE, mmio: MMIO, initial_stack_pointer: INITIAL_STACK_POINTER, return_stack: None }
+    }
+}
+
+impl MemoryMap {
+    pub fn stack_pointer_msb(&self) -> u16 {
+        self.mmio
+    }
+    pub fn stack_pointer_lsb(&self) -> u16 {
+        self.mmio + 0x1
+    }
+    pub fn interrupt_stack_msb(&self) -> u16 {
+        self.mmio + 0xA
+    }
+    pub fn interrupt_stack_lsb(&self) -> u16 {
+        self.mmio + 0xB
+    }
+    pub fn return_stack_pointer_msb(&self) -> u16 {
+        self.mmio + 0xC
+    }
+    pub fn return_stack_pointer_lsb(&self) -> u16 {
+        self.mmio + 0xD
+    }
+    pub fn interrupt_enable(&self) -> u16 {
+        self.mmio + 0xE
+    }
+    pub fn interrupt_request(&self) -> u16 {
+        self.mmio + 0xF
+    }
+
+    /// Whether the memory is a size the VM can have, with the MMIO and stack in it
+    pub fn is_valid(&self) -> bool {
+        self.size.is_power_of_two() && (256..=MEMORY_SIZE).contains(&self.size)
+            && self.mmio as usize + 16 <= self.size && self.initial_stack_pointer as usize <= self.size
+            && self.return_stack.is_none_or(|return_stack| return_stack as usize <= self.size)
+    }
+
+    /// The MMIO address of the MSB of the pointer of the stack return addresses go on, followed by its LSB
+    fn return_stack_pointer(&self) -> u16 {
+        match self.return_stack {
+            Some(_) => self.return_stack_pointer_msb(),
+            None => self.stack_pointer_msb()
+        }
+    }
+}
 
-pub(crate) struct DevolaMemory {
-    pub(crate) memory: [u8; MEMORY_SIZE],
+/// The most cycles `run_cycles` lets pass at once while a `hlt` waits
+pub const IDLE_STEP_CYCLES: u64 = 16;
+
+/// How many of the last executed instructions are kept while debugging, for dumps on a fault
+pub const HISTORY_LENGTH: usize = 16;
+
+/// A device clocked alongside the CPU, such as a timer or DMA controller, given the cycles that have passed
+/// each time the VM moves on: after every instruction, every idle cycle of a `hlt` and every interrupt taken
+pub type DevolaTick = dyn FnMut(&mut Devola, u64);
+
+/// Writes to memory are tracked in blocks of this many bytes
+pub const DIRTY_BLOCK_SIZE: usize = 16;
+const DIRTY_BLOCK_COUNT: usize = MEMORY_SIZE/DIRTY_BLOCK_SIZE;
+
+/// The VM's memory, registers and flags. Embedders can read it through `Devola::memory`.
+pub struct DevolaMemory {
+    memory: [u8; MEMORY_SIZE],
+    /// Addresses are masked with this, so that a smaller memory repeats across the address space
+    mask: u16,
     flags: u8,
-    registers: [u8; 5]
+    registers: [u8; 5],
+    /// One bit per block of memory, set when any byte in the block is written
+    dirty: [u64; DIRTY_BLOCK_COUNT/64],
+    /// The addresses written, in order, while writes are being recorded
+    writes: Option<Vec<u16>>
 }
 
 impl Index<u16> for DevolaMemory {
     type Output = u8;
     fn index(&self, index: u16) -> &Self::Output {
-        &self.memory[index as usize]
+        &self.memory[(index & self.mask) as usize]
     }
 }
 
 impl IndexMut<u16> for DevolaMemory {
     fn index_mut(&mut self, index: u16) -> &mut Self::Output {
+        let index = index & self.mask;
+        let block = index as usize / DIRTY_BLOCK_SIZE;
+        self.dirty[block / 64] |= 1 << (block % 64);
+        if let Some(writes) = self.writes.as_mut() {
+            writes.push(index);
+        }
         &mut self.memory[index as usize]
     }
 }
@@ -62,11 +176,15 @@ impl IndexMut<Register> for DevolaMemory {
 }
 
 impl DevolaMemory {
-    pub fn new() -> Self {
+    pub fn new(size: usize) -> Self {
         Self {
             memory: [0; MEMORY_SIZE],
+            mask: (size - 1) as u16,
             flags: 0,
-            registers: [0; 5]
+            registers: [0; 5],
+            // nothing has been read yet, so all of memory starts out dirty
+            dirty: [u64::MAX; DIRTY_BLOCK_COUNT/64],
+            writes: None
         }
     }
     /// Checks if the specified flag has been set.
@@ -98,134 +216,852 @@ impl DevolaMemory {
     pub fn get_index(&self) -> u16 {
         ((self[Register::IndexX] as u16) << 8) | self[Register::IndexY] as u16
     }
+
+    /// How many bytes of memory there are before it repeats
+    pub fn size(&self) -> usize {
+        self.mask as usize + 1
+    }
+    /// The `length` bytes from `start`, in order. Addresses past the end of a smaller memory repeat it, and ones past
+    /// 0xFFFF wrap around to 0, as they do for the CPU.
+    pub fn iter_range(&self, start: u16, length: usize) -> impl Iterator<Item = u8> + '_ {
+        (0..length).map(move |offset| self[start.wrapping_add(offset as u16)])
+    }
+    /// All of memory in blocks of `size` bytes, the last of which may be shorter. A size of 0 is taken as 1.
+    pub fn chunks(&self, size: u16) -> std::slice::Chunks<'_, u8> {
+        self.memory[..self.size()].chunks(size.max(1) as usize)
+    }
+    /// Borrows the `length` bytes from `start`, or returns None if they run past the end of memory and wrap around
+    pub fn view(&self, start: u16, length: usize) -> Option<MemoryView<'_>> {
+        let offset = (start & self.mask) as usize;
+        let end = offset.checked_add(length)?;
+        (end <= self.size()).then(|| MemoryView { start, bytes: &self.memory[offset..end] })
+    }
+}
+
+/// A borrowed range of memory, read as bytes or as words stored MSB first
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MemoryView<'a> {
+    start: u16,
+    bytes: &'a [u8]
+}
+
+impl<'a> MemoryView<'a> {
+    /// The address the view starts at
+    pub fn start(&self) -> u16 {
+        self.start
+    }
+    pub fn bytes(&self) -> &'a [u8] {
+        self.bytes
+    }
+    /// The word `offset` bytes into the view, or None if it runs past the end
+    pub fn word(&self, offset: usize) -> Option<u16> {
+        Some(build_u16(*self.bytes.get(offset)?, *self.bytes.get(offset + 1)?))
+    }
+    /// Splits the view into entries of `size` bytes, e.g. the rows of a table, leaving out any partial entry at the
+    /// end. A size of 0 is taken as 1.
+    pub fn entries(&self, size: usize) -> impl Iterator<Item = MemoryView<'a>> {
+        let (start, size) = (self.start, size.max(1));
+        self.bytes.chunks_exact(size).enumerate()
+            .map(move |(index, bytes)| MemoryView { start: start.wrapping_add((index*size) as u16), bytes })
+    }
+}
+
+impl Deref for MemoryView<'_> {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        self.bytes
+    }
+}
+
+/// A snapshot of everything that affects how the VM runs from here on: memory, registers, flags, and
+/// where it is in the code. The code itself isn't included, so a state only makes sense for the program
+/// it was taken from.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DevolaState {
+    memory: Vec<u8>,
+    flags: u8,
+    registers: [u8; 5],
+    pc: usize,
+    cycles: u64,
+    in_interrupt: bool,
+    #[cfg_attr(feature = "serde", serde(default))]
+    waiting: bool,
+    #[cfg_attr(feature = "serde", serde(default))]
+    interrupt_stack_active: bool
+}
+
+/// A state that can't be loaded, because its memory isn't the size of the VM's
+#[derive(Debug, PartialEq)]
+pub struct StateMismatch {
+    pub expected: usize,
+    pub found: usize
+}
+
+impl std::fmt::Display for StateMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "state has {} bytes of memory, but the VM has {}", self.found, self.expected)
+    }
+}
+
+impl DevolaState {
+    /// The length of a state written by `to_bytes`
+    pub const SIZE: usize = MEMORY_SIZE + 1 + 5 + 8 + 8 + 1;
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(Self::SIZE);
+        bytes.extend_from_slice(&self.memory);
+        bytes.push(self.flags);
+        bytes.extend_from_slice(&self.registers);
+        bytes.extend_from_slice(&(self.pc as u64).to_le_bytes());
+        bytes.extend_from_slice(&self.cycles.to_le_bytes());
+        // waiting and the interrupt stack share a byte with in_interrupt, so that states saved before they existed
+        // still read
+        bytes.push(self.in_interrupt as u8 | (self.waiting as u8) << 1 | (self.interrupt_stack_active as u8) << 2);
+        bytes
+    }
+
+    /// Reads a state written by `to_bytes`, or returns `None` if it's the wrong length
+    pub fn from_bytes(bytes: &[u8]) -> Option<DevolaState> {
+        if bytes.len() != Self::SIZE {
+            return None;
+        }
+        let (memory, rest) = bytes.split_at(MEMORY_SIZE);
+        let (&flags, rest) = rest.split_first()?;
+        let (registers, rest) = rest.split_at(5);
+        let (pc, rest) = rest.split_at(8);
+        let (cycles, rest) = rest.split_at(8);
+        Some(DevolaState {
+            memory: memory.to_vec(),
+            flags,
+            registers: registers.try_into().ok()?,
+            pc: u64::from_le_bytes(pc.try_into().ok()?) as usize,
+            cycles: u64::from_le_bytes(cycles.try_into().ok()?),
+            in_interrupt: rest[0] & 1 != 0,
+            waiting: rest[0] & 2 != 0,
+            interrupt_stack_active: rest[0] & 4 != 0
+        })
+    }
 }
 
 pub struct Devola {
     pub(crate) memory: DevolaMemory,
     code: Vec<Instruction>,
     pc: usize,
+    cycles: u64,
     debug: bool,
-    call_stack: Vec<String>,
+    /// The location of and a span for each local call that hasn't returned yet, while debugging
+    call_spans: Vec<(usize, Span)>,
+    /// The locations of the last `HISTORY_LENGTH` instructions executed, while debugging
+    history: VecDeque<usize>,
+    /// The state of the machine at the last fault, while debugging
+    fault_dump: Option<MachineDump>,
     symbol_table: Option<SymbolTable>,
-    externs: Option<HashMap<String, Box<DevolaExtern>>>
+    /// The source line each instruction was compiled from
+    line_table: Option<LineTable>,
+    externs: Option<DevolaExternTable>,
+    tick_hook: Option<Box<DevolaTick>>,
+    /// The cycle count the tick hook has been run up to
+    ticked: u64,
+    interrupt_vector: Option<usize>,
+    in_interrupt: bool,
+    /// Whether the handler running was given the interrupt stack, with the interrupted stack pointer saved on it
+    interrupt_stack_active: bool,
+    /// Whether a `hlt` has stopped instruction fetch until the next interrupt
+    waiting: bool,
+    /// Whether the library function being run has failed an assertion
+    failed_assertion: bool,
+    /// Why the last assertion failed, as reported by the library function that failed it
+    assertion_report: Option<String>,
+    memory_map: MemoryMap,
+    /// What has been executed, while coverage is being tracked
+    coverage: Option<Coverage>,
+    instructions: u64,
+    /// How many times each library function has been called
+    extern_calls: HashMap<String, u64>
+}
+/// Where a fault happened: the instruction, and the zero-based source line it was compiled from if known
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct FaultLocation {
+    pub pc: usize,
+    pub line: Option<usize>
+}
+
+impl std::fmt::Display for FaultLocation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "line {} (instruction {})", line + 1, self.pc),
+            None => write!(f, "instruction {}", self.pc)
+        }
+    }
 }
-#[derive(Copy, Clone, Debug)]
+
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum DevolaError {
-    InvalidArgument, Unimplemented, EndCode
+    /// An argument that can't be used, like an immediate store target, an address past the end of memory or a
+    /// jump past the end of the code
+    InvalidArgument(FaultLocation),
+    Unimplemented(FaultLocation),
+    /// The stack pointer would have wrapped around the end of memory
+    StackOverflow(FaultLocation),
+    /// An `_Assert` pseudo-instruction or a library function's assertion didn't hold
+    AssertionFailed(FaultLocation),
+    /// A `hlt` would wait forever, with no interrupt enabled or able to be requested to wake it
+    Deadlock(FaultLocation),
+    EndCode
+}
+
+impl std::fmt::Display for DevolaError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DevolaError::InvalidArgument(location) => write!(f, "invalid argument at {}", location),
+            DevolaError::Unimplemented(location) => write!(f, "unimplemented library call at {}", location),
+            DevolaError::StackOverflow(location) => write!(f, "stack overflow at {}", location),
+            DevolaError::AssertionFailed(location) => write!(f, "assertion failed at {}", location),
+            DevolaError::Deadlock(location) => write!(f, "hlt with no interrupt to wake it at {}", location),
+            DevolaError::EndCode => write!(f, "reached the end of the code")
+        }
+    }
 }
 
 
 impl Devola {
     pub fn new(code: Vec<Instruction>, symbol_table: Option<SymbolTable>) -> Self {
+        Self::with_memory_map(code, symbol_table, MemoryMap::default())
+    }
+
+    /// Creates a VM with a memory map other than the default one. Panics if the map isn't valid.
+    pub fn with_memory_map(code: Vec<Instruction>, symbol_table: Option<SymbolTable>, memory_map: MemoryMap) -> Self {
+        assert!(memory_map.is_valid(), "invalid memory map {:?}", memory_map);
+        let interrupt_vector = symbol_table.as_ref().and_then(|table| {
+            table.iter()
+                .find(|(_, label)| label.as_str() == INTERRUPT_VECTOR_LABEL)
+                .map(|(pc, _)| *pc)
+        });
         let mut out = Self {
-            memory: DevolaMemory::new(),
+            memory: DevolaMemory::new(memory_map.size),
             code,
             pc: 0,
+            cycles: 0,
             debug: false,
-            call_stack: Vec::new(),
+            call_spans: Vec::new(),
+            history: VecDeque::with_capacity(HISTORY_LENGTH),
+            fault_dump: None,
             symbol_table,
-            externs: None
+            line_table: None,
+            externs: None,
+            tick_hook: None,
+            ticked: 0,
+            interrupt_vector,
+            in_interrupt: false,
+            interrupt_stack_active: false,
+            waiting: false,
+            failed_assertion: false,
+            assertion_report: None,
+            memory_map,
+            coverage: None,
+            instructions: 0,
+            extern_calls: HashMap::new()
         };
-        let (msb, lsb) = break_u16(INITIAL_STACK_POINTER);
-        out.memory[STACK_POINTER_MSB] = msb;
-        out.memory[STACK_POINTER_LSB] = lsb;
+        let (msb, lsb) = break_u16(memory_map.initial_stack_pointer);
+        out.memory[memory_map.stack_pointer_msb()] = msb;
+        out.memory[memory_map.stack_pointer_lsb()] = lsb;
+        if let Some(return_stack) = memory_map.return_stack {
+            let (msb, lsb) = break_u16(return_stack);
+            out.memory[memory_map.return_stack_pointer_msb()] = msb;
+            out.memory[memory_map.return_stack_pointer_lsb()] = lsb;
+        }
 
         out
     }
 
+    /// Creates a VM with data segments copied into memory, as assembled from `.db` and `.dw` directives
+    pub fn with_data(code: Vec<Instruction>, symbol_table: Option<SymbolTable>, segments: &[DataSegment]) -> Self {
+        let mut devola = Self::new(code, symbol_table);
+        devola.write_segments(segments);
+        devola
+    }
+
+    /// Copies data segments into memory. The assembler keeps segments within memory, but one read from elsewhere
+    /// that runs past the end wraps around to the start.
+    fn write_segments(&mut self, segments: &[DataSegment]) {
+        for segment in segments {
+            for (offset, &byte) in segment.bytes.iter().enumerate() {
+                self.poke(segment.address.wrapping_add(offset as u16), byte);
+            }
+        }
+    }
+
+    /// Traces local calls as `call` spans, with events as they're made and return, and faults as events inside
+    /// the call they happened in, for a `tracing` subscriber to pick up. Faults are traced with a dump of the
+    /// machine, which is also kept for `fault_dump`.
     pub fn enable_debug(&mut self) {
         self.debug = true;
     }
     pub fn disable_debug(&mut self) {
         self.debug = false;
+        self.history.clear();
+    }
+
+    /// The locations of the last instructions executed while debugging, oldest first
+    pub fn history(&self) -> impl Iterator<Item = usize> + '_ {
+        self.history.iter().copied()
+    }
+    /// Where each local call that hasn't returned yet was made from while debugging, the innermost first
+    pub fn backtrace(&self) -> impl Iterator<Item = usize> + '_ {
+        self.call_spans.iter().rev().map(|(pc, _)| *pc)
+    }
+    /// The state of the machine when it last faulted while debugging
+    pub fn fault_dump(&self) -> Option<&MachineDump> {
+        self.fault_dump.as_ref()
+    }
+
+    /// How much the VM has run: instructions, cycles and library calls
+    pub fn stats(&self) -> Stats {
+        Stats { instructions: self.instructions, cycles: self.cycles, extern_calls: self.extern_calls.clone() }
+    }
+
+    /// Starts counting how many times each instruction is executed, from zero
+    pub fn enable_coverage(&mut self) {
+        self.coverage = Some(Coverage::new(self.code.len()));
+    }
+    /// What has been executed since coverage was enabled
+    pub fn coverage(&self) -> Option<&Coverage> {
+        self.coverage.as_ref()
     }
 
     pub fn step(&mut self) -> Result<(), DevolaError> {
+        // while a `hlt` waits, each step idles for a cycle, unless an interrupt wakes it
+        if self.waiting {
+            self.cycles += 1;
+            self.tick();
+            return self.service_interrupts();
+        }
         match self.code.get(self.pc) {
+            // an exit stays put, so that the code can be told apart from one that ran off its end
+            Some(Instruction::Exit(_)) | None => Err(DevolaError::EndCode),
             Some(instruction) => {
-                let debug_inst = instruction.clone();
+                let instruction = instruction.clone();
+                // executing may move the PC, so the trace needs to know where the instruction was first
+                let location = self.fault_location();
+                let cycles = instruction.cycles();
                 if let Err(error) = self.execute_instruction(instruction.clone()) {
                     if self.debug {
-                        eprintln!("An error of type {:?} occurred at PC {}", error, self.pc);
+                        let dump = MachineDump::new(self, location.pc);
+                        let span = self.call_spans.last().map_or_else(Span::none, |(_, span)| span.clone());
+                        span.in_scope(|| {
+                            tracing::warn!(pc = location.pc, symbol = self.enclosing_label(location.pc), %instruction, "{}", error);
+                            tracing::warn!("{}", dump);
+                        });
+                        self.fault_dump = Some(dump);
                     }
                     return Err(error);
                 }
                 if self.debug {
-                    match debug_inst {
-                        Instruction::Call(CallType::Local(loc)) => {
-                            let symbol = match &self.symbol_table {
-                                Some(table) => table.get(&loc).unwrap_or(&String::from("unknown")).clone(),
-                                None => loc.to_string()
-                            };
-
-                            println!("Call {}", symbol);
-                            self.call_stack.push(symbol);
-                        }
-                        Instruction::Return => {
-                            println!("{} returned {}", self.call_stack.pop().unwrap_or(String::from("unknown")), self.memory[Register::UtilityB]);
-                        },
-                        _ => {}
-                    };
+                    self.trace_call(&instruction, location);
+                    if self.history.len() == HISTORY_LENGTH {
+                        self.history.pop_front();
+                    }
+                    self.history.push_back(location.pc);
+                }
+                if let Some(coverage) = self.coverage.as_mut() {
+                    coverage.record(location.pc, self.pc != location.pc);
                 }
                 self.pc += 1;
-                Ok(())
+                self.cycles += cycles;
+                self.instructions += 1;
+                // a device can request an interrupt that's taken straight after the instruction
+                self.tick();
+                self.service_interrupts()
             }
-            None => { Err(DevolaError::EndCode) }
         }
     }
 
-    pub fn run(&mut self) -> Result<(), DevolaError> {
+    /// Opens a span for a local call, or closes the one for the call being returned from
+    fn trace_call(&mut self, instruction: &Instruction, location: FaultLocation) {
+        match instruction {
+            Instruction::Call(CallType::Local(destination)) => {
+                let symbol = match self.symbol_table.as_ref().and_then(|table| table.get(destination)) {
+                    Some(label) => label.clone(),
+                    None => destination.to_string()
+                };
+                let parent = self.call_spans.last().and_then(|(_, span)| span.id());
+                let span = tracing::debug_span!(parent: parent, "call", %symbol);
+                span.in_scope(|| tracing::debug!(pc = location.pc, %symbol, "call {} from {}", symbol, location));
+                self.call_spans.push((location.pc, span));
+            },
+            Instruction::Return => {
+                if let Some((_, span)) = self.call_spans.pop() {
+                    let returned = self.memory[Register::UtilityB];
+                    span.in_scope(|| tracing::debug!(pc = location.pc, returned, "return {}", returned));
+                }
+            },
+            _ => ()
+        }
+    }
+
+    /// Runs until the end of the code, returning the exit code given by `hlt N`, or 0 if it ran off the end.
+    /// Without a tick hook, nothing can request an interrupt while it runs, so a `hlt` faults with
+    /// `DevolaError::Deadlock` instead of waiting forever.
+    pub fn run(&mut self) -> Result<u8, DevolaError> {
         loop {
+            if self.waiting && self.tick_hook.is_none() {
+                return Err(DevolaError::Deadlock(self.fault_location()));
+            }
             match self.step() {
-                Err(DevolaError::EndCode) => { return Ok(()) },
+                Err(DevolaError::EndCode) => { return Ok(self.exit_code().unwrap_or(0)) },
                 Err(error) => { return Err(error ) },
                 _ => {}
             }
         }
     }
-    pub(crate) fn push(&mut self, value: u8) {
-        let new_stack_pointer = self.get_stack_pointer()-1;
+    /// Runs until at least `cycles` cycles have elapsed or the end of the code is reached.
+    /// Returns the number of cycles actually taken, which may overshoot `cycles` by part of an instruction.
+    /// Time spent waiting in a `hlt` passes `IDLE_STEP_CYCLES` at a time, so that an interrupt requested by
+    /// the tick hook is taken at most that many cycles late.
+    pub fn run_cycles(&mut self, cycles: u64) -> Result<u64, DevolaError> {
+        let start = self.cycles;
+        while self.cycles - start < cycles {
+            if self.waiting {
+                self.cycles += (start + cycles - self.cycles).min(IDLE_STEP_CYCLES);
+                self.tick();
+                self.service_interrupts()?;
+                continue;
+            }
+            match self.step() {
+                Err(DevolaError::EndCode) => { break },
+                Err(error) => { return Err(error) },
+                _ => {}
+            }
+        }
+        Ok(self.cycles - start)
+    }
+
+    /// Whether any of the `size` bytes from `start` (wrapping around the end of memory) may have
+    /// been written since the last call to `clear_dirty`. Writes are tracked per block of
+    /// `DIRTY_BLOCK_SIZE` bytes, so writes to neighbouring bytes can also mark the range dirty.
+    pub fn is_dirty(&self, start: u16, size: u16) -> bool {
+        if size == 0 {
+            return false;
+        }
+        let first = start as usize / DIRTY_BLOCK_SIZE;
+        let last = start.wrapping_add(size - 1) as usize / DIRTY_BLOCK_SIZE;
+        let count = (last + DIRTY_BLOCK_COUNT - first) % DIRTY_BLOCK_COUNT + 1;
+        (0..count)
+            .map(|offset| (first + offset) % DIRTY_BLOCK_COUNT)
+            .any(|block| self.memory.dirty[block / 64] >> (block % 64) & 1 == 1)
+    }
+    /// Marks all of memory as clean, e.g. after it has been read into a host-side model
+    pub fn clear_dirty(&mut self) {
+        self.memory.dirty = [0; DIRTY_BLOCK_COUNT/64];
+    }
+
+    /// Takes a snapshot of the VM, to be restored later with `load_state`
+    pub fn save_state(&self) -> DevolaState {
+        DevolaState {
+            memory: self.memory.memory.to_vec(),
+            flags: self.memory.flags,
+            registers: self.memory.registers,
+            pc: self.pc,
+            cycles: self.cycles,
+            in_interrupt: self.in_interrupt,
+            waiting: self.waiting,
+            interrupt_stack_active: self.interrupt_stack_active
+        }
+    }
+    /// Puts the VM back in a saved state. All of memory is marked dirty, since any of it may have changed.
+    pub fn load_state(&mut self, state: &DevolaState) -> Result<(), StateMismatch> {
+        if state.memory.len() != self.memory.memory.len() {
+            return Err(StateMismatch { expected: self.memory.memory.len(), found: state.memory.len() });
+        }
+        self.memory.memory.copy_from_slice(&state.memory);
+        self.memory.flags = state.flags;
+        self.memory.registers = state.registers;
+        self.memory.dirty = [u64::MAX; DIRTY_BLOCK_COUNT/64];
+        self.pc = state.pc;
+        self.cycles = state.cycles;
+        self.ticked = state.cycles;
+        self.in_interrupt = state.in_interrupt;
+        self.waiting = state.waiting;
+        self.interrupt_stack_active = state.interrupt_stack_active;
+        Ok(())
+    }
+
+    /// Replaces the code with a re-assembled version of the program. If its labels are all where they were,
+    /// the program carries on from where it was, with its memory, registers and stack as they were. Otherwise
+    /// the return addresses on the stack can't be trusted, so it starts again from its entry point with fresh
+    /// memory holding its data. Returns whether the state was kept.
+    pub fn hot_swap(&mut self, image: RomImage, symbol_table: Option<SymbolTable>) -> bool {
+        let keep = self.symbol_table.is_some() && self.symbol_table == symbol_table;
+        let mut fresh = Devola::with_memory_map(image.code, symbol_table, self.memory_map);
+        fresh.write_segments(&image.segments);
+        if !keep {
+            self.load_state(&DevolaState { cycles: self.cycles, pc: image.entry_point, ..fresh.save_state() })
+                .expect("a VM's state fits a VM with the same memory map");
+        }
+        self.code = fresh.code;
+        self.symbol_table = fresh.symbol_table;
+        self.interrupt_vector = fresh.interrupt_vector;
+        // the old lines don't match the new code
+        self.line_table = None;
+        keep
+    }
+
+    /// The index of the next instruction to be executed
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    /// Moves execution to another instruction, e.g. a program's entry point
+    pub fn set_pc(&mut self, pc: usize) {
+        self.pc = pc;
+        self.waiting = false;
+    }
+
+    /// The total number of cycles elapsed since the VM was created
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    /// The total number of instructions executed since the VM was created, without copying the rest of `stats`
+    pub fn instructions(&self) -> u64 {
+        self.instructions
+    }
+
+    /// The value held in a register
+    pub fn register(&self, register: Register) -> u8 {
+        self.memory[register]
+    }
+    /// Whether a flag is set
+    pub fn flag(&self, flag: Flag) -> bool {
+        self.memory.flag(flag)
+    }
+    /// The address of the last byte pushed, or the memory map's initial stack pointer while the stack is empty
+    pub fn stack_pointer(&self) -> u16 {
+        self.get_stack_pointer()
+    }
+    /// The address of the last byte pushed to the stack return addresses go on, which is the data stack unless
+    /// the memory map has a separate return stack
+    pub fn return_stack_pointer(&self) -> u16 {
+        self.get_pointer(self.memory_map.return_stack_pointer())
+    }
+    /// Where the stack and MMIO are, and how much memory there is
+    pub fn memory_map(&self) -> &MemoryMap {
+        &self.memory_map
+    }
+    /// The closest label at or before an instruction, which is usually the routine it's part of
+    pub fn enclosing_label(&self, pc: usize) -> Option<&str> {
+        self.symbol_table.as_ref()?.iter()
+            .filter(|(&at, _)| at <= pc)
+            .max_by_key(|(&at, _)| at)
+            .map(|(_, label)| label.as_str())
+    }
+    /// The code being executed
+    pub fn code(&self) -> &[Instruction] {
+        &self.code
+    }
+    /// The exit code the program stopped with, if it's reached a `hlt N`
+    pub fn exit_code(&self) -> Option<u8> {
+        match self.code.get(self.pc) {
+            Some(Instruction::Exit(code)) => Some(*code),
+            _ => None
+        }
+    }
+    /// The labels in the code, if the VM was given them
+    pub fn symbols(&self) -> Option<&SymbolTable> {
+        self.symbol_table.as_ref()
+    }
+    /// Names the VM's code locations, e.g. from a symbol file for a program that was loaded without them
+    pub fn set_symbols(&mut self, symbols: SymbolTable) {
+        self.symbol_table = Some(symbols);
+    }
+    /// Gives the VM the library functions its code was compiled against, for `call` to run
+    pub fn set_externs(&mut self, externs: DevolaExternTable) {
+        self.externs = Some(externs);
+    }
+    /// Adds a library function that `call` can run
+    pub fn register_extern(&mut self, name: &str, function: Box<DevolaExtern>) {
+        self.externs.get_or_insert_with(HashMap::new).insert(name.to_string(), function);
+    }
+    /// Sets a hook for an embedder to clock its devices by, so that they keep pace with each instruction rather
+    /// than catching up between calls to `run_cycles`
+    pub fn set_tick(&mut self, tick: Box<DevolaTick>) {
+        self.ticked = self.cycles;
+        self.tick_hook = Some(tick);
+    }
+    /// Removes the tick hook, e.g. to move it to another VM
+    pub fn take_tick(&mut self) -> Option<Box<DevolaTick>> {
+        self.tick_hook.take()
+    }
+    /// Runs the tick hook for the cycles that have passed since it last ran
+    fn tick(&mut self) {
+        let cycles = self.cycles - self.ticked;
+        self.ticked = self.cycles;
+        if cycles == 0 {
+            return;
+        }
+        if let Some(mut tick) = self.tick_hook.take() {
+            tick(self, cycles);
+            // the hook may have replaced itself
+            self.tick_hook.get_or_insert(tick);
+        }
+    }
+    /// Makes the library function being run fault with `DevolaError::AssertionFailed` once it returns
+    pub fn fail_assertion(&mut self) {
+        self.failed_assertion = true;
+    }
+    /// Like `fail_assertion`, keeping a report of why for the host to show
+    pub fn report_assertion(&mut self, report: String) {
+        self.assertion_report = Some(report);
+        self.fail_assertion();
+    }
+    /// The report given by the last library function to fail an assertion with `report_assertion`
+    pub fn assertion_report(&self) -> Option<&str> {
+        self.assertion_report.as_deref()
+    }
+    /// Removes the library functions from the VM, e.g. to move them to another one
+    pub fn take_externs(&mut self) -> Option<DevolaExternTable> {
+        self.externs.take()
+    }
+    /// Tells the VM which source line each instruction came from, so that faults can point at them
+    pub fn set_line_table(&mut self, line_table: LineTable) {
+        self.line_table = Some(line_table);
+    }
+    /// The zero-based source line an instruction was compiled from, if known
+    pub fn line(&self, pc: usize) -> Option<usize> {
+        self.line_table.as_ref().and_then(|lines| lines.get(pc).copied())
+    }
+    /// Where the instruction being executed is, for reporting a fault in it
+    fn fault_location(&self) -> FaultLocation {
+        FaultLocation { pc: self.pc, line: self.line(self.pc) }
+    }
+
+    /// The VM's memory, for reading ranges of it at a time
+    pub fn memory(&self) -> &DevolaMemory {
+        &self.memory
+    }
+    /// Reads a byte of memory.
+    pub fn peek(&self, address: u16) -> u8 {
+        self.memory[address]
+    }
+    /// Writes a byte of memory.
+    pub fn poke(&mut self, address: u16, value: u8) {
+        self.memory[address] = value;
+    }
+
+    /// Starts or stops keeping a list of the addresses written to, for `take_writes` to collect
+    pub fn record_writes(&mut self, enabled: bool) {
+        self.memory.writes = enabled.then(Vec::new);
+    }
+    /// The addresses written to since the last call, each once and in the order first written, while writes
+    /// are being recorded
+    pub fn take_writes(&mut self) -> Vec<u16> {
+        let mut writes = match self.memory.writes.as_mut() {
+            Some(writes) => std::mem::take(writes),
+            None => return Vec::new()
+        };
+        let mut seen = HashSet::new();
+        writes.retain(|&address| seen.insert(address));
+        writes
+    }
+
+    /// Whether an interrupt handler is running
+    pub fn in_interrupt(&self) -> bool {
+        self.in_interrupt
+    }
+    /// Whether a `hlt` is waiting for an interrupt
+    pub fn waiting(&self) -> bool {
+        self.waiting
+    }
+    /// Sets the instruction the VM jumps to when an interrupt is taken. By default, this is the
+    /// location of the `irq` label, if present.
+    pub fn set_interrupt_vector(&mut self, vector: Option<usize>) {
+        self.interrupt_vector = vector;
+    }
+    /// Requests an interrupt on the given line (0-7). It is serviced after the current instruction
+    /// if the line is enabled in `INTERRUPT_ENABLE` and no other interrupt is being handled.
+    pub fn request_interrupt(&mut self, line: u8) {
+        self.memory[self.memory_map.interrupt_request()] |= 1 << line;
+    }
+
+    /// Enters the interrupt handler if an enabled interrupt is pending. The flags are pushed,
+    /// followed by the location of the last instruction executed so that `reti` resumes after it.
+    /// The handler is responsible for clearing the bits it services in `INTERRUPT_REQUEST`.
+    ///
+    /// If the interrupt stack pointer is set, the stack pointer is moved there first and the interrupted one is
+    /// pushed onto the interrupt stack, so that the handler can't overflow a nearly full stack. Each interrupt
+    /// starts again from the top of the interrupt stack, as handlers can't be interrupted.
+    fn service_interrupts(&mut self) -> Result<(), DevolaError> {
+        let vector = match self.interrupt_vector {
+            Some(vector) if !self.in_interrupt => vector,
+            _ => return Ok(())
+        };
+        if self.memory[self.memory_map.interrupt_enable()] & self.memory[self.memory_map.interrupt_request()] == 0 {
+            return Ok(());
+        }
+
+        let stack_pointer = self.get_stack_pointer();
+        if let Err(error) = self.push_interrupt_frame(stack_pointer) {
+            // the program's own stack is left in place when the handler can't be entered
+            self.set_pointer(self.memory_map.stack_pointer_msb(), stack_pointer);
+            return Err(error);
+        }
+        self.pc = vector;
+        self.in_interrupt = true;
+        self.waiting = false;
+        self.cycles += INTERRUPT_CYCLES;
+        self.tick();
+        Ok(())
+    }
+
+    /// Switches to the interrupt stack, if there is one, saving the interrupted `stack_pointer` on it, then saves
+    /// the flags and return address
+    fn push_interrupt_frame(&mut self, stack_pointer: u16) -> Result<(), DevolaError> {
+        let interrupt_stack = self.get_pointer(self.memory_map.interrupt_stack_msb());
+        if interrupt_stack != 0 {
+            let (msb, lsb) = break_u16(stack_pointer);
+            self.set_pointer(self.memory_map.stack_pointer_msb(), interrupt_stack);
+            self.checked_push(msb)?;
+            self.checked_push(lsb)?;
+        }
+
+        let (msb, lsb) = break_u16(self.return_address(self.pc - 1)?);
+        let stack = self.memory_map.return_stack_pointer();
+        self.checked_push_to(stack, self.memory.flags)?;
+        self.checked_push_to(stack, msb)?;
+        self.checked_push_to(stack, lsb)?;
+        self.interrupt_stack_active = interrupt_stack != 0;
+        Ok(())
+    }
+
+    /// A code location as it's pushed to the stack, which only has room for 16 bits
+    fn return_address(&self, pc: usize) -> Result<u16, DevolaError> {
+        u16::try_from(pc).map_err(|_| DevolaError::InvalidArgument(self.fault_location()))
+    }
+
+    /// Pushes a byte to the stack, e.g. to return a value from an extern. The stack pointer wraps around the
+    /// end of memory, which instructions treat as a fault.
+    pub fn push(&mut self, value: u8) {
+        self.push_to(self.memory_map.stack_pointer_msb(), value);
+    }
+    /// Pops a byte from the stack, e.g. to take an extern's arguments
+    pub fn pop(&mut self) -> u8 {
+        self.pop_from(self.memory_map.stack_pointer_msb())
+    }
+
+    /// Pushes to the stack whose pointer is at `pointer` and the address after it
+    fn push_to(&mut self, pointer: u16, value: u8) {
+        let new_stack_pointer = self.get_pointer(pointer).wrapping_sub(1);
         let (msb, lsb) = break_u16(new_stack_pointer);
         self.memory[new_stack_pointer] = value;
-        self.memory[STACK_POINTER_MSB] = msb;
-        self.memory[STACK_POINTER_LSB] = lsb;
+        self.memory[pointer] = msb;
+        self.memory[pointer + 1] = lsb;
+    }
+    fn pop_from(&mut self, pointer: u16) -> u8 {
+        let stack_pointer = self.get_pointer(pointer);
+        let (msb, lsb) = break_u16(stack_pointer.wrapping_add(1));
+        self.memory[pointer] = msb;
+        self.memory[pointer + 1] = lsb;
+
+        self.memory[stack_pointer]
+    }
+
+    fn checked_push(&mut self, value: u8) -> Result<(), DevolaError> {
+        self.checked_push_to(self.memory_map.stack_pointer_msb(), value)
+    }
+    fn checked_pop(&mut self) -> Result<u8, DevolaError> {
+        self.checked_pop_from(self.memory_map.stack_pointer_msb())
     }
-    pub(crate) fn pop(&mut self) -> u8 {
-        let new_stack_pointer = self.get_stack_pointer()+1;
-        let (msb, lsb) = break_u16(new_stack_pointer);
-        self.memory[STACK_POINTER_MSB] = msb;
-        self.memory[STACK_POINTER_LSB] = lsb;
 
-        self.memory[new_stack_pointer-1]
+    fn checked_push_to(&mut self, pointer: u16, value: u8) -> Result<(), DevolaError> {
+        if self.get_pointer(pointer) == 0 {
+            return Err(DevolaError::StackOverflow(self.fault_location()));
+        }
+        self.push_to(pointer, value);
+        Ok(())
+    }
+    fn checked_pop_from(&mut self, pointer: u16) -> Result<u8, DevolaError> {
+        if self.get_pointer(pointer) as usize + 1 >= self.memory_map.size {
+            return Err(DevolaError::StackOverflow(self.fault_location()));
+        }
+        Ok(self.pop_from(pointer))
     }
 
-    fn resolve_rvalue(&self, addressing_mode: AddressingMode) -> u8 {
-        match addressing_mode {
+    /// The address `offset` bytes from `XY`. Addresses don't wrap around: an offset reaching below 0 or past
+    /// 0xFFFF is a fault. Past the end of a smaller memory, the address repeats it like any other.
+    fn index_offset(&self, offset: i16) -> Result<u16, DevolaError> {
+        self.memory.get_index().checked_add_signed(offset)
+            .ok_or(DevolaError::InvalidArgument(self.fault_location()))
+    }
+
+    /// Sets the flags for `value` compared with `comparator`, as `cmp` does with the accumulator as the value
+    fn compare(&mut self, value: u8, comparator: u8) {
+        self.memory.clear_flag(Flag::Zero);
+        self.memory.clear_flag(Flag::Sign);
+        self.memory.clear_flag(Flag::Parity);
+        self.memory.clear_flag(Flag::Carry);
+
+        if comparator == value {
+            self.memory.set_flag(Flag::Zero);
+        }
+        if (comparator & 0x80) == (value & 0x80) {
+            self.memory.set_flag(Flag::Sign);
+        }
+        if (comparator % 2) == (value % 2) {
+            self.memory.set_flag(Flag::Parity);
+        }
+        if value < comparator {
+            self.memory.set_flag(Flag::Carry);
+        }
+    }
+
+    fn resolve_rvalue(&self, addressing_mode: AddressingMode) -> Result<u8, DevolaError> {
+        Ok(match addressing_mode {
             AddressingMode::Register(register) => self.memory[register],
             AddressingMode::Immediate(value) => value,
             AddressingMode::Indirect(source) => self.memory[source],
             AddressingMode::Index => self.memory[self.memory.get_index()],
-            AddressingMode::IndexOffset(offset) => self.memory[self.memory.get_index() + offset]
+            AddressingMode::IndexOffset(offset) => self.memory[self.index_offset(offset)?]
+        })
+    }
+
+    /// Checks that a jump or call lands in the code. Landing on the last instruction is fine, as it's the one
+    /// before the next to be executed.
+    fn jump_destination(&self, destination: usize) -> Result<usize, DevolaError> {
+        if destination < self.code.len() {
+            Ok(destination)
+        } else {
+            Err(DevolaError::InvalidArgument(self.fault_location()))
         }
     }
 
     fn get_stack_pointer(&self) -> u16 {
-        build_u16(self.memory[STACK_POINTER_MSB], self.memory[STACK_POINTER_LSB])
+        self.get_pointer(self.memory_map.stack_pointer_msb())
+    }
+    /// A stack pointer kept at `pointer` and the address after it
+    fn get_pointer(&self, pointer: u16) -> u16 {
+        build_u16(self.memory[pointer], self.memory[pointer + 1])
+    }
+
+    fn set_pointer(&mut self, pointer: u16, value: u16) {
+        let (msb, lsb) = break_u16(value);
+        self.memory[pointer] = msb;
+        self.memory[pointer + 1] = lsb;
     }
 
     fn execute_instruction(&mut self, instruction: Instruction) -> Result<(), DevolaError> {
         match instruction {
             Instruction::Load(dest_register, addressing_mode) => {
-                let value = self.resolve_rvalue(addressing_mode);
+                let value = self.resolve_rvalue(addressing_mode)?;
                 self.memory[dest_register] = value;
                 Ok(())
             }
             Instruction::Store(register, addressing_mode) => {
                 let dest_byte = match addressing_mode {
-                    AddressingMode::Register(_) | AddressingMode::Immediate(_) => { return Err(DevolaError::InvalidArgument) }
+                    AddressingMode::Register(_) | AddressingMode::Immediate(_) => { return Err(DevolaError::InvalidArgument(self.fault_location())) }
                     AddressingMode::Indirect(pointer) => pointer,
                     AddressingMode::Index => self.memory.get_index(),
-                    AddressingMode::IndexOffset(offset) => self.memory.get_index() + offset
+                    AddressingMode::IndexOffset(offset) => self.index_offset(offset)?
                 };
                 self.memory[dest_byte] = self.memory[register];
                 Ok(())
@@ -280,7 +1116,7 @@ impl Devola {
                 self.memory.clear_flag(Flag::Parity);
                 self.memory.clear_flag(Flag::Carry);
 
-                let addand = self.resolve_rvalue(addressing_mode);
+                let addand = self.resolve_rvalue(addressing_mode)?;
                 let accumulator = self.memory[Register::Accumulator];
 
                 let (result, carry) = accumulator.overflowing_add(addand);
@@ -306,7 +1142,7 @@ impl Devola {
                 self.memory.clear_flag(Flag::Parity);
                 self.memory.clear_flag(Flag::Carry);
 
-                let addand = self.resolve_rvalue(addressing_mode);
+                let addand = self.resolve_rvalue(addressing_mode)?;
                 let index = self.memory.get_index();
 
                 let (result, carry) = index.overflowing_add(addand as u16);
@@ -332,7 +1168,7 @@ impl Devola {
                 self.memory.clear_flag(Flag::Parity);
                 self.memory.clear_flag(Flag::Carry);
 
-                let addand = self.resolve_rvalue(addressing_mode);
+                let addand = self.resolve_rvalue(addressing_mode)?;
                 let index = self.memory.get_index();
 
                 let (result, carry) = index.overflowing_sub(addand as u16);
@@ -359,7 +1195,7 @@ impl Devola {
                 self.memory.clear_flag(Flag::Parity);
                 self.memory.clear_flag(Flag::Carry);
 
-                let addand = self.resolve_rvalue(addressing_mode);
+                let addand = self.resolve_rvalue(addressing_mode)?;
                 let accumulator = self.memory[Register::Accumulator];
 
                 let (result, carry) = accumulator.overflowing_sub(addand);
@@ -381,37 +1217,25 @@ impl Devola {
                 Ok(())
             }
             Instruction::Compare(addressing_mode) => {
-                self.memory.clear_flag(Flag::Zero);
-                self.memory.clear_flag(Flag::Sign);
-                self.memory.clear_flag(Flag::Parity);
-                self.memory.clear_flag(Flag::Carry);
-
-                let comparator = self.resolve_rvalue(addressing_mode);
-                let accumulator = self.memory[Register::Accumulator];
-
-                if comparator == accumulator {
-                    self.memory.set_flag(Flag::Zero);
-                }
-                if (comparator & 0x80) == (accumulator & 0x80) {
-                    self.memory.set_flag(Flag::Sign);
-                }
-                if (comparator % 2) == (accumulator % 2) {
-                    self.memory.set_flag(Flag::Parity);
-                }
-                if accumulator < comparator {
-                    self.memory.set_flag(Flag::Carry);
-                }
-
+                let comparator = self.resolve_rvalue(addressing_mode)?;
+                self.compare(self.memory[Register::Accumulator], comparator);
                 Ok(())
             }
             Instruction::Jump(jump_type, destination) => {
                 match jump_type {
                     JumpType::Unconditional => {
-                        self.pc = destination;
+                        self.pc = self.jump_destination(destination)?;
                     }
                     JumpType::Flag(flag, set) => {
                         if (self.memory.flag(flag) && set) || (!self.memory.flag(flag) && !set) {
-                            self.pc = destination;
+                            self.pc = self.jump_destination(destination)?;
+                        }
+                    }
+                    JumpType::CompareNotEqual(addressing_mode, comparator) => {
+                        let value = self.resolve_rvalue(addressing_mode)?;
+                        self.compare(value, comparator);
+                        if value != comparator {
+                            self.pc = self.jump_destination(destination)?;
                         }
                     }
                 };
@@ -420,43 +1244,77 @@ impl Devola {
             Instruction::Call(call_type) => {
                 match call_type {
                     CallType::Local(dest) => {
-                        let (msb, lsb) = break_u16(self.pc as u16);
-                        self.push(msb);
-                        self.push(lsb);
+                        let dest = self.jump_destination(dest)?;
+                        let (msb, lsb) = break_u16(self.return_address(self.pc)?);
+                        let stack = self.memory_map.return_stack_pointer();
+                        self.checked_push_to(stack, msb)?;
+                        self.checked_push_to(stack, lsb)?;
                         self.pc = dest;
                         Ok(())
                     }
                     CallType::Library(symbol) => {
-                        // match &mut self.externs {
-                        //     None => Err(DevolaError::InvalidArgument), // TODO: make new error type
-                        //     Some(extern_table) => {
-                        //         match &mut extern_table.get_mut(&symbol) {
-                        //             Some(func) => { (**func)(self); Ok(()) },
-                        //             None => Err(DevolaError::InvalidArgument)
-                        //         }
-                        //     }
-                        // }
-                        Err(DevolaError::Unimplemented)
+                        // the function is taken out of the table while it runs, as it needs the whole VM
+                        let Some(mut function) = self.externs.as_mut().and_then(|externs| externs.remove(&symbol)) else {
+                            return Err(DevolaError::Unimplemented(self.fault_location()));
+                        };
+                        function(self);
+                        // the name is only copied the first time the function is called
+                        match self.extern_calls.get_mut(&symbol) {
+                            Some(calls) => *calls += 1,
+                            None => { self.extern_calls.insert(symbol.clone(), 1); }
+                        }
+                        self.externs.get_or_insert_with(HashMap::new).insert(symbol, function);
+                        if std::mem::take(&mut self.failed_assertion) {
+                            return Err(DevolaError::AssertionFailed(self.fault_location()));
+                        }
+                        Ok(())
                     }
                 }
             }
             Instruction::Return => {
-                let lsb = self.pop();
-                let msb = self.pop();
+                let stack = self.memory_map.return_stack_pointer();
+                let lsb = self.checked_pop_from(stack)?;
+                let msb = self.checked_pop_from(stack)?;
                 self.pc = build_u16(msb, lsb) as usize;
                 Ok(())
             }
-            Instruction::Push(register) => {
-                self.push(self.memory[register]);
+            Instruction::ReturnInterrupt => {
+                let stack = self.memory_map.return_stack_pointer();
+                let lsb = self.checked_pop_from(stack)?;
+                let msb = self.checked_pop_from(stack)?;
+                self.memory.flags = self.checked_pop_from(stack)?;
+                self.pc = build_u16(msb, lsb) as usize;
+                if self.interrupt_stack_active {
+                    let lsb = self.checked_pop()?;
+                    let msb = self.checked_pop()?;
+                    self.set_pointer(self.memory_map.stack_pointer_msb(), build_u16(msb, lsb));
+                    self.interrupt_stack_active = false;
+                }
+                self.in_interrupt = false;
                 Ok(())
             }
+            Instruction::Push(register) => {
+                self.checked_push(self.memory[register])
+            }
             Instruction::Pop(register) => {
-                self.memory[register] = self.pop();
+                self.memory[register] = self.checked_pop()?;
+                Ok(())
+            }
+            Instruction::Halt => {
+                // an interrupt can only be taken with a handler to jump to, a line enabled and no handler running
+                let enabled = self.memory[self.memory_map.interrupt_enable()] != 0;
+                if self.interrupt_vector.is_none() || !enabled || self.in_interrupt {
+                    return Err(DevolaError::Deadlock(self.fault_location()));
+                }
+                self.waiting = true;
                 Ok(())
             }
+            Instruction::Exit(_) => Err(DevolaError::EndCode),
             Instruction::Nop | Instruction::_Label(_) | Instruction::_LabeledJump(_, _) | Instruction::_LabeledCall(_) => Ok(()),
             Instruction::_Assert(addressing_mode, value) => {
-                assert_eq!(self.resolve_rvalue(addressing_mode), value);
+                if self.resolve_rvalue(addressing_mode)? != value {
+                    return Err(DevolaError::AssertionFailed(self.fault_location()));
+                }
                 Ok(())
             }
         }
@@ -539,6 +1397,35 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_negative_index_offset() {
+        let index = |x, y| vec![
+            Instruction::Load(Register::IndexX, AddressingMode::Immediate(x)),
+            Instruction::Load(Register::IndexY, AddressingMode::Immediate(y))
+        ];
+        // a local below a base pointer, the way a stack frame's would be
+        let code = [index(1, 1), vec![
+            Instruction::Load(Register::Accumulator, AddressingMode::Immediate(42)),
+            Instruction::Store(Register::Accumulator, AddressingMode::IndexOffset(-2)),
+            Instruction::_Assert(AddressingMode::Indirect(0x00FF), 42),
+            Instruction::_Assert(AddressingMode::IndexOffset(-0x0101), 0)
+        ]].concat();
+        assert_eq!(Devola::new(code, None).run(), Ok(0));
+
+        // reaching below address 0 doesn't wrap around to the top of memory
+        let code = [index(0, 1), vec![Instruction::Load(Register::Accumulator, AddressingMode::IndexOffset(-2))]].concat();
+        assert!(matches!(Devola::new(code, None).run(), Err(DevolaError::InvalidArgument(_))));
+
+        // past the end of a smaller memory, offsets repeat it the same as XY on its own
+        let memory_map = MemoryMap { size: 0x0400, mmio: 0x03F0, initial_stack_pointer: 0x0300, return_stack: None };
+        let code = [index(0x05, 0x00), vec![
+            Instruction::Load(Register::Accumulator, AddressingMode::Immediate(42)),
+            Instruction::Store(Register::Accumulator, AddressingMode::IndexOffset(1)),
+            Instruction::_Assert(AddressingMode::Indirect(0x0101), 42)
+        ]].concat();
+        assert_eq!(Devola::with_memory_map(code, None, memory_map).run(), Ok(0));
+    }
+
     #[test]
     /// Tests the equivalent of
     /// ```asm
@@ -660,6 +1547,406 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_run_cycles() {
+        let (code, _) = parser::intermediate::process_labels(vec![
+            Instruction::_Label(String::from("loop")),
+            Instruction::Increment,
+            Instruction::_LabeledJump(JumpType::Unconditional, String::from("loop"))
+        ], None).unwrap();
+
+        let mut devola = Devola::new(code, None);
+        // the label nop takes 1 cycle, then each iteration takes 1 (inc) + 3 (jmp) cycles
+        assert_eq!(devola.run_cycles(10).unwrap(), 10);
+        assert_eq!(devola.memory[Register::Accumulator], 3);
+        // the budget is overshot by the remainder of the last instruction
+        assert_eq!(devola.run_cycles(2).unwrap(), 3);
+        assert_eq!(devola.cycles(), 13);
+
+        // the end of the code stops early
+        let mut devola = Devola::new(vec![Instruction::Nop], None);
+        assert_eq!(devola.run_cycles(100).unwrap(), 1);
+    }
+
+    #[test]
+    fn test_interrupt() {
+        let (code, symbols) = parser::intermediate::process_labels(vec![
+            Instruction::Load(Register::Accumulator, AddressingMode::Immediate(1)),
+            Instruction::Store(Register::Accumulator, AddressingMode::Indirect(INTERRUPT_ENABLE)),
+            Instruction::Compare(AddressingMode::Immediate(1)),
+            Instruction::_Label(String::from("loop")),
+            Instruction::_LabeledJump(JumpType::Unconditional, String::from("loop")),
+            Instruction::_Label(String::from("irq")),
+            Instruction::Load(Register::UtilityB, AddressingMode::Immediate(0)),
+            Instruction::Store(Register::UtilityB, AddressingMode::Indirect(INTERRUPT_REQUEST)),
+            Instruction::Load(Register::UtilityC, AddressingMode::Register(Register::Accumulator)),
+            Instruction::Increment,
+            Instruction::Compare(AddressingMode::Immediate(0)),
+            Instruction::ReturnInterrupt
+        ], None).unwrap();
+
+        let mut devola = Devola::new(code, Some(symbols));
+        devola.run_cycles(20).unwrap();
+        assert!(devola.memory.flag(Flag::Zero));
+
+        devola.request_interrupt(0);
+        devola.run_cycles(40).unwrap();
+        // the handler ran exactly once and the interrupted loop's flags were restored
+        assert_eq!(devola.memory[Register::UtilityC], 1);
+        assert_eq!(devola.memory[Register::Accumulator], 2);
+        assert_eq!(devola.memory[INTERRUPT_REQUEST], 0);
+        assert!(devola.memory.flag(Flag::Zero));
+
+        // disabled lines are never serviced
+        devola.poke(INTERRUPT_ENABLE, 0);
+        devola.request_interrupt(0);
+        devola.run_cycles(40).unwrap();
+        assert_eq!(devola.memory[Register::Accumulator], 2);
+    }
+
+    #[test]
+    fn test_halt() {
+        let source = "lda 1\nsta #0FFEh\nhlt\nldb 7\njmp done\nirq:\nldc 9\nlda 0\nsta #0FFFh\nreti\ndone:";
+        let (code, symbols, _, _) = crate::parser::text::compile(String::from(source), None).unwrap();
+        let mut devola = Devola::new(code, Some(symbols));
+        // the wait passes without running anything after the hlt
+        assert_eq!(devola.run_cycles(1000), Ok(1000));
+        assert!(devola.waiting());
+        assert_eq!((devola.pc(), devola.register(Register::UtilityB)), (3, 0));
+        let state = devola.save_state();
+        assert_eq!(DevolaState::from_bytes(&state.to_bytes()), Some(state.clone()));
+
+        // an interrupt wakes it, and execution carries on after the hlt once the handler returns
+        devola.request_interrupt(0);
+        devola.run_cycles(100).unwrap();
+        assert!(!devola.waiting());
+        assert_eq!((devola.register(Register::UtilityB), devola.register(Register::UtilityC)), (7, 9));
+
+        // stepping idles a cycle at a time
+        devola.load_state(&state).unwrap();
+        devola.step().unwrap();
+        assert_eq!((devola.pc(), devola.cycles()), (3, state.cycles + 1));
+        // with nothing to request an interrupt, running stops instead of waiting forever
+        assert_eq!(devola.run(), Err(DevolaError::Deadlock(FaultLocation { pc: 3, line: None })));
+        // as does a hlt with the interrupts masked
+        let (code, symbols, _, _) = crate::parser::text::compile(source.replace("lda 1", "lda 0"), None).unwrap();
+        let mut devola = Devola::new(code, Some(symbols));
+        assert_eq!(devola.run(), Err(DevolaError::Deadlock(FaultLocation { pc: 2, line: None })));
+        assert!(!devola.waiting());
+    }
+
+    #[test]
+    fn test_idle_tick() {
+        let source = "lda 1\nsta #0FFEh\nhlt\nhlt 3\nirq:\nlda 0\nsta #0FFFh\nreti";
+        let (code, symbols, _, _) = parser::text::compile(String::from(source), None).unwrap();
+        let mut devola = Devola::new(code, Some(symbols));
+        // a timer firing 100 cycles in wakes the hlt then, rather than once the whole budget has passed
+        let mut elapsed = 0;
+        devola.set_tick(Box::new(move |devola: &mut Devola, cycles| {
+            elapsed += cycles;
+            if elapsed >= 100 && elapsed - cycles < 100 {
+                devola.request_interrupt(0);
+            }
+        }));
+        devola.run_cycles(10_000).unwrap();
+        assert!(!devola.waiting());
+        assert_eq!(devola.exit_code(), Some(3));
+        assert!(devola.cycles() < 100 + IDLE_STEP_CYCLES + INTERRUPT_CYCLES + 20);
+    }
+
+    #[test]
+    fn test_exit() {
+        let program = |value: u8| {
+            let source = format!(".equ FAILED 3\nlda {}\ncmp 2\njz passed\nhlt FAILED\npassed:\nhlt 0\nldb 1", value);
+            let (code, symbols, _, _) = crate::parser::text::compile(source, None).unwrap();
+            Devola::new(code, Some(symbols))
+        };
+        let mut devola = program(2);
+        assert_eq!(devola.run(), Ok(0));
+        // the exit stays put, without running what comes after it
+        assert_eq!((devola.exit_code(), devola.register(Register::UtilityB)), (Some(0), 0));
+        assert_eq!(devola.step(), Err(DevolaError::EndCode));
+        assert_eq!(program(1).run(), Ok(3));
+        // running off the end of the code is a success
+        assert_eq!(Devola::new(Vec::new(), None).run(), Ok(0));
+    }
+
+    #[test]
+    fn test_compare_jump() {
+        let source = String::from(".equ COUNT 5\nlda 0\nloop:\ninc\ncjne a, COUNT, loop\nldb 1\ncjne b, 1, loop");
+        let (code, symbols, _, _) = crate::parser::text::compile(source, None).unwrap();
+        let mut devola = Devola::new(code, Some(symbols));
+        assert_eq!(devola.run(), Ok(0));
+        // each time around is inc and a 4-cycle cjne, and the flags are left as cmp would leave them
+        assert_eq!((devola.register(Register::Accumulator), devola.cycles()), (5, 2 + 1 + 5*5 + 2 + 4));
+        assert!(devola.memory.flag(Flag::Zero) && !devola.memory.flag(Flag::Carry));
+    }
+
+    #[test]
+    fn test_dirty_tracking() {
+        let mut devola = Devola::new(vec![
+            Instruction::Load(Register::Accumulator, AddressingMode::Immediate(1)),
+            Instruction::Store(Register::Accumulator, AddressingMode::Indirect(0x1234))
+        ], None);
+        assert!(devola.is_dirty(0x8000, 1));
+
+        devola.clear_dirty();
+        assert!(!devola.is_dirty(0, u16::MAX));
+        devola.run().unwrap();
+        assert!(devola.is_dirty(0x1234, 1));
+        // the rest of the block is dirty too, but not the blocks around it
+        assert!(devola.is_dirty(0x1230, 1));
+        assert!(!devola.is_dirty(0x1200, 0x30));
+        assert!(!devola.is_dirty(0x1240, 0x100));
+
+        // ranges can wrap around the end of memory
+        devola.clear_dirty();
+        devola.poke(0x0002, 1);
+        assert!(devola.is_dirty(0xFFF0, 0x20));
+        assert!(!devola.is_dirty(0xFFF0, 0x10));
+    }
+
+    #[test]
+    fn test_hot_swap() {
+        let compile = |source: &str| parser::text::compile(source.to_string(), None).unwrap();
+        let (code, symbols, lines, _) = compile("lda 5\nsta #0200h\nloop:\njmp loop");
+        let mut devola = Devola::new(code, Some(symbols));
+        devola.set_line_table(lines);
+        devola.run_cycles(20).unwrap();
+        let (pc, cycles) = (devola.pc(), devola.cycles());
+
+        // the same labels in the same places, so the loop carries on
+        let (code, symbols, _, _) = compile("lda 6\nsta #0200h\nloop:\njmp loop");
+        assert!(devola.hot_swap(RomImage::new(code), Some(symbols)));
+        assert_eq!((devola.pc(), devola.cycles(), devola.peek(0x0200)), (pc, cycles, 5));
+        assert_eq!(devola.line(0), None);
+
+        // starting over, the program's data is in place and it starts from its entry point
+        let (code, symbols, _, segments) = compile("nop\nlda 7\nsta #0200h\nloop:\njmp loop\n.org 0300h\n.db 9");
+        assert!(!devola.hot_swap(RomImage { entry_point: 1, segments, ..RomImage::new(code) }, Some(symbols)));
+        assert_eq!((devola.pc(), devola.cycles(), devola.peek(0x0200), devola.peek(0x0300)), (1, cycles, 0, 9));
+        assert_eq!(devola.stack_pointer(), INITIAL_STACK_POINTER);
+        devola.run_cycles(20).unwrap();
+        assert_eq!(devola.peek(0x0200), 7);
+    }
+
+    #[test]
+    fn test_memory_map() {
+        let memory_map = MemoryMap { size: 0x0400, mmio: 0x03F0, initial_stack_pointer: 0x0300, return_stack: None };
+        let code = vec![
+            Instruction::Load(Register::Accumulator, AddressingMode::Immediate(5)),
+            Instruction::Push(Register::Accumulator),
+            Instruction::Store(Register::Accumulator, AddressingMode::Indirect(0x0410))
+        ];
+        let mut devola = Devola::with_memory_map(code, None, memory_map);
+        devola.run().unwrap();
+        assert_eq!((devola.stack_pointer(), devola.peek(0x02FF), devola.peek(memory_map.stack_pointer_lsb())), (0x02FF, 5, 0xFF));
+        // the memory repeats past its end
+        assert_eq!(devola.peek(0x0010), 5);
+
+        devola.load_state(&Devola::with_memory_map(Vec::new(), None, memory_map).save_state()).unwrap();
+        // a state read from elsewhere can have memory of any size, and is only loaded if it fits
+        let state = DevolaState { memory: vec![0; 0x0400], ..devola.save_state() };
+        assert_eq!(devola.load_state(&state), Err(StateMismatch { expected: MEMORY_SIZE, found: 0x0400 }));
+        for _ in 0..0x0100 {
+            devola.pop();
+        }
+        assert!(matches!(devola.execute_instruction(Instruction::Pop(Register::Accumulator)), Err(DevolaError::StackOverflow(_))));
+        devola.poke(memory_map.interrupt_request(), 0);
+        devola.request_interrupt(3);
+        assert_eq!(devola.peek(0x07FF), 1 << 3);
+
+        assert!(!MemoryMap { size: 3000, ..MemoryMap::default() }.is_valid());
+        assert!(!MemoryMap { return_stack: Some(0x0800), ..memory_map }.is_valid());
+        assert!(!MemoryMap { size: 0x0400, ..MemoryMap::default() }.is_valid());
+    }
+
+    #[test]
+    fn test_memory_views() {
+        let memory_map = MemoryMap { size: 0x0400, mmio: 0x03F0, initial_stack_pointer: 0x0300, return_stack: None };
+        let mut devola = Devola::with_memory_map(Vec::new(), None, memory_map);
+        for (offset, value) in [0x12, 0x34, 0x56, 0x78, 0x9A].into_iter().enumerate() {
+            devola.poke(0x0100 + offset as u16, value);
+        }
+        let memory = devola.memory();
+        assert_eq!(memory.size(), 0x0400);
+        assert_eq!(memory.chunks(0x0100).count(), 4);
+        assert_eq!(memory.chunks(0x0100).nth(1).unwrap()[..2], [0x12, 0x34]);
+        // ranges past the end of memory read it again
+        assert_eq!(memory.iter_range(0x0500, 3).collect::<Vec<u8>>(), [0x12, 0x34, 0x56]);
+        // and so do ranges past 0xFFFF, which can include it
+        assert_eq!(memory.iter_range(0xFFFF, 2).collect::<Vec<u8>>(), [0, 0]);
+
+        let view = memory.view(0x0500, 5).unwrap();
+        assert_eq!((view.start(), view.len(), view.word(1), view.word(4)), (0x0500, 5, Some(0x3456), None));
+        let entries: Vec<(u16, Option<u16>)> = view.entries(2).map(|entry| (entry.start(), entry.word(0))).collect();
+        assert_eq!(entries, [(0x0500, Some(0x1234)), (0x0502, Some(0x5678))]);
+        // a view can't wrap around to the start
+        assert!(memory.view(0x03FE, 4).is_none());
+        assert_eq!(memory.view(0x03FE, 2).unwrap().len(), 2);
+        assert_eq!(memory.view(0xFFFF, 1).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_interrupt_stack() {
+        // the handler pushes 3 bytes, which the interrupted stack doesn't have room for
+        let source = "
+            lda 0eh
+            sta #0FFAh
+            lda 0
+            sta #0FFBh
+            lda 1
+            sta #0FFEh
+            loop:
+            jmp loop
+            irq:
+            push a
+            push b
+            push c
+            ldb 0
+            stb #0FFFh
+            pop c
+            pop b
+            pop a
+            reti
+        ";
+        let (code, symbols, _, _) = parser::text::compile(String::from(source), None).unwrap();
+        let memory_map = MemoryMap { initial_stack_pointer: 0x0002, ..MemoryMap::default() };
+        let mut devola = Devola::with_memory_map(code, Some(symbols), memory_map);
+        devola.run_cycles(20).unwrap();
+        devola.request_interrupt(0);
+        devola.step().unwrap();
+        // the interrupted stack pointer, the flags and the return address are on the interrupt stack
+        assert!(devola.in_interrupt());
+        assert_eq!(devola.stack_pointer(), 0x0E00 - 5);
+        assert_eq!((devola.peek(0x0DFF), devola.peek(0x0DFE)), (0x00, 0x02));
+
+        // the handler returns to the interrupted stack, and a saved state in between carries on the same way
+        devola.run_cycles(5).unwrap();
+        let state = devola.save_state();
+        devola.load_state(&DevolaState::from_bytes(&state.to_bytes()).unwrap()).unwrap();
+        devola.run_cycles(40).unwrap();
+        assert!(!devola.in_interrupt());
+        assert_eq!(devola.stack_pointer(), 0x0002);
+
+        // an interrupt stack without room for the frame faults, leaving the interrupted stack in place
+        devola.poke(0x0FFA, 0x00);
+        devola.poke(0x0FFB, 0x01);
+        devola.request_interrupt(0);
+        assert!(matches!(devola.step(), Err(DevolaError::StackOverflow(_))));
+        assert!(!devola.in_interrupt());
+        assert_eq!(devola.stack_pointer(), 0x0002);
+    }
+
+    #[test]
+    fn test_tick() {
+        let source = "lda 1\nsta #0FFEh\nloop:\ninc\njmp loop\nirq:\nhlt 7";
+        let (code, symbols, _, _) = parser::text::compile(String::from(source), None).unwrap();
+        let mut devola = Devola::new(code, Some(symbols));
+        // a timer that fires once 20 cycles have passed, noting when the VM was told
+        let ticks = std::rc::Rc::new(std::cell::RefCell::new(Vec::new()));
+        let seen = ticks.clone();
+        let mut elapsed = 0;
+        devola.set_tick(Box::new(move |devola: &mut Devola, cycles| {
+            elapsed += cycles;
+            if elapsed >= 20 && elapsed - cycles < 20 {
+                devola.request_interrupt(0);
+            }
+            seen.borrow_mut().push(devola.cycles());
+        }));
+        assert_eq!(devola.run(), Ok(7));
+        // the interrupt is taken straight after the instruction that took the timer past 20 cycles
+        let ticks = ticks.borrow();
+        let fired = ticks.iter().position(|&cycles| cycles >= 20).unwrap();
+        assert_eq!(ticks[fired + 1], ticks[fired] + INTERRUPT_CYCLES);
+        assert_eq!(ticks.last(), Some(&devola.cycles()));
+    }
+
+    #[test]
+    fn test_return_stack() {
+        // pushes a value for the caller, which would return to it with a single stack
+        let source = "call give\npop b\njmp end\ngive:\nlda 7\npush a\nret\nend:";
+        let (code, symbols, _, _) = parser::text::compile(String::from(source), None).unwrap();
+        let memory_map = MemoryMap { return_stack: Some(0x0E00), ..MemoryMap::default() };
+        let mut devola = Devola::with_memory_map(code, Some(symbols), memory_map);
+        devola.run().unwrap();
+        assert_eq!(devola.register(Register::UtilityB), 7);
+        assert_eq!((devola.stack_pointer(), devola.return_stack_pointer()), (INITIAL_STACK_POINTER, 0x0E00));
+
+        // with one stack, `ret` takes the pushed value as part of the return address instead
+        let (code, symbols, _, _) = parser::text::compile(String::from(source), None).unwrap();
+        let mut devola = Devola::new(code, Some(symbols));
+        devola.run().unwrap();
+        assert_eq!(devola.register(Register::UtilityB), 0);
+    }
+
+    #[test]
+    fn test_save_state() {
+        let mut devola = Devola::new(vec![
+            Instruction::Load(Register::Accumulator, AddressingMode::Immediate(1)),
+            Instruction::Store(Register::Accumulator, AddressingMode::Indirect(0x1234)),
+            Instruction::Increment,
+            Instruction::Store(Register::Accumulator, AddressingMode::Indirect(0x1234))
+        ], None);
+        devola.step().unwrap();
+        devola.step().unwrap();
+        let state = devola.save_state();
+        assert_eq!(DevolaState::from_bytes(&state.to_bytes()), Some(state.clone()));
+        assert_eq!(DevolaState::from_bytes(&[0; 4]), None);
+
+        devola.run().unwrap();
+        assert_eq!(devola.peek(0x1234), 2);
+        // loading the state rewinds to just after the first store
+        devola.clear_dirty();
+        devola.load_state(&state).unwrap();
+        assert!(devola.is_dirty(0, 1));
+        assert_eq!((devola.pc(), devola.cycles(), devola.peek(0x1234)), (2, state.cycles, 1));
+        devola.run().unwrap();
+        assert_eq!(devola.peek(0x1234), 2);
+    }
+
+    #[test]
+    fn test_fault_location() {
+        let code = vec![
+            Instruction::Load(Register::Accumulator, AddressingMode::Immediate(1)),
+            Instruction::Store(Register::Accumulator, AddressingMode::Register(Register::UtilityB))
+        ];
+        let location = FaultLocation { pc: 1, line: None };
+        let mut devola = Devola::new(code.clone(), None);
+        assert_eq!(devola.run(), Err(DevolaError::InvalidArgument(location)));
+        assert_eq!(DevolaError::InvalidArgument(location).to_string(), "invalid argument at instruction 1");
+
+        let mut devola = Devola::new(code, None);
+        devola.set_line_table(vec![0, 3]);
+        let error = devola.run().unwrap_err();
+        assert_eq!(error, DevolaError::InvalidArgument(FaultLocation { pc: 1, line: Some(3) }));
+        assert_eq!(error.to_string(), "invalid argument at line 4 (instruction 1)");
+    }
+
+    #[test]
+    fn test_externs() {
+        use crate::stdlib::interface::DevolaExternTable;
+        let double = || {
+            let mut externs = DevolaExternTable::new();
+            externs.insert(String::from("double"), Box::new(|devola: &mut Devola| {
+                let value = devola.pop();
+                devola.push(value * 2);
+            }));
+            externs
+        };
+        let code = String::from("lda 21\npush a\ncall double\npop b");
+        let (code, symbols, _, _) = crate::parser::text::compile(code, Some(double())).unwrap();
+        let mut devola = Devola::new(code, Some(symbols));
+        devola.set_externs(double());
+        devola.run().unwrap();
+        assert_eq!(devola.register(Register::UtilityB), 42);
+
+        // externs the VM wasn't given can't be called
+        let mut devola = Devola::new(vec![Instruction::Call(CallType::Library(String::from("halve")))], None);
+        devola.register_extern("double", double().remove("double").unwrap());
+        assert_eq!(devola.run(), Err(DevolaError::Unimplemented(FaultLocation { pc: 0, line: None })));
+    }
+
     #[test]
     fn test_compile_run_from_source_squares() {
         crate::util::execute_file("sample/square.pop").unwrap();
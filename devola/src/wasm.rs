@@ -0,0 +1,132 @@
+//! JavaScript bindings, so that programs can be assembled and run in a browser. Built with
+//! `wasm-pack build --target web -- --features wasm`.
+use crate::instructions::{Flag, Register};
+use crate::parser::text::compile;
+use crate::vm::{Devola, DevolaError};
+use wasm_bindgen::prelude::*;
+
+fn to_js(error: DevolaError) -> JsError {
+    JsError::new(&error.to_string())
+}
+
+/// A compiled program and the VM running it
+#[wasm_bindgen(js_name = Devola)]
+pub struct WasmDevola {
+    devola: Devola
+}
+
+#[wasm_bindgen(js_class = Devola)]
+impl WasmDevola {
+    /// Compiles a program, throwing its errors, one per line, if it can't be built
+    #[wasm_bindgen(constructor)]
+    pub fn new(source: String) -> Result<WasmDevola, JsError> {
+        let (code, symbols, lines, segments) = compile(source, None).map_err(|errors| {
+            JsError::new(&errors.iter().map(|error| error.to_string()).collect::<Vec<_>>().join("\n"))
+        })?;
+        let mut devola = Devola::with_data(code, Some(symbols), &segments);
+        devola.set_line_table(lines);
+        Ok(WasmDevola { devola })
+    }
+
+    /// Executes a single instruction, returning false once the end of the code has been reached
+    pub fn step(&mut self) -> Result<bool, JsError> {
+        match self.devola.step() {
+            Ok(()) => Ok(true),
+            Err(DevolaError::EndCode) => Ok(false),
+            Err(error) => Err(to_js(error))
+        }
+    }
+
+    /// Runs until the end of the code, returning the program's exit code
+    pub fn run(&mut self) -> Result<u8, JsError> {
+        self.devola.run().map_err(to_js)
+    }
+
+    /// Runs for at least `cycles` cycles or until the end of the code, returning the number taken
+    #[wasm_bindgen(js_name = runCycles)]
+    pub fn run_cycles(&mut self, cycles: u32) -> Result<u32, JsError> {
+        self.devola.run_cycles(cycles as u64).map(|taken| taken as u32).map_err(to_js)
+    }
+
+    pub fn peek(&self, address: u16) -> u8 {
+        self.devola.peek(address)
+    }
+
+    pub fn poke(&mut self, address: u16, value: u8) {
+        self.devola.poke(address, value);
+    }
+
+    /// Copies `length` bytes of memory from `address`, wrapping around the end of memory
+    #[wasm_bindgen(js_name = readMemory)]
+    pub fn read_memory(&self, address: u16, length: u16) -> Vec<u8> {
+        (0..length).map(|offset| self.devola.peek(address.wrapping_add(offset))).collect()
+    }
+
+    #[wasm_bindgen(js_name = writeMemory)]
+    pub fn write_memory(&mut self, address: u16, bytes: &[u8]) {
+        for (offset, &byte) in bytes.iter().enumerate() {
+            self.devola.poke(address.wrapping_add(offset as u16), byte);
+        }
+    }
+
+    /// Requests an interrupt on a line (0-7)
+    #[wasm_bindgen(js_name = requestInterrupt)]
+    pub fn request_interrupt(&mut self, line: u8) {
+        self.devola.request_interrupt(line);
+    }
+
+    pub fn pc(&self) -> usize {
+        self.devola.pc()
+    }
+
+    /// The cycles elapsed since the program started, as a float since counts can outgrow 32 bits
+    pub fn cycles(&self) -> f64 {
+        self.devola.cycles() as f64
+    }
+
+    /// The registers, in the order A, B, C, X, Y
+    pub fn registers(&self) -> Vec<u8> {
+        [Register::Accumulator, Register::UtilityB, Register::UtilityC, Register::IndexX, Register::IndexY]
+            .into_iter()
+            .map(|register| self.devola.register(register))
+            .collect()
+    }
+
+    /// The flags packed into a byte: carry, zero, parity and sign from the lowest bit up
+    pub fn flags(&self) -> u8 {
+        [Flag::Carry, Flag::Zero, Flag::Parity, Flag::Sign].into_iter()
+            .enumerate()
+            .fold(0, |flags, (bit, flag)| flags | ((self.devola.flag(flag) as u8) << bit))
+    }
+
+    #[wasm_bindgen(js_name = stackPointer)]
+    pub fn stack_pointer(&self) -> u16 {
+        self.devola.stack_pointer()
+    }
+
+    /// The source line of the next instruction, counting from 1, if it has one
+    pub fn line(&self) -> Option<usize> {
+        self.devola.line(self.devola.pc()).map(|line| line + 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // only the paths that don't throw can run natively, as creating a JS error needs a JS host
+    #[test]
+    fn test_bindings() {
+        let mut devola = WasmDevola::new(String::from("lda 3\nsta #0200h\ndec\ncmp 0")).unwrap();
+        assert_eq!(devola.line(), Some(1));
+        assert!(devola.step().unwrap());
+        assert_eq!(devola.registers(), vec![3, 0, 0, 0, 0]);
+        devola.write_memory(0xFFFF, &[1, 2]);
+        assert_eq!(devola.read_memory(0xFFFF, 2), vec![1, 2]);
+        devola.run().unwrap();
+        assert_eq!(devola.peek(0x0200), 3);
+        assert!(!devola.step().unwrap());
+        // 2 has the same parity and sign as 0, and isn't less than it
+        assert_eq!(devola.flags(), 0b1100);
+    }
+}
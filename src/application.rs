@@ -1,39 +1,639 @@
+use crate::audio::apu::Apu;
+use crate::audio::output::AudioOutput;
+use crate::audit::{AuditLog, AuditMode, StateDigest};
+use crate::capture::{self, Capture};
+use crate::console::Console;
+use crate::debug::gdb::{GdbEvent, GdbServer};
+use crate::debug::reload::ReloadServer;
+use crate::debug::window::DebugWindow;
+use crate::link::{Link, LINK_SLICE_CYCLES};
 use crate::render::context::RenderContext;
-use crate::inter::mmio::{SCREEN_WIDTH, SCREEN_HEIGHT};
-use crate::gfx::{Color, SpriteSize};
+use crate::replay::{Replay, ReplayMode};
+use crate::render::overlay::SpeedCounter;
+use crate::render::pacer::{FramePacer, SyncMode};
+use crate::inter::gamepad::Gamepads;
+use crate::inter::input_map::{Input, InputMap};
+use crate::inter::mmio::*;
+use crate::inter::serial::{Serial, Transport};
+use crate::inter::text::TextConsole;
+use crate::program;
+use crate::savestate::{self, RewindBuffer, SaveState, SLOT_COUNT};
+use crate::settings::{Settings, Unfocused};
 
+use devola::gdb::{SIGINT, SIGTRAP};
+use devola::vm::{Devola, StateMismatch};
 use pixels::{Pixels, SurfaceTexture};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
 use winit::application::ApplicationHandler;
 use winit::dpi::LogicalSize;
 use winit::event::{WindowEvent};
-use winit::event_loop::ActiveEventLoop;
-use winit::window::{Window, WindowId};
+use winit::event_loop::{ActiveEventLoop, ControlFlow};
+use winit::keyboard::{KeyCode, PhysicalKey};
+use winit::window::{Fullscreen, Window, WindowId};
 
-const VIEW_SCALE: u32 = 2;
+/// Held down to fast-forward
+const TURBO_KEY: KeyCode = KeyCode::Tab;
+/// Toggles the CRT post-process
+const CRT_KEY: KeyCode = KeyCode::F1;
+/// Switches between pacing frames by a timer and by the audio queue
+const SYNC_KEY: KeyCode = KeyCode::F2;
+/// Toggles the frame rate and emulation speed overlay
+const OVERLAY_KEY: KeyCode = KeyCode::F3;
+/// Opens or closes the debugger window
+const DEBUGGER_KEY: KeyCode = KeyCode::F4;
+/// Toggles sprite bounding boxes
+const SPRITE_BOXES_KEY: KeyCode = KeyCode::F5;
+/// Cycles the tile grid through each background
+const TILE_GRID_KEY: KeyCode = KeyCode::F6;
+/// Pauses or resumes emulation
+const PAUSE_KEY: KeyCode = KeyCode::F7;
+/// Runs to the end of the current frame, pausing if running
+const FRAME_STEP_KEY: KeyCode = KeyCode::F8;
+/// Executes a single instruction, pausing if running
+const INSTRUCTION_STEP_KEY: KeyCode = KeyCode::F9;
+/// Cycles through running, pausing and throttling while in the background
+const UNFOCUSED_KEY: KeyCode = KeyCode::F10;
+/// Saves a state in the selected slot
+const SAVE_STATE_KEY: KeyCode = KeyCode::F11;
+/// Loads the state in the selected slot
+const LOAD_STATE_KEY: KeyCode = KeyCode::F12;
+/// Select the save state slot with their number, unless bound to the joypad
+const SLOT_KEYS: [KeyCode; SLOT_COUNT] = [
+    KeyCode::Digit0, KeyCode::Digit1, KeyCode::Digit2, KeyCode::Digit3, KeyCode::Digit4,
+    KeyCode::Digit5, KeyCode::Digit6, KeyCode::Digit7, KeyCode::Digit8, KeyCode::Digit9
+];
+
+/// Held down to step back in time
+const REWIND_KEY: KeyCode = KeyCode::Backspace;
+/// Starts or stops capturing gameplay to a file
+const CAPTURE_KEY: KeyCode = KeyCode::Insert;
+/// Frames between each state kept for rewinding
+const REWIND_INTERVAL: u64 = FRAME_RATE;
+/// How many states are kept for rewinding, a minute's worth
+const REWIND_STATES: usize = 60;
+/// Frames shown for each state stepped back through while rewinding
+const REWIND_STEP_FRAMES: u64 = 15;
+
+/// How long messages stay over the frame
+const NOTICE_DURATION: Duration = Duration::from_secs(2);
+
+/// How often GDB and `devola-asm` are checked on while paused
+const GDB_POLL_INTERVAL: Duration = Duration::from_millis(10);
 
 pub struct Application {
     window: Option<Window>,
-    render_context: Option<RenderContext>
+    render_context: Option<RenderContext>,
+    console: Console,
+    /// The second console in link-cable mode
+    link: Option<Link>,
+    /// Where the session's inputs are saved on exit, if they're being recorded
+    recording_path: Option<String>,
+    /// Whether the state at the end of each frame is being recorded or checked
+    audit: AuditMode,
+    /// Where the state digests of each frame are saved on exit, if they're being recorded
+    audit_path: Option<String>,
+    /// The running program's file, which its save states are kept under
+    program: Option<PathBuf>,
+    /// The save state slot the save and load keys use
+    slot: usize,
+    /// A message shown over the frame, and when it was shown
+    notice: Option<(String, Instant)>,
+    /// States to step back through while rewinding
+    rewind: RewindBuffer,
+    /// Whether the rewind key is held
+    rewinding: bool,
+    /// Frames shown since rewinding last stepped back
+    rewind_frames: u64,
+    paused: bool,
+    /// Whether none of the windows have focus
+    background: bool,
+    pacer: FramePacer,
+    /// Paces frames while throttled in the background
+    background_pacer: FramePacer,
+    sync_mode: SyncMode,
+    turbo: bool,
+    speed_counter: SpeedCounter,
+    show_overlay: bool,
+    debug_window: Option<DebugWindow>,
+    /// Where GDB can attach, if enabled
+    gdb: Option<GdbServer>,
+    /// Where re-assembled programs can be sent, if enabled
+    reload: Option<ReloadServer>,
+    settings: Settings,
+    /// Where the settings are saved when they change, if they were loaded from a file
+    settings_path: Option<String>,
+    input_map: InputMap,
+    /// Where the APU's samples are played, once the window is up
+    audio: Option<AudioOutput>,
+    /// Connected controllers, once the window is up
+    gamepads: Option<Gamepads>,
+    /// The capture being written, if one is
+    capture: Option<Capture>
 }
 
 impl Application {
-    pub fn new() -> Self {
+    pub fn new(devola: Devola) -> Self {
         Self {
             window: None,
-            render_context: None
+            render_context: None,
+            console: Console::new(devola),
+            link: None,
+            recording_path: None,
+            audit: AuditMode::Off,
+            audit_path: None,
+            program: None,
+            slot: 0,
+            notice: None,
+            rewind: RewindBuffer::new(REWIND_STATES),
+            rewinding: false,
+            rewind_frames: 0,
+            paused: false,
+            background: false,
+            pacer: FramePacer::new(FRAME_RATE, Instant::now()),
+            background_pacer: FramePacer::new(Settings::default().background_frame_rate, Instant::now()),
+            sync_mode: SyncMode::default(),
+            turbo: false,
+            speed_counter: SpeedCounter::new(Instant::now()),
+            show_overlay: false,
+            debug_window: None,
+            gdb: None,
+            reload: None,
+            settings: Settings::default(),
+            settings_path: None,
+            input_map: InputMap::default(),
+            audio: None,
+            gamepads: None,
+            capture: None
+        }
+    }
+
+    /// Plugs a transport into the serial port
+    pub fn connect_serial(&mut self, transport: Box<dyn Transport>) {
+        self.console.serial = Serial::new(Some(transport));
+    }
+
+    /// Plugs in the text console, which shows what the program writes to `TEXT_OUT`
+    pub fn plug_in_text(&mut self) {
+        self.console.text = Some(TextConsole::default());
+    }
+
+    /// Runs a second console alongside the first, with their serial ports cabled together
+    pub fn link(&mut self, devola: Devola) {
+        let (link, transport) = Link::new(devola);
+        self.connect_serial(transport);
+        self.link = Some(link);
+    }
+
+    /// Records the inputs of each frame from here on, saving them to `path` on exit
+    pub fn record(&mut self, path: &str) {
+        let console = &self.console;
+        let replay = Replay::new(console.devola.save_state(), console.apu.to_bytes(), console.serial.to_bytes());
+        self.console.replay = ReplayMode::Recording(replay);
+        self.recording_path = Some(path.to_string());
+    }
+
+    /// Restores the state a replay starts from, and plays its inputs back in place of live ones
+    pub fn play(&mut self, replay: Replay) -> Result<(), StateMismatch> {
+        self.console.devola.load_state(&replay.initial_state)?;
+        self.console.apu = Apu::from_bytes(&replay.apu).unwrap_or_default();
+        self.console.serial.restore(&replay.serial);
+        self.console.replay = ReplayMode::Playing { replay, frame: 0 };
+        Ok(())
+    }
+
+    /// Records a digest of the state at the end of each frame from here on, saving them to `path` on exit
+    pub fn record_audit(&mut self, path: &str) {
+        self.audit = AuditMode::Recording(AuditLog::default());
+        self.audit_path = Some(path.to_string());
+    }
+
+    /// Checks the state at the end of each frame against a recorded audit, stopping at the first that differs
+    pub fn check_audit(&mut self, log: AuditLog) {
+        self.audit = AuditMode::Checking { log, frame: 0 };
+    }
+
+    /// Applies settings, saving them back to `path` whenever they change, if there's somewhere to save them
+    pub fn use_settings(&mut self, settings: Settings, path: Option<&str>) {
+        self.settings = settings;
+        self.settings_path = path.map(String::from);
+        self.input_map = self.settings.input_map();
+        self.pacer.audio_target = Duration::from_millis(self.settings.audio_latency);
+        self.background_pacer = FramePacer::new(self.settings.background_frame_rate.max(1), Instant::now());
+    }
+
+    fn save_settings(&self) {
+        if let Some(path) = self.settings_path.as_ref() {
+            self.settings.save(path);
+        }
+    }
+
+    /// Keeps the window's size and whether it's fullscreen, for the next time it's opened
+    fn remember_window(&mut self) {
+        let window = match self.window.as_ref() {
+            Some(window) => window,
+            None => return
+        };
+        let fullscreen = window.fullscreen().is_some();
+        let scale = if fullscreen {
+            self.settings.scale
+        } else {
+            let size: LogicalSize<u32> = window.inner_size().to_logical(window.scale_factor());
+            Some((size.width / SCREEN_WIDTH).min(size.height / SCREEN_HEIGHT).max(1))
+        };
+        if (scale, fullscreen) != (self.settings.scale, self.settings.fullscreen) {
+            self.settings.scale = scale;
+            self.settings.fullscreen = fullscreen;
+            self.save_settings();
+        }
+    }
+
+    /// Adds a program to the recently opened list, and keeps its save states with it from here on
+    pub fn remember(&mut self, path: &Path) {
+        self.program = Some(path.to_path_buf());
+        self.settings.add_recent(path);
+        self.save_settings();
+    }
+
+    /// Lets GDB attach to the console through `server`
+    pub fn listen_gdb(&mut self, server: GdbServer) {
+        self.gdb = Some(server);
+    }
+
+    /// Swaps in the programs `devola-asm` sends to `server`
+    pub fn listen_reload(&mut self, server: ReloadServer) {
+        self.reload = Some(server);
+    }
+
+    /// Runs the VM for at least `cycles` cycles. A linked console is kept in lockstep, catching up
+    /// every `LINK_SLICE_CYCLES`. While GDB has breakpoints set, instructions run one at a time so that
+    /// the console can pause at them; returns false if it did.
+    fn run_cycles(&mut self, cycles: u64) -> bool {
+        let slice = if self.link.is_some() { LINK_SLICE_CYCLES } else { cycles };
+        let target = self.console.elapsed + cycles;
+        while self.console.elapsed < target {
+            // once the VM has stopped executing, it idles as usual
+            if self.gdb.as_ref().is_some_and(GdbServer::has_breakpoints) && self.step_instruction() > 0 {
+                if let Some(gdb) = self.gdb.as_mut().filter(|gdb| gdb.is_breakpoint(self.console.devola.pc())) {
+                    gdb.stopped(SIGTRAP);
+                    self.paused = true;
+                    return false;
+                }
+                continue;
+            }
+            let vram = self.render_context.as_mut().map(|render_context| &mut render_context.vrammodel);
+            let taken = self.console.run_cycles(slice.min(target - self.console.elapsed), vram);
+            self.speed_counter.add_cycles(taken);
+            if let Some(link) = self.link.as_mut() {
+                link.catch_up(self.console.elapsed);
+            }
+        }
+        true
+    }
+
+    /// Executes the next instruction on its own, returning the cycles it took
+    fn step_instruction(&mut self) -> u64 {
+        let vram = self.render_context.as_mut().map(|render_context| &mut render_context.vrammodel);
+        let taken = self.console.step_instruction(vram);
+        self.speed_counter.add_cycles(taken);
+        if let Some(link) = self.link.as_mut() {
+            link.catch_up(self.console.elapsed);
+        }
+        taken
+    }
+
+    /// Acts on anything GDB has sent since the last poll
+    fn poll_gdb(&mut self) {
+        let mut gdb = match self.gdb.take() {
+            Some(gdb) => gdb,
+            None => return
+        };
+        while let Some(event) = gdb.poll(&mut self.console.devola) {
+            match event {
+                GdbEvent::Attached => {
+                    tracing::info!("GDB attached");
+                    self.paused = true;
+                },
+                GdbEvent::Step => {
+                    self.step_instruction();
+                    gdb.stopped(SIGTRAP);
+                },
+                GdbEvent::Continue => self.paused = false,
+                GdbEvent::Interrupt => {
+                    self.paused = true;
+                    gdb.stopped(SIGINT);
+                },
+                GdbEvent::Detached => {
+                    tracing::info!("GDB detached");
+                    self.paused = false;
+                }
+            }
+            if let Some(window) = self.window.as_ref() {
+                window.request_redraw();
+            }
+        }
+        self.gdb = Some(gdb);
+    }
+
+    /// Starts recordings over from the current state, and stops a replay being played or an audit being
+    /// checked, as neither would match the session from here on
+    fn restart_recordings(&mut self) {
+        if let Some(recording_path) = self.recording_path.clone() {
+            self.record(&recording_path);
+        } else {
+            self.console.replay = ReplayMode::Off;
+        }
+        match self.audit_path.clone() {
+            Some(audit_path) => self.record_audit(&audit_path),
+            None => self.audit = AuditMode::Off
+        }
+    }
+
+    /// Replaces the running program with the one at `path`, from the start
+    fn open(&mut self, path: &Path) {
+        match program::load(path) {
+            Ok(devola) => {
+                self.console.reset(devola);
+                self.rewind.clear();
+                self.restart_recordings();
+                tracing::info!("Loaded {}", path.display());
+                self.remember(path);
+            },
+            Err(errors) => tracing::error!("{}", errors)
+        }
+    }
+
+    /// Shows a message over the frame for a little while
+    fn notify(&mut self, message: String) {
+        self.notice = Some((message, Instant::now()));
+        if let Some(window) = self.window.as_ref() {
+            window.request_redraw();
+        }
+    }
+
+    /// Starts a capture of the running program, for as long as the settings say unless stopped sooner
+    fn start_capture(&mut self) {
+        let path = capture::capture_path(self.program.as_deref(), self.settings.capture_format());
+        match Capture::start(&path, self.settings.capture_seconds*FRAME_RATE) {
+            Ok(capture) => {
+                self.capture = Some(capture);
+                self.notify("CAPTURING".to_string());
+            },
+            Err(error) => {
+                tracing::error!("Failed to start capturing to {}: {}", path.display(), error);
+                self.notify("CAPTURE FAILED".to_string());
+            }
+        }
+    }
+
+    fn stop_capture(&mut self) {
+        match self.capture.take().map(Capture::finish) {
+            Some(Ok(path)) => {
+                tracing::info!("Saved the capture to {}", path.display());
+                self.notify("CAPTURE SAVED".to_string());
+            },
+            Some(Err(error)) => {
+                tracing::error!("Failed to save the capture: {}", error);
+                self.notify("CAPTURE FAILED".to_string());
+            },
+            None => ()
+        }
+    }
+
+    /// Adds the frame just run and its audio to the capture, if one is running, finishing it once it's long enough
+    fn capture_frame(&mut self, samples: &[f32]) {
+        let (Some(capture), Some(render_context)) = (self.capture.as_mut(), self.render_context.as_mut()) else {
+            return;
+        };
+        match capture.add_frame(&render_context.capture_frame(), samples) {
+            Ok(true) => (),
+            Ok(false) => self.stop_capture(),
+            Err(error) => {
+                tracing::error!("Failed to capture a frame: {}", error);
+                self.capture = None;
+                self.notify("CAPTURE FAILED".to_string());
+            }
+        }
+    }
+
+    /// Where the selected slot's state is kept for the running program
+    fn slot_path(&self) -> PathBuf {
+        savestate::slot_path(self.program.as_deref().unwrap_or(Path::new("")), self.slot)
+    }
+
+    /// Saves the console's state, and what's on the screen, in the selected slot
+    fn save_slot(&mut self) {
+        let path = self.slot_path();
+        let vram = self.render_context.as_ref().map(|render_context| &render_context.vrammodel);
+        match self.console.save_state(vram).save(&path) {
+            Ok(()) => self.notify(format!("SAVED SLOT {}", self.slot)),
+            Err(error) => {
+                tracing::error!("Failed to save state to {}: {}", path.display(), error);
+                self.notify(format!("SLOT {} NOT SAVED", self.slot));
+            }
+        }
+    }
+
+    /// Puts the console back in the state saved in the selected slot, if there is one. Recordings start over
+    /// from there.
+    fn load_slot(&mut self) {
+        let path = self.slot_path();
+        if !path.exists() {
+            self.notify(format!("SLOT {} EMPTY", self.slot));
+            return;
+        }
+        let vram = self.render_context.as_mut().map(|render_context| &mut render_context.vrammodel);
+        let loaded = SaveState::load(&path)
+            .and_then(|state| self.console.load_state(&state, vram).map_err(|error| error.to_string()));
+        match loaded {
+            Ok(()) => {
+                self.restart_recordings();
+                self.notify(format!("LOADED SLOT {}", self.slot));
+            },
+            Err(error) => {
+                tracing::error!("Failed to load state from {}: {}", path.display(), error);
+                self.notify(format!("SLOT {} NOT LOADED", self.slot));
+            }
+        }
+    }
+
+    /// Puts the console back in the latest state kept for rewinding, if there are any left. Recordings start
+    /// over from there.
+    fn step_back(&mut self) {
+        self.rewind_frames = 0;
+        if let Some(state) = self.rewind.pop() {
+            let vram = self.render_context.as_mut().map(|render_context| &mut render_context.vrammodel);
+            self.console.load_state(&state, vram).expect("rewind states are taken from the same console");
+            self.restart_recordings();
+        }
+    }
+
+    /// Swaps in any program that has arrived from `devola-asm`, which starts running even if the last one halted
+    fn poll_reload(&mut self) {
+        while let Some(message) = self.reload.as_mut().and_then(ReloadServer::poll) {
+            match message {
+                Ok((image, symbols)) => {
+                    self.console.halted = false;
+                    if self.console.devola.hot_swap(image, Some(symbols)) {
+                        tracing::info!("Swapped in the new program");
+                    } else {
+                        tracing::info!("The labels moved, so the new program is starting over");
+                        self.rewind.clear();
+                    }
+                },
+                Err(error) => tracing::warn!("Received an invalid program: {}", error)
+            }
+        }
+    }
+
+    /// Whether frames are being run: the console isn't paused, by the user or for being in the background
+    fn running(&self) -> bool {
+        let background_paused = self.background && self.settings.unfocused == Unfocused::Pause;
+        !self.paused && !background_paused
+    }
+
+    /// Whether frames are run slowly and silently, for being in the background
+    fn throttled(&self) -> bool {
+        self.background && self.settings.unfocused == Unfocused::Throttle
+    }
+
+    /// The pacer frames are currently run by
+    fn active_pacer(&self) -> &FramePacer {
+        if self.throttled() { &self.background_pacer } else { &self.pacer }
+    }
+
+    /// Runs the VM to the end of the current frame. Controllers are polled first, for the joypad register
+    /// to pick up as the next frame starts, and the frame's audio is queued once it's done. Every
+    /// `REWIND_INTERVAL` frames, a state is kept for rewinding.
+    fn run_frame(&mut self) {
+        let _frame = tracing::info_span!("frame", number = self.console.frame).entered();
+        if let Some(gamepads) = self.gamepads.as_mut() {
+            gamepads.poll(&self.input_map, &mut self.console.joypad);
+        }
+        // a breakpoint leaves the rest of the frame for when the console resumes
+        if self.console.frame_cycle < ACTIVE_CYCLES && !self.run_cycles(ACTIVE_CYCLES - self.console.frame_cycle) {
+            return;
+        }
+        // an instruction overshooting the end of the active period may also have ended the frame
+        if self.console.frame_cycle >= ACTIVE_CYCLES && !self.run_cycles(CYCLES_PER_FRAME - self.console.frame_cycle) {
+            return;
+        }
+        // the linked console has caught up by now, so it's audited along with the first
+        let link = self.link.as_ref().map(|link| &link.console);
+        self.audit.end_frame(|| StateDigest::new(&self.console, link));
+        // the samples are taken either way, so that they don't pile up while muted
+        let samples = self.console.apu.take_samples();
+        let muted = self.throttled();
+        if let Some(audio) = self.audio.as_mut().filter(|_| !muted) {
+            audio.push(&samples);
+        }
+        if let Some(render_context) = self.render_context.as_mut() {
+            render_context.text = self.console.text.as_ref().map_or_else(Vec::new, |text| text.rows().to_vec());
+        }
+        self.capture_frame(&samples);
+        if self.console.frame.is_multiple_of(REWIND_INTERVAL) {
+            let vram = self.render_context.as_ref().map(|render_context| &render_context.vrammodel);
+            self.rewind.push(self.console.save_state(vram));
+        }
+    }
+
+    /// Lines shown over the frame: the speed counter if enabled, the VM's position while paused, and the
+    /// latest message for a little while after it was shown
+    fn overlay_lines(&self) -> Vec<String> {
+        let mut lines = if self.show_overlay { self.speed_counter.lines() } else { Vec::new() };
+        if self.show_overlay && self.sync_mode == SyncMode::Audio {
+            lines.push("AUDIO SYNC".to_string());
+        }
+        if self.show_overlay && self.console.serial.connected() {
+            lines.push("SERIAL LINKED".to_string());
+        }
+        if self.show_overlay && self.gdb.as_ref().is_some_and(GdbServer::connected) {
+            lines.push("GDB ATTACHED".to_string());
+        }
+        if self.paused {
+            lines.push("PAUSED".to_string());
+            lines.push(format!("PC {}", self.console.devola.pc()));
+            lines.push(format!("CYCLE {}", self.console.frame_cycle));
+        }
+        if self.rewinding {
+            lines.push("REWINDING".to_string());
+        }
+        if self.capture.is_some() {
+            lines.push("REC".to_string());
+        }
+        if let Some((message, _)) = self.notice.as_ref().filter(|(_, shown)| shown.elapsed() < NOTICE_DURATION) {
+            lines.push(message.clone());
+        }
+        lines
+    }
+
+    /// Picks a starting scale for the window: the one in the settings, or else one that takes up at most about
+    /// half of the primary monitor
+    fn initial_scale(&self, event_loop: &ActiveEventLoop) -> u32 {
+        if let Some(scale) = self.settings.scale {
+            return scale.max(1);
+        }
+        match event_loop.primary_monitor() {
+            Some(monitor) => {
+                let monitor_size: LogicalSize<u32> = monitor.size().to_logical(monitor.scale_factor());
+                let scale = (monitor_size.width / SCREEN_WIDTH).min(monitor_size.height / SCREEN_HEIGHT);
+                (scale / 2).max(1)
+            },
+            None => 1
+        }
+    }
+
+    /// Runs the frames that are due before the next presentation. In turbo mode, frames are run back to
+    /// back for up to a frame's worth of time, and only the last of them is presented. When syncing to
+    /// audio, the timer only wakes emulation up, and as many frames run as the audio queue needs, if any.
+    /// While rewinding, frames step back through the states kept instead of running.
+    fn run_due_frames(&mut self) {
+        let now = Instant::now();
+        if !self.running() {
+            return;
+        }
+        if self.rewinding {
+            if self.pacer.ready(now) {
+                self.rewind_frames += 1;
+                if self.rewind_frames == REWIND_STEP_FRAMES {
+                    self.step_back();
+                }
+                self.pacer.advance(now);
+            }
+        } else if self.throttled() {
+            if self.background_pacer.ready(now) {
+                self.run_frame();
+                self.background_pacer.advance(now);
+            }
+        } else if self.turbo {
+            while now.elapsed() < self.pacer.frame_duration() {
+                self.run_frame();
+            }
+            self.pacer.advance(Instant::now());
+        } else if self.pacer.ready(now) {
+            let frames = match (self.sync_mode, self.audio.as_ref()) {
+                (SyncMode::Audio, Some(audio)) => self.pacer.audio_frames_due(audio.queued()),
+                _ => 1
+            };
+            for _ in 0..frames {
+                self.run_frame();
+            }
+            self.pacer.advance(now);
         }
     }
 
     fn create_window(&mut self, event_loop: &ActiveEventLoop) {
+        let scale = self.initial_scale(event_loop);
         let size = LogicalSize::new(SCREEN_WIDTH, SCREEN_HEIGHT);
-        let scaled_size = LogicalSize::new(VIEW_SCALE*SCREEN_WIDTH, VIEW_SCALE*SCREEN_HEIGHT);
+        let scaled_size = LogicalSize::new(scale*SCREEN_WIDTH, scale*SCREEN_HEIGHT);
 
         let window_attributes = Window::default_attributes()
             .with_title("Popola")
             .with_inner_size(scaled_size)
             .with_min_inner_size(size)
-            .with_resizable(false);
+            .with_resizable(true)
+            .with_fullscreen(self.settings.fullscreen.then_some(Fullscreen::Borderless(None)));
 
         let window = event_loop.create_window(window_attributes).unwrap();
 
@@ -55,27 +655,47 @@ impl ApplicationHandler for Application {
 
                 let pixels = Pixels::new(SCREEN_WIDTH, SCREEN_HEIGHT, surface_texture).unwrap();
 
-                let mut render_context = RenderContext::new(pixels);
-
-                render_context.vrammodel.enable_sprite(0);
-                render_context.vrammodel.enable_sprite(1);
-                render_context.vrammodel.enable_sprite(2);
-                render_context.vrammodel.enable_sprite(3);
-                render_context.vrammodel.palettes[0].colors[0] = Color { r: 0, g: 0, b: 255 };
-
-                render_context.vrammodel.sprites[1].location = (128, 0);
-                render_context.vrammodel.sprites[1].properties.size = SpriteSize::X16;
-                render_context.vrammodel.sprites[2].location = (0, 128);
-                render_context.vrammodel.sprites[2].properties.size = SpriteSize::X32;
-                render_context.vrammodel.sprites[3].location = (128, 128);
-                render_context.vrammodel.sprites[3].properties.size = SpriteSize::X64;
-
-                self.render_context = Some(render_context);
+                self.render_context = Some(RenderContext::new(pixels));
+                let scale = self.initial_scale(event_loop);
+                if let Some(link) = self.link.as_mut() {
+                    link.create_window(event_loop, scale);
+                }
+                self.gamepads = Gamepads::new();
+                self.audio = AudioOutput::new();
             }
         }
     }
 
-    fn window_event(&mut self, event_loop: &ActiveEventLoop, _window_id: WindowId, event: WindowEvent) {
+    fn window_event(&mut self, event_loop: &ActiveEventLoop, window_id: WindowId, event: WindowEvent) {
+        // moving between windows loses focus from one before the next gains it
+        if let WindowEvent::Focused(focused) = event {
+            self.background = !focused;
+        }
+        if self.debug_window.as_ref().is_some_and(|debug_window| debug_window.id() == window_id) {
+            if let (Some(debug_window), Some(render_context)) = (self.debug_window.as_mut(), self.render_context.as_ref()) {
+                let bindings = self.input_map.clone();
+                if !debug_window.window_event(&render_context.vrammodel, &mut self.input_map, event) {
+                    self.debug_window = None;
+                }
+                if self.input_map != bindings {
+                    self.settings.bindings = self.input_map.to_bindings();
+                    self.save_settings();
+                }
+            }
+            return;
+        }
+        if let Some(link) = self.link.as_mut().filter(|link| link.id() == Some(window_id)) {
+            // closing the link window unplugs the cable
+            if !link.window_event(&self.input_map, event) {
+                self.link = None;
+            }
+            return;
+        }
+
+        if let WindowEvent::RedrawRequested = event {
+            self.run_due_frames();
+        }
+
         let window = match self.window.as_ref() {
             Some(window) => window,
             None => return
@@ -87,12 +707,310 @@ impl ApplicationHandler for Application {
 
         match event {
             WindowEvent::CloseRequested  => event_loop.exit(),
+            WindowEvent::Resized(size)   => render_context.resize(size.width, size.height),
+            WindowEvent::DroppedFile(path) => {
+                self.open(&path);
+                self.window.as_ref().unwrap().request_redraw();
+            },
+            WindowEvent::KeyboardInput { event, .. } if event.physical_key == PhysicalKey::Code(TURBO_KEY) => {
+                self.turbo = event.state.is_pressed();
+                window.request_redraw();
+            },
+            // the first step back is straight away, so that tapping the key steps back once even while paused
+            WindowEvent::KeyboardInput { event, .. } if event.physical_key == PhysicalKey::Code(REWIND_KEY) => {
+                self.rewinding = event.state.is_pressed();
+                if self.rewinding && !event.repeat {
+                    self.step_back();
+                }
+                self.window.as_ref().unwrap().request_redraw();
+            },
+            WindowEvent::KeyboardInput { event, .. } if event.physical_key == PhysicalKey::Code(CRT_KEY)
+                && event.state.is_pressed() && !event.repeat => {
+                render_context.crt_enabled = !render_context.crt_enabled;
+            },
+            WindowEvent::KeyboardInput { event, .. } if event.physical_key == PhysicalKey::Code(SYNC_KEY)
+                && event.state.is_pressed() && !event.repeat => {
+                self.sync_mode = self.sync_mode.toggled();
+            },
+            WindowEvent::KeyboardInput { event, .. } if event.physical_key == PhysicalKey::Code(OVERLAY_KEY)
+                && event.state.is_pressed() && !event.repeat => {
+                self.show_overlay = !self.show_overlay;
+            },
+            WindowEvent::KeyboardInput { event, .. } if event.physical_key == PhysicalKey::Code(DEBUGGER_KEY)
+                && event.state.is_pressed() && !event.repeat => {
+                self.debug_window = match self.debug_window.take() {
+                    Some(_) => None,
+                    None => DebugWindow::new(event_loop, self.settings.debugger)
+                        .inspect_err(|error| tracing::error!("Failed to open the debugger: {}", error))
+                        .ok()
+                };
+            },
+            WindowEvent::KeyboardInput { event, .. } if event.physical_key == PhysicalKey::Code(SPRITE_BOXES_KEY)
+                && event.state.is_pressed() && !event.repeat => {
+                let overlays = &mut render_context.debug_overlays;
+                overlays.sprite_boxes = !overlays.sprite_boxes;
+                window.request_redraw();
+            },
+            WindowEvent::KeyboardInput { event, .. } if event.physical_key == PhysicalKey::Code(TILE_GRID_KEY)
+                && event.state.is_pressed() && !event.repeat => {
+                render_context.debug_overlays.cycle_tile_grid();
+                window.request_redraw();
+            },
+            WindowEvent::KeyboardInput { event, .. } if event.physical_key == PhysicalKey::Code(PAUSE_KEY)
+                && event.state.is_pressed() && !event.repeat => {
+                self.paused = !self.paused;
+                window.request_redraw();
+            },
+            // stepping keys repeat while held
+            WindowEvent::KeyboardInput { event, .. } if event.physical_key == PhysicalKey::Code(FRAME_STEP_KEY)
+                && event.state.is_pressed() => {
+                self.paused = true;
+                self.run_frame();
+                self.window.as_ref().unwrap().request_redraw();
+            },
+            WindowEvent::KeyboardInput { event, .. } if event.physical_key == PhysicalKey::Code(INSTRUCTION_STEP_KEY)
+                && event.state.is_pressed() => {
+                self.paused = true;
+                self.step_instruction();
+                self.window.as_ref().unwrap().request_redraw();
+            },
+            WindowEvent::KeyboardInput { event, .. } if event.physical_key == PhysicalKey::Code(UNFOCUSED_KEY)
+                && event.state.is_pressed() && !event.repeat => {
+                self.settings.unfocused = self.settings.unfocused.next();
+                tracing::info!("In the background: {:?}", self.settings.unfocused);
+                self.save_settings();
+            },
+            WindowEvent::KeyboardInput { event, .. } if event.physical_key == PhysicalKey::Code(SAVE_STATE_KEY)
+                && event.state.is_pressed() && !event.repeat => {
+                self.save_slot();
+            },
+            WindowEvent::KeyboardInput { event, .. } if event.physical_key == PhysicalKey::Code(LOAD_STATE_KEY)
+                && event.state.is_pressed() && !event.repeat => {
+                self.load_slot();
+            },
+            WindowEvent::KeyboardInput { event, .. } if event.physical_key == PhysicalKey::Code(CAPTURE_KEY)
+                && event.state.is_pressed() && !event.repeat => {
+                if self.capture.is_some() {
+                    self.stop_capture();
+                } else {
+                    self.start_capture();
+                }
+            },
+            WindowEvent::KeyboardInput { event, .. } if event.state.is_pressed() && !event.repeat
+                && SLOT_KEYS.iter().any(|&key| event.physical_key == PhysicalKey::Code(key)
+                    && self.input_map.button(Input::Key(key)).is_none()) => {
+                self.slot = SLOT_KEYS.iter().position(|&key| event.physical_key == PhysicalKey::Code(key)).unwrap();
+                self.notify(format!("SLOT {}", self.slot));
+            },
+            WindowEvent::KeyboardInput { event, .. } => {
+                if let PhysicalKey::Code(key) = event.physical_key {
+                    if let Some(button) = self.input_map.button(Input::Key(key)) {
+                        self.console.joypad.set_button(button, event.state.is_pressed());
+                    }
+                }
+            },
             WindowEvent::RedrawRequested => {
+                self.speed_counter.add_frame(Instant::now(), self.console.devola.instructions());
+                let overlay = self.overlay_lines();
+                let render_context = self.render_context.as_mut().unwrap();
+                render_context.overlay = overlay;
                 render_context.render();
-                window.request_redraw();
+                if let Some(debug_window) = self.debug_window.as_ref() {
+                    debug_window.request_redraw();
+                }
+                if let Some(link) = self.link.as_ref() {
+                    link.request_redraw();
+                }
             },
             _ => ()
         }
 
     }
-}
\ No newline at end of file
+
+    fn exiting(&mut self, _event_loop: &ActiveEventLoop) {
+        self.stop_capture();
+        self.remember_window();
+        if let (Some(path), ReplayMode::Recording(replay)) = (self.recording_path.as_ref(), &self.console.replay) {
+            replay.save(path);
+        }
+        if let (Some(path), AuditMode::Recording(log)) = (self.audit_path.as_ref(), &self.audit) {
+            log.save(path);
+        }
+    }
+
+    fn about_to_wait(&mut self, event_loop: &ActiveEventLoop) {
+        self.poll_gdb();
+        self.poll_reload();
+        // frames are requested as they come due, and the event loop sleeps in between
+        let turbo = self.turbo && !self.throttled();
+        if let Some(window) = self.window.as_ref() {
+            if self.running() && (turbo || self.active_pacer().ready(Instant::now())) {
+                window.request_redraw();
+            }
+        }
+        if !self.running() && (self.gdb.is_some() || self.reload.is_some()) {
+            // GDB's packets and new programs don't wake the event loop, so it has to keep checking for them
+            event_loop.set_control_flow(ControlFlow::WaitUntil(Instant::now() + GDB_POLL_INTERVAL));
+        } else if !self.running() {
+            event_loop.set_control_flow(ControlFlow::Wait);
+        } else if turbo {
+            event_loop.set_control_flow(ControlFlow::Poll);
+        } else {
+            event_loop.set_control_flow(ControlFlow::WaitUntil(self.active_pacer().next_frame()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use devola::instructions::Instruction;
+
+    #[test]
+    fn test_run_frame() {
+        let mut application = Application::new(Devola::new(vec![Instruction::Nop; 4], None));
+        application.step_instruction();
+        assert_eq!(application.console.frame_cycle, 1);
+
+        // the rest of the frame is run, however far into it the VM was
+        application.run_frame();
+        assert_eq!(application.console.frame_cycle, 0);
+        assert_eq!(application.console.elapsed, CYCLES_PER_FRAME);
+    }
+
+    #[test]
+    fn test_unfocused() {
+        let mut application = Application::new(Devola::new(vec![Instruction::Nop; 4], None));
+        application.background = true;
+        application.settings.unfocused = Unfocused::Pause;
+        application.run_due_frames();
+        assert_eq!(application.console.elapsed, 0);
+
+        // throttled frames come at the background frame rate instead, so only one is due straight away
+        application.settings.unfocused = Unfocused::Throttle;
+        application.run_due_frames();
+        application.run_due_frames();
+        assert_eq!(application.console.elapsed, CYCLES_PER_FRAME);
+    }
+
+    #[test]
+    fn test_rewind() {
+        let mut application = Application::new(Devola::new(vec![Instruction::Nop; 4], None));
+        for _ in 0..2*REWIND_INTERVAL + 10 {
+            application.run_frame();
+        }
+        // each step goes back to the last state kept before it
+        application.step_back();
+        assert_eq!((application.console.frame, application.console.frame_cycle), (2*REWIND_INTERVAL, 0));
+        application.step_back();
+        assert_eq!(application.console.frame, REWIND_INTERVAL);
+        assert_eq!(application.console.elapsed, REWIND_INTERVAL*CYCLES_PER_FRAME);
+
+        // stepping back with none left stays put
+        application.step_back();
+        assert_eq!(application.console.frame, REWIND_INTERVAL);
+    }
+
+    #[test]
+    fn test_replay() {
+        // keeps a running total of the joypad register
+        let code = "
+        loop:
+            lda #0F3Ah
+            add #0200h
+            sta #0200h
+            jmp loop".to_string();
+        let (code, symbols, _, _) = devola::parser::text::compile(code, None).unwrap();
+        let mut application = Application::new(Devola::new(code.clone(), Some(symbols.clone())));
+        application.record("unused");
+        for buttons in [JOYPAD_A, 0, JOYPAD_UP | JOYPAD_B] {
+            application.console.joypad.set_button(buttons, true);
+            application.run_frame();
+            application.console.joypad.set_button(buttons, false);
+        }
+        let replay = match &application.console.replay {
+            ReplayMode::Recording(replay) => replay.clone(),
+            _ => panic!("not recording")
+        };
+        assert_eq!(replay.frames, vec![JOYPAD_A, 0, JOYPAD_UP | JOYPAD_B]);
+
+        // playing the inputs back, without any held, ends up in the same state
+        let mut playback = Application::new(Devola::new(code, Some(symbols)));
+        playback.play(replay).unwrap();
+        for _ in 0..3 {
+            playback.run_frame();
+        }
+        assert_eq!(playback.console.devola.save_state(), application.console.devola.save_state());
+        assert_eq!(playback.console.apu.to_bytes(), application.console.apu.to_bytes());
+        assert_ne!(playback.console.devola.peek(0x200), 0);
+    }
+
+    #[test]
+    fn test_gdb() {
+        use std::io::Write;
+        use std::net::TcpStream;
+
+        let mut application = Application::new(Devola::new(vec![Instruction::Nop; 4], None));
+        application.listen_gdb(GdbServer::listen("127.0.0.1:0").unwrap());
+        let mut client = TcpStream::connect(application.gdb.as_ref().unwrap().address().unwrap()).unwrap();
+        application.poll_gdb();
+        assert!(application.paused);
+
+        // the console runs until it reaches the breakpoint, partway through the frame
+        client.write_all(&devola::gdb::frame("Z0,2,1")).unwrap();
+        client.write_all(&devola::gdb::frame("c")).unwrap();
+        for _ in 0..100 {
+            application.poll_gdb();
+            if !application.paused {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        assert!(!application.paused);
+        application.run_frame();
+        assert!(application.paused);
+        assert_eq!(application.console.devola.pc(), 2);
+        assert_eq!(application.console.frame_cycle, 2);
+    }
+
+    #[test]
+    fn test_link() {
+        let mut application = Application::new(Devola::new(Vec::new(), None));
+        application.link(Devola::new(vec![Instruction::Nop; 4], None));
+        assert!(application.console.serial.connected());
+
+        // the linked console is kept in step, including through single instructions
+        application.run_frame();
+        application.step_instruction();
+        let link = application.link.as_ref().unwrap();
+        assert_eq!(link.console.elapsed, application.console.elapsed);
+        assert_eq!(link.console.frame_cycle, application.console.frame_cycle);
+    }
+
+    #[test]
+    fn test_open() {
+        let mut application = Application::new(Devola::new(vec![Instruction::Nop; 4], None));
+        application.record("unused");
+        application.run_frame();
+        application.console.halted = true;
+
+        let path = std::env::temp_dir().join(format!("popola_open_test_{}.pop", std::process::id()));
+        std::fs::write(&path, "lda 7\nsta #0200h").unwrap();
+        application.open(&path);
+        assert!(!application.console.halted);
+        assert_eq!((application.console.devola.pc(), application.console.frame_cycle), (0, 0));
+        // the recording starts over from the new program
+        match &application.console.replay {
+            ReplayMode::Recording(replay) => assert_eq!(replay.initial_state, application.console.devola.save_state()),
+            _ => panic!("not recording")
+        }
+        application.run_frame();
+        assert_eq!(application.console.devola.peek(0x0200), 7);
+
+        // a program that can't be loaded leaves the running one alone
+        std::fs::write(&path, "lda").unwrap();
+        application.open(&path);
+        assert_eq!(application.console.devola.code().len(), 2);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,527 @@
+use crate::inter::mmio::*;
+
+use devola::utility::build_u16;
+use devola::vm::Devola;
+use std::collections::VecDeque;
+
+/// Each wave is split into 8 steps
+const WAVE_STEPS: u8 = 8;
+/// How many steps of each wave are high, for each duty setting
+const DUTY_STEPS: [u8; 4] = [1, 2, 4, 6];
+const CYCLES_PER_LENGTH_CLOCK: u64 = CPU_FREQUENCY/LENGTH_CLOCK_RATE;
+/// Samples are kept for the frontend up to a second's worth, after which the oldest are dropped
+const MAX_BUFFERED_SAMPLES: usize = AUDIO_SAMPLE_RATE as usize;
+const CHANNEL_COUNT: usize = SQUARE_COUNT + 2;
+
+/// Counts down how long a channel has left to play
+#[derive(Debug, Default, PartialEq, Copy, Clone)]
+pub struct LengthCounter {
+    /// Whether the channel stops when the count runs out
+    pub enabled: bool,
+    /// Length clocks left
+    pub remaining: u16
+}
+
+impl LengthCounter {
+    fn reload(&mut self, length: u8) {
+        self.remaining = if length == 0 { 256 } else { length as u16 };
+    }
+
+    /// Counts down a length clock. Returns false once the channel should stop.
+    fn clock(&mut self) -> bool {
+        if !self.enabled {
+            return true;
+        }
+        self.remaining -= 1;
+        self.remaining > 0
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Copy, Clone)]
+pub struct SquareChannel {
+    pub period: u16,
+    pub duty: u8,
+    pub volume: u8,
+    pub length: LengthCounter,
+    pub playing: bool,
+    /// Cycles left until the wave moves on to its next step
+    timer: u64,
+    step: u8
+}
+
+impl SquareChannel {
+    /// Reads the channel's registers, starting the channel if asked to
+    fn read_registers(&mut self, devola: &mut Devola, index: usize) {
+        let start = SQUARE_START + (index*SQUARE_SIZE) as u16;
+        let control = devola.peek(start + 1);
+        let envelope = devola.peek(start + 2);
+
+        self.period = build_u16(control & 0b111, devola.peek(start));
+        self.length.enabled = control & AUDIO_LENGTH_ENABLE_FLAG != 0;
+        self.duty = envelope >> 6;
+        self.volume = envelope & 0x0F;
+
+        if control & AUDIO_START_FLAG != 0 {
+            self.length.reload(devola.peek(start + 3));
+            self.playing = true;
+            self.step = 0;
+            self.timer = self.step_cycles();
+            devola.poke(start + 1, control & !AUDIO_START_FLAG);
+        }
+    }
+
+    fn step_cycles(&self) -> u64 {
+        // 8 steps per wave, making the wave 16*(period+1) cycles long
+        2*(self.period as u64 + 1)
+    }
+
+    fn advance(&mut self, cycles: u64) {
+        if !self.playing {
+            return;
+        }
+        let mut cycles = cycles;
+        while cycles >= self.timer {
+            cycles -= self.timer;
+            self.timer = self.step_cycles();
+            self.step = (self.step + 1) % WAVE_STEPS;
+        }
+        self.timer -= cycles;
+    }
+
+    fn clock_length(&mut self) {
+        if self.playing {
+            self.playing = self.length.clock();
+        }
+    }
+
+    /// The channel's current level, from -1 to 1
+    fn output(&self) -> f32 {
+        if !self.playing {
+            return 0.0;
+        }
+        let level = self.volume as f32 / 15.0;
+        if self.step < DUTY_STEPS[self.duty as usize] { level } else { -level }
+    }
+}
+
+/// Plays pseudo-random noise from a linear feedback shift register
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct NoiseChannel {
+    pub period: u8,
+    pub short: bool,
+    pub volume: u8,
+    /// Length clocks between each drop in volume, or 0 to keep it steady
+    pub decay: u8,
+    pub length: LengthCounter,
+    pub playing: bool,
+    timer: u64,
+    decay_timer: u8,
+    shift_register: u16
+}
+
+impl Default for NoiseChannel {
+    fn default() -> Self {
+        // a shift register of 0 would stay 0 forever
+        NoiseChannel {
+            period: 0, short: false, volume: 0, decay: 0, length: LengthCounter::default(), playing: false,
+            timer: 0, decay_timer: 0, shift_register: 1
+        }
+    }
+}
+
+impl NoiseChannel {
+    fn read_registers(&mut self, devola: &mut Devola) {
+        let period = devola.peek(NOISE_START);
+        let control = devola.peek(NOISE_START + 1);
+        let envelope = devola.peek(NOISE_START + 2);
+
+        self.period = period & 0x0F;
+        self.short = period & NOISE_SHORT_FLAG != 0;
+        self.length.enabled = control & AUDIO_LENGTH_ENABLE_FLAG != 0;
+        self.decay = envelope & 0b111;
+        // a decaying volume carries on from where it was started
+        if self.decay == 0 {
+            self.volume = envelope >> 4;
+        }
+
+        if control & AUDIO_START_FLAG != 0 {
+            self.length.reload(devola.peek(NOISE_START + 3));
+            self.volume = envelope >> 4;
+            self.decay_timer = self.decay;
+            self.playing = true;
+            self.timer = self.step_cycles();
+            devola.poke(NOISE_START + 1, control & !AUDIO_START_FLAG);
+        }
+    }
+
+    fn step_cycles(&self) -> u64 {
+        NOISE_PERIODS[self.period as usize] as u64
+    }
+
+    /// Shifts the register along by one step, feeding the XOR of its two lowest bits back in at the top
+    fn shift(&mut self) {
+        let feedback = (self.shift_register ^ (self.shift_register >> 1)) & 1;
+        self.shift_register = (self.shift_register >> 1) | (feedback << 14);
+        if self.short {
+            self.shift_register = (self.shift_register & !(1 << 6)) | (feedback << 6);
+        }
+    }
+
+    fn advance(&mut self, cycles: u64) {
+        if !self.playing {
+            return;
+        }
+        let mut cycles = cycles;
+        while cycles >= self.timer {
+            cycles -= self.timer;
+            self.timer = self.step_cycles();
+            self.shift();
+        }
+        self.timer -= cycles;
+    }
+
+    /// Counts down the length, and the volume if it's decaying
+    fn clock_length(&mut self) {
+        if !self.playing {
+            return;
+        }
+        self.playing = self.length.clock();
+        if self.decay > 0 {
+            // decay switched on mid-note, or by a loaded state, starts its count from the top
+            if self.decay_timer == 0 {
+                self.decay_timer = self.decay;
+            }
+            self.decay_timer -= 1;
+            if self.decay_timer == 0 {
+                self.decay_timer = self.decay;
+                self.volume = self.volume.saturating_sub(1);
+            }
+        }
+    }
+
+    fn output(&self) -> f32 {
+        if !self.playing {
+            return 0.0;
+        }
+        let level = self.volume as f32 / 15.0;
+        if self.shift_register & 1 == 0 { level } else { -level }
+    }
+}
+
+/// Plays back the samples of the wave table
+#[derive(Debug, Default, PartialEq, Copy, Clone)]
+pub struct WaveChannel {
+    pub period: u16,
+    pub volume: u8,
+    pub table: [u8; WAVE_LENGTH],
+    pub length: LengthCounter,
+    pub playing: bool,
+    timer: u64,
+    position: usize
+}
+
+impl WaveChannel {
+    fn read_registers(&mut self, devola: &mut Devola) {
+        let control = devola.peek(WAVE_START + 1);
+        self.period = build_u16(control & 0b111, devola.peek(WAVE_START));
+        self.length.enabled = control & AUDIO_LENGTH_ENABLE_FLAG != 0;
+        self.volume = devola.peek(WAVE_START + 2) & 0x0F;
+        for (index, pair) in self.table.chunks_exact_mut(2).enumerate() {
+            let byte = devola.peek(WAVE_TABLE + index as u16);
+            pair.copy_from_slice(&[byte >> 4, byte & 0x0F]);
+        }
+
+        if control & AUDIO_START_FLAG != 0 {
+            self.length.reload(devola.peek(WAVE_START + 3));
+            self.playing = true;
+            self.position = 0;
+            self.timer = self.step_cycles();
+            devola.poke(WAVE_START + 1, control & !AUDIO_START_FLAG);
+        }
+    }
+
+    fn step_cycles(&self) -> u64 {
+        self.period as u64 + 1
+    }
+
+    fn advance(&mut self, cycles: u64) {
+        if !self.playing {
+            return;
+        }
+        let mut cycles = cycles;
+        while cycles >= self.timer {
+            cycles -= self.timer;
+            self.timer = self.step_cycles();
+            self.position = (self.position + 1) % WAVE_LENGTH;
+        }
+        self.timer -= cycles;
+    }
+
+    fn clock_length(&mut self) {
+        if self.playing {
+            self.playing = self.length.clock();
+        }
+    }
+
+    fn output(&self) -> f32 {
+        if !self.playing {
+            return 0.0;
+        }
+        // samples are centred on the middle of their range
+        let sample = self.table[self.position] as f32 / 7.5 - 1.0;
+        sample*self.volume as f32 / 15.0
+    }
+}
+
+/// The audio processing unit: generates samples from the audio registers, in step with the cycles the VM runs
+pub struct Apu {
+    pub squares: [SquareChannel; SQUARE_COUNT],
+    pub noise: NoiseChannel,
+    pub wave: WaveChannel,
+    /// Cycles run since the last sample
+    sample_cycles: u64,
+    /// Cycles run since the length counters were last clocked
+    length_cycles: u64,
+    samples: VecDeque<f32>
+}
+
+impl Default for Apu {
+    fn default() -> Self {
+        Apu::new()
+    }
+}
+
+impl Apu {
+    pub fn new() -> Apu {
+        Apu {
+            squares: [SquareChannel::default(); SQUARE_COUNT],
+            noise: NoiseChannel::default(),
+            wave: WaveChannel::default(),
+            sample_cycles: 0,
+            length_cycles: 0,
+            samples: VecDeque::new()
+        }
+    }
+
+    /// Reads the audio registers, then runs the channels for `cycles` cycles, mixing a sample every
+    /// `CYCLES_PER_SAMPLE` cycles. The status register is updated afterwards.
+    pub fn run(&mut self, devola: &mut Devola, cycles: u64) {
+        for (index, square) in self.squares.iter_mut().enumerate() {
+            square.read_registers(devola, index);
+        }
+        self.noise.read_registers(devola);
+        self.wave.read_registers(devola);
+
+        let mut remaining = cycles;
+        while remaining > 0 {
+            // run up to the next sample or length clock, whichever comes first
+            let cycles = remaining
+                .min(CYCLES_PER_SAMPLE - self.sample_cycles)
+                .min(CYCLES_PER_LENGTH_CLOCK - self.length_cycles);
+            for square in self.squares.iter_mut() {
+                square.advance(cycles);
+            }
+            self.noise.advance(cycles);
+            self.wave.advance(cycles);
+            remaining -= cycles;
+            self.sample_cycles += cycles;
+            self.length_cycles += cycles;
+
+            if self.sample_cycles == CYCLES_PER_SAMPLE {
+                self.sample_cycles = 0;
+                if self.samples.len() == MAX_BUFFERED_SAMPLES {
+                    self.samples.pop_front();
+                }
+                self.samples.push_back(self.mix());
+            }
+            if self.length_cycles == CYCLES_PER_LENGTH_CLOCK {
+                self.length_cycles = 0;
+                for square in self.squares.iter_mut() {
+                    square.clock_length();
+                }
+                self.noise.clock_length();
+                self.wave.clock_length();
+            }
+        }
+
+        let status = self.squares.iter().enumerate().fold(0, |status, (index, square)| status | ((square.playing as u8) << index));
+        let status = status | ((self.noise.playing as u8) << SQUARE_COUNT) | ((self.wave.playing as u8) << (SQUARE_COUNT + 1));
+        devola.poke(AUDIO_STATUS, status);
+    }
+
+    /// Averages the channels into a sample from -1 to 1
+    fn mix(&self) -> f32 {
+        let squares: f32 = self.squares.iter().map(SquareChannel::output).sum();
+        (squares + self.noise.output() + self.wave.output()) / CHANNEL_COUNT as f32
+    }
+
+    /// Takes the samples generated so far, oldest first
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        self.samples.drain(..).collect()
+    }
+
+    /// The length of the progress written by `to_bytes`
+    pub const STATE_SIZE: usize = SQUARE_COUNT*12 + 15 + 12 + 8 + 8;
+
+    /// The channels' progress through their waves and lengths, for save states. Their settings aren't included,
+    /// since they're read from the audio registers again before any more cycles run, and neither are the samples.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(Self::STATE_SIZE);
+        for square in &self.squares {
+            bytes.push(square.playing as u8);
+            bytes.extend_from_slice(&square.length.remaining.to_le_bytes());
+            bytes.extend_from_slice(&square.timer.to_le_bytes());
+            bytes.push(square.step);
+        }
+        let noise = &self.noise;
+        bytes.push(noise.playing as u8);
+        bytes.extend_from_slice(&noise.length.remaining.to_le_bytes());
+        bytes.extend_from_slice(&noise.timer.to_le_bytes());
+        // a decaying volume isn't in the registers
+        bytes.extend_from_slice(&[noise.volume, noise.decay_timer]);
+        bytes.extend_from_slice(&noise.shift_register.to_le_bytes());
+        let wave = &self.wave;
+        bytes.push(wave.playing as u8);
+        bytes.extend_from_slice(&wave.length.remaining.to_le_bytes());
+        bytes.extend_from_slice(&wave.timer.to_le_bytes());
+        bytes.push(wave.position as u8);
+        bytes.extend_from_slice(&self.sample_cycles.to_le_bytes());
+        bytes.extend_from_slice(&self.length_cycles.to_le_bytes());
+        bytes
+    }
+
+    /// Reads progress written by `to_bytes`, or returns `None` if it's the wrong length
+    pub fn from_bytes(bytes: &[u8]) -> Option<Apu> {
+        if bytes.len() != Self::STATE_SIZE {
+            return None;
+        }
+        let mut rest = bytes;
+        let mut take = |count: usize| {
+            let (taken, remaining) = rest.split_at(count);
+            rest = remaining;
+            taken
+        };
+        let mut apu = Apu::new();
+        for square in apu.squares.iter_mut() {
+            square.playing = take(1)[0] != 0;
+            square.length.remaining = u16::from_le_bytes(take(2).try_into().ok()?);
+            square.timer = u64::from_le_bytes(take(8).try_into().ok()?);
+            square.step = take(1)[0] % WAVE_STEPS;
+        }
+        apu.noise.playing = take(1)[0] != 0;
+        apu.noise.length.remaining = u16::from_le_bytes(take(2).try_into().ok()?);
+        apu.noise.timer = u64::from_le_bytes(take(8).try_into().ok()?);
+        apu.noise.volume = take(1)[0];
+        apu.noise.decay_timer = take(1)[0];
+        apu.noise.shift_register = u16::from_le_bytes(take(2).try_into().ok()?);
+        apu.wave.playing = take(1)[0] != 0;
+        apu.wave.length.remaining = u16::from_le_bytes(take(2).try_into().ok()?);
+        apu.wave.timer = u64::from_le_bytes(take(8).try_into().ok()?);
+        apu.wave.position = take(1)[0] as usize % WAVE_LENGTH;
+        apu.sample_cycles = u64::from_le_bytes(take(8).try_into().ok()?) % CYCLES_PER_SAMPLE;
+        apu.length_cycles = u64::from_le_bytes(take(8).try_into().ok()?) % CYCLES_PER_LENGTH_CLOCK;
+        Some(apu)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_square() {
+        let mut devola = Devola::new(Vec::new(), None);
+        let mut apu = Apu::new();
+        // one step of the wave per sample, at half duty and full volume
+        let start = SQUARE_START + SQUARE_SIZE as u16;
+        devola.poke(start, 19);
+        devola.poke(start + 1, AUDIO_START_FLAG);
+        devola.poke(start + 2, 0x8F);
+
+        apu.run(&mut devola, 8*CYCLES_PER_SAMPLE);
+        let (high, low) = (1.0 / CHANNEL_COUNT as f32, -1.0 / CHANNEL_COUNT as f32);
+        assert_eq!(apu.take_samples(), vec![high, high, high, low, low, low, low, high]);
+        assert_eq!(devola.peek(start + 1), 0);
+        assert_eq!(devola.peek(AUDIO_STATUS), 0b10);
+        assert!(apu.take_samples().is_empty());
+
+        // without its length enabled, the channel keeps playing
+        apu.run(&mut devola, 2*CYCLES_PER_LENGTH_CLOCK);
+        assert!(apu.squares[1].playing);
+    }
+
+    #[test]
+    fn test_length() {
+        let mut devola = Devola::new(Vec::new(), None);
+        let mut apu = Apu::new();
+        devola.poke(SQUARE_START + 3, 2);
+        devola.poke(SQUARE_START + 1, AUDIO_START_FLAG | AUDIO_LENGTH_ENABLE_FLAG);
+
+        apu.run(&mut devola, CYCLES_PER_LENGTH_CLOCK);
+        assert_eq!(devola.peek(AUDIO_STATUS), 0b01);
+        apu.run(&mut devola, CYCLES_PER_LENGTH_CLOCK);
+        assert_eq!(devola.peek(AUDIO_STATUS), 0);
+        assert_eq!(apu.squares[0].output(), 0.0);
+    }
+
+    #[test]
+    fn test_noise() {
+        let mut devola = Devola::new(Vec::new(), None);
+        let mut apu = Apu::new();
+        // volume 12, dropping every 2 length clocks
+        devola.poke(NOISE_START + 1, AUDIO_START_FLAG);
+        devola.poke(NOISE_START + 2, 0xC2);
+
+        apu.run(&mut devola, 3*CYCLES_PER_LENGTH_CLOCK);
+        assert_eq!(devola.peek(AUDIO_STATUS), 0b100);
+        assert_eq!(apu.noise.volume, 11);
+        // the noise takes both levels
+        let samples = apu.take_samples();
+        assert!(samples.iter().any(|sample| *sample > 0.0) && samples.iter().any(|sample| *sample < 0.0));
+
+        // decay switched on mid-note counts down in full before the volume drops
+        let mut apu = Apu::new();
+        devola.poke(NOISE_START + 1, AUDIO_START_FLAG);
+        devola.poke(NOISE_START + 2, 0xC0);
+        apu.run(&mut devola, CYCLES_PER_LENGTH_CLOCK);
+        devola.poke(NOISE_START + 2, 0xC2);
+        apu.run(&mut devola, 2*CYCLES_PER_LENGTH_CLOCK);
+        assert_eq!(apu.noise.volume, 11);
+
+        // the long register only repeats after 32767 steps, the short one much sooner
+        let repeats_after = |noise: &mut NoiseChannel| {
+            let start = noise.shift_register;
+            (1..=32767).find(|_| { noise.shift(); noise.shift_register == start })
+        };
+        let mut noise = NoiseChannel::default();
+        assert_eq!(repeats_after(&mut noise), Some(32767));
+        noise.short = true;
+        // the short register settles into its loop after a few steps
+        for _ in 0..128 {
+            noise.shift();
+        }
+        assert!(repeats_after(&mut noise).unwrap() < 128);
+    }
+
+    #[test]
+    fn test_wave() {
+        let mut devola = Devola::new(Vec::new(), None);
+        let mut apu = Apu::new();
+        // a sawtooth, climbing one level every two samples of the table
+        for index in 0..WAVE_LENGTH/2 {
+            devola.poke(WAVE_TABLE + index as u16, (index as u8) << 4 | index as u8);
+        }
+        // one step through the table per output sample
+        devola.poke(WAVE_START, (CYCLES_PER_SAMPLE - 1) as u8);
+        devola.poke(WAVE_START + 1, AUDIO_START_FLAG);
+        devola.poke(WAVE_START + 2, 15);
+
+        apu.run(&mut devola, WAVE_LENGTH as u64*CYCLES_PER_SAMPLE);
+        assert_eq!(devola.peek(AUDIO_STATUS), 0b1000);
+        let samples = apu.take_samples();
+        // samples are taken after the step, so the first is of the table's second sample
+        let level = |sample: usize| (sample as f32 / 7.5 - 1.0) / CHANNEL_COUNT as f32;
+        let expected: Vec<f32> = (1..=WAVE_LENGTH).map(|step| level(step % WAVE_LENGTH / 2)).collect();
+        assert_eq!(samples, expected);
+    }
+}
@@ -0,0 +1,2 @@
+pub mod apu;
+pub mod output;
@@ -0,0 +1,161 @@
+use crate::inter::mmio::*;
+
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use cpal::{SampleFormat, Stream};
+use rtrb::{Consumer, Producer, RingBuffer};
+use std::time::Duration;
+
+/// How much resampled audio can be queued for the device, in seconds
+const BUFFER_SECONDS: f32 = 0.25;
+
+/// Converts the APU's samples to the device's rate, interpolating linearly between neighbouring samples
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct Resampler {
+    /// Input samples per output sample
+    step: f64,
+    /// Where the next output sample falls, in input samples after `previous`
+    position: f64,
+    previous: f32
+}
+
+impl Resampler {
+    pub fn new(input_rate: u32, output_rate: u32) -> Resampler {
+        Resampler { step: input_rate as f64 / output_rate as f64, position: 0.0, previous: 0.0 }
+    }
+
+    pub fn process(&mut self, input: &[f32], output: &mut Vec<f32>) {
+        for &sample in input {
+            while self.position < 1.0 {
+                output.push(self.previous + (sample - self.previous)*self.position as f32);
+                self.position += self.step;
+            }
+            self.position -= 1.0;
+            self.previous = sample;
+        }
+    }
+}
+
+/// Fills a buffer of interleaved frames from the queue, each sample going to every channel. If the queue runs
+/// dry, the last sample is held rather than dropping to silence, so that an underrun doesn't click.
+fn fill(consumer: &mut Consumer<f32>, data: &mut [f32], channels: usize, last: &mut f32) {
+    for frame in data.chunks_mut(channels) {
+        if let Ok(sample) = consumer.pop() {
+            *last = sample;
+        }
+        frame.fill(*last);
+    }
+}
+
+/// Plays the APU's output on the default audio device
+pub struct AudioOutput {
+    // kept alive for as long as audio should play
+    _stream: Stream,
+    producer: Producer<f32>,
+    sample_rate: u32,
+    resampler: Resampler,
+    resampled: Vec<f32>
+}
+
+impl AudioOutput {
+    /// Opens the default output device, or returns `None` if there isn't one that takes float samples
+    pub fn new() -> Option<AudioOutput> {
+        let device = match cpal::default_host().default_output_device() {
+            Some(device) => device,
+            None => {
+                tracing::warn!("Audio unavailable: no output device");
+                return None;
+            }
+        };
+        let supported = match device.default_output_config() {
+            Ok(supported) => supported,
+            Err(error) => {
+                tracing::warn!("Audio unavailable: {}", error);
+                return None;
+            }
+        };
+        if supported.sample_format() != SampleFormat::F32 {
+            tracing::warn!("Audio unavailable: unsupported sample format {}", supported.sample_format());
+            return None;
+        }
+        let config = supported.config();
+        let channels = config.channels as usize;
+
+        let (producer, mut consumer) = RingBuffer::new((config.sample_rate.0 as f32*BUFFER_SECONDS) as usize);
+        let mut last = 0.0;
+        let stream = device.build_output_stream(
+            &config,
+            move |data: &mut [f32], _| fill(&mut consumer, data, channels, &mut last),
+            |error| tracing::error!("Audio stream error: {}", error),
+            None
+        );
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(error) => {
+                tracing::warn!("Audio unavailable: {}", error);
+                return None;
+            }
+        };
+        if let Err(error) = stream.play() {
+            tracing::warn!("Audio unavailable: {}", error);
+            return None;
+        }
+
+        Some(AudioOutput {
+            _stream: stream,
+            producer,
+            sample_rate: config.sample_rate.0,
+            resampler: Resampler::new(AUDIO_SAMPLE_RATE as u32, config.sample_rate.0),
+            resampled: Vec::new()
+        })
+    }
+
+    /// How long the audio waiting to be played will last
+    pub fn queued(&self) -> Duration {
+        let queued = self.producer.buffer().capacity() - self.producer.slots();
+        Duration::from_secs_f64(queued as f64 / self.sample_rate as f64)
+    }
+
+    /// Queues samples from the APU. Samples that don't fit are dropped, which only happens when
+    /// emulation runs ahead of the device, as in turbo mode.
+    pub fn push(&mut self, samples: &[f32]) {
+        self.resampled.clear();
+        self.resampler.process(samples, &mut self.resampled);
+        for &sample in &self.resampled {
+            if self.producer.push(sample).is_err() {
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resampler() {
+        let mut output = Vec::new();
+        // halving the rate keeps every other sample
+        Resampler::new(2, 1).process(&[1.0, 2.0, 3.0, 4.0, 5.0], &mut output);
+        assert_eq!(output, vec![0.0, 2.0, 4.0]);
+
+        // doubling it adds a sample halfway between each, carried across calls
+        let mut resampler = Resampler::new(1, 2);
+        output.clear();
+        resampler.process(&[1.0], &mut output);
+        resampler.process(&[3.0], &mut output);
+        assert_eq!(output, vec![0.0, 0.5, 1.0, 2.0]);
+    }
+
+    #[test]
+    fn test_fill() {
+        let (mut producer, mut consumer) = RingBuffer::new(4);
+        producer.push(0.5).unwrap();
+        producer.push(-0.5).unwrap();
+        let mut data = [1.0; 6];
+        let mut last = 0.0;
+        fill(&mut consumer, &mut data, 2, &mut last);
+        // each sample goes to both channels, and the last is held once the queue is empty
+        assert_eq!(data, [0.5, 0.5, -0.5, -0.5, -0.5, -0.5]);
+    }
+}
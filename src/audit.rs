@@ -0,0 +1,266 @@
+//! Determinism audits: a digest of the console's state at the end of every frame, recorded in one run and checked
+//! against in another, to find the first frame where the two runs went their separate ways.
+use crate::console::Console;
+
+use devola::instructions::{Flag, Register};
+use devola::vm::MEMORY_SIZE;
+use std::fs;
+
+/// Identifies audit files, followed by the format version
+const AUDIT_MAGIC: &[u8; 4] = b"PAUD";
+const AUDIT_VERSION: u8 = 2;
+const HEADER_SIZE: usize = AUDIT_MAGIC.len() + 1;
+
+/// Memory is hashed in pages of this many bytes, so that a divergence can be narrowed down to the pages that differ
+const PAGE_SIZE: usize = 1024;
+const PAGE_COUNT: usize = MEMORY_SIZE / PAGE_SIZE;
+
+const REGISTERS: [(Register, &str); 5] = [
+    (Register::Accumulator, "A"), (Register::UtilityB, "B"), (Register::UtilityC, "C"),
+    (Register::IndexX, "X"), (Register::IndexY, "Y")
+];
+/// Packed as `0b0000SPZC`, as the VM keeps them
+const FLAGS: [Flag; 4] = [Flag::Carry, Flag::Zero, Flag::Parity, Flag::Sign];
+
+/// 64-bit FNV-1a, which unlike the standard library's hasher gives the same hashes from one build to the next
+pub fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3))
+}
+
+/// Everything about a console's state that's compared between runs, with memory boiled down to a hash per page
+#[derive(Clone, Debug, PartialEq)]
+pub struct StateDigest {
+    pub pc: usize,
+    pub cycles: u64,
+    pub registers: [u8; 5],
+    pub flags: u8,
+    pub in_interrupt: bool,
+    /// A hash of the APU's and the serial port's state
+    pub devices: u64,
+    /// The hash of the linked console's digest, or 0 without one
+    pub link: u64,
+    pub pages: [u64; PAGE_COUNT]
+}
+
+impl StateDigest {
+    /// The length of a digest written by `to_bytes`
+    const SIZE: usize = 8 + 8 + 5 + 1 + 1 + 8 + 8 + 8 * PAGE_COUNT;
+
+    pub fn new(console: &Console, link: Option<&Console>) -> StateDigest {
+        let devola = &console.devola;
+        let mut page = [0; PAGE_SIZE];
+        let pages = std::array::from_fn(|index| {
+            for (offset, byte) in page.iter_mut().enumerate() {
+                *byte = devola.peek((index * PAGE_SIZE + offset) as u16);
+            }
+            fnv1a(&page)
+        });
+        StateDigest {
+            pc: devola.pc(),
+            cycles: devola.cycles(),
+            registers: REGISTERS.map(|(register, _)| devola.register(register)),
+            flags: FLAGS.iter().enumerate().fold(0, |flags, (bit, &flag)| flags | (devola.flag(flag) as u8) << bit),
+            in_interrupt: devola.in_interrupt(),
+            devices: fnv1a(&[console.apu.to_bytes(), console.serial.to_bytes()].concat()),
+            link: link.map_or(0, |link| StateDigest::new(link, None).hash()),
+            pages
+        }
+    }
+
+    /// A hash of the whole state
+    pub fn hash(&self) -> u64 {
+        fnv1a(&self.to_bytes())
+    }
+
+    /// What's different in `actual` from this digest, one item each
+    pub fn diff(&self, actual: &StateDigest) -> Vec<String> {
+        let mut differences = Vec::new();
+        let mut compare = |name: &str, expected: String, actual: String| if expected != actual {
+            differences.push(format!("{} was {}, now {}", name, expected, actual));
+        };
+        compare("pc", self.pc.to_string(), actual.pc.to_string());
+        compare("cycles", self.cycles.to_string(), actual.cycles.to_string());
+        for (index, (_, name)) in REGISTERS.iter().enumerate() {
+            compare(name, self.registers[index].to_string(), actual.registers[index].to_string());
+        }
+        compare("flags", format!("{:04b}", self.flags), format!("{:04b}", actual.flags));
+        compare("in_interrupt", self.in_interrupt.to_string(), actual.in_interrupt.to_string());
+        if self.devices != actual.devices {
+            differences.push("the APU or serial port".to_string());
+        }
+        if self.link != actual.link {
+            differences.push("the linked console".to_string());
+        }
+        for (index, (expected, actual)) in self.pages.iter().zip(&actual.pages).enumerate() {
+            if expected != actual {
+                differences.push(format!("memory {:04X}h-{:04X}h", index * PAGE_SIZE, (index + 1) * PAGE_SIZE - 1));
+            }
+        }
+        differences
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(Self::SIZE);
+        bytes.extend_from_slice(&(self.pc as u64).to_le_bytes());
+        bytes.extend_from_slice(&self.cycles.to_le_bytes());
+        bytes.extend_from_slice(&self.registers);
+        bytes.push(self.flags);
+        bytes.push(self.in_interrupt as u8);
+        bytes.extend_from_slice(&self.devices.to_le_bytes());
+        bytes.extend_from_slice(&self.link.to_le_bytes());
+        for page in self.pages {
+            bytes.extend_from_slice(&page.to_le_bytes());
+        }
+        bytes
+    }
+
+    /// Reads a digest written by `to_bytes`, which must be exactly `SIZE` bytes long
+    fn from_bytes(bytes: &[u8]) -> StateDigest {
+        let u64_at = |offset: usize| u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap());
+        StateDigest {
+            pc: u64_at(0) as usize,
+            cycles: u64_at(8),
+            registers: bytes[16..21].try_into().unwrap(),
+            flags: bytes[21],
+            in_interrupt: bytes[22] != 0,
+            devices: u64_at(23),
+            link: u64_at(31),
+            pages: std::array::from_fn(|index| u64_at(39 + index * 8))
+        }
+    }
+}
+
+/// The digests of a run, one for the end of each frame
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct AuditLog {
+    pub frames: Vec<StateDigest>
+}
+
+impl AuditLog {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = AUDIT_MAGIC.to_vec();
+        bytes.push(AUDIT_VERSION);
+        for digest in &self.frames {
+            bytes.extend(digest.to_bytes());
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<AuditLog, String> {
+        if bytes.len() < HEADER_SIZE || &bytes[..AUDIT_MAGIC.len()] != AUDIT_MAGIC {
+            return Err("not an audit file".to_string());
+        }
+        if bytes[AUDIT_MAGIC.len()] != AUDIT_VERSION {
+            return Err(format!("unsupported audit version {}", bytes[AUDIT_MAGIC.len()]));
+        }
+        let frames = &bytes[HEADER_SIZE..];
+        if !frames.len().is_multiple_of(StateDigest::SIZE) {
+            return Err("truncated audit file".to_string());
+        }
+        Ok(AuditLog { frames: frames.chunks(StateDigest::SIZE).map(StateDigest::from_bytes).collect() })
+    }
+
+    pub fn load(path: &str) -> Result<AuditLog, String> {
+        let bytes = fs::read(path).map_err(|error| error.to_string())?;
+        AuditLog::from_bytes(&bytes)
+    }
+
+    pub fn save(&self, path: &str) {
+        if let Err(error) = fs::write(path, self.to_bytes()) {
+            tracing::error!("Failed to save audit to {}: {}", path, error);
+        }
+    }
+}
+
+/// Whether a console's state is being recorded or checked at the end of each frame
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum AuditMode {
+    #[default]
+    Off,
+    Recording(AuditLog),
+    /// Checking against a recorded audit, `frame` frames in
+    Checking { log: AuditLog, frame: usize },
+    /// A check found a frame that didn't match the recording, and stopped there
+    Diverged { frame: usize, differences: Vec<String> }
+}
+
+impl AuditMode {
+    /// Takes the digest of the frame that just ended, recording it or checking it against the recording. The
+    /// digest is only worked out if it's needed.
+    pub fn end_frame(&mut self, digest: impl FnOnce() -> StateDigest) {
+        match self {
+            AuditMode::Off | AuditMode::Diverged { .. } => (),
+            AuditMode::Recording(log) => log.frames.push(digest()),
+            AuditMode::Checking { log, frame } => match log.frames.get(*frame) {
+                Some(expected) => {
+                    let actual = digest();
+                    if actual.hash() == expected.hash() {
+                        *frame += 1;
+                    } else {
+                        let differences = expected.diff(&actual);
+                        tracing::error!("Frame {} diverged from the audit: {}", frame, differences.join(", "));
+                        *self = AuditMode::Diverged { frame: *frame, differences };
+                    }
+                },
+                None => {
+                    tracing::info!("Audit passed: all {} frames matched", frame);
+                    *self = AuditMode::Off;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use devola::instructions::Instruction;
+    use devola::vm::Devola;
+
+    fn console() -> Console {
+        Console::new(Devola::new(vec![Instruction::Increment; 4], None))
+    }
+
+    #[test]
+    fn test_audit() {
+        let mut console = console();
+        let mut recording = AuditMode::Recording(AuditLog::default());
+        for _ in 0..3 {
+            console.devola.step().unwrap();
+            recording.end_frame(|| StateDigest::new(&console, None));
+        }
+        let log = match recording {
+            AuditMode::Recording(log) => log,
+            _ => panic!("not recording")
+        };
+        assert_eq!(AuditLog::from_bytes(&log.to_bytes()), Ok(log.clone()));
+        assert_eq!(AuditLog::from_bytes(b"PAUD\x02\x00"), Err("truncated audit file".to_string()));
+
+        // the same run matches all the way through
+        let mut console = self::console();
+        let mut checking = AuditMode::Checking { log: log.clone(), frame: 0 };
+        for _ in 0..4 {
+            console.devola.step().unwrap();
+            checking.end_frame(|| StateDigest::new(&console, None));
+        }
+        assert_eq!(checking, AuditMode::Off);
+
+        // one that goes differently is stopped at the first frame that doesn't match
+        let mut console = self::console();
+        let mut checking = AuditMode::Checking { log: log.clone(), frame: 0 };
+        console.devola.step().unwrap();
+        checking.end_frame(|| StateDigest::new(&console, None));
+        console.devola.poke(0x0400, 1);
+        console.devola.step().unwrap();
+        checking.end_frame(|| StateDigest::new(&console, None));
+        assert_eq!(checking, AuditMode::Diverged { frame: 1, differences: vec!["memory 0400h-07FFh".to_string()] });
+
+        // as do the APU and a linked console, which the VM's state doesn't show
+        let mut console = self::console();
+        console.devola.step().unwrap();
+        console.apu.run(&mut Devola::new(Vec::new(), None), 100);
+        let linked = self::console();
+        let actual = StateDigest::new(&console, Some(&linked));
+        assert_eq!(log.frames[0].diff(&actual), ["the APU or serial port", "the linked console"]);
+    }
+}
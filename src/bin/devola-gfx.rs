@@ -0,0 +1,231 @@
+//! Converts an indexed PNG into the palettes, tiles and background map Popola draws it with, laid out as they are
+//! in VRAM: either as binary files for `.incbin`, or as assembly placing them at their VRAM addresses.
+use popola::inter::mmio::*;
+use std::path::Path;
+use std::{env, fs, process};
+
+const USAGE: &str = "Usage: devola-gfx <image.png> [-o <name>] [--db] [--palette <0-7>] [--tilemap <0-1>] [--bg <0-3>]";
+
+/// How many distinct tiles fit in a tilemap
+const TILEMAP_TILES: usize = TILEMAP_LENGTH*TILEMAP_LENGTH;
+
+#[derive(Debug, Default, PartialEq)]
+struct Options {
+    image: String,
+    /// What the outputs are named after, e.g. `name.tiles.bin`
+    output: String,
+    /// Whether to write assembly with `.db` instead of binary files
+    db: bool,
+    /// The first palette, tilemap and background the image is placed in
+    palette: usize,
+    tilemap: usize,
+    background: usize
+}
+
+fn parse_options(args: &[String]) -> Option<Options> {
+    let (image, rest) = args.split_first()?;
+    let mut options = Options {
+        image: image.clone(),
+        // next to the image by default
+        output: Path::new(image).with_extension("").to_string_lossy().into_owned(),
+        ..Options::default()
+    };
+    let mut rest = rest.iter();
+    while let Some(option) = rest.next() {
+        match option.as_str() {
+            "-o" => options.output = rest.next()?.clone(),
+            "--db" => options.db = true,
+            "--palette" => options.palette = rest.next()?.parse().ok().filter(|&palette| palette < PALETTE_COUNT)?,
+            "--tilemap" => options.tilemap = rest.next()?.parse().ok().filter(|&tilemap| tilemap < TILEMAP_COUNT)?,
+            "--bg" => options.background = rest.next()?.parse().ok().filter(|&background| background < BG_COUNT)?,
+            _ => return None
+        }
+    }
+    Some(options)
+}
+
+/// An indexed image, one byte per pixel
+struct IndexedImage {
+    width: usize,
+    height: usize,
+    pixels: Vec<u8>,
+    /// The RGB colors the pixels index
+    colors: Vec<[u8; 3]>
+}
+
+fn decode(bytes: &[u8]) -> Result<IndexedImage, String> {
+    let mut reader = png::Decoder::new(bytes).read_info().map_err(|error| error.to_string())?;
+    let mut data = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut data).map_err(|error| error.to_string())?;
+    if info.color_type != png::ColorType::Indexed {
+        return Err(String::from("the image isn't indexed"));
+    }
+    let colors = reader.info().palette.as_ref().ok_or("the image has no palette")?
+        .chunks_exact(3)
+        .map(|color| [color[0], color[1], color[2]])
+        .collect();
+    // rows are padded to a whole byte, with pixels packed from the most significant bit
+    let depth = info.bit_depth as usize;
+    let (width, height) = (info.width as usize, info.height as usize);
+    let pixels = data.chunks(info.line_size).take(height).flat_map(|row| (0..width).map(move |x| {
+        let bit = x*depth;
+        (row[bit/8] >> (8 - depth - bit%8)) & ((1 << depth) - 1) as u8
+    })).collect();
+    Ok(IndexedImage { width, height, pixels, colors })
+}
+
+/// The image as it's laid out in VRAM
+#[derive(Debug, PartialEq)]
+struct Graphics {
+    /// Enough palettes for all of the image's colors, 16 colors each
+    palettes: Vec<u8>,
+    /// Each distinct tile in the image, once. The high nibble of each pixel picks its palette, for mode 2.
+    tiles: Vec<u8>,
+    /// A background map showing the image in its top left, as many rows high as the image. Each entry picks
+    /// the first palette, for modes 0 and 1.
+    map: Vec<u8>
+}
+
+fn color_to_rgb15([r, g, b]: [u8; 3]) -> u16 {
+    ((r as u16 / 8) << 10) | ((g as u16 / 8) << 5) | (b as u16 / 8)
+}
+
+fn convert(image: &IndexedImage, options: &Options) -> Result<Graphics, String> {
+    if !image.width.is_multiple_of(TILE_LENGTH) || !image.height.is_multiple_of(TILE_LENGTH) {
+        return Err(format!("the image is {}x{}, which isn't a whole number of tiles", image.width, image.height));
+    }
+    if image.width > SCREEN_WIDTH as usize || image.height > SCREEN_HEIGHT as usize {
+        return Err(format!("the image is {}x{}, which is bigger than a background", image.width, image.height));
+    }
+    let palette_count = image.colors.len().div_ceil(PALETTE_LENGTH);
+    if options.palette + palette_count > PALETTE_COUNT {
+        return Err(format!("{} colors don't fit in the palettes from palette {}", image.colors.len(), options.palette));
+    }
+    let mut palettes = vec![0; palette_count*PALETTE_SIZE];
+    for (index, &color) in image.colors.iter().enumerate() {
+        palettes[index*COLOR_SIZE..(index + 1)*COLOR_SIZE].copy_from_slice(&color_to_rgb15(color).to_be_bytes());
+    }
+
+    let (columns, rows) = (image.width/TILE_LENGTH, image.height/TILE_LENGTH);
+    let mut tiles: Vec<Vec<u8>> = Vec::new();
+    let mut map = vec![0; rows*BG_WIDTH*BG_ENTRY_SIZE];
+    for row in 0..rows {
+        for column in 0..columns {
+            let tile: Vec<u8> = (0..TILE_SIZE).map(|pixel| {
+                let (x, y) = (column*TILE_LENGTH + pixel%TILE_LENGTH, row*TILE_LENGTH + pixel/TILE_LENGTH);
+                // mode 2 takes the palette from the high nibble, so the colors are counted from the first palette
+                image.pixels[y*image.width + x] + (options.palette*PALETTE_LENGTH) as u8
+            }).collect();
+            // repeated tiles are only stored once
+            let index = tiles.iter().position(|existing| *existing == tile).unwrap_or_else(|| {
+                tiles.push(tile);
+                tiles.len() - 1
+            });
+            if index >= TILEMAP_TILES {
+                return Err(format!("the image has more than {} different tiles", TILEMAP_TILES));
+            }
+            let entry = (row*BG_WIDTH + column)*BG_ENTRY_SIZE;
+            map[entry] = index as u8;
+            // the palette bits of the attributes, which modes 0 and 1 add to the background's palette
+            map[entry + 1] = (options.palette as u8) << 2;
+        }
+    }
+    Ok(Graphics { palettes, tiles: tiles.concat(), map })
+}
+
+/// Assembly placing each part of the image at its address in VRAM
+fn to_source(graphics: &Graphics, options: &Options) -> String {
+    let parts = [
+        ("palettes", PALETTE_START as usize + options.palette*PALETTE_SIZE, &graphics.palettes),
+        ("tiles", TILEMAP_START as usize + options.tilemap*TILEMAP_SIZE, &graphics.tiles),
+        ("map", BG_START as usize + options.background*BG_SIZE, &graphics.map)
+    ];
+    let mut source = format!("; {}, converted by devola-gfx\n", options.image);
+    for (name, address, bytes) in parts {
+        source.push_str(&format!("\n; {}\n.org {:04X}h\n", name, address));
+        for line in bytes.chunks(16) {
+            let values: Vec<String> = line.iter().map(|byte| byte.to_string()).collect();
+            source.push_str(&format!(".db {}\n", values.join(", ")));
+        }
+    }
+    source
+}
+
+fn write(path: &str, contents: &[u8]) {
+    if let Err(error) = fs::write(path, contents) {
+        eprintln!("Failed to write {}: {}", path, error);
+        process::exit(1);
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let options = match parse_options(&args) {
+        Some(options) => options,
+        None => {
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        }
+    };
+    let image = fs::read(&options.image)
+        .map_err(|error| error.to_string())
+        .and_then(|bytes| decode(&bytes))
+        .and_then(|image| convert(&image, &options));
+    let graphics = match image {
+        Ok(graphics) => graphics,
+        Err(error) => {
+            eprintln!("Failed to convert {}: {}", options.image, error);
+            process::exit(1);
+        }
+    };
+    if options.db {
+        write(&format!("{}.pop", options.output), to_source(&graphics, &options).as_bytes());
+    } else {
+        write(&format!("{}.pal.bin", options.output), &graphics.palettes);
+        write(&format!("{}.tiles.bin", options.output), &graphics.tiles);
+        write(&format!("{}.map.bin", options.output), &graphics.map);
+    }
+    println!("{} colors, {} tiles", graphics.palettes.len()/COLOR_SIZE, graphics.tiles.len()/TILE_SIZE);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A 16x8 2-bit image of a tile of color 1 beside a tile of color 2
+    fn image() -> Vec<u8> {
+        let mut bytes = Vec::new();
+        let mut encoder = png::Encoder::new(&mut bytes, 16, 8);
+        encoder.set_color(png::ColorType::Indexed);
+        encoder.set_depth(png::BitDepth::Two);
+        encoder.set_palette(vec![0, 0, 0, 255, 0, 0, 0, 0, 255]);
+        encoder.write_header().unwrap().write_image_data(&[0b0101_0101, 0b0101_0101, 0b1010_1010, 0b1010_1010].repeat(8)).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn test_convert() {
+        let args: Vec<String> = ["logo.png", "--db", "--bg", "2"].iter().map(|arg| arg.to_string()).collect();
+        let options = parse_options(&args).unwrap();
+        assert_eq!(options, Options { image: String::from("logo.png"), output: String::from("logo"), db: true, background: 2, ..Options::default() });
+        assert_eq!(parse_options(&[String::from("logo.png"), String::from("--palette"), String::from("8")]), None);
+
+        let graphics = convert(&decode(&image()).unwrap(), &options).unwrap();
+        assert_eq!(graphics.palettes.len(), PALETTE_SIZE);
+        assert_eq!(graphics.palettes[..6], [0, 0, 0x7C, 0x00, 0x00, 0x1F]);
+        assert_eq!(graphics.tiles, [vec![1; TILE_SIZE], vec![2; TILE_SIZE]].concat());
+        assert_eq!(graphics.map.len(), BG_WIDTH*BG_ENTRY_SIZE);
+        assert_eq!(graphics.map[..4], [0, 0, 1, 0]);
+
+        let source = to_source(&graphics, &options);
+        assert!(source.contains(&format!("; map\n.org {:04X}h\n.db 0, 0, 1, 0,", BG_START as usize + 2*BG_SIZE)));
+
+        // a later palette is picked by the map's attributes, and by each pixel's high nibble for mode 2
+        let options = Options { palette: 3, ..options };
+        let graphics = convert(&decode(&image()).unwrap(), &options).unwrap();
+        assert_eq!(graphics.tiles, [vec![0x31; TILE_SIZE], vec![0x32; TILE_SIZE]].concat());
+        assert_eq!(graphics.map[..4], [0, 3 << 2, 1, 3 << 2]);
+        let source = to_source(&graphics, &options);
+        assert!(source.contains(&format!("; palettes\n.org {:04X}h\n", PALETTE_START as usize + 3*PALETTE_SIZE)));
+    }
+}
@@ -0,0 +1,296 @@
+//! Converts a song written in a simple tracker format into the stream of APU register writes played by
+//! `devola::stdlib::music_tick`, either as a binary file for `.incbin` or as assembly with `.db`.
+//!
+//! A song is a list of rows, one per line, each with a cell for the square channels 0 and 1, the noise channel and
+//! the wave channel, separated by `|`. A cell is a note and an optional volume in hex, `F` by default: `C-4`,
+//! `C#4 8`, or the noise period in hex for the noise channel, e.g. `7 F`. `---` or nothing leaves the channel
+//! alone, and `===` silences it. Each row lasts `speed` frames. Lines can also be settings:
+//! - `speed <frames>`: how long the rows after it last, 6 frames by default
+//! - `duty <0-1> <0-3>`: the duty cycle of a square channel's notes after it
+//! - `wave <32 hex digits>`: the wave channel's wave table, written where the setting is
+//! - `loop`: where the song carries on from once it reaches the end, instead of stopping
+//!
+//! `;` starts a comment.
+use devola::stdlib::{MUSIC_END, MUSIC_JUMP, MUSIC_WAIT, MUSIC_WRITE};
+use popola::inter::mmio::*;
+use std::path::Path;
+use std::{env, fs, process};
+
+const USAGE: &str = "Usage: devola-music <song.txt> [-o <name>] [--db] [--org <address>]";
+
+/// Where the song is placed in memory unless told otherwise, just above the I/O page
+const DEFAULT_ORG: u16 = 0x1000;
+const DEFAULT_SPEED: u8 = 6;
+const CHANNEL_COUNT: usize = SQUARE_COUNT + 2;
+const NOISE_CHANNEL: usize = SQUARE_COUNT;
+const WAVE_CHANNEL: usize = SQUARE_COUNT + 1;
+const MAX_PERIOD: u16 = 2047;
+
+#[derive(Debug, Default, PartialEq)]
+struct Options {
+    song: String,
+    /// What the output is named after, e.g. `name.bin`
+    output: String,
+    /// Whether to write assembly with `.db` instead of a binary file
+    db: bool,
+    /// Where the song will be in memory, which jumps back to its loop point depend on
+    org: u16
+}
+
+fn parse_address(text: &str) -> Option<u16> {
+    match text.strip_suffix(['h', 'H']) {
+        Some(hex) => u16::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok()
+    }
+}
+
+fn parse_options(args: &[String]) -> Option<Options> {
+    let (song, rest) = args.split_first()?;
+    let mut options = Options {
+        song: song.clone(),
+        // next to the song by default
+        output: Path::new(song).with_extension("").to_string_lossy().into_owned(),
+        db: false,
+        org: DEFAULT_ORG
+    };
+    let mut rest = rest.iter();
+    while let Some(option) = rest.next() {
+        match option.as_str() {
+            "-o" => options.output = rest.next()?.clone(),
+            "--db" => options.db = true,
+            "--org" => options.org = parse_address(rest.next()?)?,
+            _ => return None
+        }
+    }
+    Some(options)
+}
+
+/// What a cell asks of its channel
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Cell {
+    Empty,
+    Off,
+    /// A note's frequency in Hz and its volume
+    Note(f64, u8),
+    /// The noise channel's period and its volume
+    Noise(u8, u8)
+}
+
+fn parse_cell(text: &str, channel: usize) -> Result<Cell, String> {
+    let mut parts = text.split_whitespace();
+    let note = match parts.next() {
+        None | Some("---") => return Ok(Cell::Empty),
+        Some("===") => return Ok(Cell::Off),
+        Some(note) => note
+    };
+    let volume = match parts.next() {
+        Some(volume) => u8::from_str_radix(volume, 16).ok().filter(|&volume| volume < 16)
+            .ok_or_else(|| format!("{} isn't a volume", volume))?,
+        None => 15
+    };
+    if channel == NOISE_CHANNEL {
+        let period = u8::from_str_radix(note, 16).ok().filter(|&period| (period as usize) < NOISE_PERIODS.len())
+            .ok_or_else(|| format!("{} isn't a noise period", note))?;
+        return Ok(Cell::Noise(period, volume));
+    }
+    let semitone = |name: char| "C D EF G A B".find(name);
+    let mut chars = note.chars();
+    let frequency = match (chars.next().and_then(semitone), chars.next(), chars.next().and_then(|octave| octave.to_digit(10)), chars.next()) {
+        (Some(semitone), Some(accidental @ ('-' | '#')), Some(octave), None) => {
+            // semitones from A4, at 440 Hz
+            let from_a4 = (octave as i32 - 4)*12 + semitone as i32 + (accidental == '#') as i32 - 9;
+            440.0*2f64.powf(from_a4 as f64/12.0)
+        },
+        _ => return Err(format!("{} isn't a note", note))
+    };
+    Ok(Cell::Note(frequency, volume))
+}
+
+/// The period making a channel that plays at `CPU_FREQUENCY/(divider*(period+1))` Hz play at `frequency`
+fn period(frequency: f64, divider: f64) -> Option<u16> {
+    let period = (CPU_FREQUENCY as f64/(divider*frequency)).round() - 1.0;
+    (0.0..=MAX_PERIOD as f64).contains(&period).then_some(period as u16)
+}
+
+/// Builds up the stream of commands
+#[derive(Default)]
+struct Song {
+    bytes: Vec<u8>,
+    /// Where the song carries on from once it reaches the end
+    loop_point: Option<usize>
+}
+
+impl Song {
+    fn write(&mut self, address: u16, value: u8) {
+        self.bytes.push(MUSIC_WRITE);
+        self.bytes.extend_from_slice(&address.to_be_bytes());
+        self.bytes.push(value);
+    }
+
+    fn wait(&mut self, mut frames: usize) {
+        while frames > 0 {
+            let wait = frames.min(u8::MAX as usize);
+            self.bytes.extend_from_slice(&[MUSIC_WAIT, wait as u8]);
+            frames -= wait;
+        }
+    }
+
+    /// The writes playing a cell on a channel
+    fn play(&mut self, cell: Cell, channel: usize, duty: u8) -> Result<(), String> {
+        let (start, divider) = match channel {
+            NOISE_CHANNEL => (NOISE_START, 0.0),
+            WAVE_CHANNEL => (WAVE_START, 32.0),
+            _ => (SQUARE_START + (channel*SQUARE_SIZE) as u16, 16.0)
+        };
+        // the period, control, envelope or volume, and length registers
+        let (period_register, control, envelope) = (start, start + 1, start + 2);
+        match cell {
+            Cell::Empty => (),
+            // the noise and wave channels have no duty cycle to keep
+            Cell::Off if channel >= NOISE_CHANNEL => self.write(envelope, 0),
+            Cell::Off => self.write(envelope, duty << 6),
+            Cell::Noise(period, volume) => {
+                self.write(period_register, period);
+                self.write(envelope, volume << 4);
+                self.write(control, AUDIO_START_FLAG);
+            },
+            Cell::Note(frequency, volume) => {
+                let period = period(frequency, divider).ok_or("the note is out of the channel's range")?;
+                let [high, low] = period.to_be_bytes();
+                self.write(period_register, low);
+                self.write(envelope, if channel == WAVE_CHANNEL { volume } else { duty << 6 | volume });
+                self.write(control, AUDIO_START_FLAG | high);
+            }
+        }
+        Ok(())
+    }
+
+    /// The stream, ending in a jump back to the loop point if the song is at `org`
+    fn finish(mut self, org: u16) -> Vec<u8> {
+        match self.loop_point {
+            Some(loop_point) => {
+                self.bytes.push(MUSIC_JUMP);
+                self.bytes.extend_from_slice(&org.wrapping_add(loop_point as u16).to_be_bytes());
+            },
+            None => self.bytes.push(MUSIC_END)
+        }
+        self.bytes
+    }
+}
+
+fn convert(text: &str, org: u16) -> Result<Vec<u8>, String> {
+    let mut song = Song::default();
+    let mut speed = DEFAULT_SPEED;
+    let mut duties = [0; SQUARE_COUNT];
+    for (number, line) in text.lines().enumerate() {
+        let error = |message: String| format!("line {}: {}", number + 1, message);
+        let line = line.split(';').next().unwrap().trim();
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words[..] {
+            [] => (),
+            ["speed", frames] => speed = frames.parse().ok().filter(|&speed| speed > 0)
+                .ok_or_else(|| error(format!("{} isn't a speed", frames)))?,
+            ["duty", channel, duty] => {
+                let channel: usize = channel.parse().ok().filter(|&channel| channel < SQUARE_COUNT)
+                    .ok_or_else(|| error(format!("{} isn't a square channel", channel)))?;
+                duties[channel] = duty.parse().ok().filter(|&duty| duty < 4)
+                    .ok_or_else(|| error(format!("{} isn't a duty cycle", duty)))?;
+            },
+            ["wave", samples] => {
+                let samples: Vec<u8> = samples.chars().filter_map(|sample| sample.to_digit(16)).map(|sample| sample as u8).collect();
+                if samples.len() != WAVE_LENGTH {
+                    return Err(error(format!("a wave has {} samples", WAVE_LENGTH)));
+                }
+                for (index, pair) in samples.chunks(2).enumerate() {
+                    song.write(WAVE_TABLE + index as u16, pair[0] << 4 | pair[1]);
+                }
+            },
+            ["loop"] => song.loop_point = Some(song.bytes.len()),
+            _ => {
+                let cells: Vec<&str> = line.split('|').collect();
+                if cells.len() > CHANNEL_COUNT {
+                    return Err(error(format!("a row has at most {} cells", CHANNEL_COUNT)));
+                }
+                for (channel, cell) in cells.iter().enumerate() {
+                    let duty = duties.get(channel).copied().unwrap_or(0);
+                    parse_cell(cell, channel).and_then(|cell| song.play(cell, channel, duty)).map_err(error)?;
+                }
+                song.wait(speed as usize);
+            }
+        }
+    }
+    Ok(song.finish(org))
+}
+
+/// Assembly placing the song at its address
+fn to_source(bytes: &[u8], options: &Options) -> String {
+    let mut source = format!("; {}, converted by devola-music\n.org {:04X}h\n", options.song, options.org);
+    for line in bytes.chunks(16) {
+        let values: Vec<String> = line.iter().map(|byte| byte.to_string()).collect();
+        source.push_str(&format!(".db {}\n", values.join(", ")));
+    }
+    source
+}
+
+fn write(path: &str, contents: &[u8]) {
+    if let Err(error) = fs::write(path, contents) {
+        eprintln!("Failed to write {}: {}", path, error);
+        process::exit(1);
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let options = match parse_options(&args) {
+        Some(options) => options,
+        None => {
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        }
+    };
+    let song = fs::read_to_string(&options.song)
+        .map_err(|error| error.to_string())
+        .and_then(|text| convert(&text, options.org));
+    let bytes = match song {
+        Ok(bytes) => bytes,
+        Err(error) => {
+            eprintln!("Failed to convert {}: {}", options.song, error);
+            process::exit(1);
+        }
+    };
+    if options.db {
+        write(&format!("{}.pop", options.output), to_source(&bytes, &options).as_bytes());
+    } else {
+        write(&format!("{}.bin", options.output), &bytes);
+    }
+    println!("{} bytes", bytes.len());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_convert() {
+        let args: Vec<String> = ["theme.txt", "--org", "2000h"].iter().map(|arg| arg.to_string()).collect();
+        assert_eq!(parse_options(&args), Some(Options {
+            song: String::from("theme.txt"), output: String::from("theme"), db: false, org: 0x2000
+        }));
+
+        assert_eq!(parse_cell("A-4 8", 0), Ok(Cell::Note(440.0, 8)));
+        assert_eq!(parse_cell("7", NOISE_CHANNEL), Ok(Cell::Noise(7, 15)));
+        assert_eq!(parse_cell("H-4", 0), Err(String::from("H-4 isn't a note")));
+        assert_eq!(period(440.0, 16.0), Some(253));
+
+        let song = "speed 2\nduty 1 2\nloop\n--- | A-4 8 ; a comment\n=== | |\n";
+        let [square_1, envelope, control] = [0, 2, 1].map(|register| (SQUARE_START + SQUARE_SIZE as u16 + register).to_be_bytes());
+        assert_eq!(convert(song, 0x2000), Ok(vec![
+            MUSIC_WRITE, square_1[0], square_1[1], 253, MUSIC_WRITE, envelope[0], envelope[1], 0b1000_1000,
+            MUSIC_WRITE, control[0], control[1], AUDIO_START_FLAG, MUSIC_WAIT, 2,
+            MUSIC_WRITE, (SQUARE_START + 2).to_be_bytes()[0], (SQUARE_START + 2).to_be_bytes()[1], 0, MUSIC_WAIT, 2,
+            MUSIC_JUMP, 0x20, 0x00
+        ]));
+        assert_eq!(convert("speed 0", 0x2000), Err(String::from("line 1: 0 isn't a speed")));
+        assert_eq!(convert("C-0", 0x2000), Err(String::from("line 1: the note is out of the channel's range")));
+    }
+}
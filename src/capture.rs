@@ -0,0 +1,258 @@
+//! Capturing gameplay: frames are written to an animated GIF, or piped to `ffmpeg` for any other format, which
+//! also gets the console's audio once the capture is done
+use crate::inter::mmio::*;
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc::{self, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Where captures are saved, named after the program and when they were started
+const CAPTURES_DIRECTORY: &str = "captures";
+/// GIFs keep every other frame, as their delays are in hundredths of a second and most viewers slow down
+/// anything shorter than 2
+const GIF_FRAME_INTERVAL: u64 = 2;
+/// The formats captures can be saved in: GIFs are written directly, and the rest by `ffmpeg`
+pub const CAPTURE_FORMATS: [&str; 5] = ["gif", "mp4", "mkv", "webm", "mov"];
+
+/// A capture in progress, which stops itself after a set number of frames
+pub struct Capture {
+    sink: Sink,
+    path: PathBuf,
+    frame: u64,
+    frames: u64
+}
+
+enum Sink {
+    Gif(gif::Encoder<BufWriter<File>>),
+    Video(Video)
+}
+
+impl Capture {
+    /// Starts capturing `frames` frames to `path`, as a GIF if it ends in `.gif` and through `ffmpeg` otherwise
+    pub fn start(path: &Path, frames: u64) -> Result<Capture, String> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(|error| error.to_string())?;
+        }
+        let sink = if path.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("gif")) {
+            let file = File::create(path).map_err(|error| error.to_string())?;
+            let mut gif = gif::Encoder::new(BufWriter::new(file), SCREEN_WIDTH as u16, SCREEN_HEIGHT as u16, &[])
+                .map_err(|error| error.to_string())?;
+            // looping forever
+            gif.set_repeat(gif::Repeat::Infinite).map_err(|error| error.to_string())?;
+            Sink::Gif(gif)
+        } else {
+            Sink::Video(Video::start(path)?)
+        };
+        Ok(Capture { sink, path: path.to_path_buf(), frame: 0, frames })
+    }
+
+    /// Adds a frame, in RGBA, and the audio samples made during it. Returns false once the capture has all
+    /// its frames.
+    pub fn add_frame(&mut self, frame: &[u8], samples: &[f32]) -> Result<bool, String> {
+        match &mut self.sink {
+            Sink::Gif(gif) if self.frame.is_multiple_of(GIF_FRAME_INTERVAL) => {
+                // 2 frames at 60 a second are 3.33 hundredths, so delays are a mix of 3s and 4s
+                let delay = (self.frame + GIF_FRAME_INTERVAL)*100/FRAME_RATE - self.frame*100/FRAME_RATE;
+                gif.write_frame(&gif_frame(SCREEN_WIDTH as u16, SCREEN_HEIGHT as u16, frame, delay as u16))
+                    .map_err(|error| error.to_string())?;
+            },
+            Sink::Gif(_) => (),
+            Sink::Video(video) => video.add_frame(frame, samples)?
+        }
+        self.frame += 1;
+        Ok(self.frame < self.frames)
+    }
+
+    /// Finishes writing the capture, returning where it was saved
+    pub fn finish(self) -> Result<PathBuf, String> {
+        match self.sink {
+            Sink::Gif(gif) => gif.into_inner().and_then(|mut writer| writer.flush()).map_err(|error| error.to_string())?,
+            Sink::Video(video) => video.finish(&self.path)?
+        }
+        Ok(self.path)
+    }
+}
+
+/// Where a new capture of a program is saved, in the given format
+pub fn capture_path(program: Option<&Path>, format: &str) -> PathBuf {
+    let name = program.and_then(Path::file_stem).map_or_else(|| String::from("unnamed"), |name| name.to_string_lossy().into_owned());
+    let time = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |time| time.as_secs());
+    Path::new(CAPTURES_DIRECTORY).join(format!("{}-{}.{}", name, time, format))
+}
+
+/// Frames piped to `ffmpeg` as raw RGBA, encoded to a silent file next to the capture while the audio is kept
+/// aside, to be muxed in at the end. The frames are written from a thread of their own, so that the console
+/// doesn't wait on `ffmpeg` to keep up.
+struct Video {
+    ffmpeg: Child,
+    frames: Sender<Vec<u8>>,
+    writer: JoinHandle<io::Result<()>>,
+    silent_path: PathBuf,
+    samples: Vec<f32>
+}
+
+impl Video {
+    fn start(path: &Path) -> Result<Video, String> {
+        let silent_path = sibling(path, "silent");
+        let mut ffmpeg = Command::new("ffmpeg")
+            .args(["-y", "-loglevel", "error", "-f", "rawvideo", "-pixel_format", "rgba"])
+            .args(["-video_size", &format!("{}x{}", SCREEN_WIDTH, SCREEN_HEIGHT), "-framerate", &FRAME_RATE.to_string()])
+            .args(["-i", "-", "-pix_fmt", "yuv420p"])
+            .arg(&silent_path)
+            .stdin(Stdio::piped())
+            .spawn()
+            .map_err(|error| format!("couldn't run ffmpeg: {}", error))?;
+        let mut input = ffmpeg.stdin.take().ok_or("ffmpeg isn't taking frames")?;
+        let (frames, received) = mpsc::channel::<Vec<u8>>();
+        // the pipe is closed once the frames stop coming, which ends the video
+        let writer = thread::spawn(move || received.iter().try_for_each(|frame| input.write_all(&frame)));
+        Ok(Video { ffmpeg, frames, writer, silent_path, samples: Vec::new() })
+    }
+
+    fn add_frame(&mut self, frame: &[u8], samples: &[f32]) -> Result<(), String> {
+        // the writer only stops early if writing failed, which `finish` would report
+        self.frames.send(frame.to_vec()).map_err(|_| "ffmpeg stopped taking frames")?;
+        self.samples.extend_from_slice(samples);
+        Ok(())
+    }
+
+    /// Waits for the frames to be encoded, then muxes in the audio. If that fails, the silent video is kept
+    /// along with the audio as a WAV file.
+    fn finish(self, path: &Path) -> Result<(), String> {
+        let Video { mut ffmpeg, frames, writer, silent_path, samples } = self;
+        drop(frames);
+        let written = writer.join().map_err(|_| "the frame writer panicked")?;
+        let status = ffmpeg.wait().map_err(|error| error.to_string())?;
+        if !status.success() {
+            return Err(format!("ffmpeg failed with {}", status));
+        }
+        written.map_err(|error| format!("ffmpeg stopped taking frames: {}", error))?;
+        if samples.is_empty() {
+            return fs::rename(&silent_path, path).map_err(|error| error.to_string());
+        }
+        let audio_path = path.with_extension("wav");
+        write_wav(&mut BufWriter::new(File::create(&audio_path).map_err(|error| error.to_string())?), &samples)
+            .map_err(|error| error.to_string())?;
+        let muxed = Command::new("ffmpeg")
+            .args(["-y", "-loglevel", "error", "-i"])
+            .arg(&silent_path)
+            .arg("-i")
+            .arg(&audio_path)
+            .args(["-c:v", "copy", "-shortest"])
+            .arg(path)
+            .status();
+        match muxed {
+            Ok(status) if status.success() => {
+                fs::remove_file(&silent_path).and_then(|_| fs::remove_file(&audio_path)).map_err(|error| error.to_string())
+            },
+            _ => {
+                fs::rename(&silent_path, path).map_err(|error| error.to_string())?;
+                Err(format!("couldn't add the audio, which was saved to {}", audio_path.display()))
+            }
+        }
+    }
+}
+
+/// `capture.mp4` becomes `capture.<tag>.mp4`, keeping the extension that tells `ffmpeg` the format
+fn sibling(path: &Path, tag: &str) -> PathBuf {
+    let extension = path.extension().map_or_else(String::new, |extension| extension.to_string_lossy().into_owned());
+    path.with_extension(format!("{}.{}", tag, extension))
+}
+
+/// Writes mono samples as a 32-bit float WAV file at the APU's sample rate
+fn write_wav(writer: &mut impl Write, samples: &[f32]) -> io::Result<()> {
+    let data_size = 4*samples.len() as u32;
+    writer.write_all(b"RIFF")?;
+    writer.write_all(&(36 + data_size).to_le_bytes())?;
+    writer.write_all(b"WAVEfmt ")?;
+    writer.write_all(&16u32.to_le_bytes())?;
+    // IEEE float, 1 channel
+    writer.write_all(&3u16.to_le_bytes())?;
+    writer.write_all(&1u16.to_le_bytes())?;
+    writer.write_all(&(AUDIO_SAMPLE_RATE as u32).to_le_bytes())?;
+    writer.write_all(&(4*AUDIO_SAMPLE_RATE as u32).to_le_bytes())?;
+    writer.write_all(&4u16.to_le_bytes())?;
+    writer.write_all(&32u16.to_le_bytes())?;
+    writer.write_all(b"data")?;
+    writer.write_all(&data_size.to_le_bytes())?;
+    for sample in samples {
+        writer.write_all(&sample.to_le_bytes())?;
+    }
+    writer.flush()
+}
+
+/// A GIF frame from an RGBA frame, shown for `delay` hundredths of a second. Each frame gets its own palette of
+/// the colors in it, which the console's frames nearly always fit in; any that don't are reduced to 3 bits of red
+/// and green and 2 of blue.
+fn gif_frame(width: u16, height: u16, frame: &[u8], delay: u16) -> gif::Frame<'static> {
+    let (palette, indices) = palettize(frame);
+    let mut gif_frame = gif::Frame::from_palette_pixels(width, height, indices, palette.concat(), None);
+    gif_frame.delay = delay;
+    gif_frame
+}
+
+/// The colors in an RGBA frame, and each pixel's index into them
+fn palettize(frame: &[u8]) -> (Vec<[u8; 3]>, Vec<u8>) {
+    let mut palette = Vec::new();
+    let mut lookup = HashMap::new();
+    let mut indices = Vec::with_capacity(frame.len()/4);
+    for pixel in frame.chunks_exact(4) {
+        let color = [pixel[0], pixel[1], pixel[2]];
+        let index = *lookup.entry(color).or_insert_with(|| {
+            palette.push(color);
+            palette.len() - 1
+        });
+        if index > u8::MAX as usize {
+            return quantize(frame);
+        }
+        indices.push(index as u8);
+    }
+    (palette, indices)
+}
+
+/// An RGBA frame reduced to a fixed palette of 3 bits of red and green and 2 of blue
+fn quantize(frame: &[u8]) -> (Vec<[u8; 3]>, Vec<u8>) {
+    let palette = (0..=u8::MAX).map(|index| {
+        let scale = |value: u8, max: u8| (value as u16*255/max as u16) as u8;
+        [scale(index >> 5, 7), scale((index >> 2) & 7, 7), scale(index & 3, 3)]
+    }).collect();
+    let indices = frame.chunks_exact(4).map(|pixel| (pixel[0] & 0xe0) | ((pixel[1] & 0xe0) >> 3) | (pixel[2] >> 6)).collect();
+    (palette, indices)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gif() {
+        // colors are listed in the order they're first seen, until there are too many for a palette
+        let frame = [[9, 9, 9, 255], [1, 2, 3, 255], [9, 9, 9, 255]].concat();
+        assert_eq!(palettize(&frame), (vec![[9, 9, 9], [1, 2, 3]], vec![0, 1, 0]));
+        let many: Vec<u8> = (0..300u32).flat_map(|index| [index as u8, (index >> 8) as u8, 0xff, 255]).collect();
+        let (palette, indices) = palettize(&many);
+        // past that the frame is quantized, each index packing 3 bits of red, 3 of green and 2 of blue
+        assert_eq!((palette.len(), indices[0], indices[299]), (256, 0b0000_0011, 0b0010_0011));
+
+        // frames come back out as they went in, with their delays
+        let mut bytes = Vec::new();
+        let mut encoder = gif::Encoder::new(&mut bytes, 3, 1, &[]).unwrap();
+        encoder.set_repeat(gif::Repeat::Infinite).unwrap();
+        encoder.write_frame(&gif_frame(3, 1, &frame, 3)).unwrap();
+        encoder.write_frame(&gif_frame(3, 1, &frame[..4].repeat(3), 4)).unwrap();
+        encoder.into_inner().unwrap();
+        let mut options = gif::DecodeOptions::new();
+        options.set_color_output(gif::ColorOutput::RGBA);
+        let mut decoder = options.read_info(bytes.as_slice()).unwrap();
+        let first = decoder.read_next_frame().unwrap().unwrap();
+        assert_eq!((first.buffer.to_vec(), first.delay), (frame.clone(), 3));
+        let second = decoder.read_next_frame().unwrap().unwrap();
+        assert_eq!((second.buffer.to_vec(), second.delay), (frame[..4].repeat(3), 4));
+        assert!(decoder.read_next_frame().unwrap().is_none());
+    }
+}
@@ -0,0 +1,289 @@
+use crate::audio::apu::Apu;
+use crate::inter::animation::animate_sprites;
+use crate::gfx::VRAMModel;
+use crate::inter::joypad::Joypad;
+use crate::inter::mmio::*;
+use crate::inter::serial::Serial;
+use crate::inter::text::TextConsole;
+use crate::inter::toggles::apply_toggles;
+use crate::render::renderer::Renderer;
+use crate::replay::ReplayMode;
+use crate::savestate::SaveState;
+
+use devola::utility::{break_u16, build_u16};
+use devola::vm::{Devola, DevolaError, StateMismatch};
+
+/// One emulated machine: the VM, and the devices clocked alongside it
+pub struct Console {
+    pub devola: Devola,
+    pub halted: bool,
+    /// Cycles elapsed since the start of the current frame
+    pub frame_cycle: u64,
+    /// Cycles elapsed since the console started, including those spent idling
+    pub elapsed: u64,
+    /// Frames completed since the console started
+    pub frame: u64,
+    pub joypad: Joypad,
+    pub apu: Apu,
+    pub serial: Serial,
+    /// The text console, if it's plugged in
+    pub text: Option<TextConsole>,
+    pub replay: ReplayMode
+}
+
+impl Console {
+    pub fn new(devola: Devola) -> Console {
+        Console {
+            devola,
+            halted: false,
+            frame_cycle: 0,
+            elapsed: 0,
+            frame: 0,
+            joypad: Joypad::default(),
+            apu: Apu::new(),
+            serial: Serial::default(),
+            text: None,
+            replay: ReplayMode::Off
+        }
+    }
+
+    /// Swaps in another VM, as if the console had been switched off and on with a new program in it. Anything
+    /// plugged into the serial port stays connected.
+    pub fn reset(&mut self, devola: Devola) {
+        self.devola = devola;
+        self.halted = false;
+        self.frame_cycle = 0;
+        self.apu = Apu::new();
+        if let Some(text) = self.text.as_mut() {
+            text.clear();
+        }
+    }
+
+    /// Takes a snapshot of the console, along with the model the screen is drawn from, if there is one
+    pub fn save_state(&self, vram: Option<&VRAMModel>) -> SaveState {
+        let devola = self.devola.save_state();
+        // the model is written back into a copy of memory, leaving the VM itself alone, and only the bytes it
+        // changes are kept
+        let vram = match vram {
+            Some(vram) => {
+                let mut copy = Devola::new(Vec::new(), None);
+                copy.load_state(&devola).expect("the console's memory is the default size");
+                vram.write_back(&mut copy);
+                (0..=u16::MAX)
+                    .filter(|&address| copy.peek(address) != self.devola.peek(address))
+                    .map(|address| (address, copy.peek(address)))
+                    .collect()
+            },
+            None => Vec::new()
+        };
+        SaveState {
+            devola,
+            vram,
+            apu: self.apu.to_bytes(),
+            serial: self.serial.to_bytes(),
+            text: self.text.as_ref().map_or_else(Vec::new, |text| text.rows().to_vec()),
+            frame_cycle: self.frame_cycle,
+            elapsed: self.elapsed,
+            frame: self.frame
+        }
+    }
+
+    /// Puts the console back in a saved state, carrying on from it even if the console had halted since. A state
+    /// that doesn't fit the console's memory is refused, leaving the console as it was.
+    pub fn load_state(&mut self, state: &SaveState, vram: Option<&mut VRAMModel>) -> Result<(), StateMismatch> {
+        let mut copy = Devola::new(Vec::new(), None);
+        copy.load_state(&state.devola)?;
+        self.devola.load_state(&state.devola)?;
+        if let Some(vram) = vram {
+            for &(address, byte) in &state.vram {
+                copy.poke(address, byte);
+            }
+            vram.sync(&mut copy);
+        }
+        self.apu = Apu::from_bytes(&state.apu).unwrap_or_default();
+        self.serial.restore(&state.serial);
+        if let Some(text) = self.text.as_mut() {
+            text.set_rows(state.text.clone());
+        }
+        self.halted = false;
+        self.frame_cycle = state.frame_cycle;
+        self.elapsed = state.elapsed;
+        self.frame = state.frame;
+        Ok(())
+    }
+
+    fn halt(&mut self, error: DevolaError) {
+        let pc = self.devola.pc();
+        tracing::error!(pc, symbol = self.devola.enclosing_label(pc), "Devola halted with error: {}", error);
+        self.halted = true;
+    }
+
+    /// Runs the VM for at least `cycles` cycles, returning how many it actually ran for. Once the VM has
+    /// halted or reached the end of its code, it idles through the remaining time so that frames keep coming.
+    pub fn run_cycles(&mut self, cycles: u64, vram: Option<&mut VRAMModel>) -> u64 {
+        let mut elapsed = cycles;
+        let mut taken = 0;
+        if !self.halted {
+            match self.devola.run_cycles(cycles) {
+                Ok(ran) => {
+                    taken = ran;
+                    elapsed = ran.max(cycles);
+                },
+                Err(error) => self.halt(error)
+            }
+        }
+        self.advance_frame_cycle(elapsed, vram);
+        taken
+    }
+
+    /// Executes the next instruction on its own, returning the cycles it took
+    pub fn step_instruction(&mut self, vram: Option<&mut VRAMModel>) -> u64 {
+        if self.halted {
+            return 0;
+        }
+        let start = self.devola.cycles();
+        match self.devola.step() {
+            Ok(()) | Err(DevolaError::EndCode) => (),
+            Err(error) => self.halt(error)
+        }
+        let taken = self.devola.cycles() - start;
+        self.advance_frame_cycle(taken, vram);
+        taken
+    }
+
+    /// Moves the position within the frame forward, running the APU, serial port and any text console alongside.
+    /// At the end of the active display period, sprite toggles and background settings are applied, sprites are
+    /// animated and the VRAM model is latched from memory, then the vblank flag is raised, along with the sprite
+    /// overflow and collision flags for the latched frame, the vblank count goes up and the vblank interrupt is
+    /// requested for the remainder of the frame; the vblank flag is cleared again as the next frame starts, when
+    /// the frame counter goes up and the joypad register is updated from the held buttons or the replay being played.
+    fn advance_frame_cycle(&mut self, cycles: u64, vram: Option<&mut VRAMModel>) {
+        self.apu.run(&mut self.devola, cycles);
+        self.serial.run(&mut self.devola, cycles);
+        if let Some(text) = self.text.as_mut() {
+            text.update(&mut self.devola, vram.is_none());
+        }
+        self.elapsed += cycles;
+        let previous = self.frame_cycle;
+        self.frame_cycle += cycles;
+
+        if previous < ACTIVE_CYCLES && self.frame_cycle >= ACTIVE_CYCLES {
+            apply_toggles(&mut self.devola);
+            animate_sprites(&mut self.devola);
+            let (overflow, sprite_collision, bg_collision) = match vram {
+                Some(vram) => {
+                    vram.latch(&mut self.devola);
+                    Renderer::sprite_status(vram)
+                },
+                None => (false, false, false)
+            };
+            let status = self.devola.peek(VIDEO_STATUS) & !(SPRITE_OVERFLOW_FLAG | SPRITE_COLLISION_FLAG | BG_COLLISION_FLAG);
+            let overflow_flag = if overflow { SPRITE_OVERFLOW_FLAG } else { 0 };
+            let sprite_collision_flag = if sprite_collision { SPRITE_COLLISION_FLAG } else { 0 };
+            let bg_collision_flag = if bg_collision { BG_COLLISION_FLAG } else { 0 };
+            self.devola.poke(VIDEO_STATUS, status | VBLANK_FLAG | overflow_flag | sprite_collision_flag | bg_collision_flag);
+            let vblanks = self.devola.peek(VBLANK_COUNT);
+            self.devola.poke(VBLANK_COUNT, vblanks.saturating_add(1));
+            self.devola.request_interrupt(VBLANK_INTERRUPT);
+        }
+        if self.frame_cycle >= CYCLES_PER_FRAME {
+            // cycles taken past the end of the frame count towards the next one
+            self.frame_cycle -= CYCLES_PER_FRAME;
+            self.frame += 1;
+            let status = self.devola.peek(VIDEO_STATUS);
+            self.devola.poke(VIDEO_STATUS, status & !VBLANK_FLAG);
+            let counter = build_u16(self.devola.peek(FRAME_COUNTER), self.devola.peek(FRAME_COUNTER + 1));
+            let (hi, lo) = break_u16(counter.wrapping_add(1));
+            self.devola.poke(FRAME_COUNTER, hi);
+            self.devola.poke(FRAME_COUNTER + 1, lo);
+            let buttons = self.replay.next_frame(self.joypad.buttons());
+            self.devola.poke(JOYPAD, buttons);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use devola::instructions::Instruction;
+
+    #[test]
+    fn test_stepping() {
+        let mut console = Console::new(Devola::new(vec![Instruction::Nop; 4], None));
+
+        assert_eq!(console.step_instruction(None), 1);
+        assert_eq!(console.devola.pc(), 1);
+        assert_eq!(console.frame_cycle, 1);
+
+        // the code ends partway through the frame, and the VM idles through the rest
+        console.run_cycles(ACTIVE_CYCLES - 1, None);
+        assert_eq!(console.devola.pc(), 4);
+        assert_eq!(console.frame_cycle, ACTIVE_CYCLES);
+        assert_eq!(console.devola.peek(VIDEO_STATUS) & VBLANK_FLAG, VBLANK_FLAG);
+
+        console.run_cycles(CYCLES_PER_FRAME - ACTIVE_CYCLES, None);
+        assert_eq!(console.frame_cycle, 0);
+        assert_eq!(console.elapsed, CYCLES_PER_FRAME);
+        assert_eq!(console.devola.peek(VIDEO_STATUS) & VBLANK_FLAG, 0);
+
+        // stepping past the end of the code leaves the frame where it was
+        console.step_instruction(None);
+        assert_eq!(console.frame_cycle, 0);
+        assert!(!console.halted);
+    }
+
+    #[test]
+    fn test_save_state() {
+        let mut console = Console::new(Devola::new(vec![Instruction::Nop; 4], None));
+        let mut vram = VRAMModel::empty_vram();
+        console.devola.poke(BG_SCROLL, 5);
+        console.run_cycles(ACTIVE_CYCLES + 10, Some(&mut vram));
+        assert_eq!(console.save_state(Some(&vram)).vram, Vec::new());
+        // the saved model is the one latched at the end of the active period, whatever memory holds since
+        console.devola.poke(BG_SCROLL, 9);
+        let state = console.save_state(Some(&vram));
+        assert_eq!(state.vram, vec![(BG_SCROLL, 5)]);
+
+        console.run_cycles(3*CYCLES_PER_FRAME, Some(&mut vram));
+        console.halted = true;
+        let mut restored = VRAMModel::empty_vram();
+        console.load_state(&state, Some(&mut restored)).unwrap();
+        assert!(!console.halted);
+        assert_eq!((console.frame_cycle, console.elapsed, console.frame), (ACTIVE_CYCLES + 10, ACTIVE_CYCLES + 10, 0));
+        assert_eq!(console.devola.peek(BG_SCROLL), 9);
+        assert_eq!(restored.bg_scroll[0], (5, 0));
+        assert_eq!(console.save_state(Some(&restored)), state);
+    }
+
+    #[test]
+    fn test_frame_counters() {
+        let mut console = Console::new(Devola::new(Vec::new(), None));
+        console.devola.poke(FRAME_COUNTER, 0xFF);
+        console.devola.poke(FRAME_COUNTER + 1, 0xFE);
+        for _ in 0..3 {
+            console.run_cycles(CYCLES_PER_FRAME, None);
+        }
+        // the frame counter wraps around, while the vblank count holds until cleared
+        assert_eq!((console.devola.peek(FRAME_COUNTER), console.devola.peek(FRAME_COUNTER + 1)), (0, 1));
+        assert_eq!(console.devola.peek(VBLANK_COUNT), 3);
+        console.devola.poke(VBLANK_COUNT, 0);
+        console.run_cycles(CYCLES_PER_FRAME, None);
+        assert_eq!(console.devola.peek(VBLANK_COUNT), 1);
+
+        console.devola.poke(VBLANK_COUNT, 255);
+        console.run_cycles(CYCLES_PER_FRAME, None);
+        assert_eq!(console.devola.peek(VBLANK_COUNT), 255);
+    }
+
+    #[test]
+    fn test_joypad() {
+        let mut console = Console::new(Devola::new(Vec::new(), None));
+
+        // buttons pressed partway through a frame only show up once the next frame starts
+        console.run_cycles(100, None);
+        console.joypad.set_button(JOYPAD_A, true);
+        assert_eq!(console.devola.peek(JOYPAD), 0);
+        console.run_cycles(CYCLES_PER_FRAME - 100, None);
+        assert_eq!(console.devola.peek(JOYPAD), JOYPAD_A);
+    }
+}
@@ -0,0 +1,171 @@
+use devola::gdb::{self, GdbStub, Incoming, Packets, Reply};
+use devola::vm::Devola;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+
+/// Something the application has to act on, from a GDB connection
+#[derive(Debug, PartialEq)]
+pub enum GdbEvent {
+    /// GDB connected; the console should pause
+    Attached,
+    /// GDB asked for a single instruction to be executed; `stopped` should be called after it
+    Step,
+    /// GDB asked for the console to run until it hits a breakpoint
+    Continue,
+    /// GDB asked for the running console to pause; `stopped` should be called once it has
+    Interrupt,
+    Detached
+}
+
+/// Waits for GDB to attach at an address, and talks to it once it has. Everything is non-blocking, so the
+/// server can be polled from the event loop.
+pub struct GdbServer {
+    listener: TcpListener,
+    connection: Option<TcpStream>,
+    packets: Packets,
+    stub: GdbStub
+}
+
+impl GdbServer {
+    pub fn listen(address: &str) -> io::Result<GdbServer> {
+        let listener = TcpListener::bind(address)?;
+        listener.set_nonblocking(true)?;
+        Ok(GdbServer { listener, connection: None, packets: Packets::default(), stub: GdbStub::default() })
+    }
+
+    /// Where GDB can attach
+    pub fn address(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    pub fn connected(&self) -> bool {
+        self.connection.is_some()
+    }
+
+    /// Whether the console should stop before executing the instruction at `pc`
+    pub fn is_breakpoint(&self, pc: usize) -> bool {
+        self.connection.is_some() && self.stub.breakpoints().contains(&pc)
+    }
+
+    pub fn has_breakpoints(&self) -> bool {
+        self.connection.is_some() && !self.stub.breakpoints().is_empty()
+    }
+
+    fn send(&mut self, bytes: &[u8]) {
+        if let Some(connection) = self.connection.as_mut() {
+            // the socket is non-blocking, but replies are small enough to always fit in its buffer
+            if connection.write_all(bytes).is_err() {
+                self.connection = None;
+            }
+        }
+    }
+
+    /// Tells GDB that the console has stopped with a signal
+    pub fn stopped(&mut self, signal: u8) {
+        self.send(&gdb::frame(&gdb::stop_reply(signal)));
+    }
+
+    /// Accepts a connection, or handles the packets that have arrived on it until one needs the application to
+    /// act. Packets are answered using `devola`.
+    pub fn poll(&mut self, devola: &mut Devola) -> Option<GdbEvent> {
+        let connection = match self.connection.as_mut() {
+            Some(connection) => connection,
+            None => {
+                let (connection, _) = self.listener.accept().ok()?;
+                connection.set_nonblocking(true).ok()?;
+                connection.set_nodelay(true).ok()?;
+                self.connection = Some(connection);
+                // nothing carries over from an earlier connection
+                self.packets = Packets::default();
+                self.stub = GdbStub::default();
+                return Some(GdbEvent::Attached);
+            }
+        };
+
+        let mut buffer = [0; 4096];
+        match connection.read(&mut buffer) {
+            Ok(0) => {
+                self.connection = None;
+                return Some(GdbEvent::Detached);
+            },
+            Ok(read) => self.packets.push(&buffer[..read]),
+            Err(error) if error.kind() == ErrorKind::WouldBlock => (),
+            Err(_) => {
+                self.connection = None;
+                return Some(GdbEvent::Detached);
+            }
+        }
+
+        while let Some(incoming) = self.packets.next() {
+            let packet = match incoming {
+                Incoming::Packet(packet) => packet,
+                Incoming::Corrupt => {
+                    self.send(b"-");
+                    continue;
+                },
+                Incoming::Interrupt => return Some(GdbEvent::Interrupt)
+            };
+            self.send(b"+");
+            match self.stub.handle(&packet, devola) {
+                Reply::Send(reply) => self.send(&gdb::frame(&reply)),
+                Reply::Step => return Some(GdbEvent::Step),
+                Reply::Continue => return Some(GdbEvent::Continue),
+                Reply::Detach => {
+                    self.send(&gdb::frame("OK"));
+                    self.connection = None;
+                    return Some(GdbEvent::Detached);
+                }
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use devola::instructions::Instruction;
+    use std::time::Duration;
+
+    /// Polls until something happens, as the client's bytes may take a moment to arrive
+    fn poll(server: &mut GdbServer, devola: &mut Devola) -> Option<GdbEvent> {
+        for _ in 0..100 {
+            if let Some(event) = server.poll(devola) {
+                return Some(event);
+            }
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        None
+    }
+
+    fn receive(client: &mut TcpStream, expected: &[u8]) {
+        let mut received = vec![0; expected.len()];
+        client.read_exact(&mut received).unwrap();
+        assert_eq!(received, expected);
+    }
+
+    #[test]
+    fn test_gdb_server() {
+        let mut server = GdbServer::listen("127.0.0.1:0").unwrap();
+        let mut devola = Devola::new(vec![Instruction::Nop; 4], None);
+        let mut client = TcpStream::connect(server.address().unwrap()).unwrap();
+        assert_eq!(poll(&mut server, &mut devola), Some(GdbEvent::Attached));
+
+        // packets that only inspect the VM are answered straight away
+        client.write_all(&gdb::frame("Z0,2,1")).unwrap();
+        client.write_all(&gdb::frame("s")).unwrap();
+        assert_eq!(poll(&mut server, &mut devola), Some(GdbEvent::Step));
+        receive(&mut client, b"+$OK#9a+");
+        assert!(server.is_breakpoint(2));
+
+        server.stopped(gdb::SIGTRAP);
+        receive(&mut client, b"$S05#b8");
+        client.write_all(b"\x03").unwrap();
+        assert_eq!(poll(&mut server, &mut devola), Some(GdbEvent::Interrupt));
+
+        drop(client);
+        assert_eq!(poll(&mut server, &mut devola), Some(GdbEvent::Detached));
+        assert!(!server.connected());
+        assert!(!server.is_breakpoint(2));
+    }
+}
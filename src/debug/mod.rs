@@ -0,0 +1,5 @@
+pub mod gdb;
+pub mod overlays;
+pub mod reload;
+pub mod viewer;
+pub mod window;
\ No newline at end of file
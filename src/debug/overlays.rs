@@ -0,0 +1,151 @@
+use crate::inter::mmio::*;
+use crate::gfx::*;
+use crate::render::overlay::{draw_text, put_pixel, GLYPH_HEIGHT};
+
+/// Box colors for sprites of each priority
+const PRIORITY_COLORS: [Color; 4] = [
+    Color { r: 248, g: 248, b: 0 },
+    Color { r: 0, g: 248, b: 248 },
+    Color { r: 248, g: 0, b: 248 },
+    Color { r: 248, g: 128, b: 0 }
+];
+const GRID_COLOR: Color = Color { r: 248, g: 248, b: 248 };
+/// Lines along the top and left edges of the map, where it wraps around
+const MAP_EDGE_COLOR: Color = Color::RED;
+
+/// Debugging aids drawn over the composed frame
+#[derive(Debug, Default, PartialEq, Copy, Clone)]
+pub struct DebugOverlays {
+    /// Outlines every enabled sprite, labelled with its index and priority
+    pub sprite_boxes: bool,
+    /// Draws the tile grid of a background, following its scroll
+    pub tile_grid: Option<usize>
+}
+
+impl DebugOverlays {
+    /// Steps the tile grid through each background, then back to off
+    pub fn cycle_tile_grid(&mut self) {
+        self.tile_grid = match self.tile_grid {
+            None => Some(0),
+            Some(index) if index + 1 < BG_COUNT => Some(index + 1),
+            Some(_) => None
+        };
+    }
+
+    pub fn draw(&self, vram: &VRAMModel, frame: &mut [u8]) {
+        if let Some(bg_index) = self.tile_grid {
+            DebugOverlays::draw_tile_grid(vram, bg_index, frame);
+        }
+        if self.sprite_boxes {
+            DebugOverlays::draw_sprite_boxes(vram, frame);
+        }
+    }
+
+    /// Mixes the color half and half with the pixel underneath, so that the frame shows through the grid
+    fn blend_pixel(frame: &mut [u8], x: usize, y: usize, color: Color) {
+        let start = (y*SCREEN_WIDTH as usize + x)*4;
+        for (channel, value) in frame[start..start+3].iter_mut().zip([color.r, color.g, color.b]) {
+            *channel = ((*channel as u16 + value as u16) / 2) as u8;
+        }
+    }
+
+    fn draw_tile_grid(vram: &VRAMModel, bg_index: usize, frame: &mut [u8]) {
+        let background = &vram.backgrounds[bg_index];
+        let (scroll_x, scroll_y) = vram.bg_scroll[bg_index];
+        let (map_width, map_height) = (background.width*TILE_LENGTH, background.height*TILE_LENGTH);
+
+        for y in 0..SCREEN_HEIGHT as usize {
+            let bg_y = (y + scroll_y as usize) % map_height;
+            for x in 0..SCREEN_WIDTH as usize {
+                let bg_x = (x + scroll_x as usize) % map_width;
+                if bg_x == 0 || bg_y == 0 {
+                    DebugOverlays::blend_pixel(frame, x, y, MAP_EDGE_COLOR);
+                } else if bg_x.is_multiple_of(TILE_LENGTH) || bg_y.is_multiple_of(TILE_LENGTH) {
+                    DebugOverlays::blend_pixel(frame, x, y, GRID_COLOR);
+                }
+            }
+        }
+        draw_text(frame, SCREEN_WIDTH as usize, (0, SCREEN_HEIGHT as usize - GLYPH_HEIGHT - 2), &[format!("BG {} GRID", bg_index)], GRID_COLOR);
+    }
+
+    fn draw_sprite_boxes(vram: &VRAMModel, frame: &mut [u8]) {
+        let width = SCREEN_WIDTH as usize;
+        // drawn back to front, so that the labels of sprites drawn on top stay readable
+        for (index, sprite) in vram.sprites.iter().enumerate().rev() {
+            if !sprite.enabled() {
+                continue;
+            }
+            let color = PRIORITY_COLORS[sprite.properties.priority as usize];
+            let (left, top) = (sprite.location.0 as usize, sprite.location.1 as usize);
+            let (sprite_width, sprite_height) = sprite.dimensions();
+            let (right, bottom) = (left + sprite_width as usize - 1, top + sprite_height as usize - 1);
+
+            // put_pixel clips the parts of the box past the right edge of the frame
+            for x in left..=right {
+                put_pixel(frame, width, x, top, color);
+                put_pixel(frame, width, x, bottom, color);
+            }
+            for y in top..=bottom {
+                put_pixel(frame, width, left, y, color);
+                put_pixel(frame, width, right, y, color);
+            }
+            let label = format!("{} P{}", index, sprite.properties.priority);
+            draw_text(frame, width, (left + 1, top + 1), &[label], color);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pixel(frame: &[u8], x: usize, y: usize) -> [u8; 4] {
+        let start = (SCREEN_WIDTH as usize*y + x)*4;
+        frame[start..start+4].try_into().unwrap()
+    }
+
+    #[test]
+    fn test_cycle_tile_grid() {
+        let mut overlays = DebugOverlays::default();
+        let mut grids = Vec::new();
+        for _ in 0..=BG_COUNT {
+            overlays.cycle_tile_grid();
+            grids.push(overlays.tile_grid);
+        }
+        assert_eq!(grids, vec![Some(0), Some(1), Some(2), Some(3), None]);
+    }
+
+    #[test]
+    fn test_draw() {
+        let mut vram = VRAMModel::empty_vram();
+        vram.bg_scroll[1] = (3, 0);
+        vram.sprites[2] = Sprite {
+            properties: SpriteProperties { tilemap_index: 0, size: SpriteSize::X16, palette_index: 0, priority: 1 },
+            location: (100, 50),
+            gfx_start: 0,
+            info: 0x01
+        };
+        let mut frame = vec![0; (SCREEN_WIDTH*SCREEN_HEIGHT*4) as usize];
+        let overlays = DebugOverlays { sprite_boxes: true, tile_grid: Some(1) };
+        overlays.draw(&vram, &mut frame);
+
+        // the grid follows the scroll, with the map edge marked where it wraps
+        let edge = [124, 0, 0, 0];
+        let grid = [124, 124, 124, 0];
+        assert_eq!(pixel(&frame, 5, 20), grid);
+        assert_eq!(pixel(&frame, 6, 20), [0; 4]);
+        assert_eq!(pixel(&frame, 20, 16), grid);
+        assert_eq!(pixel(&frame, 20, 0), edge);
+        assert_eq!(pixel(&frame, BG_WIDTH*TILE_LENGTH - 3, 20), edge);
+
+        // the sprite is outlined in its priority's color, with its label inside
+        let color = PRIORITY_COLORS[1];
+        let color = [color.r, color.g, color.b, 0xFF];
+        assert_eq!(pixel(&frame, 100, 50), color);
+        assert_eq!(pixel(&frame, 115, 65), color);
+        assert_eq!(pixel(&frame, 115, 62), color);
+        assert_eq!(pixel(&frame, 101, 51), [0, 0, 0, 0xFF]);
+        // the "2" in the label
+        assert_eq!(pixel(&frame, 103, 52), color);
+    }
+}
@@ -0,0 +1,94 @@
+use devola::binary::{DecodeError, RomImage};
+use devola::hotswap::Messages;
+use devola::parser::intermediate::SymbolTable;
+use std::io::{self, ErrorKind, Read};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+
+/// Waits for `devola-asm` to send re-assembled programs, one per connection. Everything is non-blocking, so the
+/// server can be polled from the event loop.
+pub struct ReloadServer {
+    listener: TcpListener,
+    connection: Option<TcpStream>,
+    messages: Messages
+}
+
+impl ReloadServer {
+    pub fn listen(address: &str) -> io::Result<ReloadServer> {
+        let listener = TcpListener::bind(address)?;
+        listener.set_nonblocking(true)?;
+        Ok(ReloadServer { listener, connection: None, messages: Messages::default() })
+    }
+
+    /// Where programs can be sent
+    pub fn address(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Accepts a connection, or reads from it until a whole program has arrived
+    pub fn poll(&mut self) -> Option<Result<(RomImage, SymbolTable), DecodeError>> {
+        if let Some(message) = self.messages.next_message() {
+            return Some(message);
+        }
+        let connection = match self.connection.as_mut() {
+            Some(connection) => connection,
+            None => {
+                let (connection, _) = self.listener.accept().ok()?;
+                connection.set_nonblocking(true).ok()?;
+                self.connection = Some(connection);
+                self.messages = Messages::default();
+                self.connection.as_mut()?
+            }
+        };
+
+        let mut buffer = [0; 4096];
+        loop {
+            match connection.read(&mut buffer) {
+                Ok(0) => {
+                    self.connection = None;
+                    break;
+                },
+                Ok(read) => self.messages.push(&buffer[..read]),
+                Err(error) if error.kind() == ErrorKind::WouldBlock => break,
+                Err(_) => {
+                    self.connection = None;
+                    break;
+                }
+            }
+        }
+        self.messages.next_message()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use devola::hotswap;
+    use devola::instructions::Instruction;
+    use std::io::Write;
+    use std::time::Duration;
+
+    #[test]
+    fn test_reload_server() {
+        let mut server = ReloadServer::listen("127.0.0.1:0").unwrap();
+        assert!(server.poll().is_none());
+
+        let code = vec![Instruction::Nop, Instruction::Increment];
+        let symbols = SymbolTable::from([(0, String::from("start"))]);
+        let mut client = TcpStream::connect(server.address().unwrap()).unwrap();
+        client.write_all(&hotswap::encode(&RomImage::new(code.clone()), &symbols).unwrap()).unwrap();
+        drop(client);
+
+        // the program may take a moment to arrive
+        let mut received = None;
+        for _ in 0..100 {
+            received = server.poll();
+            if received.is_some() {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        let (received_image, received_symbols) = received.unwrap().unwrap();
+        assert_eq!(format!("{:?}", received_image.code), format!("{:?}", code));
+        assert_eq!(received_symbols, symbols);
+    }
+}
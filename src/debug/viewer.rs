@@ -0,0 +1,368 @@
+use crate::inter::input_map::{Input, InputMap, JOYPAD_BUTTONS};
+use crate::inter::mmio::*;
+use crate::gfx::*;
+use crate::render::overlay::{draw_text, put_pixel, GLYPH_HEIGHT};
+use crate::settings::DebuggerColors;
+
+use winit::keyboard::KeyCode;
+
+pub const DEBUG_WIDTH: u32 = 704;
+pub const DEBUG_HEIGHT: u32 = 320;
+
+const SWATCH_SIZE: usize = 8;
+const SPRITE_CELL_SIZE: usize = TILE_LENGTH + 2;
+const SPRITE_COLUMNS: usize = 16;
+const BINDING_ROW_HEIGHT: usize = GLYPH_HEIGHT + 2;
+const BINDING_WIDTH: usize = 288;
+
+// Top left corners of each panel; labels are drawn just above them
+const PALETTE_ORIGIN: (usize, usize) = (8, 14);
+const SPRITE_ORIGIN: (usize, usize) = (8, 94);
+const TILEMAP_ORIGINS: [(usize, usize); TILEMAP_COUNT] = [(304, 14), (304, 160)];
+const BG_ORIGIN: (usize, usize) = (440, 14);
+const BINDING_ORIGIN: (usize, usize) = (8, 190);
+const INFO_ORIGIN: (usize, usize) = (8, 304);
+
+/// An item picked in one of the viewers
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Selection {
+    Color { palette: usize, color: usize },
+    Tile { tilemap: usize, tile: usize },
+    MapEntry { background: usize, x: usize, y: usize },
+    Sprite(usize),
+    /// A joypad button, waiting for a key to be bound to it
+    Binding(usize)
+}
+
+impl Selection {
+    /// The address of the selected item in memory
+    pub fn address(&self, vram: &VRAMModel) -> u16 {
+        match *self {
+            Selection::Color { palette, color } => PALETTE_START + (palette*PALETTE_SIZE + color*COLOR_SIZE) as u16,
+            Selection::Tile { tilemap, tile } => TILEMAP_START + (tilemap*TILEMAP_SIZE + tile*TILE_SIZE) as u16,
+            Selection::MapEntry { background, x, y } => {
+                let map = &vram.backgrounds[background];
+                map.address.wrapping_add(((y*map.width + x)*BG_ENTRY_SIZE) as u16)
+            },
+            Selection::Sprite(index) => SPRITE_START + (index*SPRITE_SIZE) as u16,
+            Selection::Binding(_) => JOYPAD
+        }
+    }
+
+    pub fn describe(&self, vram: &VRAMModel) -> String {
+        let address = self.address(vram);
+        match *self {
+            Selection::Color { palette, color } => {
+                let Color { r, g, b } = vram.palettes[palette].colors[color];
+                format!("PALETTE {} COLOR {}: {:04X} (RGB {},{},{})", palette, color, address, r, g, b)
+            },
+            Selection::Tile { tilemap, tile } => format!("TILEMAP {} TILE {}: {:04X}", tilemap, tile, address),
+            Selection::MapEntry { background, x, y } => {
+                let entry = vram.backgrounds[background].tile_at(x, y);
+                let attributes = entry.attributes;
+                format!(
+                    "BG {} TILE {},{}: {:04X} (TILE {}, PALETTE {}, PRIORITY {}{}{})",
+                    background, x, y, address, entry.index, attributes.palette_index, attributes.priority,
+                    if attributes.h_flip { ", H FLIP" } else { "" }, if attributes.v_flip { ", V FLIP" } else { "" }
+                )
+            },
+            Selection::Sprite(index) => {
+                let sprite = &vram.sprites[index];
+                format!(
+                    "SPRITE {}: {:04X} (X {}, Y {}, TILE {}, PALETTE {}, {})",
+                    index, address, sprite.location.0, sprite.location.1, sprite.gfx_start,
+                    sprite.properties.palette_index, if sprite.enabled() { "ENABLED" } else { "DISABLED" }
+                )
+            },
+            Selection::Binding(index) => format!("JOYPAD {}: {:04X} - PRESS A KEY TO BIND IT", JOYPAD_BUTTONS[index].0.to_uppercase(), address)
+        }
+    }
+
+    /// The area of the debug frame highlighted for this selection: x, y, width, height
+    fn rect(&self) -> (usize, usize, usize, usize) {
+        match *self {
+            Selection::Color { palette, color } =>
+                (PALETTE_ORIGIN.0 + color*SWATCH_SIZE, PALETTE_ORIGIN.1 + palette*SWATCH_SIZE, SWATCH_SIZE, SWATCH_SIZE),
+            Selection::Tile { tilemap, tile } => {
+                let (left, top) = TILEMAP_ORIGINS[tilemap];
+                let (x, y) = (tile % TILEMAP_LENGTH, tile / TILEMAP_LENGTH);
+                (left + x*TILE_LENGTH, top + y*TILE_LENGTH, TILE_LENGTH, TILE_LENGTH)
+            },
+            Selection::MapEntry { x, y, .. } => (BG_ORIGIN.0 + x*TILE_LENGTH, BG_ORIGIN.1 + y*TILE_LENGTH, TILE_LENGTH, TILE_LENGTH),
+            Selection::Sprite(index) => {
+                let (x, y) = (index % SPRITE_COLUMNS, index / SPRITE_COLUMNS);
+                (SPRITE_ORIGIN.0 + x*SPRITE_CELL_SIZE, SPRITE_ORIGIN.1 + y*SPRITE_CELL_SIZE, SPRITE_CELL_SIZE, SPRITE_CELL_SIZE)
+            },
+            Selection::Binding(index) => (BINDING_ORIGIN.0, BINDING_ORIGIN.1 + index*BINDING_ROW_HEIGHT, BINDING_WIDTH, BINDING_ROW_HEIGHT)
+        }
+    }
+}
+
+/// Draws decoded palettes, tilemaps, a background map, the sprite table and the joypad bindings side by side
+/// into an RGBA frame of `DEBUG_WIDTH` by `DEBUG_HEIGHT` pixels. Clicking an item selects it, showing its address.
+pub struct DebugView {
+    /// The background shown in the map viewer
+    pub background: usize,
+    /// The palette tilemaps are drawn with, picked by clicking a palette
+    pub tile_palette: usize,
+    pub selection: Option<Selection>,
+    pub colors: DebuggerColors
+}
+
+impl Default for DebugView {
+    fn default() -> Self {
+        DebugView::new()
+    }
+}
+
+impl DebugView {
+    pub fn new() -> DebugView {
+        DebugView { background: 0, tile_palette: 0, selection: None, colors: DebuggerColors::default() }
+    }
+
+    /// Finds the item under a point of the debug frame
+    pub fn hit_test(&self, vram: &VRAMModel, x: usize, y: usize) -> Option<Selection> {
+        let within = |origin: (usize, usize), width: usize, height: usize| {
+            (origin.0..origin.0 + width).contains(&x) && (origin.1..origin.1 + height).contains(&y)
+        };
+
+        if within(PALETTE_ORIGIN, PALETTE_LENGTH*SWATCH_SIZE, PALETTE_COUNT*SWATCH_SIZE) {
+            return Some(Selection::Color {
+                palette: (y - PALETTE_ORIGIN.1) / SWATCH_SIZE,
+                color: (x - PALETTE_ORIGIN.0) / SWATCH_SIZE
+            });
+        }
+        let sprite_rows = SPRITE_COUNT / SPRITE_COLUMNS;
+        if within(SPRITE_ORIGIN, SPRITE_COLUMNS*SPRITE_CELL_SIZE, sprite_rows*SPRITE_CELL_SIZE) {
+            let (column, row) = ((x - SPRITE_ORIGIN.0) / SPRITE_CELL_SIZE, (y - SPRITE_ORIGIN.1) / SPRITE_CELL_SIZE);
+            return Some(Selection::Sprite(row*SPRITE_COLUMNS + column));
+        }
+        for (tilemap, origin) in TILEMAP_ORIGINS.iter().enumerate() {
+            let size = TILEMAP_LENGTH*TILE_LENGTH;
+            if within(*origin, size, size) {
+                let (column, row) = ((x - origin.0) / TILE_LENGTH, (y - origin.1) / TILE_LENGTH);
+                return Some(Selection::Tile { tilemap, tile: row*TILEMAP_LENGTH + column });
+            }
+        }
+        let map = &vram.backgrounds[self.background];
+        if within(BG_ORIGIN, map.width.min(BG_WIDTH)*TILE_LENGTH, map.height.min(BG_HEIGHT)*TILE_LENGTH) {
+            return Some(Selection::MapEntry {
+                background: self.background,
+                x: (x - BG_ORIGIN.0) / TILE_LENGTH,
+                y: (y - BG_ORIGIN.1) / TILE_LENGTH
+            });
+        }
+        if within(BINDING_ORIGIN, BINDING_WIDTH, JOYPAD_BUTTONS.len()*BINDING_ROW_HEIGHT) {
+            return Some(Selection::Binding((y - BINDING_ORIGIN.1) / BINDING_ROW_HEIGHT));
+        }
+        None
+    }
+
+    /// Selects whatever is under the point, if anything. Clicking a palette also draws the tilemaps with it.
+    pub fn click(&mut self, vram: &VRAMModel, x: usize, y: usize) {
+        self.selection = self.hit_test(vram, x, y);
+        if let Some(Selection::Color { palette, .. }) = self.selection {
+            self.tile_palette = palette;
+        }
+    }
+
+    /// Binds a key to the selected joypad button, if one is selected. Returns whether the key was used.
+    pub fn bind_key(&mut self, input_map: &mut InputMap, key: KeyCode) -> bool {
+        match self.selection {
+            Some(Selection::Binding(index)) => {
+                if input_map.bind_key(JOYPAD_BUTTONS[index].1, key) {
+                    self.selection = None;
+                }
+                true
+            },
+            _ => false
+        }
+    }
+
+    pub fn render(&self, vram: &VRAMModel, input_map: &InputMap, frame: &mut [u8]) {
+        for pixel in frame.chunks_exact_mut(4) {
+            pixel.copy_from_slice(&[self.colors.background.r, self.colors.background.g, self.colors.background.b, 0xFF]);
+        }
+
+        self.render_palettes(vram, frame);
+        self.render_sprites(vram, frame);
+        for (tilemap, origin) in TILEMAP_ORIGINS.iter().enumerate() {
+            let label = format!("TILEMAP {} PAL {}", tilemap, self.tile_palette);
+            self.label(frame, *origin, label);
+            let (left, top) = *origin;
+            let palette = &vram.palettes[self.tile_palette];
+            for (index, tile) in vram.tilemaps[tilemap].tiles.iter().enumerate() {
+                let (x, y) = (index % TILEMAP_LENGTH, index / TILEMAP_LENGTH);
+                DebugView::draw_tile(frame, tile, palette, (left + x*TILE_LENGTH, top + y*TILE_LENGTH), (false, false));
+            }
+        }
+        self.render_background(vram, frame);
+        self.render_bindings(input_map, frame);
+
+        if let Some(selection) = self.selection {
+            let (x, y, width, height) = selection.rect();
+            for offset in 0..width {
+                put_pixel(frame, DEBUG_WIDTH as usize, x + offset, y, self.colors.highlight);
+                put_pixel(frame, DEBUG_WIDTH as usize, x + offset, y + height - 1, self.colors.highlight);
+            }
+            for offset in 0..height {
+                put_pixel(frame, DEBUG_WIDTH as usize, x, y + offset, self.colors.highlight);
+                put_pixel(frame, DEBUG_WIDTH as usize, x + width - 1, y + offset, self.colors.highlight);
+            }
+            draw_text(frame, DEBUG_WIDTH as usize, INFO_ORIGIN, &[selection.describe(vram)], self.colors.label);
+        }
+    }
+
+    fn label(&self, frame: &mut [u8], origin: (usize, usize), text: String) {
+        draw_text(frame, DEBUG_WIDTH as usize, (origin.0, origin.1 - GLYPH_HEIGHT - 4), &[text], self.colors.label);
+    }
+
+    fn draw_tile(frame: &mut [u8], tile: &Tile, palette: &Palette, origin: (usize, usize), flip: (bool, bool)) {
+        for (index, palette_index) in tile.pixels.iter().enumerate() {
+            let (mut x, mut y) = (index % TILE_LENGTH, index / TILE_LENGTH);
+            if flip.0 { x = TILE_LENGTH - 1 - x; }
+            if flip.1 { y = TILE_LENGTH - 1 - y; }
+            // full color tiles are shown as their low nibbles, the same as in the 16-color modes
+            put_pixel(frame, DEBUG_WIDTH as usize, origin.0 + x, origin.1 + y, palette.colors[(*palette_index & 0x0F) as usize]);
+        }
+    }
+
+    fn render_palettes(&self, vram: &VRAMModel, frame: &mut [u8]) {
+        self.label(frame, PALETTE_ORIGIN, "PALETTES".to_string());
+        for (palette_index, palette) in vram.palettes.iter().enumerate() {
+            for (color_index, color) in palette.colors.iter().enumerate() {
+                for offset in 0..SWATCH_SIZE*SWATCH_SIZE {
+                    let x = PALETTE_ORIGIN.0 + color_index*SWATCH_SIZE + offset % SWATCH_SIZE;
+                    let y = PALETTE_ORIGIN.1 + palette_index*SWATCH_SIZE + offset / SWATCH_SIZE;
+                    put_pixel(frame, DEBUG_WIDTH as usize, x, y, *color);
+                }
+            }
+        }
+    }
+
+    /// Shows the first tile of every sprite, with disabled sprites dimmed
+    fn render_sprites(&self, vram: &VRAMModel, frame: &mut [u8]) {
+        self.label(frame, SPRITE_ORIGIN, "SPRITES".to_string());
+        for (index, sprite) in vram.sprites.iter().enumerate() {
+            let (column, row) = (index % SPRITE_COLUMNS, index / SPRITE_COLUMNS);
+            let left = SPRITE_ORIGIN.0 + column*SPRITE_CELL_SIZE + 1;
+            let top = SPRITE_ORIGIN.1 + row*SPRITE_CELL_SIZE + 1;
+            let tile = &vram.tilemaps[sprite.properties.tilemap_index as usize].tiles[sprite.gfx_start as usize];
+            let mut palette = vram.palettes[sprite.properties.palette_index as usize];
+            if !sprite.enabled() {
+                for color in palette.colors.iter_mut() {
+                    *color = Color { r: color.r / 3, g: color.g / 3, b: color.b / 3 };
+                }
+            }
+            DebugView::draw_tile(frame, tile, &palette, (left, top), (false, false));
+        }
+    }
+
+    /// Shows the top left of the selected background's map, ignoring scrolling
+    fn render_background(&self, vram: &VRAMModel, frame: &mut [u8]) {
+        let map = &vram.backgrounds[self.background];
+        let properties = vram.bg_properties[self.background];
+        let label = format!(
+            "BG {} ({}X{}, {}) KEYS 1-4",
+            self.background, map.width, map.height, if properties.enabled { "ENABLED" } else { "DISABLED" }
+        );
+        self.label(frame, BG_ORIGIN, label);
+
+        let tilemap = &vram.tilemaps[properties.tilemap_index as usize];
+        for y in 0..map.height.min(BG_HEIGHT) {
+            for x in 0..map.width.min(BG_WIDTH) {
+                let entry = map.tile_at(x, y);
+                let attributes = entry.attributes;
+                let palette = &vram.palettes[((properties.palette_index + attributes.palette_index) as usize) % PALETTE_COUNT];
+                let origin = (BG_ORIGIN.0 + x*TILE_LENGTH, BG_ORIGIN.1 + y*TILE_LENGTH);
+                DebugView::draw_tile(frame, &tilemap.tiles[entry.index as usize], palette, origin, (attributes.h_flip, attributes.v_flip));
+            }
+        }
+    }
+
+    fn render_bindings(&self, input_map: &InputMap, frame: &mut [u8]) {
+        self.label(frame, BINDING_ORIGIN, "JOYPAD - CLICK TO REBIND".to_string());
+        for (row, (name, button)) in JOYPAD_BUTTONS.iter().enumerate() {
+            let inputs: Vec<String> = input_map.inputs(*button).iter().map(|input| match input {
+                Input::Key(key) => format!("{:?}", key),
+                Input::Controller(button) => format!("PAD {:?}", button)
+            }).collect();
+            let text = format!("{:<6} {}", name.to_uppercase(), inputs.join(", "));
+            let origin = (BINDING_ORIGIN.0, BINDING_ORIGIN.1 + row*BINDING_ROW_HEIGHT);
+            draw_text(frame, DEBUG_WIDTH as usize, origin, &[text], self.colors.label);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pixel(frame: &[u8], x: usize, y: usize) -> [u8; 4] {
+        let start = (DEBUG_WIDTH as usize*y + x)*4;
+        frame[start..start+4].try_into().unwrap()
+    }
+
+    #[test]
+    fn test_hit_test() {
+        let mut vram = VRAMModel::empty_vram();
+        vram.backgrounds[2].address = 0x3000;
+        let mut view = DebugView::new();
+
+        let color = view.hit_test(&vram, PALETTE_ORIGIN.0 + 5*SWATCH_SIZE + 1, PALETTE_ORIGIN.1 + 3*SWATCH_SIZE);
+        assert_eq!(color, Some(Selection::Color { palette: 3, color: 5 }));
+        assert_eq!(color.unwrap().address(&vram), PALETTE_START + 3*PALETTE_SIZE as u16 + 10);
+
+        let tile = view.hit_test(&vram, TILEMAP_ORIGINS[1].0 + 2*TILE_LENGTH, TILEMAP_ORIGINS[1].1 + TILE_LENGTH);
+        assert_eq!(tile, Some(Selection::Tile { tilemap: 1, tile: 18 }));
+        assert_eq!(tile.unwrap().address(&vram), TILEMAP_START + (TILEMAP_SIZE + 18*TILE_SIZE) as u16);
+
+        let sprite = view.hit_test(&vram, SPRITE_ORIGIN.0 + SPRITE_CELL_SIZE, SPRITE_ORIGIN.1 + 2*SPRITE_CELL_SIZE);
+        assert_eq!(sprite, Some(Selection::Sprite(33)));
+        assert_eq!(sprite.unwrap().address(&vram), SPRITE_START + 33*SPRITE_SIZE as u16);
+
+        view.background = 2;
+        let entry = view.hit_test(&vram, BG_ORIGIN.0 + 4*TILE_LENGTH, BG_ORIGIN.1 + 7*TILE_LENGTH);
+        assert_eq!(entry, Some(Selection::MapEntry { background: 2, x: 4, y: 7 }));
+        assert_eq!(entry.unwrap().address(&vram), 0x3000 + ((7*BG_WIDTH + 4)*BG_ENTRY_SIZE) as u16);
+
+        assert_eq!(view.hit_test(&vram, 0, 0), None);
+    }
+
+    #[test]
+    fn test_bind_key() {
+        let vram = VRAMModel::empty_vram();
+        let mut input_map = InputMap::default();
+        let mut view = DebugView::new();
+
+        // keys only rebind while a joypad button is selected
+        assert!(!view.bind_key(&mut input_map, KeyCode::KeyQ));
+        view.click(&vram, BINDING_ORIGIN.0 + 10, BINDING_ORIGIN.1 + 5*BINDING_ROW_HEIGHT + 2);
+        assert_eq!(view.selection, Some(Selection::Binding(5)));
+        assert_eq!(view.selection.unwrap().address(&vram), JOYPAD);
+
+        // hotkeys can't be bound, and leave the button selected
+        assert!(view.bind_key(&mut input_map, KeyCode::F1));
+        assert_eq!(view.selection, Some(Selection::Binding(5)));
+        assert!(view.bind_key(&mut input_map, KeyCode::KeyQ));
+        assert_eq!(input_map.button(Input::Key(KeyCode::KeyQ)), Some(JOYPAD_B));
+        assert_eq!(view.selection, None);
+    }
+
+    #[test]
+    fn test_render() {
+        let mut vram = VRAMModel::empty_vram();
+        vram.palettes[2].colors[1] = Color::GREEN;
+        vram.tilemaps[0].tiles[0].pixels[0] = 1;
+        let mut view = DebugView::new();
+        let mut frame = vec![0; (DEBUG_WIDTH*DEBUG_HEIGHT*4) as usize];
+
+        // clicking a palette highlights it and draws the tilemaps with it
+        view.click(&vram, PALETTE_ORIGIN.0 + SWATCH_SIZE + 2, PALETTE_ORIGIN.1 + 2*SWATCH_SIZE + 2);
+        view.render(&vram, &InputMap::default(), &mut frame);
+        assert_eq!(view.tile_palette, 2);
+        assert_eq!(pixel(&frame, PALETTE_ORIGIN.0 + SWATCH_SIZE + 2, PALETTE_ORIGIN.1 + 2*SWATCH_SIZE + 2), [0, 248, 0, 0xFF]);
+        assert_eq!(pixel(&frame, PALETTE_ORIGIN.0 + SWATCH_SIZE, PALETTE_ORIGIN.1 + 2*SWATCH_SIZE), [0xFF; 4]);
+        assert_eq!(pixel(&frame, TILEMAP_ORIGINS[0].0, TILEMAP_ORIGINS[0].1), [0, 248, 0, 0xFF]);
+    }
+}
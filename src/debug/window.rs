@@ -0,0 +1,98 @@
+use crate::debug::viewer::{DebugView, Selection, DEBUG_WIDTH, DEBUG_HEIGHT};
+use crate::gfx::VRAMModel;
+use crate::inter::input_map::InputMap;
+use crate::settings::DebuggerColors;
+
+use pixels::{Pixels, SurfaceTexture};
+
+use winit::dpi::LogicalSize;
+use winit::event::{ElementState, MouseButton, WindowEvent};
+use winit::event_loop::ActiveEventLoop;
+use winit::keyboard::{KeyCode, PhysicalKey};
+use winit::window::{Window, WindowId};
+
+/// A second window showing the debug viewers
+pub struct DebugWindow {
+    window: Window,
+    pixels: Pixels,
+    view: DebugView,
+    cursor: (f32, f32)
+}
+
+impl DebugWindow {
+    /// Opens the window, or returns why it or its pixel buffer couldn't be created
+    pub fn new(event_loop: &ActiveEventLoop, colors: DebuggerColors) -> Result<DebugWindow, String> {
+        let size = LogicalSize::new(DEBUG_WIDTH, DEBUG_HEIGHT);
+        let window_attributes = Window::default_attributes()
+            .with_title("Popola debugger")
+            .with_inner_size(LogicalSize::new(2*DEBUG_WIDTH, 2*DEBUG_HEIGHT))
+            .with_min_inner_size(size);
+        let window = event_loop.create_window(window_attributes).map_err(|error| error.to_string())?;
+
+        let inner_size = window.inner_size();
+        let surface_texture = SurfaceTexture::new(inner_size.width, inner_size.height, &window);
+        let pixels = Pixels::new(DEBUG_WIDTH, DEBUG_HEIGHT, surface_texture).map_err(|error| error.to_string())?;
+
+        Ok(DebugWindow { window, pixels, view: DebugView { colors, ..DebugView::new() }, cursor: (0.0, 0.0) })
+    }
+
+    pub fn id(&self) -> WindowId {
+        self.window.id()
+    }
+
+    pub fn request_redraw(&self) {
+        self.window.request_redraw();
+    }
+
+    /// Handles an event for this window, which can change the joypad bindings. Returns false once the window
+    /// has been closed.
+    pub fn window_event(&mut self, vram: &VRAMModel, input_map: &mut InputMap, event: WindowEvent) -> bool {
+        match event {
+            WindowEvent::CloseRequested => return false,
+            // minimized windows report a size of 0, which the surface can't be resized to
+            WindowEvent::Resized(size) if size.width > 0 && size.height > 0 => {
+                if let Err(error) = self.pixels.resize_surface(size.width, size.height) {
+                    tracing::error!("Failed to resize debugger surface: {:?}", error);
+                }
+            },
+            WindowEvent::CursorMoved { position, .. } => self.cursor = (position.x as f32, position.y as f32),
+            WindowEvent::MouseInput { state: ElementState::Pressed, button: MouseButton::Left, .. } => {
+                if let Ok((x, y)) = self.pixels.window_pos_to_pixel(self.cursor) {
+                    self.view.click(vram, x, y);
+                    self.window.request_redraw();
+                }
+            },
+            WindowEvent::KeyboardInput { event, .. } if event.state.is_pressed() => {
+                if let PhysicalKey::Code(key) = event.physical_key {
+                    if self.view.bind_key(input_map, key) {
+                        self.window.request_redraw();
+                        return true;
+                    }
+                }
+                let background = match event.physical_key {
+                    PhysicalKey::Code(KeyCode::Digit1) => Some(0),
+                    PhysicalKey::Code(KeyCode::Digit2) => Some(1),
+                    PhysicalKey::Code(KeyCode::Digit3) => Some(2),
+                    PhysicalKey::Code(KeyCode::Digit4) => Some(3),
+                    _ => None
+                };
+                if let Some(background) = background {
+                    self.view.background = background;
+                    // map entries of the previous background no longer match what's shown
+                    if let Some(Selection::MapEntry { .. }) = self.view.selection {
+                        self.view.selection = None;
+                    }
+                    self.window.request_redraw();
+                }
+            },
+            WindowEvent::RedrawRequested => {
+                self.view.render(vram, input_map, self.pixels.frame_mut());
+                if let Err(error) = self.pixels.render() {
+                    tracing::error!("Failed to render debugger: {:?}", error);
+                }
+            },
+            _ => ()
+        }
+        true
+    }
+}
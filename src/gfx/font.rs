@@ -0,0 +1,219 @@
+//! A built-in 8x8 font covering printable ASCII, so that programs can draw text before they have tiles of their
+//! own, and that the text console and debug overlays are drawn in
+use crate::gfx::VRAMModel;
+use crate::inter::mmio::*;
+
+use devola::stdlib::interface::{DevolaExtern, DevolaExternTable};
+use devola::stdlib::read_string;
+use devola::utility::build_u16;
+use devola::vm::Devola;
+
+/// The first and last characters with glyphs
+pub const FIRST_CHARACTER: u8 = b' ';
+pub const LAST_CHARACTER: u8 = b'~';
+
+/// Each glyph is 8 rows of 8 pixels, with the low bit of the row on the left
+const FONT: [[u8; TILE_LENGTH]; (LAST_CHARACTER - FIRST_CHARACTER + 1) as usize] = [
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00], // ' '
+    [0x18, 0x3C, 0x3C, 0x18, 0x18, 0x00, 0x18, 0x00], // '!'
+    [0x36, 0x36, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00], // '"'
+    [0x36, 0x36, 0x7F, 0x36, 0x7F, 0x36, 0x36, 0x00], // '#'
+    [0x0C, 0x3E, 0x03, 0x1E, 0x30, 0x1F, 0x0C, 0x00], // '$'
+    [0x00, 0x63, 0x33, 0x18, 0x0C, 0x66, 0x63, 0x00], // '%'
+    [0x1C, 0x36, 0x1C, 0x6E, 0x3B, 0x33, 0x6E, 0x00], // '&'
+    [0x06, 0x06, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00], // "'"
+    [0x18, 0x0C, 0x06, 0x06, 0x06, 0x0C, 0x18, 0x00], // '('
+    [0x06, 0x0C, 0x18, 0x18, 0x18, 0x0C, 0x06, 0x00], // ')'
+    [0x00, 0x66, 0x3C, 0xFF, 0x3C, 0x66, 0x00, 0x00], // '*'
+    [0x00, 0x0C, 0x0C, 0x3F, 0x0C, 0x0C, 0x00, 0x00], // '+'
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x0C, 0x0C, 0x06], // ','
+    [0x00, 0x00, 0x00, 0x3F, 0x00, 0x00, 0x00, 0x00], // '-'
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x0C, 0x0C, 0x00], // '.'
+    [0x60, 0x30, 0x18, 0x0C, 0x06, 0x03, 0x01, 0x00], // '/'
+    [0x3E, 0x63, 0x73, 0x7B, 0x6F, 0x67, 0x3E, 0x00], // '0'
+    [0x0C, 0x0E, 0x0C, 0x0C, 0x0C, 0x0C, 0x3F, 0x00], // '1'
+    [0x1E, 0x33, 0x30, 0x1C, 0x06, 0x33, 0x3F, 0x00], // '2'
+    [0x1E, 0x33, 0x30, 0x1C, 0x30, 0x33, 0x1E, 0x00], // '3'
+    [0x38, 0x3C, 0x36, 0x33, 0x7F, 0x30, 0x78, 0x00], // '4'
+    [0x3F, 0x03, 0x1F, 0x30, 0x30, 0x33, 0x1E, 0x00], // '5'
+    [0x1C, 0x06, 0x03, 0x1F, 0x33, 0x33, 0x1E, 0x00], // '6'
+    [0x3F, 0x33, 0x30, 0x18, 0x0C, 0x0C, 0x0C, 0x00], // '7'
+    [0x1E, 0x33, 0x33, 0x1E, 0x33, 0x33, 0x1E, 0x00], // '8'
+    [0x1E, 0x33, 0x33, 0x3E, 0x30, 0x18, 0x0E, 0x00], // '9'
+    [0x00, 0x0C, 0x0C, 0x00, 0x00, 0x0C, 0x0C, 0x00], // ':'
+    [0x00, 0x0C, 0x0C, 0x00, 0x00, 0x0C, 0x0C, 0x06], // ';'
+    [0x18, 0x0C, 0x06, 0x03, 0x06, 0x0C, 0x18, 0x00], // '<'
+    [0x00, 0x00, 0x3F, 0x00, 0x00, 0x3F, 0x00, 0x00], // '='
+    [0x06, 0x0C, 0x18, 0x30, 0x18, 0x0C, 0x06, 0x00], // '>'
+    [0x1E, 0x33, 0x30, 0x18, 0x0C, 0x00, 0x0C, 0x00], // '?'
+    [0x3E, 0x63, 0x7B, 0x7B, 0x7B, 0x03, 0x1E, 0x00], // '@'
+    [0x0C, 0x1E, 0x33, 0x33, 0x3F, 0x33, 0x33, 0x00], // 'A'
+    [0x3F, 0x66, 0x66, 0x3E, 0x66, 0x66, 0x3F, 0x00], // 'B'
+    [0x3C, 0x66, 0x03, 0x03, 0x03, 0x66, 0x3C, 0x00], // 'C'
+    [0x1F, 0x36, 0x66, 0x66, 0x66, 0x36, 0x1F, 0x00], // 'D'
+    [0x7F, 0x46, 0x16, 0x1E, 0x16, 0x46, 0x7F, 0x00], // 'E'
+    [0x7F, 0x46, 0x16, 0x1E, 0x16, 0x06, 0x0F, 0x00], // 'F'
+    [0x3C, 0x66, 0x03, 0x03, 0x73, 0x66, 0x7C, 0x00], // 'G'
+    [0x33, 0x33, 0x33, 0x3F, 0x33, 0x33, 0x33, 0x00], // 'H'
+    [0x1E, 0x0C, 0x0C, 0x0C, 0x0C, 0x0C, 0x1E, 0x00], // 'I'
+    [0x78, 0x30, 0x30, 0x30, 0x33, 0x33, 0x1E, 0x00], // 'J'
+    [0x67, 0x66, 0x36, 0x1E, 0x36, 0x66, 0x67, 0x00], // 'K'
+    [0x0F, 0x06, 0x06, 0x06, 0x46, 0x66, 0x7F, 0x00], // 'L'
+    [0x63, 0x77, 0x7F, 0x7F, 0x6B, 0x63, 0x63, 0x00], // 'M'
+    [0x63, 0x67, 0x6F, 0x7B, 0x73, 0x63, 0x63, 0x00], // 'N'
+    [0x1C, 0x36, 0x63, 0x63, 0x63, 0x36, 0x1C, 0x00], // 'O'
+    [0x3F, 0x66, 0x66, 0x3E, 0x06, 0x06, 0x0F, 0x00], // 'P'
+    [0x1E, 0x33, 0x33, 0x33, 0x3B, 0x1E, 0x38, 0x00], // 'Q'
+    [0x3F, 0x66, 0x66, 0x3E, 0x36, 0x66, 0x67, 0x00], // 'R'
+    [0x1E, 0x33, 0x07, 0x0E, 0x38, 0x33, 0x1E, 0x00], // 'S'
+    [0x3F, 0x2D, 0x0C, 0x0C, 0x0C, 0x0C, 0x1E, 0x00], // 'T'
+    [0x33, 0x33, 0x33, 0x33, 0x33, 0x33, 0x3F, 0x00], // 'U'
+    [0x33, 0x33, 0x33, 0x33, 0x33, 0x1E, 0x0C, 0x00], // 'V'
+    [0x63, 0x63, 0x63, 0x6B, 0x7F, 0x77, 0x63, 0x00], // 'W'
+    [0x63, 0x63, 0x36, 0x1C, 0x1C, 0x36, 0x63, 0x00], // 'X'
+    [0x33, 0x33, 0x33, 0x1E, 0x0C, 0x0C, 0x1E, 0x00], // 'Y'
+    [0x7F, 0x63, 0x31, 0x18, 0x4C, 0x66, 0x7F, 0x00], // 'Z'
+    [0x1E, 0x06, 0x06, 0x06, 0x06, 0x06, 0x1E, 0x00], // '['
+    [0x03, 0x06, 0x0C, 0x18, 0x30, 0x60, 0x40, 0x00], // '\\'
+    [0x1E, 0x18, 0x18, 0x18, 0x18, 0x18, 0x1E, 0x00], // ']'
+    [0x08, 0x1C, 0x36, 0x63, 0x00, 0x00, 0x00, 0x00], // '^'
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xFF], // '_'
+    [0x0C, 0x0C, 0x18, 0x00, 0x00, 0x00, 0x00, 0x00], // '`'
+    [0x00, 0x00, 0x1E, 0x30, 0x3E, 0x33, 0x6E, 0x00], // 'a'
+    [0x07, 0x06, 0x06, 0x3E, 0x66, 0x66, 0x3B, 0x00], // 'b'
+    [0x00, 0x00, 0x1E, 0x33, 0x03, 0x33, 0x1E, 0x00], // 'c'
+    [0x38, 0x30, 0x30, 0x3E, 0x33, 0x33, 0x6E, 0x00], // 'd'
+    [0x00, 0x00, 0x1E, 0x33, 0x3F, 0x03, 0x1E, 0x00], // 'e'
+    [0x1C, 0x36, 0x06, 0x0F, 0x06, 0x06, 0x0F, 0x00], // 'f'
+    [0x00, 0x00, 0x6E, 0x33, 0x33, 0x3E, 0x30, 0x1F], // 'g'
+    [0x07, 0x06, 0x36, 0x6E, 0x66, 0x66, 0x67, 0x00], // 'h'
+    [0x0C, 0x00, 0x0E, 0x0C, 0x0C, 0x0C, 0x1E, 0x00], // 'i'
+    [0x30, 0x00, 0x30, 0x30, 0x30, 0x33, 0x33, 0x1E], // 'j'
+    [0x07, 0x06, 0x66, 0x36, 0x1E, 0x36, 0x67, 0x00], // 'k'
+    [0x0E, 0x0C, 0x0C, 0x0C, 0x0C, 0x0C, 0x1E, 0x00], // 'l'
+    [0x00, 0x00, 0x33, 0x7F, 0x7F, 0x6B, 0x63, 0x00], // 'm'
+    [0x00, 0x00, 0x1F, 0x33, 0x33, 0x33, 0x33, 0x00], // 'n'
+    [0x00, 0x00, 0x1E, 0x33, 0x33, 0x33, 0x1E, 0x00], // 'o'
+    [0x00, 0x00, 0x3B, 0x66, 0x66, 0x3E, 0x06, 0x0F], // 'p'
+    [0x00, 0x00, 0x6E, 0x33, 0x33, 0x3E, 0x30, 0x78], // 'q'
+    [0x00, 0x00, 0x3B, 0x6E, 0x66, 0x06, 0x0F, 0x00], // 'r'
+    [0x00, 0x00, 0x3E, 0x03, 0x1E, 0x30, 0x1F, 0x00], // 's'
+    [0x08, 0x0C, 0x3E, 0x0C, 0x0C, 0x2C, 0x18, 0x00], // 't'
+    [0x00, 0x00, 0x33, 0x33, 0x33, 0x33, 0x6E, 0x00], // 'u'
+    [0x00, 0x00, 0x33, 0x33, 0x33, 0x1E, 0x0C, 0x00], // 'v'
+    [0x00, 0x00, 0x63, 0x6B, 0x7F, 0x7F, 0x36, 0x00], // 'w'
+    [0x00, 0x00, 0x63, 0x36, 0x1C, 0x36, 0x63, 0x00], // 'x'
+    [0x00, 0x00, 0x33, 0x33, 0x33, 0x3E, 0x30, 0x1F], // 'y'
+    [0x00, 0x00, 0x3F, 0x19, 0x0C, 0x26, 0x3F, 0x00], // 'z'
+    [0x38, 0x0C, 0x0C, 0x07, 0x0C, 0x0C, 0x38, 0x00], // '{'
+    [0x18, 0x18, 0x18, 0x00, 0x18, 0x18, 0x18, 0x00], // '|'
+    [0x07, 0x0C, 0x0C, 0x38, 0x0C, 0x0C, 0x07, 0x00], // '}'
+    [0x6E, 0x3B, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00], // '~'
+];
+
+/// The glyph for a character, blank for anything outside of printable ASCII
+pub fn glyph(character: char) -> [u8; TILE_LENGTH] {
+    match u8::try_from(character) {
+        Ok(code @ FIRST_CHARACTER..=LAST_CHARACTER) => FONT[(code - FIRST_CHARACTER) as usize],
+        _ => [0; TILE_LENGTH]
+    }
+}
+
+/// Whether the pixel of a glyph at (`x`, `y`) from its top left is set
+pub fn glyph_pixel(glyph: &[u8; TILE_LENGTH], x: usize, y: usize) -> bool {
+    glyph[y] >> x & 1 == 1
+}
+
+/// Writes the font into a tilemap (wrapping around the tilemaps there are), as tiles of palette index `color`
+/// on transparent pixels. Each glyph is at the tile index of its character code, so text can be drawn by writing
+/// its characters straight into a background map.
+pub fn load_font(devola: &mut Devola, tilemap: usize, color: u8) {
+    let tilemap = tilemap % TILEMAP_COUNT;
+    for code in FIRST_CHARACTER..=LAST_CHARACTER {
+        let glyph = glyph(code as char);
+        let start = TILEMAP_START + (tilemap*TILEMAP_SIZE + code as usize*TILE_SIZE) as u16;
+        for y in 0..TILE_LENGTH {
+            for x in 0..TILE_LENGTH {
+                let pixel = if glyph_pixel(&glyph, x, y) { color } else { 0 };
+                devola.poke(start + (y*TILE_LENGTH + x) as u16, pixel);
+            }
+        }
+    }
+}
+
+/// Writes text into a background map (wrapping around the backgrounds there are) from the tile at (`x`, `y`),
+/// each character's code as its tile index with no attributes, for showing with the font loaded by `load_font`.
+/// A newline carries on from `x` on the next row, and anything past the edge of the map is left out.
+pub fn draw_text(devola: &mut Devola, background: usize, x: usize, y: usize, text: &str) {
+    let (start, width, height) = VRAMModel::background_layout(devola, background % BG_COUNT);
+    for (row, line) in text.split('\n').enumerate() {
+        for (column, code) in line.bytes().enumerate() {
+            let (tile_x, tile_y) = (x + column, y + row);
+            if tile_x >= width || tile_y >= height {
+                continue;
+            }
+            let address = start.wrapping_add(((tile_y*width + tile_x)*BG_ENTRY_SIZE) as u16);
+            devola.poke(address, code);
+            devola.poke(address.wrapping_add(1), 0);
+        }
+    }
+}
+
+/// `load_font(tilemap, color)`
+///
+/// Accepts arguments from the stack. Writes the font into `tilemap` in palette index `color`,
+/// each glyph at the tile index of its character.
+pub fn i_load_font(devola: &mut Devola) {
+    let (color, tilemap) = (devola.pop(), devola.pop());
+    load_font(devola, tilemap as usize, color);
+}
+
+/// `draw_text(background, x, y, text_hi, text_lo)`
+///
+/// Accepts arguments from the stack. Writes the string at `text`, up to its 0 terminator, into
+/// `background`'s map from tile (`x`, `y`), for showing with the font loaded by `load_font`.
+pub fn i_draw_text(devola: &mut Devola) {
+    let (text_lo, text_hi) = (devola.pop(), devola.pop());
+    let (y, x, background) = (devola.pop(), devola.pop(), devola.pop());
+    let text = read_string(devola, build_u16(text_hi, text_lo));
+    draw_text(devola, background as usize, x as usize, y as usize, &text);
+}
+
+/// The library functions Popola gives programs
+pub fn externs() -> DevolaExternTable {
+    let mut externs = DevolaExternTable::new();
+    externs.insert(String::from("load_font"), Box::new(i_load_font) as Box<DevolaExtern>);
+    externs.insert(String::from("draw_text"), Box::new(i_draw_text) as Box<DevolaExtern>);
+    externs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use devola::cartridge::Cartridge;
+
+    #[test]
+    fn test_font() {
+        assert_eq!(glyph('A'), [0x0C, 0x1E, 0x33, 0x33, 0x3F, 0x33, 0x33, 0x00]);
+        assert_eq!(glyph('\u{e9}'), [0; TILE_LENGTH]);
+
+        // "Hi" at 0200h, drawn at (3, 2) of background 1
+        let source = "lda 0\npush a\nlda 5\npush a\ncall load_font\nlda 1\npush a\nlda 3\npush a\nlda 2\npush a\nlda 02h\npush a\nlda 0\npush a\ncall draw_text";
+        let mut devola = Cartridge::compile_with_externs(String::from(source), externs()).unwrap().load().unwrap();
+        devola.set_externs(externs());
+        devola.poke(0x0200, b'H');
+        devola.poke(0x0201, b'i');
+        devola.run().unwrap();
+
+        // the top row of the A has its two middle pixels set, in palette index 5
+        let a = TILEMAP_START + (b'A' as usize*TILE_SIZE) as u16;
+        assert_eq!((0..TILE_LENGTH as u16).map(|x| devola.peek(a + x)).collect::<Vec<_>>(), [0, 0, 5, 5, 0, 0, 0, 0]);
+        let entry = BG_START + (BG_SIZE + (2*BG_WIDTH + 3)*BG_ENTRY_SIZE) as u16;
+        assert_eq!([0, 2].map(|offset| devola.peek(entry + offset)), [b'H', b'i']);
+
+        // text running off the edge of the map is cut off there
+        draw_text(&mut devola, 0, BG_WIDTH - 1, 0, "ok");
+        assert_eq!(devola.peek(BG_START + ((BG_WIDTH - 1)*BG_ENTRY_SIZE) as u16), b'o');
+        assert_eq!(devola.peek(BG_START + (BG_WIDTH*BG_ENTRY_SIZE) as u16), 0);
+    }
+}
@@ -1,2 +1,3 @@
+pub mod font;
 mod model;
 pub use model::*;
\ No newline at end of file
@@ -1,30 +1,118 @@
 use crate::inter::mmio;
 
-#[derive(Debug,Clone,Copy)]
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+#[derive(Debug,Clone,Copy,PartialEq,Serialize,Deserialize)]
 pub struct Color {
     pub r: u8,
     pub g: u8,
     pub b: u8
 }
 
-#[derive(Debug, Clone, Copy)]
+impl Color {
+    pub const BLACK: Color = Color { r: 0, g: 0, b: 0 };
+    pub const RED: Color = Color { r: 248, g: 0, b: 0 };
+    pub const GREEN: Color = Color { r: 0, g: 248, b: 0 };
+    pub const BLUE: Color = Color { r: 0, g: 0, b: 248 };
+    pub const WHITE: Color = Color { r: 248, g: 248, b: 248 };
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub struct Palette {
     pub colors: [Color; mmio::PALETTE_LENGTH]
 }
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 
 pub struct Tile {
     pub pixels: [u8; mmio::TILE_SIZE]
 }
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq)]
 
 pub struct Tilemap {
     pub tiles: [Tile; mmio::TILEMAP_LENGTH*mmio::TILEMAP_LENGTH]
 }
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 
 pub struct Background {
-    pub tiles: [u8; mmio::BG_SIZE]
+    /// Where the map was read from in memory
+    pub address: u16,
+    pub width: usize,
+    pub height: usize,
+    pub tiles: Vec<BackgroundTile>
+}
+
+impl Background {
+    /// Returns the map entry at the given tile coordinates, wrapping around the edges of the map
+    pub fn tile_at(&self, x: usize, y: usize) -> BackgroundTile {
+        self.tiles[(y % self.height)*self.width + (x % self.width)]
+    }
+}
+
+/// A single background map entry
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct BackgroundTile {
+    pub index: u8,
+    pub attributes: TileAttributes
+}
+
+impl BackgroundTile {
+    /// An entry for the given tile, with no attributes set
+    pub fn new(index: u8) -> Self {
+        Self { index, attributes: TileAttributes::from(0) }
+    }
+}
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct TileAttributes {
+    pub h_flip: bool,
+    pub v_flip: bool,
+    pub palette_index: u8,
+    pub priority: u8
+}
+
+impl From<u8> for TileAttributes {
+    fn from(value: u8) -> Self {
+        Self {
+            h_flip: value >> 7 == 1,
+            v_flip: (value >> 6) & 0b1 == 1,
+            palette_index: (value >> 2) & 0b111,
+            priority: value & 0b11
+        }
+    }
+}
+
+impl From<TileAttributes> for u8 {
+    fn from(attributes: TileAttributes) -> Self {
+        ((attributes.h_flip as u8) << 7) | ((attributes.v_flip as u8) << 6)
+            | (attributes.palette_index << 2) | attributes.priority
+    }
+}
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct BackgroundProperties {
+    pub enabled: bool,
+    pub tilemap_index: u8,
+    pub palette_index: u8,
+    pub priority: u8
+}
+
+impl From<u8> for BackgroundProperties {
+    fn from(value: u8) -> Self {
+        Self {
+            enabled: value >> 7 == 1,
+            tilemap_index: (value >> 6) & 0b1,
+            palette_index: (value >> 2) & 0b111,
+            priority: value & 0b11
+        }
+    }
+}
+
+impl From<BackgroundProperties> for u8 {
+    fn from(properties: BackgroundProperties) -> Self {
+        ((properties.enabled as u8) << 7) | (properties.tilemap_index << 6)
+            | (properties.palette_index << 2) | properties.priority
+    }
 }
 #[derive(Debug, PartialEq, Copy, Clone)]
 
@@ -46,6 +134,17 @@ impl From<u8> for SpriteSize {
     }
 }
 
+impl From<SpriteSize> for u8 {
+    fn from(sprite_size: SpriteSize) -> Self {
+        match sprite_size {
+            SpriteSize::X8 => 0,
+            SpriteSize::X16 => 1,
+            SpriteSize::X32 => 2,
+            SpriteSize::X64 => 3
+        }
+    }
+}
+
 impl SpriteSize {
     pub fn size(sprite_size: SpriteSize) -> usize {
         match sprite_size {
@@ -85,6 +184,13 @@ impl From<u8> for SpriteProperties {
     }
 }
 
+impl From<SpriteProperties> for u8 {
+    fn from(properties: SpriteProperties) -> Self {
+        (properties.tilemap_index << 7) | (u8::from(properties.size) << 5)
+            | (properties.palette_index << 2) | properties.priority
+    }
+}
+
 #[derive(Debug, PartialEq, Copy, Clone)]
 
 pub struct Sprite {
@@ -98,12 +204,311 @@ impl Sprite {
     pub fn enabled(&self) -> bool {
         self.info & 0b00000001 == 0b00000001
     }
+    /// Opaque sprites draw palette index 0 instead of treating it as transparent
+    pub fn opaque(&self) -> bool {
+        self.info & 0b00000010 == 0b00000010
+    }
+    /// Tall sprites are twice as high as they are wide, e.g. 8x16 for `SpriteSize::X8`
+    pub fn tall(&self) -> bool {
+        self.info & 0b00000100 == 0b00000100
+    }
+    /// Transformed sprites are drawn through the affine parameters selected by `affine_index`
+    pub fn transformed(&self) -> bool {
+        self.info & 0b00001000 == 0b00001000
+    }
+    pub fn affine_index(&self) -> u8 {
+        (self.info >> 4) & 0b111
+    }
+    /// The width and height of the sprite in pixels
+    pub fn dimensions(&self) -> (u32, u32) {
+        let pitch = SpriteSize::pitch(self.properties.size);
+        (pitch, if self.tall() { 2*pitch } else { pitch })
+    }
+    /// Whether the sprite is enabled and overlaps the given scanline
+    pub fn on_line(&self, y: usize) -> bool {
+        let top = self.location.1 as usize;
+        self.enabled() && (top..top + self.dimensions().1 as usize).contains(&y)
+    }
+}
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct AffineParameters {
+    pub pa: i16,
+    pub pb: i16,
+    pub pc: i16,
+    pub pd: i16
+}
+
+impl AffineParameters {
+    pub const IDENTITY: AffineParameters = AffineParameters { pa: 0x100, pb: 0, pc: 0, pd: 0x100 };
+
+    /// Maps a pixel in a `width` by `height` bounding box to the pixel of the untransformed sprite
+    /// it samples, rotating and scaling about the centre of the box. Returns `None` if the sampled
+    /// pixel lies outside the sprite.
+    pub fn transform(&self, x: usize, y: usize, width: usize, height: usize) -> Option<(usize, usize)> {
+        let (source_x, source_y) = self.transform_unclipped(x, y, width, height);
+
+        if (0..width as i32).contains(&source_x) && (0..height as i32).contains(&source_y) {
+            Some((source_x as usize, source_y as usize))
+        } else {
+            None
+        }
+    }
+
+    /// Like `transform`, but returns the sampled pixel even when it lies outside the box
+    pub fn transform_unclipped(&self, x: usize, y: usize, width: usize, height: usize) -> (i32, i32) {
+        let (width, height) = (width as i32, height as i32);
+        // offsets from the centre are measured in half pixels so that pixel centres line up
+        let (dx, dy) = (2*x as i32 + 1 - width, 2*y as i32 + 1 - height);
+        let source_x = ((self.pa as i32*dx + self.pb as i32*dy) >> 8) + width;
+        let source_y = ((self.pc as i32*dx + self.pd as i32*dy) >> 8) + height;
+        (source_x.div_euclid(2), source_y.div_euclid(2))
+    }
+}
+
+/// A rectangle of the screen, with inclusive bounds
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct Window {
+    pub left: u8,
+    pub top: u8,
+    pub right: u8,
+    pub bottom: u8
+}
+
+impl Window {
+    pub fn contains(&self, x: usize, y: usize) -> bool {
+        (self.left as usize..=self.right as usize).contains(&x) && (self.top as usize..=self.bottom as usize).contains(&y)
+    }
+}
+
+/// Selects the windows a layer is hidden inside of (or outside of, if inverted)
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct WindowMask {
+    pub windows: [bool; mmio::WINDOW_COUNT],
+    pub invert: bool
+}
+
+impl From<u8> for WindowMask {
+    fn from(value: u8) -> Self {
+        Self {
+            windows: [value & 0b001 == 0b001, value & 0b010 == 0b010],
+            invert: value & 0b100 == 0b100
+        }
+    }
+}
+
+impl From<WindowMask> for u8 {
+    fn from(mask: WindowMask) -> Self {
+        (mask.windows[0] as u8) | ((mask.windows[1] as u8) << 1) | ((mask.invert as u8) << 2)
+    }
+}
+
+impl WindowMask {
+    /// Whether a layer with this mask is hidden at the given screen coordinates
+    pub fn hides(&self, windows: &[Window; mmio::WINDOW_COUNT], x: usize, y: usize) -> bool {
+        if !self.windows.contains(&true) {
+            return false;
+        }
+        let inside = windows.iter()
+            .zip(self.windows)
+            .any(|(window, selected)| selected && window.contains(x, y));
+        inside != self.invert
+    }
 }
 
-#[derive(Debug)]
+/// Blends one layer with the layers drawn beneath it
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct ColorMath {
+    pub enabled: bool,
+    pub subtract: bool,
+    pub half: bool,
+    /// A background index, or `mmio::COLOR_MATH_SPRITE_LAYER` for sprites
+    pub layer: u8
+}
+
+impl From<u8> for ColorMath {
+    fn from(value: u8) -> Self {
+        Self {
+            enabled: value >> 7 == 1,
+            subtract: (value >> 6) & 0b1 == 1,
+            half: (value >> 5) & 0b1 == 1,
+            layer: value & 0b111
+        }
+    }
+}
+
+impl From<ColorMath> for u8 {
+    fn from(color_math: ColorMath) -> Self {
+        ((color_math.enabled as u8) << 7) | ((color_math.subtract as u8) << 6)
+            | ((color_math.half as u8) << 5) | color_math.layer
+    }
+}
+
+impl ColorMath {
+    /// Whether the given layer is blended
+    pub fn targets(&self, layer: u8) -> bool {
+        self.enabled && self.layer == layer
+    }
+
+    /// Blends a color of the selected layer with the color beneath it
+    pub fn blend(&self, under: Color, over: Color) -> Color {
+        // the brightest a channel of a 15-bit color can be
+        const MAX: i16 = 248;
+        let channel = |under: u8, over: u8| {
+            let value = if self.subtract { under as i16 - over as i16 } else { under as i16 + over as i16 };
+            let value = if self.half { value / 2 } else { value };
+            value.clamp(0, MAX) as u8
+        };
+        Color { r: channel(under.r, over.r), g: channel(under.g, over.g), b: channel(under.b, over.b) }
+    }
+}
+
+/// The arrangement of backgrounds selected by the video mode register
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum BgLayout {
+    /// Four 16-color backgrounds
+    Tiled,
+    /// Two 16-color backgrounds, and a third one that's rotated and scaled
+    Affine,
+    /// A single 128-color background
+    FullColor
+}
+
+impl BgLayout {
+    /// How many backgrounds are drawn, starting from BG 0
+    pub fn background_count(&self) -> usize {
+        match self {
+            BgLayout::Tiled => mmio::BG_COUNT,
+            BgLayout::Affine => 3,
+            BgLayout::FullColor => 1
+        }
+    }
+
+    /// The background that's rotated and scaled, if any
+    pub fn affine_background(&self) -> Option<usize> {
+        match self {
+            BgLayout::Affine => Some(2),
+            _ => None
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct VideoMode {
+    pub layout: BgLayout,
+    /// The affine parameters used by the affine background
+    pub affine_index: u8
+}
+
+impl From<u8> for VideoMode {
+    fn from(value: u8) -> Self {
+        Self {
+            layout: match value & 0b11 {
+                1 => BgLayout::Affine,
+                2 => BgLayout::FullColor,
+                _ => BgLayout::Tiled
+            },
+            affine_index: (value >> 4) & 0b111
+        }
+    }
+}
+
+impl From<VideoMode> for u8 {
+    fn from(mode: VideoMode) -> Self {
+        let layout = match mode.layout {
+            BgLayout::Tiled => 0,
+            BgLayout::Affine => 1,
+            BgLayout::FullColor => 2
+        };
+        (mode.affine_index << 4) | layout
+    }
+}
+
+/// A write to a register, applied just before the given scanline is drawn
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct ScanlineWrite {
+    pub scanline: u8,
+    pub address: u16,
+    pub value: u8
+}
+
+/// Tracks when each tile and palette was last changed, so that copies decoded from them can tell when
+/// they've gone stale. Every change takes a new generation from a counter shared by all models, so a
+/// changed copy of a model never reuses a generation of the original.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Generations {
+    pub tiles: [[u64; mmio::TILEMAP_LENGTH*mmio::TILEMAP_LENGTH]; mmio::TILEMAP_COUNT],
+    pub palettes: [u64; mmio::PALETTE_COUNT]
+}
+
+impl Default for Generations {
+    fn default() -> Self {
+        Generations { tiles: [[0; mmio::TILEMAP_LENGTH*mmio::TILEMAP_LENGTH]; mmio::TILEMAP_COUNT], palettes: [0; mmio::PALETTE_COUNT] }
+    }
+}
+
+impl Generations {
+    fn next() -> u64 {
+        static NEXT: AtomicU64 = AtomicU64::new(1);
+        NEXT.fetch_add(1, Ordering::Relaxed)
+    }
+
+    pub fn touch_tile(&mut self, tilemap_index: usize, tile_index: usize) {
+        self.tiles[tilemap_index][tile_index] = Generations::next();
+    }
+
+    pub fn touch_palette(&mut self, palette_index: usize) {
+        self.palettes[palette_index] = Generations::next();
+    }
+
+    pub fn touch_all(&mut self) {
+        let generation = Generations::next();
+        self.tiles = [[generation; mmio::TILEMAP_LENGTH*mmio::TILEMAP_LENGTH]; mmio::TILEMAP_COUNT];
+        self.palettes = [generation; mmio::PALETTE_COUNT];
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct VRAMModel {
     pub palettes: [Palette; mmio::PALETTE_COUNT],
     pub tilemaps: [Tilemap; mmio::TILEMAP_COUNT],
     pub backgrounds: [Background; mmio::BG_COUNT],
-    pub sprites: [Sprite; mmio::SPRITE_COUNT]
-}
\ No newline at end of file
+    pub bg_properties: [BackgroundProperties; mmio::BG_COUNT],
+    pub bg_scroll: [(u16, u16); mmio::BG_COUNT],
+    pub sprites: [Sprite; mmio::SPRITE_COUNT],
+    /// Whether any sprites are drawn at all, as set by the all off bit of the sprite toggles
+    pub sprites_enabled: bool,
+    pub affine_parameters: [AffineParameters; mmio::AFFINE_COUNT],
+    pub fade: u8,
+    pub windows: [Window; mmio::WINDOW_COUNT],
+    pub bg_window_masks: [WindowMask; mmio::BG_COUNT],
+    pub sprite_window_mask: WindowMask,
+    pub backdrop: Color,
+    pub color_math: ColorMath,
+    pub video_mode: VideoMode,
+    pub generations: Generations,
+    pub scanline_writes: Vec<ScanlineWrite>
+}
+
+impl VRAMModel {
+    /// Picks the sprites drawn on a scanline within the `mmio::SPRITE_LINE_PIXELS` limit. Returns whether
+    /// each sprite is drawn, and whether any were dropped.
+    pub fn line_sprites(&self, y: usize) -> ([bool; mmio::SPRITE_COUNT], bool) {
+        let mut drawn = [false; mmio::SPRITE_COUNT];
+        let mut candidates: Vec<usize> = (0..mmio::SPRITE_COUNT)
+            .filter(|index| self.sprites_enabled && self.sprites[*index].on_line(y))
+            .collect();
+        // highest priority first, then lowest index, matching the order sprites end up on top
+        candidates.sort_by_key(|index| (std::cmp::Reverse(self.sprites[*index].properties.priority), *index));
+
+        let mut pixels = 0;
+        for index in candidates {
+            pixels += self.sprites[index].dimensions().0 as usize;
+            if pixels > mmio::SPRITE_LINE_PIXELS {
+                return (drawn, true);
+            }
+            drawn[index] = true;
+        }
+        (drawn, false)
+    }
+}
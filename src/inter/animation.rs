@@ -0,0 +1,75 @@
+use crate::inter::mmio::*;
+
+use devola::utility::build_u16;
+use devola::vm::Devola;
+
+/// Where a sprite's start index is, after its properties and location
+const START_INDEX_OFFSET: u16 = 3;
+
+/// Moves every sprite in the animation table on by a vblank, setting each one's start index to its current frame
+pub fn animate_sprites(devola: &mut Devola) {
+    let table = build_u16(devola.peek(SPRITE_ANIMATION), devola.peek(SPRITE_ANIMATION + 1));
+    if table == 0 {
+        return;
+    }
+    // each sprite can only be animated once, so a table without its end can't run on through memory
+    for index in 0..SPRITE_COUNT as u16 {
+        let entry = table.wrapping_add(index*ANIMATION_ENTRY_SIZE as u16);
+        let field = |offset: u16| entry.wrapping_add(offset);
+        let sprite = devola.peek(entry);
+        if sprite == ANIMATION_END {
+            break;
+        }
+        let frames = build_u16(devola.peek(field(1)), devola.peek(field(2)));
+        let (length, duration) = (devola.peek(field(3)), devola.peek(field(4)));
+        let (mut position, mut timer) = (devola.peek(field(5)), devola.peek(field(6)));
+        if length == 0 || sprite as usize >= SPRITE_COUNT {
+            continue;
+        }
+        if duration > 0 {
+            timer = timer.saturating_add(1);
+            if timer >= duration {
+                timer = 0;
+                position = position.wrapping_add(1);
+            }
+        }
+        position %= length;
+        devola.poke(field(5), position);
+        devola.poke(field(6), timer);
+        let frame = devola.peek(frames.wrapping_add(position as u16));
+        devola.poke(SPRITE_START + sprite as u16*SPRITE_SIZE as u16 + START_INDEX_OFFSET, frame);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use devola::stdlib;
+
+    #[test]
+    fn test_animate_sprites() {
+        let mut devola = Devola::new(Vec::new(), None);
+        // sprite 2 cycles through tiles 10, 11 and 12, two vblanks each; sprite 5 holds its second frame
+        stdlib::memset(&mut devola, &[10, 11, 12], 0x0300, 3);
+        let table = [2, 0x03, 0x00, 3, 2, 0, 0, 5, 0x03, 0x00, 3, 0, 1, 0, ANIMATION_END];
+        stdlib::memset(&mut devola, &table, 0x0200, table.len() as u16);
+        let start_index = |devola: &Devola, sprite: u16| devola.peek(SPRITE_START + sprite*SPRITE_SIZE as u16 + START_INDEX_OFFSET);
+
+        // nothing moves until the table is set
+        animate_sprites(&mut devola);
+        assert_eq!(start_index(&devola, 2), 0);
+        stdlib::memset(&mut devola, &[0x02, 0x00], SPRITE_ANIMATION, 2);
+        let frames: Vec<u8> = (0..7).map(|_| {
+            animate_sprites(&mut devola);
+            start_index(&devola, 2)
+        }).collect();
+        assert_eq!(frames, [10, 11, 11, 12, 12, 10, 10]);
+        assert_eq!(start_index(&devola, 5), 11);
+
+        // the position is written back, so the animation can be restarted
+        devola.poke(0x0205, 0);
+        devola.poke(0x0206, 0);
+        animate_sprites(&mut devola);
+        assert_eq!(start_index(&devola, 2), 10);
+    }
+}
@@ -0,0 +1,96 @@
+use crate::inter::input_map::{Input, InputMap};
+use crate::inter::joypad::Joypad;
+use crate::inter::mmio::*;
+
+use gilrs::{Axis, EventType, GamepadId, Gilrs};
+use std::collections::HashMap;
+
+/// How far an analog stick has to be pushed to count as a D-pad press
+const STICK_THRESHOLD: f32 = 0.5;
+
+/// The D-pad buttons held by the left stick, which isn't remappable. The stick's Y axis points up.
+fn stick_bits(x: f32, y: f32) -> u8 {
+    let mut bits = 0;
+    if y > STICK_THRESHOLD { bits |= JOYPAD_UP; }
+    if y < -STICK_THRESHOLD { bits |= JOYPAD_DOWN; }
+    if x < -STICK_THRESHOLD { bits |= JOYPAD_LEFT; }
+    if x > STICK_THRESHOLD { bits |= JOYPAD_RIGHT; }
+    bits
+}
+
+/// The buttons held on one controller, and where its left stick is
+#[derive(Debug, Default, Copy, Clone)]
+struct ControllerState {
+    buttons: u8,
+    stick: (f32, f32)
+}
+
+impl ControllerState {
+    fn bits(&self) -> u8 {
+        self.buttons | stick_bits(self.stick.0, self.stick.1)
+    }
+}
+
+/// Physical controllers feeding the joypad. Any number can be connected at once, with their buttons merged.
+pub struct Gamepads {
+    gilrs: Gilrs,
+    controllers: HashMap<GamepadId, ControllerState>
+}
+
+impl Gamepads {
+    /// Starts listening for controllers, or returns `None` if the platform doesn't support them
+    pub fn new() -> Option<Gamepads> {
+        match Gilrs::new() {
+            Ok(gilrs) => Some(Gamepads { gilrs, controllers: HashMap::new() }),
+            Err(error) => {
+                tracing::warn!("Controllers unavailable: {}", error);
+                None
+            }
+        }
+    }
+
+    /// Handles the controller events since the last poll, including controllers being connected or
+    /// disconnected, and passes the merged buttons on to the joypad
+    pub fn poll(&mut self, input_map: &InputMap, joypad: &mut Joypad) {
+        while let Some(event) = self.gilrs.next_event() {
+            match event.event {
+                EventType::Connected => {
+                    tracing::info!("Controller connected: {}", self.gilrs.gamepad(event.id).name());
+                    self.controllers.insert(event.id, ControllerState::default());
+                },
+                EventType::Disconnected => {
+                    tracing::info!("Controller disconnected: {}", self.gilrs.gamepad(event.id).name());
+                    self.controllers.remove(&event.id);
+                },
+                EventType::ButtonPressed(button, _) | EventType::ButtonReleased(button, _) => {
+                    if let Some(bit) = input_map.button(Input::Controller(button)) {
+                        let state = self.controllers.entry(event.id).or_default();
+                        if let EventType::ButtonPressed(..) = event.event {
+                            state.buttons |= bit;
+                        } else {
+                            state.buttons &= !bit;
+                        }
+                    }
+                },
+                EventType::AxisChanged(Axis::LeftStickX, value, _) => self.controllers.entry(event.id).or_default().stick.0 = value,
+                EventType::AxisChanged(Axis::LeftStickY, value, _) => self.controllers.entry(event.id).or_default().stick.1 = value,
+                _ => ()
+            }
+        }
+        joypad.set_controller_buttons(self.controllers.values().fold(0, |bits, state| bits | state.bits()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mapping() {
+        assert_eq!(stick_bits(0.2, -0.3), 0);
+        assert_eq!(stick_bits(-0.8, 0.9), JOYPAD_LEFT | JOYPAD_UP);
+
+        let state = ControllerState { buttons: JOYPAD_START, stick: (1.0, 0.0) };
+        assert_eq!(state.bits(), JOYPAD_START | JOYPAD_RIGHT);
+    }
+}
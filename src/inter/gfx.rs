@@ -15,8 +15,19 @@ impl VRAMModel {
     fn empty_tilemap() -> Tilemap {
         Tilemap { tiles: [VRAMModel::empty_tile(); TILEMAP_LENGTH*TILEMAP_LENGTH] }
     }
-    fn empty_background() -> Background {
-        Background { tiles: [0; BG_SIZE] }
+    fn empty_background(index: usize) -> Background {
+        Background {
+            address: BG_START + (BG_SIZE*index) as u16,
+            width: BG_WIDTH,
+            height: BG_HEIGHT,
+            tiles: vec![BackgroundTile::new(0); BG_LENGTH]
+        }
+    }
+
+    fn empty_bg_properties() -> BackgroundProperties {
+        BackgroundProperties {
+            enabled: false, tilemap_index: 0, palette_index: 0, priority: 0
+        }
     }
 
     fn empty_sprite() -> Sprite {
@@ -35,14 +46,285 @@ impl VRAMModel {
         let tilemaps = [VRAMModel::empty_tilemap(); TILEMAP_COUNT];
         // let tiles = [VRAMModel::empty_tile(); TILEMAP_LENGTH*TILEMAP_LENGTH];
 
-        let backgrounds = [VRAMModel::empty_background(); BG_COUNT];
+        let backgrounds = std::array::from_fn(VRAMModel::empty_background);
+        let bg_properties = [VRAMModel::empty_bg_properties(); BG_COUNT];
         let sprites = [VRAMModel::empty_sprite(); SPRITE_COUNT];
+        let affine_parameters = [AffineParameters::IDENTITY; AFFINE_COUNT];
 
         VRAMModel {
-            palettes, tilemaps, backgrounds, sprites
+            palettes, tilemaps, backgrounds, bg_properties, sprites, affine_parameters,
+            sprites_enabled: true,
+            bg_scroll: [(0, 0); BG_COUNT],
+            fade: 0,
+            windows: [Window { left: 0, top: 0, right: 0, bottom: 0 }; WINDOW_COUNT],
+            bg_window_masks: [WindowMask::from(0); BG_COUNT],
+            sprite_window_mask: WindowMask::from(0),
+            backdrop: Color::BLACK,
+            color_math: ColorMath::from(0),
+            video_mode: VideoMode::from(0),
+            generations: Generations::default(),
+            scanline_writes: Vec::new()
+        }
+    }
+
+    /// Rebuilds the model from the contents of VRAM and the background control registers
+    pub fn sync(&mut self, devola: &mut Devola) {
+        for (index, palette) in self.palettes.iter_mut().enumerate() {
+            *palette = Palette::get_nth(devola, index as u16);
+        }
+        for (index, tilemap) in self.tilemaps.iter_mut().enumerate() {
+            *tilemap = Tilemap::get_nth(devola, index as u16);
+        }
+        for (index, background) in self.backgrounds.iter_mut().enumerate() {
+            *background = VRAMModel::read_background(devola, index);
+        }
+        for (index, sprite) in self.sprites.iter_mut().enumerate() {
+            *sprite = Sprite::get_nth(devola, index as u16);
+        }
+        for (index, parameters) in self.affine_parameters.iter_mut().enumerate() {
+            *parameters = AffineParameters::get_nth(devola, index as u16);
+        }
+        self.generations.touch_all();
+        self.read_registers(devola);
+        devola.clear_dirty();
+    }
+
+    /// Like `sync`, but only decodes the palettes, tiles, background maps, sprites and affine parameters
+    /// whose memory has been written to since the model was last synced. A model should only be refreshed
+    /// from the one VM; memory starts out dirty in a new VM, so the first refresh decodes everything.
+    pub fn refresh(&mut self, devola: &mut Devola) {
+        for (index, palette) in self.palettes.iter_mut().enumerate() {
+            if Palette::is_nth_dirty(devola, index as u16) {
+                *palette = Palette::get_nth(devola, index as u16);
+                self.generations.touch_palette(index);
+            }
+        }
+        for (tilemap_index, tilemap) in self.tilemaps.iter_mut().enumerate() {
+            for (tile_index, tile) in tilemap.tiles.iter_mut().enumerate() {
+                let start = TILEMAP_START + (tilemap_index*TILEMAP_SIZE + tile_index*TILE_SIZE) as u16;
+                if devola.is_dirty(start, TILE_SIZE as u16) {
+                    *tile = Tile::deserialize(stdlib::memgetn(devola, start, TILE_SIZE as u16).expect("VRAM is within memory"));
+                    self.generations.touch_tile(tilemap_index, tile_index);
+                }
+            }
+        }
+        for (index, background) in self.backgrounds.iter_mut().enumerate() {
+            // maps that moved or changed size are read again in full
+            let (address, width, height) = VRAMModel::background_layout(devola, index);
+            let moved = (background.address, background.width, background.height) != (address, width, height);
+            if moved || devola.is_dirty(address, (width*height*BG_ENTRY_SIZE) as u16) {
+                *background = VRAMModel::read_background(devola, index);
+            }
+        }
+        for (index, sprite) in self.sprites.iter_mut().enumerate() {
+            if Sprite::is_nth_dirty(devola, index as u16) {
+                *sprite = Sprite::get_nth(devola, index as u16);
+            }
+        }
+        for (index, parameters) in self.affine_parameters.iter_mut().enumerate() {
+            if AffineParameters::is_nth_dirty(devola, index as u16) {
+                *parameters = AffineParameters::get_nth(devola, index as u16);
+            }
+        }
+        self.read_registers(devola);
+        devola.clear_dirty();
+    }
+
+    /// Reads the video registers and the scanline register table, which are small enough to be read in
+    /// full every time
+    fn read_registers(&mut self, devola: &mut Devola) {
+        for (index, properties) in self.bg_properties.iter_mut().enumerate() {
+            *properties = BackgroundProperties::get_nth(devola, index as u16);
+        }
+        let scroll = stdlib::memgetn(devola, BG_SCROLL, 2*BG_COUNT as u16).expect("IO is within memory");
+        for (index, offset) in self.bg_scroll.iter_mut().enumerate() {
+            let high = devola.peek(BG_SCROLL_HIGH + index as u16);
+            *offset = (
+                build_u16(high & 0b01, scroll[2*index]),
+                build_u16((high >> 1) & 0b01, scroll[2*index+1])
+            );
+        }
+        self.fade = devola.peek(FADE) & 0x0F;
+        self.sprites_enabled = devola.peek(SPRITE_TOGGLES) & SPRITES_OFF_FLAG == 0;
+        for (index, window) in self.windows.iter_mut().enumerate() {
+            *window = Window::get_nth(devola, index as u16);
+        }
+        for (index, mask) in self.bg_window_masks.iter_mut().enumerate() {
+            *mask = WindowMask::from(devola.peek(BG_WINDOW_MASK + index as u16));
+        }
+        self.sprite_window_mask = WindowMask::from(devola.peek(SPRITE_WINDOW_MASK));
+        self.backdrop = rgb15_to_color(build_u16(devola.peek(BACKDROP_COLOR), devola.peek(BACKDROP_COLOR+1)));
+        self.color_math = ColorMath::from(devola.peek(COLOR_MATH));
+        self.video_mode = VideoMode::from(devola.peek(VIDEO_MODE));
+        self.scanline_writes = VRAMModel::read_scanline_writes(devola);
+    }
+
+    /// Writes the model back into VRAM and the video registers, the inverse of `sync`. The scanline
+    /// register table is left alone, since it lives in user memory.
+    pub fn write_back(&self, devola: &mut Devola) {
+        for (index, palette) in self.palettes.iter().enumerate() {
+            palette.set_nth(devola, index as u16);
+        }
+        for (index, tilemap) in self.tilemaps.iter().enumerate() {
+            tilemap.set_nth(devola, index as u16);
+        }
+        for (index, background) in self.backgrounds.iter().enumerate() {
+            VRAMModel::write_background(devola, index, background);
+        }
+        for (index, properties) in self.bg_properties.iter().enumerate() {
+            properties.set_nth(devola, index as u16);
+        }
+        for (index, sprite) in self.sprites.iter().enumerate() {
+            sprite.set_nth(devola, index as u16);
+        }
+        for (index, parameters) in self.affine_parameters.iter().enumerate() {
+            parameters.set_nth(devola, index as u16);
+        }
+
+        for (index, (x, y)) in self.bg_scroll.iter().enumerate() {
+            let index = index as u16;
+            devola.poke(BG_SCROLL + 2*index, *x as u8);
+            devola.poke(BG_SCROLL + 2*index + 1, *y as u8);
+            devola.poke(BG_SCROLL_HIGH + index, ((x >> 8) & 0b01) as u8 | (((y >> 8) & 0b01) << 1) as u8);
+        }
+        let fade = devola.peek(FADE);
+        devola.poke(FADE, (fade & 0xF0) | (self.fade & 0x0F));
+        let toggles = devola.peek(SPRITE_TOGGLES) & !SPRITES_OFF_FLAG;
+        devola.poke(SPRITE_TOGGLES, if self.sprites_enabled { toggles } else { toggles | SPRITES_OFF_FLAG });
+        for (index, window) in self.windows.iter().enumerate() {
+            window.set_nth(devola, index as u16);
+        }
+        for (index, mask) in self.bg_window_masks.iter().enumerate() {
+            devola.poke(BG_WINDOW_MASK + index as u16, u8::from(*mask));
+        }
+        devola.poke(SPRITE_WINDOW_MASK, u8::from(self.sprite_window_mask));
+        let (hi, lo) = break_u16(color_to_rgb15(self.backdrop));
+        stdlib::memset(devola, &[hi, lo], BACKDROP_COLOR, 2);
+        devola.poke(COLOR_MATH, u8::from(self.color_math));
+        devola.poke(VIDEO_MODE, u8::from(self.video_mode));
+    }
+
+    /// Refreshes the model at a frame boundary, unless the program has taken manual control of presenting
+    /// and hasn't asked for a new frame. Returns whether the model was refreshed.
+    pub fn latch(&mut self, devola: &mut Devola) -> bool {
+        let control = devola.peek(VIDEO_CONTROL);
+        if control & MANUAL_PRESENT_FLAG != 0 && control & PRESENT_FLAG == 0 {
+            return false;
+        }
+        self.refresh(devola);
+        devola.poke(VIDEO_CONTROL, control & !PRESENT_FLAG);
+        true
+    }
+
+    /// The address, width and height of a background map, as given by its map registers
+    pub(crate) fn background_layout(devola: &Devola, index: usize) -> (u16, usize, usize) {
+        let size = devola.peek(BG_MAP_SIZE + index as u16);
+        let width = if size & 0b01 == 0 { BG_WIDTH } else { BG_LARGE_WIDTH };
+        let height = if size & 0b10 == 0 { BG_HEIGHT } else { BG_LARGE_HEIGHT };
+        let address = build_u16(devola.peek(BG_MAP_ADDRESS + 2*index as u16), devola.peek(BG_MAP_ADDRESS + 2*index as u16 + 1));
+        let start = if address == 0 { BG_START + (BG_SIZE*index) as u16 } else { address };
+        (start, width, height)
+    }
+
+    /// Reads a background map, at the size and address given by its map registers
+    fn read_background(devola: &mut Devola, index: usize) -> Background {
+        let (start, width, height) = VRAMModel::background_layout(devola, index);
+
+        let tiles = (0..(width*height) as u16)
+            .map(|entry| {
+                let address = start.wrapping_add(entry*BG_ENTRY_SIZE as u16);
+                BackgroundTile {
+                    index: devola.peek(address),
+                    attributes: TileAttributes::from(devola.peek(address.wrapping_add(1)))
+                }
+            })
+            .collect();
+        Background { address: start, width, height, tiles }
+    }
+
+    /// Writes a background map and its map registers. Maps in their VRAM slot are written with an
+    /// address of 0, as they would be by default.
+    fn write_background(devola: &mut Devola, index: usize, background: &Background) {
+        let size = (background.width == BG_LARGE_WIDTH) as u8 | ((background.height == BG_LARGE_HEIGHT) as u8) << 1;
+        devola.poke(BG_MAP_SIZE + index as u16, size);
+        let slot = BG_START + (BG_SIZE*index) as u16;
+        let (hi, lo) = break_u16(if background.address == slot { 0 } else { background.address });
+        stdlib::memset(devola, &[hi, lo], BG_MAP_ADDRESS + 2*index as u16, 2);
+
+        for (entry, tile) in background.tiles.iter().enumerate() {
+            let address = background.address.wrapping_add((entry*BG_ENTRY_SIZE) as u16);
+            devola.poke(address, tile.index);
+            devola.poke(address.wrapping_add(1), u8::from(tile.attributes));
         }
     }
 
+    /// Reads the scanline register table, if enabled, up to its terminating entry
+    fn read_scanline_writes(devola: &mut Devola) -> Vec<ScanlineWrite> {
+        if devola.peek(HDMA_CONTROL) & 1 == 0 {
+            return Vec::new();
+        }
+        let table = build_u16(devola.peek(HDMA_TABLE), devola.peek(HDMA_TABLE+1));
+
+        (0..HDMA_MAX_ENTRIES as u16)
+            .map(|index| {
+                // a table at the end of memory carries on from the start
+                let entry: Vec<u8> = devola.memory().iter_range(table.wrapping_add(index*HDMA_ENTRY_SIZE as u16), HDMA_ENTRY_SIZE).collect();
+                ScanlineWrite {
+                    scanline: entry[0],
+                    address: build_u16(entry[1], entry[2]),
+                    value: entry[3]
+                }
+            })
+            .take_while(|write| write.scanline != HDMA_END)
+            .collect()
+    }
+
+    /// Applies a single byte written to `address` to the model. Only palettes and the video registers
+    /// are supported; returns whether the address was recognized.
+    pub fn write_register(&mut self, address: u16, value: u8) -> bool {
+        if (PALETTE_START..PALETTE_OFFSET).contains(&address) {
+            let offset = (address - PALETTE_START) as usize;
+            let (palette_index, color_index) = (offset / PALETTE_SIZE, (offset % PALETTE_SIZE) / COLOR_SIZE);
+            write_color_byte(&mut self.palettes[palette_index].colors[color_index], offset.is_multiple_of(COLOR_SIZE), value);
+            self.generations.touch_palette(palette_index);
+        } else if (BG_CONTROL..BG_CONTROL+BG_COUNT as u16).contains(&address) {
+            self.bg_properties[(address - BG_CONTROL) as usize] = BackgroundProperties::from(value);
+        } else if (BG_SCROLL..BG_SCROLL+2*BG_COUNT as u16).contains(&address) {
+            let offset = (address - BG_SCROLL) as usize;
+            let scroll = &mut self.bg_scroll[offset / 2];
+            let axis = if offset.is_multiple_of(2) { &mut scroll.0 } else { &mut scroll.1 };
+            *axis = (*axis & 0x100) | value as u16;
+        } else if (BG_SCROLL_HIGH..BG_SCROLL_HIGH+BG_COUNT as u16).contains(&address) {
+            let scroll = &mut self.bg_scroll[(address - BG_SCROLL_HIGH) as usize];
+            scroll.0 = (scroll.0 & 0xFF) | ((value as u16 & 0b01) << 8);
+            scroll.1 = (scroll.1 & 0xFF) | ((value as u16 & 0b10) << 7);
+        } else if address == FADE {
+            self.fade = value & 0x0F;
+        } else if (WINDOW_START..WINDOW_START+(WINDOW_SIZE*WINDOW_COUNT) as u16).contains(&address) {
+            let offset = (address - WINDOW_START) as usize;
+            let window = &mut self.windows[offset / WINDOW_SIZE];
+            match offset % WINDOW_SIZE {
+                0 => window.left = value,
+                1 => window.top = value,
+                2 => window.right = value,
+                _ => window.bottom = value
+            }
+        } else if (BG_WINDOW_MASK..SPRITE_WINDOW_MASK).contains(&address) {
+            self.bg_window_masks[(address - BG_WINDOW_MASK) as usize] = WindowMask::from(value);
+        } else if address == SPRITE_WINDOW_MASK {
+            self.sprite_window_mask = WindowMask::from(value);
+        } else if (BACKDROP_COLOR..BACKDROP_COLOR+COLOR_SIZE as u16).contains(&address) {
+            write_color_byte(&mut self.backdrop, address == BACKDROP_COLOR, value);
+        } else if address == COLOR_MATH {
+            self.color_math = ColorMath::from(value);
+        } else if address == VIDEO_MODE {
+            self.video_mode = VideoMode::from(value);
+        } else {
+            return false;
+        }
+        true
+    }
+
     pub fn enable_sprite(&mut self, sprite_index: u8) {
         let render_info = self.sprites[sprite_index as usize].info;
 
@@ -67,10 +349,29 @@ pub trait VRAMDeserialize: Sized {
     /// Get the nth member in VRAM
     fn get_nth(devola: &mut Devola, index: u16) -> Self {
         let (start, size) = Self::dimensions();
-        let data = stdlib::memgetn(devola, start+size*index, size);
+        let data = stdlib::memgetn(devola, start+size*index, size).expect("VRAM is within memory");
         Self::deserialize(data)
     }
 
+    /// Whether the nth member's memory has been written to since the last sync
+    fn is_nth_dirty(devola: &Devola, index: u16) -> bool {
+        let (start, size) = Self::dimensions();
+        devola.is_dirty(start+size*index, size)
+    }
+
+}
+
+pub trait VRAMSerialize: VRAMDeserialize {
+
+    /// Converts the member into the byte data it is stored as
+    fn serialize(&self) -> Vec<u8>;
+
+    /// Write the nth member into VRAM
+    fn set_nth(&self, devola: &mut Devola, index: u16) {
+        let (start, size) = Self::dimensions();
+        stdlib::memset(devola, &self.serialize(), start+size*index, size);
+    }
+
 }
 
 pub fn rgb15_to_color(color_word: u16) -> Color {
@@ -84,6 +385,13 @@ pub fn color_to_rgb15(color: Color) -> u16 {
     (((color.r as u16) / 8) << 10) | (((color.g as u16) / 8) << 5) | ((color.b as u16) / 8)
 }
 
+/// Replaces one byte (the MSB if `high`) of a color's 15-bit representation
+fn write_color_byte(color: &mut Color, high: bool, value: u8) {
+    let (hi, lo) = break_u16(color_to_rgb15(*color));
+    let word = if high { build_u16(value, lo) } else { build_u16(hi, value) };
+    *color = rgb15_to_color(word);
+}
+
 // Palette
 impl VRAMDeserialize for Palette {
     fn dimensions() -> (u16, u16) {
@@ -111,6 +419,16 @@ impl VRAMDeserialize for Palette {
         }
     }
 }
+impl VRAMSerialize for Palette {
+    fn serialize(&self) -> Vec<u8> {
+        self.colors.iter()
+            .flat_map(|color| {
+                let (hi, lo) = break_u16(color_to_rgb15(*color));
+                [hi, lo]
+            })
+            .collect()
+    }
+}
 // Tile
 impl VRAMDeserialize for Tile {
 
@@ -125,6 +443,11 @@ impl VRAMDeserialize for Tile {
         }
     }
 }
+impl VRAMSerialize for Tile {
+    fn serialize(&self) -> Vec<u8> {
+        self.pixels.to_vec()
+    }
+}
 
 // Tilemap
 impl VRAMDeserialize for Tilemap {
@@ -143,16 +466,24 @@ impl VRAMDeserialize for Tilemap {
         }
     }
 }
-// Background
-impl VRAMDeserialize for Background {
+impl VRAMSerialize for Tilemap {
+    fn serialize(&self) -> Vec<u8> {
+        self.tiles.iter().flat_map(Tile::serialize).collect()
+    }
+}
+// Background properties
+impl VRAMDeserialize for BackgroundProperties {
     fn dimensions() -> (u16, u16) {
-        (BG_START, BG_SIZE as u16)
+        (BG_CONTROL, 1)
     }
-    
-    fn deserialize(data: &[u8]) -> Background {
-        Background {
-            tiles: data.try_into().unwrap()
-        }
+
+    fn deserialize(data: &[u8]) -> BackgroundProperties {
+        BackgroundProperties::from(data[0])
+    }
+}
+impl VRAMSerialize for BackgroundProperties {
+    fn serialize(&self) -> Vec<u8> {
+        vec![u8::from(*self)]
     }
 }
 
@@ -170,11 +501,289 @@ impl VRAMDeserialize for Sprite {
         }
     }
 }
+impl VRAMSerialize for Sprite {
+    fn serialize(&self) -> Vec<u8> {
+        vec![u8::from(self.properties), self.location.0, self.location.1, self.gfx_start, self.info]
+    }
+}
+
+// Affine parameters
+impl VRAMDeserialize for AffineParameters {
+    fn dimensions() -> (u16, u16) {
+        (AFFINE_START, AFFINE_SIZE as u16)
+    }
+    fn deserialize(data: &[u8]) -> AffineParameters {
+        let word = |index: usize| build_u16(data[2*index], data[2*index+1]) as i16;
+        AffineParameters {
+            pa: word(0),
+            pb: word(1),
+            pc: word(2),
+            pd: word(3)
+        }
+    }
+}
+impl VRAMSerialize for AffineParameters {
+    fn serialize(&self) -> Vec<u8> {
+        [self.pa, self.pb, self.pc, self.pd].iter()
+            .flat_map(|word| {
+                let (hi, lo) = break_u16(*word as u16);
+                [hi, lo]
+            })
+            .collect()
+    }
+}
+
+// Window
+impl VRAMDeserialize for Window {
+    fn dimensions() -> (u16, u16) {
+        (WINDOW_START, WINDOW_SIZE as u16)
+    }
+    fn deserialize(data: &[u8]) -> Window {
+        Window {
+            left: data[0],
+            top: data[1],
+            right: data[2],
+            bottom: data[3]
+        }
+    }
+}
+impl VRAMSerialize for Window {
+    fn serialize(&self) -> Vec<u8> {
+        vec![self.left, self.top, self.right, self.bottom]
+    }
+}
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_sync() {
+        let mut devola = Devola::new(Vec::new(), None);
+        let mut vram = VRAMModel::empty_vram();
+
+        // palette 1, color 2 is pure green
+        let (hi, lo) = break_u16(0b0_00000_11111_00000);
+        stdlib::memset(&mut devola, &[hi, lo], PALETTE_START + PALETTE_SIZE as u16 + 4, 2);
+        // tilemap 1, tile 3, pixel 5 uses color 2
+        stdlib::memset(&mut devola, &[2], TILEMAP_START + (TILEMAP_SIZE + 3*TILE_SIZE + 5) as u16, 1);
+        // background 2 places tile 3 at the top left, and tile 4 next to it, flipped both ways with palette 2 at priority 1
+        stdlib::memset(&mut devola, &[3, 0, 4, 0b1100_1001], BG_START + 2*BG_SIZE as u16, 4);
+        // and is enabled with tilemap 1 and palette 1 at priority 2
+        stdlib::memset(&mut devola, &[0b1100_0110], BG_CONTROL + 2, 1);
+        // sprite 127 is enabled, an 8x8 sprite from tilemap 1 with palette 1 at priority 3
+        stdlib::memset(&mut devola, &[0b1000_0111, 16, 32, 3, 1], SPRITE_START + 127*SPRITE_SIZE as u16, 5);
+        // background 1 is a 64x64 map read from 0x3000, with tile 5 at (40, 50)
+        stdlib::memset(&mut devola, &[0b11], BG_MAP_SIZE + 1, 1);
+        stdlib::memset(&mut devola, &[0x30, 0x00], BG_MAP_ADDRESS + 2, 2);
+        stdlib::memset(&mut devola, &[5], 0x3000 + ((50*BG_LARGE_WIDTH + 40)*BG_ENTRY_SIZE) as u16, 1);
+        // background 3 is scrolled, and the screen is faded
+        stdlib::memset(&mut devola, &[12, 34], BG_SCROLL + 6, 2);
+        stdlib::memset(&mut devola, &[0b10], BG_SCROLL_HIGH + 3, 1);
+        stdlib::memset(&mut devola, &[0xF7], FADE, 1);
+        // window 1 covers a 16x8 box, and sprites are hidden outside of it
+        stdlib::memset(&mut devola, &[8, 16, 23, 23], WINDOW_START + WINDOW_SIZE as u16, 4);
+        stdlib::memset(&mut devola, &[0b110], SPRITE_WINDOW_MASK, 1);
+        stdlib::memset(&mut devola, &[0x03, 0xE0], BACKDROP_COLOR, 2); // green
+        // a scanline table at 0x2000 with two entries
+        stdlib::memset(&mut devola, &[1, 0x20, 0x00], HDMA_CONTROL, 3);
+        stdlib::memset(&mut devola, &[
+            10, (FADE >> 8) as u8, FADE as u8, 4,
+            20, (BG_SCROLL >> 8) as u8, BG_SCROLL as u8, 8,
+            HDMA_END, 0, 0, 0
+        ], 0x2000, 12);
+        // affine parameters 7 scale by 2 horizontally and mirror vertically
+        stdlib::memset(&mut devola, &[0x02, 0x00, 0, 0, 0, 0, 0xFF, 0x00], AFFINE_START + 7*AFFINE_SIZE as u16, 8);
+
+        vram.sync(&mut devola);
+
+        assert_eq!(vram.palettes[1].colors[2].g, 248);
+        assert_eq!(vram.tilemaps[1].tiles[3].pixels[5], 2);
+        assert_eq!(vram.backgrounds[2].tiles[0], BackgroundTile::new(3));
+        assert_eq!(vram.backgrounds[2].tiles[1], BackgroundTile {
+            index: 4,
+            attributes: TileAttributes { h_flip: true, v_flip: true, palette_index: 2, priority: 1 }
+        });
+        assert_eq!(vram.bg_properties[2], BackgroundProperties {
+            enabled: true, tilemap_index: 1, palette_index: 1, priority: 2
+        });
+        assert!(vram.sprites[127].enabled());
+        assert_eq!(vram.sprites[127].location, (16, 32));
+        assert!(!vram.sprites[0].enabled());
+        assert_eq!(vram.affine_parameters[7], AffineParameters { pa: 0x200, pb: 0, pc: 0, pd: -0x100 });
+        assert_eq!(vram.affine_parameters[0], AffineParameters { pa: 0, pb: 0, pc: 0, pd: 0 });
+        assert_eq!((vram.backgrounds[1].width, vram.backgrounds[1].height), (BG_LARGE_WIDTH, BG_LARGE_HEIGHT));
+        assert_eq!(vram.backgrounds[1].tile_at(40, 50), BackgroundTile::new(5));
+        assert_eq!(vram.backgrounds[1].tile_at(40 + BG_LARGE_WIDTH, 50), BackgroundTile::new(5));
+        assert_eq!(vram.backgrounds[1].address, 0x3000);
+        assert_eq!(vram.backgrounds[0].tiles.len(), BG_LENGTH);
+        assert_eq!(vram.bg_scroll[3], (12, 256 + 34));
+        assert_eq!(vram.windows[1], Window { left: 8, top: 16, right: 23, bottom: 23 });
+        assert_eq!(vram.sprite_window_mask, WindowMask { windows: [false, true], invert: true });
+        assert_eq!(vram.bg_window_masks[0], WindowMask { windows: [false, false], invert: false });
+        assert_eq!(vram.backdrop, Color::GREEN);
+        assert_eq!(vram.fade, 7);
+        assert_eq!(vram.scanline_writes, vec![
+            ScanlineWrite { scanline: 10, address: FADE, value: 4 },
+            ScanlineWrite { scanline: 20, address: BG_SCROLL, value: 8 }
+        ]);
+    }
+
+    #[test]
+    fn test_refresh() {
+        let mut devola = Devola::new(Vec::new(), None);
+        let mut vram = VRAMModel::empty_vram();
+        stdlib::memset(&mut devola, &[0x7C, 0x00], PALETTE_START, 2);
+        stdlib::memset(&mut devola, &[0b11], BG_MAP_SIZE, 1);
+
+        // everything is decoded the first time
+        vram.refresh(&mut devola);
+        let mut synced = VRAMModel::empty_vram();
+        synced.sync(&mut Devola::new(Vec::new(), None));
+        synced.palettes[0].colors[0] = Color::RED;
+        synced.backgrounds[0] = Background {
+            address: BG_START, width: BG_LARGE_WIDTH, height: BG_LARGE_HEIGHT,
+            tiles: vec![BackgroundTile::new(0); BG_LARGE_WIDTH*BG_LARGE_HEIGHT]
+        };
+        // generations count changes rather than describing the contents
+        synced.generations = vram.generations.clone();
+        assert_eq!(vram, synced);
+        let generations = vram.generations.clone();
+
+        // afterwards, only what was written is decoded again. Changes made to the model alone are
+        // kept, showing which parts were skipped.
+        vram.palettes[1].colors[0] = Color::GREEN;
+        vram.tilemaps[0].tiles[1].pixels[0] = 1;
+        // sprites 0 and 4 are in different blocks
+        vram.sprites[4].gfx_start = 1;
+        stdlib::memset(&mut devola, &[2], TILEMAP_START + TILE_SIZE as u16, 1);
+        stdlib::memset(&mut devola, &[9], SPRITE_START + 3, 1);
+        stdlib::memset(&mut devola, &[4], BG_START + BG_SIZE as u16 + 2, 1);
+        vram.refresh(&mut devola);
+        assert_eq!(vram.palettes[1].colors[0], Color::GREEN);
+        assert_eq!(vram.tilemaps[0].tiles[1].pixels[0], 2);
+        assert_eq!(vram.sprites[0].gfx_start, 9);
+        assert_eq!(vram.sprites[4].gfx_start, 1);
+        assert_eq!(vram.backgrounds[1].tiles[1], BackgroundTile::new(4));
+        // only the tile that was decoded again has moved on to a new generation
+        assert!(vram.generations.tiles[0][1] > generations.tiles[0][1]);
+        assert_eq!(vram.generations.tiles[0][2], generations.tiles[0][2]);
+        assert_eq!(vram.generations.palettes, generations.palettes);
+
+        // registers are always read, and moving a map reads it again from its new address
+        stdlib::memset(&mut devola, &[0x20, 0x00], BG_MAP_ADDRESS + 2, 2);
+        devola.clear_dirty();
+        vram.refresh(&mut devola);
+        assert_eq!(vram.backgrounds[1].address, 0x2000);
+        assert_eq!(vram.backgrounds[1].tiles[1], BackgroundTile::new(0));
+    }
+
+    #[test]
+    fn test_latch() {
+        let mut devola = Devola::new(Vec::new(), None);
+        let mut vram = VRAMModel::empty_vram();
+
+        // VRAM is latched every frame by default
+        stdlib::memset(&mut devola, &[1], FADE, 1);
+        assert!(vram.latch(&mut devola));
+        assert_eq!(vram.fade, 1);
+
+        // in manual mode, updates are held back until presented
+        stdlib::memset(&mut devola, &[MANUAL_PRESENT_FLAG], VIDEO_CONTROL, 1);
+        stdlib::memset(&mut devola, &[2], FADE, 1);
+        assert!(!vram.latch(&mut devola));
+        assert_eq!(vram.fade, 1);
+
+        stdlib::memset(&mut devola, &[MANUAL_PRESENT_FLAG | PRESENT_FLAG], VIDEO_CONTROL, 1);
+        assert!(vram.latch(&mut devola));
+        assert_eq!(vram.fade, 2);
+        assert_eq!(devola.peek(VIDEO_CONTROL), MANUAL_PRESENT_FLAG);
+        assert!(!vram.latch(&mut devola));
+    }
+
+    #[test]
+    fn test_write_register() {
+        let mut vram = VRAMModel::empty_vram();
+
+        // the two bytes of a color can be written independently, here setting full red and blue
+        assert!(vram.write_register(PALETTE_START + PALETTE_SIZE as u16 + 2, 0b0111_1100));
+        assert!(vram.write_register(PALETTE_START + PALETTE_SIZE as u16 + 3, 0b0001_1111));
+        assert_eq!(color_to_rgb15(vram.palettes[1].colors[1]), 0b0_11111_00000_11111);
+
+        // enabled at priority 3
+        assert!(vram.write_register(BG_CONTROL + 1, 0b1000_0011));
+        assert!(vram.bg_properties[1].enabled);
+        assert!(vram.write_register(BG_SCROLL + 3, 100));
+        assert_eq!(vram.bg_scroll[1], (0, 100));
+        // the high bits of the scroll offsets are kept when writing the low byte
+        assert!(vram.write_register(BG_SCROLL_HIGH + 1, 0b11));
+        assert!(vram.write_register(BG_SCROLL + 2, 7));
+        assert_eq!(vram.bg_scroll[1], (256 + 7, 256 + 100));
+        assert!(vram.write_register(WINDOW_START + 2, 40));
+        assert_eq!(vram.windows[0].right, 40);
+        assert!(vram.write_register(BG_WINDOW_MASK + 3, 0b001));
+        assert_eq!(vram.bg_window_masks[3].windows, [true, false]);
+        assert!(vram.write_register(BACKDROP_COLOR + 1, 0x1F));
+        assert_eq!(vram.backdrop, Color::BLUE);
+        assert!(vram.write_register(COLOR_MATH, 0xC2));
+        assert_eq!(vram.color_math, ColorMath { enabled: true, subtract: true, half: false, layer: 2 });
+        assert!(vram.write_register(VIDEO_MODE, 0x51));
+        assert_eq!(vram.video_mode, VideoMode { layout: BgLayout::Affine, affine_index: 5 });
+        assert!(!vram.write_register(VRAM - 1, 0));
+    }
+
+    #[test]
+    fn test_write_back() {
+        let mut devola = Devola::new(Vec::new(), None);
+        let mut vram = VRAMModel::empty_vram();
+
+        vram.palettes[3].colors[15] = rgb15_to_color(0b0_10101_01010_11111);
+        vram.tilemaps[1].tiles[200].pixels[63] = 9;
+        vram.backgrounds[0].tiles[5] = BackgroundTile {
+            index: 7,
+            attributes: TileAttributes { h_flip: true, v_flip: false, palette_index: 5, priority: 2 }
+        };
+        // a 64x32 map moved out of VRAM
+        vram.backgrounds[2] = Background {
+            address: 0x4000,
+            width: BG_LARGE_WIDTH,
+            height: BG_HEIGHT,
+            tiles: vec![BackgroundTile::new(3); BG_LARGE_WIDTH*BG_HEIGHT]
+        };
+        vram.bg_properties[2] = BackgroundProperties { enabled: true, tilemap_index: 1, palette_index: 6, priority: 3 };
+        vram.bg_scroll[2] = (300, 17);
+        vram.sprites[64] = Sprite {
+            properties: SpriteProperties { tilemap_index: 1, size: SpriteSize::X64, palette_index: 2, priority: 1 },
+            location: (200, 100),
+            gfx_start: 12,
+            info: 0x1D
+        };
+        vram.sprites_enabled = false;
+        vram.affine_parameters[1] = AffineParameters { pa: -0x80, pb: 0x40, pc: -1, pd: 0x7FFF };
+        vram.fade = 9;
+        vram.windows[1] = Window { left: 1, top: 2, right: 3, bottom: 4 };
+        vram.bg_window_masks[1] = WindowMask { windows: [true, true], invert: false };
+        vram.sprite_window_mask = WindowMask { windows: [false, true], invert: true };
+        vram.backdrop = Color::RED;
+        vram.color_math = ColorMath { enabled: true, subtract: false, half: true, layer: COLOR_MATH_SPRITE_LAYER };
+        vram.video_mode = VideoMode { layout: BgLayout::FullColor, affine_index: 3 };
+
+        // bits of the registers outside of the model are kept
+        devola.poke(FADE, 0xA0);
+        devola.poke(SPRITE_TOGGLES, 5);
+        vram.write_back(&mut devola);
+        assert_eq!(devola.peek(FADE), 0xA9);
+        assert_eq!(devola.peek(SPRITE_TOGGLES), SPRITES_OFF_FLAG | 5);
+        assert_eq!(devola.peek(BG_MAP_ADDRESS), 0);
+        assert_eq!(devola.peek(BG_MAP_ADDRESS + 4), 0x40);
+
+        let mut synced = VRAMModel::empty_vram();
+        synced.sync(&mut devola);
+        // generations count changes rather than describing the contents
+        vram.generations = synced.generations.clone();
+        assert_eq!(synced, vram);
+    }
+
     #[test]
     fn test_sprite_deserialize() {
         let data: [u8; 5] = [
@@ -198,4 +807,10 @@ mod tests {
             }
         )
     }
+
+    #[test]
+    fn test_sprite_serialize() {
+        let data: [u8; 5] = [0xEA, 3, 4, 5, 0x77];
+        assert_eq!(Sprite::deserialize(&data).serialize(), data.to_vec());
+    }
 }
\ No newline at end of file
@@ -0,0 +1,193 @@
+use crate::inter::mmio::*;
+
+use gilrs::Button;
+use std::collections::BTreeMap;
+use winit::keyboard::KeyCode;
+
+/// The joypad buttons in register order, with the names used in the settings
+pub const JOYPAD_BUTTONS: [(&str, u8); 8] = [
+    ("up", JOYPAD_UP),
+    ("down", JOYPAD_DOWN),
+    ("left", JOYPAD_LEFT),
+    ("right", JOYPAD_RIGHT),
+    ("a", JOYPAD_A),
+    ("b", JOYPAD_B),
+    ("select", JOYPAD_SELECT),
+    ("start", JOYPAD_START)
+];
+
+/// Keys that can be bound. The function keys and `Tab` are left out, since they're emulator hotkeys.
+const BINDABLE_KEYS: [KeyCode; 66] = [
+    KeyCode::KeyA, KeyCode::KeyB, KeyCode::KeyC, KeyCode::KeyD, KeyCode::KeyE, KeyCode::KeyF, KeyCode::KeyG,
+    KeyCode::KeyH, KeyCode::KeyI, KeyCode::KeyJ, KeyCode::KeyK, KeyCode::KeyL, KeyCode::KeyM, KeyCode::KeyN,
+    KeyCode::KeyO, KeyCode::KeyP, KeyCode::KeyQ, KeyCode::KeyR, KeyCode::KeyS, KeyCode::KeyT, KeyCode::KeyU,
+    KeyCode::KeyV, KeyCode::KeyW, KeyCode::KeyX, KeyCode::KeyY, KeyCode::KeyZ,
+    KeyCode::Digit0, KeyCode::Digit1, KeyCode::Digit2, KeyCode::Digit3, KeyCode::Digit4,
+    KeyCode::Digit5, KeyCode::Digit6, KeyCode::Digit7, KeyCode::Digit8, KeyCode::Digit9,
+    KeyCode::Numpad0, KeyCode::Numpad1, KeyCode::Numpad2, KeyCode::Numpad3, KeyCode::Numpad4,
+    KeyCode::Numpad5, KeyCode::Numpad6, KeyCode::Numpad7, KeyCode::Numpad8, KeyCode::Numpad9,
+    KeyCode::ArrowUp, KeyCode::ArrowDown, KeyCode::ArrowLeft, KeyCode::ArrowRight,
+    KeyCode::Enter, KeyCode::Space, KeyCode::Backspace,
+    KeyCode::ShiftLeft, KeyCode::ShiftRight, KeyCode::ControlLeft, KeyCode::ControlRight, KeyCode::AltLeft, KeyCode::AltRight,
+    KeyCode::Comma, KeyCode::Period, KeyCode::Slash, KeyCode::Semicolon, KeyCode::Quote, KeyCode::BracketLeft, KeyCode::BracketRight
+];
+
+const BINDABLE_BUTTONS: [Button; 17] = [
+    Button::South, Button::East, Button::North, Button::West,
+    Button::LeftTrigger, Button::LeftTrigger2, Button::RightTrigger, Button::RightTrigger2,
+    Button::Select, Button::Start, Button::Mode, Button::LeftThumb, Button::RightThumb,
+    Button::DPadUp, Button::DPadDown, Button::DPadLeft, Button::DPadRight
+];
+
+/// A keyboard key or controller button bound to a joypad button
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Input {
+    Key(KeyCode),
+    Controller(Button)
+}
+
+impl Input {
+    /// Parses `key:<name>` or `pad:<name>`, with the names of winit's key codes and gilrs' buttons
+    fn parse(text: &str) -> Option<Input> {
+        match text.split_once(':')? {
+            ("key", name) => BINDABLE_KEYS.iter().find(|key| format!("{:?}", key) == name).map(|key| Input::Key(*key)),
+            ("pad", name) => BINDABLE_BUTTONS.iter().find(|button| format!("{:?}", button) == name).map(|button| Input::Controller(*button)),
+            _ => None
+        }
+    }
+
+    fn name(&self) -> String {
+        match self {
+            Input::Key(key) => format!("key:{:?}", key),
+            Input::Controller(button) => format!("pad:{:?}", button)
+        }
+    }
+}
+
+/// Which keys and controller buttons stand in for each joypad button
+#[derive(Debug, PartialEq, Clone)]
+pub struct InputMap {
+    bindings: Vec<(Input, u8)>
+}
+
+impl Default for InputMap {
+    fn default() -> Self {
+        // A on the right and B at the bottom of controllers, matching the joypad's layout
+        InputMap { bindings: vec![
+            (Input::Key(KeyCode::ArrowUp), JOYPAD_UP),
+            (Input::Controller(Button::DPadUp), JOYPAD_UP),
+            (Input::Key(KeyCode::ArrowDown), JOYPAD_DOWN),
+            (Input::Controller(Button::DPadDown), JOYPAD_DOWN),
+            (Input::Key(KeyCode::ArrowLeft), JOYPAD_LEFT),
+            (Input::Controller(Button::DPadLeft), JOYPAD_LEFT),
+            (Input::Key(KeyCode::ArrowRight), JOYPAD_RIGHT),
+            (Input::Controller(Button::DPadRight), JOYPAD_RIGHT),
+            (Input::Key(KeyCode::KeyX), JOYPAD_A),
+            (Input::Controller(Button::East), JOYPAD_A),
+            (Input::Key(KeyCode::KeyZ), JOYPAD_B),
+            (Input::Controller(Button::South), JOYPAD_B),
+            (Input::Key(KeyCode::ShiftRight), JOYPAD_SELECT),
+            (Input::Controller(Button::Select), JOYPAD_SELECT),
+            (Input::Key(KeyCode::Enter), JOYPAD_START),
+            (Input::Controller(Button::Start), JOYPAD_START)
+        ] }
+    }
+}
+
+impl InputMap {
+    /// Reads bindings from the names of joypad buttons to the inputs bound to them. Buttons left out have no
+    /// bindings.
+    pub fn from_bindings(bindings: &BTreeMap<String, Vec<String>>) -> Result<InputMap, Vec<String>> {
+        let mut input_map = InputMap { bindings: Vec::new() };
+        let mut errors = Vec::new();
+        for (name, inputs) in bindings {
+            let button = match JOYPAD_BUTTONS.iter().find(|(button_name, _)| button_name == name) {
+                Some((_, button)) => *button,
+                None => {
+                    errors.push(format!("unknown joypad button {}", name));
+                    continue;
+                }
+            };
+            for input in inputs {
+                match Input::parse(input) {
+                    Some(input) => input_map.bindings.push((input, button)),
+                    None => errors.push(format!("unknown input {} for {}", input, name))
+                }
+            }
+        }
+        if errors.is_empty() { Ok(input_map) } else { Err(errors) }
+    }
+
+    /// Writes the bindings out in the form read by `from_bindings`
+    pub fn to_bindings(&self) -> BTreeMap<String, Vec<String>> {
+        JOYPAD_BUTTONS.iter()
+            .map(|(name, button)| (name.to_string(), self.inputs(*button).iter().map(Input::name).collect()))
+            .collect()
+    }
+
+    /// The inputs bound to a joypad button
+    pub fn inputs(&self, button: u8) -> Vec<Input> {
+        self.bindings.iter().filter(|(_, bound)| *bound == button).map(|(input, _)| *input).collect()
+    }
+
+    /// The joypad button an input is bound to, if any
+    pub fn button(&self, input: Input) -> Option<u8> {
+        self.bindings.iter().find(|(bound, _)| *bound == input).map(|(_, button)| *button)
+    }
+
+    /// Binds a key to a joypad button in place of the keys already bound to it. Controller bindings are kept.
+    pub fn bind_key(&mut self, button: u8, key: KeyCode) -> bool {
+        if !BINDABLE_KEYS.contains(&key) {
+            return false;
+        }
+        let input = Input::Key(key);
+        self.bindings.retain(|(bound, bound_button)| *bound != input && !(matches!(bound, Input::Key(_)) && *bound_button == button));
+        self.bindings.push((input, button));
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bindings() {
+        let bindings = BTreeMap::from([
+            ("a".to_string(), vec!["key:KeyA".to_string(), "pad:South".to_string()]),
+            ("start".to_string(), vec!["key:Space".to_string()])
+        ]);
+        let input_map = InputMap::from_bindings(&bindings).unwrap();
+        assert_eq!(input_map.button(Input::Key(KeyCode::KeyA)), Some(JOYPAD_A));
+        assert_eq!(input_map.button(Input::Controller(Button::South)), Some(JOYPAD_A));
+        assert_eq!(input_map.button(Input::Key(KeyCode::KeyX)), None);
+        assert_eq!(input_map.inputs(JOYPAD_START), vec![Input::Key(KeyCode::Space)]);
+
+        let bindings = BTreeMap::from([
+            ("a".to_string(), vec!["key:F1".to_string()]),
+            ("jump".to_string(), vec!["key:KeyA".to_string()])
+        ]);
+        assert_eq!(InputMap::from_bindings(&bindings).unwrap_err(), vec![
+            "unknown input key:F1 for a".to_string(),
+            "unknown joypad button jump".to_string()
+        ]);
+
+        // the defaults survive being written out and read back, though they come back ordered by button
+        let input_map = InputMap::from_bindings(&InputMap::default().to_bindings()).unwrap();
+        for (_, button) in JOYPAD_BUTTONS {
+            assert_eq!(input_map.inputs(button), InputMap::default().inputs(button));
+        }
+    }
+
+    #[test]
+    fn test_bind_key() {
+        let mut input_map = InputMap::default();
+        // a key moves from its old button, replacing the keys of the new one
+        assert!(input_map.bind_key(JOYPAD_A, KeyCode::KeyZ));
+        assert_eq!(input_map.button(Input::Key(KeyCode::KeyZ)), Some(JOYPAD_A));
+        assert_eq!(input_map.button(Input::Key(KeyCode::KeyX)), None);
+        assert_eq!(input_map.inputs(JOYPAD_B), vec![Input::Controller(Button::South)]);
+        assert_eq!(input_map.inputs(JOYPAD_A), vec![Input::Controller(Button::East), Input::Key(KeyCode::KeyZ)]);
+        assert!(!input_map.bind_key(JOYPAD_A, KeyCode::F1));
+    }
+}
@@ -0,0 +1,48 @@
+/// The buttons currently held, as laid out in the joypad register. Buttons held on the keyboard and on
+/// controllers are tracked separately, and merged.
+#[derive(Debug, Default, PartialEq, Copy, Clone)]
+pub struct Joypad {
+    keys: u8,
+    controllers: u8
+}
+
+impl Joypad {
+    /// Presses or releases a button from the keyboard
+    pub fn set_button(&mut self, button: u8, pressed: bool) {
+        if pressed {
+            self.keys |= button;
+        } else {
+            self.keys &= !button;
+        }
+    }
+
+    /// Replaces the buttons held on controllers
+    pub fn set_controller_buttons(&mut self, buttons: u8) {
+        self.controllers = buttons;
+    }
+
+    pub fn buttons(&self) -> u8 {
+        self.keys | self.controllers
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::inter::mmio::*;
+
+    #[test]
+    fn test_buttons() {
+        let mut joypad = Joypad::default();
+        joypad.set_button(JOYPAD_LEFT, true);
+        joypad.set_button(JOYPAD_START, true);
+        // pressing a held button again changes nothing
+        joypad.set_button(JOYPAD_LEFT, true);
+        assert_eq!(joypad.buttons(), JOYPAD_LEFT | JOYPAD_START);
+        joypad.set_button(JOYPAD_LEFT, false);
+        // controller buttons are merged in, and releasing a key doesn't release the same button on a controller
+        joypad.set_controller_buttons(JOYPAD_START | JOYPAD_A);
+        joypad.set_button(JOYPAD_START, false);
+        assert_eq!(joypad.buttons(), JOYPAD_START | JOYPAD_A);
+    }
+}
@@ -3,14 +3,248 @@ use devola::vm::*;
 pub const SCREEN_WIDTH: u32 = 256;
 pub const SCREEN_HEIGHT: u32 = 224;
 
+// Timing
+pub const CPU_FREQUENCY: u64 = 1_789_773; // cycles per second
+pub const FRAME_RATE: u64 = 60;
+pub const CYCLES_PER_FRAME: u64 = CPU_FREQUENCY/FRAME_RATE;
+pub const SCANLINES: u64 = 262; // visible scanlines followed by the vertical blanking period
+pub const CYCLES_PER_SCANLINE: u64 = CYCLES_PER_FRAME/SCANLINES;
+pub const ACTIVE_CYCLES: u64 = CYCLES_PER_SCANLINE*SCREEN_HEIGHT as u64;
+
+// Interrupt lines
+pub const VBLANK_INTERRUPT: u8 = 0;
+pub const SERIAL_INTERRUPT: u8 = 1;
+
 // MMIO+0x0, 0x1 reserved
 
-// all sprites can be enabled, disabled by toggling the high bit (+/- 128)
-// individual sprites can be enabled or disabled via the 7 lower bits
+// Sprite toggles: 1 byte [all off|sprite 6|sprite 5|sprite 4|sprite 3|sprite 2|sprite 1|sprite 0]
+//                         all off: while set, no sprites are drawn, whatever their own enabled bits. Adding or
+//                                  subtracting 128 turns all sprites off or back on
+//                                  sprite: 1-127, flips the enabled bit of that sprite's rendering info
+// As each vblank begins, before VRAM is latched, the sprite is toggled and the sprite bits cleared again, leaving
+// all off as it was. Only one sprite is toggled per frame, and sprite 0 has to be toggled through its rendering info.
+// The low bits used to be read as a mask, one per sprite, which could only ever reach 7 of the 128 sprites, so they
+// hold a sprite number instead. 0 is left meaning no change, as the register is 0 whenever nothing's been asked for.
 pub const SPRITE_TOGGLES: u16 = MMIO+0x2;
+pub const SPRITES_OFF_FLAG: u8 = 0b1000_0000;
+pub const SPRITE_TOGGLE_MASK: u8 = 0b0111_1111;
 
-// [enable 0|tilemap 0|palette 2|palette 1|palette 0|bg 1|bg 0|unused]
+// Background settings: 1 byte [enable 0|tilemap 0|palette 2|palette 1|palette 0|bg 1|bg 0|set]
+//                              enable, tilemap, palette: as for background control
+//                                                                   bg: 0-3, selects the background to change
+//                                                                                       set: changes the background
+// As each vblank begins, before VRAM is latched, the selected background's control register takes the enable,
+// tilemap and palette bits, keeping its priority, and set is cleared again.
 pub const BG_SETTINGS: u16 = MMIO+0x3;
+pub const BG_SETTINGS_SET_FLAG: u8 = 0b0000_0001;
+
+// Popola device registers occupy the page between the top of the stack (0x0F00) and MMIO
+pub const IO: u16 = 0x0F00;
+
+// Background control: 1 byte per background
+// [enable 0|tilemap 0|unused|palette 2|palette 1|palette 0|priority 1|priority 0]
+//  enable: 0 or 1, whether the background is drawn
+//           tilemap: 0 or 1, selects tilemap to be used
+//                            palette: 0-7, selects palette to use
+//                                                          priority: 0-3, higher priority is drawn over lower
+pub const BG_CONTROL: u16 = IO;
+
+// Video status: 1 byte [unused|unused|unused|unused|bg collision|sprite collision|sprite overflow|vblank]
+//   vblank: set during the vertical blanking period, when VRAM can be updated without tearing
+//   sprite overflow: set as vblank begins if sprites were dropped from any scanline of the latched frame
+//   sprite collision: set as vblank begins if the opaque pixels of any two sprites overlap in the latched frame
+//   bg collision: set as vblank begins if a sprite's opaque pixels overlap those of a background drawn at the
+//                 sprite's priority or above in the latched frame
+// Pixels hidden by a window or dropped by the sprite limit don't collide. The flags hold until the next vblank.
+pub const VIDEO_STATUS: u16 = IO+0x04;
+pub const VBLANK_FLAG: u8 = 0b0000_0001;
+pub const SPRITE_OVERFLOW_FLAG: u8 = 0b0000_0010;
+pub const SPRITE_COLLISION_FLAG: u8 = 0b0000_0100;
+pub const BG_COLLISION_FLAG: u8 = 0b0000_1000;
+
+// Fade: 1 byte [unused|unused|unused|unused|fade 3|fade 2|fade 1|fade 0]
+//   fade: 0-15, each step darkens the final image by 1/16th; 0 leaves it untouched
+pub const FADE: u16 = IO+0x05;
+
+// Scanline register table (HDMA)
+// Control: 1 byte [unused|unused|unused|unused|unused|unused|unused|enable]
+// Table: 2 bytes (MSB first), the address of the table in memory
+// The table is a list of 4 byte entries [scanline, address MSB, address LSB, value], sorted by scanline and
+// terminated by a scanline of 0xFF. Before each scanline is drawn, the value of every entry for that scanline
+// is written to its address. Palettes and the video registers in the I/O page can be targeted.
+pub const HDMA_CONTROL: u16 = IO+0x06;
+pub const HDMA_TABLE: u16 = IO+0x07;
+pub const HDMA_ENTRY_SIZE: usize = 4;
+pub const HDMA_MAX_ENTRIES: usize = 256;
+pub const HDMA_END: u8 = 0xFF;
+
+// Background scroll: 2 bytes per background (x then y), offsets the top left of the screen into the background
+pub const BG_SCROLL: u16 = IO+0x10;
+
+// Windows: 4 bytes per window [left, top, right, bottom], inclusive screen coordinates
+pub const WINDOW_START: u16 = IO+0x18;
+pub const WINDOW_SIZE: usize = 4;
+pub const WINDOW_COUNT: usize = 2;
+
+// Window masks: 1 byte per background, followed by 1 byte for all sprites
+// [unused|unused|unused|unused|unused|invert|window 1|window 0]
+//                                     invert: hide the layer outside of the selected windows instead of inside
+//                                            window: 0 or 1, whether the layer is hidden inside the window
+// A mask of 0 selects no windows, so the layer is never hidden.
+pub const BG_WINDOW_MASK: u16 = IO+0x20;
+pub const SPRITE_WINDOW_MASK: u16 = BG_WINDOW_MASK+BG_COUNT as u16;
+
+// Backdrop color: 2 bytes (MSB first), the 15-bit color shown where no background or sprite is drawn
+pub const BACKDROP_COLOR: u16 = IO+0x25;
+
+// Background map size: 1 byte per background [unused|unused|unused|unused|unused|unused|tall|wide]
+//   wide: the map is BG_LARGE_WIDTH tiles wide instead of BG_WIDTH
+//   tall: the map is BG_LARGE_HEIGHT tiles high instead of BG_HEIGHT
+// Scroll high bits: 1 byte per background [unused|unused|unused|unused|unused|unused|y 8|x 8]
+//   x, y: the 9th bit of the scroll offsets, for maps larger than 256 pixels
+// Map address: 2 bytes per background (MSB first), where the map is read from; 0 reads it from its slot in VRAM.
+// Maps larger than the screen don't fit in their VRAM slot, and should be placed in user memory instead.
+pub const BG_MAP_SIZE: u16 = IO+0x27;
+pub const BG_SCROLL_HIGH: u16 = IO+0x2B;
+pub const BG_MAP_ADDRESS: u16 = IO+0x2F;
+pub const BG_LARGE_WIDTH: usize = 64;
+pub const BG_LARGE_HEIGHT: usize = 64;
+
+// Video control: 1 byte [unused|unused|unused|unused|unused|unused|present|manual]
+//   present: latch VRAM at the start of the next vblank
+//   manual: VRAM is only latched when present is set
+// VRAM is read into the renderer once per frame, as vblank begins. In manual mode, frames keep showing the
+// last latched state until the program sets present, which is cleared again once VRAM has been latched.
+pub const VIDEO_CONTROL: u16 = IO+0x37;
+pub const MANUAL_PRESENT_FLAG: u8 = 0b0000_0001;
+pub const PRESENT_FLAG: u8 = 0b0000_0010;
+
+// Color math: 1 byte [enable|subtract|half|unused|unused|layer 2|layer 1|layer 0]
+//                     enable: 0 or 1, whether the selected layer is blended with the layers beneath it
+//                            subtract: 0 adds the layer's colors to the colors beneath, 1 subtracts them
+//                                     half: 0 or 1, halves the result, e.g. averaging the colors when adding
+//                                                        layer: 0-3 selects a background, 4 selects all sprites
+// Channels are clamped to the range of a 15-bit color. Blending only takes place where the selected layer is drawn,
+// and uses whatever was drawn beneath it so far: the backdrop, and any layers of lower priority.
+pub const COLOR_MATH: u16 = IO+0x38;
+pub const COLOR_MATH_SPRITE_LAYER: u8 = 4;
+
+// Video mode: 1 byte [unused|affine 2|affine 1|affine 0|unused|unused|mode 1|mode 0]
+//                            affine: 0-7, the affine parameters transforming the rotating background in mode 1
+//                                                                     mode: 0-3, selects how the backgrounds are laid out
+//   mode 0: BG 0-3 are all drawn, with 16-color tiles
+//   mode 1: BG 0-1 are drawn as in mode 0, and BG 2 is rotated and scaled about the centre of the screen, before
+//           being scrolled. BG 3 isn't drawn
+//   mode 2: only BG 0 is drawn, with 128-color tiles. The high nibble of each pixel selects one of the palettes
+//           (ignoring the top bit), and the low nibble the color within it. Palette selection bits are ignored
+//   mode 3: reserved, drawn as mode 0
+// In modes 0 and 1, only the low nibble of each pixel is used.
+pub const VIDEO_MODE: u16 = IO+0x39;
+
+// Joypad: 1 byte [start|select|b|a|right|left|down|up]
+// Each bit is set while the button is held. The register is updated as each frame starts, so it reads the same
+// for the whole frame; writes to it are overwritten by the next update.
+pub const JOYPAD: u16 = IO+0x3A;
+pub const JOYPAD_UP: u8 = 0b0000_0001;
+pub const JOYPAD_DOWN: u8 = 0b0000_0010;
+pub const JOYPAD_LEFT: u8 = 0b0000_0100;
+pub const JOYPAD_RIGHT: u8 = 0b0000_1000;
+pub const JOYPAD_A: u8 = 0b0001_0000;
+pub const JOYPAD_B: u8 = 0b0010_0000;
+pub const JOYPAD_SELECT: u8 = 0b0100_0000;
+pub const JOYPAD_START: u8 = 0b1000_0000;
+
+// Audio
+// Samples are generated at AUDIO_SAMPLE_RATE, one every CYCLES_PER_SAMPLE cycles. Registers are read by the APU after
+// each batch of instructions, so changes within a batch take effect together.
+
+// Square channels: 4 bytes per channel [period LSB, control, envelope, length]
+// Control: 1 byte [start|length enable|unused|unused|unused|period 10|period 9|period 8]
+//                  start: starts the channel playing from the start of its wave, reloading its length. Cleared once started
+//                        length enable: the channel stops once its length runs out, instead of playing until started again
+//                                                           period: 0-2047, the channel plays at CPU_FREQUENCY/(16*(period+1)) Hz
+// Envelope: 1 byte [duty 1|duty 0|unused|unused|volume 3|volume 2|volume 1|volume 0]
+//                   duty: 0 (12.5%) 1 (25%) 2 (50%) 3 (75%) of each wave is high
+//                                               volume: 0-15
+// Length: 1 byte, how long the channel plays for in 1/64ths of a second; 0 plays for 256/64ths
+pub const SQUARE_START: u16 = IO+0x40;
+pub const SQUARE_SIZE: usize = 4;
+pub const SQUARE_COUNT: usize = 2;
+pub const AUDIO_START_FLAG: u8 = 0b1000_0000;
+pub const AUDIO_LENGTH_ENABLE_FLAG: u8 = 0b0100_0000;
+
+// Audio status: 1 byte [unused|unused|unused|unused|wave|noise|square 1|square 0]
+//                                                   wave, noise, square: set while the channel is playing
+pub const AUDIO_STATUS: u16 = IO+0x48;
+pub const AUDIO_SAMPLE_RATE: u64 = CPU_FREQUENCY/CYCLES_PER_SAMPLE;
+pub const CYCLES_PER_SAMPLE: u64 = 40;
+pub const LENGTH_CLOCK_RATE: u64 = 64; // length counters count down 64 times per second
+
+// Noise channel: 4 bytes [period, control, envelope, length]
+// Period: 1 byte [short|unused|unused|unused|period 3|period 2|period 1|period 0]
+//                 short: repeats the noise after a few dozen steps instead of 32767, for a more metallic sound
+//                                            period: 0-15, selects how many cycles each step of the noise lasts from NOISE_PERIODS
+// Control: 1 byte, laid out as for the square channels but without the period bits
+// Envelope: 1 byte [volume 3|volume 2|volume 1|volume 0|unused|decay 2|decay 1|decay 0]
+//                   volume: 0-15, the volume the channel starts at
+//                                                              decay: 0 keeps the volume steady, otherwise it drops by 1 every `decay` 64ths of a second
+// The volume is only read when the channel starts while it's decaying.
+// Length: 1 byte, as for the square channels
+pub const NOISE_START: u16 = IO+0x49;
+pub const NOISE_PERIODS: [u16; 16] = [4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068];
+pub const NOISE_SHORT_FLAG: u8 = 0b1000_0000;
+
+// Wave channel: 4 bytes [period LSB, control, volume, length]
+// Control: 1 byte, laid out as for the square channels; the channel plays at CPU_FREQUENCY/(32*(period+1)) Hz
+// Volume: 1 byte [unused|unused|unused|unused|volume 3|volume 2|volume 1|volume 0]
+//                                             volume: 0-15
+// Length: 1 byte, as for the square channels
+// Wave table: 16 bytes, the 32 4-bit samples of the wave the channel plays, 2 per byte with the high nibble first.
+// A sample of 0 is the lowest point of the wave, and 15 the highest.
+pub const WAVE_START: u16 = IO+0x4D;
+pub const WAVE_TABLE: u16 = IO+0x51;
+pub const WAVE_LENGTH: usize = 32;
+
+// Serial port: 3 bytes [out, in, status]
+// Out: 1 byte, the byte to send
+// In: 1 byte, the last byte received
+// Status: 1 byte [send|unused|unused|unused|unused|unused|unused|received]
+//                 send: set to send the out byte. Cleared once it has been sent, SERIAL_BYTE_CYCLES later
+//                                                                received: set when a byte arrives in the in register. Clear it to receive the next
+// The serial interrupt is requested whenever a byte finishes sending or is received.
+pub const SERIAL_OUT: u16 = IO+0x61;
+pub const SERIAL_IN: u16 = IO+0x62;
+pub const SERIAL_STATUS: u16 = IO+0x63;
+pub const SERIAL_SEND_FLAG: u8 = 0b1000_0000;
+pub const SERIAL_RECEIVED_FLAG: u8 = 0b0000_0001;
+pub const SERIAL_BYTE_CYCLES: u64 = CPU_FREQUENCY/960; // 9600 baud, with a start and stop bit per byte
+
+// Frame counter: 2 bytes (MSB first), counts up by one as each frame starts, wrapping around after 65535. Writing
+// to it sets where it carries on counting from.
+// Vblank count: 1 byte, counts up by one as each vertical blanking period begins, stopping at 255. It holds its
+// value until the program writes to it, so a main loop can wait for it to be nonzero, advance its animations by
+// that many frames and clear it, without missing frames it was too slow for or needing an interrupt handler.
+pub const FRAME_COUNTER: u16 = IO+0x64;
+pub const VBLANK_COUNT: u16 = IO+0x66;
+// Text console: 1 byte, write a character to print it. The console takes it the next time it catches up with the VM,
+// setting the register back to 0, so wait for the register to read 0 before writing the next. Text is drawn over the
+// screen in a built-in font, TEXT_COLUMNS characters to a row. Newline (0x0A) starts a new row, backspace (0x08)
+// takes back the last character and form feed (0x0C) clears the text; other characters outside of printable ASCII
+// are ignored. Once TEXT_ROWS rows are full, the text scrolls up. Without a screen, the text is printed to the terminal.
+// The text console is only plugged in with `--text`; otherwise the register is plain memory.
+pub const TEXT_OUT: u16 = IO+0x67;
+pub const TEXT_COLUMNS: usize = SCREEN_WIDTH as usize/TILE_LENGTH;
+pub const TEXT_ROWS: usize = SCREEN_HEIGHT as usize/TILE_LENGTH;
+// Sprite animation: 2 bytes (MSB first), the address of the animation table; 0 turns animation off
+// The table is a list of 7 byte entries [sprite, frames MSB, frames LSB, length, duration, position, timer], ended by
+// a sprite of 0xFF. Frames is the address of a list of `length` start indices, and each is shown for `duration`
+// vblanks before moving on to the next, looping back to the first after the last. As each vblank begins, before VRAM
+// is latched, the timer counts up and the position moves on once it reaches the duration, then the sprite's start
+// index is set to the frame at the position. Position and timer are written back, so setting both to 0 restarts the
+// animation. A duration of 0 holds the current frame, and a length of 0 leaves the sprite alone.
+pub const SPRITE_ANIMATION: u16 = IO+0x68;
+pub const ANIMATION_ENTRY_SIZE: usize = 7;
+pub const ANIMATION_END: u8 = 0xFF;
 // VRAM mapping -- 48KiB
 pub const VRAM: u16 = 0x6000;
 // Palettes
@@ -31,10 +265,19 @@ pub const TILEMAP_COUNT: usize = 2;
 pub const TILEMAP_OFFSET: u16 = TILEMAP_START+(TILEMAP_SIZE*TILEMAP_COUNT) as u16;
 
 // Backgrounds
+
+// Each background map entry is 2 bytes: a tile index, followed by an attribute byte
+// [h flip|v flip|unused|palette 2|palette 1|palette 0|priority 1|priority 0]
+//  h flip: the tile is mirrored horizontally
+//         v flip: the tile is mirrored vertically
+//                       palette: 0-7, added to the background's palette (wrapping around)
+//                                                     priority: 0-3, raises the tile above its background's priority
 pub const BG_START: u16 = TILEMAP_OFFSET;
 pub const BG_WIDTH: usize = SCREEN_WIDTH as usize/TILE_LENGTH;
 pub const BG_HEIGHT: usize = SCREEN_HEIGHT as usize/TILE_LENGTH;
-pub const BG_SIZE: usize = BG_WIDTH*BG_HEIGHT;
+pub const BG_ENTRY_SIZE: usize = 2;
+pub const BG_LENGTH: usize = BG_WIDTH*BG_HEIGHT;
+pub const BG_SIZE: usize = BG_ENTRY_SIZE*BG_LENGTH;
 pub const BG_COUNT: usize = 4;
 pub const BG_OFFSET: u16 = BG_START+(BG_SIZE*BG_COUNT) as u16;
 
@@ -48,8 +291,26 @@ pub const BG_OFFSET: u16 = BG_START+(BG_SIZE*BG_COUNT) as u16;
 //                                                                           priority: 0-3, higher priority is drawn over lower
 // Location: 2 bytes (x then y)
 // Start index: 1 byte
-// Rendering info: 1 byte [unused|unused|unused|unused|unused|unused|unused|enabled]
+// Rendering info: 1 byte [unused|affine 2|affine 1|affine 0|transform|tall|opaque|enabled]
+//                                affine: 0-7, selects the affine parameters used when transform is set
+//                                                           transform: the sprite is rotated/scaled by its affine parameters
+//                                                                     tall: the sprite is twice as high as it is wide, using vertically stacked tiles
+//                                                                          opaque: palette index 0 is drawn instead of being transparent
 pub const SPRITE_START: u16 = BG_OFFSET;
 pub const SPRITE_SIZE: usize = 5;
 pub const SPRITE_COUNT: usize = 128;
-// pub const SPRITE_OFFSET: u16 = SPRITE_START+(SPRITE_SIZE*SPRITE_COUNT) as u16;
\ No newline at end of file
+pub const SPRITE_OFFSET: u16 = SPRITE_START+(SPRITE_SIZE*SPRITE_COUNT) as u16;
+// At most SPRITE_LINE_PIXELS pixels of sprites are drawn on each scanline, counting the full width of every sprite
+// overlapping it. Sprites are taken from highest to lowest priority (lowest to highest index within a priority),
+// and once one doesn't fit, it and the rest are dropped from that scanline.
+pub const SPRITE_LINE_PIXELS: usize = 256;
+
+// Affine parameters
+
+// Each set of affine parameters is a 2x2 matrix of signed 8.8 fixed point words (MSB first), laid out as
+// [pa, pb, pc, pd]. A transformed sprite samples its own pixels at (pa*dx + pb*dy, pc*dx + pd*dy), where
+// (dx, dy) is the offset of a screen pixel from the centre of the sprite -- e.g. [1.0, 0, 0, 1.0] is the
+// identity, and [cos, -sin, sin, cos] rotates the sprite counter-clockwise.
+pub const AFFINE_START: u16 = SPRITE_OFFSET;
+pub const AFFINE_SIZE: usize = 8;
+pub const AFFINE_COUNT: usize = 8;
@@ -1,2 +1,9 @@
-pub mod mmio;
-pub mod gfx;
\ No newline at end of file
+pub use popola::inter::mmio;
+pub mod animation;
+pub mod gfx;
+pub mod gamepad;
+pub mod input_map;
+pub mod joypad;
+pub mod serial;
+pub mod text;
+pub mod toggles;
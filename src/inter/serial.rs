@@ -0,0 +1,261 @@
+use crate::inter::mmio::*;
+
+use devola::vm::Devola;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::rc::Rc;
+
+/// Where the serial port's bytes go on the host side. Reads mustn't block: with nothing to read, they
+/// should fail with `WouldBlock`, while reading 0 bytes means the other end has gone away.
+pub trait Transport: Read + Write {}
+
+impl<T: Read + Write> Transport for T {}
+
+/// Sets up a TCP stream to carry serial bytes, sending each one as it's written
+fn tcp_transport(stream: TcpStream) -> io::Result<Box<dyn Transport>> {
+    stream.set_nodelay(true)?;
+    stream.set_nonblocking(true)?;
+    Ok(Box::new(stream))
+}
+
+/// Connects to another instance listening at `address`
+pub fn connect(address: &str) -> io::Result<Box<dyn Transport>> {
+    tcp_transport(TcpStream::connect(address)?)
+}
+
+/// Waits for another instance to connect at `address`
+pub fn listen(address: &str) -> io::Result<Box<dyn Transport>> {
+    let (stream, _) = TcpListener::bind(address)?.accept()?;
+    tcp_transport(stream)
+}
+
+/// One end of an in-memory cable, reading what the other end writes
+pub struct PipeEnd {
+    input: Rc<RefCell<VecDeque<u8>>>,
+    output: Rc<RefCell<VecDeque<u8>>>
+}
+
+/// Two transports wired to each other, for connecting consoles in the same process
+pub fn pipe() -> (PipeEnd, PipeEnd) {
+    let (forward, backward) = (Rc::new(RefCell::new(VecDeque::new())), Rc::new(RefCell::new(VecDeque::new())));
+    (
+        PipeEnd { input: backward.clone(), output: forward.clone() },
+        PipeEnd { input: forward, output: backward }
+    )
+}
+
+impl Read for PipeEnd {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        let mut input = self.input.borrow_mut();
+        if input.is_empty() {
+            // the other end holds the only other reference, so once it's dropped nothing more can arrive
+            return if Rc::strong_count(&self.input) == 1 { Ok(0) } else { Err(ErrorKind::WouldBlock.into()) };
+        }
+        let read = buffer.len().min(input.len());
+        for (byte, received) in buffer.iter_mut().zip(input.drain(..read)) {
+            *byte = received;
+        }
+        Ok(read)
+    }
+}
+
+impl Write for PipeEnd {
+    fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+        self.output.borrow_mut().extend(buffer);
+        Ok(buffer.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The serial port. Without a transport, bytes sent go nowhere and nothing is ever received.
+pub struct Serial {
+    transport: Option<Box<dyn Transport>>,
+    /// Bytes read from the transport, waiting for the program to take the last one
+    incoming: VecDeque<u8>,
+    /// Cycles left before the byte being sent is done, if one is
+    sending: Option<u64>
+}
+
+impl Default for Serial {
+    fn default() -> Self {
+        Serial::new(None)
+    }
+}
+
+impl Serial {
+    pub fn new(transport: Option<Box<dyn Transport>>) -> Serial {
+        Serial { transport, incoming: VecDeque::new(), sending: None }
+    }
+
+    pub fn connected(&self) -> bool {
+        self.transport.is_some()
+    }
+
+    fn disconnect(&mut self, error: &str) {
+        tracing::warn!("Serial transport disconnected: {}", error);
+        self.transport = None;
+    }
+
+    fn send(&mut self, byte: u8) {
+        if let Some(transport) = self.transport.as_mut() {
+            if let Err(error) = transport.write_all(&[byte]).and_then(|_| transport.flush()) {
+                self.disconnect(&error.to_string());
+            }
+        }
+    }
+
+    fn receive(&mut self) {
+        let mut buffer = [0; 64];
+        while let Some(transport) = self.transport.as_mut() {
+            match transport.read(&mut buffer) {
+                Ok(0) => self.disconnect("end of stream"),
+                Ok(read) => self.incoming.extend(&buffer[..read]),
+                Err(error) if error.kind() == ErrorKind::WouldBlock => break,
+                Err(error) if error.kind() == ErrorKind::Interrupted => (),
+                Err(error) => self.disconnect(&error.to_string())
+            }
+        }
+    }
+
+    /// The port's progress: the cycles left on the byte being sent, or 0, then the received bytes waiting to be
+    /// handed to the program
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.sending.unwrap_or(0).to_le_bytes().to_vec();
+        bytes.extend(&self.incoming);
+        bytes
+    }
+
+    /// Puts the port's progress back as `to_bytes` wrote it, keeping what it's connected to. Bytes too short to
+    /// hold the cycles left are ignored.
+    pub fn restore(&mut self, bytes: &[u8]) {
+        let Some((sending, incoming)) = bytes.split_first_chunk::<8>() else { return };
+        let sending = u64::from_le_bytes(*sending);
+        self.sending = (sending != 0).then_some(sending);
+        self.incoming = incoming.iter().copied().collect();
+    }
+
+    /// Moves the port forward by `cycles`: starts sending a byte if the program asked to, finishes the one
+    /// being sent, and hands the next received byte to the program once it has taken the last.
+    pub fn run(&mut self, devola: &mut Devola, cycles: u64) {
+        let status = devola.peek(SERIAL_STATUS);
+        if self.sending.is_none() && status & SERIAL_SEND_FLAG != 0 {
+            self.send(devola.peek(SERIAL_OUT));
+            self.sending = Some(SERIAL_BYTE_CYCLES);
+        }
+        if let Some(remaining) = self.sending {
+            if remaining <= cycles {
+                self.sending = None;
+                devola.poke(SERIAL_STATUS, devola.peek(SERIAL_STATUS) & !SERIAL_SEND_FLAG);
+                devola.request_interrupt(SERIAL_INTERRUPT);
+            } else {
+                self.sending = Some(remaining - cycles);
+            }
+        }
+
+        self.receive();
+        let status = devola.peek(SERIAL_STATUS);
+        if status & SERIAL_RECEIVED_FLAG == 0 {
+            if let Some(byte) = self.incoming.pop_front() {
+                devola.poke(SERIAL_IN, byte);
+                devola.poke(SERIAL_STATUS, status | SERIAL_RECEIVED_FLAG);
+                devola.request_interrupt(SERIAL_INTERRUPT);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use devola::vm::INTERRUPT_REQUEST;
+
+    /// Reads from a fixed queue, and records what's written where the test can see it
+    struct FakeTransport {
+        input: VecDeque<u8>,
+        output: Rc<RefCell<Vec<u8>>>
+    }
+
+    impl Read for FakeTransport {
+        fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+            match self.input.pop_front() {
+                Some(byte) => {
+                    buffer[0] = byte;
+                    Ok(1)
+                },
+                None => Err(ErrorKind::WouldBlock.into())
+            }
+        }
+    }
+
+    impl Write for FakeTransport {
+        fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+            self.output.borrow_mut().extend_from_slice(buffer);
+            Ok(buffer.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_serial() {
+        let output = Rc::new(RefCell::new(Vec::new()));
+        let transport = FakeTransport { input: VecDeque::from([0x12, 0x34]), output: output.clone() };
+        let mut serial = Serial::new(Some(Box::new(transport)));
+        let mut devola = Devola::new(Vec::new(), None);
+
+        // the first byte arrives straight away, and the second waits until the first is taken
+        serial.run(&mut devola, 1);
+        assert_eq!(devola.peek(SERIAL_IN), 0x12);
+        assert_eq!(devola.peek(SERIAL_STATUS), SERIAL_RECEIVED_FLAG);
+        assert_eq!(devola.peek(INTERRUPT_REQUEST), 1 << SERIAL_INTERRUPT);
+        serial.run(&mut devola, 1);
+        assert_eq!(devola.peek(SERIAL_IN), 0x12);
+        devola.poke(SERIAL_STATUS, 0);
+        serial.run(&mut devola, 1);
+        assert_eq!(devola.peek(SERIAL_IN), 0x34);
+
+        // sending takes a byte's worth of cycles
+        devola.poke(INTERRUPT_REQUEST, 0);
+        devola.poke(SERIAL_OUT, 0x56);
+        devola.poke(SERIAL_STATUS, SERIAL_SEND_FLAG | SERIAL_RECEIVED_FLAG);
+        serial.run(&mut devola, SERIAL_BYTE_CYCLES - 1);
+        assert_eq!(*output.borrow(), vec![0x56]);
+        assert_eq!(devola.peek(SERIAL_STATUS), SERIAL_SEND_FLAG | SERIAL_RECEIVED_FLAG);
+        assert_eq!(devola.peek(INTERRUPT_REQUEST), 0);
+        serial.run(&mut devola, 1);
+        assert_eq!(devola.peek(SERIAL_STATUS), SERIAL_RECEIVED_FLAG);
+        assert_eq!(devola.peek(INTERRUPT_REQUEST), 1 << SERIAL_INTERRUPT);
+        assert_eq!(*output.borrow(), vec![0x56]);
+        assert!(serial.connected());
+
+        // a port put back partway through sending, with a byte waiting, stays connected
+        let progress = [5, 0, 0, 0, 0, 0, 0, 0, 0x78];
+        serial.restore(&progress);
+        assert_eq!(serial.to_bytes(), progress);
+        assert!(serial.connected());
+    }
+
+    #[test]
+    fn test_pipe() {
+        let (mut first, mut second) = pipe();
+        let mut buffer = [0; 4];
+        assert_eq!(first.read(&mut buffer).unwrap_err().kind(), ErrorKind::WouldBlock);
+        first.write_all(&[1, 2, 3]).unwrap();
+        second.write_all(&[4]).unwrap();
+        assert_eq!(second.read(&mut buffer).unwrap(), 3);
+        assert_eq!(buffer[..3], [1, 2, 3]);
+        assert_eq!(first.read(&mut buffer).unwrap(), 1);
+        assert_eq!(buffer[0], 4);
+
+        // dropping one end reads as the end of the stream at the other
+        drop(second);
+        assert_eq!(first.read(&mut buffer).unwrap(), 0);
+    }
+}
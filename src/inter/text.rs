@@ -0,0 +1,119 @@
+use crate::inter::mmio::*;
+
+use devola::vm::Devola;
+use std::io::{self, Write};
+
+const NEWLINE: u8 = 0x0A;
+const BACKSPACE: u8 = 0x08;
+const FORM_FEED: u8 = 0x0C;
+
+/// The text console: characters written to `TEXT_OUT`, kept as rows of text to draw over the screen
+#[derive(Default)]
+pub struct TextConsole {
+    rows: Vec<String>
+}
+
+impl TextConsole {
+    /// The rows of text, oldest first
+    pub fn rows(&self) -> &[String] {
+        &self.rows
+    }
+
+    /// Puts back rows taken from `rows`, e.g. by a save state
+    pub fn set_rows(&mut self, rows: Vec<String>) {
+        self.rows = rows;
+    }
+
+    pub fn clear(&mut self) {
+        self.rows.clear();
+    }
+
+    /// Takes the character waiting in the port, if there is one, and sets the port back to 0. It's added to the
+    /// rows, or without a screen to show it on, printed to the terminal instead.
+    pub fn update(&mut self, devola: &mut Devola, headless: bool) {
+        let character = devola.peek(TEXT_OUT);
+        if character == 0 {
+            return;
+        }
+        devola.poke(TEXT_OUT, 0);
+        if headless {
+            let mut stdout = io::stdout();
+            let _ = stdout.write_all(&[character]).and_then(|_| stdout.flush());
+        } else {
+            self.write(character);
+        }
+    }
+
+    fn write(&mut self, character: u8) {
+        match character {
+            NEWLINE => self.new_row(),
+            BACKSPACE => {
+                self.rows.last_mut().map(String::pop);
+            },
+            FORM_FEED => self.rows.clear(),
+            b' '..=b'~' => {
+                if self.rows.last().is_none_or(|row| row.len() == TEXT_COLUMNS) {
+                    self.new_row();
+                }
+                self.rows.last_mut().unwrap().push(character as char);
+            },
+            _ => ()
+        }
+    }
+
+    /// Starts a new row, scrolling the oldest off the top once the screen is full
+    fn new_row(&mut self) {
+        if self.rows.len() == TEXT_ROWS {
+            self.rows.remove(0);
+        }
+        self.rows.push(String::new());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use devola::parser::text::compile;
+
+    #[test]
+    fn test_text_console() {
+        // "Hi", a newline and "!", each written once the last has been taken
+        let source = format!("
+            lda 72
+            call put
+            lda 105
+            call put
+            lda 10
+            call put
+            lda 33
+            call put
+            jmp end
+            put:
+            sta #{0:04X}h
+            wait:
+            cjne #{0:04X}h, 0, wait
+            ret
+            end:
+        ", TEXT_OUT);
+        let (code, symbols, _, _) = compile(source, None).unwrap();
+        let mut devola = Devola::new(code, Some(symbols));
+        let mut text = TextConsole::default();
+        while devola.run_cycles(8).unwrap() > 0 {
+            text.update(&mut devola, false);
+        }
+        assert_eq!(text.rows(), ["Hi", "!"]);
+        assert_eq!(devola.peek(TEXT_OUT), 0);
+
+        // long rows wrap, backspace takes back a character and the oldest rows scroll off
+        text.write(FORM_FEED);
+        assert!(text.rows().is_empty());
+        for character in std::iter::repeat_n(b'a', TEXT_COLUMNS + 1).chain([BACKSPACE]) {
+            text.write(character);
+        }
+        assert_eq!(text.rows(), ["a".repeat(TEXT_COLUMNS), String::new()]);
+        for _ in 0..TEXT_ROWS - 1 {
+            text.write(NEWLINE);
+        }
+        assert_eq!((text.rows().len(), text.rows()[0].as_str()), (TEXT_ROWS, ""));
+    }
+}
@@ -0,0 +1,60 @@
+use crate::inter::mmio::*;
+
+use devola::vm::Devola;
+
+/// Where a sprite's rendering info is, after its properties, location and start index
+const RENDERING_INFO_OFFSET: u16 = 4;
+
+/// Takes the sprite toggled through `SPRITE_TOGGLES` and the background settings written to `BG_SETTINGS`,
+/// applying them to the sprite table and background control registers
+pub fn apply_toggles(devola: &mut Devola) {
+    let toggles = devola.peek(SPRITE_TOGGLES);
+    let sprite = (toggles & SPRITE_TOGGLE_MASK) as u16;
+    if sprite != 0 {
+        let info = SPRITE_START + sprite*SPRITE_SIZE as u16 + RENDERING_INFO_OFFSET;
+        devola.poke(info, devola.peek(info) ^ 0b0000_0001);
+        devola.poke(SPRITE_TOGGLES, toggles & SPRITES_OFF_FLAG);
+    }
+
+    let settings = devola.peek(BG_SETTINGS);
+    if settings & BG_SETTINGS_SET_FLAG != 0 {
+        let control = BG_CONTROL + ((settings >> 1) & 0b11) as u16;
+        // enable and tilemap are in the same place, and the palette moves down past the unused bit
+        let bits = (settings & 0b1100_0000) | ((settings >> 1) & 0b0001_1100);
+        devola.poke(control, (devola.peek(control) & 0b0010_0011) | bits);
+        devola.poke(BG_SETTINGS, settings & !BG_SETTINGS_SET_FLAG);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply_toggles() {
+        let mut devola = Devola::new(Vec::new(), None);
+        let info = |devola: &Devola, sprite: u16| devola.peek(SPRITE_START + sprite*SPRITE_SIZE as u16 + RENDERING_INFO_OFFSET);
+
+        // sprite 3 is flipped on and back off, leaving all off alone
+        devola.poke(SPRITE_TOGGLES, SPRITES_OFF_FLAG | 3);
+        apply_toggles(&mut devola);
+        assert_eq!(info(&devola, 3), 0b0000_0001);
+        assert_eq!(devola.peek(SPRITE_TOGGLES), SPRITES_OFF_FLAG);
+        apply_toggles(&mut devola);
+        assert_eq!(info(&devola, 3), 0b0000_0001);
+        devola.poke(SPRITE_TOGGLES, 3);
+        apply_toggles(&mut devola);
+        assert_eq!(info(&devola, 3), 0);
+
+        // BG 2 is enabled with tilemap 1 and palette 5, keeping its priority of 3
+        devola.poke(BG_CONTROL + 2, 0b0000_0011);
+        devola.poke(BG_SETTINGS, 0b1110_1101);
+        apply_toggles(&mut devola);
+        assert_eq!(devola.peek(BG_CONTROL + 2), 0b1101_0111);
+        assert_eq!(devola.peek(BG_SETTINGS), 0b1110_1100);
+        // without set, nothing changes
+        devola.poke(BG_SETTINGS, 0b0000_0100);
+        apply_toggles(&mut devola);
+        assert_eq!(devola.peek(BG_CONTROL + 2), 0b1101_0111);
+    }
+}
@@ -0,0 +1,4 @@
+//! The parts of Popola its tools share with it: the memory map and the hardware registers
+pub mod inter {
+    pub mod mmio;
+}
@@ -0,0 +1,129 @@
+use crate::console::Console;
+use crate::inter::input_map::{Input, InputMap};
+use crate::inter::mmio::*;
+use crate::inter::serial::{self, Serial, Transport};
+use crate::render::context::RenderContext;
+
+use devola::vm::Devola;
+use pixels::{Pixels, SurfaceTexture};
+
+use winit::dpi::LogicalSize;
+use winit::event::WindowEvent;
+use winit::event_loop::ActiveEventLoop;
+use winit::keyboard::PhysicalKey;
+use winit::window::{Window, WindowId};
+
+/// How far the first console runs ahead before the second catches up with it. Well under the time a serial
+/// byte takes, so bytes arrive when they would between two real machines.
+pub const LINK_SLICE_CYCLES: u64 = CYCLES_PER_SCANLINE;
+
+/// A second console in its own window, with its serial port cabled to the first's. It's clocked in lockstep
+/// with the first, and played with the keyboard while its window has focus.
+pub struct Link {
+    pub console: Console,
+    window: Option<Window>,
+    render_context: Option<RenderContext>
+}
+
+impl Link {
+    /// Sets up the second console, returning the other end of its cable for the first
+    pub fn new(devola: Devola) -> (Link, Box<dyn Transport>) {
+        let (first, second) = serial::pipe();
+        let mut console = Console::new(devola);
+        console.serial = Serial::new(Some(Box::new(second)));
+        (Link { console, window: None, render_context: None }, Box::new(first))
+    }
+
+    pub fn create_window(&mut self, event_loop: &ActiveEventLoop, scale: u32) {
+        let window_attributes = Window::default_attributes()
+            .with_title("Popola (link)")
+            .with_inner_size(LogicalSize::new(scale*SCREEN_WIDTH, scale*SCREEN_HEIGHT))
+            .with_min_inner_size(LogicalSize::new(SCREEN_WIDTH, SCREEN_HEIGHT))
+            .with_resizable(true);
+        let window = event_loop.create_window(window_attributes).unwrap();
+
+        let inner_size = window.inner_size();
+        let surface_texture = SurfaceTexture::new(inner_size.width, inner_size.height, &window);
+        let pixels = Pixels::new(SCREEN_WIDTH, SCREEN_HEIGHT, surface_texture).unwrap();
+
+        self.render_context = Some(RenderContext::new(pixels));
+        self.window = Some(window);
+    }
+
+    pub fn id(&self) -> Option<WindowId> {
+        self.window.as_ref().map(Window::id)
+    }
+
+    pub fn request_redraw(&self) {
+        if let Some(window) = self.window.as_ref() {
+            window.request_redraw();
+        }
+    }
+
+    /// Runs the console until it has caught up with the first, which has run for `elapsed` cycles
+    pub fn catch_up(&mut self, elapsed: u64) {
+        if elapsed > self.console.elapsed {
+            let vram = self.render_context.as_mut().map(|render_context| &mut render_context.vrammodel);
+            self.console.run_cycles(elapsed - self.console.elapsed, vram);
+        }
+    }
+
+    /// Handles an event for the link window. Returns false once it has been closed.
+    pub fn window_event(&mut self, input_map: &InputMap, event: WindowEvent) -> bool {
+        let render_context = match self.render_context.as_mut() {
+            Some(render_context) => render_context,
+            None => return true
+        };
+        match event {
+            WindowEvent::CloseRequested => return false,
+            WindowEvent::Resized(size) => render_context.resize(size.width, size.height),
+            WindowEvent::KeyboardInput { event, .. } => {
+                if let PhysicalKey::Code(key) = event.physical_key {
+                    if let Some(button) = input_map.button(Input::Key(key)) {
+                        self.console.joypad.set_button(button, event.state.is_pressed());
+                    }
+                }
+            },
+            WindowEvent::RedrawRequested => render_context.render(),
+            _ => ()
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use devola::parser;
+
+    #[test]
+    fn test_link() {
+        // sends a byte, and stores any byte received at 0x200
+        let code = "
+            lda 42
+            sta #0F61h
+            lda 80h
+            sta #0F63h
+        wait:
+            lda #0F63h
+            cmp 1
+            jnz wait
+            lda #0F62h
+            sta #0200h
+        end:
+            jmp end".to_string();
+        let (code, symbols, _, _) = parser::text::compile(code, None).unwrap();
+        let mut first = Console::new(Devola::new(code.clone(), Some(symbols.clone())));
+        let (mut link, transport) = Link::new(Devola::new(code, Some(symbols)));
+        first.serial = Serial::new(Some(transport));
+
+        while first.elapsed < 2*SERIAL_BYTE_CYCLES {
+            first.run_cycles(LINK_SLICE_CYCLES, None);
+            link.catch_up(first.elapsed);
+            // lockstep keeps the two within an instruction of each other
+            assert!(link.console.elapsed.abs_diff(first.elapsed) < 16);
+        }
+        assert_eq!(first.devola.peek(0x200), 42);
+        assert_eq!(link.console.devola.peek(0x200), 42);
+    }
+}
@@ -2,12 +2,210 @@ mod inter;
 mod gfx;
 mod render;
 mod application;
+mod debug;
+mod audio;
+mod console;
+mod link;
+mod replay;
+mod savestate;
+mod audit;
+mod program;
+mod settings;
+mod capture;
 
+use audit::AuditLog;
+use debug::gdb::GdbServer;
+use debug::reload::ReloadServer;
+use inter::serial::{self, Transport};
+use replay::Replay;
+use settings::{Settings, SETTINGS_PATH};
+
+use devola::vm::Devola;
+use std::path::Path;
+use std::{env, io, process};
+use tracing_subscriber::EnvFilter;
 use winit::event_loop::EventLoop;
 
+/// Reads a program, exiting with the errors if it can't be loaded
+fn load_program(path: &str) -> Devola {
+    match program::load(Path::new(path)) {
+        Ok(devola) => devola,
+        Err(errors) => {
+            eprintln!("{}", errors);
+            process::exit(1);
+        }
+    }
+}
+
+const USAGE: &str = "Usage: popola [program.pop | program.popr] [--serial-connect <address> | --serial-listen <address> | --link <program.pop>] [--record <file> | --replay <file>] [--record-audit <file> | --audit <file>] [--gdb <address>] [--hot-reload <address>] [--text]";
+
+/// What the serial port is plugged into, as given on the command line
+#[derive(Default)]
+enum SerialCable<'a> {
+    #[default]
+    Unplugged,
+    Connect(&'a str),
+    Listen(&'a str),
+    /// Another program, run alongside in a second window
+    Link(&'a str)
+}
+
+/// Options given after the program on the command line
+#[derive(Default)]
+struct Options<'a> {
+    serial: SerialCable<'a>,
+    record: Option<&'a str>,
+    replay: Option<&'a str>,
+    record_audit: Option<&'a str>,
+    audit: Option<&'a str>,
+    /// Where GDB can attach
+    gdb: Option<&'a str>,
+    /// Where `devola-asm` can send re-assembled programs
+    hot_reload: Option<&'a str>,
+    /// Whether the text console is plugged in
+    text: bool
+}
+
+fn parse_options(args: &[String]) -> Options<'_> {
+    let mut options = Options::default();
+    let mut args = args.iter();
+    while let Some(option) = args.next() {
+        if option == "--text" {
+            options.text = true;
+            continue;
+        }
+        match (option.as_str(), args.next()) {
+            ("--serial-connect", Some(address)) => options.serial = SerialCable::Connect(address),
+            ("--serial-listen", Some(address)) => options.serial = SerialCable::Listen(address),
+            ("--link", Some(path)) => options.serial = SerialCable::Link(path),
+            ("--record", Some(path)) => options.record = Some(path),
+            ("--replay", Some(path)) => options.replay = Some(path),
+            ("--record-audit", Some(path)) => options.record_audit = Some(path),
+            ("--audit", Some(path)) => options.audit = Some(path),
+            ("--gdb", Some(address)) => options.gdb = Some(address),
+            ("--hot-reload", Some(address)) => options.hot_reload = Some(address),
+            _ => {
+                eprintln!("{}", USAGE);
+                process::exit(2);
+            }
+        }
+    }
+    // an audit is either recorded or checked, not both
+    if options.record_audit.is_some() && options.audit.is_some() {
+        eprintln!("{}", USAGE);
+        process::exit(2);
+    }
+    options
+}
+
+/// Exits if a serial connection couldn't be made
+fn expect_transport(transport: io::Result<Box<dyn Transport>>) -> Box<dyn Transport> {
+    match transport {
+        Ok(transport) => transport,
+        Err(error) => {
+            eprintln!("Failed to open serial connection: {}", error);
+            process::exit(1);
+        }
+    }
+}
 
 fn main() {
-    let mut application = application::Application::new();
+    // `RUST_LOG` picks what's logged, e.g. `RUST_LOG=devola=debug` to follow the program's calls
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")))
+        .with_writer(io::stderr)
+        .init();
+    let args: Vec<String> = env::args().skip(1).collect();
+    let (program, options) = match args.split_first() {
+        Some((program, options)) if !program.starts_with("--") => (Some(program.clone()), options),
+        _ => (None, &args[..])
+    };
+    // without a program, the last one opened is run again
+    // settings that can't be read are left alone, rather than saved over with the defaults
+    let (settings, settings_path) = match Settings::load(SETTINGS_PATH) {
+        Ok(settings) => (settings, Some(SETTINGS_PATH)),
+        Err(error) => {
+            tracing::error!("Failed to load settings from {}, so they won't be saved: {}", SETTINGS_PATH, error);
+            (Settings::default(), None)
+        }
+    };
+    let path = match program.or_else(|| settings.recent.first().cloned()) {
+        Some(path) => path,
+        None => {
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        }
+    };
+
+    let mut application = application::Application::new(load_program(&path));
+    application.use_settings(settings, settings_path);
+    application.remember(Path::new(&path));
+    let options = parse_options(options);
+    if options.text {
+        application.plug_in_text();
+    }
+    match options.serial {
+        SerialCable::Unplugged => (),
+        SerialCable::Connect(address) => application.connect_serial(expect_transport(serial::connect(address))),
+        SerialCable::Listen(address) => {
+            tracing::info!("Waiting for a serial connection on {}", address);
+            application.connect_serial(expect_transport(serial::listen(address)));
+        },
+        SerialCable::Link(path) => application.link(load_program(path))
+    }
+    if let Some(path) = options.replay {
+        match Replay::load(path).and_then(|replay| application.play(replay).map_err(|error| error.to_string())) {
+            Ok(()) => (),
+            Err(error) => {
+                eprintln!("Failed to load replay {}: {}", path, error);
+                process::exit(1);
+            }
+        }
+    }
+    if let Some(path) = options.record {
+        application.record(path);
+    }
+    if let Some(path) = options.audit {
+        match AuditLog::load(path) {
+            Ok(log) => application.check_audit(log),
+            Err(error) => {
+                eprintln!("Failed to load audit {}: {}", path, error);
+                process::exit(1);
+            }
+        }
+    }
+    if let Some(path) = options.record_audit {
+        application.record_audit(path);
+    }
+    if let Some(address) = options.gdb {
+        match GdbServer::listen(address) {
+            Ok(server) => {
+                // the address the port was actually bound to, in case it was left to the OS
+                if let Ok(bound) = server.address() {
+                    tracing::info!("GDB can attach on {}", bound);
+                }
+                application.listen_gdb(server);
+            },
+            Err(error) => {
+                eprintln!("Failed to listen for GDB on {}: {}", address, error);
+                process::exit(1);
+            }
+        }
+    }
+    if let Some(address) = options.hot_reload {
+        match ReloadServer::listen(address) {
+            Ok(server) => {
+                if let Ok(bound) = server.address() {
+                    tracing::info!("Listening for programs on {}", bound);
+                }
+                application.listen_reload(server);
+            },
+            Err(error) => {
+                eprintln!("Failed to listen for programs on {}: {}", address, error);
+                process::exit(1);
+            }
+        }
+    }
     let event_loop = EventLoop::new().unwrap();
 
     event_loop.run_app(&mut application).unwrap();
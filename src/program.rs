@@ -0,0 +1,75 @@
+//! Reading programs from disk, either as source to assemble or as ROM images built with `devola-asm`
+use crate::gfx::font;
+
+use devola::binary::{self, RomImage};
+use devola::cartridge::Cartridge;
+use devola::vm::Devola;
+use std::fs;
+use std::path::Path;
+
+/// Whether a file is assembly source rather than a ROM image, going by its extension
+pub fn is_source(path: &Path) -> bool {
+    path.extension().is_some_and(|extension| extension == "pop")
+}
+
+/// Reads a program into a fresh VM, or returns why it couldn't be, one error per line
+pub fn load(path: &Path) -> Result<Devola, String> {
+    let located = |error: &dyn std::fmt::Display| format!("{}: {}", path.display(), error);
+    let read_error = |error: std::io::Error| format!("Failed to read {}: {}", path.display(), error);
+    let bytes = fs::read(path).map_err(read_error)?;
+    // the extension decides how the file is read, so one that doesn't match its contents is reported as such
+    let cartridge = if is_source(path) {
+        if binary::is_encoded(&bytes) {
+            return Err(located(&"an encoded program, not source; rename it to .popr"));
+        }
+        let source = String::from_utf8(bytes).map_err(|_| located(&"not source, as it isn't valid UTF-8"))?;
+        Cartridge::compile_from(source, font::externs(), path)
+            .map_err(|errors| errors.iter().map(|error| located(error)).collect::<Vec<_>>().join("\n"))?
+    } else {
+        if !binary::is_encoded(&bytes) {
+            return Err(located(&"not an encoded program; source should be named .pop"));
+        }
+        Cartridge::from(RomImage::decode(&bytes).map_err(|error| located(&error))?)
+    };
+    let mut devola = cartridge.load().map_err(|error| located(&error))?;
+    for (name, function) in font::externs() {
+        devola.register_extern(&name, function);
+    }
+    // calls are only traced while debugging, so the VM is kept out of it unless someone's listening
+    if tracing::enabled!(target: "devola::vm", tracing::Level::DEBUG) {
+        devola.enable_debug();
+    }
+    Ok(devola)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use devola::instructions::{Instruction, Register};
+
+    #[test]
+    fn test_load() {
+        let directory = std::env::temp_dir();
+        let source = directory.join(format!("popola_program_test_{}.pop", std::process::id()));
+        fs::write(&source, "lda 2\ninc").unwrap();
+        let mut devola = load(&source).unwrap();
+        devola.run().unwrap();
+        assert_eq!(devola.register(Register::Accumulator), 3);
+
+        let rom = directory.join(format!("popola_program_test_{}.popr", std::process::id()));
+        fs::write(&rom, RomImage::new(vec![Instruction::Increment]).encode().unwrap()).unwrap();
+        let mut devola = load(&rom).unwrap();
+        devola.run().unwrap();
+        assert_eq!(devola.register(Register::Accumulator), 1);
+
+        // a source file dropped under the wrong name isn't assembled
+        fs::rename(&source, &rom).unwrap();
+        assert_eq!(load(&rom).err(), Some(format!("{}: not an encoded program; source should be named .pop", rom.display())));
+        // and neither is an encoded program named as source
+        fs::write(&source, RomImage::new(vec![Instruction::Increment]).encode().unwrap()).unwrap();
+        assert_eq!(load(&source).err(), Some(format!("{}: an encoded program, not source; rename it to .popr", source.display())));
+        fs::remove_file(&source).unwrap();
+        fs::remove_file(&rom).unwrap();
+        assert!(load(&rom).err().unwrap().starts_with("Failed to read"));
+    }
+}
@@ -1,160 +1,68 @@
 use crate::inter::mmio::*;
 use crate::gfx::*;
+use crate::debug::overlays::DebugOverlays;
+use crate::render::crt::CrtRenderer;
+use crate::render::overlay;
+use crate::render::renderer::Renderer;
+use crate::render::tile_cache::TileCache;
 
 use pixels::Pixels;
 
 pub(crate) struct RenderContext {
     pub vrammodel: VRAMModel,
+    /// Whether the frame is drawn through the CRT shader
+    pub crt_enabled: bool,
+    /// The text console's rows, drawn over the console's frame
+    pub text: Vec<String>,
+    /// Lines of text drawn over the corner of the frame
+    pub overlay: Vec<String>,
+    pub debug_overlays: DebugOverlays,
+    tile_cache: TileCache,
     pixels: Pixels,
+    crt: CrtRenderer
 }
 
 impl RenderContext {
     pub fn new(pixels: Pixels) -> RenderContext {
-        RenderContext { vrammodel: VRAMModel::empty_vram(), pixels }
+        let crt = CrtRenderer::new(pixels.context(), pixels.render_texture_format());
+        RenderContext { vrammodel: VRAMModel::empty_vram(), crt_enabled: false, text: Vec::new(), overlay: Vec::new(), debug_overlays: DebugOverlays::default(), tile_cache: TileCache::new(), pixels, crt }
     }
 
-    pub fn render(&mut self) {
-        let frame = self.pixels.frame_mut();
-        for (pi, pixel) in frame.chunks_exact_mut(4).enumerate() {
-            let x  = pi % SCREEN_WIDTH as usize;
-            let val = (8*(x/8) % 256) as u8;
-            let color = [val, 0x00, 0x00, 0xff];
-            pixel.copy_from_slice(&color);
+    /// Resizes the surface to match the window. The frame is drawn at the largest integer scale that
+    /// fits, with the remainder of the window letterboxed.
+    pub fn resize(&mut self, width: u32, height: u32) {
+        // minimized windows report a size of 0, which the surface can't be resized to
+        if width == 0 || height == 0 {
+            return;
         }
-        for sprite in &self.vrammodel.sprites {
-            if sprite.enabled() {
-                RenderContext::render_sprite(&self.vrammodel, sprite, frame);
-            }
+        if let Err(error) = self.pixels.resize_surface(width, height) {
+            tracing::error!("Failed to resize surface: {:?}", error);
         }
-        self.pixels.render().unwrap();
-    }
-
-    fn render_sprite(vram: &VRAMModel, sprite: &Sprite, frame: &mut [u8]) {
-        let properties = sprite.properties;
-        let tilemap = vram.tilemaps[properties.tilemap_index as usize];
-        let palette = vram.palettes[properties.palette_index as usize];
-
-        let pitch = SpriteSize::pitch(properties.size); // width of the whole sprite
-        let tile_pitch = pitch as usize / TILE_LENGTH; // width of the sprite in tiles
-
-        let tile_count = (tile_pitch * tile_pitch) as u8;
-
-        let tiles = &tilemap.tiles[sprite.gfx_start as usize..(sprite.gfx_start + tile_count) as usize];
-
-        let (top_x, top_y) = sprite.location;
-
-        tiles.iter()
-            .enumerate()
-            .for_each(|(index, tile)| {
-                // convert the tile into an array of bytes representing the pixel data
-                let tile_flat: Vec<u8> = tile.pixels
-                    .iter()
-                    .map(|palette_index| {
-                        let color = palette.colors[*palette_index as usize];
-                        [color.r, color.g, color.b, 0xFF]
-                    })
-                    .flatten()
-                    .collect();
-
-                let (tx, ty) = (index % tile_pitch, index / tile_pitch);
-
-                let (absolute_x, absolute_y) = (top_x as usize + TILE_LENGTH*tx, top_y as usize + TILE_LENGTH*ty);
-
-                tile_flat.chunks_exact(TILE_LENGTH*4)
-                    .enumerate()
-                    .for_each(|(line_index, line)| {
-                        let linear_start = SCREEN_WIDTH as usize*(absolute_y+line_index)*4 + absolute_x*4;
-                        frame[linear_start..linear_start+TILE_LENGTH*4].copy_from_slice(line);
-                    });
-            });
     }
 
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    fn dummy_tile() -> Tile {
-        Tile { pixels: [0; TILE_SIZE] }
+    /// The frame as the console drew it, without the debug overlays or text over it
+    pub fn capture_frame(&mut self) -> Vec<u8> {
+        let mut frame = vec![0; (SCREEN_WIDTH*SCREEN_HEIGHT*4) as usize];
+        Renderer::compose(&self.vrammodel, &mut self.tile_cache, &mut frame);
+        overlay::draw_console(&mut frame, SCREEN_WIDTH as usize, &self.text);
+        frame
     }
 
-    fn dummy_palette() -> Palette {
-        Palette { colors: [Color::BLACK; PALETTE_LENGTH] }
-    }
-    fn dummy_tilemap() -> Tilemap {
-        Tilemap { tiles: [dummy_tile(); TILEMAP_LENGTH*TILEMAP_LENGTH] }
-    }
-    fn dummy_background() -> Background {
-        Background { tiles: [0; BG_SIZE] }
-    }
-
-    fn dummy_sprite() -> Sprite {
-        Sprite {
-            properties: SpriteProperties {
-                tilemap_index: 0, palette_index: 0, size: SpriteSize::X8, priority: 0
-            },
-            location: (0, 0),
-            gfx_start: 0,
-            info: 0
+    pub fn render(&mut self) {
+        Renderer::compose(&self.vrammodel, &mut self.tile_cache, self.pixels.frame_mut());
+        overlay::draw_console(self.pixels.frame_mut(), SCREEN_WIDTH as usize, &self.text);
+        self.debug_overlays.draw(&self.vrammodel, self.pixels.frame_mut());
+        if !self.overlay.is_empty() {
+            overlay::draw_text(self.pixels.frame_mut(), SCREEN_WIDTH as usize, (0, 0), &self.overlay, Color::GREEN);
+        }
+        if self.crt_enabled {
+            let crt = &self.crt;
+            self.pixels.render_with(|encoder, render_target, context| {
+                crt.render(encoder, render_target, context);
+                Ok(())
+            }).unwrap();
+        } else {
+            self.pixels.render().unwrap();
         }
     }
-
-    #[test]
-    fn test_render_sprite() {
-        let mut palettes = [dummy_palette(); PALETTE_COUNT];
-        palettes[0] = Palette { colors: [
-            Color::BLACK,
-            Color::RED,
-            Color::GREEN,
-            Color::BLUE,
-            Color::RED,
-            Color::RED,
-            Color::RED,
-            Color::RED,
-            Color::RED,
-            Color::RED,
-            Color::RED,
-            Color::RED,
-            Color::RED,
-            Color::RED,
-            Color::RED,
-            Color::RED,
-        ] };
-        let mut tilemaps = [dummy_tilemap(); TILEMAP_COUNT];
-        let mut tiles = [dummy_tile(); TILEMAP_LENGTH*TILEMAP_LENGTH];
-        tiles[0] = Tile {
-            pixels: [
-                1, 1, 1, 1, 1, 1, 1, 1, // !!!!!!!!
-                0, 1, 2, 2, 2, 2, 1, 0, // _!@@@@!_
-                0, 0, 1, 3, 3, 1, 0, 0, // __!##!__
-                0, 0, 0, 2, 2, 0, 0, 0, // ___@@___
-                0, 0, 0, 2, 2, 0, 0, 0, // ___@@___
-                0, 0, 1, 3, 3, 1, 0, 0, // __!##!__
-                0, 1, 2, 2, 2, 2, 1, 0, // _!@@@@!_
-                1, 1, 1, 1, 1, 1, 1, 1, // !!!!!!!!
-            ]
-        };
-        tilemaps[0].tiles = tiles;
-
-        let backgrounds = [dummy_background(); BG_COUNT];
-        let mut sprites = [dummy_sprite(); SPRITE_COUNT];
-
-        sprites[0] = Sprite {
-            properties: SpriteProperties {
-                tilemap_index: 0,
-                size: SpriteSize::X8,
-                palette_index: 0,
-                priority: 0
-            },
-            location: (128, 128),
-            gfx_start: 0,
-            info: 0
-        };
-
-        let fake_vram = VRAMModel {
-            palettes, tilemaps, backgrounds, sprites
-        };
-
-    }
-}
\ No newline at end of file
+}
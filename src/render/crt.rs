@@ -0,0 +1,135 @@
+use pixels::{wgpu, PixelsContext};
+
+const CURVATURE: f32 = 0.03;
+const SCANLINE_STRENGTH: f32 = 0.35;
+const LOCALS_SIZE: u64 = 32;
+
+/// Draws the pixel buffer to the surface through a CRT shader, in place of the default scaling renderer.
+/// The frame keeps the same integer-scaled area that the scaling renderer would have drawn it to.
+pub struct CrtRenderer {
+    uniform_buffer: wgpu::Buffer,
+    bind_group: wgpu::BindGroup,
+    render_pipeline: wgpu::RenderPipeline
+}
+
+impl CrtRenderer {
+    pub fn new(context: &PixelsContext, render_texture_format: wgpu::TextureFormat) -> CrtRenderer {
+        let device = &context.device;
+        let module = device.create_shader_module(wgpu::include_wgsl!("crt.wgsl"));
+
+        let texture_view = context.texture.create_view(&wgpu::TextureViewDescriptor::default());
+        // linear filtering provides the blur
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("crt_sampler"),
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            ..Default::default()
+        });
+        let uniform_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("crt_uniform_buffer"),
+            size: LOCALS_SIZE,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false
+        });
+
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("crt_bind_group_layout"),
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                        multisampled: false,
+                        view_dimension: wgpu::TextureViewDimension::D2
+                    },
+                    count: None
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                    count: None
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: wgpu::BufferSize::new(LOCALS_SIZE)
+                    },
+                    count: None
+                }
+            ]
+        });
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("crt_bind_group"),
+            layout: &bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry { binding: 0, resource: wgpu::BindingResource::TextureView(&texture_view) },
+                wgpu::BindGroupEntry { binding: 1, resource: wgpu::BindingResource::Sampler(&sampler) },
+                wgpu::BindGroupEntry { binding: 2, resource: uniform_buffer.as_entire_binding() }
+            ]
+        });
+
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("crt_pipeline_layout"),
+            bind_group_layouts: &[&bind_group_layout],
+            push_constant_ranges: &[]
+        });
+        let render_pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("crt_pipeline"),
+            layout: Some(&pipeline_layout),
+            vertex: wgpu::VertexState {
+                module: &module,
+                entry_point: "vs_main",
+                buffers: &[]
+            },
+            primitive: wgpu::PrimitiveState::default(),
+            depth_stencil: None,
+            multisample: wgpu::MultisampleState::default(),
+            fragment: Some(wgpu::FragmentState {
+                module: &module,
+                entry_point: "fs_main",
+                targets: &[Some(wgpu::ColorTargetState {
+                    format: render_texture_format,
+                    blend: Some(wgpu::BlendState::REPLACE),
+                    write_mask: wgpu::ColorWrites::ALL
+                })]
+            }),
+            multiview: None
+        });
+
+        CrtRenderer { uniform_buffer, bind_group, render_pipeline }
+    }
+
+    pub fn render(&self, encoder: &mut wgpu::CommandEncoder, render_target: &wgpu::TextureView, context: &PixelsContext) {
+        // the clipping rectangle follows the surface size, so the locals are refreshed every frame
+        let (x, y, width, height) = context.scaling_renderer.clip_rect();
+        let locals = [
+            x as f32, y as f32, width as f32, height as f32,
+            context.texture_extent.width as f32, context.texture_extent.height as f32,
+            CURVATURE, SCANLINE_STRENGTH
+        ];
+        let bytes: Vec<u8> = locals.iter().flat_map(|value| value.to_ne_bytes()).collect();
+        context.queue.write_buffer(&self.uniform_buffer, 0, &bytes);
+
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("crt_render_pass"),
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view: render_target,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                    store: true
+                }
+            })],
+            depth_stencil_attachment: None
+        });
+        render_pass.set_pipeline(&self.render_pipeline);
+        render_pass.set_bind_group(0, &self.bind_group, &[]);
+        render_pass.set_scissor_rect(x, y, width, height);
+        render_pass.draw(0..3, 0..1);
+    }
+}
@@ -0,0 +1,38 @@
+// CRT post-process: barrel curvature, bilinear blur and scanlines
+
+struct Locals {
+    // the area of the surface the frame is drawn to: x, y, width, height
+    clip: vec4<f32>,
+    texture_size: vec2<f32>,
+    curvature: f32,
+    scanline_strength: f32,
+}
+
+@group(0) @binding(0) var r_tex_color: texture_2d<f32>;
+@group(0) @binding(1) var r_tex_sampler: sampler;
+@group(0) @binding(2) var<uniform> r_locals: Locals;
+
+// One full-screen triangle, generated from the vertex index
+@vertex
+fn vs_main(@builtin(vertex_index) index: u32) -> @builtin(position) vec4<f32> {
+    let x = f32(i32(index & 1u) * 4 - 1);
+    let y = f32(i32(index >> 1u) * 4 - 1);
+    return vec4<f32>(x, y, 0.0, 1.0);
+}
+
+@fragment
+fn fs_main(@builtin(position) position: vec4<f32>) -> @location(0) vec4<f32> {
+    // bend the frame outwards from the centre, like the face of a tube
+    let centered = (position.xy - r_locals.clip.xy) / r_locals.clip.zw * 2.0 - 1.0;
+    let curved = centered + centered * centered.yx * centered.yx * r_locals.curvature;
+    let tex_coord = curved * 0.5 + 0.5;
+
+    let color = textureSampleLevel(r_tex_color, r_tex_sampler, tex_coord, 0.0).rgb;
+
+    // darken the gaps between rows, leaving the centre of each row at full brightness
+    let row = fract(tex_coord.y * r_locals.texture_size.y);
+    let scanline = 1.0 - r_locals.scanline_strength * (1.0 - sin(row * 3.14159265));
+
+    let inside = all(tex_coord >= vec2<f32>(0.0)) && all(tex_coord <= vec2<f32>(1.0));
+    return select(vec4<f32>(0.0, 0.0, 0.0, 1.0), vec4<f32>(color * scanline, 1.0), inside);
+}
@@ -0,0 +1,193 @@
+//! Golden image tests: known VRAM fixtures are rendered headlessly and compared against the reference
+//! images checked in under `tests/golden`. Run with `POPOLA_REGENERATE_GOLDEN=1` to rewrite the
+//! references after an intentional change to the output; on a mismatch, the rendered frame is saved
+//! next to the reference as `<name>.actual.png` for inspection.
+
+use crate::inter::mmio::*;
+use crate::gfx::*;
+use crate::render::renderer::Renderer;
+use crate::render::tile_cache::TileCache;
+
+use std::env;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::PathBuf;
+
+const REGENERATE_VARIABLE: &str = "POPOLA_REGENERATE_GOLDEN";
+/// Largest difference allowed in any color channel before a pixel counts as mismatched
+const CHANNEL_TOLERANCE: u8 = 2;
+/// Number of mismatched pixels allowed before the test fails
+const MAX_MISMATCHED_PIXELS: usize = 0;
+
+fn golden_path(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests").join("golden").join(name)
+}
+
+fn write_png(path: &PathBuf, frame: &[u8]) {
+    let file = File::create(path).unwrap();
+    let mut encoder = png::Encoder::new(BufWriter::new(file), SCREEN_WIDTH, SCREEN_HEIGHT);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.set_compression(png::Compression::Best);
+    encoder.write_header().unwrap().write_image_data(frame).unwrap();
+}
+
+fn read_png(path: &PathBuf) -> Vec<u8> {
+    let file = File::open(path)
+        .unwrap_or_else(|_| panic!("missing reference {:?}, run with {}=1 to create it", path, REGENERATE_VARIABLE));
+    let mut reader = png::Decoder::new(file).read_info().unwrap();
+    let mut data = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut data).unwrap();
+    assert_eq!((info.width, info.height), (SCREEN_WIDTH, SCREEN_HEIGHT), "reference {:?} has the wrong size", path);
+    assert_eq!(info.color_type, png::ColorType::Rgba, "reference {:?} isn't RGBA", path);
+    data.truncate(info.buffer_size());
+    data
+}
+
+/// Renders the model and compares it against the reference image of the same name
+fn assert_golden(name: &str, vram: &VRAMModel) {
+    let mut frame = vec![0; (SCREEN_WIDTH*SCREEN_HEIGHT*4) as usize];
+    Renderer::compose(vram, &mut TileCache::new(), &mut frame);
+    let path = golden_path(&format!("{}.png", name));
+
+    if env::var_os(REGENERATE_VARIABLE).is_some() {
+        write_png(&path, &frame);
+        return;
+    }
+
+    let reference = read_png(&path);
+    let mismatched: Vec<usize> = frame.chunks_exact(4)
+        .zip(reference.chunks_exact(4))
+        .enumerate()
+        .filter(|(_, (actual, expected))| actual.iter().zip(expected.iter()).any(|(a, e)| a.abs_diff(*e) > CHANNEL_TOLERANCE))
+        .map(|(index, _)| index)
+        .collect();
+
+    if mismatched.len() > MAX_MISMATCHED_PIXELS {
+        let actual_path = golden_path(&format!("{}.actual.png", name));
+        write_png(&actual_path, &frame);
+        let first = mismatched[0];
+        panic!(
+            "{} pixels differ from {:?}, starting at ({}, {}); the rendered frame was saved to {:?}",
+            mismatched.len(), path, first % SCREEN_WIDTH as usize, first / SCREEN_WIDTH as usize, actual_path
+        );
+    }
+}
+
+// Fixtures
+
+/// Palette n fades from black to a different hue in each palette
+fn gradient_palettes(vram: &mut VRAMModel) {
+    for (palette_index, palette) in vram.palettes.iter_mut().enumerate() {
+        for (color_index, color) in palette.colors.iter_mut().enumerate() {
+            let level = (color_index * 16) as u8;
+            *color = Color {
+                r: if palette_index & 0b001 != 0 { level } else { level / 4 },
+                g: if palette_index & 0b010 != 0 { level } else { level / 4 },
+                b: if palette_index & 0b100 != 0 { level } else { level / 4 }
+            };
+        }
+    }
+}
+
+/// Tile n of tilemap 0 is a pattern that is asymmetric in both directions, so flips and rotations show
+fn pattern_tiles(vram: &mut VRAMModel) {
+    for (tile_index, tile) in vram.tilemaps[0].tiles.iter_mut().enumerate() {
+        for (pixel_index, pixel) in tile.pixels.iter_mut().enumerate() {
+            let (x, y) = (pixel_index % TILE_LENGTH, pixel_index / TILE_LENGTH);
+            *pixel = if x == 0 || y == 0 || x == y { 15 } else { ((x + 2*y + tile_index) % 14 + 1) as u8 };
+        }
+    }
+    // tile 0 is left transparent
+    vram.tilemaps[0].tiles[0].pixels = [0; TILE_SIZE];
+}
+
+fn sprite(location: (u8, u8), gfx_start: u8, size: SpriteSize, palette_index: u8, info: u8) -> Sprite {
+    let properties = SpriteProperties { tilemap_index: 0, size, palette_index, priority: 0 };
+    Sprite { properties, location, gfx_start, info }
+}
+
+fn fixture_backgrounds() -> VRAMModel {
+    let mut vram = VRAMModel::empty_vram();
+    gradient_palettes(&mut vram);
+    pattern_tiles(&mut vram);
+    vram.backdrop = Color { r: 32, g: 32, b: 64 };
+
+    // BG 0: a checkerboard of flipped tiles, scrolled diagonally
+    vram.bg_properties[0] = BackgroundProperties::from(0b1000_0101);
+    for (index, tile) in vram.backgrounds[0].tiles.iter_mut().enumerate() {
+        let (x, y) = (index % BG_WIDTH, index / BG_WIDTH);
+        if (x + y) % 2 == 0 {
+            *tile = BackgroundTile { index: (index % 7 + 1) as u8, attributes: TileAttributes::from(((x % 4) as u8) << 6) };
+        }
+    }
+    vram.bg_scroll[0] = (12, 5);
+
+    // BG 1: horizontal bands with per-tile palettes, underneath BG 0
+    vram.bg_properties[1] = BackgroundProperties::from(0b1000_1000);
+    for (index, tile) in vram.backgrounds[1].tiles.iter_mut().enumerate() {
+        let y = index / BG_WIDTH;
+        *tile = BackgroundTile { index: 9, attributes: TileAttributes::from(((y / 4 % 8) as u8) << 2) };
+    }
+    vram
+}
+
+fn fixture_sprites() -> VRAMModel {
+    let mut vram = VRAMModel::empty_vram();
+    gradient_palettes(&mut vram);
+    pattern_tiles(&mut vram);
+    vram.backdrop = Color { r: 16, g: 48, b: 16 };
+
+    vram.affine_parameters[0] = AffineParameters { pa: 0xB5, pb: -0xB5, pc: 0xB5, pd: 0xB5 }; // 45 degrees
+    vram.affine_parameters[1] = AffineParameters { pa: 0x80, pb: 0, pc: 0, pd: 0x180 };
+
+    // plain, opaque, tall and transformed sprites
+    vram.sprites[0] = sprite((16, 16), 1, SpriteSize::X8, 1, 0b0000_0001);
+    vram.sprites[1] = sprite((40, 16), 2, SpriteSize::X16, 2, 0b0000_0001);
+    vram.sprites[2] = sprite((72, 16), 3, SpriteSize::X32, 3, 0b0000_0011);
+    vram.sprites[3] = sprite((120, 16), 4, SpriteSize::X16, 4, 0b0000_0101);
+    vram.sprites[4] = sprite((16, 96), 5, SpriteSize::X32, 5, 0b0000_1001);
+    vram.sprites[5] = sprite((80, 96), 6, SpriteSize::X32, 6, 0b0001_1001);
+    // overlapping sprites, partially off screen
+    vram.sprites[6] = sprite((236, 200), 7, SpriteSize::X32, 7, 0b0000_0001);
+    vram.sprites[7] = sprite((228, 192), 8, SpriteSize::X32, 1, 0b0000_0011);
+    vram
+}
+
+fn fixture_effects() -> VRAMModel {
+    let mut vram = fixture_backgrounds();
+    vram.bg_properties[1].enabled = false;
+    vram.bg_window_masks[0] = WindowMask::from(0b001);
+    vram.windows[0] = Window { left: 32, top: 150, right: 223, bottom: 207 };
+    vram.fade = 4;
+
+    // a sky gradient in the backdrop, and a wavy scroll on BG 0
+    vram.scanline_writes = (0..SCREEN_HEIGHT as u8)
+        .step_by(8)
+        .flat_map(|scanline| [
+            ScanlineWrite { scanline, address: BACKDROP_COLOR + 1, value: scanline / 8 },
+            ScanlineWrite { scanline, address: BG_SCROLL, value: [0, 2, 4, 2][(scanline / 8 % 4) as usize] }
+        ])
+        .collect();
+    vram
+}
+
+#[test]
+fn golden_empty() {
+    assert_golden("empty", &VRAMModel::empty_vram());
+}
+
+#[test]
+fn golden_backgrounds() {
+    assert_golden("backgrounds", &fixture_backgrounds());
+}
+
+#[test]
+fn golden_sprites() {
+    assert_golden("sprites", &fixture_sprites());
+}
+
+#[test]
+fn golden_effects() {
+    assert_golden("effects", &fixture_effects());
+}
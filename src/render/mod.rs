@@ -1 +1,9 @@
-pub mod context;
\ No newline at end of file
+pub mod context;
+pub mod crt;
+pub mod overlay;
+pub mod pacer;
+pub mod renderer;
+pub mod tile_cache;
+
+#[cfg(test)]
+mod golden;
\ No newline at end of file
@@ -0,0 +1,168 @@
+use crate::inter::mmio::*;
+use crate::gfx::Color;
+use crate::gfx::font;
+
+use std::time::{Duration, Instant};
+
+/// Overlay text is drawn in the built-in font, a tile per character
+pub const GLYPH_WIDTH: usize = TILE_LENGTH;
+pub const GLYPH_HEIGHT: usize = TILE_LENGTH;
+/// Blank pixels around the text; the glyphs keep their own space between characters
+const GLYPH_SPACING: usize = 1;
+
+/// Sets a pixel of an RGBA frame `width` pixels wide, ignoring pixels outside of the frame
+pub fn put_pixel(frame: &mut [u8], width: usize, x: usize, y: usize, color: Color) {
+    let start = (y*width + x)*4;
+    if x < width && start + 4 <= frame.len() {
+        frame[start..start+4].copy_from_slice(&[color.r, color.g, color.b, 0xFF]);
+    }
+}
+
+/// Draws lines of text into an RGBA frame `width` pixels wide, with the top left corner at `origin`,
+/// over a black box so that they stay readable whatever is behind them
+pub fn draw_text(frame: &mut [u8], width: usize, origin: (usize, usize), lines: &[String], color: Color) {
+    let (origin_x, origin_y) = origin;
+    let columns = lines.iter().map(|line| line.chars().count()).max().unwrap_or(0);
+    let box_width = columns*GLYPH_WIDTH + 2*GLYPH_SPACING;
+    let box_height = lines.len()*GLYPH_HEIGHT + 2*GLYPH_SPACING;
+    for y in 0..box_height {
+        for x in 0..box_width {
+            put_pixel(frame, width, origin_x + x, origin_y + y, Color::BLACK);
+        }
+    }
+
+    for (row, line) in lines.iter().enumerate() {
+        let top = origin_y + GLYPH_SPACING + row*GLYPH_HEIGHT;
+        for (column, character) in line.chars().enumerate() {
+            let left = origin_x + GLYPH_SPACING + column*GLYPH_WIDTH;
+            let glyph = font::glyph(character);
+            for glyph_y in 0..GLYPH_HEIGHT {
+                for glyph_x in 0..GLYPH_WIDTH {
+                    if font::glyph_pixel(&glyph, glyph_x, glyph_y) {
+                        put_pixel(frame, width, left + glyph_x, top + glyph_y, color);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Draws the text console's rows over an RGBA frame `width` pixels wide in the built-in font, a tile per
+/// character over black, so that they stay readable whatever is behind them
+pub fn draw_console(frame: &mut [u8], width: usize, rows: &[String]) {
+    for (row, line) in rows.iter().enumerate() {
+        for (column, character) in line.chars().enumerate() {
+            let glyph = font::glyph(character);
+            for y in 0..TILE_LENGTH {
+                for x in 0..TILE_LENGTH {
+                    let color = if font::glyph_pixel(&glyph, x, y) { Color::WHITE } else { Color::BLACK };
+                    put_pixel(frame, width, column*TILE_LENGTH + x, row*TILE_LENGTH + y, color);
+                }
+            }
+        }
+    }
+}
+
+/// Measures presented frames, emulated cycles and executed instructions per second, averaged over intervals of
+/// a second
+pub struct SpeedCounter {
+    interval_start: Instant,
+    frames: u32,
+    cycles: u64,
+    /// The VM's instruction count when the interval started
+    interval_instructions: u64,
+    frames_per_second: f64,
+    cycles_per_second: f64,
+    instructions_per_second: f64
+}
+
+impl SpeedCounter {
+    const INTERVAL: Duration = Duration::from_secs(1);
+
+    pub fn new(now: Instant) -> SpeedCounter {
+        SpeedCounter {
+            interval_start: now, frames: 0, cycles: 0, interval_instructions: 0,
+            frames_per_second: 0.0, cycles_per_second: 0.0, instructions_per_second: 0.0
+        }
+    }
+
+    pub fn add_cycles(&mut self, cycles: u64) {
+        self.cycles += cycles;
+    }
+
+    /// Counts a presented frame, given how many instructions the VM has executed so far from its stats, updating
+    /// the averages once the interval has elapsed
+    pub fn add_frame(&mut self, now: Instant, instructions: u64) {
+        self.frames += 1;
+        let elapsed = now.duration_since(self.interval_start);
+        if elapsed >= SpeedCounter::INTERVAL {
+            self.frames_per_second = self.frames as f64 / elapsed.as_secs_f64();
+            self.cycles_per_second = self.cycles as f64 / elapsed.as_secs_f64();
+            // a program loaded in the meantime starts counting again
+            self.instructions_per_second = instructions.saturating_sub(self.interval_instructions) as f64 / elapsed.as_secs_f64();
+            self.interval_instructions = instructions;
+            self.interval_start = now;
+            self.frames = 0;
+            self.cycles = 0;
+        }
+    }
+
+    /// The overlay text: frames per second, then the emulated clock speed and how it compares to
+    /// the console's, and millions of instructions executed per second
+    pub fn lines(&self) -> Vec<String> {
+        vec![
+            format!("{:.0} FPS", self.frames_per_second),
+            format!("{:.2} MHZ", self.cycles_per_second / 1_000_000.0),
+            format!("{:.0}%", 100.0 * self.cycles_per_second / CPU_FREQUENCY as f64),
+            format!("{:.2} MIPS", self.instructions_per_second / 1_000_000.0)
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pixel(frame: &[u8], x: usize, y: usize) -> [u8; 4] {
+        let start = (SCREEN_WIDTH as usize*y + x)*4;
+        frame[start..start+4].try_into().unwrap()
+    }
+
+    #[test]
+    fn test_draw_text() {
+        let mut frame = vec![0x80; (SCREEN_WIDTH*SCREEN_HEIGHT*4) as usize];
+        draw_text(&mut frame, SCREEN_WIDTH as usize, (0, 0), &["1".to_string(), "F0".to_string()], Color::GREEN);
+
+        let green = [0, 248, 0, 0xFF];
+        let black = [0, 0, 0, 0xFF];
+        // the top row of "1" in the built-in font is ..##....
+        assert_eq!(pixel(&frame, 2, 1), black);
+        assert_eq!(pixel(&frame, 3, 1), green);
+        assert_eq!(pixel(&frame, 4, 1), green);
+        assert_eq!(pixel(&frame, 5, 1), black);
+        // "F" starts the second line, and the box is as wide as the longest line
+        assert_eq!(pixel(&frame, 1, 9), green);
+        assert_eq!(pixel(&frame, 8, 9), black);
+        assert_eq!(pixel(&frame, 17, 9), black);
+        assert_eq!(pixel(&frame, 18, 9), [0x80; 4]);
+        assert_eq!(pixel(&frame, 0, 17), black);
+        assert_eq!(pixel(&frame, 0, 18), [0x80; 4]);
+
+        // text near the edge is clipped rather than wrapping onto the next row
+        draw_text(&mut frame, SCREEN_WIDTH as usize, (SCREEN_WIDTH as usize - 4, 20), &["1".to_string()], Color::GREEN);
+        assert_eq!(pixel(&frame, SCREEN_WIDTH as usize - 1, 21), green);
+        assert_eq!(pixel(&frame, 0, 21), [0x80; 4]);
+        assert_eq!(pixel(&frame, 0, 22), [0x80; 4]);
+    }
+
+    #[test]
+    fn test_speed_counter() {
+        let start = Instant::now();
+        let mut counter = SpeedCounter::new(start);
+        for frame in 1..=30 {
+            counter.add_cycles(CYCLES_PER_FRAME);
+            counter.add_frame(start + Duration::from_millis(frame * 1000 / 30), frame * 10_000);
+        }
+        assert_eq!(counter.lines(), vec!["30 FPS".to_string(), "0.89 MHZ".to_string(), "50%".to_string(), "0.30 MIPS".to_string()]);
+    }
+}
@@ -0,0 +1,107 @@
+use std::time::{Duration, Instant};
+
+/// How much audio to keep queued when syncing to it, unless set otherwise
+pub const AUDIO_SYNC_TARGET: Duration = Duration::from_millis(50);
+/// The most frames run at once to refill the audio queue, so that a stalled device can't stall the window
+const MAX_AUDIO_SYNC_FRAMES: u32 = 4;
+
+/// What sets the speed of emulation
+#[derive(Debug, Default, PartialEq, Copy, Clone)]
+pub enum SyncMode {
+    /// Frames run on a fixed timer
+    #[default]
+    Timer,
+    /// Frames run whenever the audio queue drops below its target, so the audio device's clock sets the pace
+    Audio
+}
+
+impl SyncMode {
+    pub fn toggled(self) -> SyncMode {
+        match self {
+            SyncMode::Timer => SyncMode::Audio,
+            SyncMode::Audio => SyncMode::Timer
+        }
+    }
+}
+
+/// Schedules frames at a fixed rate, independent of the display's refresh rate
+pub struct FramePacer {
+    frame_duration: Duration,
+    next_frame: Instant,
+    /// How much audio to keep queued when syncing to it
+    pub audio_target: Duration
+}
+
+impl FramePacer {
+    pub fn new(frame_rate: u64, now: Instant) -> FramePacer {
+        FramePacer {
+            frame_duration: Duration::from_secs(1) / frame_rate as u32,
+            next_frame: now,
+            audio_target: AUDIO_SYNC_TARGET
+        }
+    }
+
+    pub fn frame_duration(&self) -> Duration {
+        self.frame_duration
+    }
+
+    /// The instant the next frame is due
+    pub fn next_frame(&self) -> Instant {
+        self.next_frame
+    }
+
+    pub fn ready(&self, now: Instant) -> bool {
+        now >= self.next_frame
+    }
+
+    /// Schedules the frame after the one that was due. If more than a frame behind (e.g. after the
+    /// window was dragged, or after fast-forwarding), the schedule restarts from `now` rather than
+    /// running several frames back to back to catch up.
+    pub fn advance(&mut self, now: Instant) {
+        self.next_frame += self.frame_duration;
+        if self.next_frame < now {
+            self.next_frame = now + self.frame_duration;
+        }
+    }
+
+    /// How many frames to run to bring the audio queue back up to its target
+    pub fn audio_frames_due(&self, queued: Duration) -> u32 {
+        let missing = self.audio_target.saturating_sub(queued);
+        (missing.as_secs_f64() / self.frame_duration.as_secs_f64()).ceil().min(MAX_AUDIO_SYNC_FRAMES as f64) as u32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pacer() {
+        let start = Instant::now();
+        let mut pacer = FramePacer::new(50, start);
+        let frame = Duration::from_millis(20);
+        assert_eq!(pacer.frame_duration(), frame);
+        assert!(pacer.ready(start));
+
+        // a frame that ran late doesn't push back the ones after it
+        pacer.advance(start + Duration::from_millis(5));
+        assert_eq!(pacer.next_frame(), start + frame);
+        assert!(!pacer.ready(start + Duration::from_millis(19)));
+        assert!(pacer.ready(start + frame));
+
+        // falling far behind restarts the schedule
+        pacer.advance(start + Duration::from_millis(200));
+        assert_eq!(pacer.next_frame(), start + Duration::from_millis(200) + frame);
+    }
+
+    #[test]
+    fn test_audio_frames_due() {
+        let pacer = FramePacer::new(50, Instant::now());
+        assert_eq!(pacer.audio_frames_due(AUDIO_SYNC_TARGET), 0);
+        assert_eq!(pacer.audio_frames_due(AUDIO_SYNC_TARGET + Duration::from_millis(30)), 0);
+        // partial frames round up, so the queue never settles below its target
+        assert_eq!(pacer.audio_frames_due(AUDIO_SYNC_TARGET - Duration::from_millis(5)), 1);
+        assert_eq!(pacer.audio_frames_due(AUDIO_SYNC_TARGET - Duration::from_millis(25)), 2);
+        assert_eq!(pacer.audio_frames_due(Duration::ZERO), 3);
+    }
+}
@@ -0,0 +1,770 @@
+use crate::inter::mmio::*;
+use crate::gfx::*;
+use crate::render::tile_cache::TileCache;
+
+use std::borrow::Cow;
+
+/// Renders the VRAM model into an RGBA frame buffer, independently of any window or GPU surface
+pub struct Renderer;
+
+impl Renderer {
+    /// Builds the frame one scanline at a time. Each scanline is drawn back to front: the backdrop,
+    /// then for each priority level from lowest to highest, the backgrounds and then the sprites
+    /// of that priority. Within a priority level, lower-indexed layers are drawn over higher ones.
+    /// Sprites over the per-scanline limit are left out, as are backgrounds the video mode doesn't use.
+    /// Tiles are drawn from the cache, which can be kept between frames.
+    pub fn compose(vram: &VRAMModel, tile_cache: &mut TileCache, frame: &mut [u8]) {
+        // scanline writes are applied to a copy so that the model is left as it was synced
+        let mut vram = Cow::Borrowed(vram);
+        let mut next_write = 0;
+
+        let line_pitch = SCREEN_WIDTH as usize*4;
+        for (y, line) in frame.chunks_exact_mut(line_pitch).enumerate() {
+            Renderer::apply_scanline_writes(&mut vram, &mut next_write, y);
+            let vram = vram.as_ref();
+
+            Renderer::render_backdrop_line(vram.backdrop, line);
+            let (drawn_sprites, _) = vram.line_sprites(y);
+
+            for priority in 0..4 {
+                // the video mode decides which backgrounds exist at all
+                for bg_index in (0..vram.video_mode.layout.background_count()).rev() {
+                    let properties = vram.bg_properties[bg_index];
+                    // tiles can raise themselves above their background's priority
+                    if properties.enabled && properties.priority <= priority {
+                        Renderer::render_background_line(vram, tile_cache, bg_index, priority, y, line);
+                    }
+                }
+                for (index, sprite) in vram.sprites.iter().enumerate().rev() {
+                    if drawn_sprites[index] && sprite.properties.priority == priority {
+                        Renderer::render_sprite_line(vram, tile_cache, sprite, y, line);
+                    }
+                }
+            }
+
+            if vram.fade > 0 {
+                Renderer::fade_line(vram.fade, line);
+            }
+        }
+    }
+
+    /// Checks the frame for the sprite flags without drawing it, picking each scanline's sprites once: whether
+    /// sprites are dropped from any scanline, whether the opaque pixels of any two sprites overlap, and whether any
+    /// sprite's opaque pixels overlap a background's drawn at the sprite's priority or above. Only pixels that would
+    /// be drawn count towards collisions, so sprites over the scanline limit and pixels hidden by windows are left out.
+    pub fn sprite_status(vram: &VRAMModel) -> (bool, bool, bool) {
+        let mut vram = Cow::Borrowed(vram);
+        let mut next_write = 0;
+        let (mut overflow, mut sprite_collision, mut background_collision) = (false, false, false);
+
+        for y in 0..SCREEN_HEIGHT as usize {
+            Renderer::apply_scanline_writes(&mut vram, &mut next_write, y);
+            let vram = vram.as_ref();
+            let (drawn_sprites, dropped) = vram.line_sprites(y);
+            overflow |= dropped;
+            if sprite_collision && background_collision {
+                // all that's left to find is whether the remaining lines drop sprites
+                continue;
+            }
+            let mut covered = [false; SCREEN_WIDTH as usize];
+
+            for (sprite, _) in vram.sprites.iter().zip(drawn_sprites).filter(|(_, drawn)| *drawn) {
+                let (top_x, width) = (sprite.location.0 as usize, sprite.dimensions().0 as usize);
+                for x in (top_x..top_x + width).take_while(|x| *x < SCREEN_WIDTH as usize) {
+                    if Renderer::sprite_texel(vram, sprite, x, y).is_none() {
+                        continue;
+                    }
+                    sprite_collision |= std::mem::replace(&mut covered[x], true);
+                    background_collision = background_collision || (0..vram.video_mode.layout.background_count()).any(|bg_index| {
+                        vram.bg_properties[bg_index].enabled && Renderer::background_texel(vram, bg_index, x, y)
+                            .is_some_and(|(_, _, _, priority)| priority >= sprite.properties.priority)
+                    });
+                    if overflow && sprite_collision && background_collision {
+                        return (true, true, true);
+                    }
+                }
+            }
+        }
+        (overflow, sprite_collision, background_collision)
+    }
+
+    /// Applies the scanline writes due by scanline `y`, starting from `next_write`, to a copy of the model
+    fn apply_scanline_writes(vram: &mut Cow<VRAMModel>, next_write: &mut usize, y: usize) {
+        while let Some(write) = vram.scanline_writes.get(*next_write).copied() {
+            if write.scanline as usize > y {
+                break;
+            }
+            vram.to_mut().write_register(write.address, write.value);
+            *next_write += 1;
+        }
+    }
+
+    fn fade_line(fade: u8, line: &mut [u8]) {
+        for pixel in line.chunks_exact_mut(4) {
+            for channel in &mut pixel[0..3] {
+                *channel = (*channel as u16 * (16 - fade as u16) / 16) as u8;
+            }
+        }
+    }
+
+    fn render_backdrop_line(backdrop: Color, line: &mut [u8]) {
+        for pixel in line.chunks_exact_mut(4) {
+            pixel.copy_from_slice(&[backdrop.r, backdrop.g, backdrop.b, 0xff]);
+        }
+    }
+
+    fn put_pixel(line: &mut [u8], x: usize, color: Color) {
+        line[x*4..x*4+4].copy_from_slice(&[color.r, color.g, color.b, 0xFF]);
+    }
+
+    /// Draws a pixel of a layer, blending it with what's beneath if the layer is selected for color math
+    fn draw_pixel(vram: &VRAMModel, layer: u8, line: &mut [u8], x: usize, color: Color) {
+        if vram.color_math.targets(layer) {
+            let under = Color { r: line[x*4], g: line[x*4+1], b: line[x*4+2] };
+            Renderer::put_pixel(line, x, vram.color_math.blend(under, color));
+        } else {
+            Renderer::put_pixel(line, x, color);
+        }
+    }
+
+    /// Draws the tiles of a background that end up at the given priority
+    fn render_background_line(vram: &VRAMModel, tile_cache: &mut TileCache, bg_index: usize, priority: u8, y: usize, line: &mut [u8]) {
+        let tilemap_index = vram.bg_properties[bg_index].tilemap_index as usize;
+
+        for x in 0..SCREEN_WIDTH as usize {
+            match Renderer::background_texel(vram, bg_index, x, y) {
+                Some((tile_index, offset, palette_index, texel_priority)) if texel_priority == priority => {
+                    let color = tile_cache.decoded(vram, tilemap_index, tile_index, palette_index)[offset];
+                    Renderer::draw_pixel(vram, bg_index as u8, line, x, color);
+                },
+                _ => ()
+            }
+        }
+    }
+
+    /// Finds the pixel a background draws at (`x`, `y`) on the screen: its tile index, its offset within the tile,
+    /// the palette it's drawn with (None in full color) and the priority it ends up at. Returns None where the
+    /// background is transparent or hidden by a window.
+    fn background_texel(vram: &VRAMModel, bg_index: usize, x: usize, y: usize) -> Option<(usize, usize, Option<usize>, u8)> {
+        let properties = vram.bg_properties[bg_index];
+        let tilemap = &vram.tilemaps[properties.tilemap_index as usize];
+        let background = &vram.backgrounds[bg_index];
+        let (scroll_x, scroll_y) = vram.bg_scroll[bg_index];
+        let (map_width, map_height) = ((background.width*TILE_LENGTH) as i32, (background.height*TILE_LENGTH) as i32);
+        let layout = vram.video_mode.layout;
+
+        if vram.bg_window_masks[bg_index].hides(&vram.windows, x, y) {
+            return None;
+        }
+        let (source_x, source_y) = if layout.affine_background() == Some(bg_index) {
+            let parameters = vram.affine_parameters[vram.video_mode.affine_index as usize];
+            parameters.transform_unclipped(x, y, SCREEN_WIDTH as usize, SCREEN_HEIGHT as usize)
+        } else {
+            (x as i32, y as i32)
+        };
+        // backgrounds wrap around at their edges
+        let bg_x = (source_x + scroll_x as i32).rem_euclid(map_width) as usize;
+        let bg_y = (source_y + scroll_y as i32).rem_euclid(map_height) as usize;
+        let (tile_x, pixel_x) = (bg_x / TILE_LENGTH, bg_x % TILE_LENGTH);
+        let (tile_y, pixel_y) = (bg_y / TILE_LENGTH, bg_y % TILE_LENGTH);
+
+        let entry = background.tile_at(tile_x, tile_y);
+        let attributes = entry.attributes;
+        let pixel_x = if attributes.h_flip { TILE_LENGTH - 1 - pixel_x } else { pixel_x };
+        let pixel_y = if attributes.v_flip { TILE_LENGTH - 1 - pixel_y } else { pixel_y };
+
+        let offset = pixel_y*TILE_LENGTH + pixel_x;
+        let pixel = tilemap.tiles[entry.index as usize].pixels[offset];
+        let palette_index = match layout {
+            BgLayout::FullColor => None,
+            _ => Some(((properties.palette_index + attributes.palette_index) as usize) % PALETTE_COUNT)
+        };
+
+        // pixel 0 is transparent for backgrounds, counting only the bits the mode uses
+        let transparent = if palette_index.is_none() { pixel == 0 } else { pixel & 0x0F == 0 };
+        if transparent {
+            return None;
+        }
+        // tiles can raise themselves above their background's priority
+        Some((entry.index as usize, offset, palette_index, attributes.priority.max(properties.priority)))
+    }
+
+    fn render_sprite_line(vram: &VRAMModel, tile_cache: &mut TileCache, sprite: &Sprite, y: usize, line: &mut [u8]) {
+        let properties = sprite.properties;
+        let (width, height) = sprite.dimensions();
+        let (width, height) = (width as usize, height as usize);
+        let (top_x, top_y) = (sprite.location.0 as usize, sprite.location.1 as usize);
+
+        if y < top_y || y >= top_y + height {
+            return;
+        }
+
+        let tilemap_index = properties.tilemap_index as usize;
+        for x in (top_x..top_x + width).take_while(|x| *x < SCREEN_WIDTH as usize) {
+            if let Some((tile_index, offset)) = Renderer::sprite_texel(vram, sprite, x, y) {
+                let color = tile_cache.decoded(vram, tilemap_index, tile_index, Some(properties.palette_index as usize))[offset];
+                Renderer::draw_pixel(vram, COLOR_MATH_SPRITE_LAYER, line, x, color);
+            }
+        }
+    }
+
+    /// Finds the pixel a sprite draws at (`x`, `y`) on the screen, which has to lie within the sprite: its tile
+    /// index and its offset within the tile. Returns None where the sprite is transparent or hidden by a window.
+    fn sprite_texel(vram: &VRAMModel, sprite: &Sprite, x: usize, y: usize) -> Option<(usize, usize)> {
+        let (width, height) = sprite.dimensions();
+        let (sprite_x, sprite_y) = (x - sprite.location.0 as usize, y - sprite.location.1 as usize);
+        let tilemap = &vram.tilemaps[sprite.properties.tilemap_index as usize];
+
+        if vram.sprite_window_mask.hides(&vram.windows, x, y) {
+            return None;
+        }
+        let source = if sprite.transformed() {
+            vram.affine_parameters[sprite.affine_index() as usize].transform(sprite_x, sprite_y, width as usize, height as usize)
+        } else {
+            Some((sprite_x, sprite_y))
+        };
+        let (tile_index, offset) = source
+            .map(|(source_x, source_y)| Renderer::sprite_pixel(sprite, source_x, source_y))
+            .filter(|(tile_index, _)| *tile_index < tilemap.tiles.len())?;
+
+        // palette index 0 is transparent unless the sprite is marked opaque
+        if tilemap.tiles[tile_index].pixels[offset] & 0x0F == 0 && !sprite.opaque() {
+            return None;
+        }
+        Some((tile_index, offset))
+    }
+
+    /// Finds the tile holding the pixel at (`x`, `y`) within the untransformed sprite, and the pixel's
+    /// offset within it. The tile may lie past the end of the tilemap.
+    fn sprite_pixel(sprite: &Sprite, x: usize, y: usize) -> (usize, usize) {
+        let tile_pitch = SpriteSize::pitch(sprite.properties.size) as usize / TILE_LENGTH; // width of the sprite in tiles
+        let tile_index = sprite.gfx_start as usize + (y / TILE_LENGTH)*tile_pitch + x / TILE_LENGTH;
+
+        (tile_index, (y % TILE_LENGTH)*TILE_LENGTH + x % TILE_LENGTH)
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::inter::gfx::color_to_rgb15;
+    use devola::utility::break_u16;
+
+    fn dummy_tile() -> Tile {
+        Tile { pixels: [0; TILE_SIZE] }
+    }
+
+    fn dummy_palette() -> Palette {
+        Palette { colors: [Color::BLACK; PALETTE_LENGTH] }
+    }
+    fn dummy_tilemap() -> Tilemap {
+        Tilemap { tiles: [dummy_tile(); TILEMAP_LENGTH*TILEMAP_LENGTH] }
+    }
+    fn dummy_background() -> Background {
+        Background { address: BG_START, width: BG_WIDTH, height: BG_HEIGHT, tiles: vec![BackgroundTile::new(0); BG_LENGTH] }
+    }
+
+    fn dummy_bg_properties() -> BackgroundProperties {
+        BackgroundProperties {
+            enabled: false, tilemap_index: 0, palette_index: 0, priority: 0
+        }
+    }
+
+    fn dummy_sprite() -> Sprite {
+        Sprite {
+            properties: SpriteProperties {
+                tilemap_index: 0, palette_index: 0, size: SpriteSize::X8, priority: 0
+            },
+            location: (0, 0),
+            gfx_start: 0,
+            info: 0
+        }
+    }
+
+    fn render_sprite(vram: &VRAMModel, sprite: &Sprite, frame: &mut [u8]) {
+        let mut tile_cache = TileCache::new();
+        for (y, line) in frame.chunks_exact_mut(SCREEN_WIDTH as usize*4).enumerate() {
+            Renderer::render_sprite_line(vram, &mut tile_cache, sprite, y, line);
+        }
+    }
+
+    fn pixel(frame: &[u8], x: usize, y: usize) -> [u8; 4] {
+        let start = (SCREEN_WIDTH as usize*y + x)*4;
+        frame[start..start+4].try_into().unwrap()
+    }
+
+    fn rgba(color: Color) -> [u8; 4] {
+        [color.r, color.g, color.b, 0xFF]
+    }
+
+    #[test]
+    fn test_render_sprite() {
+        let mut palettes = [dummy_palette(); PALETTE_COUNT];
+        palettes[0] = Palette { colors: [
+            Color::BLACK,
+            Color::RED,
+            Color::GREEN,
+            Color::BLUE,
+            Color::RED,
+            Color::RED,
+            Color::RED,
+            Color::RED,
+            Color::RED,
+            Color::RED,
+            Color::RED,
+            Color::RED,
+            Color::RED,
+            Color::RED,
+            Color::RED,
+            Color::RED,
+        ] };
+        let mut tilemaps = [dummy_tilemap(); TILEMAP_COUNT];
+        let mut tiles = [dummy_tile(); TILEMAP_LENGTH*TILEMAP_LENGTH];
+        tiles[0] = Tile {
+            pixels: [
+                1, 1, 1, 1, 1, 1, 1, 1, // !!!!!!!!
+                0, 1, 2, 2, 2, 2, 1, 0, // _!@@@@!_
+                0, 0, 1, 3, 3, 1, 0, 0, // __!##!__
+                0, 0, 0, 2, 2, 0, 0, 0, // ___@@___
+                0, 0, 0, 2, 2, 0, 0, 0, // ___@@___
+                0, 0, 1, 3, 3, 1, 0, 0, // __!##!__
+                0, 1, 2, 2, 2, 2, 1, 0, // _!@@@@!_
+                1, 1, 1, 1, 1, 1, 1, 1, // !!!!!!!!
+            ]
+        };
+        tilemaps[0].tiles = tiles;
+
+        let backgrounds = std::array::from_fn(|_| dummy_background());
+        let bg_properties = [dummy_bg_properties(); BG_COUNT];
+        let mut sprites = [dummy_sprite(); SPRITE_COUNT];
+
+        sprites[0] = Sprite {
+            properties: SpriteProperties {
+                tilemap_index: 0,
+                size: SpriteSize::X8,
+                palette_index: 0,
+                priority: 0
+            },
+            location: (128, 128),
+            gfx_start: 0,
+            info: 0
+        };
+
+        let affine_parameters = [AffineParameters::IDENTITY; AFFINE_COUNT];
+
+        let fake_vram = VRAMModel {
+            palettes, tilemaps, backgrounds, bg_properties, sprites, affine_parameters,
+            sprites_enabled: true,
+            bg_scroll: [(0, 0); BG_COUNT],
+            fade: 0,
+            windows: [Window { left: 0, top: 0, right: 0, bottom: 0 }; WINDOW_COUNT],
+            bg_window_masks: [WindowMask::from(0); BG_COUNT],
+            sprite_window_mask: WindowMask::from(0),
+            backdrop: Color::BLACK,
+            color_math: ColorMath::from(0),
+            video_mode: VideoMode::from(0),
+            generations: Generations::default(),
+            scanline_writes: Vec::new()
+        };
+
+        let background = [0x10, 0x20, 0x30, 0xFF];
+        let mut frame = background.repeat((SCREEN_WIDTH*SCREEN_HEIGHT) as usize);
+        render_sprite(&fake_vram, &fake_vram.sprites[0], &mut frame);
+
+        assert_eq!(pixel(&frame, 128, 128), rgba(Color::RED));
+        assert_eq!(pixel(&frame, 131, 130), rgba(Color::BLUE));
+        // color 0 is transparent, so the existing frame shows through
+        assert_eq!(pixel(&frame, 128, 129), background);
+        assert_eq!(pixel(&frame, 130, 131), background);
+    }
+
+    #[test]
+    fn test_render_opaque_sprite() {
+        let mut vram = VRAMModel::empty_vram();
+        vram.palettes[0].colors[0] = Color::GREEN;
+        vram.sprites[0].info = 0b00000011;
+
+        let mut frame = vec![0; (SCREEN_WIDTH*SCREEN_HEIGHT*4) as usize];
+        render_sprite(&vram, &vram.sprites[0], &mut frame);
+
+        assert_eq!(pixel(&frame, 0, 0), rgba(Color::GREEN));
+        assert_eq!(pixel(&frame, 8, 0), [0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_render_tall_sprite() {
+        let mut vram = VRAMModel::empty_vram();
+        vram.palettes[0].colors[1] = Color::RED;
+        vram.palettes[0].colors[2] = Color::GREEN;
+        vram.tilemaps[0].tiles[4].pixels = [1; TILE_SIZE];
+        vram.tilemaps[0].tiles[5].pixels = [2; TILE_SIZE];
+        vram.sprites[0].gfx_start = 4;
+        vram.sprites[0].location = (0, 200);
+        vram.sprites[0].info = 0b00000101;
+        assert_eq!(vram.sprites[0].dimensions(), (8, 16));
+
+        let mut frame = vec![0; (SCREEN_WIDTH*SCREEN_HEIGHT*4) as usize];
+        render_sprite(&vram, &vram.sprites[0], &mut frame);
+
+        // the second tile is stacked below the first, not beside it
+        assert_eq!(pixel(&frame, 7, 207), rgba(Color::RED));
+        assert_eq!(pixel(&frame, 7, 208), rgba(Color::GREEN));
+        assert_eq!(pixel(&frame, 7, 215), rgba(Color::GREEN));
+        assert_eq!(pixel(&frame, 8, 200), [0, 0, 0, 0]);
+        assert_eq!(pixel(&frame, 0, 216), [0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_render_affine_sprite() {
+        let mut vram = VRAMModel::empty_vram();
+        vram.palettes[0].colors[1] = Color::RED;
+        // the left column of the sprite is red
+        for row in 0..TILE_LENGTH {
+            vram.tilemaps[0].tiles[0].pixels[row*TILE_LENGTH] = 1;
+        }
+        // rotate 90 degrees counter-clockwise
+        vram.affine_parameters[3] = AffineParameters { pa: 0, pb: -0x100, pc: 0x100, pd: 0 };
+        vram.sprites[0].info = 0b0011_1001; // affine 3, transformed and enabled
+
+        let mut frame = vec![0; (SCREEN_WIDTH*SCREEN_HEIGHT*4) as usize];
+        render_sprite(&vram, &vram.sprites[0], &mut frame);
+
+        // the left column becomes the bottom row
+        assert!((0..TILE_LENGTH).all(|x| pixel(&frame, x, 7) == rgba(Color::RED)));
+        assert!((0..TILE_LENGTH).all(|y| y == 7 || pixel(&frame, 0, y) == [0, 0, 0, 0]));
+
+        // the identity leaves the sprite untouched
+        vram.affine_parameters[3] = AffineParameters::IDENTITY;
+        let mut frame = vec![0; (SCREEN_WIDTH*SCREEN_HEIGHT*4) as usize];
+        render_sprite(&vram, &vram.sprites[0], &mut frame);
+        assert!((0..TILE_LENGTH).all(|y| pixel(&frame, 0, y) == rgba(Color::RED)));
+        assert_eq!(pixel(&frame, 1, 0), [0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_compose_priority() {
+        let mut vram = VRAMModel::empty_vram();
+        vram.palettes[0].colors[1] = Color::RED;
+        vram.palettes[0].colors[2] = Color::GREEN;
+        vram.palettes[0].colors[3] = Color::BLUE;
+        vram.tilemaps[0].tiles[1].pixels = [1; TILE_SIZE];
+        vram.tilemaps[0].tiles[2].pixels = [2; TILE_SIZE];
+        vram.tilemaps[0].tiles[3].pixels = [3; TILE_SIZE];
+
+        // BG 0 covers the whole screen in red at priority 1
+        vram.backgrounds[0].tiles = vec![BackgroundTile::new(1); BG_LENGTH];
+        vram.bg_properties[0] = BackgroundProperties::from(0b1000_0001); // enabled, at priority 1
+
+        // sprite 0 is green at priority 0, sprite 1 is blue at priority 1, both at the origin
+        vram.sprites[0].gfx_start = 2;
+        vram.sprites[1].gfx_start = 3;
+        vram.sprites[1].properties.priority = 1;
+        vram.sprites[1].location = (4, 0);
+        vram.enable_sprite(0);
+        vram.enable_sprite(1);
+
+        let mut frame = vec![0; (SCREEN_WIDTH*SCREEN_HEIGHT*4) as usize];
+        Renderer::compose(&vram, &mut TileCache::new(), &mut frame);
+
+        // the low priority sprite is hidden behind the background
+        assert_eq!(pixel(&frame, 0, 0), rgba(Color::RED));
+        // the sprite sharing the background's priority is drawn over it
+        assert_eq!(pixel(&frame, 4, 0), rgba(Color::BLUE));
+        assert_eq!(pixel(&frame, 100, 100), rgba(Color::RED));
+
+        // within a priority level, lower-indexed sprites are drawn on top
+        vram.sprites[0].properties.priority = 1;
+        Renderer::compose(&vram, &mut TileCache::new(), &mut frame);
+        assert_eq!(pixel(&frame, 4, 0), rgba(Color::GREEN));
+        assert_eq!(pixel(&frame, 8, 0), rgba(Color::BLUE));
+    }
+
+    #[test]
+    fn test_compose_tile_attributes() {
+        let mut vram = VRAMModel::empty_vram();
+        vram.palettes[0].colors[1] = Color::RED;
+        vram.palettes[1].colors[1] = Color::GREEN;
+        vram.palettes[2].colors[1] = Color::BLUE;
+        vram.tilemaps[0].tiles[1].pixels[0] = 1; // only the top left pixel is set
+        vram.tilemaps[0].tiles[2].pixels = [1; TILE_SIZE];
+
+        // BG 0 uses palette 1, and is drawn at priority 0
+        vram.bg_properties[0] = BackgroundProperties { enabled: true, tilemap_index: 0, palette_index: 1, priority: 0 };
+        vram.backgrounds[0].tiles[0] = BackgroundTile::new(1);
+        let attributes = TileAttributes { h_flip: true, v_flip: false, palette_index: 0, priority: 0 };
+        vram.backgrounds[0].tiles[1] = BackgroundTile { index: 1, attributes };
+        let attributes = TileAttributes { v_flip: true, ..attributes };
+        vram.backgrounds[0].tiles[2] = BackgroundTile { index: 1, attributes };
+        // the palette offset wraps around to palette 0, and the tile is raised to priority 1
+        let attributes = TileAttributes { h_flip: false, v_flip: false, palette_index: 7, priority: 1 };
+        vram.backgrounds[0].tiles[3] = BackgroundTile { index: 2, attributes };
+        vram.backgrounds[0].tiles[4] = BackgroundTile::new(2);
+        // BG 1 is blue at priority 1: it covers BG 0, except for the raised tile
+        vram.bg_properties[1] = BackgroundProperties { enabled: true, tilemap_index: 0, palette_index: 2, priority: 1 };
+        vram.backgrounds[1].tiles[3] = BackgroundTile::new(2);
+        vram.backgrounds[1].tiles[4] = BackgroundTile::new(2);
+
+        let mut frame = vec![0; (SCREEN_WIDTH*SCREEN_HEIGHT*4) as usize];
+        Renderer::compose(&vram, &mut TileCache::new(), &mut frame);
+
+        assert_eq!(pixel(&frame, 0, 0), rgba(Color::GREEN));
+        assert_eq!(pixel(&frame, 8, 0), rgba(Color::BLACK));
+        assert_eq!(pixel(&frame, 15, 0), rgba(Color::GREEN));
+        assert_eq!(pixel(&frame, 23, 7), rgba(Color::GREEN));
+        assert_eq!(pixel(&frame, 24, 0), rgba(Color::RED));
+        assert_eq!(pixel(&frame, 32, 0), rgba(Color::BLUE));
+    }
+
+    #[test]
+    fn test_compose_large_background() {
+        let mut vram = VRAMModel::empty_vram();
+        vram.palettes[0].colors[1] = Color::RED;
+        vram.palettes[0].colors[2] = Color::GREEN;
+        vram.tilemaps[0].tiles[1].pixels = [1; TILE_SIZE];
+        vram.tilemaps[0].tiles[2].pixels = [2; TILE_SIZE];
+        vram.bg_properties[0].enabled = true;
+
+        let mut background = Background {
+            address: 0x2000,
+            width: BG_LARGE_WIDTH,
+            height: BG_LARGE_HEIGHT,
+            tiles: vec![BackgroundTile::new(1); BG_LARGE_WIDTH*BG_LARGE_HEIGHT]
+        };
+        background.tiles[BG_LARGE_WIDTH*BG_LARGE_HEIGHT - 1] = BackgroundTile::new(2);
+        vram.backgrounds[0] = background;
+        // the bottom right tile of the map is at the top left of the screen, and the map wraps around after it
+        vram.bg_scroll[0] = (504, 504);
+
+        let mut frame = vec![0; (SCREEN_WIDTH*SCREEN_HEIGHT*4) as usize];
+        Renderer::compose(&vram, &mut TileCache::new(), &mut frame);
+
+        assert_eq!(pixel(&frame, 7, 7), rgba(Color::GREEN));
+        assert_eq!(pixel(&frame, 8, 7), rgba(Color::RED));
+        assert_eq!(pixel(&frame, 7, 8), rgba(Color::RED));
+        // scrolling by the width of the screen is no longer a full turn
+        vram.bg_scroll[0] = (504 - 256, 504);
+        Renderer::compose(&vram, &mut TileCache::new(), &mut frame);
+        assert_eq!(pixel(&frame, 7, 7), rgba(Color::RED));
+    }
+
+    #[test]
+    fn test_compose_backdrop() {
+        let mut vram = VRAMModel::empty_vram();
+        vram.backdrop = Color::BLUE;
+        // the backdrop can be changed mid-frame like any other register
+        let (hi, lo) = break_u16(color_to_rgb15(Color::GREEN));
+        vram.scanline_writes = vec![
+            ScanlineWrite { scanline: 112, address: BACKDROP_COLOR, value: hi },
+            ScanlineWrite { scanline: 112, address: BACKDROP_COLOR + 1, value: lo }
+        ];
+
+        let mut frame = vec![0; (SCREEN_WIDTH*SCREEN_HEIGHT*4) as usize];
+        Renderer::compose(&vram, &mut TileCache::new(), &mut frame);
+
+        assert_eq!(pixel(&frame, 0, 0), rgba(Color::BLUE));
+        assert_eq!(pixel(&frame, 255, 111), rgba(Color::BLUE));
+        assert_eq!(pixel(&frame, 0, 112), rgba(Color::GREEN));
+    }
+
+    #[test]
+    fn test_compose_color_math() {
+        let mut vram = VRAMModel::empty_vram();
+        vram.backdrop = Color::BLUE;
+        vram.palettes[0].colors[1] = Color { r: 248, g: 64, b: 0 };
+        vram.palettes[0].colors[2] = Color::GREEN;
+        vram.tilemaps[0].tiles[1].pixels = [1; TILE_SIZE];
+        vram.tilemaps[0].tiles[2].pixels = [2; TILE_SIZE];
+        vram.backgrounds[0].tiles = vec![BackgroundTile::new(1); BG_LENGTH];
+        vram.bg_properties[0].enabled = true;
+        vram.sprites[0] = Sprite { gfx_start: 2, info: 0x01, ..dummy_sprite() };
+
+        // BG 0 is added to the backdrop, then from scanline 100 subtracted from it and halved
+        vram.color_math = ColorMath { enabled: true, subtract: false, half: false, layer: 0 };
+        vram.scanline_writes = vec![ScanlineWrite { scanline: 100, address: COLOR_MATH, value: 0xE0 }];
+
+        let mut frame = vec![0; (SCREEN_WIDTH*SCREEN_HEIGHT*4) as usize];
+        Renderer::compose(&vram, &mut TileCache::new(), &mut frame);
+
+        assert_eq!(pixel(&frame, 50, 50), rgba(Color { r: 248, g: 64, b: 248 }));
+        assert_eq!(pixel(&frame, 50, 150), rgba(Color { r: 0, g: 0, b: 124 }));
+        // other layers are drawn as usual
+        assert_eq!(pixel(&frame, 0, 0), rgba(Color::GREEN));
+    }
+
+    #[test]
+    fn test_compose_sprite_limit() {
+        let mut vram = VRAMModel::empty_vram();
+        vram.palettes[0].colors[1] = Color::RED;
+        vram.tilemaps[0].tiles[1].pixels = [1; TILE_SIZE];
+        // one 8x8 sprite more than fits on a line, with sprite 5 the lowest priority
+        for index in 0..=SPRITE_LINE_PIXELS/8 {
+            let mut sprite = Sprite { location: ((7*index) as u8, 0), gfx_start: 1, info: 0x01, ..dummy_sprite() };
+            sprite.properties.priority = if index == 5 { 0 } else { 1 };
+            vram.sprites[index] = sprite;
+        }
+
+        let (drawn, overflow) = vram.line_sprites(0);
+        assert!(overflow);
+        assert!(!drawn[5]);
+        assert!(drawn[SPRITE_LINE_PIXELS/8]);
+        assert!(Renderer::sprite_status(&vram).0);
+
+        let mut frame = vec![0; (SCREEN_WIDTH*SCREEN_HEIGHT*4) as usize];
+        Renderer::compose(&vram, &mut TileCache::new(), &mut frame);
+        assert_eq!(pixel(&frame, 34, 0), rgba(Color::RED));
+        // only sprite 5 covers x = 35 to 41
+        assert_eq!(pixel(&frame, 41, 0), rgba(Color::BLACK));
+
+        // with one sprite gone, everything fits
+        vram.sprites[0].info = 0;
+        assert!(!Renderer::sprite_status(&vram).0);
+    }
+
+    #[test]
+    fn test_collisions() {
+        let mut vram = VRAMModel::empty_vram();
+        vram.tilemaps[0].tiles[1].pixels = [1; TILE_SIZE];
+        // tile 2 leaves its leftmost column transparent
+        vram.tilemaps[0].tiles[2].pixels = [1; TILE_SIZE];
+        for row in 0..TILE_LENGTH {
+            vram.tilemaps[0].tiles[2].pixels[row*TILE_LENGTH] = 0;
+        }
+        vram.sprites[0] = Sprite { location: (0, 0), gfx_start: 1, info: 0x01, ..dummy_sprite() };
+        vram.sprites[1] = Sprite { location: (8, 0), gfx_start: 1, info: 0x01, ..dummy_sprite() };
+        assert_eq!(Renderer::sprite_status(&vram), (false, false, false));
+
+        vram.sprites[1].location = (7, 0);
+        assert_eq!(Renderer::sprite_status(&vram), (false, true, false));
+        // with all sprites off, nothing is drawn to collide
+        vram.sprites_enabled = false;
+        assert_eq!(Renderer::sprite_status(&vram), (false, false, false));
+        assert!(!vram.line_sprites(0).0.contains(&true));
+        vram.sprites_enabled = true;
+        // only transparent pixels overlap
+        vram.sprites[1].gfx_start = 2;
+        assert_eq!(Renderer::sprite_status(&vram), (false, false, false));
+        vram.sprites[1].info = 0x03;
+        assert_eq!(Renderer::sprite_status(&vram), (false, true, false));
+        vram.sprites[1].info = 0;
+
+        // BG 0 has a single opaque tile at priority 1
+        vram.bg_properties[0] = BackgroundProperties { enabled: true, tilemap_index: 0, palette_index: 0, priority: 1 };
+        vram.backgrounds[0].tiles[5*BG_WIDTH + 5] = BackgroundTile::new(1);
+        assert_eq!(Renderer::sprite_status(&vram), (false, false, false));
+        vram.sprites[0].location = (44, 36);
+        assert_eq!(Renderer::sprite_status(&vram), (false, false, true));
+        // sprites drawn above the background don't collide with it
+        vram.sprites[0].properties.priority = 2;
+        assert_eq!(Renderer::sprite_status(&vram), (false, false, false));
+        vram.sprites[0].properties.priority = 1;
+        vram.bg_properties[0].enabled = false;
+        assert_eq!(Renderer::sprite_status(&vram), (false, false, false));
+    }
+
+    #[test]
+    fn test_compose_video_modes() {
+        let mut vram = VRAMModel::empty_vram();
+        vram.palettes[0].colors[1] = Color::RED;
+        vram.palettes[0].colors[2] = Color::BLUE;
+        vram.palettes[2].colors[1] = Color::GREEN;
+        vram.tilemaps[0].tiles[1].pixels[0] = 1;
+        vram.tilemaps[0].tiles[2].pixels = [2; TILE_SIZE];
+        vram.tilemaps[0].tiles[3].pixels = [0x21; TILE_SIZE];
+        vram.backgrounds[0].tiles[0] = BackgroundTile::new(3);
+        vram.backgrounds[1].tiles = vec![BackgroundTile::new(2); BG_LENGTH];
+        for properties in &mut vram.bg_properties[0..2] {
+            properties.enabled = true;
+        }
+        let mut frame = vec![0; (SCREEN_WIDTH*SCREEN_HEIGHT*4) as usize];
+
+        // mode 0 only uses the low nibble of each pixel
+        Renderer::compose(&vram, &mut TileCache::new(), &mut frame);
+        assert_eq!(pixel(&frame, 0, 0), rgba(Color::RED));
+        assert_eq!(pixel(&frame, 100, 100), rgba(Color::BLUE));
+
+        // mode 2 picks the palette from the high nibble, and leaves out BG 1
+        vram.video_mode = VideoMode { layout: BgLayout::FullColor, affine_index: 0 };
+        Renderer::compose(&vram, &mut TileCache::new(), &mut frame);
+        assert_eq!(pixel(&frame, 0, 0), rgba(Color::GREEN));
+        assert_eq!(pixel(&frame, 100, 100), rgba(Color::BLACK));
+
+        // mode 1 turns BG 2 upside down, moving its top left pixel to the bottom right, and leaves out BG 3
+        vram.bg_properties[0].enabled = false;
+        vram.bg_properties[1].enabled = false;
+        vram.bg_properties[2].enabled = true;
+        vram.bg_properties[3].enabled = true;
+        vram.backgrounds[2].tiles[0] = BackgroundTile::new(1);
+        vram.backgrounds[3].tiles = vec![BackgroundTile::new(2); BG_LENGTH];
+        vram.affine_parameters[1] = AffineParameters { pa: -0x100, pb: 0, pc: 0, pd: -0x100 };
+        vram.video_mode = VideoMode { layout: BgLayout::Affine, affine_index: 1 };
+        Renderer::compose(&vram, &mut TileCache::new(), &mut frame);
+        assert_eq!(pixel(&frame, SCREEN_WIDTH as usize - 1, SCREEN_HEIGHT as usize - 1), rgba(Color::RED));
+        assert_eq!(pixel(&frame, 0, 0), rgba(Color::BLACK));
+    }
+
+    #[test]
+    fn test_compose_scanline_writes() {
+        let mut vram = VRAMModel::empty_vram();
+        vram.palettes[0].colors[1] = Color::RED;
+        vram.tilemaps[0].tiles[1].pixels = [1; TILE_SIZE];
+        vram.tilemaps[0].tiles[2].pixels[0] = 1;
+        vram.backgrounds[0].tiles = vec![BackgroundTile::new(1); BG_LENGTH];
+        vram.backgrounds[0].tiles[0] = BackgroundTile::new(2);
+        vram.bg_properties[0].enabled = true;
+
+        let (hi, lo) = break_u16(color_to_rgb15(Color::GREEN));
+        vram.scanline_writes = vec![
+            // color 1 turns green from scanline 100
+            ScanlineWrite { scanline: 100, address: PALETTE_START + 2, value: hi },
+            ScanlineWrite { scanline: 100, address: PALETTE_START + 3, value: lo },
+            // the background is scrolled back to its top left corner on scanline 200
+            ScanlineWrite { scanline: 200, address: BG_SCROLL + 1, value: 24 },
+            // the last scanline is faded halfway
+            ScanlineWrite { scanline: 223, address: FADE, value: 8 }
+        ];
+
+        let mut frame = vec![0; (SCREEN_WIDTH*SCREEN_HEIGHT*4) as usize];
+        Renderer::compose(&vram, &mut TileCache::new(), &mut frame);
+
+        assert_eq!(pixel(&frame, 0, 0), rgba(Color::RED));
+        assert_eq!(pixel(&frame, 1, 0), [0, 0, 0, 0xFF]); // transparent, shows the backdrop
+        assert_eq!(pixel(&frame, 10, 99), rgba(Color::RED));
+        assert_eq!(pixel(&frame, 10, 100), rgba(Color::GREEN));
+        // scanline 200 now shows row 200 + 24 = 224 of the background, which wraps around to row 0
+        assert_eq!(pixel(&frame, 0, 200), rgba(Color::GREEN));
+        assert_eq!(pixel(&frame, 1, 200), pixel(&frame, 1, 0));
+        assert_eq!(pixel(&frame, 8, 201), rgba(Color::GREEN));
+        assert_eq!(pixel(&frame, 10, 223), [0, Color::GREEN.g/2, 0, 0xFF]);
+        // the model itself is left untouched
+        assert_eq!(vram.palettes[0].colors[1].r, Color::RED.r);
+    }
+
+    #[test]
+    fn test_compose_windows() {
+        let mut vram = VRAMModel::empty_vram();
+        vram.palettes[0].colors[1] = Color::RED;
+        vram.palettes[0].colors[2] = Color::GREEN;
+        vram.tilemaps[0].tiles[1].pixels = [1; TILE_SIZE];
+        vram.tilemaps[0].tiles[2].pixels = [2; TILE_SIZE];
+        vram.backgrounds[0].tiles = vec![BackgroundTile::new(1); BG_LENGTH];
+        vram.backgrounds[1].tiles = vec![BackgroundTile::new(2); BG_LENGTH];
+        vram.bg_properties[0].enabled = true;
+        vram.bg_properties[1].enabled = true;
+
+        // a dialogue box: BG 0 is hidden inside window 0, revealing BG 1 beneath it
+        vram.windows[0] = Window { left: 16, top: 160, right: 239, bottom: 207 };
+        vram.bg_window_masks[0] = WindowMask::from(0b001);
+        // a spotlight: BG 1 is only visible inside window 1
+        vram.windows[1] = Window { left: 100, top: 180, right: 120, bottom: 200 };
+        vram.bg_window_masks[1] = WindowMask::from(0b110);
+
+        let mut frame = vec![0; (SCREEN_WIDTH*SCREEN_HEIGHT*4) as usize];
+        Renderer::compose(&vram, &mut TileCache::new(), &mut frame);
+
+        assert_eq!(pixel(&frame, 15, 160), rgba(Color::RED));
+        assert_eq!(pixel(&frame, 110, 190), rgba(Color::GREEN));
+        assert_eq!(pixel(&frame, 239, 207), rgba(Color::BLACK)); // the backdrop
+        assert_eq!(pixel(&frame, 240, 207), rgba(Color::RED));
+    }
+}
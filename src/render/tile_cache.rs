@@ -0,0 +1,87 @@
+use crate::inter::mmio::*;
+use crate::gfx::*;
+
+const TILES_PER_TILEMAP: usize = TILEMAP_LENGTH*TILEMAP_LENGTH;
+/// Each tile is decoded once per palette, plus once more for full color backgrounds
+const PALETTE_SLOTS: usize = PALETTE_COUNT + 1;
+
+#[derive(Clone)]
+struct DecodedTile {
+    tile_generation: u64,
+    palette_generation: u64,
+    colors: [Color; TILE_SIZE]
+}
+
+/// Tiles already looked up in their palettes, kept between frames. Entries are checked against the
+/// generations of the model's tiles and palettes, so only what changed since it was decoded is decoded again.
+pub struct TileCache {
+    tiles: Vec<Option<DecodedTile>>
+}
+
+impl Default for TileCache {
+    fn default() -> Self {
+        TileCache::new()
+    }
+}
+
+impl TileCache {
+    pub fn new() -> TileCache {
+        TileCache { tiles: vec![None; TILEMAP_COUNT*TILES_PER_TILEMAP*PALETTE_SLOTS] }
+    }
+
+    /// The colors of a tile's pixels, row by row. With no palette, the tile is decoded in full color,
+    /// with the high nibble of each pixel selecting the palette.
+    pub fn decoded(&mut self, vram: &VRAMModel, tilemap_index: usize, tile_index: usize, palette_index: Option<usize>) -> &[Color; TILE_SIZE] {
+        let tile_generation = vram.generations.tiles[tilemap_index][tile_index];
+        let palette_generation = match palette_index {
+            Some(palette_index) => vram.generations.palettes[palette_index],
+            // generations only go up, so the newest one changes whenever any palette does
+            None => vram.generations.palettes.iter().copied().max().unwrap_or(0)
+        };
+
+        let slot = (tilemap_index*TILES_PER_TILEMAP + tile_index)*PALETTE_SLOTS + palette_index.unwrap_or(PALETTE_COUNT);
+        let entry = &mut self.tiles[slot];
+        let stale = !matches!(entry, Some(decoded) if decoded.tile_generation == tile_generation && decoded.palette_generation == palette_generation);
+        if stale {
+            let tile = &vram.tilemaps[tilemap_index].tiles[tile_index];
+            let colors = tile.pixels.map(|pixel| match palette_index {
+                Some(palette_index) => vram.palettes[palette_index].colors[(pixel & 0x0F) as usize],
+                None => vram.palettes[((pixel >> 4) & 0b111) as usize].colors[(pixel & 0x0F) as usize]
+            });
+            *entry = Some(DecodedTile { tile_generation, palette_generation, colors });
+        }
+        &entry.as_ref().unwrap().colors
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decoded() {
+        let mut vram = VRAMModel::empty_vram();
+        vram.palettes[1].colors[2] = Color::RED;
+        vram.palettes[3].colors[2] = Color::GREEN;
+        vram.tilemaps[1].tiles[5].pixels[0] = 0x32;
+        let mut cache = TileCache::new();
+
+        // palette selection bits are ignored, unless decoding in full color
+        assert_eq!(cache.decoded(&vram, 1, 5, Some(1))[0], Color::RED);
+        assert_eq!(cache.decoded(&vram, 1, 5, None)[0], Color::GREEN);
+
+        // changes that don't move the generations on aren't seen
+        vram.palettes[1].colors[2] = Color::BLUE;
+        assert_eq!(cache.decoded(&vram, 1, 5, Some(1))[0], Color::RED);
+        vram.generations.touch_palette(1);
+        assert_eq!(cache.decoded(&vram, 1, 5, Some(1))[0], Color::BLUE);
+
+        vram.tilemaps[1].tiles[5].pixels[0] = 0x30;
+        vram.generations.touch_tile(1, 5);
+        assert_eq!(cache.decoded(&vram, 1, 5, Some(1))[0], Color::BLACK);
+        // any palette changing decodes full color tiles again
+        vram.palettes[3].colors[0] = Color::BLUE;
+        vram.generations.touch_palette(3);
+        assert_eq!(cache.decoded(&vram, 1, 5, None)[0], Color::BLUE);
+    }
+}
@@ -0,0 +1,136 @@
+use crate::audio::apu::Apu;
+use crate::savestate::length_prefixed;
+
+use devola::vm::DevolaState;
+use std::fs;
+
+/// Identifies replay files, followed by the format version
+const REPLAY_MAGIC: &[u8; 4] = b"PRPL";
+const REPLAY_VERSION: u8 = 2;
+const HEADER_SIZE: usize = REPLAY_MAGIC.len() + 1;
+/// The size of a replay with no serial bytes and no frames
+const REPLAY_SIZE: usize = HEADER_SIZE + DevolaState::SIZE + Apu::STATE_SIZE + 4;
+
+/// A recorded session: the state the VM, APU and serial port started from, then the joypad register for each frame
+/// after it. Replaying the inputs from that state reproduces the session exactly.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Replay {
+    pub initial_state: DevolaState,
+    /// The APU's progress, as written by `Apu::to_bytes`
+    pub apu: Vec<u8>,
+    /// The serial port's progress, as written by `Serial::to_bytes`
+    pub serial: Vec<u8>,
+    pub frames: Vec<u8>
+}
+
+impl Replay {
+    pub fn new(initial_state: DevolaState, apu: Vec<u8>, serial: Vec<u8>) -> Replay {
+        Replay { initial_state, apu, serial, frames: Vec::new() }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = REPLAY_MAGIC.to_vec();
+        bytes.push(REPLAY_VERSION);
+        bytes.extend(self.initial_state.to_bytes());
+        bytes.extend(&self.apu);
+        bytes.extend_from_slice(&(self.serial.len() as u32).to_le_bytes());
+        bytes.extend(&self.serial);
+        bytes.extend(&self.frames);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Replay, String> {
+        if bytes.len() < HEADER_SIZE || &bytes[..REPLAY_MAGIC.len()] != REPLAY_MAGIC {
+            return Err("not a replay file".to_string());
+        }
+        if bytes[REPLAY_MAGIC.len()] != REPLAY_VERSION {
+            return Err(format!("unsupported replay version {}", bytes[REPLAY_MAGIC.len()]));
+        }
+        let wrong_length = || "the replay is the wrong length".to_string();
+        if bytes.len() < REPLAY_SIZE {
+            return Err(wrong_length());
+        }
+        let (state, rest) = bytes[HEADER_SIZE..].split_at(DevolaState::SIZE);
+        let (apu, rest) = rest.split_at(Apu::STATE_SIZE);
+        let (serial, frames) = length_prefixed(rest, 1).ok_or_else(wrong_length)?;
+        // the length was checked above, so the state can always be read
+        let initial_state = DevolaState::from_bytes(state).unwrap();
+        Ok(Replay { initial_state, apu: apu.to_vec(), serial: serial.to_vec(), frames: frames.to_vec() })
+    }
+
+    pub fn load(path: &str) -> Result<Replay, String> {
+        let bytes = fs::read(path).map_err(|error| error.to_string())?;
+        Replay::from_bytes(&bytes)
+    }
+
+    pub fn save(&self, path: &str) {
+        if let Err(error) = fs::write(path, self.to_bytes()) {
+            tracing::error!("Failed to save replay to {}: {}", path, error);
+        }
+    }
+}
+
+/// Whether a console's inputs are being recorded or played back
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum ReplayMode {
+    #[default]
+    Off,
+    Recording(Replay),
+    /// Playing back a replay, `frame` frames in. Live input takes over once it runs out.
+    Playing { replay: Replay, frame: usize }
+}
+
+impl ReplayMode {
+    /// The joypad register for the frame starting now: `live` unless a replay is playing, in which
+    /// case it's the recorded value. Recordings take note of the value.
+    pub fn next_frame(&mut self, live: u8) -> u8 {
+        match self {
+            ReplayMode::Off => live,
+            ReplayMode::Recording(replay) => {
+                replay.frames.push(live);
+                live
+            },
+            ReplayMode::Playing { replay, frame } => match replay.frames.get(*frame) {
+                Some(&buttons) => {
+                    *frame += 1;
+                    buttons
+                },
+                None => {
+                    tracing::info!("Replay finished after {} frames", frame);
+                    *self = ReplayMode::Off;
+                    live
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use devola::vm::Devola;
+
+    #[test]
+    fn test_replay() {
+        let replay = Replay {
+            initial_state: Devola::new(Vec::new(), None).save_state(),
+            apu: Apu::new().to_bytes(),
+            serial: vec![0, 0, 0, 0, 0, 0, 0, 0, 0x42],
+            frames: vec![1, 2, 0x80]
+        };
+        let bytes = replay.to_bytes();
+        assert_eq!(Replay::from_bytes(&bytes), Ok(replay.clone()));
+        assert_eq!(Replay::from_bytes(b"PRPL"), Err("not a replay file".to_string()));
+        assert_eq!(Replay::from_bytes(&bytes[..REPLAY_SIZE]), Err("the replay is the wrong length".to_string()));
+
+        // recorded values are played back in order, then live input takes over
+        let mut recording = ReplayMode::Recording(Replay::new(replay.initial_state.clone(), replay.apu.clone(), replay.serial.clone()));
+        for buttons in [1, 2, 0x80] {
+            assert_eq!(recording.next_frame(buttons), buttons);
+        }
+        assert_eq!(recording, ReplayMode::Recording(replay.clone()));
+        let mut playing = ReplayMode::Playing { replay, frame: 0 };
+        assert_eq!([0; 4].map(|_| playing.next_frame(0x10)), [1, 2, 0x80, 0x10]);
+        assert_eq!(playing, ReplayMode::Off);
+    }
+}
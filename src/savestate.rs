@@ -0,0 +1,191 @@
+use crate::audio::apu::Apu;
+use crate::audit::fnv1a;
+
+use devola::vm::DevolaState;
+use std::collections::VecDeque;
+use std::fs;
+use std::path::{self, Path, PathBuf};
+
+/// Identifies save state files, followed by the format version
+const STATE_MAGIC: &[u8; 4] = b"PSAV";
+const STATE_VERSION: u8 = 3;
+const HEADER_SIZE: usize = STATE_MAGIC.len() + 1;
+/// The frame cycle, elapsed cycles and frame count
+const COUNTERS_SIZE: usize = 3*8;
+/// An address and the byte there, for each byte the VRAM model changes
+const VRAM_ENTRY_SIZE: usize = 3;
+/// The size of a state with no VRAM changes, no serial bytes and no text
+const STATE_SIZE: usize = HEADER_SIZE + COUNTERS_SIZE + DevolaState::SIZE + Apu::STATE_SIZE + 4 + 4;
+
+/// How many slots each program has for its states
+pub const SLOT_COUNT: usize = 10;
+/// Where states are saved, in a directory for each program
+const STATES_DIRECTORY: &str = "states";
+
+/// Everything needed to carry on from a moment in a console's session: the VM, what's on the screen, where
+/// the APU is in its waves, the serial port's progress, what's on the text console and where the console is in its
+/// frame. Live input and what the serial port is connected to aren't included, and like `DevolaState` it only makes
+/// sense for the program it was taken from.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SaveState {
+    pub devola: DevolaState,
+    /// The bytes of memory that differ once the VRAM model is written back into it, as the model is only latched
+    /// once a frame
+    pub vram: Vec<(u16, u8)>,
+    /// The APU's progress, as written by `Apu::to_bytes`
+    pub apu: Vec<u8>,
+    /// The serial port's progress, as written by `Serial::to_bytes`
+    pub serial: Vec<u8>,
+    /// The text console's rows, empty if it isn't plugged in
+    pub text: Vec<String>,
+    pub frame_cycle: u64,
+    pub elapsed: u64,
+    pub frame: u64
+}
+
+impl SaveState {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = STATE_MAGIC.to_vec();
+        bytes.push(STATE_VERSION);
+        for counter in [self.frame_cycle, self.elapsed, self.frame] {
+            bytes.extend_from_slice(&counter.to_le_bytes());
+        }
+        bytes.extend(self.devola.to_bytes());
+        bytes.extend(&self.apu);
+        bytes.extend_from_slice(&(self.vram.len() as u32).to_le_bytes());
+        for &(address, byte) in &self.vram {
+            bytes.extend_from_slice(&address.to_le_bytes());
+            bytes.push(byte);
+        }
+        bytes.extend_from_slice(&(self.serial.len() as u32).to_le_bytes());
+        bytes.extend(&self.serial);
+        for row in &self.text {
+            bytes.extend(row.as_bytes());
+            bytes.push(b'\n');
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<SaveState, String> {
+        if bytes.len() < HEADER_SIZE || &bytes[..STATE_MAGIC.len()] != STATE_MAGIC {
+            return Err("not a save state".to_string());
+        }
+        if bytes[STATE_MAGIC.len()] != STATE_VERSION {
+            return Err(format!("unsupported save state version {}", bytes[STATE_MAGIC.len()]));
+        }
+        let wrong_length = || "the save state is the wrong length".to_string();
+        if bytes.len() < STATE_SIZE {
+            return Err(wrong_length());
+        }
+        let (counters, rest) = bytes[HEADER_SIZE..].split_at(COUNTERS_SIZE);
+        let (devola, rest) = rest.split_at(DevolaState::SIZE);
+        let (apu, rest) = rest.split_at(Apu::STATE_SIZE);
+        let (vram, rest) = length_prefixed(rest, VRAM_ENTRY_SIZE).ok_or_else(wrong_length)?;
+        let (serial, text) = length_prefixed(rest, 1).ok_or_else(wrong_length)?;
+        let text = std::str::from_utf8(text).map_err(|_| "the save state's text isn't valid UTF-8".to_string())?;
+        let counter = |index: usize| u64::from_le_bytes(counters[8*index..8*(index + 1)].try_into().unwrap());
+        // the length was checked above, so the state can always be read
+        Ok(SaveState {
+            devola: DevolaState::from_bytes(devola).unwrap(),
+            vram: vram.chunks_exact(VRAM_ENTRY_SIZE).map(|entry| (u16::from_le_bytes([entry[0], entry[1]]), entry[2])).collect(),
+            apu: apu.to_vec(),
+            serial: serial.to_vec(),
+            text: text.split_terminator('\n').map(str::to_string).collect(),
+            frame_cycle: counter(0),
+            elapsed: counter(1),
+            frame: counter(2)
+        })
+    }
+
+    pub fn load(path: &Path) -> Result<SaveState, String> {
+        let bytes = fs::read(path).map_err(|error| error.to_string())?;
+        SaveState::from_bytes(&bytes)
+    }
+
+    /// Saves the state, making its program's directory if it's the first
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let written = path.parent().map_or(Ok(()), fs::create_dir_all).and_then(|_| fs::write(path, self.to_bytes()));
+        written.map_err(|error| error.to_string())
+    }
+}
+
+/// Splits off a count of `entry_size` byte entries (u32, little endian) and the entries after it, or `None` if
+/// there aren't that many
+pub(crate) fn length_prefixed(bytes: &[u8], entry_size: usize) -> Option<(&[u8], &[u8])> {
+    let (count, rest) = bytes.split_first_chunk::<4>()?;
+    let length = (u32::from_le_bytes(*count) as usize).checked_mul(entry_size)?;
+    rest.split_at_checked(length)
+}
+
+/// Save states taken as the console runs, to step back through, the oldest dropped once full
+pub struct RewindBuffer {
+    states: VecDeque<SaveState>,
+    capacity: usize
+}
+
+impl RewindBuffer {
+    pub fn new(capacity: usize) -> RewindBuffer {
+        RewindBuffer { states: VecDeque::with_capacity(capacity), capacity }
+    }
+
+    pub fn push(&mut self, state: SaveState) {
+        if self.states.len() == self.capacity {
+            self.states.pop_front();
+        }
+        self.states.push_back(state);
+    }
+
+    /// Takes the latest state, if there are any left
+    pub fn pop(&mut self) -> Option<SaveState> {
+        self.states.pop_back()
+    }
+
+    pub fn clear(&mut self) {
+        self.states.clear();
+    }
+}
+
+/// Where the state in a slot is kept for a program, in a directory named after the program's file and a hash of
+/// its full path, so that programs with the same name in different places don't share slots
+pub fn slot_path(program: &Path, slot: usize) -> PathBuf {
+    let name = program.file_name().map_or_else(|| String::from("unnamed"), |name| name.to_string_lossy().into_owned());
+    let full_path = path::absolute(program).unwrap_or_else(|_| program.to_path_buf());
+    let directory = format!("{}-{:016x}", name, fnv1a(full_path.as_os_str().as_encoded_bytes()));
+    Path::new(STATES_DIRECTORY).join(directory).join(format!("slot{}.state", slot))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use devola::vm::Devola;
+
+    #[test]
+    fn test_save_state() {
+        let devola = Devola::new(Vec::new(), None).save_state();
+        let state = SaveState {
+            devola, vram: vec![(0x6000, 0x1F), (0xFFFF, 2)], apu: Apu::new().to_bytes(), serial: vec![0, 1, 0, 0, 0, 0, 0, 0, 0x41],
+            text: vec!["Hi".to_string(), String::new()], frame_cycle: 7, elapsed: 1000, frame: 3
+        };
+        let bytes = state.to_bytes();
+        assert_eq!(SaveState::from_bytes(&bytes), Ok(state.clone()));
+        assert_eq!(SaveState::from_bytes(b"PSAV"), Err("not a save state".to_string()));
+        assert_eq!(SaveState::from_bytes(&bytes[..100]), Err("the save state is the wrong length".to_string()));
+        // the VRAM changes and serial bytes are cut short
+        assert_eq!(SaveState::from_bytes(&bytes[..STATE_SIZE + 4]), Err("the save state is the wrong length".to_string()));
+
+        // programs with the same name elsewhere have slots of their own
+        let slot = slot_path(Path::new("games/snake.pop"), 3);
+        assert!(slot.starts_with(STATES_DIRECTORY));
+        assert!(slot.parent().unwrap().file_name().unwrap().to_string_lossy().starts_with("snake.pop-"));
+        assert!(slot.ends_with("slot3.state"));
+        assert_eq!(slot, slot_path(Path::new("games/snake.pop"), 3));
+        assert_ne!(slot.parent(), slot_path(Path::new("other/snake.pop"), 3).parent());
+
+        // the oldest states make way for new ones
+        let mut rewind = RewindBuffer::new(2);
+        for frame in 0..3 {
+            rewind.push(SaveState { frame, ..state.clone() });
+        }
+        assert_eq!([0; 3].map(|_| rewind.pop().map(|state| state.frame)), [Some(2), Some(1), None]);
+    }
+}
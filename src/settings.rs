@@ -0,0 +1,227 @@
+//! The frontend's settings, read from a TOML file at startup and written back to it whenever they change
+use crate::capture::CAPTURE_FORMATS;
+use crate::gfx::Color;
+use crate::inter::input_map::InputMap;
+use crate::render::pacer::AUDIO_SYNC_TARGET;
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Where settings are loaded from at startup, and saved to when changed
+pub const SETTINGS_PATH: &str = "popola.toml";
+/// Where bindings were kept before the settings file, next to it
+const INPUT_CONFIG_FILE: &str = "popola-input.cfg";
+/// How many recently opened programs are remembered
+const RECENT_LIMIT: usize = 10;
+/// How many frames a second run while throttled in the background, unless set otherwise
+const BACKGROUND_FRAME_RATE: u64 = 10;
+/// How long a capture runs for unless stopped sooner, unless set otherwise
+const CAPTURE_SECONDS: u64 = 10;
+
+/// What the console does while none of its windows have focus
+#[derive(Debug, Default, PartialEq, Copy, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Unfocused {
+    #[default]
+    Run,
+    Pause,
+    /// Runs at the background frame rate, with the audio muted
+    Throttle
+}
+
+impl Unfocused {
+    pub fn next(self) -> Unfocused {
+        match self {
+            Unfocused::Run => Unfocused::Pause,
+            Unfocused::Pause => Unfocused::Throttle,
+            Unfocused::Throttle => Unfocused::Run
+        }
+    }
+}
+
+/// The colors the debugger window is drawn in
+#[derive(Debug, PartialEq, Copy, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DebuggerColors {
+    pub background: Color,
+    pub label: Color,
+    /// The outline around the selected item
+    pub highlight: Color
+}
+
+impl Default for DebuggerColors {
+    fn default() -> Self {
+        DebuggerColors {
+            background: Color { r: 24, g: 24, b: 32 },
+            label: Color { r: 200, g: 200, b: 200 },
+            highlight: Color { r: 255, g: 255, b: 255 }
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    /// How many times the screen's size the window starts at, as it was last closed, or picked to fit the monitor
+    /// if left out
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scale: Option<u32>,
+    pub fullscreen: bool,
+    /// How much audio is kept queued when syncing to it, in milliseconds
+    pub audio_latency: u64,
+    /// Programs that have been opened, the latest first
+    pub recent: Vec<String>,
+    /// The inputs bound to each joypad button, as `key:<winit key code>` or `pad:<gilrs button>`
+    pub bindings: BTreeMap<String, Vec<String>>,
+    pub debugger: DebuggerColors,
+    pub unfocused: Unfocused,
+    /// How many frames a second run while throttled in the background
+    pub background_frame_rate: u64,
+    /// The file extension captures are saved with, one of `CAPTURE_FORMATS`
+    pub capture_format: String,
+    /// How long a capture runs for unless stopped sooner
+    pub capture_seconds: u64
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            scale: None,
+            fullscreen: false,
+            audio_latency: AUDIO_SYNC_TARGET.as_millis() as u64,
+            recent: Vec::new(),
+            bindings: InputMap::default().to_bindings(),
+            debugger: DebuggerColors::default(),
+            unfocused: Unfocused::default(),
+            background_frame_rate: BACKGROUND_FRAME_RATE,
+            capture_format: String::from("gif"),
+            capture_seconds: CAPTURE_SECONDS
+        }
+    }
+}
+
+impl Settings {
+    /// Loads settings from a file, or the defaults if there isn't one yet. Settings left out of the file take their
+    /// defaults. A file that can't be read or parsed is an error, so that it isn't saved over.
+    pub fn load(path: &str) -> Result<Settings, String> {
+        match fs::read_to_string(path) {
+            Ok(text) => toml::from_str(&text).map_err(|error| error.to_string()),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(Settings::migrate(path)),
+            Err(error) => Err(error.to_string())
+        }
+    }
+
+    /// The defaults, with the bindings from an input config left by an older version, if there is one next to
+    /// where the settings go
+    fn migrate(path: &str) -> Settings {
+        let config_path = Path::new(path).with_file_name(INPUT_CONFIG_FILE);
+        match fs::read_to_string(&config_path) {
+            Ok(text) => {
+                tracing::info!("Moving the bindings in {} into {}", config_path.display(), path);
+                Settings { bindings: parse_input_config(&text), ..Settings::default() }
+            },
+            Err(_) => Settings::default()
+        }
+    }
+
+    pub fn save(&self, path: &str) {
+        if let Err(error) = fs::write(path, toml::to_string_pretty(self).unwrap()) {
+            tracing::error!("Failed to save settings to {}: {}", path, error);
+        }
+    }
+
+    /// The bindings, or the defaults if any of them can't be read
+    pub fn input_map(&self) -> InputMap {
+        match InputMap::from_bindings(&self.bindings) {
+            Ok(input_map) => input_map,
+            Err(errors) => {
+                for error in errors {
+                    tracing::warn!("bindings: {}", error);
+                }
+                InputMap::default()
+            }
+        }
+    }
+
+    /// The format captures are saved in, or GIF if the one set isn't supported
+    pub fn capture_format(&self) -> &str {
+        if CAPTURE_FORMATS.contains(&self.capture_format.as_str()) {
+            &self.capture_format
+        } else {
+            tracing::warn!("capture_format: {} isn't one of {}, so captures are saved as GIFs", self.capture_format, CAPTURE_FORMATS.join(", "));
+            "gif"
+        }
+    }
+
+    /// Moves a program to the top of the recently opened list
+    pub fn add_recent(&mut self, path: &Path) {
+        let path = fs::canonicalize(path).unwrap_or(path.to_path_buf()).to_string_lossy().into_owned();
+        self.recent.retain(|recent| *recent != path);
+        self.recent.insert(0, path);
+        self.recent.truncate(RECENT_LIMIT);
+    }
+}
+
+/// Reads the bindings out of an input config, one joypad button per line: its name, `=`, then the inputs bound to
+/// it separated by spaces. Blank lines, lines starting with `#` and lines without `=` are skipped.
+fn parse_input_config(text: &str) -> BTreeMap<String, Vec<String>> {
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.starts_with('#'))
+        .filter_map(|line| line.split_once('='))
+        .map(|(name, inputs)| (name.trim().to_string(), inputs.split_whitespace().map(String::from).collect()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::inter::input_map::Input;
+    use crate::inter::mmio::JOYPAD_A;
+    use winit::keyboard::KeyCode;
+
+    #[test]
+    fn test_settings() {
+        let settings: Settings = toml::from_str("
+            scale = 3
+            unfocused = \"throttle\"
+            [bindings]
+            a = [\"key:KeyA\"]
+            [debugger]
+            label = { r = 1, g = 2, b = 3 }
+        ").unwrap();
+        assert_eq!(settings.scale, Some(3));
+        assert_eq!(settings.audio_latency, Settings::default().audio_latency);
+        assert_eq!((settings.unfocused, settings.background_frame_rate), (Unfocused::Throttle, BACKGROUND_FRAME_RATE));
+        assert_eq!(settings.debugger.label, Color { r: 1, g: 2, b: 3 });
+        assert_eq!(settings.debugger.background, DebuggerColors::default().background);
+        assert_eq!(Settings { capture_format: String::from("../mp4"), ..Settings::default() }.capture_format(), "gif");
+        let input_map = settings.input_map();
+        assert_eq!(input_map.button(Input::Key(KeyCode::KeyA)), Some(JOYPAD_A));
+        assert_eq!(input_map.button(Input::Key(KeyCode::KeyX)), None);
+
+        // the defaults survive being written out and read back
+        let text = toml::to_string_pretty(&Settings::default()).unwrap();
+        assert_eq!(toml::from_str::<Settings>(&text).unwrap(), Settings::default());
+
+        let mut settings = Settings::default();
+        for index in 0..=RECENT_LIMIT {
+            settings.add_recent(Path::new(&format!("missing{}.pop", index)));
+        }
+        settings.add_recent(Path::new("missing5.pop"));
+        assert_eq!(settings.recent.len(), RECENT_LIMIT);
+        assert_eq!(settings.recent[..2], ["missing5.pop", &format!("missing{}.pop", RECENT_LIMIT)]);
+
+        // bindings from an older input config carry over, and a file that doesn't parse isn't replaced
+        let bindings = parse_input_config("# comment\n\na = key:KeyA pad:South\nstart = key:Space\n");
+        assert_eq!(bindings["a"], ["key:KeyA", "pad:South"]);
+        assert_eq!((bindings.len(), bindings["start"].len()), (2, 1));
+        let path = std::env::temp_dir().join(format!("popola_settings_{}.toml", std::process::id()));
+        fs::write(&path, "scale = ").unwrap();
+        assert!(Settings::load(path.to_str().unwrap()).is_err());
+        fs::remove_file(path).unwrap();
+    }
+}